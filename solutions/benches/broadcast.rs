@@ -0,0 +1,75 @@
+//! A broadcast node's handlers, and building a round of gossip, on a node that has seen
+//! plenty: `cargo bench --bench broadcast`.
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Instant};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use solutions::{message::{Body, Envelope}, topology::Overlay, workloads::broadcast::{self, Payload, State}};
+use tokio::{runtime::Runtime, sync::mpsc::{self, UnboundedSender}};
+
+
+/// How many nodes are in the cluster; every node is a neighbor.
+const NODES: usize = 25;
+/// How many messages the node has seen (and none of its neighbors have acknowledged).
+const SEEN: usize = 10_000;
+
+
+fn request(message: Payload) -> Envelope<Payload> {
+    Envelope::new("c1", "n1", Body { msg_id: Some(1), in_reply_to: None, message })
+}
+
+/// A node that's been told about [`SEEN`] messages, with its replies going nowhere.
+fn node(runtime: &Runtime) -> (Arc<Mutex<State>>, UnboundedSender<Envelope<Payload>>) {
+    // A stride of 1 makes every node a neighbor.
+    let state = Arc::new(Mutex::new(State::new(Overlay::Stride(1))));
+    let (writer, mut replies) = mpsc::unbounded_channel();
+    runtime.spawn(async move { while replies.recv().await.is_some() {} });
+    let node_ids = (1..=NODES).map(|n| format!("n{n}")).collect();
+    let setup =
+        [
+            Payload::Init { node_id: "n1".to_owned(), node_ids },
+            Payload::Topology { topology: HashMap::new() },
+        ]
+        .into_iter()
+        .chain((0..SEEN).map(|message| Payload::Broadcast { message }));
+    runtime.block_on(async {
+        for message in setup {
+            broadcast::handle_envelope(state.clone(), request(message), writer.clone()).await;
+        }
+    });
+    (state, writer)
+}
+
+fn handlers(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let (state, writer) = node(&runtime);
+    let mut group = c.benchmark_group("broadcast handler");
+    let mut next = SEEN;
+    group.bench_function("broadcast", |b| b.iter(|| {
+        next += 1;
+        runtime.block_on(broadcast::handle_envelope(state.clone(), request(Payload::Broadcast { message: next }), writer.clone()));
+    }));
+    group.bench_function("read", |b| b.iter(|| {
+        runtime.block_on(broadcast::handle_envelope(state.clone(), request(Payload::Read { chunk_size: None, ranges: false }), writer.clone()));
+    }));
+    group.bench_function("sync of 100 seen", |b| b.iter_batched(
+        || request(Payload::Sync { messages: (0..100).collect(), updates: vec![] }),
+        |sync| runtime.block_on(broadcast::handle_envelope(state.clone(), sync, writer.clone())),
+        BatchSize::SmallInput,
+    ));
+    group.finish();
+}
+
+fn gossip_round(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let (state, _writer) = node(&runtime);
+    // Each round starts from the same node, rather than one with every earlier round's
+    // syncs in flight.
+    c.bench_function("broadcast gossip round", |b| b.iter_batched(
+        || state.lock().unwrap().clone(),
+        |mut state| broadcast::syncs(&mut state, Instant::now()),
+        BatchSize::SmallInput,
+    ));
+}
+
+criterion_group!(benches, handlers, gossip_round);
+criterion_main!(benches);
@@ -0,0 +1,36 @@
+//! Serializing and parsing envelopes, small (a `broadcast`) and large (a `sync` carrying
+//! 10,000 messages), both ways: `cargo bench --bench envelope`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use solutions::{message::{Body, Envelope}, workloads::broadcast::Payload};
+
+
+fn envelopes() -> [(&'static str, Envelope<Payload>); 2] {
+    let body = |message| Body { msg_id: Some(12), in_reply_to: None, message };
+    [
+        ("small", Envelope::new("c1", "n1", body(Payload::Broadcast { message: 1024 }))),
+        ("large", Envelope::new("n1", "n2", body(Payload::Sync { messages: (0..10_000).map(|message| message * 7919).collect(), updates: vec![] }))),
+    ]
+}
+
+fn serde(c: &mut Criterion) {
+    let mut group = c.benchmark_group("envelope");
+    for (size, envelope) in envelopes() {
+        let line = serde_json::to_vec(&envelope).unwrap();
+        group.throughput(Throughput::Bytes(line.len() as u64));
+        group.bench_with_input(BenchmarkId::new("serialize", size), &envelope, |b, envelope| {
+            let mut buffer = Vec::with_capacity(line.len());
+            b.iter(|| {
+                buffer.clear();
+                serde_json::to_writer(&mut buffer, envelope).unwrap();
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("deserialize", size), &line, |b, line| {
+            b.iter(|| serde_json::from_slice::<Envelope<Payload>>(line).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, serde);
+criterion_main!(benches);
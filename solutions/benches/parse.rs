@@ -0,0 +1,32 @@
+//! How fast `io_channel` parses broadcast `sync` messages of growing size, against plain
+//! serde_json: `cargo bench --bench parse`, and again with `--features simd-json` to see
+//! what simd-json buys.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use solutions::{io, message::{Body, Envelope}, workloads::broadcast::Payload};
+
+
+fn sync_line(num_messages: usize) -> Vec<u8> {
+    let payload = Payload::Sync { messages: (0..num_messages).map(|message| message * 7919).collect(), updates: vec![] };
+    let envelope = Envelope::new("n1", "n2", Body { msg_id: Some(1), in_reply_to: None, message: payload });
+    serde_json::to_vec(&envelope).unwrap()
+}
+
+fn parse_sync(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse sync");
+    for num_messages in [10, 1_000, 100_000] {
+        let line = sync_line(num_messages);
+        group.throughput(Throughput::Bytes(line.len() as u64));
+        group.bench_with_input(BenchmarkId::new("serde_json", num_messages), &line, |b, line| {
+            b.iter(|| serde_json::from_slice::<Envelope<Payload>>(line).unwrap())
+        });
+        let mut scratch = Vec::new();
+        group.bench_with_input(BenchmarkId::new("io::parse_line", num_messages), &line, |b, line| {
+            b.iter(|| io::parse_line_with::<Envelope<Payload>>(line, &mut scratch).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse_sync);
+criterion_main!(benches);
@@ -0,0 +1,7 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use solutions::{message::Envelope, workloads::echo::Payload};
+
+
+fuzz_target!(|data: &[u8]| solutions_fuzz::run::<Envelope<Payload>>(data));
@@ -0,0 +1,7 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use serde_json::Value;
+use solutions::message::Envelope;
+
+fuzz_target!(|data: &[u8]| solutions_fuzz::run::<Envelope<Value>>(data));
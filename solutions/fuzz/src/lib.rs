@@ -0,0 +1,85 @@
+use arbitrary::{Arbitrary, Result, Unstructured};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Map, Value};
+use solutions::testing::fuzz_line;
+
+
+/// Every message `type` any of the workloads (or the services they talk to) use.
+const TYPES: &[&str] = &[
+    "init", "init_ok", "error",
+    "echo", "echo_ok",
+    "generate", "generate_ok",
+    "broadcast", "broadcast_ok", "read", "read_ok", "topology", "topology_ok", "sync", "sync_ok",
+    "add", "add_ok", "write", "write_ok", "cas", "cas_ok", "update_counter",
+    "send", "send_ok", "poll", "poll_ok", "commit_offsets", "commit_offsets_ok", "list_committed_offsets", "list_committed_offsets_ok",
+    "txn", "txn_ok", "shard_execute", "shard_execute_ok", "shard_finish", "shard_finish_ok",
+];
+
+/// Every field name those messages carry.
+const FIELDS: &[&str] = &[
+    "msg_id", "in_reply_to", "node_id", "node_ids", "code", "text",
+    "echo", "id", "message", "messages", "topology", "delta", "key", "value", "from", "to",
+    "create_if_not_exists", "msg", "offset", "offsets", "msgs", "keys", "sub_key", "txn", "commit",
+];
+
+
+/// What every target runs: `data` as a line of input verbatim (which is what the
+/// corpora hold), and then as the seed for a [`Line`].
+pub fn run<M: Serialize + DeserializeOwned>(data: &[u8]) {
+    fuzz_line::<M>(data);
+    if let Ok(line) = Line::arbitrary_take_rest(Unstructured::new(data)) {
+        fuzz_line::<M>(&line.0);
+    }
+}
+
+
+/// An envelope around a body with a real message type and a random selection of
+/// real field names holding random JSON, which gets much deeper into the payload
+/// parsers than mutating raw bytes does.
+#[derive(Debug)]
+pub struct Line(pub Vec<u8>);
+
+impl<'a> Arbitrary<'a> for Line {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut body = Map::new();
+        body.insert("type".to_owned(), Value::from(*u.choose(TYPES)?));
+        for _ in 0..u.int_in_range(0..=6)? {
+            body.insert((*u.choose(FIELDS)?).to_owned(), value(u, 3)?);
+        }
+        let envelope = json!({ "src": node(u)?, "dest": node(u)?, "body": body });
+        Ok(Line(serde_json::to_vec(&envelope).unwrap()))
+    }
+}
+
+
+fn node(u: &mut Unstructured) -> Result<String> {
+    Ok(match u.int_in_range(0..=2)? {
+        0 => format!("n{}", u.int_in_range(0..=9)?),
+        1 => format!("c{}", u.int_in_range(0..=99)?),
+        _ => u.arbitrary()?,
+    })
+}
+
+fn value(u: &mut Unstructured, depth: usize) -> Result<Value> {
+    let max = if depth == 0 { 5 } else { 7 };
+    Ok(match u.int_in_range(0..=max)? {
+        0 => Value::Null,
+        1 => Value::from(u.arbitrary::<bool>()?),
+        2 => Value::from(u.arbitrary::<i64>()?),
+        3 => Value::from(u.arbitrary::<u64>()?),
+        4 => Value::from(u.arbitrary::<f64>()?),
+        5 => Value::from(u.arbitrary::<String>()?),
+        6 => Value::Array((0..u.int_in_range(0..=4)?).map(|_| value(u, depth - 1)).collect::<Result<_>>()?),
+        _ => {
+            let mut map = Map::new();
+            for _ in 0..u.int_in_range(0..=4)? {
+                let key = match u.arbitrary()? {
+                    true => (*u.choose(FIELDS)?).to_owned(),
+                    false => u.arbitrary()?,
+                };
+                map.insert(key, value(u, depth - 1)?);
+            }
+            Value::Object(map)
+        },
+    })
+}
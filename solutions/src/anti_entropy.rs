@@ -0,0 +1,246 @@
+use std::{collections::{BTreeMap, HashSet}, future::Future, hash::{Hash, Hasher}, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::Duration};
+use siphasher::sip::SipHasher13;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use crate::{message::NodeId, metrics, scheduler::{Job, JobHandle, Period, Scheduler, DEFAULT_JITTER}, task};
+
+
+/// How many leaves (buckets of keys) a [`MerkleTree`] has: a power of two.
+pub const LEAVES: usize = 64;
+
+
+/// A store whose keys are kept on several nodes, which [`repair`] can bring back in line
+/// with a peer's copy.
+pub trait Replica {
+    /// What's sent for a key that a peer is missing, or has an older copy of.
+    type Entry;
+
+    /// Each key this node keeps that `peer` is meant to keep too (the range of keys they
+    /// own in common), with a digest of this node's copy: two copies with the same
+    /// digest have the same contents.
+    fn digests(&self, peer: &str) -> Vec<(String, u64)>;
+
+    /// What of `key` to send a peer whose copy of it has the digest `theirs` (`None` if
+    /// it has no copy): nothing if it has everything this node does.
+    fn entry(&self, key: &str, theirs: Option<u64>) -> Option<Self::Entry>;
+
+    /// Take in an entry from a peer, keeping whatever's newer of it. Whether anything
+    /// changed.
+    fn take(&mut self, entry: Self::Entry) -> bool;
+}
+
+
+/// A hash tree over a set of keys' digests: each of its [`LEAVES`] hashes a bucket of
+/// keys, and each node above them hashes the two below it, so two trees with the same
+/// root hold the same digests, and where they differ can be narrowed down to the buckets
+/// that do without looking at the rest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MerkleTree(Vec<u64>);
+
+impl MerkleTree {
+    /// The tree over `digests`, in any order.
+    pub fn build<'a>(digests: impl IntoIterator<Item = (&'a str, u64)>) -> Self {
+        // Laid out as a heap: the root at 0, the children of `i` at `2i + 1` and `2i + 2`,
+        // and the leaves last.
+        let mut nodes = vec![0u64; 2 * LEAVES - 1];
+        for (key, digest) in digests {
+            // Added up, so the order keys come in doesn't matter.
+            let leaf = &mut nodes[LEAVES - 1 + bucket(key)];
+            *leaf = leaf.wrapping_add(hash(&(key, digest)));
+        }
+        for i in (0..LEAVES - 1).rev() {
+            nodes[i] = hash(&(nodes[2 * i + 1], nodes[2 * i + 2]));
+        }
+        Self(nodes)
+    }
+
+    pub fn root(&self) -> u64 {
+        self.0[0]
+    }
+
+    /// The buckets whose keys differ between the two trees, found by going down only
+    /// the branches whose hashes differ. A malformed tree differs everywhere.
+    pub fn diff(&self, other: &MerkleTree) -> Vec<usize> {
+        if other.0.len() != self.0.len() {
+            return (0..LEAVES).collect();
+        }
+        let mut differing = vec![];
+        let mut unvisited = vec![0];
+        while let Some(i) = unvisited.pop() {
+            if self.0[i] == other.0[i] {
+                continue;
+            }
+            match i >= LEAVES - 1 {
+                true => differing.push(i - (LEAVES - 1)),
+                false => unvisited.extend([2 * i + 2, 2 * i + 1]),
+            }
+        }
+        differing
+    }
+}
+
+/// Which of a [`MerkleTree`]'s leaves `key` is in.
+pub fn bucket(key: &str) -> usize {
+    hash(key) as usize % LEAVES
+}
+
+/// Trees are compared across nodes, so this has to hash the same everywhere: a
+/// zero-keyed `SipHasher13` is pinned by its crate, where `DefaultHasher` is free to
+/// change with the toolchain.
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = SipHasher13::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+
+/// A round of repair between two nodes, as the workload sends it: the node starting it
+/// sends its tree, is told the digests of the peer's keys in the buckets that differ,
+/// and sends what of those the peer is missing (with its own digests of them), getting
+/// back what it's missing in turn. Tagged with `step`, so it can sit inside a workload's
+/// own `type`-tagged messages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum Message<E> {
+    Digest {
+        tree: MerkleTree,
+    },
+    /// Empty if the trees are the same.
+    DigestOk {
+        buckets: Vec<usize>,
+        digests: Vec<(String, u64)>,
+    },
+    Entries {
+        buckets: Vec<usize>,
+        entries: Vec<E>,
+        digests: Vec<(String, u64)>,
+    },
+    EntriesOk {
+        entries: Vec<E>,
+    },
+}
+
+
+/// Answer a step of a round of repair that `peer` started, taking in what it sent.
+/// `None` for the steps that are answers themselves.
+pub fn answer<R: Replica>(replica: &mut R, peer: &str, message: Message<R::Entry>) -> Option<Message<R::Entry>> {
+    match message {
+        Message::Digest { tree } => {
+            let digests = replica.digests(peer);
+            let buckets = MerkleTree::build(digests.iter().map(|(key, digest)| (&**key, *digest))).diff(&tree);
+            let digests = in_buckets(digests, &buckets);
+            Some(Message::DigestOk { buckets, digests })
+        },
+        Message::Entries { buckets, entries, digests } => {
+            take(replica, entries);
+            let entries = missing(replica, peer, &buckets, digests);
+            Some(Message::EntriesOk { entries })
+        },
+        Message::DigestOk { .. } | Message::EntriesOk { .. } => None,
+    }
+}
+
+/// Go through a round of repair with `peer`, sending it each step through `call`, which
+/// gives back its answer (`None` if there isn't one in time). Whether the round went all
+/// the way through. Counted in `anti_entropy.rounds`, and those that found nothing to
+/// repair in `anti_entropy.in_sync`.
+pub async fn repair<R, F, Fut>(replica: &Mutex<R>, peer: &str, call: F) -> bool
+where
+    R: Replica,
+    F: Fn(Message<R::Entry>) -> Fut,
+    Fut: Future<Output = Option<Message<R::Entry>>>,
+{
+    metrics::global().increment("anti_entropy.rounds");
+    let tree = {
+        let replica = replica.lock().unwrap();
+        MerkleTree::build(replica.digests(peer).iter().map(|(key, digest)| (&**key, *digest)))
+    };
+    let Some(Message::DigestOk { buckets, digests }) = call(Message::Digest { tree }).await else {
+        return false;
+    };
+    if buckets.is_empty() {
+        metrics::global().increment("anti_entropy.in_sync");
+        return true;
+    }
+    debug!(peer, buckets = buckets.len(), "repairing buckets that differ");
+    let sent = {
+        let replica = replica.lock().unwrap();
+        let entries = missing(&*replica, peer, &buckets, digests);
+        let digests = in_buckets(replica.digests(peer), &buckets);
+        Message::Entries { buckets, entries, digests }
+    };
+    let Some(Message::EntriesOk { entries }) = call(sent).await else {
+        return false;
+    };
+    take(&mut *replica.lock().unwrap(), entries);
+    true
+}
+
+/// Every `period`, as a `repair` job on `scheduler`: [`repair`] with one of `peers()`,
+/// picked at random, unless the last round hasn't finished. `call` sends a step to a
+/// peer.
+pub fn repair_every_so_often<R, P, F, Fut>(scheduler: &Scheduler, period: Duration, replica: Arc<Mutex<R>>, mut peers: P, call: F) -> JobHandle
+where
+    R: Replica + Send + 'static,
+    R::Entry: Send,
+    P: FnMut() -> Vec<NodeId> + Send + 'static,
+    F: Fn(NodeId, Message<R::Entry>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Option<Message<R::Entry>>> + Send,
+{
+    let repairing = Arc::new(AtomicBool::new(false));
+    scheduler.spawn(Job::new("repair", Period::Every(period)).jitter(DEFAULT_JITTER), move |_| {
+        let Some(peer) = peers().choose(&mut rand::thread_rng()).cloned() else {
+            return;
+        };
+        if repairing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let (replica, call, repairing) = (replica.clone(), call.clone(), Repairing(repairing.clone()));
+        task::spawn("anti_entropy.repair", async move {
+            let _repairing = repairing;
+            repair(&replica, &peer, |message| call(peer.clone(), message)).await;
+        });
+    })
+}
+
+/// Marks a round of [`repair_every_so_often`] as over when dropped: once it's done, and
+/// just as well if it panics or is cancelled part way, so later rounds aren't held back
+/// forever.
+struct Repairing(Arc<AtomicBool>);
+
+impl Drop for Repairing {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+
+fn in_buckets(digests: Vec<(String, u64)>, buckets: &[usize]) -> Vec<(String, u64)> {
+    let buckets: HashSet<usize> = buckets.iter().copied().collect();
+    digests.into_iter().filter(|(key, _)| buckets.contains(&bucket(key))).collect()
+}
+
+/// What `peer`, whose digests of its keys in `buckets` are `theirs`, is missing of this
+/// node's keys in them. Counted in `anti_entropy.entries_sent`.
+fn missing<R: Replica>(replica: &R, peer: &str, buckets: &[usize], theirs: Vec<(String, u64)>) -> Vec<R::Entry> {
+    let theirs: BTreeMap<String, u64> = theirs.into_iter().collect();
+    let entries: Vec<R::Entry> =
+        in_buckets(replica.digests(peer), buckets)
+        .into_iter()
+        .filter(|(key, digest)| theirs.get(key) != Some(digest))
+        .filter_map(|(key, _)| replica.entry(&key, theirs.get(&key).copied()))
+        .collect();
+    metrics::global().add("anti_entropy.entries_sent", entries.len() as u64);
+    entries
+}
+
+/// Take in `entries`, counting those that changed anything in `anti_entropy.repaired`.
+fn take<R: Replica>(replica: &mut R, entries: Vec<R::Entry>) {
+    for entry in entries {
+        if replica.take(entry) {
+            metrics::global().increment("anti_entropy.repaired");
+        }
+    }
+}
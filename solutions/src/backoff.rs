@@ -0,0 +1,90 @@
+use std::{sync::Arc, time::Duration};
+use rand::Rng;
+use crate::clock::{self, Clock};
+
+
+/// How much of each delay a [`Backoff`] randomizes, so nodes that failed together don't
+/// all retry together.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Jitter {
+    /// Wait exactly the delay.
+    #[default]
+    None,
+    /// Wait anywhere from nothing up to the delay.
+    Full,
+    /// Wait at least half the delay, and up to all of it.
+    Equal,
+}
+
+
+/// Exponential backoff: the first delay is `initial`, and every one after it `multiplier`
+/// times longer (twice as long, unless told otherwise), up to `max`, with some
+/// [`Jitter`] on top. Built up from [`Backoff::new`], so each retry loop (transaction
+/// conflicts, resends, reconnects) needn't keep its own timer.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    multiplier: f64,
+    max: Duration,
+    jitter: Jitter,
+    /// What [`wait`](Self::wait) sleeps by.
+    clock: Arc<dyn Clock>,
+    /// The delay the next wait is based on.
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration) -> Self {
+        Self { initial, multiplier: 2., max: Duration::MAX, jitter: Jitter::None, clock: clock::system(), current: initial }
+    }
+
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// The longest delay to back off to, before jitter. The initial one is held to it too.
+    pub fn max(mut self, max: Duration) -> Self {
+        self.max = max;
+        self.current = self.current.min(max);
+        self
+    }
+
+    pub fn jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// How long to wait this time, drawing the jitter from `rng`, and backing off further
+    /// for next time.
+    pub fn next_delay_with<R: Rng>(&mut self, rng: &mut R) -> Duration {
+        let delay = self.current;
+        let scaled = Duration::try_from_secs_f64(delay.as_secs_f64() * self.multiplier).unwrap_or(Duration::MAX);
+        self.current = scaled.min(self.max);
+        match self.jitter {
+            Jitter::None => delay,
+            Jitter::Full => delay.mul_f64(rng.gen_range(0.0..=1.0)),
+            Jitter::Equal => delay / 2 + (delay / 2).mul_f64(rng.gen_range(0.0..=1.0)),
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        self.next_delay_with(&mut rand::thread_rng())
+    }
+
+    /// Sleep for the [next delay](Self::next_delay).
+    pub async fn wait(&mut self) {
+        let delay = self.next_delay();
+        self.clock.sleep(delay).await;
+    }
+
+    /// Start over from the initial delay, say after a success.
+    pub fn reset(&mut self) {
+        self.current = self.initial.min(self.max);
+    }
+}
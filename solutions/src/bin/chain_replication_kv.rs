@@ -0,0 +1,10 @@
+use solutions::{cli, conformance::Workload, workloads::{self, chain_replication_kv}};
+
+fn main() {
+    let opts = cli::parse::<chain_replication_kv::Opts>();
+    workloads::self_test(&opts.common, Workload::LinKv, {
+        let opts = opts.clone();
+        move || chain_replication_kv::server(opts)
+    });
+    workloads::run(opts.common.clone(), chain_replication_kv::server(opts));
+}
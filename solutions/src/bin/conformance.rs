@@ -0,0 +1,204 @@
+use std::{process::{ExitCode, Stdio}, time::Duration};
+use clap::Parser;
+use serde_json::{json, Value};
+use solutions::{conformance::{self, Finding, Severity, Workload}, message::{Body, Envelope}};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, process::{Child, ChildStdin, Command}, sync::mpsc::{self, UnboundedReceiver}, time::Instant};
+
+
+#[derive(Debug, Parser)]
+#[clap(author, version, about = "Walk a node through Maelstrom's protocol (init, topology, its workload's ops, and some awkward input) and report what it gets wrong.")]
+pub struct Opts {
+    #[clap(long, help = "The workload the node implements: echo, unique-ids, broadcast, g-counter, kafka, txn-rw-register or lin-kv.", env = "WORKLOAD")]
+    pub workload: Workload,
+    #[clap(long, default_value = "1000", help = "How long to wait for each reply, in milliseconds.", env = "TIMEOUT_MS")]
+    pub timeout_ms: u64,
+    #[clap(required = true, trailing_var_arg = true, help = "The node to run, and its arguments.")]
+    pub command: Vec<String>,
+}
+
+
+const NODE: &str = "n1";
+
+
+struct Node {
+    child: Child,
+    stdin: ChildStdin,
+    lines: UnboundedReceiver<String>,
+    timeout: Duration,
+}
+
+impl Node {
+    async fn write(&mut self, line: &str) -> bool {
+        self.stdin.write_all(format!("{line}\n").as_bytes()).await.is_ok()
+    }
+
+    /// Send `requests` and wait for a reply to each (matched by recipient and
+    /// `in_reply_to`). Anything else the node prints that isn't an envelope, or that
+    /// answers a request it wasn't asked, is a problem.
+    async fn exchange(&mut self, requests: &[Envelope<Value>]) -> (Vec<Option<Envelope<Value>>>, Vec<String>) {
+        let mut replies = vec![None; requests.len()];
+        let mut problems = vec![];
+        for request in requests {
+            if !self.write(&serde_json::to_string(request).unwrap()).await {
+                problems.push("node stopped reading its input".to_owned());
+                return (replies, problems);
+            }
+        }
+        let deadline = Instant::now() + self.timeout;
+        while replies.iter().any(Option::is_none) {
+            let line = match tokio::time::timeout_at(deadline, self.lines.recv()).await {
+                Ok(Some(line)) => line,
+                Ok(None) => {
+                    problems.push("node exited".to_owned());
+                    break;
+                },
+                Err(_) => break,
+            };
+            let Ok(envelope) = serde_json::from_str::<Envelope<Value>>(&line) else {
+                problems.push(format!("printed something that isn't an envelope: {line}"));
+                continue;
+            };
+            let answers = requests.iter().position(|request| {
+                envelope.destination == request.source && envelope.body.in_reply_to.is_some() && envelope.body.in_reply_to == request.body.msg_id
+            });
+            match answers {
+                Some(index) if replies[index].is_none() => replies[index] = Some(envelope),
+                Some(_) => problems.push(format!("answered {:?} twice", envelope.body.in_reply_to)),
+                // Messages to other nodes or to Maelstrom's services are none of our business.
+                None if envelope.destination.starts_with('c') => {
+                    problems.push(format!("sent `{}` a reply to {:?}, which it never asked", envelope.destination, envelope.body.in_reply_to))
+                },
+                None => {},
+            }
+        }
+        (replies, problems)
+    }
+
+    /// Ask, and hold each reply to the protocol's obligations, noting what's wrong
+    /// under `step`. A reply is `None` if it never came.
+    async fn ask(&mut self, step: &str, requests: Vec<Envelope<Value>>, findings: &mut Vec<Finding>) -> Vec<Option<Envelope<Value>>> {
+        let (replies, mut problems) = self.exchange(&requests).await;
+        for (request, reply) in requests.iter().zip(&replies) {
+            match reply {
+                Some(reply) => problems.extend(conformance::check_reply(request, reply)),
+                None => problems.push(format!("no reply to {} from {} within {:?}", request.body.message["type"], request.source, self.timeout)),
+            }
+        }
+        findings.extend(problems.into_iter().map(|message| Finding { step: step.to_owned(), severity: Severity::Violation, message }));
+        replies
+    }
+}
+
+
+/// One step's outcome, as a line of the report.
+fn report(step: &str, findings: &[Finding]) {
+    let findings: Vec<&Finding> = findings.iter().filter(|finding| finding.step == step).collect();
+    if findings.is_empty() {
+        println!("ok    {step}");
+    }
+    for finding in findings {
+        let label = match finding.severity {
+            Severity::Warning => "warn",
+            Severity::Violation => "FAIL",
+        };
+        println!("{label}  {step}: {}", finding.message);
+    }
+}
+
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let opts = Opts::parse();
+    let mut child = match Command::new(&opts.command[0])
+        .args(&opts.command[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => {
+            eprintln!("failed to start `{}`: {err}", opts.command[0]);
+            return ExitCode::FAILURE;
+        },
+    };
+    let (lines_tx, lines) = mpsc::unbounded_channel();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+    tokio::spawn(async move {
+        while let Ok(Some(line)) = stdout.next_line().await {
+            if lines_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    let stdin = child.stdin.take().unwrap();
+    let mut node = Node { child, stdin, lines, timeout: Duration::from_millis(opts.timeout_ms) };
+
+    let mut findings = vec![];
+    let mut msg_id = 0;
+    let mut request = |client: &str, message: Value| {
+        msg_id += 1;
+        Envelope::new(client, NODE, Body { msg_id: Some(msg_id), in_reply_to: None, message })
+    };
+
+    let replies = node.ask("init", vec![request("c0", json!({"type": "init", "node_id": NODE, "node_ids": [NODE]}))], &mut findings).await;
+    report("init", &findings);
+    if replies[0].is_none() {
+        println!("giving up: the node never finished initializing");
+        return ExitCode::FAILURE;
+    }
+
+    if opts.workload.takes_topology() {
+        node.ask("topology", vec![request("c0", json!({"type": "topology", "topology": {NODE: []}}))], &mut findings).await;
+        report("topology", &findings);
+    }
+
+    let script = opts.workload.script();
+    let mut earlier: Vec<(Value, Value)> = vec![];
+    for (index, body) in script.iter().enumerate() {
+        let step = format!("{} #{}", body["type"].as_str().unwrap(), index + 1);
+        let replies = node.ask(&step, vec![request("c1", body.clone())], &mut findings).await;
+        if let Some(reply) = &replies[0] {
+            if let Some(problem) = opts.workload.check(body, &reply.body.message, &earlier) {
+                findings.push(Finding { step: step.clone(), severity: Severity::Violation, message: problem });
+            }
+            earlier.push((body.clone(), reply.body.message.clone()));
+        }
+        report(&step, &findings);
+    }
+
+    // msg_ids are only unique per client, so two clients can send the same one at once.
+    let same_msg_id: Vec<Envelope<Value>> = ["c1", "c2"].into_iter().map(|client| {
+        Envelope::new(client, NODE, Body { msg_id: Some(1000), in_reply_to: None, message: script[0].clone() })
+    }).collect();
+    node.ask("same msg_id from two clients", same_msg_id, &mut findings).await;
+    report("same msg_id from two clients", &findings);
+
+    let step = "unknown message type".to_owned();
+    let unknown = request("c1", json!({"type": "conformance_probe"}));
+    let (replies, problems) = node.exchange(std::slice::from_ref(&unknown)).await;
+    for problem in problems.into_iter().chain(conformance::check_unsupported(replies[0].as_ref())) {
+        findings.push(Finding { step: step.clone(), severity: Severity::Warning, message: problem });
+    }
+    report(&step, &findings);
+
+    for line in ["{\"src\": \"c1\", \"dest\"", "not json at all", "{\"src\": \"c1\", \"dest\": \"n1\", \"body\": {}}"] {
+        node.write(line).await;
+    }
+    node.ask("still answering after malformed input", vec![request("c1", script[0].clone())], &mut findings).await;
+    report("still answering after malformed input", &findings);
+
+    if let Ok(Some(status)) = node.child.try_wait() {
+        findings.push(Finding { step: "exit".to_owned(), severity: Severity::Violation, message: format!("node exited with {status}") });
+        report("exit", &findings);
+    }
+
+    let violations = findings.iter().filter(|finding| finding.severity == Severity::Violation).count();
+    let warnings = findings.len() - violations;
+    println!("{violations} violation(s), {warnings} warning(s)");
+    match violations {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    }
+}
@@ -0,0 +1,10 @@
+use solutions::{cli, conformance::Workload, workloads::{self, dynamo_kv}};
+
+fn main() {
+    let opts = cli::parse::<dynamo_kv::Opts>();
+    workloads::self_test(&opts.common, Workload::LwwKv, {
+        let opts = opts.clone();
+        move || dynamo_kv::server(opts)
+    });
+    workloads::run(opts.common.clone(), dynamo_kv::server(opts));
+}
@@ -1,3 +1,11 @@
+use solutions::{cli, conformance::Workload, workloads::{self, kafka}};
+
+// The kafka-style log with `--offsets lin-kv` unless told otherwise.
 fn main() {
-    println!("Hello, world!");
+    let opts = cli::parse_with_profile(|_: &kafka::Opts| Some(r#"offsets = "lin-kv""#));
+    workloads::self_test(&opts.common, Workload::Kafka, {
+        let opts = opts.clone();
+        move || kafka::server(opts)
+    });
+    workloads::run(opts.common.clone(), kafka::server(opts));
 }
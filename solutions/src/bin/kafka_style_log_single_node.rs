@@ -1,3 +1,10 @@
+use solutions::{cli, conformance::Workload, workloads::{self, kafka}};
+
 fn main() {
-    println!("Hello, world!");
+    let opts = cli::parse::<kafka::Opts>();
+    workloads::self_test(&opts.common, Workload::Kafka, {
+        let opts = opts.clone();
+        move || kafka::server(opts)
+    });
+    workloads::run(opts.common.clone(), kafka::server(opts));
 }
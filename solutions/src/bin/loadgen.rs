@@ -0,0 +1,191 @@
+use std::{collections::HashMap, process::{ExitCode, Stdio}, time::{Duration, Instant}};
+use clap::Parser;
+use rand::{rngs::StdRng, SeedableRng};
+use serde_json::{json, Value};
+use solutions::{loadgen::{Mix, Op, Summary}, message::{Body, Envelope}};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, process::{Child, ChildStdin, Command}, sync::mpsc};
+
+
+#[derive(Debug, Parser)]
+#[clap(author, version, about = "Run a few copies of a node and throw client requests at them, like Maelstrom would, then summarize how they did.")]
+pub struct Opts {
+    #[clap(long, default_value = "1", help = "How many copies of the node to run (n1, n2, ...).", env = "NODES")]
+    pub nodes: usize,
+    #[clap(long, default_value = "100", help = "Requests per second, across all nodes.", env = "RATE")]
+    pub rate: f64,
+    #[clap(long, default_value = "10", help = "How long to keep sending requests, in seconds.", env = "DURATION")]
+    pub duration: f64,
+    #[clap(long, default_value = "1000", help = "How long to wait for a reply before counting it as timed out, in milliseconds.", env = "TIMEOUT_MS")]
+    pub timeout_ms: u64,
+    #[clap(long, default_value = "echo", help = "Which requests to send and how often, e.g. `broadcast=3,read=1`.", env = "MIX")]
+    pub mix: Mix,
+    #[clap(long, help = "Seed for picking ops, nodes and values. Random if not given.", env = "SEED")]
+    pub seed: Option<u64>,
+    #[clap(required = true, trailing_var_arg = true, help = "The node to run, and its arguments.")]
+    pub command: Vec<String>,
+}
+
+
+const CLIENT: &str = "c1";
+
+
+struct Node {
+    id: String,
+    stdin: ChildStdin,
+    _child: Child,
+}
+
+async fn send(node: &mut Node, envelope: &Envelope<Value>) -> bool {
+    let line = format!("{}\n", serde_json::to_string(envelope).unwrap());
+    node.stdin.write_all(line.as_bytes()).await.is_ok()
+}
+
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let opts = Opts::parse();
+    let seed = opts.seed.unwrap_or_else(rand::random);
+    eprintln!("seed: {seed}");
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    // Every line any node prints comes through here, tagged with who printed it.
+    let (lines_tx, mut lines) = mpsc::unbounded_channel::<(usize, String)>();
+    let mut nodes = vec![];
+    for index in 0..opts.nodes {
+        let mut child = match Command::new(&opts.command[0])
+            .args(&opts.command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(err) => {
+                eprintln!("failed to start `{}`: {err}", opts.command[0]);
+                return ExitCode::FAILURE;
+            },
+        };
+        let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+        let lines_tx = lines_tx.clone();
+        tokio::spawn(async move {
+            while let Ok(Some(line)) = stdout.next_line().await {
+                if lines_tx.send((index, line)).is_err() {
+                    break;
+                }
+            }
+        });
+        nodes.push(Node { id: format!("n{}", index + 1), stdin: child.stdin.take().unwrap(), _child: child });
+    }
+    let node_ids: Vec<String> = nodes.iter().map(|node| node.id.clone()).collect();
+    let timeout = Duration::from_millis(opts.timeout_ms);
+
+    let mut msg_id = 0;
+    let mut next_envelope = |destination: &str, message: Value| {
+        msg_id += 1;
+        Envelope::new(CLIENT, destination, Body { msg_id: Some(msg_id), in_reply_to: None, message })
+    };
+
+    // Set the nodes up the way Maelstrom does before the load starts.
+    let mut setup: Vec<Envelope<Value>> =
+        node_ids
+        .iter()
+        .map(|id| next_envelope(id, json!({"type": "init", "node_id": id, "node_ids": node_ids})))
+        .collect();
+    if opts.mix.contains(Op::Broadcast) {
+        let topology: HashMap<&String, Vec<&String>> =
+            node_ids.iter().map(|id| (id, node_ids.iter().filter(|other| *other != id).collect())).collect();
+        setup.extend(node_ids.iter().map(|id| next_envelope(id, json!({"type": "topology", "topology": topology}))));
+    }
+    let mut awaiting_setup: HashMap<usize, String> = HashMap::new();
+    for envelope in setup {
+        let node = nodes.iter_mut().find(|node| *node.id == *envelope.destination).unwrap();
+        send(node, &envelope).await;
+        awaiting_setup.insert(envelope.msg_id().unwrap(), envelope.destination.to_string());
+    }
+    let setup_deadline = tokio::time::sleep(timeout.max(Duration::from_secs(5)));
+    tokio::pin!(setup_deadline);
+    while !awaiting_setup.is_empty() {
+        tokio::select! {
+            Some((_, line)) = lines.recv() => {
+                if let Ok(envelope) = serde_json::from_str::<Envelope<Value>>(&line) {
+                    if let Some(in_reply_to) = envelope.body.in_reply_to.filter(|_| *envelope.destination == *CLIENT) {
+                        awaiting_setup.remove(&in_reply_to);
+                    }
+                }
+            },
+            _ = &mut setup_deadline => {
+                let mut nodes: Vec<_> = awaiting_setup.into_values().collect();
+                nodes.sort();
+                nodes.dedup();
+                eprintln!("no reply to init/topology from {}", nodes.join(", "));
+                return ExitCode::FAILURE;
+            },
+        }
+    }
+
+    let mut summary = Summary::default();
+    let mut outstanding: HashMap<usize, (Op, Instant)> = HashMap::new();
+    let mut ticks = tokio::time::interval(Duration::from_secs_f64(1. / opts.rate.max(0.001)));
+    let mut sweeps = tokio::time::interval(Duration::from_millis(10));
+    let started = Instant::now();
+    let load_duration = Duration::from_secs_f64(opts.duration.max(0.));
+    let mut seq = 0;
+    loop {
+        let sending = started.elapsed() < load_duration;
+        if !sending && outstanding.is_empty() {
+            break;
+        }
+        tokio::select! {
+            _ = ticks.tick(), if sending => {
+                let op = opts.mix.pick(&mut rng);
+                seq += 1;
+                let node = rand::Rng::gen_range(&mut rng, 0..nodes.len());
+                let envelope = next_envelope(&nodes[node].id, op.body(&mut rng, seq));
+                summary.ops.entry(op).or_default().sent += 1;
+                outstanding.insert(envelope.msg_id().unwrap(), (op, Instant::now()));
+                if !send(&mut nodes[node], &envelope).await {
+                    eprintln!("{} stopped reading its input", nodes[node].id);
+                    break;
+                }
+            },
+            _ = sweeps.tick() => {
+                outstanding.retain(|_, (op, sent)| {
+                    let timed_out = sent.elapsed() >= timeout;
+                    if timed_out {
+                        summary.ops.entry(*op).or_default().timeouts += 1;
+                    }
+                    !timed_out
+                });
+            },
+            Some((from, line)) = lines.recv() => {
+                let Ok(envelope) = serde_json::from_str::<Envelope<Value>>(&line) else {
+                    continue;
+                };
+                if let Some(node) = nodes.iter_mut().find(|node| *node.id == *envelope.destination) {
+                    summary.server_messages += 1;
+                    send(node, &envelope).await;
+                } else if *envelope.destination == *CLIENT {
+                    let Some((op, sent)) = envelope.body.in_reply_to.and_then(|id| outstanding.remove(&id)) else {
+                        continue;
+                    };
+                    let stats = summary.ops.entry(op).or_default();
+                    stats.latencies.push(sent.elapsed());
+                    match envelope.body.message.get("type").and_then(Value::as_str) {
+                        Some("error") => stats.errors += 1,
+                        _ => stats.ok += 1,
+                    }
+                } else {
+                    let count = summary.undeliverable.entry(envelope.destination.to_string()).or_default();
+                    if *count == 0 {
+                        eprintln!("{} sent a message to `{}`, which isn't simulated", node_ids[from], envelope.destination);
+                    }
+                    *count += 1;
+                }
+            },
+        }
+    }
+    summary.elapsed = started.elapsed();
+    print!("{summary}");
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,33 @@
+use std::{path::PathBuf, process::ExitCode};
+use clap::Parser;
+use solutions::scaffold;
+
+
+#[derive(Debug, Parser)]
+#[clap(author, version, about = "Start a new workload: a node under src/workloads, a binary for it, and a test on the in-memory network.")]
+pub struct Opts {
+    #[clap(help = "The workload's name, in snake_case (e.g. `lin_kv`).")]
+    pub name: String,
+    #[clap(long, default_value = ".", help = "The crate to add it to.")]
+    pub root: PathBuf,
+}
+
+
+fn main() -> ExitCode {
+    let opts = Opts::parse();
+    match scaffold::generate(&opts.root, &opts.name) {
+        Ok(files) => {
+            for file in files {
+                println!("created {}", file.display());
+            }
+            println!("declared it in src/workloads.rs");
+            println!();
+            println!("Next: add a `{}` subcommand to src/main.rs, then `cargo test --test {}`.", opts.name.replace('_', "-"), opts.name);
+            ExitCode::SUCCESS
+        },
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        },
+    }
+}
@@ -0,0 +1,131 @@
+use std::{fs::File, io::Write, path::PathBuf, process::{ExitCode, Stdio}, sync::{Arc, Mutex}};
+use clap::Parser;
+use serde_json::{json, Value};
+use solutions::{message::RawEnvelope, proxy::{Direction, Rule, Rules, Verdict}};
+use tokio::{io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader}, process::Command, sync::mpsc::{self, UnboundedSender}, task::JoinHandle, time::Instant};
+
+
+#[derive(Debug, Parser)]
+#[clap(author, version, about = "Sit between Maelstrom and a node, passing messages both ways, and record, delay or drop them by rule.")]
+pub struct Opts {
+    #[clap(long, help = "Append every message that passes through (with when, which way, and what happened to it) to this file as JSON lines.", env = "PROXY_RECORD")]
+    pub record: Option<PathBuf>,
+    #[clap(long, help = "What to do to which messages, e.g. `drop type=gossip src=n1 p=0.2` or `delay 200ms dir=out after=10 count=5` (can be repeated; the first that fires wins).")]
+    pub rule: Vec<Rule>,
+    #[clap(long, help = "Seed for the rules' coin flips. Random (and logged) if not given.", env = "PROXY_SEED")]
+    pub seed: Option<u64>,
+    #[clap(required = true, trailing_var_arg = true, help = "The node to run, and its arguments.")]
+    pub command: Vec<String>,
+}
+
+
+/// Read lines from `from`, run them past the rules, and pass on what survives to `to`
+/// (now, or after a delay). Lines that aren't envelopes go straight through.
+async fn forward(
+    from: impl AsyncRead + Unpin,
+    to: UnboundedSender<String>,
+    direction: Direction,
+    rules: Arc<Mutex<Rules>>,
+    recorder: Option<Arc<Mutex<File>>>,
+    started: Instant,
+) {
+    let mut lines = BufReader::new(from).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let Ok(envelope) = serde_json::from_str::<RawEnvelope>(&line) else {
+            to.send(line).ok();
+            continue;
+        };
+        let verdict = rules.lock().unwrap().decide(direction, &envelope);
+        if let Some(recorder) = &recorder {
+            let mut record = json!({
+                "time": started.elapsed().as_secs_f64(),
+                "direction": direction.as_str(),
+                "src": envelope.source,
+                "dest": envelope.destination,
+                "body": envelope.body,
+            });
+            match verdict {
+                Verdict::Forward => {},
+                Verdict::Drop => record["dropped"] = Value::from(true),
+                Verdict::Delay(delay) => record["delay_ms"] = Value::from(delay.as_secs_f64() * 1000.),
+            }
+            writeln!(recorder.lock().unwrap(), "{record}").ok();
+        }
+        match verdict {
+            Verdict::Forward => { to.send(line).ok(); },
+            Verdict::Drop => {},
+            Verdict::Delay(delay) => {
+                let to = to.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    to.send(line).ok();
+                });
+            },
+        }
+    }
+}
+
+/// Write whatever lines come in to `to`, flushing after each so nothing sits in a buffer.
+/// The task finishes once every sender is gone and the lines they sent are written.
+fn writer(mut to: impl AsyncWrite + Unpin + Send + 'static) -> (UnboundedSender<String>, JoinHandle<()>) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    let handle = tokio::spawn(async move {
+        while let Some(line) = rx.recv().await {
+            if to.write_all(format!("{line}\n").as_bytes()).await.is_err() || to.flush().await.is_err() {
+                break;
+            }
+        }
+    });
+    (tx, handle)
+}
+
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let opts = Opts::parse();
+    let seed = opts.seed.unwrap_or_else(rand::random);
+    eprintln!("proxy seed: {seed}");
+    let rules = Arc::new(Mutex::new(Rules::new(opts.rule, seed)));
+
+    let recorder = match &opts.record {
+        Some(path) => match File::options().create(true).append(true).open(path) {
+            Ok(file) => Some(Arc::new(Mutex::new(file))),
+            Err(err) => {
+                eprintln!("failed to open {}: {err}", path.display());
+                return ExitCode::FAILURE;
+            },
+        },
+        None => None,
+    };
+
+    let mut child = match Command::new(&opts.command[0])
+        .args(&opts.command[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => {
+            eprintln!("failed to start `{}`: {err}", opts.command[0]);
+            return ExitCode::FAILURE;
+        },
+    };
+    let started = Instant::now();
+    let (to_node, _) = writer(child.stdin.take().unwrap());
+    let (to_maelstrom, written) = writer(tokio::io::stdout());
+
+    // Once Maelstrom closes our stdin (and any delayed messages have gone through), the
+    // node's stdin closes too, and it's up to the node to wind down.
+    tokio::spawn(forward(tokio::io::stdin(), to_node, Direction::In, rules.clone(), recorder.clone(), started));
+    // The node's done talking once its stdout closes; let its last (and delayed) messages
+    // out before leaving.
+    forward(child.stdout.take().unwrap(), to_maelstrom, Direction::Out, rules, recorder, started).await;
+    written.await.ok();
+
+    match child.wait().await {
+        Ok(status) if status.success() => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    }
+}
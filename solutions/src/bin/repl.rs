@@ -0,0 +1,133 @@
+use std::{collections::HashSet, io::IsTerminal, process::{ExitCode, Stdio}, time::Duration};
+use clap::Parser;
+use serde_json::Value;
+use solutions::{message::Envelope, repl::{self, Shorthand}};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, process::Command, sync::mpsc};
+
+
+#[derive(Debug, Parser)]
+#[clap(author, version, about = "Spawn a node and talk to it: `init n1 [n1,n2]`, `broadcast 42`, `read`, ... (`help` lists them).")]
+pub struct Opts {
+    #[clap(long, default_value = "c1", help = "The client id messages are sent from.")]
+    pub src: String,
+    #[clap(long, default_value = "1000", help = "How long to wait for outstanding replies once input runs out, in milliseconds.")]
+    pub wait_ms: u64,
+    #[clap(required = true, trailing_var_arg = true, help = "The node to run, and its arguments.")]
+    pub command: Vec<String>,
+}
+
+
+/// A message from the node: who it's from and to, then the body, pretty-printed.
+fn show(envelope: &Envelope<Value>, client: &str) {
+    let note = if *envelope.destination == *client { "" } else { "  (not delivered)" };
+    println!("<- {} -> {}{note}", envelope.source, envelope.destination);
+    let body = serde_json::to_string_pretty(&envelope.body).unwrap_or_default();
+    for line in body.lines() {
+        println!("   {line}");
+    }
+}
+
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let opts = Opts::parse();
+    let mut child = match Command::new(&opts.command[0])
+        .args(&opts.command[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => {
+            eprintln!("failed to start `{}`: {err}", opts.command[0]);
+            return ExitCode::FAILURE;
+        },
+    };
+    let mut node_stdin = child.stdin.take().unwrap();
+    let mut node_stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+
+    // Lines typed in come through here, so they can be raced against the node's output.
+    let (lines_tx, mut lines) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = stdin.next_line().await {
+            if lines_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let interactive = std::io::stdin().is_terminal();
+    let prompt = || if interactive {
+        print!("> ");
+        use std::io::Write;
+        std::io::stdout().flush().ok();
+    };
+    let mut shorthand = Shorthand::new(&opts.src);
+    let mut outstanding = HashSet::new();
+    let mut input_done = false;
+    prompt();
+    loop {
+        let deadline = tokio::time::sleep(Duration::from_millis(opts.wait_ms));
+        tokio::select! {
+            line = lines.recv(), if !input_done => {
+                let Some(line) = line else {
+                    input_done = true;
+                    if outstanding.is_empty() {
+                        break;
+                    }
+                    continue;
+                };
+                match line.trim() {
+                    "help" => print!("{}", repl::usage()),
+                    "quit" | "exit" => break,
+                    line => match shorthand.parse(line) {
+                        Ok(None) => {},
+                        Ok(Some(envelope)) => {
+                            let json = serde_json::to_string(&envelope).unwrap();
+                            println!("-> {json}");
+                            outstanding.extend(envelope.msg_id());
+                            if node_stdin.write_all(format!("{json}\n").as_bytes()).await.is_err() {
+                                eprintln!("the node isn't reading its input anymore");
+                                break;
+                            }
+                        },
+                        Err(err) => eprintln!("{err}"),
+                    },
+                }
+                prompt();
+            },
+            line = node_stdout.next_line() => match line {
+                Ok(Some(line)) => {
+                    match serde_json::from_str::<Envelope<Value>>(&line) {
+                        Ok(envelope) => {
+                            if *envelope.destination == *opts.src {
+                                if let Some(in_reply_to) = envelope.body.in_reply_to {
+                                    outstanding.remove(&in_reply_to);
+                                }
+                            }
+                            show(&envelope, &opts.src);
+                        },
+                        Err(_) => println!("<- (not an envelope) {line}"),
+                    }
+                    if input_done && outstanding.is_empty() {
+                        break;
+                    }
+                    prompt();
+                },
+                _ => {
+                    eprintln!("the node exited");
+                    break;
+                },
+            },
+            _ = deadline, if input_done => {
+                eprintln!("gave up waiting on replies to {} message(s)", outstanding.len());
+                break;
+            },
+        }
+    }
+    child.kill().await.ok();
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,79 @@
+use std::{path::PathBuf, process::ExitCode};
+use clap::Parser;
+use solutions::{checker::Percentiles, results::{StoreRun, Target, Validity}};
+
+
+#[derive(Debug, Parser)]
+#[clap(author, version, about = "Summarize the outcome of a Maelstrom test from its store directory.")]
+pub struct Opts {
+    #[clap(default_value = "store/latest", help = "The test's directory in Maelstrom's store.")]
+    pub dir: PathBuf,
+    #[clap(long, value_parser = parse_target, help = "Also fail unless the run meets this challenge's efficiency targets (3d or 3e).", env = "TARGET")]
+    pub target: Option<Target>,
+}
+
+
+fn parse_target(name: &str) -> Result<Target, String> {
+    Target::named(name).ok_or_else(|| format!("no targets for `{name}`, try one of 3d, 3e"))
+}
+
+
+fn print_percentiles(label: &str, percentiles: Percentiles) {
+    println!(
+        "{label}: p50 {:?}, p95 {:?}, p99 {:?}, max {:?}",
+        percentiles.p50, percentiles.p95, percentiles.p99, percentiles.max
+    );
+}
+
+
+fn main() -> ExitCode {
+    let opts = Opts::parse();
+    let run = match StoreRun::load(&opts.dir) {
+        Ok(run) => run,
+        Err(err) => {
+            eprintln!("failed to load {}: {err}", opts.dir.display());
+            return ExitCode::FAILURE;
+        },
+    };
+    let results = &run.results;
+
+    println!("valid: {:?} (workload: {:?})", results.valid, results.workload_valid);
+    if let Some(stats) = results.stats {
+        println!("ops: {} ({} ok, {} failed, {} indeterminate)", stats.count, stats.ok_count, stats.fail_count, stats.info_count);
+    }
+    if let Some(net) = results.net {
+        println!("server msgs: {} ({} per op)", net.servers.msg_count, net.servers.msgs_per_op.map_or("?".to_owned(), |msgs| format!("{msgs:.2}")));
+        println!("all msgs: {} ({} per op)", net.all.msg_count, net.all.msgs_per_op.map_or("?".to_owned(), |msgs| format!("{msgs:.2}")));
+    }
+    if let (Some(median), Some(max)) = (results.stable_latency(0.5), results.stable_latency(1.)) {
+        println!("stable latency: median {median:?}, max {max:?}");
+    }
+    if let Some(percentiles) = run.latencies.as_ref().and_then(|latencies| latencies.percentiles(None)) {
+        print_percentiles("op latency", percentiles);
+    }
+    if let Some(messages) = &run.messages {
+        println!("messages.log: {} messages, {} between servers, {} with clients", messages.total, messages.between_servers, messages.with_clients);
+        for (kind, count) in &messages.by_type {
+            println!("  {kind}: {count}");
+        }
+    }
+
+    if let Some(target) = opts.target {
+        let analysis = run.analyze(target);
+        match analysis.passed() {
+            true => println!("met the {} targets", target.name),
+            false => {
+                println!("missed the {} targets:", target.name);
+                for miss in &analysis.misses {
+                    println!("  {miss}");
+                }
+                return ExitCode::FAILURE;
+            },
+        }
+    }
+
+    match results.valid {
+        Validity::Valid => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    }
+}
@@ -0,0 +1,76 @@
+use std::{fs, io::Read, path::PathBuf, process::ExitCode, time::Duration};
+use clap::{Parser, ValueEnum};
+use solutions::{diagram::{self, Filter, Format}, session};
+
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum DiagramFormat {
+    Mermaid,
+    Plantuml,
+}
+
+
+#[derive(Debug, Parser)]
+#[clap(author, version, about = "Draw a sequence diagram of a recorded session (NDJSON envelopes, or Maelstrom's messages.log).")]
+pub struct Opts {
+    #[clap(help = "The recording to draw. Reads stdin if not given.")]
+    pub input: Option<PathBuf>,
+    #[clap(long, value_enum, default_value = "mermaid", help = "What to draw it in.")]
+    pub format: DiagramFormat,
+    #[clap(long, help = "Only messages to or from this node (can be repeated).")]
+    pub node: Vec<String>,
+    #[clap(long, value_parser = parse_chain, help = "Only the request/reply chain around this msg_id, as `<msg_id>` or `<sender>:<msg_id>`.")]
+    pub chain: Option<(Option<String>, usize)>,
+    #[clap(long, help = "Only messages recorded at or after this many seconds into the session.")]
+    pub since: Option<f64>,
+    #[clap(long, help = "Only messages recorded at or before this many seconds into the session.")]
+    pub until: Option<f64>,
+}
+
+
+fn parse_chain(chain: &str) -> Result<(Option<String>, usize), String> {
+    let (node, msg_id) = match chain.rsplit_once(':') {
+        Some((node, msg_id)) => (Some(node.to_owned()), msg_id),
+        None => (None, chain),
+    };
+    let msg_id = msg_id.parse().map_err(|err| format!("bad msg_id `{msg_id}`: {err}"))?;
+    Ok((node, msg_id))
+}
+
+
+fn main() -> ExitCode {
+    let opts = Opts::parse();
+    let input = match &opts.input {
+        Some(path) => fs::read_to_string(path),
+        None => {
+            let mut input = String::new();
+            std::io::stdin().read_to_string(&mut input).map(|_| input)
+        },
+    };
+    let input = match input {
+        Ok(input) => input,
+        Err(err) => {
+            eprintln!("failed to read the recording: {err}");
+            return ExitCode::FAILURE;
+        },
+    };
+
+    let session = session::parse(&input);
+    let filter = Filter {
+        nodes: opts.node,
+        chain: opts.chain,
+        since: opts.since.map(Duration::from_secs_f64),
+        until: opts.until.map(Duration::from_secs_f64),
+    };
+    let messages = filter.apply(&session);
+    if messages.is_empty() {
+        eprintln!("no messages left to draw (out of {} recorded)", session.len());
+        return ExitCode::FAILURE;
+    }
+    let format = match opts.format {
+        DiagramFormat::Mermaid => Format::Mermaid,
+        DiagramFormat::Plantuml => Format::PlantUml,
+    };
+    print!("{}", diagram::render(&messages, format));
+    ExitCode::SUCCESS
+}
@@ -1,3 +1,11 @@
+use solutions::{cli, conformance::Workload, workloads::{self, transactions}};
+
+// The transactions node with `--consistency read-uncommitted` unless told otherwise.
 fn main() {
-    println!("Hello, world!");
+    let opts = cli::parse_with_profile(|_: &transactions::Opts| Some(r#"consistency = "read-uncommitted""#));
+    workloads::self_test(&opts.common, Workload::Txn, {
+        let opts = opts.clone();
+        move || transactions::server(opts)
+    });
+    workloads::run(opts.common.clone(), transactions::server(opts));
 }
@@ -1,3 +1,10 @@
+use solutions::{cli, conformance::Workload, workloads::{self, transactions}};
+
 fn main() {
-    println!("Hello, world!");
+    let opts = cli::parse::<transactions::Opts>();
+    workloads::self_test(&opts.common, Workload::Txn, {
+        let opts = opts.clone();
+        move || transactions::server(opts)
+    });
+    workloads::run(opts.common.clone(), transactions::server(opts));
 }
@@ -0,0 +1,90 @@
+//! A node that misbehaves on purpose.
+//!
+//! Wrapping a node's writer with [`layer`] drops, duplicates and delays what it sends,
+//! so the conformance checker, the load generator and the runner have something
+//! known-bad to prove themselves against.
+
+use std::time::Duration;
+use rand::Rng;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use crate::{message::Envelope, metrics, task};
+
+
+/// How badly to behave. All zeroes (the default) changes nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Chaos {
+    /// Hold every message back this long...
+    pub delay: Duration,
+    /// ...plus up to this much more, picked per message, so they can overtake each other.
+    pub jitter: Duration,
+    /// Chance of never sending a message at all.
+    pub drop_rate: f64,
+    /// Chance of sending a message twice.
+    pub duplicate_rate: f64,
+}
+
+impl Chaos {
+    pub fn is_calm(&self) -> bool {
+        self.delay.is_zero() && self.jitter.is_zero() && self.drop_rate <= 0. && self.duplicate_rate <= 0.
+    }
+
+    fn wait(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.delay;
+        }
+        self.delay + rand::thread_rng().gen_range(Duration::ZERO..=self.jitter)
+    }
+}
+
+
+/// A writer that passes what it's given on to `writer`, misbehaving as `chaos` says.
+pub fn layer<M>(chaos: Chaos, writer: UnboundedSender<Envelope<M>>) -> UnboundedSender<Envelope<M>>
+where
+    M: Clone + Send + 'static,
+{
+    if chaos.is_calm() {
+        return writer;
+    }
+    let (sender, mut receiver) = unbounded_channel::<Envelope<M>>();
+    task::spawn("chaos", async move {
+        while let Some(envelope) = receiver.recv().await {
+            let (dropped, duplicated) = {
+                let mut rng = rand::thread_rng();
+                (rng.gen_bool(chaos.drop_rate), rng.gen_bool(chaos.duplicate_rate))
+            };
+            if dropped {
+                metrics::global().increment("chaos.dropped");
+                continue;
+            }
+            let copies = if duplicated {
+                metrics::global().increment("chaos.duplicated");
+                vec![envelope.clone(), envelope]
+            } else {
+                vec![envelope]
+            };
+            for envelope in copies {
+                let wait = chaos.wait();
+                if wait.is_zero() {
+                    let _ = writer.send(envelope);
+                    continue;
+                }
+                let writer = writer.clone();
+                task::spawn("chaos.delay", async move {
+                    tokio::time::sleep(wait).await;
+                    let _ = writer.send(envelope);
+                });
+            }
+        }
+    });
+    sender
+}
+
+
+/// For rate flags: a probability between 0 and 1.
+pub fn parse_rate(rate: &str) -> Result<f64, String> {
+    let value: f64 = rate.parse().map_err(|err| format!("bad rate `{rate}`: {err}"))?;
+    if !(0. ..=1.).contains(&value) {
+        return Err(format!("rate `{rate}` should be between 0 and 1"));
+    }
+    Ok(value)
+}
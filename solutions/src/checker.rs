@@ -0,0 +1,345 @@
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, time::Duration};
+use crate::history::{History, OpType};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Percentiles {
+    pub fn from_samples(mut samples: Vec<Duration>) -> Option<Self> {
+        samples.sort();
+        let at = |quantile: f64| samples[((samples.len() - 1) as f64 * quantile).round() as usize];
+        (!samples.is_empty()).then(|| Self { p50: at(0.5), p95: at(0.95), p99: at(0.99), max: at(1.) })
+    }
+}
+
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BroadcastReport {
+    /// Distinct values clients tried to broadcast.
+    pub attempted: usize,
+    /// Distinct values whose broadcast was acknowledged.
+    pub acknowledged: usize,
+    /// Acknowledged values missing from some node's last read.
+    pub lost: BTreeSet<u64>,
+    /// Values some node read that nobody ever tried to broadcast.
+    pub unexpected: BTreeSet<u64>,
+    /// For every value that made it everywhere: how long after its broadcast was
+    /// invoked until every node's reads included it for good.
+    pub stable_latencies: Option<Percentiles>,
+}
+
+impl BroadcastReport {
+    pub fn is_valid(&self) -> bool {
+        self.lost.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+
+/// Check a `broadcast` workload history (ops `broadcast` with a number, and `read`
+/// returning the list of numbers a node has seen) the way Maelstrom's broadcast
+/// checker does: nothing acknowledged is missing from any node's final read, and
+/// nothing read was made up.
+pub fn broadcast(history: &History) -> BroadcastReport {
+    let mut invoked_at = HashMap::new();
+    let mut acknowledged = BTreeSet::new();
+    // Each node's successful reads, in the order they completed.
+    let mut reads: BTreeMap<&str, Vec<(Duration, BTreeSet<u64>)>> = BTreeMap::new();
+
+    for (invoke, completion) in history.pairs() {
+        match invoke.f.as_str() {
+            "broadcast" => {
+                let Some(value) = invoke.value.as_u64() else {
+                    continue;
+                };
+                invoked_at.entry(value).or_insert(invoke.time);
+                if completion.is_some_and(|op| op.kind == OpType::Ok) {
+                    acknowledged.insert(value);
+                }
+            },
+            "read" => if let Some(op) = completion.filter(|op| op.kind == OpType::Ok) {
+                let values = serde_json::from_value(op.value.clone()).unwrap_or_default();
+                reads.entry(&invoke.node).or_default().push((op.time, values));
+            },
+            _ => {},
+        }
+    }
+    for node_reads in reads.values_mut() {
+        node_reads.sort_by_key(|(time, _)| *time);
+    }
+
+    let mut report = BroadcastReport {
+        attempted: invoked_at.len(),
+        acknowledged: acknowledged.len(),
+        ..Default::default()
+    };
+    for (_, values) in reads.values().flatten() {
+        report.unexpected.extend(values.iter().filter(|value| !invoked_at.contains_key(value)));
+    }
+
+    let mut latencies = vec![];
+    for (&value, &invoked) in &invoked_at {
+        // The earliest time from which every node kept reading `value`.
+        let mut stable_at = Some(invoked);
+        for node_reads in reads.values() {
+            let since = node_reads
+                .iter()
+                .rev()
+                .take_while(|(_, values)| values.contains(&value))
+                .last()
+                .map(|(time, _)| *time);
+            stable_at = stable_at.zip(since).map(|(stable_at, since)| stable_at.max(since));
+        }
+        match stable_at {
+            Some(stable_at) if !reads.is_empty() => latencies.push(stable_at.saturating_sub(invoked)),
+            None if acknowledged.contains(&value) => { report.lost.insert(value); },
+            _ => {},
+        }
+    }
+    report.stable_latencies = Percentiles::from_samples(latencies);
+    report
+}
+
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CounterReport {
+    /// The sum of every acknowledged `add`.
+    pub acknowledged: i64,
+    /// The range the counter may end up in, since indeterminate adds may or may not
+    /// have taken effect. Without any, this is just `acknowledged` on both ends.
+    pub lower: i64,
+    pub upper: i64,
+    /// The last value each node read.
+    pub final_reads: BTreeMap<String, i64>,
+    /// Nodes whose final read is outside of `lower..=upper`.
+    pub wrong_final_reads: BTreeMap<String, i64>,
+    /// (node, value) for every read, at any point, above what all the adds attempted so
+    /// far could add up to (or below, for negative deltas).
+    pub impossible_reads: Vec<(String, i64)>,
+}
+
+impl CounterReport {
+    pub fn is_valid(&self) -> bool {
+        self.wrong_final_reads.is_empty() && self.impossible_reads.is_empty()
+    }
+}
+
+
+/// Check a `g-counter` workload history (ops `add` with a delta, and `read` returning
+/// the counter's value): once the adds have settled, every node should read the sum
+/// of the acknowledged deltas, give or take the ones whose outcome is unknown; and no
+/// read should ever exceed what's been attempted. Failed adds don't count either way.
+pub fn counter(history: &History) -> CounterReport {
+    let mut report = CounterReport::default();
+    // The widest range any read could fall in, given every add invoked before it completed.
+    let (mut lowest, mut highest) = (0, 0);
+    // Reads can't be judged against adds until we know how they ended, so sort it all out by time.
+    let mut events = vec![];
+
+    for (invoke, completion) in history.pairs() {
+        match invoke.f.as_str() {
+            "add" => {
+                let Some(delta) = invoke.value.as_i64() else {
+                    continue;
+                };
+                match completion.map(|op| op.kind) {
+                    Some(OpType::Fail) => continue,
+                    Some(OpType::Ok) => report.acknowledged += delta,
+                    _ => match delta < 0 {
+                        true => report.lower += delta,
+                        false => report.upper += delta,
+                    },
+                }
+                events.push((invoke.time, None, delta));
+            },
+            "read" => if let Some(op) = completion.filter(|op| op.kind == OpType::Ok) {
+                if let Some(value) = op.value.as_i64() {
+                    events.push((op.time, Some(invoke.node.as_str()), value));
+                }
+            },
+            _ => {},
+        }
+    }
+    report.lower += report.acknowledged;
+    report.upper += report.acknowledged;
+
+    // Adds sort before reads at the same instant, so a read can always see an add invoked with it.
+    events.sort_by_key(|(time, read, _)| (*time, read.is_some()));
+    for (_, read, value) in events {
+        match read {
+            None if value < 0 => lowest += value,
+            None => highest += value,
+            Some(node) => {
+                if !(lowest..=highest).contains(&value) {
+                    report.impossible_reads.push((node.to_owned(), value));
+                }
+                report.final_reads.insert(node.to_owned(), value);
+            },
+        }
+    }
+    report.wrong_final_reads = report
+        .final_reads
+        .iter()
+        .filter(|(_, value)| !(report.lower..=report.upper).contains(*value))
+        .map(|(node, &value)| (node.clone(), value))
+        .collect();
+    report
+}
+
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KafkaReport {
+    /// How many sends were acknowledged, and polls answered.
+    pub acknowledged: usize,
+    pub polls: usize,
+    /// (key, offset) acknowledged for two different messages.
+    pub duplicate_offsets: Vec<(String, u64)>,
+    /// (key, earlier, later): a send acknowledged with offset `earlier` before another
+    /// was invoked, which was then acknowledged with `later`, no higher.
+    pub nonmonotonic_sends: Vec<(String, u64, u64)>,
+    /// (key, offset): a poll handed back `offset` out of order, or with a message other
+    /// than the one its send was acknowledged with.
+    pub nonmonotonic_polls: Vec<(String, u64)>,
+    /// (key, offset): a send acknowledged before a poll was invoked that the poll
+    /// skipped over, handing back offsets on either side of it.
+    pub skipped: Vec<(String, u64)>,
+    /// (process, key, at least, listed): a process was told it had committed less than
+    /// it already had (or than it was told before), or nothing at all.
+    pub regressed_commits: Vec<(usize, String, u64, Option<u64>)>,
+}
+
+impl KafkaReport {
+    pub fn is_valid(&self) -> bool {
+        self.duplicate_offsets.is_empty()
+            && self.nonmonotonic_sends.is_empty()
+            && self.nonmonotonic_polls.is_empty()
+            && self.skipped.is_empty()
+            && self.regressed_commits.is_empty()
+    }
+}
+
+
+/// Check a `kafka` workload history, something like Maelstrom's kafka checker does:
+/// ops `send` (with `[key, msg]`, completing with the offset it got), `poll` (completing
+/// with `{key: [[offset, msg], ...]}`), `commit_offsets` (with `{key: offset}`) and
+/// `list_committed_offsets` (completing with `{key: offset}`). Every key's offsets should
+/// go up with every send, polls should hand them back in order without skipping any
+/// that were acknowledged, and committed offsets should never go back. Gaps between
+/// offsets are fine as long as no acknowledged send falls in them.
+pub fn kafka(history: &History) -> KafkaReport {
+    let mut report = KafkaReport::default();
+    // Acknowledged sends by key, each as (offset, msg, invoked, completed).
+    let mut sends: BTreeMap<String, Vec<(u64, u64, Duration, Duration)>> = BTreeMap::new();
+    let mut polls = vec![];
+    // Each process's commits and listings, as (time, the keys a listing asked about if
+    // it's being checked, offsets), sorted out by time below.
+    type Offsets = (Duration, Option<Vec<String>>, BTreeMap<String, u64>);
+    let mut offsets: BTreeMap<usize, Vec<Offsets>> = BTreeMap::new();
+
+    for (invoke, completion) in history.pairs() {
+        let Some(op) = completion.filter(|op| op.kind == OpType::Ok) else {
+            continue;
+        };
+        match invoke.f.as_str() {
+            "send" => {
+                let (Ok((key, msg)), Some(offset)) = (serde_json::from_value::<(String, u64)>(invoke.value.clone()), op.value.as_u64()) else {
+                    continue;
+                };
+                sends.entry(key).or_default().push((offset, msg, invoke.time, op.time));
+            },
+            "poll" => if let Ok(msgs) = serde_json::from_value::<BTreeMap<String, Vec<(u64, u64)>>>(op.value.clone()) {
+                polls.push((invoke.time, msgs));
+            },
+            "commit_offsets" => if let Ok(committed) = serde_json::from_value(invoke.value.clone()) {
+                offsets.entry(invoke.process).or_default().push((op.time, None, committed));
+            },
+            "list_committed_offsets" => if let Ok(listed) = serde_json::from_value::<BTreeMap<String, u64>>(op.value.clone()) {
+                let asked = serde_json::from_value(invoke.value.clone()).unwrap_or_else(|_| listed.keys().cloned().collect());
+                let process = offsets.entry(invoke.process).or_default();
+                process.push((invoke.time, Some(asked), listed.clone()));
+                process.push((op.time, None, listed));
+            },
+            _ => {},
+        }
+    }
+    report.acknowledged = sends.values().map(Vec::len).sum();
+    report.polls = polls.len();
+
+    // Every key's acknowledged sends by offset, with when they were acknowledged.
+    let mut by_offset: BTreeMap<&str, BTreeMap<u64, (u64, Duration)>> = BTreeMap::new();
+    for (key, key_sends) in &mut sends {
+        let acknowledged = by_offset.entry(key).or_default();
+        for &(offset, msg, _, completed) in key_sends.iter() {
+            match acknowledged.get(&offset) {
+                Some(&(other, _)) if other != msg => report.duplicate_offsets.push((key.clone(), offset)),
+                Some(_) => {},
+                None => { acknowledged.insert(offset, (msg, completed)); },
+            }
+        }
+        // Going through them in the order they were invoked, the highest offset of any
+        // acknowledged before each was.
+        key_sends.sort_by_key(|&(_, _, _, completed)| completed);
+        let mut by_invocation: Vec<_> = key_sends.clone();
+        by_invocation.sort_by_key(|&(_, _, invoked, _)| invoked);
+        let (mut done, mut highest) = (0, None);
+        for &(offset, _, invoked, _) in &by_invocation {
+            while done < key_sends.len() && key_sends[done].3 < invoked {
+                highest = highest.max(Some(key_sends[done].0));
+                done += 1;
+            }
+            if let Some(earlier) = highest.filter(|&earlier| earlier >= offset) {
+                report.nonmonotonic_sends.push((key.clone(), earlier, offset));
+            }
+        }
+    }
+
+    for (invoked, msgs) in &polls {
+        for (key, polled) in msgs {
+            let acknowledged = by_offset.get(key.as_str());
+            for pair in polled.windows(2) {
+                let ((from, _), (to, _)) = (pair[0], pair[1]);
+                if to <= from {
+                    report.nonmonotonic_polls.push((key.clone(), to));
+                    continue;
+                }
+                let skipped = acknowledged.into_iter().flat_map(|acknowledged| acknowledged.range(from + 1..to)).filter(|(_, (_, completed))| completed < invoked);
+                report.skipped.extend(skipped.map(|(&offset, _)| (key.clone(), offset)));
+            }
+            for &(offset, msg) in polled {
+                if acknowledged.and_then(|acknowledged| acknowledged.get(&offset)).is_some_and(|&(sent, _)| sent != msg) {
+                    report.nonmonotonic_polls.push((key.clone(), offset));
+                }
+            }
+        }
+    }
+
+    for (&process, events) in &mut offsets {
+        // A listing's checked against what was committed before it was invoked.
+        events.sort_by_key(|(time, asked, _)| (*time, asked.is_none()));
+        let mut floor: BTreeMap<&str, u64> = BTreeMap::new();
+        for (_, asked, committed) in events.iter() {
+            if let Some(asked) = asked {
+                for key in asked {
+                    let Some(&at_least) = floor.get(key.as_str()) else {
+                        continue;
+                    };
+                    let listed = committed.get(key).copied();
+                    if listed.map_or(true, |listed| listed < at_least) {
+                        report.regressed_commits.push((process, key.clone(), at_least, listed));
+                    }
+                }
+                continue;
+            }
+            for (key, &offset) in committed {
+                let at_least = floor.entry(key).or_default();
+                *at_least = offset.max(*at_least);
+            }
+        }
+    }
+    report
+}
@@ -0,0 +1,84 @@
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+use tracing::{info, warn};
+use crate::{message::{self, NodeId}, metrics};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Circuit {
+    /// Calls go through; this many in a row have timed out.
+    Closed { timeouts: u32 },
+    /// Calls fail fast until `until`.
+    Open { until: Instant },
+    /// The cool-down is over and one call went out at `since` to see whether the
+    /// destination is back.
+    HalfOpen { since: Instant },
+}
+
+
+/// Stops an [`RpcClient`](crate::rpc::RpcClient) piling up calls to a destination that
+/// isn't answering (say, `seq-kv` on the far side of a partition): after `threshold`
+/// timeouts in a row, calls to it fail straight away for a `cool_down`. Then a single call
+/// is let through to try it again: an answer closes the circuit, another timeout opens
+/// it for another cool-down.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cool_down: Duration,
+    circuits: HashMap<NodeId, Circuit>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cool_down: Duration) -> Self {
+        Self { threshold: threshold.max(1), cool_down, circuits: HashMap::new() }
+    }
+
+    /// Whether a call to `destination` may go out at `now`. Once the cool-down is over,
+    /// only the first call asking is let through, until it's [recorded](Self::record) (or
+    /// another cool-down passes without it being, in case its caller gave up on it).
+    pub fn allow(&mut self, destination: &str, now: Instant) -> bool {
+        let Some(circuit) = self.circuits.get_mut(destination) else {
+            return true;
+        };
+        match *circuit {
+            Circuit::Closed { .. } => true,
+            Circuit::Open { until } if now >= until => {
+                *circuit = Circuit::HalfOpen { since: now };
+                true
+            },
+            Circuit::HalfOpen { since } if now >= since + self.cool_down => {
+                *circuit = Circuit::HalfOpen { since: now };
+                true
+            },
+            Circuit::Open { .. } | Circuit::HalfOpen { .. } => {
+                metrics::global().increment("rpc.short_circuited");
+                false
+            },
+        }
+    }
+
+    /// Note how a call to `destination` went: whether it `timed_out`, at `now`.
+    pub fn record(&mut self, destination: &str, timed_out: bool, now: Instant) {
+        let circuit = self.circuits.entry(message::intern(destination)).or_insert(Circuit::Closed { timeouts: 0 });
+        let next = match (*circuit, timed_out) {
+            (Circuit::HalfOpen { .. }, false) => {
+                info!(destination, "destination is answering again");
+                Circuit::Closed { timeouts: 0 }
+            },
+            (_, false) => Circuit::Closed { timeouts: 0 },
+            (Circuit::Closed { timeouts }, true) if timeouts + 1 < self.threshold => Circuit::Closed { timeouts: timeouts + 1 },
+            (Circuit::Open { until }, true) => Circuit::Open { until },
+            (Circuit::Closed { .. } | Circuit::HalfOpen { .. }, true) => {
+                warn!(destination, cool_down = ?self.cool_down, "destination isn't answering, failing calls to it for a while");
+                metrics::global().increment("rpc.circuits_opened");
+                Circuit::Open { until: now + self.cool_down }
+            },
+        };
+        *circuit = next;
+    }
+
+    /// Whether calls to `destination` are failing fast at `now`.
+    pub fn is_open(&self, destination: &str, now: Instant) -> bool {
+        matches!(self.circuits.get(destination), Some(Circuit::Open { until }) if now < *until)
+    }
+}
@@ -0,0 +1,274 @@
+use std::{ffi::OsString, fmt::Display, num::NonZeroUsize, path::{Path, PathBuf}, str::FromStr, time::Duration};
+use clap::{error::ErrorKind, Command, Parser, ValueEnum};
+use crate::topology::Overlay;
+
+
+/// How often a node's background work (gossip, resends, ...) runs, unless
+/// `--tick-rate-ms` says otherwise.
+pub const TICK_RATE_MS: u64 = 100;
+
+
+/// How log lines look.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// One line per event, with its spans' fields.
+    #[default]
+    Full,
+    /// Shorter lines: span names are left out.
+    Compact,
+    /// Several lines per event, for reading by eye.
+    Pretty,
+}
+
+
+/// Where a node reads its messages from and writes its replies to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Transport {
+    /// Maelstrom's: a JSON message per line on stdin and stdout. (Tests run nodes on the
+    /// in-memory network instead.)
+    #[default]
+    Stdio,
+}
+
+
+/// Which tokio runtime a node runs on, as `--runtime` spells it: `single` (everything on
+/// the main thread, which is plenty for echo or unique ids), or `multi` (a worker per
+/// core) with `multi:N` for N workers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Runtime {
+    Single,
+    #[default]
+    Multi,
+    MultiWith(NonZeroUsize),
+}
+
+impl FromStr for Runtime {
+    type Err = String;
+
+    fn from_str(runtime: &str) -> Result<Self, Self::Err> {
+        match runtime.split_once(':') {
+            None if runtime == "single" => Ok(Self::Single),
+            None if runtime == "multi" => Ok(Self::Multi),
+            Some(("multi", threads)) => threads.parse().map(Self::MultiWith).map_err(|_| format!("`{threads}` isn't a number of threads")),
+            _ => Err(format!("expected `single`, `multi` or `multi:<threads>`, not `{runtime}`")),
+        }
+    }
+}
+
+impl Display for Runtime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Single => write!(f, "single"),
+            Self::Multi => write!(f, "multi"),
+            Self::MultiWith(threads) => write!(f, "multi:{threads}"),
+        }
+    }
+}
+
+
+/// What `--fanout` counts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum FanoutStrategy {
+    /// That many peers, picked at random each round.
+    #[default]
+    Peers,
+    /// That percentage of the rest of the cluster (rounded up), picked at random each round.
+    Percent,
+}
+
+
+/// The options every node takes, whatever its workload: logging, the background tick and
+/// gossip fanout, where its messages come from and go (and a recording of them), its runtime and its transport. Node binaries flatten these into their
+/// own options (`#[clap(flatten)] common: CommonOpts`), and
+/// [`workloads::run`](crate::workloads::run) puts them into effect, so a new one shows
+/// up in every workload at once.
+#[derive(Debug, Clone, Parser)]
+#[clap(about = None, long_about = None, next_help_heading = "Common options")]
+pub struct CommonOpts {
+    #[clap(long, help = "Read settings from this TOML file (`stride = 3`, `tick_rate_ms = 155`, ...). Environment variables and flags take precedence over it.", env = "CONFIG")]
+    pub config: Option<PathBuf>,
+    #[clap(long, help = "Which logs to write, as `RUST_LOG` directives (e.g. `info` or `solutions::io=trace`). Only errors if not given.", env = "RUST_LOG")]
+    pub log_level: Option<String>,
+    #[clap(long, value_enum, default_value_t = LogFormat::Full, help = "How log lines look.", env = "LOG_FORMAT")]
+    pub log_format: LogFormat,
+    #[clap(long, help = "Only trace 1 in every LOG_SAMPLE messages read and written (warnings and errors are always logged).", env = "LOG_SAMPLE")]
+    pub log_sample: Option<usize>,
+    #[clap(long, value_delimiter = ',', help = "Estimate messages per operation (printed to stderr as it goes), counting these request types from clients as operations, e.g. `broadcast,read`.", env = "CLIENT_OPS")]
+    pub client_ops: Vec<String>,
+    #[clap(short, long, default_value_t = TICK_RATE_MS, help = "Number of milliseconds between rounds of background work, like syncing unacknowledged messages with neighbors.", env = "TICK_RATE_MS")]
+    pub tick_rate_ms: u64,
+    #[clap(long, help = "Gossip to this many peers per round (see --fanout-strategy), picked at random each time. All of them if not given (or 0).", env = "FANOUT")]
+    pub fanout: Option<usize>,
+    #[clap(long, value_enum, default_value_t = FanoutStrategy::Peers, help = "Whether --fanout is a number of peers or a percentage of the cluster.", env = "FANOUT_STRATEGY")]
+    pub fanout_strategy: FanoutStrategy,
+    #[clap(long, help = "Let a peer have at most this many gossip messages (like broadcast's syncs) it has yet to acknowledge: it's sent no more until it acknowledges one, or one times out. No limit if not given (or 0).", env = "GOSSIP_WINDOW")]
+    pub gossip_window: Option<usize>,
+    #[clap(long, help = "Put at most this many items in one gossip message (like broadcast's syncs), leaving the rest for the next round. No limit if not given (or 0).", env = "BATCH_SIZE")]
+    pub batch_size: Option<usize>,
+    #[clap(long, help = "Which peers a gossiping node talks to directly: `stride:<n>`, `ring`, `grid`, `tree:<k>`, `hypercube`, `random:<degree>`, `nearest:<near>+<far>` (the `near` peers with the quickest round trips, picked again every so often, plus `far` random ones), or `maelstrom` for the ones in Maelstrom's topology message. Broadcast uses `stride:<--stride>` if not given, and the counter every node.", env = "OVERLAY")]
+    pub overlay: Option<Overlay>,
+    #[clap(long, help = "Keep track of which peers are alive SWIM-style (pinging one a tick, with rumors of who's suspect or dead piggybacked on gossip), and stop gossiping to ones taken for dead until they're back. Broadcast and the counter only.", env = "SWIM")]
+    pub swim: bool,
+    #[clap(long, help = "Gossip with a few neighbors kept up HyParView-style (a small active view, with a bigger passive one in reserve to replace neighbors that fail), rather than every node, for large clusters. The counter only.", env = "HYPARVIEW")]
+    pub hyparview: bool,
+    #[clap(long, help = "Send no more than this many messages a second, in bursts of up to a second's worth.", env = "RATE_LIMIT")]
+    pub rate_limit: Option<f64>,
+    #[clap(long, help = "Send no more than this many messages a second to any one node, client or service, in bursts of up to a second's worth.", env = "RATE_LIMIT_PER_DEST")]
+    pub rate_limit_per_dest: Option<f64>,
+    #[clap(long, help = "Read messages from this file (one JSON message per line, or a --record recording) instead of stdin, and stop at its end.", env = "INPUT")]
+    pub input: Option<PathBuf>,
+    #[clap(long, help = "Write messages to this file instead of stdout.", env = "OUTPUT")]
+    pub output: Option<PathBuf>,
+    #[clap(long, help = "Before starting, run a copy of the node through init, topology and a few of its workload's operations in-process, and exit if any reply isn't what it should be.", env = "SELF_TEST")]
+    pub self_test: bool,
+    #[clap(long, help = "Lint the messages read instead of answering them: report protocol violations (unknown types, requests without a msg_id, replies to unknown ids, ...) to stderr, and exit non-zero if there are any.", env = "VALIDATE")]
+    pub validate: bool,
+    #[clap(long, help = "Append every message read and written (with when, and which way) to this file as JSON lines.", env = "RECORD")]
+    pub record: Option<PathBuf>,
+    #[clap(long, default_value_t = Runtime::Multi, help = "Which tokio runtime to run on: `single` (one thread), `multi` (a worker per core) or `multi:<threads>`.", env = "RUNTIME")]
+    pub runtime: Runtime,
+    #[clap(long, value_enum, default_value_t = Transport::Stdio, help = "Where messages come from and replies go.", env = "TRANSPORT")]
+    pub transport: Transport,
+}
+
+impl CommonOpts {
+    pub fn tick_rate(&self) -> Duration {
+        Duration::from_millis(self.tick_rate_ms)
+    }
+}
+
+/// What a node gets without any flags or environment variables.
+impl Default for CommonOpts {
+    fn default() -> Self {
+        Self {
+            config: None,
+            log_level: None,
+            log_format: LogFormat::Full,
+            log_sample: None,
+            client_ops: vec![],
+            tick_rate_ms: TICK_RATE_MS,
+            fanout: None,
+            fanout_strategy: FanoutStrategy::Peers,
+            gossip_window: None,
+            batch_size: None,
+            overlay: None,
+            swim: false,
+            hyparview: false,
+            rate_limit: None,
+            rate_limit_per_dest: None,
+            input: None,
+            output: None,
+            self_test: false,
+            validate: false,
+            record: None,
+            runtime: Runtime::Multi,
+            transport: Transport::Stdio,
+        }
+    }
+}
+
+
+/// The environment variables a config file stands for: each of its settings is named
+/// after an option of `command` (or of one of its subcommands), as in `tick_rate_ms = 155`
+/// or `tick-rate-ms = 155`, and sets that option's environment variable. Lists (like
+/// `client_ops = ["broadcast", "read"]`) are joined with commas.
+pub fn settings(config: &str, command: &Command) -> Result<Vec<(String, String)>, String> {
+    let table: toml::Table = config.parse().map_err(|err| format!("{err}"))?;
+    table.into_iter().map(|(key, value)| {
+        let id = key.replace('-', "_");
+        let Some(env) = find_env(command, &id) else {
+            return Err(format!("unknown setting `{key}`"));
+        };
+        let value = match value {
+            toml::Value::Array(values) => values.into_iter().map(scalar).collect::<Option<Vec<_>>>().map(|values| values.join(",")),
+            value => scalar(value),
+        };
+        value.map(|value| (env, value)).ok_or_else(|| format!("`{key}` should be a string, number or boolean (or a list of them)"))
+    }).collect()
+}
+
+fn scalar(value: toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        toml::Value::Boolean(value) => Some(value.to_string()),
+        toml::Value::Datetime(value) => Some(value.to_string()),
+        toml::Value::Array(_) | toml::Value::Table(_) => None,
+    }
+}
+
+fn find_env(command: &Command, id: &str) -> Option<String> {
+    command
+    .get_arguments()
+    .find(|arg| arg.get_id() == id)
+    .and_then(|arg| arg.get_env())
+    .map(|env| env.to_string_lossy().into_owned())
+    .or_else(|| command.get_subcommands().find_map(|subcommand| find_env(subcommand, id)))
+}
+
+/// Where `--config` (or `CONFIG`) says the config file is, if anywhere.
+fn config_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().skip(1).map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next().map(|path| PathBuf::from(path.as_ref()));
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os("CONFIG").map(PathBuf::from)
+}
+
+fn read_config(path: &Path, command: &Command) -> Result<Vec<(String, String)>, String> {
+    let config = std::fs::read_to_string(path).map_err(|err| format!("can't read {}: {err}", path.display()))?;
+    settings(&config, command).map_err(|err| format!("in {}: {err}", path.display()))
+}
+
+/// Parse a node binary's options like [`Parser::parse`] does, after filling in any
+/// environment variables that aren't set from the `--config` file, so what's in the file
+/// gives way to the environment, which gives way to flags. Exits with a usage error if
+/// the file can't be read or has settings the binary doesn't know.
+pub fn parse<T: Parser>() -> T {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    if let Some(path) = config_path(&args) {
+        let command = T::command();
+        match read_config(&path, &command) {
+            Ok(settings) => {
+                for (env, value) in settings {
+                    if std::env::var_os(&env).is_none() {
+                        std::env::set_var(env, value);
+                    }
+                }
+            },
+            Err(err) => T::command().error(ErrorKind::InvalidValue, err).exit(),
+        }
+    }
+    T::parse_from(args)
+}
+
+/// [`parse`], for options that can pick a bundled set of settings by name, like
+/// broadcast's `--profile 3e`: `profile` finds the chosen one's settings (written like a
+/// config file) in what was parsed, and they fill in anything the config file, the
+/// environment and flags leave unset.
+pub fn parse_with_profile<T: Parser>(profile: impl Fn(&T) -> Option<&'static str>) -> T {
+    let opts = parse::<T>();
+    let Some(profile) = profile(&opts) else {
+        return opts;
+    };
+    match settings(profile, &T::command()) {
+        Ok(settings) => {
+            for (env, value) in settings {
+                if std::env::var_os(&env).is_none() {
+                    std::env::set_var(env, value);
+                }
+            }
+        },
+        Err(err) => T::command().error(ErrorKind::InvalidValue, format!("in the profile: {err}")).exit(),
+    }
+    T::parse()
+}
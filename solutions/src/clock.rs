@@ -0,0 +1,218 @@
+use std::{fmt::Debug, future::Future, pin::Pin, sync::{Arc, Mutex, OnceLock}, task::{Poll, Waker}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use tokio::time::Instant;
+
+
+/// What a [`Clock`] hands back to wait on.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+
+/// Where periodic work and timeouts get the time from, and wait on it: the
+/// [system](SystemClock) clock when a node runs, or a [`ManualClock`] that only moves
+/// when a test says so, to see what happens after a timeout without waiting one out.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Resolves once it's `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        self.sleep_until(self.now() + duration)
+    }
+}
+
+impl dyn Clock {
+    /// Ticks right away, then every `period`.
+    pub fn interval(self: &Arc<Self>, period: Duration) -> Interval {
+        self.interval_at(self.now(), period)
+    }
+
+    /// Ticks at `start`, then every `period`.
+    pub fn interval_at(self: &Arc<Self>, start: Instant, period: Duration) -> Interval {
+        assert!(!period.is_zero(), "an interval's period can't be zero");
+        Interval { clock: self.clone(), next: start, period }
+    }
+
+    /// Run `work` until it's `deadline` (then `None`, and `work` is dropped where it
+    /// stands). Like [`tokio::time::timeout_at`], `work` gets polled first, so it can
+    /// finish even if it's already late.
+    pub async fn timeout_at<F: Future>(&self, deadline: Instant, work: F) -> Option<F::Output> {
+        tokio::select! {
+            biased;
+            output = work => Some(output),
+            () = self.sleep_until(deadline) => None,
+        }
+    }
+
+    pub async fn timeout<F: Future>(&self, duration: Duration, work: F) -> Option<F::Output> {
+        self.timeout_at(self.now() + duration, work).await
+    }
+}
+
+
+/// Tokio's clock, so a paused runtime (as simulations use) still fast-forwards it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(deadline))
+    }
+}
+
+/// The [`SystemClock`], for whatever isn't handed a clock of its own.
+pub fn system() -> Arc<dyn Clock> {
+    static SYSTEM: OnceLock<Arc<dyn Clock>> = OnceLock::new();
+    SYSTEM.get_or_init(|| Arc::new(SystemClock)).clone()
+}
+
+
+/// A [`Clock`] to hand to state that derives `Default`: the [system](system) one unless
+/// it's given another.
+#[derive(Debug, Clone)]
+pub struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self(clock)
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        Self(system())
+    }
+}
+
+impl std::ops::Deref for SharedClock {
+    type Target = Arc<dyn Clock>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Milliseconds since the Unix epoch, as told by a [`Clock`]: the system's wall-clock
+/// time when it's made, moved on by however far the clock's moved since. Maelstrom runs
+/// every node on the same machine, so they all agree on it; a [`ManualClock`] moves it
+/// on in tests.
+#[derive(Debug, Clone)]
+pub struct WallClock {
+    clock: Arc<dyn Clock>,
+    epoch_ms: u64,
+    started: Instant,
+}
+
+impl WallClock {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        let epoch_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        Self { epoch_ms, started: clock.now(), clock }
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.epoch_ms + self.clock.now().saturating_duration_since(self.started).as_millis() as u64
+    }
+}
+
+impl Default for WallClock {
+    fn default() -> Self {
+        Self::new(system())
+    }
+}
+
+
+#[derive(Debug)]
+struct Manual {
+    now: Instant,
+    /// Whoever's waiting, and until when.
+    sleepers: Vec<(Instant, Waker)>,
+}
+
+/// A clock that stands still until it's [advanced](Self::advance), waking whatever was
+/// waiting on the time it's moved past. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    inner: Arc<Mutex<Manual>>,
+}
+
+impl ManualClock {
+    /// Starts at the current (tokio) time.
+    pub fn new() -> Self {
+        Self { inner: Arc::new(Mutex::new(Manual { now: Instant::now(), sleepers: vec![] })) }
+    }
+
+    /// Move the time forward `by`.
+    pub fn advance(&self, by: Duration) {
+        let mut due = vec![];
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.now += by;
+            let now = inner.now;
+            inner.sleepers.retain(|(deadline, waker)| {
+                if *deadline <= now {
+                    due.push(waker.clone());
+                }
+                *deadline > now
+            });
+        }
+        for waker in due {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.inner.lock().unwrap().now
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        let inner = self.inner.clone();
+        Box::pin(std::future::poll_fn(move |cx| {
+            let mut inner = inner.lock().unwrap();
+            if inner.now >= deadline {
+                return Poll::Ready(());
+            }
+            inner.sleepers.push((deadline, cx.waker().clone()));
+            Poll::Pending
+        }))
+    }
+}
+
+
+/// Like [`tokio::time::Interval`], on any [`Clock`]. A tick that comes late pushes the
+/// ones after it back, rather than firing a burst of them to catch up.
+#[derive(Debug)]
+pub struct Interval {
+    clock: Arc<dyn Clock>,
+    next: Instant,
+    period: Duration,
+}
+
+impl Interval {
+    /// Wait for the next tick, returning when it was due.
+    pub async fn tick(&mut self) -> Instant {
+        self.clock.sleep_until(self.next).await;
+        let due = self.next;
+        let now = self.clock.now();
+        self.next = if due + self.period <= now { now + self.period } else { due + self.period };
+        due
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+}
@@ -0,0 +1,234 @@
+use std::{fmt::Display, str::FromStr};
+use serde_json::{json, Value};
+use crate::message::{Envelope, ErrorCode};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Allowed, but not what Maelstrom's own nodes would do.
+    Warning,
+    /// Breaks the protocol: Maelstrom would reject the run, or hang waiting on the node.
+    Violation,
+}
+
+
+/// Something a node did wrong during one step of the conformance script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub step: String,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Violation => "violation",
+        };
+        write!(f, "{severity} in `{}`: {}", self.step, self.message)
+    }
+}
+
+
+fn kind(body: &Value) -> &str {
+    body.get("type").and_then(Value::as_str).unwrap_or_default()
+}
+
+/// What every reply owes the request it answers, whatever the workload: it comes back
+/// from whom the request went to, to whom it came from, says which message it answers,
+/// and is either `<type>_ok` or a well-formed `error`.
+pub fn check_reply(request: &Envelope<Value>, reply: &Envelope<Value>) -> Vec<String> {
+    let mut problems = vec![];
+    if reply.source != request.destination {
+        problems.push(format!("reply came from `{}`, not `{}`", reply.source, request.destination));
+    }
+    if reply.destination != request.source {
+        problems.push(format!("reply went to `{}`, not `{}`", reply.destination, request.source));
+    }
+    if reply.body.in_reply_to != request.body.msg_id {
+        problems.push(format!("reply is in_reply_to {:?}, not {:?}", reply.body.in_reply_to, request.body.msg_id));
+    }
+    let expected = format!("{}_ok", kind(&request.body.message));
+    match kind(&reply.body.message) {
+        "error" => match reply.body.message.get("code").and_then(Value::as_u64) {
+            None => problems.push("error reply without an integer `code`".to_owned()),
+            Some(code) => problems.push(format!("answered with error {code}: {}", reply.body.message.get("text").and_then(Value::as_str).unwrap_or("(no text)"))),
+        },
+        other if other != expected => problems.push(format!("reply has type `{other}`, not `{expected}`")),
+        _ => {},
+    }
+    problems
+}
+
+/// What a reply to a message type the node doesn't know should be: an `error` with
+/// code 10 (not supported). Anything else is `Some(problem)`.
+pub fn check_unsupported(reply: Option<&Envelope<Value>>) -> Option<String> {
+    let Some(reply) = reply else {
+        return Some("no reply to an unknown message type (expected error code 10)".to_owned());
+    };
+    let body = &reply.body.message;
+    let not_supported = ErrorCode::NotSupported.code() as u64;
+    match (kind(body), body.get("code").and_then(Value::as_u64)) {
+        ("error", Some(code)) if code == not_supported => None,
+        ("error", code) => Some(format!("unknown message type answered with error code {code:?}, not {not_supported}")),
+        (other, _) => Some(format!("unknown message type answered with `{other}`")),
+    }
+}
+
+
+/// The workload a node implements, which decides what the script asks of it past
+/// `init`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    Echo,
+    UniqueIds,
+    Broadcast,
+    Counter,
+    Kafka,
+    Txn,
+    LinKv,
+    /// `lin-kv`'s requests, asked of a store that only promises the last write wins:
+    /// its `cas` may act on a stale read, so the script doesn't lean on it.
+    LwwKv,
+}
+
+impl Workload {
+    pub const ALL: [Workload; 8] = [Workload::Echo, Workload::UniqueIds, Workload::Broadcast, Workload::Counter, Workload::Kafka, Workload::Txn, Workload::LinKv, Workload::LwwKv];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Workload::Echo => "echo",
+            Workload::UniqueIds => "unique-ids",
+            Workload::Broadcast => "broadcast",
+            Workload::Counter => "g-counter",
+            Workload::Kafka => "kafka",
+            Workload::Txn => "txn-rw-register",
+            Workload::LinKv => "lin-kv",
+            Workload::LwwKv => "lww-kv",
+        }
+    }
+
+    /// Whether Maelstrom sends this workload a `topology` right after `init`.
+    pub fn takes_topology(self) -> bool {
+        self == Workload::Broadcast
+    }
+
+    /// The requests to make of a single node, in order, once it's set up.
+    pub fn script(self) -> Vec<Value> {
+        match self {
+            Workload::Echo => vec![
+                json!({"type": "echo", "echo": "hello"}),
+                json!({"type": "echo", "echo": "hello again"}),
+            ],
+            Workload::UniqueIds => vec![json!({"type": "generate"}); 3],
+            Workload::Broadcast => vec![
+                json!({"type": "broadcast", "message": 1}),
+                json!({"type": "broadcast", "message": 2}),
+                json!({"type": "read"}),
+            ],
+            Workload::Counter => vec![
+                json!({"type": "add", "delta": 3}),
+                json!({"type": "read"}),
+            ],
+            Workload::Kafka => vec![
+                json!({"type": "send", "key": "k1", "msg": 10}),
+                json!({"type": "send", "key": "k1", "msg": 11}),
+                json!({"type": "poll", "offsets": {"k1": 0}}),
+                json!({"type": "commit_offsets", "offsets": {"k1": 0}}),
+                json!({"type": "list_committed_offsets", "keys": ["k1"]}),
+            ],
+            Workload::Txn => vec![
+                json!({"type": "txn", "txn": [["w", 1, 5]]}),
+                json!({"type": "txn", "txn": [["r", 1, null], ["w", 2, 6]]}),
+            ],
+            Workload::LinKv => vec![
+                json!({"type": "write", "key": 0, "value": 1}),
+                json!({"type": "read", "key": 0}),
+                json!({"type": "cas", "key": 0, "from": 1, "to": 2}),
+                json!({"type": "read", "key": 0}),
+            ],
+            Workload::LwwKv => vec![
+                json!({"type": "write", "key": 0, "value": 1}),
+                json!({"type": "read", "key": 0}),
+                json!({"type": "write", "key": 0, "value": 2}),
+                json!({"type": "read", "key": 0}),
+            ],
+        }
+    }
+
+    /// Whether a `_ok` reply says what it should, given the request and the exchanges
+    /// before it in the script. `Some(problem)` if not.
+    pub fn check(self, request: &Value, reply: &Value, earlier: &[(Value, Value)]) -> Option<String> {
+        let earlier_replies = || earlier.iter().filter(|(request, _)| kind(request) != "init").map(|(_, reply)| reply);
+        match (self, kind(request)) {
+            (Workload::Echo, "echo") => (reply.get("echo") != request.get("echo")).then(|| format!("echoed {:?} back for {:?}", reply.get("echo"), request.get("echo"))),
+            (Workload::UniqueIds, "generate") => match reply.get("id") {
+                None => Some("generate_ok without an `id`".to_owned()),
+                Some(id) => earlier_replies().any(|earlier| earlier.get("id") == Some(id)).then(|| format!("generated id {id} twice")),
+            },
+            (Workload::Broadcast, "read") => {
+                let messages = reply.get("messages").and_then(Value::as_array);
+                let broadcast = earlier.iter().filter(|(request, _)| kind(request) == "broadcast").filter_map(|(request, _)| request.get("message"));
+                let missing: Vec<&Value> = broadcast.filter(|message| !messages.is_some_and(|messages| messages.contains(message))).collect();
+                match messages {
+                    None => Some("read_ok without a `messages` list".to_owned()),
+                    Some(_) if !missing.is_empty() => Some(format!("read is missing acknowledged broadcasts {missing:?}")),
+                    _ => None,
+                }
+            },
+            (Workload::Counter, "read") => reply.get("value").and_then(Value::as_i64).is_none().then(|| "read_ok without an integer `value`".to_owned()),
+            (Workload::Kafka, "send") => {
+                let Some(offset) = reply.get("offset").and_then(Value::as_i64) else {
+                    return Some("send_ok without an integer `offset`".to_owned());
+                };
+                let previous = earlier_replies().filter_map(|earlier| earlier.get("offset").and_then(Value::as_i64)).max();
+                previous.filter(|previous| offset <= *previous).map(|previous| format!("offset {offset} doesn't come after {previous}"))
+            },
+            (Workload::Kafka, "poll") => {
+                let polled = reply.pointer("/msgs/k1").and_then(Value::as_array);
+                let sent: Vec<(Option<&Value>, Option<&Value>)> =
+                    earlier
+                    .iter()
+                    .filter(|(request, _)| kind(request) == "send")
+                    .map(|(request, reply)| (reply.get("offset"), request.get("msg")))
+                    .collect();
+                let polled_pairs: Vec<(Option<&Value>, Option<&Value>)> = polled.into_iter().flatten().map(|pair| (pair.get(0), pair.get(1))).collect();
+                sent.iter().find(|pair| !polled_pairs.contains(pair)).map(|(offset, msg)| format!("poll from 0 is missing [{offset:?}, {msg:?}]"))
+            },
+            (Workload::Kafka, "list_committed_offsets") => reply.pointer("/offsets/k1").is_none().then(|| "list_committed_offsets_ok doesn't list the committed `k1`".to_owned()),
+            (Workload::Txn, "txn") => {
+                let (Some(asked), Some(done)) = (request.get("txn").and_then(Value::as_array), reply.get("txn").and_then(Value::as_array)) else {
+                    return Some("txn_ok without a `txn` list".to_owned());
+                };
+                if asked.len() != done.len() {
+                    return Some(format!("txn_ok has {} micro-ops for {} requested", done.len(), asked.len()));
+                }
+                asked.iter().zip(done).find_map(|(asked, done)| match asked.get(0).and_then(Value::as_str) {
+                    Some("w") => (asked != done).then(|| format!("write {asked} came back as {done}")),
+                    _ => (asked.get(1) != done.get(1)).then(|| format!("read {asked} came back as {done}")),
+                })
+            },
+            (Workload::LinKv | Workload::LwwKv, "read") => {
+                // What the last write or cas before it left the key at.
+                let written = earlier.iter().rev().find_map(|(request, _)| match kind(request) {
+                    "write" => request.get("value"),
+                    "cas" => request.get("to"),
+                    _ => None,
+                });
+                (reply.get("value") != written).then(|| format!("read {:?} back after writing {written:?}", reply.get("value")))
+            },
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Workload {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Workload::ALL.into_iter().find(|workload| workload.name() == name).ok_or_else(|| {
+            format!("unknown workload `{name}` (expected one of {})", Workload::ALL.map(Workload::name).join(", "))
+        })
+    }
+}
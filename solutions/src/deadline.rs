@@ -0,0 +1,78 @@
+use std::{future::Future, sync::Arc, time::Duration};
+use tokio::time::Instant;
+use crate::clock::Clock;
+
+
+/// When whoever is waiting on a request gives up on it, as told by a [`Clock`]. A
+/// request forwarded to another node carries what's left of it as a budget
+/// ([`budget_ms`](Self::budget_ms)), since node clocks needn't agree, so that node can
+/// drop work nobody will be waiting for.
+#[derive(Debug, Clone)]
+pub struct Deadline {
+    at: Instant,
+    clock: Arc<dyn Clock>,
+}
+
+impl Deadline {
+    pub fn at(clock: Arc<dyn Clock>, instant: Instant) -> Self {
+        Self { at: instant, clock }
+    }
+
+    pub fn after(clock: Arc<dyn Clock>, budget: Duration) -> Self {
+        Self { at: clock.now() + budget, clock }
+    }
+
+    /// The deadline of a request forwarded with `budget_ms` to go.
+    pub fn from_budget_ms(clock: Arc<dyn Clock>, budget_ms: u64) -> Self {
+        Self::after(clock, Duration::from_millis(budget_ms))
+    }
+
+    pub fn instant(&self) -> Instant {
+        self.at
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.at.saturating_duration_since(self.clock.now())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining().is_zero()
+    }
+
+    /// What's left, in whole milliseconds, to send along with a forwarded request.
+    pub fn budget_ms(&self) -> u64 {
+        self.remaining().as_millis() as u64
+    }
+}
+
+tokio::task_local! {
+    static CURRENT: Option<Deadline>;
+}
+
+/// Handle a request by running `handler` with `deadline` as its [`current`] one, so
+/// anything it calls can see how long it has left without it being passed around.
+pub async fn scope<F: Future>(deadline: Option<Deadline>, handler: F) -> F::Output {
+    CURRENT.scope(deadline, handler).await
+}
+
+/// The deadline of the request being handled, if it has one.
+pub fn current() -> Option<Deadline> {
+    CURRENT.try_with(|deadline| deadline.clone()).ok().flatten()
+}
+
+/// How long to wait on something for the request being handled: `timeout`, or whatever's
+/// left before its deadline, if that's sooner.
+pub fn remaining_or(timeout: Duration) -> Duration {
+    current().map_or(timeout, |deadline| deadline.remaining().min(timeout))
+}
+
+/// Run `work` for the request being handled, unless its deadline passes first (then
+/// `None`, and `work` is dropped where it stands, or never started if it's already
+/// passed).
+pub async fn within<F: Future>(work: F) -> Option<F::Output> {
+    match current() {
+        Some(deadline) if deadline.is_expired() => None,
+        Some(deadline) => deadline.clock.timeout_at(deadline.at, work).await,
+        None => Some(work.await),
+    }
+}
@@ -0,0 +1,163 @@
+use std::{collections::{HashMap, HashSet}, time::Duration};
+use serde_json::Value;
+use crate::session::Recorded;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Mermaid,
+    PlantUml,
+}
+
+
+/// Which messages of a session make it into the diagram. Everything, by default.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Only messages to or from one of these nodes.
+    pub nodes: Vec<String>,
+    /// Only the request/reply chain around a message with this `msg_id` (sent by this
+    /// node, if given): the message itself, the replies to it, the replies to those, and
+    /// so on, plus whatever it was itself a reply to.
+    pub chain: Option<(Option<String>, usize)>,
+    /// Only messages recorded within this window. Messages recorded without a time
+    /// are placed by their position in the session instead (as if one per second).
+    pub since: Option<Duration>,
+    pub until: Option<Duration>,
+}
+
+impl Filter {
+    pub fn apply<'a>(&self, session: &'a [Recorded]) -> Vec<&'a Recorded> {
+        let in_chain = self.chain.as_ref().map(|(node, msg_id)| chain(session, node.as_deref(), *msg_id));
+        session
+            .iter()
+            .enumerate()
+            .filter(|(index, recorded)| {
+                let envelope = &recorded.envelope;
+                let time = recorded.time.unwrap_or(Duration::from_secs(*index as u64));
+                (self.nodes.is_empty() || self.nodes.iter().any(|node| **node == *envelope.source || **node == *envelope.destination))
+                    && in_chain.as_ref().map_or(true, |in_chain| in_chain.contains(index))
+                    && self.since.map_or(true, |since| time >= since)
+                    && self.until.map_or(true, |until| time <= until)
+            })
+            .map(|(_, recorded)| recorded)
+            .collect()
+    }
+}
+
+
+/// Indices of every message linked to the seed message(s) through `in_reply_to`.
+/// msg_ids are only unique per sender, so a reply is matched by who sent it to whom too.
+fn chain(session: &[Recorded], node: Option<&str>, msg_id: usize) -> HashSet<usize> {
+    // (sender, receiver, msg_id) for requests, and the same key seen from the reply's side.
+    let mut requests: HashMap<(&str, &str, usize), Vec<usize>> = HashMap::new();
+    let mut replies: HashMap<(&str, &str, usize), Vec<usize>> = HashMap::new();
+    for (index, recorded) in session.iter().enumerate() {
+        let envelope = &recorded.envelope;
+        if let Some(msg_id) = envelope.body.msg_id {
+            requests.entry((&*envelope.source, &*envelope.destination, msg_id)).or_default().push(index);
+        }
+        if let Some(in_reply_to) = envelope.body.in_reply_to {
+            replies.entry((&*envelope.destination, &*envelope.source, in_reply_to)).or_default().push(index);
+        }
+    }
+
+    let mut in_chain = HashSet::new();
+    let mut queue: Vec<usize> =
+        session
+        .iter()
+        .enumerate()
+        .filter(|(_, recorded)| recorded.envelope.body.msg_id == Some(msg_id) && node.map_or(true, |node| *recorded.envelope.source == *node))
+        .map(|(index, _)| index)
+        .collect();
+    while let Some(index) = queue.pop() {
+        if !in_chain.insert(index) {
+            continue;
+        }
+        let envelope = &session[index].envelope;
+        if let Some(msg_id) = envelope.body.msg_id {
+            queue.extend(replies.get(&(&*envelope.source, &*envelope.destination, msg_id)).into_iter().flatten());
+        }
+        if let Some(in_reply_to) = envelope.body.in_reply_to {
+            queue.extend(requests.get(&(&*envelope.destination, &*envelope.source, in_reply_to)).into_iter().flatten());
+        }
+    }
+    in_chain
+}
+
+
+/// What an arrow says: the message type, its ids, and the rest of its body (cut short).
+fn label(recorded: &Recorded) -> String {
+    let body = &recorded.envelope.body;
+    let mut label = recorded.kind().to_owned();
+    if let Some(msg_id) = body.msg_id {
+        label.push_str(&format!(" #{msg_id}"));
+    }
+    if let Some(in_reply_to) = body.in_reply_to {
+        label.push_str(&format!(" re #{in_reply_to}"));
+    }
+    if let Value::Object(fields) = &body.message {
+        let rest: serde_json::Map<_, _> = fields.iter().filter(|(key, _)| *key != "type").map(|(k, v)| (k.clone(), v.clone())).collect();
+        if !rest.is_empty() {
+            let mut rest = Value::Object(rest).to_string();
+            if rest.chars().count() > 60 {
+                rest = rest.chars().take(57).collect::<String>() + "...";
+            }
+            label.push(' ');
+            label.push_str(&rest);
+        }
+    }
+    label
+}
+
+
+/// Render `messages` as a sequence diagram, with participants in the order they first
+/// show up. Replies are drawn as dashed arrows.
+pub fn render(messages: &[&Recorded], format: Format) -> String {
+    let mut participants: Vec<&str> = vec![];
+    for recorded in messages {
+        for node in [&*recorded.envelope.source, &*recorded.envelope.destination] {
+            if !participants.contains(&node) {
+                participants.push(node);
+            }
+        }
+    }
+    // Mermaid can't take node ids like `lin-kv` as participants directly.
+    let alias = |node: &str| node.replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+
+    let mut lines = vec![];
+    match format {
+        Format::Mermaid => lines.push("sequenceDiagram".to_owned()),
+        Format::PlantUml => lines.push("@startuml".to_owned()),
+    }
+    for node in &participants {
+        lines.push(match format {
+            Format::Mermaid => format!("    participant {} as {node}", alias(node)),
+            Format::PlantUml => format!("participant \"{node}\" as {}", alias(node)),
+        });
+    }
+    for recorded in messages {
+        let (from, to) = (alias(&recorded.envelope.source), alias(&recorded.envelope.destination));
+        let is_reply = recorded.envelope.body.in_reply_to.is_some();
+        lines.push(match format {
+            // `#` and `;` mean something to Mermaid, so write them as entity codes.
+            Format::Mermaid => {
+                let arrow = if is_reply { "-->>" } else { "->>" };
+                let label: String = label(recorded).chars().map(|c| match c {
+                    '#' => "#35;".to_owned(),
+                    ';' => "#59;".to_owned(),
+                    c => c.to_string(),
+                }).collect();
+                format!("    {from}{arrow}{to}: {label}")
+            },
+            Format::PlantUml => {
+                let arrow = if is_reply { "-->" } else { "->" };
+                format!("{from} {arrow} {to}: {}", label(recorded))
+            },
+        });
+    }
+    if format == Format::PlantUml {
+        lines.push("@enduml".to_owned());
+    }
+    lines.push(String::new());
+    lines.join("\n")
+}
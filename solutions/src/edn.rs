@@ -0,0 +1,337 @@
+use std::fmt::Display;
+
+
+/// A value read from EDN, the Clojure data format Jepsen and Maelstrom write their
+/// results in. Maps keep their entries in order (and may have any value as a key),
+/// since EDN floats and collections don't hash.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edn {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    /// Floats, plus ratios, big decimals and integers too big for an `i64`.
+    Float(f64),
+    String(String),
+    Char(char),
+    /// Without the leading `:`.
+    Keyword(String),
+    Symbol(String),
+    List(Vec<Edn>),
+    Vector(Vec<Edn>),
+    Map(Vec<(Edn, Edn)>),
+    Set(Vec<Edn>),
+    /// `#tag value`, e.g. `#inst "..."` or a record like `#jepsen.history.Op{...}`.
+    Tagged(String, Box<Edn>),
+}
+
+impl Edn {
+    /// The value under keyword `key`, if this is a map that has one.
+    pub fn get(&self, key: &str) -> Option<&Edn> {
+        match self {
+            Edn::Map(entries) => entries.iter().find(|(k, _)| matches!(k, Edn::Keyword(k) if k == key)).map(|(_, v)| v),
+            Edn::Tagged(_, value) => value.get(key),
+            _ => None,
+        }
+    }
+
+    /// Follow keyword `keys` down through nested maps.
+    pub fn get_in(&self, keys: &[&str]) -> Option<&Edn> {
+        keys.iter().try_fold(self, |value, key| value.get(key))
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Edn::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Edn::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Any number, as a float.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Edn::Int(value) => Some(*value as f64),
+            Edn::Float(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The text of a string, keyword or symbol.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Edn::String(value) | Edn::Keyword(value) | Edn::Symbol(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The entries of a map.
+    pub fn as_map(&self) -> Option<&[(Edn, Edn)]> {
+        match self {
+            Edn::Map(entries) => Some(entries),
+            Edn::Tagged(_, value) => value.as_map(),
+            _ => None,
+        }
+    }
+
+    /// The elements of a list, vector or set.
+    pub fn as_seq(&self) -> Option<&[Edn]> {
+        match self {
+            Edn::List(values) | Edn::Vector(values) | Edn::Set(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnError {
+    /// Byte offset into the input.
+    pub offset: usize,
+    pub message: String,
+}
+
+impl Display for EdnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid EDN at byte {}: {}", self.offset, self.message)
+    }
+}
+
+impl std::error::Error for EdnError {}
+
+
+/// Parse a single EDN value (surrounding whitespace and comments are fine).
+pub fn parse(input: &str) -> Result<Edn, EdnError> {
+    let mut reader = Reader { input, position: 0 };
+    let value = reader.value()?;
+    reader.skip_whitespace();
+    match reader.position == input.len() {
+        true => Ok(value),
+        false => Err(reader.error("trailing input after value")),
+    }
+}
+
+
+struct Reader<'a> {
+    input: &'a str,
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn error(&self, message: impl Into<String>) -> EdnError {
+        EdnError { offset: self.position, message: message.into() }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.position..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    /// Whitespace, commas (which EDN treats as whitespace) and `;` comments.
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            match c {
+                ';' => while self.peek().is_some_and(|c| c != '\n') {
+                    self.bump();
+                },
+                c if c.is_whitespace() || c == ',' => { self.bump(); },
+                _ => break,
+            }
+        }
+    }
+
+    /// Everything up to the next delimiter.
+    fn token(&mut self) -> &str {
+        let start = self.position;
+        while self.peek().is_some_and(|c| !(c.is_whitespace() || "()[]{}\",;".contains(c))) {
+            self.bump();
+        }
+        &self.input[start..self.position]
+    }
+
+    fn value(&mut self) -> Result<Edn, EdnError> {
+        self.skip_whitespace();
+        let Some(c) = self.peek() else {
+            return Err(self.error("unexpected end of input"));
+        };
+        match c {
+            '(' => { self.bump(); Ok(Edn::List(self.values_until(')')?)) },
+            '[' => { self.bump(); Ok(Edn::Vector(self.values_until(']')?)) },
+            '{' => { self.bump(); self.map() },
+            '"' => { self.bump(); self.string() },
+            '\\' => { self.bump(); self.char() },
+            '#' => { self.bump(); self.dispatch() },
+            ':' => {
+                self.bump();
+                Ok(Edn::Keyword(self.token().to_owned()))
+            },
+            ')' | ']' | '}' => Err(self.error(format!("unexpected `{c}`"))),
+            _ => {
+                let start = self.position;
+                let token = self.token();
+                if token.is_empty() {
+                    return Err(self.error(format!("unexpected `{c}`")));
+                }
+                atom(token).ok_or_else(|| EdnError { offset: start, message: format!("can't read `{token}`") })
+            },
+        }
+    }
+
+    fn values_until(&mut self, close: char) -> Result<Vec<Edn>, EdnError> {
+        let mut values = vec![];
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(c) if c == close => {
+                    self.bump();
+                    return Ok(values);
+                },
+                None => return Err(self.error(format!("expected `{close}`"))),
+                _ => if let Some(value) = self.value_or_discard()? {
+                    values.push(value);
+                },
+            }
+        }
+    }
+
+    /// A value, or `None` if it was `#_`-discarded.
+    fn value_or_discard(&mut self) -> Result<Option<Edn>, EdnError> {
+        if self.input[self.position..].starts_with("#_") {
+            self.position += 2;
+            self.value()?;
+            return Ok(None);
+        }
+        self.value().map(Some)
+    }
+
+    fn map(&mut self) -> Result<Edn, EdnError> {
+        let values = self.values_until('}')?;
+        if values.len() % 2 != 0 {
+            return Err(self.error("map with an odd number of forms"));
+        }
+        let mut values = values.into_iter();
+        let mut entries = vec![];
+        while let (Some(key), Some(value)) = (values.next(), values.next()) {
+            entries.push((key, value));
+        }
+        Ok(Edn::Map(entries))
+    }
+
+    fn string(&mut self) -> Result<Edn, EdnError> {
+        let mut string = String::new();
+        loop {
+            match self.bump() {
+                None => return Err(self.error("unterminated string")),
+                Some('"') => return Ok(Edn::String(string)),
+                Some('\\') => string.push(match self.bump() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('u') => self.unicode_escape()?,
+                    Some(c @ ('"' | '\\')) => c,
+                    other => return Err(self.error(format!("unknown escape `\\{}`", other.unwrap_or(' ')))),
+                }),
+                Some(c) => string.push(c),
+            }
+        }
+    }
+
+    fn unicode_escape(&mut self) -> Result<char, EdnError> {
+        let hex = self.input.get(self.position..self.position + 4).ok_or_else(|| self.error("truncated \\u escape"))?;
+        let c = u32::from_str_radix(hex, 16).ok().and_then(char::from_u32).ok_or_else(|| self.error(format!("bad \\u escape `{hex}`")))?;
+        self.position += 4;
+        Ok(c)
+    }
+
+    fn char(&mut self) -> Result<Edn, EdnError> {
+        // A delimiter right after the backslash is the character itself, e.g. `\(`.
+        let Some(first) = self.bump() else {
+            return Err(self.error("expected a character"));
+        };
+        let name = format!("{first}{}", self.token());
+        let c = match name.as_str() {
+            "newline" => '\n',
+            "space" => ' ',
+            "tab" => '\t',
+            "return" => '\r',
+            "backspace" => '\u{8}',
+            "formfeed" => '\u{c}',
+            _ if name.chars().count() == 1 => first,
+            _ if name.starts_with('u') && name.len() == 5 => {
+                u32::from_str_radix(&name[1..], 16).ok().and_then(char::from_u32).ok_or_else(|| self.error(format!("bad character `\\{name}`")))?
+            },
+            _ => return Err(self.error(format!("unknown character `\\{name}`"))),
+        };
+        Ok(Edn::Char(c))
+    }
+
+    /// Whatever follows a `#`: a set, a discard, a symbolic value, or a tagged value.
+    fn dispatch(&mut self) -> Result<Edn, EdnError> {
+        match self.peek() {
+            Some('{') => {
+                self.bump();
+                Ok(Edn::Set(self.values_until('}')?))
+            },
+            Some('_') => {
+                self.bump();
+                self.value()?;
+                self.value()
+            },
+            Some('#') => {
+                self.bump();
+                match self.token().to_owned().as_str() {
+                    "Inf" => Ok(Edn::Float(f64::INFINITY)),
+                    "-Inf" => Ok(Edn::Float(f64::NEG_INFINITY)),
+                    "NaN" => Ok(Edn::Float(f64::NAN)),
+                    other => Err(self.error(format!("unknown symbolic value `##{other}`"))),
+                }
+            },
+            _ => {
+                let tag = self.token().to_owned();
+                if tag.is_empty() {
+                    return Err(self.error("expected a tag after `#`"));
+                }
+                Ok(Edn::Tagged(tag, Box::new(self.value()?)))
+            },
+        }
+    }
+}
+
+
+/// Read a token that isn't a collection, string, character or keyword.
+fn atom(token: &str) -> Option<Edn> {
+    match token {
+        "nil" => return Some(Edn::Nil),
+        "true" => return Some(Edn::Bool(true)),
+        "false" => return Some(Edn::Bool(false)),
+        _ => {},
+    }
+    let starts_numeric = token.starts_with(|c: char| c.is_ascii_digit())
+        || (token.len() > 1 && token.starts_with(['-', '+']) && token[1..].starts_with(|c: char| c.is_ascii_digit()));
+    if !starts_numeric {
+        return Some(Edn::Symbol(token.to_owned()));
+    }
+    if let Some((numerator, denominator)) = token.split_once('/') {
+        return Some(Edn::Float(numerator.parse::<f64>().ok()? / denominator.parse::<f64>().ok()?));
+    }
+    // Arbitrary precision suffixes: `12N` is an integer, `1.5M` a decimal.
+    if let Some(integer) = token.strip_suffix('N') {
+        return integer.parse().map(Edn::Int).or_else(|_| integer.parse().map(Edn::Float)).ok();
+    }
+    if let Some(decimal) = token.strip_suffix('M') {
+        return decimal.parse().map(Edn::Float).ok();
+    }
+    token.parse().map(Edn::Int).or_else(|_| token.parse().map(Edn::Float)).ok()
+}
@@ -0,0 +1,161 @@
+use std::{collections::VecDeque, fmt::Display, sync::{Mutex, OnceLock}, time::{Duration, Instant}};
+use crate::scheduler::{Job, JobHandle, Period, Scheduler};
+
+
+/// How far back [`MsgsPerOp`] looks, unless `MSGS_PER_OP_WINDOW_MS` says otherwise.
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// How many slices a window is kept in: the estimate forgets a slice at a time.
+const BUCKETS: u32 = 10;
+
+
+/// How many messages a node sent to other nodes for each client operation it was asked
+/// to do, over the last `window` (the number Gossip Glomers' efficiency targets are about).
+///
+/// It's one node's view: with client load spread evenly over the cluster, the cluster's
+/// own figure is close to the average of its nodes'.
+#[derive(Debug)]
+pub struct MsgsPerOp {
+    window: Duration,
+    /// When each slice started, and the ops and messages seen in it; oldest first.
+    buckets: VecDeque<(Instant, u64, u64)>,
+}
+
+impl MsgsPerOp {
+    pub fn new(window: Duration) -> Self {
+        Self { window, buckets: VecDeque::new() }
+    }
+
+    /// A client operation came in at `at`.
+    pub fn op(&mut self, at: Instant) {
+        self.bucket(at).1 += 1;
+    }
+
+    /// A message went out to another node at `at`.
+    pub fn message(&mut self, at: Instant) {
+        self.bucket(at).2 += 1;
+    }
+
+    /// The ops and messages seen in the window up to `now`.
+    pub fn estimate(&mut self, now: Instant) -> Estimate {
+        self.forget(now);
+        let (ops, messages) = self.buckets.iter().fold((0, 0), |(ops, messages), (_, o, m)| (ops + o, messages + m));
+        Estimate { ops, messages, window: self.window }
+    }
+
+    fn bucket(&mut self, at: Instant) -> &mut (Instant, u64, u64) {
+        self.forget(at);
+        let slice = self.window / BUCKETS;
+        if self.buckets.back().map_or(true, |(started, _, _)| at.saturating_duration_since(*started) >= slice) {
+            self.buckets.push_back((at, 0, 0));
+        }
+        self.buckets.back_mut().unwrap()
+    }
+
+    fn forget(&mut self, now: Instant) {
+        while self.buckets.front().is_some_and(|(started, _, _)| now.saturating_duration_since(*started) > self.window) {
+            self.buckets.pop_front();
+        }
+    }
+}
+
+
+/// What a [`MsgsPerOp`] has seen over one window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Estimate {
+    pub ops: u64,
+    pub messages: u64,
+    pub window: Duration,
+}
+
+impl Estimate {
+    /// `None` until there's been an op to divide by.
+    pub fn msgs_per_op(&self) -> Option<f64> {
+        (self.ops > 0).then(|| self.messages as f64 / self.ops as f64)
+    }
+}
+
+impl Display for Estimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.msgs_per_op() {
+            Some(msgs_per_op) => write!(f, "{msgs_per_op:.2}")?,
+            None => write!(f, "-")?,
+        }
+        write!(f, " (messages={} ops={} window={:?})", self.messages, self.ops, self.window)
+    }
+}
+
+
+/// The payload types that count as client operations; empty until [`count_client_ops`]
+/// (or `CLIENT_OPS`) says otherwise.
+static CLIENT_OPS: OnceLock<Vec<String>> = OnceLock::new();
+
+static ESTIMATOR: Mutex<Option<MsgsPerOp>> = Mutex::new(None);
+
+/// Estimate messages per operation, counting requests of these `types` (like
+/// `["broadcast", "read"]`) from clients as operations. Without a call to this, the
+/// comma-separated `CLIENT_OPS` environment variable decides, and nothing is estimated
+/// if that's unset too. Only the first call counts.
+pub fn count_client_ops(types: Vec<String>) {
+    CLIENT_OPS.set(types).ok();
+}
+
+fn client_ops() -> &'static [String] {
+    CLIENT_OPS.get_or_init(|| {
+        std::env::var("CLIENT_OPS")
+        .map(|types| types.split(',').map(str::trim).filter(|kind| !kind.is_empty()).map(str::to_owned).collect())
+        .unwrap_or_default()
+    })
+}
+
+/// Whether there's anything to estimate.
+pub fn enabled() -> bool {
+    !client_ops().is_empty()
+}
+
+fn with_estimator(f: impl FnOnce(&mut MsgsPerOp)) {
+    let mut estimator = ESTIMATOR.lock().unwrap();
+    f(estimator.get_or_insert_with(|| {
+        let window =
+            std::env::var("MSGS_PER_OP_WINDOW_MS")
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .filter(|ms| *ms > 0)
+            .map_or(WINDOW, Duration::from_millis);
+        MsgsPerOp::new(window)
+    }));
+}
+
+/// Note a message of type `kind` read from `source`.
+pub(crate) fn received(source: &str, kind: &str) {
+    if source.starts_with('c') && client_ops().iter().any(|op| op == kind) {
+        with_estimator(|estimator| estimator.op(Instant::now()));
+    }
+}
+
+/// Note a message written to `destination`.
+pub(crate) fn sent(destination: &str) {
+    if enabled() && destination.starts_with('n') {
+        with_estimator(|estimator| estimator.message(Instant::now()));
+    }
+}
+
+/// The estimate so far, if there's anything to estimate.
+pub fn estimate() -> Option<Estimate> {
+    if !enabled() {
+        return None;
+    }
+    let mut estimate = None;
+    with_estimator(|estimator| estimate = Some(estimator.estimate(Instant::now())));
+    estimate
+}
+
+/// Print the estimate to stderr (as `msgs-per-op: 12.34 (messages=... ops=... window=...)`)
+/// every `period`, in the background.
+pub fn log_every(period: Duration) -> JobHandle {
+    Scheduler::new().spawn(Job::new("msgs-per-op log", Period::Every(period)), |_| {
+        if let Some(estimate) = estimate() {
+            eprintln!("msgs-per-op: {estimate}");
+        }
+    })
+}
@@ -0,0 +1,167 @@
+use std::{fmt::Debug, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::Duration};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::broadcast;
+use tracing::{debug, info};
+use crate::{clock::{self, Clock}, kv::{KvClient, KvError}, lease::{FencingToken, Lease, LeaseError}, message::NodeId, metrics, scheduler::{Job, JobHandle, Period, Scheduler}, task};
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// The lin-kv key the lease is kept under.
+    pub key: String,
+    /// How long a lease lasts without being renewed.
+    pub lease: Duration,
+    /// How often the leader renews it, and everyone else checks whether it's run out.
+    pub renew_every: Duration,
+}
+
+impl Default for Config {
+    /// A one-second lease under `leader`, renewed three times a second or so.
+    fn default() -> Self {
+        Self { key: "leader".to_owned(), lease: Duration::from_secs(1), renew_every: Duration::from_millis(300) }
+    }
+}
+
+
+/// Who the leader is now, as far as this node can tell: `None` once the lease it knew
+/// about has run out with nobody taking it over yet. The term is the lease's
+/// [fencing token](FencingToken).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub leader: Option<NodeId>,
+    pub term: u64,
+}
+
+
+/// Elects a leader among the nodes by way of a [`Lease`] in lin-kv, for workloads that
+/// want one node coordinating without going as far as Raft: whoever holds the lease
+/// leads, and keeps it by renewing it well before it runs out (see [`run`](Self::run)).
+/// The term is the lease's fencing token, for the leader to fence what it does with.
+///
+/// Leadership changes go out to every [subscriber](Self::subscribe).
+#[derive(Debug)]
+pub struct Election<M> {
+    lease: Lease<M>,
+    renew_every: Duration,
+    /// What subscribers were last told.
+    announced: Mutex<Event>,
+    campaigning: AtomicBool,
+    events: broadcast::Sender<Event>,
+}
+
+impl<M> Election<M>
+where
+    M: Serialize + DeserializeOwned + Debug + Send + 'static,
+{
+    pub fn new(kv: KvClient<M>, node_id: &str, config: Config) -> Self {
+        Self::with_clock(kv, node_id, config, clock::system())
+    }
+
+    pub fn with_clock(kv: KvClient<M>, node_id: &str, config: Config, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            lease: Lease::with_clock(kv, &config.key, node_id, config.lease, clock),
+            renew_every: config.renew_every,
+            announced: Mutex::new(Event { leader: None, term: 0 }),
+            campaigning: AtomicBool::new(false),
+            events: broadcast::channel(16).0,
+        }
+    }
+
+    /// Leadership changes from here on, as [`campaign`](Self::campaign) finds them.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.lease.is_held()
+    }
+
+    /// The leader as of the last [`campaign`](Self::campaign), if there was one.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.announced.lock().unwrap().leader.clone()
+    }
+
+    pub fn term(&self) -> u64 {
+        self.announced.lock().unwrap().term
+    }
+
+    /// The token to fence what the leader does with, while this node is leader.
+    pub fn token(&self) -> Option<FencingToken> {
+        self.lease.token()
+    }
+
+    pub fn lease(&self) -> &Lease<M> {
+        &self.lease
+    }
+
+    /// One round of the election: renew the lease if this node holds it, take it if
+    /// there's none or it's run out, and otherwise just note who holds it. Returns
+    /// whether this node is leader afterwards. Losing a race for the lease to another
+    /// node isn't an error; it's just not being leader.
+    pub async fn campaign(&self) -> Result<bool, KvError> {
+        let result = self.lease.acquire().await;
+        let term = self.lease.grant().map_or(0, |grant| grant.token.0);
+        self.announce(Event { leader: self.lease.holder(), term });
+        match result {
+            Ok(_) => Ok(self.is_leader()),
+            Err(LeaseError::Kv(err)) => Err(err),
+            Err(err) => {
+                debug!(error = %err, "not leader");
+                Ok(false)
+            },
+        }
+    }
+
+    /// Give up the lease, if this node holds it, so another can take over straight away
+    /// rather than waiting for it to run out.
+    pub async fn resign(&self) -> Result<(), KvError> {
+        if !self.is_leader() {
+            return Ok(());
+        }
+        let result = self.lease.release().await;
+        let term = self.term();
+        self.announce(Event { leader: None, term });
+        match result {
+            Err(LeaseError::Kv(err)) => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Run a [`campaign`](Self::campaign) every [`renew_every`](Config::renew_every), as
+    /// the scheduler's "election" job, skipping a round if the last is still going.
+    pub fn run(self: &Arc<Self>, scheduler: &Scheduler) -> JobHandle
+    where
+        M: Sync,
+    {
+        let election = self.clone();
+        scheduler.spawn(Job::new("election", Period::Every(self.renew_every)), move |_| {
+            if election.campaigning.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            let election = election.clone();
+            task::spawn("campaign", async move {
+                if let Err(err) = election.campaign().await {
+                    debug!(error = %err, "couldn't reach lin-kv to campaign");
+                }
+                election.campaigning.store(false, Ordering::SeqCst);
+            });
+        })
+    }
+
+    fn announce(&self, event: Event) {
+        let mut announced = self.announced.lock().unwrap();
+        if *announced == event {
+            return;
+        }
+        match &event.leader {
+            Some(_) if self.is_leader() => {
+                info!(term = event.term, "elected leader");
+                metrics::global().increment("election.elected");
+            },
+            Some(leader) => info!(%leader, term = event.term, "following a new leader"),
+            None => info!(term = event.term, "no leader"),
+        }
+        *announced = event.clone();
+        let _ = self.events.send(event);
+    }
+}
@@ -0,0 +1,186 @@
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}, time::Duration};
+use tokio::{sync::broadcast, time::Instant};
+use tracing::info;
+use crate::{message::{self, NodeId}, scheduler::{Job, JobHandle, Period, Scheduler}};
+
+
+/// How many gaps between hearing from a peer its expected gap is averaged over.
+const HISTORY: usize = 100;
+
+
+/// What a [`FailureDetector`] makes of a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Status {
+    Up,
+    /// Quiet for longer than it usually is, but maybe just slow.
+    Suspect,
+    /// Quiet for so long it's taken for crashed, or cut off.
+    Down,
+}
+
+/// A peer's [`Status`] changing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub peer: NodeId,
+    pub from: Status,
+    pub to: Status,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// How often a quiet peer is pinged; peers are expected to be heard from at least
+    /// this often.
+    pub interval: Duration,
+    /// How suspicious (see [`FailureDetector::phi`]) a peer's silence has to get for it to
+    /// be [suspected](Status::Suspect).
+    pub suspect_phi: f64,
+    /// How suspicious a peer's silence has to get for it to be taken [down](Status::Down).
+    pub down_phi: f64,
+}
+
+impl Default for Config {
+    /// With pings every 100ms, a peer is suspected after about half a second of silence,
+    /// and taken down after about a second.
+    fn default() -> Self {
+        Self { interval: Duration::from_millis(100), suspect_phi: 2., down_phi: 4. }
+    }
+}
+
+
+#[derive(Debug)]
+struct Peer {
+    last_heard: Instant,
+    last_pinged: Option<Instant>,
+    /// The most recent gaps between hearing from it.
+    gaps: VecDeque<Duration>,
+    status: Status,
+}
+
+
+/// Keeps an up/suspect/down view of a node's peers, phi-accrual style: how suspicious a
+/// peer's silence is depends on how long it's usually quiet for, so a peer across a slow
+/// link isn't suspected as soon as one nearby would be. Anything heard from a peer counts
+/// ([`heard_from`](Self::heard_from)), and peers that have been quiet for a while are
+/// pinged (see [`run`]), so it works alongside gossip without adding to it.
+///
+/// Status changes go out to every [subscriber](Self::subscribe), for gossip to skip peers
+/// that are down, or replication to pick another one.
+#[derive(Debug)]
+pub struct FailureDetector {
+    config: Config,
+    peers: HashMap<NodeId, Peer>,
+    events: broadcast::Sender<Event>,
+}
+
+impl FailureDetector {
+    /// Every one of `peers` starts out up, as if just heard from at `now`.
+    pub fn new<I: IntoIterator<Item = NodeId>>(config: Config, peers: I, now: Instant) -> Self {
+        let peers = peers.into_iter().map(|peer| (peer, Peer { last_heard: now, last_pinged: None, gaps: VecDeque::new(), status: Status::Up })).collect();
+        Self { config, peers, events: broadcast::channel(64).0 }
+    }
+
+    /// Status changes from here on, as [`check`](Self::check) finds them.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Note hearing from `peer` at `now`: a reply to a ping, or anything else it sent.
+    /// It's up again, if it wasn't.
+    pub fn heard_from(&mut self, peer: &str, now: Instant) -> Option<Event> {
+        let state = self.peers.get_mut(peer)?;
+        if state.gaps.len() == HISTORY {
+            state.gaps.pop_front();
+        }
+        state.gaps.push_back(now.saturating_duration_since(state.last_heard));
+        state.last_heard = now;
+        self.transition(peer, Status::Up)
+    }
+
+    /// How suspicious `peer`'s silence is at `now`: how unlikely it is (-log10 of the
+    /// probability) that it would have been quiet for this long if it were fine, taking
+    /// the gaps between hearing from it to be exponentially distributed around their
+    /// average (or the ping interval, if that's longer). 1 means one chance in 10, 2 one
+    /// in 100, and so on.
+    pub fn phi(&self, peer: &str, now: Instant) -> Option<f64> {
+        let state = self.peers.get(peer)?;
+        let gaps = state.gaps.iter().sum::<Duration>().as_secs_f64() / state.gaps.len().max(1) as f64;
+        let expected = gaps.max(self.config.interval.as_secs_f64());
+        let silence = now.saturating_duration_since(state.last_heard).as_secs_f64();
+        Some(silence / expected * std::f64::consts::LOG10_E)
+    }
+
+    pub fn status(&self, peer: &str) -> Option<Status> {
+        self.peers.get(peer).map(|state| state.status)
+    }
+
+    /// The peers that are `status`.
+    pub fn peers(&self, status: Status) -> Vec<NodeId> {
+        self.peers.iter().filter(|(_, state)| state.status == status).map(|(peer, _)| peer.clone()).collect()
+    }
+
+    /// Go over how suspicious each peer is at `now`, and tell subscribers about the ones
+    /// whose status changed.
+    pub fn check(&mut self, now: Instant) -> Vec<Event> {
+        let peers: Vec<NodeId> = self.peers.keys().cloned().collect();
+        peers
+        .into_iter()
+        .filter_map(|peer| {
+            let phi = self.phi(&peer, now)?;
+            let status = match phi {
+                phi if phi >= self.config.down_phi => Status::Down,
+                phi if phi >= self.config.suspect_phi => Status::Suspect,
+                _ => Status::Up,
+            };
+            self.transition(&peer, status)
+        })
+        .collect()
+    }
+
+    /// The peers to ping at `now`: the ones not heard from, or pinged, for an interval.
+    pub fn to_ping(&mut self, now: Instant) -> Vec<NodeId> {
+        let interval = self.config.interval;
+        self.peers
+        .iter_mut()
+        .filter(|(_, state)| {
+            let last = state.last_pinged.map_or(state.last_heard, |pinged| pinged.max(state.last_heard));
+            now.saturating_duration_since(last) >= interval
+        })
+        .map(|(peer, state)| {
+            state.last_pinged = Some(now);
+            peer.clone()
+        })
+        .collect()
+    }
+
+    fn transition(&mut self, peer: &str, to: Status) -> Option<Event> {
+        let state = self.peers.get_mut(peer)?;
+        if state.status == to {
+            return None;
+        }
+        let event = Event { peer: message::intern(peer), from: state.status, to };
+        state.status = to;
+        info!(peer, from = ?event.from, to = ?event.to, "peer status changed");
+        // Nobody may be listening.
+        let _ = self.events.send(event.clone());
+        Some(event)
+    }
+}
+
+
+/// Every tick, as a `heartbeat` job on `scheduler`, ping whoever `detector` says is due
+/// (with `ping`, which sends whatever the workload's peers answer), then check on
+/// everyone. Replies and any other messages from peers go to
+/// [`FailureDetector::heard_from`] from the workload's handlers.
+pub fn heartbeat<F>(scheduler: &Scheduler, detector: Arc<Mutex<FailureDetector>>, mut ping: F) -> JobHandle
+where
+    F: FnMut(&NodeId) + Send + 'static,
+{
+    scheduler.spawn(Job::new("heartbeat", Period::TickRate), move |now| {
+        let mut detector = detector.lock().unwrap();
+        for peer in detector.to_ping(now) {
+            ping(&peer);
+        }
+        detector.check(now);
+    })
+}
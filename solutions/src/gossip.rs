@@ -0,0 +1,33 @@
+use rand::seq::SliceRandom;
+use crate::{cli::FanoutStrategy, tuning};
+
+
+/// How many peers a round of gossip reaches, out of the `peers` other nodes in the
+/// cluster: every one of them without a `fanout`, otherwise `fanout` of them or, as a
+/// [`FanoutStrategy::Percent`], that percentage of them rounded up (so never none).
+pub fn fanout(fanout: Option<usize>, strategy: FanoutStrategy, peers: usize) -> usize {
+    match (fanout, strategy) {
+        (None, _) => peers,
+        (Some(fanout), FanoutStrategy::Peers) => fanout.min(peers),
+        (Some(percent), FanoutStrategy::Percent) => (peers * percent.min(100)).div_ceil(100),
+    }
+}
+
+
+/// The peers this round of gossip goes to, out of the `candidates` that could use it, as
+/// the [`tuning::global`] fanout says: all of them without one, otherwise as many as
+/// [`fanout`] allows for a cluster of `peers` other nodes, picked at random so every
+/// candidate gets its turn. Counter and broadcast nodes both choose who to gossip with
+/// through here, so `--fanout` and `configure fanout=...` apply to either.
+pub fn choose<T>(mut candidates: Vec<T>, peers: usize) -> Vec<T> {
+    let tunables = tuning::global();
+    let Some(limit) = tunables.fanout() else {
+        return candidates;
+    };
+    let count = fanout(Some(limit), tunables.fanout_strategy(), peers);
+    if count < candidates.len() {
+        candidates.shuffle(&mut rand::thread_rng());
+        candidates.truncate(count);
+    }
+    candidates
+}
@@ -0,0 +1,111 @@
+use std::{collections::BTreeMap, hash::{Hash, Hasher}};
+use siphasher::sip::SipHasher13;
+use crate::message::{self, NodeId};
+
+
+/// How many points each node gets on a [`HashRing`] unless it's
+/// [told otherwise](HashRing::with_virtual_nodes): enough that a handful of nodes split
+/// the keys roughly evenly.
+pub const DEFAULT_VIRTUAL_NODES: usize = 64;
+
+
+/// Maps keys onto the nodes that own them, for workloads that shard their data across
+/// the cluster. Each node is hashed onto the ring at several points (its virtual nodes),
+/// and a key belongs to the first node at or after its own hash, so adding or removing
+/// a node only moves the keys next to its points. Every node builds the same ring from
+/// the same `node_ids`, without talking it over.
+#[derive(Debug, Clone, Default)]
+pub struct HashRing {
+    points: BTreeMap<u64, NodeId>,
+    nodes: Vec<NodeId>,
+    virtual_nodes: usize,
+}
+
+impl HashRing {
+    pub fn new(nodes: &[impl AsRef<str>]) -> Self {
+        Self::with_virtual_nodes(nodes, DEFAULT_VIRTUAL_NODES)
+    }
+
+    /// Panics if `virtual_nodes` is 0.
+    pub fn with_virtual_nodes(nodes: &[impl AsRef<str>], virtual_nodes: usize) -> Self {
+        assert!(virtual_nodes > 0, "every node needs at least one point on the ring");
+        let mut ring = Self { points: BTreeMap::new(), nodes: vec![], virtual_nodes };
+        for node in nodes {
+            ring.add(node.as_ref());
+        }
+        ring
+    }
+
+    /// Put `node` on the ring, if it isn't already.
+    pub fn add(&mut self, node: &str) {
+        if self.contains(node) {
+            return;
+        }
+        let node = message::intern(node);
+        for point in 0..self.virtual_nodes {
+            self.points.insert(hash(&(&*node, point)), node.clone());
+        }
+        self.nodes.push(node);
+    }
+
+    /// Take `node` off the ring; its keys go to whoever's next.
+    pub fn remove(&mut self, node: &str) {
+        self.points.retain(|_, owner| **owner != *node);
+        self.nodes.retain(|owner| **owner != *node);
+    }
+
+    pub fn contains(&self, node: &str) -> bool {
+        self.nodes.iter().any(|owner| **owner == *node)
+    }
+
+    /// The nodes on the ring, in the order they were added.
+    pub fn nodes(&self) -> &[NodeId] {
+        &self.nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// The node `key` belongs to; none if the ring is empty.
+    pub fn owner<K: Hash + ?Sized>(&self, key: &K) -> Option<&NodeId> {
+        self.clockwise(key).next()
+    }
+
+    /// The `replicas` nodes that keep a copy of `key`: its owner first, then the next
+    /// distinct nodes round the ring. Fewer if there aren't that many nodes.
+    pub fn owners<K: Hash + ?Sized>(&self, key: &K, replicas: usize) -> Vec<&NodeId> {
+        let mut owners: Vec<&NodeId> = Vec::with_capacity(replicas.min(self.nodes.len()));
+        for node in self.clockwise(key) {
+            if owners.len() == replicas.min(self.nodes.len()) {
+                break;
+            }
+            if !owners.contains(&node) {
+                owners.push(node);
+            }
+        }
+        owners
+    }
+
+    /// Every point's node, starting from `key`'s hash and going once round the ring.
+    fn clockwise<K: Hash + ?Sized>(&self, key: &K) -> impl Iterator<Item = &NodeId> {
+        let hash = hash(key);
+        self.points
+            .range(hash..)
+            .chain(self.points.range(..hash))
+            .map(|(_, node)| node)
+    }
+}
+
+/// SipHash-1-3 with zero keys, from a crate rather than std's `DefaultHasher`, whose
+/// algorithm may change between Rust releases: nodes built by different toolchains
+/// still have to agree on who owns a key.
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = SipHasher13::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
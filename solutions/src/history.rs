@@ -0,0 +1,242 @@
+use std::{collections::HashMap, fs::File, io::{self, BufRead, BufWriter, Write}, path::Path, sync::{Arc, Mutex}, time::Duration};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::Instant;
+
+
+/// The same four outcomes Jepsen distinguishes: an op is invoked, then completes as
+/// `Ok` (it happened), `Fail` (it definitely didn't) or `Info` (we can't tell).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpType {
+    Invoke,
+    Ok,
+    Fail,
+    Info,
+}
+
+impl OpType {
+    fn as_str(self) -> &'static str {
+        match self {
+            OpType::Invoke => "invoke",
+            OpType::Ok => "ok",
+            OpType::Fail => "fail",
+            OpType::Info => "info",
+        }
+    }
+}
+
+
+/// One client operation event, shaped like a Jepsen op so checkers written against
+/// Maelstrom's histories read the same way here: `f` names the operation (`broadcast`,
+/// `read`, `add`, ...) and `value` is whatever it carries (its result, on completion).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Op {
+    pub process: usize,
+    #[serde(rename = "type")]
+    pub kind: OpType,
+    pub f: String,
+    pub value: Value,
+    /// The node the operation was sent to.
+    pub node: String,
+    /// Since the start of the run.
+    pub time: Duration,
+}
+
+
+/// Every op event of a run, in the order they happened. Each process has at most one
+/// operation in flight, so an invocation is completed by the next event of its process.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct History {
+    pub ops: Vec<Op>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn push(&mut self, op: Op) {
+        self.ops.push(op);
+    }
+
+    /// Every invocation alongside its completion, if it ever completed.
+    pub fn pairs(&self) -> Vec<(&Op, Option<&Op>)> {
+        let mut pairs: Vec<(&Op, Option<&Op>)> = vec![];
+        let mut in_flight = HashMap::new();
+        for op in &self.ops {
+            match op.kind {
+                OpType::Invoke => {
+                    in_flight.insert(op.process, pairs.len());
+                    pairs.push((op, None));
+                },
+                _ => if let Some(index) = in_flight.remove(&op.process) {
+                    pairs[index].1 = Some(op);
+                },
+            }
+        }
+        pairs
+    }
+
+    /// Read a history saved by [`History::write_json`], one op per line.
+    pub fn read_json(reader: impl BufRead) -> io::Result<Self> {
+        let mut history = History::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            history.push(serde_json::from_str(&line)?);
+        }
+        Ok(history)
+    }
+
+    pub fn write_json(&self, mut writer: impl Write) -> io::Result<()> {
+        for op in &self.ops {
+            serde_json::to_writer(&mut writer, op)?;
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Write the history the way Jepsen writes `history.edn`: one op map per line,
+    /// with `:time` in nanoseconds, so elle, knossos and friends can analyze it.
+    /// A `txn`'s micro-ops are written `[:r 1 nil]`, with their `f` as a keyword too.
+    pub fn write_edn(&self, mut writer: impl Write) -> io::Result<()> {
+        for (index, op) in self.ops.iter().enumerate() {
+            let f = keyword(&op.f).unwrap_or_else(|| edn(&Value::from(op.f.as_str())));
+            let value = match op.f.as_str() {
+                "txn" => micro_ops_edn(&op.value),
+                _ => edn(&op.value),
+            };
+            writeln!(
+                writer,
+                "{{:index {index}, :type :{}, :f {f}, :value {value}, :process {}, :node {}, :time {}}}",
+                op.kind.as_str(),
+                op.process,
+                edn(&Value::from(op.node.as_str())),
+                op.time.as_nanos(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Save to `path` as EDN if it ends in `.edn`, and as JSON lines otherwise.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut writer = BufWriter::new(File::create(path)?);
+        match path.extension().is_some_and(|extension| extension == "edn") {
+            true => self.write_edn(&mut writer)?,
+            false => self.write_json(&mut writer)?,
+        }
+        writer.flush()
+    }
+}
+
+
+/// `:name`, if `name` can be written as an EDN keyword.
+fn keyword(name: &str) -> Option<String> {
+    let mut chars = name.chars();
+    let valid =
+        chars.next().is_some_and(|first| first.is_ascii_alphabetic() || "*+!-_?<>=".contains(first))
+        && chars.all(|c| c.is_ascii_alphanumeric() || "*+!-_?<>=.".contains(c));
+    valid.then(|| format!(":{name}"))
+}
+
+/// A JSON value as EDN. Object keys become keywords where they can.
+fn edn(value: &Value) -> String {
+    match value {
+        Value::Null => "nil".to_owned(),
+        Value::Bool(_) | Value::Number(_) => value.to_string(),
+        // Every escape serde_json emits (`\"`, `\n`, `\uXXXX`, ...) means the same in EDN.
+        Value::String(_) => value.to_string(),
+        Value::Array(values) => format!("[{}]", values.iter().map(edn).collect::<Vec<_>>().join(" ")),
+        Value::Object(entries) => {
+            let entries: Vec<_> =
+                entries
+                .iter()
+                .map(|(key, value)| format!("{} {}", keyword(key).unwrap_or_else(|| edn(&Value::from(key.as_str()))), edn(value)))
+                .collect();
+            format!("{{{}}}", entries.join(", "))
+        },
+    }
+}
+
+/// A transaction's micro-ops (`[["r", 1, null], ...]`) as EDN, with each one's `f` as
+/// a keyword. Anything that isn't shaped like a list of micro-ops is written as is.
+fn micro_ops_edn(value: &Value) -> String {
+    let Value::Array(ops) = value else {
+        return edn(value);
+    };
+    let ops: Vec<_> =
+        ops
+        .iter()
+        .map(|op| match op.as_array().and_then(|op| op.split_first()) {
+            Some((Value::String(f), args)) if keyword(f).is_some() => {
+                let op: Vec<_> = keyword(f).into_iter().chain(args.iter().map(edn)).collect();
+                format!("[{}]", op.join(" "))
+            },
+            _ => edn(op),
+        })
+        .collect();
+    format!("[{}]", ops.join(" "))
+}
+
+
+#[derive(Debug, Default)]
+struct Recording {
+    history: History,
+    /// The `f` and node of each process's operation in flight.
+    in_flight: HashMap<usize, (String, String)>,
+}
+
+
+/// Builds a [`History`] while a run is going, stamping every event with the time
+/// since the recorder was created (virtual time, under tokio's paused clock).
+/// Cheap to clone, so every client can hold its own handle.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    started: Instant,
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            recording: Default::default(),
+        }
+    }
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn invoke(&self, process: usize, node: &str, f: &str, value: Value) {
+        let mut recording = self.recording.lock().unwrap();
+        recording.in_flight.insert(process, (f.to_owned(), node.to_owned()));
+        recording.history.push(Op {
+            process,
+            kind: OpType::Invoke,
+            f: f.to_owned(),
+            value,
+            node: node.to_owned(),
+            time: self.started.elapsed(),
+        });
+    }
+
+    /// Complete the operation `process` has in flight. Does nothing if it has none.
+    pub fn complete(&self, process: usize, kind: OpType, value: Value) {
+        let mut recording = self.recording.lock().unwrap();
+        let Some((f, node)) = recording.in_flight.remove(&process) else {
+            return;
+        };
+        recording.history.push(Op { process, kind, f, value, node, time: self.started.elapsed() });
+    }
+
+    pub fn history(&self) -> History {
+        self.recording.lock().unwrap().history.clone()
+    }
+}
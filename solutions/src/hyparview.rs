@@ -0,0 +1,280 @@
+use std::time::Duration;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+use crate::{message::{self, NodeId}, metrics};
+
+
+/// How often a node shuffles its view with a peer's, and tries to fill its active view
+/// back up.
+pub const SHUFFLE_EVERY: Duration = Duration::from_secs(1);
+
+
+/// What nodes send each other to keep their [`HyParView`]s up. The workload wraps these
+/// in a message of its own, and hands them to [`receive`](HyParView::receive) as they
+/// come in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "view", rename_all = "snake_case")]
+pub enum Message {
+    /// "Take me in": from a node joining the cluster through this one.
+    Join,
+    /// A join, passed along a random walk of `ttl` more hops, for whoever it ends at to
+    /// take the joiner in.
+    ForwardJoin { joiner: String, ttl: usize },
+    /// "Be my neighbor": always taken up if `high_priority` (the asker has no neighbors
+    /// left), and otherwise only if there's room.
+    Neighbor { high_priority: bool },
+    NeighborReply { accepted: bool },
+    /// "I'm not your neighbor any more": to make room for another.
+    Disconnect,
+    /// Some of `origin`'s view, on a random walk of `ttl` more hops, to swap for some of
+    /// the view of whoever it ends at.
+    Shuffle { origin: String, nodes: Vec<String>, ttl: usize },
+    ShuffleReply { nodes: Vec<String> },
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// How many neighbors a node gossips with, at most.
+    pub active_size: usize,
+    /// How many more nodes it keeps in reserve, to replace neighbors that fail or leave.
+    pub passive_size: usize,
+    /// How many hops a join is passed along before whoever it's got to takes the joiner
+    /// in as a neighbor.
+    pub active_walk: usize,
+    /// How many hops from the end of its walk a join leaves the joiner in the passive
+    /// view of whoever it's got to.
+    pub passive_walk: usize,
+    /// How many of the active view go in a shuffle.
+    pub shuffle_active: usize,
+    /// How many of the passive view go in a shuffle.
+    pub shuffle_passive: usize,
+    /// How many hops a shuffle is passed along.
+    pub shuffle_walk: usize,
+}
+
+impl Default for Config {
+    /// Sized for a few dozen nodes: 4 neighbors (a bit over log2 of the cluster size),
+    /// and six times as many in reserve.
+    fn default() -> Self {
+        Self { active_size: 4, passive_size: 24, active_walk: 6, passive_walk: 3, shuffle_active: 3, shuffle_passive: 4, shuffle_walk: 4 }
+    }
+}
+
+
+/// A HyParView partial view of the cluster, for gossiping with a few neighbors (the
+/// active view) rather than every node, while the cluster stays connected. A node keeps
+/// a bigger passive view of others in reserve, and replaces a neighbor that fails (or
+/// disconnects to make room for another) with one of them. Links are symmetric: a
+/// neighbor has this node as a neighbor too, so gossip still reaches everyone.
+///
+/// New nodes [`join`](Self::join) through any node already in, whose neighbors pass the
+/// join along random walks, and every so often a node [shuffles](Self::tick) some of
+/// its view with a random node's, so passive views stay fresh.
+///
+/// Like [`Membership`](crate::membership::Membership), it only keeps the books: the
+/// workload sends the [`Message`]s it hands back (to whoever each is for), and tells it
+/// about those that come in.
+#[derive(Debug, Clone)]
+pub struct HyParView {
+    config: Config,
+    me: NodeId,
+    active: Vec<NodeId>,
+    passive: Vec<NodeId>,
+    /// Who was last asked to become a neighbor, until they answer (or the next tick).
+    asked: Option<NodeId>,
+    /// What went out in the last shuffle, the first to make room for what comes back.
+    shuffled: Vec<NodeId>,
+}
+
+impl HyParView {
+    /// `me`, with an empty active view and a random sample of `known` in reserve.
+    pub fn new<I: IntoIterator<Item = NodeId>>(config: Config, me: &str, known: I) -> Self {
+        let me = message::intern(me);
+        let mut passive: Vec<NodeId> = known.into_iter().filter(|node| *node != me).collect();
+        passive.shuffle(&mut rand::thread_rng());
+        passive.truncate(config.passive_size);
+        Self { config, me, active: vec![], passive, asked: None, shuffled: vec![] }
+    }
+
+    /// The neighbors to gossip with.
+    pub fn active(&self) -> &[NodeId] {
+        &self.active
+    }
+
+    pub fn passive(&self) -> &[NodeId] {
+        &self.passive
+    }
+
+    /// Join the cluster through `contact`.
+    pub fn join(&mut self, contact: &str) -> Vec<(NodeId, Message)> {
+        let contact = message::intern(contact);
+        let mut sends = self.add_active(&contact);
+        sends.push((contact, Message::Join));
+        sends
+    }
+
+    /// What to send on account of `message` from `from`.
+    pub fn receive(&mut self, from: &str, message: Message) -> Vec<(NodeId, Message)> {
+        let from = message::intern(from);
+        match message {
+            Message::Join => {
+                debug!(joiner = %from, "node joining through us");
+                let mut sends = self.add_active(&from);
+                let walk = self.config.active_walk;
+                sends.extend(self.active.iter().filter(|&neighbor| *neighbor != from).map(|neighbor| (neighbor.clone(), Message::ForwardJoin { joiner: from.to_string(), ttl: walk })));
+                sends
+            },
+            Message::ForwardJoin { joiner, ttl } => {
+                let joiner = message::intern(&joiner);
+                if joiner == self.me {
+                    return vec![];
+                }
+                if ttl == self.config.passive_walk {
+                    self.add_passive(&joiner);
+                }
+                match self.random_active(&[&from, &joiner]) {
+                    Some(next) if ttl > 0 && self.active.len() > 1 => vec![(next, Message::ForwardJoin { joiner: joiner.to_string(), ttl: ttl - 1 })],
+                    _ => {
+                        let mut sends = self.add_active(&joiner);
+                        sends.push((joiner, Message::Neighbor { high_priority: true }));
+                        sends
+                    },
+                }
+            },
+            Message::Neighbor { high_priority } => {
+                let accepted = high_priority || self.active.contains(&from) || self.active.len() < self.config.active_size;
+                let mut sends = if accepted { self.add_active(&from) } else { vec![] };
+                sends.push((from, Message::NeighborReply { accepted }));
+                sends
+            },
+            Message::NeighborReply { accepted } => {
+                if self.asked.as_ref() == Some(&from) {
+                    self.asked = None;
+                }
+                // If not, someone else is asked on the next tick.
+                if accepted { self.add_active(&from) } else { vec![] }
+            },
+            Message::Disconnect => {
+                self.active.retain(|neighbor| *neighbor != from);
+                self.add_passive(&from);
+                self.repair(&[&from])
+            },
+            Message::Shuffle { origin, nodes, ttl } => {
+                let origin = message::intern(&origin);
+                if origin == self.me {
+                    return vec![];
+                }
+                if let Some(next) = self.random_active(&[&from, &origin]).filter(|_| ttl > 1) {
+                    return vec![(next, Message::Shuffle { origin: origin.to_string(), nodes, ttl: ttl - 1 })];
+                }
+                let mut reply = self.passive.clone();
+                reply.shuffle(&mut rand::thread_rng());
+                reply.truncate(nodes.len());
+                self.shuffled = reply.clone();
+                self.integrate(nodes);
+                vec![(origin, Message::ShuffleReply { nodes: reply.iter().map(|node| node.to_string()).collect() })]
+            },
+            Message::ShuffleReply { nodes } => {
+                self.integrate(nodes);
+                vec![]
+            },
+        }
+    }
+
+    /// Shuffle some of the view with a random node's, and ask someone from the passive
+    /// view to fill a gap in the active one, if there is one. Whoever was asked last time
+    /// and hasn't answered is taken to not be going to.
+    pub fn tick(&mut self) -> Vec<(NodeId, Message)> {
+        self.asked = None;
+        let mut sends = self.repair(&[]);
+        let Some(peer) = self.random_active(&[]) else {
+            return sends;
+        };
+        let mut rng = rand::thread_rng();
+        let mut shuffled: Vec<NodeId> = self.active.choose_multiple(&mut rng, self.config.shuffle_active).filter(|&node| *node != peer).cloned().collect();
+        shuffled.extend(self.passive.choose_multiple(&mut rng, self.config.shuffle_passive).cloned());
+        self.shuffled = shuffled.clone();
+        let nodes = std::iter::once(self.me.to_string()).chain(shuffled.iter().map(|node| node.to_string())).collect();
+        sends.push((peer, Message::Shuffle { origin: self.me.to_string(), nodes, ttl: self.config.shuffle_walk }));
+        sends
+    }
+
+    /// Drop `peer` altogether (it's failed, or left the cluster), and ask someone to
+    /// take its place if it was a neighbor.
+    pub fn failed(&mut self, peer: &str) -> Vec<(NodeId, Message)> {
+        let was_active = self.active.iter().any(|neighbor| **neighbor == *peer);
+        self.active.retain(|neighbor| **neighbor != *peer);
+        self.passive.retain(|node| **node != *peer);
+        metrics::global().set("hyparview.active", self.active.len() as i64);
+        if !was_active {
+            return vec![];
+        }
+        info!(%peer, "neighbor failed, replacing it");
+        self.repair(&[])
+    }
+
+    /// Keep `node` in reserve, say, when it's just joined the cluster.
+    pub fn learn(&mut self, node: &str) {
+        self.add_passive(&message::intern(node));
+    }
+
+    /// Make `node` a neighbor, disconnecting a random one to make room if need be.
+    fn add_active(&mut self, node: &NodeId) -> Vec<(NodeId, Message)> {
+        if *node == self.me || self.active.contains(node) {
+            return vec![];
+        }
+        self.passive.retain(|other| other != node);
+        let mut sends = vec![];
+        if self.active.len() >= self.config.active_size {
+            if let Some(dropped) = self.random_active(&[]) {
+                self.active.retain(|neighbor| *neighbor != dropped);
+                self.add_passive(&dropped);
+                sends.push((dropped, Message::Disconnect));
+            }
+        }
+        self.active.push(node.clone());
+        metrics::global().set("hyparview.active", self.active.len() as i64);
+        sends
+    }
+
+    fn add_passive(&mut self, node: &NodeId) {
+        if *node == self.me || self.active.contains(node) || self.passive.contains(node) {
+            return;
+        }
+        if self.passive.len() >= self.config.passive_size {
+            let evicted = self.passive.iter().position(|other| self.shuffled.contains(other)).unwrap_or_else(|| rand::random::<usize>() % self.passive.len());
+            self.passive.swap_remove(evicted);
+        }
+        self.passive.push(node.clone());
+    }
+
+    /// Take `nodes` from a shuffle into the passive view, making room by dropping what
+    /// went out in the last one first.
+    fn integrate(&mut self, nodes: Vec<String>) {
+        for node in nodes {
+            self.add_passive(&message::intern(&node));
+        }
+        self.shuffled.clear();
+    }
+
+    /// Ask someone from the passive view (other than `except`) to become a neighbor, if
+    /// there's a gap in the active view and nobody's been asked already.
+    fn repair(&mut self, except: &[&NodeId]) -> Vec<(NodeId, Message)> {
+        if self.active.len() >= self.config.active_size || self.asked.is_some() {
+            return vec![];
+        }
+        let candidates: Vec<&NodeId> = self.passive.iter().filter(|node| !except.contains(node)).collect();
+        let Some(candidate) = candidates.choose(&mut rand::thread_rng()).map(|&node| node.clone()) else {
+            return vec![];
+        };
+        self.asked = Some(candidate.clone());
+        vec![(candidate, Message::Neighbor { high_priority: self.active.is_empty() })]
+    }
+
+    fn random_active(&self, except: &[&NodeId]) -> Option<NodeId> {
+        let candidates: Vec<&NodeId> = self.active.iter().filter(|neighbor| !except.contains(neighbor)).collect();
+        candidates.choose(&mut rand::thread_rng()).map(|&neighbor| neighbor.clone())
+    }
+}
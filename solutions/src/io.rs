@@ -1,26 +1,796 @@
-use std::{fmt::Debug, io::{stdin, stdout, BufRead, Write}};
+use std::{borrow::Cow, collections::{HashMap, VecDeque}, fmt::Debug, fs::File, io::{stdin, stdout, BufRead, Cursor, Read, Write}, path::Path, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex, OnceLock}, time::{Duration, Instant}};
 use tokio::{sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender}, task::JoinHandle};
-use tracing::{error, trace};
-use serde::{de::DeserializeOwned, Serialize};
+use tracing::{error, field, info, info_span, trace, warn, Span};
+use serde::{de::DeserializeOwned, ser::{Impossible, SerializeMap, SerializeStruct}, Deserialize, Serialize, Serializer};
+use serde_json::{json, Value};
+use crate::{clock, efficiency, message::{Envelope, ErrorCode}, metrics, rate_limit::{self, Backlog, RateLimiter}, shutdown, task, tuning, validate};
 
 
-pub fn io_channel<Message>() -> (UnboundedSender<Message>, UnboundedReceiver<Message>, JoinHandle<()>) 
+/// Parse one line of input into a message, the way [`io_channel`] does for every line
+/// on stdin. Surrounding whitespace (like the trailing newline) is ignored.
+///
+/// With the `simd-json` feature, lines are parsed with [simd-json](https://docs.rs/simd-json)
+/// first, and with serde_json only if that fails, so what doesn't parse is still reported
+/// (and [validated](crate::validate)) with serde_json's errors.
+pub fn parse_line<Message: DeserializeOwned>(line: &[u8]) -> serde_json::Result<Message> {
+    parse_line_with(line, &mut Vec::new())
+}
+
+/// [`parse_line`], with `scratch` to copy the line into for simd-json (which parses in
+/// place), so a reader going through many lines doesn't allocate one per line.
+pub fn parse_line_with<Message: DeserializeOwned>(line: &[u8], scratch: &mut Vec<u8>) -> serde_json::Result<Message> {
+    #[cfg(feature = "simd-json")]
+    {
+        scratch.clear();
+        scratch.extend_from_slice(line);
+        if let Ok(message) = simd_json::serde::from_slice(scratch) {
+            return Ok(message);
+        }
+    }
+    #[cfg(not(feature = "simd-json"))]
+    let _ = scratch;
+    serde_json::from_slice(line)
+}
+
+
+/// The span to handle `envelope` in. It records who sent the envelope, its `msg_id`
+/// (and what it answers, if it's a reply), which node is handling it, plus the span's own
+/// `id`: the RPCs sent while handling it are logged with that as their `parent`, and its
+/// replies go out with `in_reply_to` set to its `msg_id`, so one client operation can be
+/// followed from log line to log line, through every node it touches. With the `otel`
+/// feature, the span is also linked to the one that sent the envelope, if that was
+/// another node.
+pub fn handler_span<M>(envelope: &Envelope<M>) -> Span {
+    let span = info_span!(
+        "handle",
+        src = %envelope.source,
+        msg_id = envelope.body.msg_id,
+        in_reply_to = envelope.body.in_reply_to,
+        id = field::Empty,
+        node = %envelope.destination,
+    );
+    if let Some(id) = span.id() {
+        span.record("id", id.into_u64());
+    }
+    #[cfg(feature = "otel")]
+    if let Some(msg_id) = envelope.body.msg_id {
+        crate::otel::link(&span, &envelope.source, msg_id);
+    }
+    span
+}
+
+/// The id of the span we're in, if it's being recorded, to log as a `parent`.
+pub fn current_span_id() -> Option<u64> {
+    Span::current().id().map(|id| id.into_u64())
+}
+
+
+/// 1 in how many messages [`io_channel`] traces; `0` until [`sample_logs`] is called.
+static LOG_EVERY: AtomicUsize = AtomicUsize::new(0);
+
+/// Only write the per-message `trace!` lines (what was read and written) for 1 in every
+/// `every` messages each way, since at high throughput they cost more than the node's
+/// actual work. Warnings and errors are always logged. Without a call to this, the
+/// `LOG_SAMPLE` environment variable decides, and every message is traced if that's
+/// unset too.
+pub fn sample_logs(every: usize) {
+    LOG_EVERY.store(every.max(1), Ordering::Relaxed);
+}
+
+fn log_every() -> usize {
+    match LOG_EVERY.load(Ordering::Relaxed) {
+        0 => std::env::var("LOG_SAMPLE").ok().and_then(|every| every.parse().ok()).filter(|every| *every > 0).unwrap_or(1),
+        every => every,
+    }
+}
+
+/// Picks which messages get traced: the first, then every `every`th after it.
+#[derive(Debug)]
+struct Sampler {
+    every: usize,
+    seen: usize,
+}
+
+impl Sampler {
+    fn new(every: usize) -> Self {
+        Self { every, seen: 0 }
+    }
+
+    fn sample(&mut self) -> bool {
+        self.seen += 1;
+        (self.seen - 1) % self.every == 0
+    }
+}
+
+
+/// Who a message is from and to, its body `type`, and which message it is (or answers):
+/// what it's counted and timed by.
+#[derive(Debug, Default)]
+struct Header {
+    source: String,
+    destination: String,
+    /// `"?"` if there isn't one.
+    kind: String,
+    msg_id: Option<usize>,
+    in_reply_to: Option<usize>,
+}
+
+/// Just enough of a line read to tell whether it's for the io layer rather than the node:
+/// a `stats` or `configure` request, or a message a recording says went out (see
+/// [`record_to`]). Strings are only borrowed from the line when they have no escapes in
+/// them.
+#[derive(Deserialize)]
+struct Peek<'a> {
+    #[serde(borrow)]
+    src: Option<Cow<'a, str>>,
+    #[serde(borrow)]
+    dest: Option<Cow<'a, str>>,
+    #[serde(borrow)]
+    body: PeekBody<'a>,
+    #[serde(borrow)]
+    direction: Option<Cow<'a, str>>,
+}
+
+#[derive(Deserialize)]
+struct PeekBody<'a> {
+    #[serde(rename = "type", borrow)]
+    kind: Option<Cow<'a, str>>,
+    msg_id: Option<usize>,
+}
+
+impl Peek<'_> {
+    /// Peeks at a line only if it could be for the io layer, going by a scan for the
+    /// strings that would have to be in it. Most lines are for the node, and this
+    /// saves parsing them twice.
+    fn at(line: &[u8]) -> Option<Peek<'_>> {
+        let mentions = |needle: &[u8]| line.windows(needle.len()).any(|window| window == needle);
+        if !(mentions(b"\"stats\"") || mentions(b"\"configure\"") || mentions(b"\"direction\"")) {
+            return None;
+        }
+        serde_json::from_slice(line).ok()
+    }
+
+    fn kind(&self) -> Option<&str> {
+        self.body.kind.as_deref()
+    }
+
+    /// The header of a request the io layer answers itself.
+    fn request(&self) -> Header {
+        Header {
+            source: self.src.as_deref().unwrap_or_default().to_owned(),
+            destination: self.dest.as_deref().unwrap_or_default().to_owned(),
+            kind: self.kind().unwrap_or("?").to_owned(),
+            msg_id: self.body.msg_id,
+            in_reply_to: None,
+        }
+    }
+}
+
+/// The header of a message just parsed, or about to be written, read off it as serde
+/// walks it rather than out of its JSON. Of the body, only `type`, `msg_id` and
+/// `in_reply_to` are looked at; the rest is skipped.
+fn header_of<M: Serialize>(message: &M) -> Header {
+    let mut header = Header::default();
+    message.serialize(HeaderOf { header: &mut header, in_body: false }).ok();
+    if header.kind.is_empty() {
+        header.kind = "?".to_owned();
+    }
+    header
+}
+
+/// Serializes an envelope, or its body, into the [`Header`] it has.
+struct HeaderOf<'a> {
+    header: &'a mut Header,
+    in_body: bool,
+}
+
+impl HeaderOf<'_> {
+    fn field<T: Serialize + ?Sized>(&mut self, name: &str, value: &T) -> Result<(), serde_json::Error> {
+        match (self.in_body, name) {
+            (false, "src") => self.header.source = value.serialize(Scalar)?.text(),
+            (false, "dest") => self.header.destination = value.serialize(Scalar)?.text(),
+            (false, "body") => value.serialize(HeaderOf { header: self.header, in_body: true })?,
+            (true, "type") => self.header.kind = value.serialize(Scalar)?.text(),
+            (true, "msg_id") => self.header.msg_id = value.serialize(Scalar)?.number(),
+            (true, "in_reply_to") => self.header.in_reply_to = value.serialize(Scalar)?.number(),
+            _ => {},
+        }
+        Ok(())
+    }
+}
+
+/// A value in a [`Header`], as [`Scalar`] serializes it.
+enum ScalarValue {
+    Text(String),
+    Number(usize),
+    Other,
+}
+
+impl ScalarValue {
+    fn text(self) -> String {
+        match self {
+            ScalarValue::Text(text) => text,
+            _ => String::new(),
+        }
+    }
+
+    fn number(self) -> Option<usize> {
+        match self {
+            ScalarValue::Number(number) => Some(number),
+            _ => None,
+        }
+    }
+}
+
+/// Serializes a string or a number as itself, and anything else as
+/// [`ScalarValue::Other`] (or an error, if it has anything inside).
+struct Scalar;
+
+fn not_a_header(what: &str) -> serde_json::Error {
+    serde::ser::Error::custom(format!("a message's header has no {what}"))
+}
+
+/// Serializer methods that turn whatever's passed in into `$value`.
+macro_rules! serialize_as {
+    ($value:expr; $($method:ident($($arg:ident: $ty:ty),*)),* $(,)?) => {
+        $(fn $method(self, $($arg: $ty),*) -> Result<Self::Ok, Self::Error> {
+            $(let _ = $arg;)*
+            $value
+        })*
+    };
+}
+
+/// Serializer methods for compound values, which `$what` can't be.
+macro_rules! reject_compound {
+    ($what:literal) => {
+        fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> { Err(not_a_header($what)) }
+        fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Self::Error> { Err(not_a_header($what)) }
+        fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeTupleStruct, Self::Error> { Err(not_a_header($what)) }
+        fn serialize_tuple_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<Self::SerializeTupleVariant, Self::Error> { Err(not_a_header($what)) }
+        fn serialize_struct_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<Self::SerializeStructVariant, Self::Error> { Err(not_a_header($what)) }
+        fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _: &'static str, _: u32, _: &'static str, _: &T) -> Result<Self::Ok, Self::Error> { Err(not_a_header($what)) }
+    };
+}
+
+impl Serializer for Scalar {
+    type Ok = ScalarValue;
+    type Error = serde_json::Error;
+    type SerializeSeq = Impossible<ScalarValue, serde_json::Error>;
+    type SerializeTuple = Impossible<ScalarValue, serde_json::Error>;
+    type SerializeTupleStruct = Impossible<ScalarValue, serde_json::Error>;
+    type SerializeTupleVariant = Impossible<ScalarValue, serde_json::Error>;
+    type SerializeMap = Impossible<ScalarValue, serde_json::Error>;
+    type SerializeStruct = Impossible<ScalarValue, serde_json::Error>;
+    type SerializeStructVariant = Impossible<ScalarValue, serde_json::Error>;
+
+    serialize_as!(Ok(ScalarValue::Number(value as usize));
+        serialize_u8(value: u8), serialize_u16(value: u16), serialize_u32(value: u32),
+    );
+    // Ids are never negative.
+    serialize_as!(Ok(ScalarValue::Other);
+        serialize_i8(value: i8), serialize_i16(value: i16), serialize_i32(value: i32), serialize_i64(value: i64),
+        serialize_bool(value: bool), serialize_f32(value: f32), serialize_f64(value: f64), serialize_char(value: char),
+        serialize_bytes(value: &[u8]), serialize_none(), serialize_unit(), serialize_unit_struct(name: &'static str),
+    );
+    reject_compound!("lists or objects");
+
+    fn serialize_u64(self, value: u64) -> Result<ScalarValue, serde_json::Error> {
+        Ok(usize::try_from(value).map_or(ScalarValue::Other, ScalarValue::Number))
+    }
+
+    fn serialize_str(self, value: &str) -> Result<ScalarValue, serde_json::Error> {
+        Ok(ScalarValue::Text(value.to_owned()))
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str) -> Result<ScalarValue, serde_json::Error> {
+        Ok(ScalarValue::Text(variant.to_owned()))
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<ScalarValue, serde_json::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<ScalarValue, serde_json::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, serde_json::Error> {
+        Err(not_a_header("objects"))
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeStruct, serde_json::Error> {
+        Err(not_a_header("objects"))
+    }
+}
+
+impl<'a> Serializer for HeaderOf<'a> {
+    type Ok = ();
+    type Error = serde_json::Error;
+    type SerializeSeq = Impossible<(), serde_json::Error>;
+    type SerializeTuple = Impossible<(), serde_json::Error>;
+    type SerializeTupleStruct = Impossible<(), serde_json::Error>;
+    type SerializeTupleVariant = Impossible<(), serde_json::Error>;
+    type SerializeMap = HeaderFields<'a>;
+    type SerializeStruct = HeaderFields<'a>;
+    type SerializeStructVariant = Impossible<(), serde_json::Error>;
+
+    serialize_as!(Err(not_a_header("scalars"));
+        serialize_bool(value: bool), serialize_i8(value: i8), serialize_i16(value: i16), serialize_i32(value: i32),
+        serialize_i64(value: i64), serialize_u8(value: u8), serialize_u16(value: u16), serialize_u32(value: u32),
+        serialize_u64(value: u64), serialize_f32(value: f32), serialize_f64(value: f64), serialize_char(value: char),
+        serialize_str(value: &str), serialize_bytes(value: &[u8]), serialize_none(), serialize_unit(),
+        serialize_unit_struct(name: &'static str), serialize_unit_variant(name: &'static str, index: u32, variant: &'static str),
+    );
+    reject_compound!("lists");
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), serde_json::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<(), serde_json::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<HeaderFields<'a>, serde_json::Error> {
+        Ok(HeaderFields { of: self, key: None })
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<HeaderFields<'a>, serde_json::Error> {
+        Ok(HeaderFields { of: self, key: None })
+    }
+}
+
+/// The fields of an envelope, or its body (a map, since its message is flattened into it).
+struct HeaderFields<'a> {
+    of: HeaderOf<'a>,
+    /// The key of the entry whose value is next.
+    key: Option<String>,
+}
+
+impl SerializeStruct for HeaderFields<'_> {
+    type Ok = ();
+    type Error = serde_json::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, name: &'static str, value: &T) -> Result<(), serde_json::Error> {
+        self.of.field(name, value)
+    }
+
+    fn end(self) -> Result<(), serde_json::Error> {
+        Ok(())
+    }
+}
+
+impl SerializeMap for HeaderFields<'_> {
+    type Ok = ();
+    type Error = serde_json::Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), serde_json::Error> {
+        self.key = Some(key.serialize(Scalar)?.text());
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), serde_json::Error> {
+        let key = self.key.take().unwrap_or_default();
+        self.of.field(&key, value)
+    }
+
+    fn end(self) -> Result<(), serde_json::Error> {
+        Ok(())
+    }
+}
+
+
+/// The file [`io_channel`] records messages to, and when it started.
+static RECORDING: OnceLock<Mutex<(Instant, File)>> = OnceLock::new();
+
+/// Append every message read and written from now on to the file at `path`, one JSON
+/// object per line with when it was seen (`time`, in seconds) and which way it went
+/// (`direction`, `in` or `out`) next to its `src`, `dest` and `body`: what the proxy
+/// records, and what the sequence diagram reads. Only the first call counts.
+pub fn record_to(path: &Path) -> std::io::Result<()> {
+    let file = File::options().create(true).append(true).open(path)?;
+    RECORDING.set(Mutex::new((Instant::now(), file))).ok();
+    Ok(())
+}
+
+fn record(direction: &str, line: &[u8]) {
+    let Some(recording) = RECORDING.get() else {
+        return;
+    };
+    let Ok(Value::Object(mut record)) = serde_json::from_slice::<Value>(line) else {
+        return;
+    };
+    let (started, file) = &mut *recording.lock().unwrap();
+    record.insert("time".to_owned(), Value::from(started.elapsed().as_secs_f64()));
+    record.insert("direction".to_owned(), Value::from(direction));
+    if let Err(err) = writeln!(file, "{}", Value::Object(record)) {
+        warn!(error = ?err, "failed to record a message");
+    }
+}
+
+
+/// Where [`io_channel`] reads from and writes to instead of stdin and stdout, if anywhere.
+static INPUT: Mutex<Option<Box<dyn Read + Send>>> = Mutex::new(None);
+static OUTPUT: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+/// Read messages from the file at `path` rather than stdin, to replay a scenario (say,
+/// one captured from a Maelstrom run that crashed the node) as often as it takes, under
+/// a debugger or not. It's read like stdin: one JSON message per line. A recording from
+/// [`record_to`] (or the proxy) works too, since the messages it has going `out` are
+/// skipped. The node stops at the end of the file. Only the next [`io_channel`] reads it.
+pub fn read_from(path: &Path) -> std::io::Result<()> {
+    *INPUT.lock().unwrap() = Some(Box::new(File::open(path)?));
+    Ok(())
+}
+
+/// Write messages to the file at `path` (replacing what's in it) rather than stdout.
+pub fn write_to(path: &Path) -> std::io::Result<()> {
+    *OUTPUT.lock().unwrap() = Some(Box::new(File::create(path)?));
+    Ok(())
+}
+
+/// The loopback transport: the next [`io_channel`] reads what's written to the returned
+/// writer, and writes what the returned reader reads, instead of using stdin and stdout.
+/// Its node stops once the writer is dropped. This is how a node can talk to itself
+/// in-process, as in a [self-test](crate::self_test).
+pub fn loopback() -> (LoopbackWriter, LoopbackReader) {
+    let (requests, input) = pipe();
+    let (output, replies) = pipe();
+    *INPUT.lock().unwrap() = Some(Box::new(input));
+    *OUTPUT.lock().unwrap() = Some(Box::new(output));
+    (requests, replies)
+}
+
+/// An in-memory pipe: what's written to one end can be read from the other, which sees
+/// the end of its input once the writing end is dropped.
+fn pipe() -> (LoopbackWriter, LoopbackReader) {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    (LoopbackWriter(sender), LoopbackReader { receiver, pending: Cursor::new(vec![]) })
+}
+
+/// The writing end of one of [`loopback`]'s pipes.
+#[derive(Debug)]
+pub struct LoopbackWriter(std::sync::mpsc::Sender<Vec<u8>>);
+
+impl Write for LoopbackWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.send(buf.to_vec()).map_err(|_| std::io::ErrorKind::BrokenPipe)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The reading end of one of [`loopback`]'s pipes.
+#[derive(Debug)]
+pub struct LoopbackReader {
+    receiver: std::sync::mpsc::Receiver<Vec<u8>>,
+    /// What's left of the last chunk written.
+    pending: Cursor<Vec<u8>>,
+}
+
+impl Read for LoopbackReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pending.position() as usize == self.pending.get_ref().len() {
+            match self.receiver.recv() {
+                Ok(chunk) => self.pending = Cursor::new(chunk),
+                Err(_) => return Ok(0),
+            }
+        }
+        self.pending.read(buf)
+    }
+}
+
+
+/// How deep a queue can get before [`io_channel`] warns about it, unless
+/// `QUEUE_WARN_DEPTH` says otherwise.
+const QUEUE_WARN_DEPTH: usize = 1000;
+
+fn queue_warn_depth() -> usize {
+    std::env::var("QUEUE_WARN_DEPTH").ok().and_then(|depth| depth.parse().ok()).unwrap_or(QUEUE_WARN_DEPTH)
+}
+
+/// Keeps an eye on how many messages are waiting in one of the io channels: the depth
+/// and its high-water mark go in the `queue.<name>` and `queue.<name>.max` gauges, and
+/// once it's past the threshold, a warning is logged (and `warnings.queue.<name>`
+/// counted). It warns again only after the queue has drained to half the threshold.
+#[derive(Debug)]
+struct DepthMonitor {
+    name: &'static str,
+    threshold: usize,
+    max: usize,
+    backed_up: bool,
+}
+
+impl DepthMonitor {
+    fn new(name: &'static str, threshold: usize) -> Self {
+        Self { name, threshold, max: 0, backed_up: false }
+    }
+
+    fn observe(&mut self, depth: usize) {
+        metrics::global().set(&format!("queue.{}", self.name), depth as i64);
+        if depth > self.max {
+            self.max = depth;
+            metrics::global().set(&format!("queue.{}.max", self.name), depth as i64);
+        }
+        if depth > self.threshold && !self.backed_up {
+            self.backed_up = true;
+            metrics::global().increment(&format!("warnings.queue.{}", self.name));
+            warn!(queue = self.name, depth, threshold = self.threshold, "messages are piling up faster than they're handled");
+        } else if depth <= self.threshold / 2 && self.backed_up {
+            self.backed_up = false;
+            info!(queue = self.name, depth, "queue has caught up");
+        }
+    }
+}
+
+
+/// The node's end of [`io_channel`]'s inbound channel: a receiver that keeps track of how
+/// many messages are still waiting behind the one it hands out (in the `queue.stdin`
+/// gauge, warning when that gets too deep).
+#[derive(Debug)]
+pub struct Inbox<Message> {
+    receiver: UnboundedReceiver<Message>,
+    monitor: DepthMonitor,
+}
+
+impl<Message> Inbox<Message> {
+    pub async fn recv(&mut self) -> Option<Message> {
+        let message = self.receiver.recv().await;
+        self.monitor.observe(self.receiver.len());
+        message
+    }
+
+    /// How many messages are waiting.
+    pub fn len(&self) -> usize {
+        self.receiver.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.receiver.is_empty()
+    }
+}
+
+/// Any receiver (say, one from [`sim::Network`](crate::sim::Network)) can stand in for
+/// stdin, its depth reported as `queue.stdin` too.
+impl<Message> From<UnboundedReceiver<Message>> for Inbox<Message> {
+    fn from(receiver: UnboundedReceiver<Message>) -> Self {
+        Self { receiver, monitor: DepthMonitor::new("stdin", queue_warn_depth()) }
+    }
+}
+
+
+/// The `stats_ok` answering a `stats` request: every counter, gauge and latency summary
+/// in the global [`metrics`] registry, as `counters`, `gauges` and `latencies` objects.
+fn stats_reply(request: &Header) -> (Header, String) {
+    let mut body = metrics::global().snapshot().to_json();
+    body["type"] = Value::from("stats_ok");
+    admin_reply(request, body)
+}
+
+/// The reply to a `configure` request: a `configure_ok` with every [`tuning`] parameter
+/// as it now stands, or a `malformed-request` error (changing nothing) if the request
+/// asks for something that can't be done.
+fn configure_reply(request: &Header, line: &[u8]) -> (Header, String) {
+    let body = serde_json::from_slice::<Value>(line).ok().and_then(|envelope| envelope.get("body")?.as_object().cloned()).unwrap_or_default();
+    let reply = match tuning::global().configure(&body) {
+        Ok(()) => {
+            let mut reply = tuning::global().to_json();
+            info!(parameters = %reply, "reconfigured");
+            reply["type"] = Value::from("configure_ok");
+            reply
+        },
+        Err(err) => {
+            warn!(error = err, "ignoring a bad configure request");
+            json!({"type": "error", "code": ErrorCode::MalformedRequest.code(), "text": err})
+        },
+    };
+    admin_reply(request, reply)
+}
+
+/// The line answering `request` with `body`, and its header.
+fn admin_reply(request: &Header, mut body: Value) -> (Header, String) {
+    body["in_reply_to"] = Value::from(request.msg_id);
+    let header = Header {
+        source: request.destination.clone(),
+        destination: request.source.clone(),
+        kind: body["type"].as_str().unwrap_or("?").to_owned(),
+        msg_id: None,
+        in_reply_to: request.msg_id,
+    };
+    let line = json!({"src": request.destination, "dest": request.source, "body": body}).to_string();
+    (header, line)
+}
+
+
+/// Check a line read on input line `line_number` with the [`validate::global`] validator,
+/// if there is one.
+fn validated(line_number: usize, line: &[u8], parsed: Result<(), &serde_json::Error>) {
+    if let Some(validator) = validate::global() {
+        validator.lock().unwrap().received(line_number, line, parsed);
+    }
+}
+
+
+/// Requests waiting on a reply, keyed by who asked and the `msg_id` they asked with:
+/// when each was sent, and its `type`. Requests are also queued in the order they were
+/// made, so the ones that have waited longest are the first to look at when pruning.
+#[derive(Debug, Default)]
+struct InFlight {
+    waiting: HashMap<(String, usize), (Instant, String)>,
+    order: VecDeque<(Instant, (String, usize))>,
+}
+
+impl InFlight {
+    /// Requests that have waited this long are forgotten, so ones that are never
+    /// answered (gossip to a node that's down, say) don't pile up forever.
+    const GIVE_UP: Duration = Duration::from_secs(60);
+
+    /// Note a request from `asker`, if it is one (it has a `msg_id`, and answers nothing).
+    fn asked(&mut self, asker: &str, header: &Header) {
+        let (Some(msg_id), None) = (header.msg_id, header.in_reply_to) else {
+            return;
+        };
+        let now = Instant::now();
+        self.prune(now);
+        let key = (asker.to_owned(), msg_id);
+        self.waiting.insert(key.clone(), (now, header.kind.clone()));
+        self.order.push_back((now, key));
+    }
+
+    /// Pop requests off the front of the queue that have been answered (or asked again
+    /// since), or have waited too long, stopping at the first that's still waiting.
+    fn prune(&mut self, now: Instant) {
+        while let Some((sent, key)) = self.order.front() {
+            let waiting = matches!(self.waiting.get(key), Some((since, _)) if since == sent);
+            if waiting && now - *sent < Self::GIVE_UP {
+                break;
+            }
+            if waiting {
+                self.waiting.remove(key);
+            }
+            self.order.pop_front();
+        }
+    }
+
+    /// If the message answers a request `asker` made, record how long that took in
+    /// the `<histogram>.<request type>` histogram.
+    fn answered(&mut self, asker: &str, header: &Header, histogram: &str) {
+        let Some(in_reply_to) = header.in_reply_to else {
+            return;
+        };
+        if let Some((sent, kind)) = self.waiting.remove(&(asker.to_owned(), in_reply_to)) {
+            metrics::global().record(&format!("{histogram}.{kind}"), sent.elapsed());
+        }
+    }
+}
+
+
+/// Read messages from stdin and write them to stdout, one JSON line each, over a pair of
+/// channels. Every message in and out is counted by type in the global
+/// [`metrics`] registry (`messages_in.<type>`, `messages_out.<type>`), along with lines
+/// that couldn't be handled (`errors.*`), and how many messages are waiting on either side
+/// (`queue.stdin`, `queue.stdout`, and `queue.stdout.background` for
+/// [`io_channel_with_background`]), with a warning when either gets deeper than
+/// `QUEUE_WARN_DEPTH` (1000 by default).
+///
+/// Requests are timed too, per `type`: from reading one to writing its reply in
+/// `reply.<type>`, and from writing one of our own (to another node or a service) to
+/// reading its reply in `roundtrip.<type>`.
+///
+/// What's read and written is traced, for every message or a sample of them (see
+/// [`sample_logs`]).
+///
+/// A `stats` message, from anyone, never reaches the node: it's answered right here
+/// with a `stats_ok` holding a snapshot of the metrics (see [`stats_reply`]). Nor does a
+/// `configure` message, which changes the node's [`tuning`] parameters as it runs (see
+/// [`configure_reply`]).
+///
+/// With [`rate_limit::limit`], the writer sends no faster than the limits allow, waiting
+/// for them as it has to (which is counted in `rate_limit.waits`).
+///
+/// Stdin and stdout can be swapped for files with [`read_from`] and [`write_to`]. With
+/// [`validate::enable`], every line read is linted and nothing is written.
+///
+/// When the node is asked to [`shutdown`], the writer writes out every message already
+/// queued and stops.
+pub fn io_channel<Message>() -> (UnboundedSender<Message>, Inbox<Message>, JoinHandle<()>) 
+where Message: Serialize + DeserializeOwned + Debug + Sync + Send + 'static
+{
+    let (output_tx, _, inbox, handle) = io_channel_with_background();
+    (output_tx, inbox, handle)
+}
+
+/// [`io_channel`], with a second sender for bulk traffic nobody's waiting on (gossip, 
+/// periodic syncs and reads): anything queued on the first sender is written before
+/// anything queued on the second, so a client's reply doesn't wait its turn behind a
+/// round of gossip. The writer stops once the first sender (and its clones) are gone,
+/// whatever's left on the second.
+pub fn io_channel_with_background<Message>() -> (UnboundedSender<Message>, UnboundedSender<Message>, Inbox<Message>, JoinHandle<()>) 
 where Message: Serialize + DeserializeOwned + Debug + Sync + Send + 'static
 {
 
     let (input_tx, input_rx) = unbounded_channel();
+    // Requests others made of us, and ones we made of others.
+    let theirs = Arc::new(Mutex::new(InFlight::default()));
+    let ours = Arc::new(Mutex::new(InFlight::default()));
 
-    let read_handle = tokio::task::spawn(async move {
-        let mut lines = std::io::BufReader::new(stdin()).lines();
-        while let Some(Ok(line)) = lines.next() {
-            trace!(num_bytes = line.as_bytes().len(), line = ?line, "read line");
+    let (theirs_cp, ours_cp) = (theirs.clone(), ours.clone());
+    // Replies the io layer makes itself, straight to stdout.
+    let (admin_tx, mut admin_rx) = unbounded_channel::<(Header, String)>();
+    let every = log_every();
+    let (input, output) = (INPUT.lock().unwrap().take(), OUTPUT.lock().unwrap().take());
+    // Reading stdin blocks, so it gets a thread of its own rather than tying up one of
+    // the runtime's workers (along with any task it wakes, like the writer answering a
+    // `stats` request).
+    let read_handle = task::spawn_blocking("stdin reader", move || {
+        // Split on raw bytes rather than `lines()`, so a line that isn't valid UTF-8 is
+        // skipped like any other malformed line instead of ending the loop.
+        let input = input.unwrap_or_else(|| Box::new(stdin()));
+        let mut input = std::io::BufReader::new(input);
+        let mut sampler = Sampler::new(every);
+        let mut line_number = 0;
+        // One line at a time is read into (and parsed from) the same buffers.
+        let (mut line, mut scratch) = (Vec::new(), Vec::new());
+        loop {
+            line.clear();
+            match input.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {},
+            }
+            if line.last() == Some(&b'\n') {
+                line.pop();
+            }
+            line_number += 1;
+            let sampled = sampler.sample();
+            if sampled {
+                trace!(num_bytes = line.len(), line = %String::from_utf8_lossy(&line), "read line");
+            }
+            let peek = Peek::at(&line);
+            if peek.as_ref().is_some_and(|peek| peek.direction.as_deref() == Some("out")) {
+                if let Some(validator) = validate::global() {
+                    validator.lock().unwrap().sent(&line);
+                }
+                continue;
+            }
+            record("in", &line);
+            let kind = peek.as_ref().and_then(Peek::kind);
+            if let (Some(peek), Some("stats")) = (&peek, kind) {
+                validated(line_number, &line, Ok(()));
+                metrics::global().increment("messages_in.stats");
+                admin_tx.send(stats_reply(&peek.request())).ok();
+                continue;
+            }
+            if let (Some(peek), Some("configure")) = (&peek, kind) {
+                validated(line_number, &line, Ok(()));
+                metrics::global().increment("messages_in.configure");
+                admin_tx.send(configure_reply(&peek.request(), &line)).ok();
+                continue;
+            }
+            let parsed = parse_line_with(&line, &mut scratch);
+            validated(line_number, &line, parsed.as_ref().map(|_| ()));
+            // One bad line shouldn't take the whole node down with it.
             let Ok(message) = 
-                serde_json::from_str(&line)
-                .inspect_err(|err| {error!(error = ?err, "failed to deserialize line into message")}) 
+                parsed
+                .inspect_err(|err| {warn!(error = ?err, line = %String::from_utf8_lossy(&line), "skipping line that doesn't deserialize into a message")}) 
             else {
-                break;
+                metrics::global().increment("errors.unparseable_input");
+                continue;
             };
-            trace!(message = ?message, "read message");
+            #[cfg(feature = "otel")]
+            if crate::otel::enabled() {
+                crate::otel::received(&line);
+            }
+            let header = header_of(&message);
+            metrics::global().increment(&format!("messages_in.{}", header.kind));
+            efficiency::received(&header.source, &header.kind);
+            theirs_cp.lock().unwrap().asked(&header.source, &header);
+            ours_cp.lock().unwrap().answered(&header.destination, &header, "roundtrip");
+            if sampled {
+                trace!(message = ?message, "read message");
+            }
             if let Err(err) = input_tx.send(message) {
                 error!(message = ?err, error = ?err, "No receiver is interested in listening to stdin. Dropping message");
                 break;
@@ -29,25 +799,142 @@ where Message: Serialize + DeserializeOwned + Debug + Sync + Send + 'static
     });
 
     let (output_tx, mut output_rx) = unbounded_channel::<Message>();
+    let (background_tx, mut background_rx) = unbounded_channel::<Message>();
 
-    let write_handle = tokio::task::spawn(async move {
-        let mut stdout = std::io::BufWriter::new(stdout());
-        while let Some(message) = output_rx.recv().await {
-            trace!(message = ?message, "writing message");
-            let Ok(line) = 
-                serde_json::to_string(&message)
-                .inspect_err(|err| {error!(error = ?err, "failed to serialize message")}) 
-            else {
+    // Taken before the writer is spawned, so a shutdown can't come and go before it runs.
+    let cleaning_up = shutdown::Guard::new();
+    let write_handle = task::spawn("stdout writer", async move {
+        let output = output.unwrap_or_else(|| Box::new(stdout()));
+        let mut stdout = std::io::BufWriter::new(output);
+        let mut sampler = Sampler::new(every);
+        let mut monitor = DepthMonitor::new("stdout", queue_warn_depth());
+        let mut background_monitor = DepthMonitor::new("stdout.background", queue_warn_depth());
+        // Validating, nothing's sent to hold back.
+        let mut limiter = rate_limit::global().filter(|_| validate::global().is_none()).map(RateLimiter::new);
+        // Lines over the limit wait here, rather than hold up lines to anyone else.
+        let mut held_back = Backlog::new();
+        // Every line is put together in here, so writing one doesn't allocate.
+        let mut line = Vec::new();
+        // The header's taken from the message itself, before it's written into `line`.
+        let to_line = |message: Message, sampled: bool, line: &mut Vec<u8>| {
+            if sampled {
+                trace!(message = ?message, "writing message");
+            }
+            let header = header_of(&message);
+            line.clear();
+            serde_json::to_writer(&mut *line, &message)
+            .inspect_err(|err| {error!(error = ?err, "failed to serialize message")})
+            .inspect_err(|_| metrics::global().increment("errors.unserializable_output"))
+            .ok()?;
+            #[cfg(feature = "otel")]
+            if crate::otel::enabled() {
+                crate::otel::inject(line);
+            }
+            Some(header)
+        };
+        // Once the node is asked to stop, what's already queued is written out before
+        // the writer stops, so nothing is cut off mid-line or left behind.
+        let _cleaning_up = cleaning_up;
+        let mut stopping = false;
+        // The node's done sending, but some of what it sent is still held back.
+        let mut output_closed = false;
+        loop {
+            if output_closed && held_back.is_empty() {
                 break;
+            }
+            let sampled = sampler.sample();
+            // Whether the limiter already let this line through, having held it back.
+            let mut released = false;
+            let mut header = if stopping {
+                match admin_rx.try_recv() {
+                    Ok((header, admin)) => {
+                        line.clear();
+                        line.extend_from_slice(admin.as_bytes());
+                        header
+                    },
+                    Err(_) => {
+                        let Ok(message) = output_rx.try_recv().or_else(|_| background_rx.try_recv()) else {
+                            break;
+                        };
+                        let Some(header) = to_line(message, sampled, &mut line) else {
+                            break;
+                        };
+                        header
+                    },
+                }
+            } else {
+                let next_due = held_back.next_due();
+                let until_due = next_due.map(|due| due.saturating_duration_since(Instant::now())).unwrap_or_default();
+                // In order of priority: lines that were held back and whose turn has come,
+                // the node's own messages (mostly replies), then the io layer's, then bulk
+                // traffic.
+                tokio::select! {
+                    biased;
+                    () = clock::system().sleep(until_due), if next_due.is_some() => {
+                        let Some((header, held)) = limiter.as_mut().and_then(|limiter| held_back.pop(limiter, Instant::now())) else {
+                            continue;
+                        };
+                        line = held;
+                        released = true;
+                        header
+                    },
+                    message = output_rx.recv(), if !output_closed => {
+                        let Some(message) = message else {
+                            output_closed = true;
+                            continue;
+                        };
+                        monitor.observe(output_rx.len());
+                        let Some(header) = to_line(message, sampled, &mut line) else {
+                            break;
+                        };
+                        header
+                    },
+                    Some((header, admin)) = admin_rx.recv() => {
+                        line.clear();
+                        line.extend_from_slice(admin.as_bytes());
+                        header
+                    },
+                    Some(message) = background_rx.recv() => {
+                        background_monitor.observe(background_rx.len());
+                        let Some(header) = to_line(message, sampled, &mut line) else {
+                            break;
+                        };
+                        header
+                    },
+                    // Like anything still queued, what's held back goes out at the limit.
+                    () = shutdown::requested(), if held_back.is_empty() => {
+                        stopping = true;
+                        continue;
+                    },
+                }
             };
-            let bytes = line.as_bytes();
-            trace!(num_bytes = bytes.len(), line = ?line, "writing line");
-            if let Err(err) = stdout.write_all(bytes) {
-                error!(message = ?err, error = ?err, "failed to write to stdout");
-                break;
+            // Once the node's stopping, what's left goes out as fast as it can.
+            if let (Some(limiter), false, false) = (&mut limiter, stopping, released) {
+                let destination = header.destination.clone();
+                match held_back.offer(limiter, &destination, (header, std::mem::take(&mut line)), Instant::now()) {
+                    Some((allowed_header, allowed)) => (header, line) = (allowed_header, allowed),
+                    None => {
+                        metrics::global().increment("rate_limit.waits");
+                        continue;
+                    },
+                }
+            }
+            record("out", &line);
+            metrics::global().increment(&format!("messages_out.{}", header.kind));
+            efficiency::sent(&header.destination);
+            theirs.lock().unwrap().answered(&header.destination, &header, "reply");
+            ours.lock().unwrap().asked(&header.source, &header);
+            // Validating, the node's replies are checked against, never written.
+            if let Some(validator) = validate::global() {
+                validator.lock().unwrap().sent(&line);
+                continue;
             }
-            if let Err(err) = stdout.write_all(b"\n") {
-                error!(message = ?err, error = ?err, "failed to write newline to stdout");
+            if sampled {
+                trace!(dest = header.destination, msg_id = header.msg_id, in_reply_to = header.in_reply_to, num_bytes = line.len(), line = %String::from_utf8_lossy(&line), "writing line");
+            }
+            line.push(b'\n');
+            if let Err(err) = stdout.write_all(&line) {
+                error!(message = ?err, error = ?err, "failed to write to stdout");
                 break;
             }
             
@@ -57,11 +944,11 @@ where Message: Serialize + DeserializeOwned + Debug + Sync + Send + 'static
         }
     });
 
-    let joined_handle = tokio::task::spawn(async move {
+    let joined_handle = task::spawn("io", async move {
         let (read_result, write_result) = tokio::join!(read_handle, write_handle);
         read_result.unwrap();
         write_result.unwrap();
     });
 
-    (output_tx, input_rx, joined_handle)
+    (output_tx, background_tx, input_rx.into(), joined_handle)
 }
\ No newline at end of file
@@ -0,0 +1,186 @@
+use std::{fmt::Debug, time::Duration};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use crate::{message::ErrorCode, rpc::{RpcClient, RpcError}};
+
+
+/// The bodies Maelstrom's key-value and timestamp-oracle services speak.
+///
+/// A node's own payload enum only needs variants with the same names and fields
+/// for [`KvClient`] to work with it; messages are converted through serde.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum KvPayload {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        create_if_not_exists: Option<bool>,
+    },
+    CasOk,
+    Ts,
+    TsOk {
+        ts: u64,
+    },
+    Error {
+        code: usize,
+        #[serde(default)]
+        text: String,
+    },
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvError {
+    Rpc(RpcError),
+    /// The service answered with an `error` body.
+    Service { code: usize, text: String },
+    /// The service's reply didn't fit the node's payload type, or vice versa.
+    Codec(String),
+    /// Stopped trying after too many timeouts or lost races, not knowing whether what
+    /// was tried took effect.
+    GaveUp(String),
+}
+
+impl KvError {
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            KvError::Service { code, .. } => ErrorCode::from_code(*code),
+            KvError::Rpc(RpcError::Timeout) | KvError::GaveUp(_) => Some(ErrorCode::Timeout),
+            KvError::Rpc(RpcError::CircuitOpen) => Some(ErrorCode::TemporarilyUnavailable),
+            _ => None,
+        }
+    }
+
+    pub fn is(&self, code: ErrorCode) -> bool {
+        self.code() == Some(code)
+    }
+}
+
+impl std::fmt::Display for KvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KvError::Rpc(err) => write!(f, "{err}"),
+            KvError::Service { code, text } => write!(f, "[{code}] {text}"),
+            KvError::Codec(err) => write!(f, "codec error: {err}"),
+            KvError::GaveUp(reason) => write!(f, "gave up: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+impl From<RpcError> for KvError {
+    fn from(err: RpcError) -> Self {
+        KvError::Rpc(err)
+    }
+}
+
+
+fn transcode<A: Serialize, B: DeserializeOwned>(value: A) -> Result<B, KvError> {
+    serde_json::to_value(value)
+        .and_then(serde_json::from_value)
+        .map_err(|err| KvError::Codec(err.to_string()))
+}
+
+
+/// A client for one of Maelstrom's KV services (`lin-kv`, `seq-kv`, `lww-kv`) or the
+/// `lin-tso` timestamp oracle.
+#[derive(Debug)]
+pub struct KvClient<M> {
+    service: String,
+    rpc: RpcClient<M>,
+    timeout: Duration,
+}
+
+impl<M> Clone for KvClient<M> {
+    fn clone(&self) -> Self {
+        Self { service: self.service.clone(), rpc: self.rpc.clone(), timeout: self.timeout }
+    }
+}
+
+
+impl<M> KvClient<M>
+where
+    M: Serialize + DeserializeOwned + Debug,
+{
+    pub fn new(service: &str, rpc: RpcClient<M>, timeout: Duration) -> Self {
+        Self { service: service.to_owned(), rpc, timeout }
+    }
+
+    pub fn lin_kv(rpc: RpcClient<M>, timeout: Duration) -> Self {
+        Self::new("lin-kv", rpc, timeout)
+    }
+
+    pub fn seq_kv(rpc: RpcClient<M>, timeout: Duration) -> Self {
+        Self::new("seq-kv", rpc, timeout)
+    }
+
+    pub fn lin_tso(rpc: RpcClient<M>, timeout: Duration) -> Self {
+        Self::new("lin-tso", rpc, timeout)
+    }
+
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    async fn request(&self, request: KvPayload) -> Result<KvPayload, KvError> {
+        let message: M = transcode(request)?;
+        let reply = self.rpc.call(&self.service, message, self.timeout).await?;
+        match transcode(reply.body.message)? {
+            KvPayload::Error { code, text } => Err(KvError::Service { code, text }),
+            payload => Ok(payload),
+        }
+    }
+
+    /// Read `key`, or `None` if it doesn't exist yet.
+    pub async fn read<K: Serialize, V: DeserializeOwned>(&self, key: K) -> Result<Option<V>, KvError> {
+        let key = transcode(key)?;
+        match self.request(KvPayload::Read { key }).await {
+            Ok(KvPayload::ReadOk { value }) => transcode(value).map(Some),
+            Ok(other) => Err(KvError::Codec(format!("unexpected reply to read: {other:?}"))),
+            Err(err) if err.is(ErrorCode::KeyDoesNotExist) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub async fn write<K: Serialize, V: Serialize>(&self, key: K, value: V) -> Result<(), KvError> {
+        let (key, value) = (transcode(key)?, transcode(value)?);
+        match self.request(KvPayload::Write { key, value }).await? {
+            KvPayload::WriteOk => Ok(()),
+            other => Err(KvError::Codec(format!("unexpected reply to write: {other:?}"))),
+        }
+    }
+
+    /// Atomically replace `from` with `to`. Fails with [`ErrorCode::PreconditionFailed`]
+    /// if the current value isn't `from`, or [`ErrorCode::KeyDoesNotExist`] if there's no
+    /// value at all and `create_if_not_exists` is off.
+    pub async fn cas<K: Serialize, V: Serialize>(&self, key: K, from: V, to: V, create_if_not_exists: bool) -> Result<(), KvError> {
+        let (key, from, to) = (transcode(key)?, transcode(from)?, transcode(to)?);
+        let create_if_not_exists = create_if_not_exists.then_some(true);
+        match self.request(KvPayload::Cas { key, from, to, create_if_not_exists }).await? {
+            KvPayload::CasOk => Ok(()),
+            other => Err(KvError::Codec(format!("unexpected reply to cas: {other:?}"))),
+        }
+    }
+
+    /// Ask a timestamp oracle for a fresh, strictly increasing timestamp.
+    pub async fn ts(&self) -> Result<u64, KvError> {
+        match self.request(KvPayload::Ts).await? {
+            KvPayload::TsOk { ts } => Ok(ts),
+            other => Err(KvError::Codec(format!("unexpected reply to ts: {other:?}"))),
+        }
+    }
+}
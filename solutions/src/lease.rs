@@ -0,0 +1,300 @@
+use std::{fmt::{self, Debug}, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::debug;
+use crate::{clock::{self, Clock}, kv::{KvClient, KvError}, message::{self, ErrorCode, NodeId}, metrics};
+
+
+/// Goes up every time a [`Lease`] changes hands, so whatever the holder does with it can
+/// be told apart from what an earlier holder (who may not know it's been deposed yet)
+/// does: see [`Fence`] and [`write_fenced`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FencingToken(pub u64);
+
+impl FencingToken {
+    pub fn next(self) -> Self {
+        Self(self.0 + 1)
+    }
+}
+
+impl fmt::Display for FencingToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+
+/// What's kept under a lease's key: who holds it, with which token, and until when.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Grant {
+    pub holder: String,
+    pub token: FencingToken,
+    /// When it runs out, in milliseconds since the Unix epoch (Maelstrom runs every node
+    /// on the same machine, so they all agree on the time).
+    pub expires_at: u64,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LeaseError {
+    /// Someone else holds the lease, or got to it first.
+    Taken,
+    /// This node doesn't hold the lease (any more).
+    NotHeld,
+    /// The operation carried an older token than one already seen.
+    Stale { token: FencingToken, newer: FencingToken },
+    Kv(KvError),
+}
+
+impl fmt::Display for LeaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LeaseError::Taken => write!(f, "the lease is held by someone else"),
+            LeaseError::NotHeld => write!(f, "the lease isn't held here"),
+            LeaseError::Stale { token, newer } => write!(f, "fencing token {token} is stale (seen {newer})"),
+            LeaseError::Kv(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for LeaseError {}
+
+impl From<KvError> for LeaseError {
+    fn from(err: KvError) -> Self {
+        LeaseError::Kv(err)
+    }
+}
+
+
+#[derive(Debug)]
+struct Held {
+    /// What the lease was last seen (or written) as.
+    grant: Option<Grant>,
+    /// Until when this node holds it, by its own clock, if it does.
+    held_until: Option<Instant>,
+}
+
+
+/// A lease on a lin-kv key: at most one node holds it at a time, for `duration` at a
+/// time unless it's [renewed](Self::renew). Whoever [CASes](KvClient::cas) in a
+/// [`Grant`] when there's none (or the last one has run out) holds it, with a
+/// [`FencingToken`] one higher than the last holder's. The holder counts itself as
+/// holding it until a tenth of `duration` before it runs out, allowing for a renewal
+/// that's slow to come back, so two nodes never both think they hold it.
+#[derive(Debug)]
+pub struct Lease<M> {
+    kv: KvClient<M>,
+    key: String,
+    node_id: NodeId,
+    duration: Duration,
+    clock: Arc<dyn Clock>,
+    /// The wall-clock time, in milliseconds since the epoch, at `started` on `clock`.
+    epoch_ms: u64,
+    started: Instant,
+    held: Mutex<Held>,
+}
+
+impl<M> Lease<M>
+where
+    M: Serialize + DeserializeOwned + Debug,
+{
+    /// A lease under `key`, for `node_id` to hold for `duration` at a time.
+    pub fn new(kv: KvClient<M>, key: &str, node_id: &str, duration: Duration) -> Self {
+        Self::with_clock(kv, key, node_id, duration, clock::system())
+    }
+
+    pub fn with_clock(kv: KvClient<M>, key: &str, node_id: &str, duration: Duration, clock: Arc<dyn Clock>) -> Self {
+        let epoch_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        Self {
+            kv,
+            key: key.to_owned(),
+            node_id: message::intern(node_id),
+            duration,
+            started: clock.now(),
+            clock,
+            epoch_ms,
+            held: Mutex::new(Held { grant: None, held_until: None }),
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Take the lease if there's no holder (or the last one's has run out), or renew it
+    /// if it's already held here. Fails with [`LeaseError::Taken`] if another node has
+    /// it.
+    pub async fn acquire(&self) -> Result<FencingToken, LeaseError> {
+        let sent = self.clock.now();
+        let current: Option<Grant> = self.kv.read(&self.key).await?;
+        let token = match &current {
+            Some(grant) if *grant.holder == *self.node_id => grant.token,
+            Some(grant) if grant.expires_at > self.wall_ms(sent) => {
+                self.held.lock().unwrap().grant = current;
+                return Err(LeaseError::Taken);
+            },
+            Some(grant) => grant.token.next(),
+            None => FencingToken(1),
+        };
+        self.write(sent, current, token).await.map_err(|err| if err.is(ErrorCode::PreconditionFailed) { LeaseError::Taken } else { err.into() })
+    }
+
+    /// Hold on to the lease for another `duration`, keeping the same token. Fails with
+    /// [`LeaseError::NotHeld`] if this node hasn't got it, or has lost it.
+    pub async fn renew(&self) -> Result<FencingToken, LeaseError> {
+        let sent = self.clock.now();
+        let current = self.held.lock().unwrap().grant.clone().filter(|grant| *grant.holder == *self.node_id).ok_or(LeaseError::NotHeld)?;
+        let token = current.token;
+        self.write(sent, Some(current), token).await.map_err(|err| if err.is(ErrorCode::PreconditionFailed) { LeaseError::NotHeld } else { err.into() })
+    }
+
+    /// Give the lease up, if it's held here, so another node can take it straight away
+    /// rather than waiting for it to run out.
+    pub async fn release(&self) -> Result<(), LeaseError> {
+        let grant = {
+            let mut held = self.held.lock().unwrap();
+            held.held_until = None;
+            held.grant.clone().filter(|grant| *grant.holder == *self.node_id)
+        };
+        let Some(grant) = grant else {
+            return Ok(());
+        };
+        let released = Grant { expires_at: 0, ..grant.clone() };
+        self.kv.cas(&self.key, Some(&grant), Some(&released), false).await?;
+        self.held.lock().unwrap().grant = Some(released);
+        Ok(())
+    }
+
+    /// CAS `current` to a grant of `token` to this node, running `duration` from `sent`.
+    async fn write(&self, sent: Instant, current: Option<Grant>, token: FencingToken) -> Result<FencingToken, KvError> {
+        let next = Grant { holder: self.node_id.to_string(), token, expires_at: self.wall_ms(sent) + self.duration.as_millis() as u64 };
+        let written = self.kv.cas(&self.key, current.as_ref(), Some(&next), current.is_none()).await;
+        let mut held = self.held.lock().unwrap();
+        match written {
+            Ok(()) => {
+                held.held_until = Some(sent + self.duration.mul_f64(0.9));
+                held.grant = Some(next);
+                Ok(token)
+            },
+            Err(err) => {
+                if err.is(ErrorCode::PreconditionFailed) {
+                    held.held_until = None;
+                }
+                held.grant = current;
+                Err(err)
+            },
+        }
+    }
+
+    /// The token to fence operations with, while the lease is held here.
+    pub fn token(&self) -> Option<FencingToken> {
+        let now = self.clock.now();
+        let held = self.held.lock().unwrap();
+        held.held_until.filter(|until| now < *until).and(held.grant.as_ref().map(|grant| grant.token))
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.token().is_some()
+    }
+
+    /// Who holds the lease, as of when it was last looked at, if anyone does still.
+    pub fn holder(&self) -> Option<NodeId> {
+        if self.is_held() {
+            return Some(self.node_id.clone());
+        }
+        let now = self.wall_ms(self.clock.now());
+        let held = self.held.lock().unwrap();
+        held.grant.as_ref().filter(|grant| *grant.holder != *self.node_id && grant.expires_at > now).map(|grant| message::intern(&grant.holder))
+    }
+
+    /// The grant as it was last seen.
+    pub fn grant(&self) -> Option<Grant> {
+        self.held.lock().unwrap().grant.clone()
+    }
+
+    /// [`write_fenced`] `value` under `key`, with this lease's token.
+    pub async fn write_fenced<K, V>(&self, key: K, value: V) -> Result<(), LeaseError>
+    where
+        K: Serialize,
+        V: Serialize + DeserializeOwned,
+    {
+        let token = self.token().ok_or(LeaseError::NotHeld)?;
+        write_fenced(&self.kv, key, value, token).await
+    }
+
+    fn wall_ms(&self, at: Instant) -> u64 {
+        self.epoch_ms + at.saturating_duration_since(self.started).as_millis() as u64
+    }
+}
+
+
+/// Turns away operations carrying an older [`FencingToken`] than the newest it's seen,
+/// for whatever a lease holder sends its orders to: once a new holder has been heard
+/// from, the old one's orders (delayed, or sent before it noticed it had been deposed)
+/// are [`LeaseError::Stale`].
+#[derive(Debug, Clone, Default)]
+pub struct Fence {
+    newest: Option<FencingToken>,
+}
+
+impl Fence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Let an operation with `token` through, if it's at least as new as any seen so far.
+    pub fn check(&mut self, token: FencingToken) -> Result<(), LeaseError> {
+        match self.newest {
+            Some(newer) if token < newer => {
+                metrics::global().increment("lease.stale_rejected");
+                Err(LeaseError::Stale { token, newer })
+            },
+            _ => {
+                self.newest = Some(token);
+                Ok(())
+            },
+        }
+    }
+
+    pub fn newest(&self) -> Option<FencingToken> {
+        self.newest
+    }
+}
+
+
+/// A value written with [`write_fenced`], along with the token it was written with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fenced<V> {
+    pub token: FencingToken,
+    pub value: V,
+}
+
+/// Write `value` under `key`, as a [`Fenced`] value, unless it was last written with a
+/// newer token than `token`: lin-kv itself acting as the [`Fence`], so a deposed
+/// holder's writes can't land on top of its successor's.
+pub async fn write_fenced<M, K, V>(kv: &KvClient<M>, key: K, value: V, token: FencingToken) -> Result<(), LeaseError>
+where
+    M: Serialize + DeserializeOwned + Debug,
+    K: Serialize,
+    V: Serialize + DeserializeOwned,
+{
+    let next = Fenced { token, value };
+    loop {
+        let current: Option<Fenced<V>> = kv.read(&key).await?;
+        if let Some(newer) = current.as_ref().map(|current| current.token).filter(|newer| token < *newer) {
+            debug!(%token, %newer, "not writing with a stale fencing token");
+            metrics::global().increment("lease.stale_rejected");
+            return Err(LeaseError::Stale { token, newer });
+        }
+        match kv.cas(&key, current.as_ref(), Some(&next), current.is_none()).await {
+            Ok(()) => return Ok(()),
+            Err(err) if err.is(ErrorCode::PreconditionFailed) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
@@ -1,2 +1,61 @@
+pub mod cli;
 pub mod message;
-pub mod io;
\ No newline at end of file
+pub mod io;
+pub mod metrics;
+pub mod efficiency;
+pub mod tuning;
+pub mod clock;
+pub mod scheduler;
+pub mod gossip;
+pub mod topology;
+pub mod hash_ring;
+pub mod rate_limit;
+pub mod backoff;
+pub mod rtt;
+pub mod deadline;
+pub mod failure_detector;
+pub mod membership;
+pub mod hyparview;
+pub mod task;
+pub mod shutdown;
+pub mod validate;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod storage;
+pub mod outbox;
+pub mod reliable;
+pub mod routing;
+pub mod log;
+#[cfg(feature = "txn")]
+pub mod mvcc;
+pub mod two_phase_commit;
+pub mod rpc;
+pub mod circuit_breaker;
+pub mod kv;
+pub mod vector_clock;
+pub mod anti_entropy;
+pub mod lease;
+pub mod election;
+#[cfg(feature = "txn")]
+pub mod txn;
+#[cfg(feature = "txn")]
+pub mod percolator;
+#[cfg(feature = "txn")]
+pub mod lock_manager;
+pub mod sim;
+pub mod history;
+pub mod checker;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod edn;
+pub mod results;
+pub mod session;
+pub mod diagram;
+pub mod repl;
+pub mod loadgen;
+pub mod conformance;
+pub mod self_test;
+pub mod proxy;
+pub mod chaos;
+pub mod workloads;
+pub mod scaffold;
@@ -0,0 +1,167 @@
+use std::{collections::BTreeMap, fmt::Display, str::FromStr, time::Duration};
+use rand::Rng;
+use serde_json::{json, Value};
+use crate::checker::Percentiles;
+
+
+/// A kind of client request the load generator knows how to make up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Op {
+    Echo,
+    Generate,
+    Broadcast,
+    Read,
+    Add,
+    Send,
+    Poll,
+    Txn,
+}
+
+impl Op {
+    pub const ALL: [Op; 8] = [Op::Echo, Op::Generate, Op::Broadcast, Op::Read, Op::Add, Op::Send, Op::Poll, Op::Txn];
+
+    /// The message `type` it's sent as.
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Echo => "echo",
+            Op::Generate => "generate",
+            Op::Broadcast => "broadcast",
+            Op::Read => "read",
+            Op::Add => "add",
+            Op::Send => "send",
+            Op::Poll => "poll",
+            Op::Txn => "txn",
+        }
+    }
+
+    /// A request body for the `seq`th request, the way a Maelstrom workload would fill it
+    /// in: broadcast/send values are unique, keys come from a small set so they collide.
+    pub fn body(self, rng: &mut impl Rng, seq: u64) -> Value {
+        let mut body = match self {
+            Op::Echo => json!({"echo": format!("load {seq}")}),
+            Op::Generate | Op::Read => json!({}),
+            Op::Broadcast => json!({"message": seq}),
+            Op::Add => json!({"delta": rng.gen_range(1..=10)}),
+            Op::Send => json!({"key": format!("k{}", rng.gen_range(0..5)), "msg": seq}),
+            Op::Poll => json!({"offsets": {format!("k{}", rng.gen_range(0..5)): 0}}),
+            Op::Txn => json!({"txn": [["r", rng.gen_range(0..10), null], ["w", rng.gen_range(0..10), seq]]}),
+        };
+        body["type"] = Value::from(self.name());
+        body
+    }
+}
+
+impl FromStr for Op {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Op::ALL.into_iter().find(|op| op.name() == name).ok_or_else(|| {
+            format!("unknown op `{name}` (expected one of {})", Op::ALL.map(Op::name).join(", "))
+        })
+    }
+}
+
+
+/// How often each op is picked, relative to the others. Written as `broadcast=3,read`
+/// (a missing weight is 1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mix {
+    weights: Vec<(Op, u32)>,
+}
+
+impl Mix {
+    pub fn contains(&self, op: Op) -> bool {
+        self.weights.iter().any(|(other, _)| *other == op)
+    }
+
+    pub fn pick(&self, rng: &mut impl Rng) -> Op {
+        let total: u32 = self.weights.iter().map(|(_, weight)| weight).sum();
+        let mut roll = rng.gen_range(0..total);
+        for (op, weight) in &self.weights {
+            if roll < *weight {
+                return *op;
+            }
+            roll -= weight;
+        }
+        unreachable!("roll is below the total weight")
+    }
+}
+
+impl FromStr for Mix {
+    type Err = String;
+
+    fn from_str(mix: &str) -> Result<Self, Self::Err> {
+        let mut weights = vec![];
+        for entry in mix.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (op, weight) = match entry.split_once('=') {
+                Some((op, weight)) => (op, weight.parse().map_err(|err| format!("bad weight for `{op}`: {err}"))?),
+                None => (entry, 1),
+            };
+            if weight > 0 {
+                weights.push((op.parse()?, weight));
+            }
+        }
+        match weights.is_empty() {
+            true => Err("the mix needs at least one op with a non-zero weight".to_owned()),
+            false => Ok(Self { weights }),
+        }
+    }
+}
+
+
+/// How the requests of one kind fared.
+#[derive(Debug, Clone, Default)]
+pub struct OpStats {
+    pub sent: usize,
+    pub ok: usize,
+    /// Answered with an `error` body.
+    pub errors: usize,
+    /// Not answered within the timeout.
+    pub timeouts: usize,
+    /// Round trip times of the answered requests (errors included).
+    pub latencies: Vec<Duration>,
+}
+
+
+/// What a load generator run adds up to.
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    pub ops: BTreeMap<Op, OpStats>,
+    pub elapsed: Duration,
+    /// Messages the nodes sent each other.
+    pub server_messages: usize,
+    /// Messages to anyone but a node or the client (e.g. `lin-kv`), which nobody answers.
+    pub undeliverable: BTreeMap<String, usize>,
+}
+
+impl Summary {
+    pub fn sent(&self) -> usize {
+        self.ops.values().map(|stats| stats.sent).sum()
+    }
+
+    pub fn timeouts(&self) -> usize {
+        self.ops.values().map(|stats| stats.timeouts).sum()
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let seconds = self.elapsed.as_secs_f64();
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.;
+        writeln!(f, "{} requests in {seconds:.1}s ({:.1}/s)", self.sent(), self.sent() as f64 / seconds.max(f64::EPSILON))?;
+        writeln!(f, "{:<10} {:>7} {:>7} {:>7} {:>8} {:>9} {:>9} {:>9} {:>9}", "op", "sent", "ok", "error", "timeout", "p50 ms", "p95 ms", "p99 ms", "max ms")?;
+        for (op, stats) in &self.ops {
+            write!(f, "{:<10} {:>7} {:>7} {:>7} {:>8}", op.name(), stats.sent, stats.ok, stats.errors, stats.timeouts)?;
+            match Percentiles::from_samples(stats.latencies.clone()) {
+                Some(p) => writeln!(f, " {:>9.2} {:>9.2} {:>9.2} {:>9.2}", ms(p.p50), ms(p.p95), ms(p.p99), ms(p.max))?,
+                None => writeln!(f, " {:>9} {:>9} {:>9} {:>9}", "-", "-", "-", "-")?,
+            }
+        }
+        let per_op = self.server_messages as f64 / self.sent().max(1) as f64;
+        writeln!(f, "{} messages between nodes ({per_op:.2} per request)", self.server_messages)?;
+        for (destination, count) in &self.undeliverable {
+            writeln!(f, "{count} message(s) to `{destination}` went unanswered (not simulated)")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, fmt::{Debug, Display}, hash::Hash, sync::Mutex};
+use tokio::sync::oneshot;
+use tracing::debug;
+
+
+/// Identifies a transaction to the lock manager, unless it's given ids of its own.
+/// Larger ids are younger, which decides who gets sacrificed when a deadlock is found.
+pub type TxnId = u64;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+impl LockMode {
+    fn compatible_with(self, other: LockMode) -> bool {
+        self == LockMode::Shared && other == LockMode::Shared
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockError<T = TxnId> {
+    /// Granting this request would complete a cycle in the waits-for graph and this
+    /// transaction was picked as the victim. It must release its locks and abort.
+    Deadlock { victim: T },
+    /// The transaction's locks were released while it waited, so the request will never
+    /// be granted. Whoever released them has given up on the transaction.
+    Released { txn: T },
+}
+
+impl<T: Display> Display for LockError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::Deadlock { victim } => write!(f, "txn {victim} aborted to break a deadlock"),
+            LockError::Released { txn } => write!(f, "txn {txn} was released while waiting for a lock"),
+        }
+    }
+}
+
+impl<T: Debug + Display> std::error::Error for LockError<T> {}
+
+
+#[derive(Debug)]
+pub enum Acquire<T = TxnId> {
+    Granted,
+    /// Resolves once the lock is granted, or with an error if we're chosen as a deadlock
+    /// victim. Dropped unanswered if the transaction is released while it waits.
+    Wait(oneshot::Receiver<Result<(), LockError<T>>>),
+}
+
+
+#[derive(Debug)]
+struct Waiter<T> {
+    txn: T,
+    mode: LockMode,
+    granted: oneshot::Sender<Result<(), LockError<T>>>,
+}
+
+
+#[derive(Debug)]
+struct KeyLock<T> {
+    holders: HashMap<T, LockMode>,
+    queue: VecDeque<Waiter<T>>,
+}
+
+impl<T> Default for KeyLock<T> {
+    fn default() -> Self {
+        Self { holders: HashMap::new(), queue: VecDeque::new() }
+    }
+}
+
+impl<T: Copy + Eq + Hash> KeyLock<T> {
+    fn can_grant(&self, txn: T, mode: LockMode) -> bool {
+        self.holders
+            .iter()
+            .filter(|(&holder, _)| holder != txn)
+            .all(|(_, &held)| mode.compatible_with(held))
+    }
+
+    /// Who `txn` has to wait for before it could get `mode`: incompatible holders, plus
+    /// everyone queued ahead of it since the queue is strictly FIFO.
+    fn blockers(&self, txn: T, mode: LockMode) -> Vec<T> {
+        let holders =
+            self.holders
+            .iter()
+            .filter(|(&holder, &held)| holder != txn && !mode.compatible_with(held))
+            .map(|(&holder, _)| holder);
+        let ahead =
+            self.queue
+            .iter()
+            .take_while(|waiter| waiter.txn != txn)
+            .map(|waiter| waiter.txn);
+        holders.chain(ahead).collect()
+    }
+}
+
+
+/// Per-key shared/exclusive locks for pessimistic (two-phase locking) transactions.
+///
+/// Requests that can't be granted right away wait in a FIFO queue per key. Every time
+/// a transaction starts waiting, the waits-for graph is checked for a cycle; if there
+/// is one, the youngest transaction in it is aborted with [`LockError::Deadlock`].
+#[derive(Debug)]
+pub struct LockManager<K, T = TxnId> {
+    locks: HashMap<K, KeyLock<T>>,
+    /// The key each blocked transaction is queued on.
+    waiting_on: HashMap<T, K>,
+    /// Every key each transaction holds a lock on, so they can all be released at once.
+    held: HashMap<T, HashSet<K>>,
+}
+
+impl<K, T> Default for LockManager<K, T> {
+    fn default() -> Self {
+        Self {
+            locks: HashMap::new(),
+            waiting_on: HashMap::new(),
+            held: HashMap::new(),
+        }
+    }
+}
+
+
+impl<K: Clone + Eq + Hash + Debug, T: Copy + Ord + Hash + Debug> LockManager<K, T> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn acquire(&mut self, txn: T, key: K, mode: LockMode) -> Result<Acquire<T>, LockError<T>> {
+        let lock = self.locks.entry(key.clone()).or_default();
+
+        match lock.holders.get(&txn) {
+            Some(LockMode::Exclusive) => return Ok(Acquire::Granted),
+            Some(LockMode::Shared) if mode == LockMode::Shared => return Ok(Acquire::Granted),
+            _ => {},
+        }
+
+        // Upgrades jump the queue, since they already hold the key in shared mode and
+        // anyone queued behind them would have to wait for them anyway.
+        let is_upgrade = lock.holders.contains_key(&txn);
+        if (lock.queue.is_empty() || is_upgrade) && lock.can_grant(txn, mode) {
+            lock.holders.insert(txn, mode);
+            self.held.entry(txn).or_default().insert(key);
+            return Ok(Acquire::Granted);
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let waiter = Waiter { txn, mode, granted: tx };
+        match is_upgrade {
+            true => lock.queue.push_front(waiter),
+            false => lock.queue.push_back(waiter),
+        }
+        self.waiting_on.insert(txn, key.clone());
+
+        // Waiting here may close several cycles at once, so keep going until none are left.
+        while let Some(cycle) = self.find_cycle(txn) {
+            let victim = *cycle.iter().max().unwrap();
+            debug!(?cycle, ?victim, "found deadlock");
+            let err = LockError::Deadlock { victim };
+            if victim == txn {
+                self.dequeue(txn);
+                return Err(err);
+            }
+            if let Some(waiter) = self.dequeue(victim) {
+                let _ = waiter.granted.send(Err(err));
+            }
+        }
+        Ok(Acquire::Wait(rx))
+    }
+
+    /// Release every lock `txn` holds (and give up any request it's waiting on), then
+    /// grant whatever that unblocks.
+    pub fn release_all(&mut self, txn: T) {
+        self.dequeue(txn);
+        for key in self.held.remove(&txn).unwrap_or_default() {
+            if let Some(lock) = self.locks.get_mut(&key) {
+                lock.holders.remove(&txn);
+            }
+            self.grant_waiters(&key);
+        }
+    }
+
+    pub fn holders(&self, key: &K) -> Vec<(T, LockMode)> {
+        self.locks
+            .get(key)
+            .map(|lock| lock.holders.iter().map(|(&txn, &mode)| (txn, mode)).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn num_waiting(&self) -> usize {
+        self.waiting_on.len()
+    }
+
+    fn dequeue(&mut self, txn: T) -> Option<Waiter<T>> {
+        let key = self.waiting_on.remove(&txn)?;
+        let lock = self.locks.get_mut(&key)?;
+        let position = lock.queue.iter().position(|waiter| waiter.txn == txn)?;
+        let waiter = lock.queue.remove(position);
+        // Whoever was stuck behind it might be grantable now.
+        self.grant_waiters(&key);
+        waiter
+    }
+
+    fn grant_waiters(&mut self, key: &K) {
+        let Some(lock) = self.locks.get_mut(key) else {
+            return;
+        };
+        while let Some(waiter) = lock.queue.front() {
+            if !lock.can_grant(waiter.txn, waiter.mode) {
+                break;
+            }
+            let waiter = lock.queue.pop_front().unwrap();
+            self.waiting_on.remove(&waiter.txn);
+            // If the waiter gave up, it'll release this along with everything else it holds.
+            lock.holders.insert(waiter.txn, waiter.mode);
+            self.held.entry(waiter.txn).or_default().insert(key.clone());
+            let _ = waiter.granted.send(Ok(()));
+        }
+        if lock.holders.is_empty() && lock.queue.is_empty() {
+            self.locks.remove(key);
+        }
+    }
+
+    /// Depth-first search of the waits-for graph for a cycle through `start`.
+    fn find_cycle(&self, start: T) -> Option<Vec<T>> {
+        let mut path = vec![start];
+        let mut visited = HashSet::new();
+        self.visit(start, start, &mut path, &mut visited).then_some(path)
+    }
+
+    fn visit(&self, start: T, txn: T, path: &mut Vec<T>, visited: &mut HashSet<T>) -> bool {
+        let Some(key) = self.waiting_on.get(&txn) else {
+            return false;
+        };
+        let Some(lock) = self.locks.get(key) else {
+            return false;
+        };
+        let mode = lock.queue.iter().find(|waiter| waiter.txn == txn).map(|waiter| waiter.mode).unwrap_or(LockMode::Exclusive);
+
+        for blocker in lock.blockers(txn, mode) {
+            if blocker == start {
+                return true;
+            }
+            if visited.insert(blocker) {
+                path.push(blocker);
+                if self.visit(start, blocker, path, visited) {
+                    return true;
+                }
+                path.pop();
+            }
+        }
+        false
+    }
+}
+
+
+/// Acquire a lock through a shared manager, waiting (without holding the manager's
+/// mutex) until it's granted or we're picked as a deadlock victim.
+pub async fn lock<K: Clone + Eq + Hash + Debug, T: Copy + Ord + Hash + Debug>(
+    manager: &Mutex<LockManager<K, T>>,
+    txn: T,
+    key: K,
+    mode: LockMode,
+) -> Result<(), LockError<T>> {
+    let acquire = manager.lock().unwrap().acquire(txn, key, mode)?;
+    match acquire {
+        Acquire::Granted => Ok(()),
+        // The sender is only dropped unanswered if this txn was released while it
+        // waited, so it doesn't hold the lock and mustn't carry on as if it did.
+        Acquire::Wait(granted) => granted.await.unwrap_or(Err(LockError::Released { txn })),
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::{HashMap, HashSet};
+use serde::{Serialize, Deserialize};
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub offset: usize,
+    pub msg: usize,
+    /// Records sharing a sub-key supersede each other under compaction.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_key: Option<String>,
+}
+
+
+/// A contiguous run of offsets within a log. Once full, a segment is sealed and
+/// never appended to again, but compaction may still punch holes into it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Segment {
+    pub base_offset: usize,
+    pub records: Vec<Record>,
+}
+
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Log {
+    segments: Vec<Segment>,
+    next_offset: usize,
+}
+
+
+impl Log {
+    pub fn append(&mut self, msg: usize, sub_key: Option<String>, segment_size: usize) -> usize {
+        let offset = self.next_offset;
+        let needs_new_segment = match self.segments.last() {
+            Some(segment) => offset - segment.base_offset >= segment_size,
+            None => true,
+        };
+        if needs_new_segment {
+            self.segments.push(Segment { base_offset: offset, records: vec![] });
+        }
+        self.segments.last_mut().unwrap().records.push(Record { offset, msg, sub_key });
+        self.next_offset += 1;
+        offset
+    }
+
+    /// All surviving records at or after `offset`, in offset order.
+    pub fn read_from(&self, offset: usize) -> impl Iterator<Item = &Record> {
+        // Segments are sorted by base offset so we can skip the ones that end before `offset`.
+        let first = self.segments.partition_point(|segment| {
+            segment.records.last().map(|record| record.offset < offset).unwrap_or(false)
+        });
+        self.segments[first..]
+            .iter()
+            .flat_map(|segment| segment.records.iter())
+            .filter(move |record| record.offset >= offset)
+    }
+
+    pub fn next_offset(&self) -> usize {
+        self.next_offset
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|segment| segment.records.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop every record at or after `offset`, so the next one appended gets `offset`:
+    /// say, records a deposed leader appended that a newer one's overwriting. Returns
+    /// the number of records removed.
+    pub fn truncate(&mut self, offset: usize) -> usize {
+        let before = self.len();
+        for segment in self.segments.iter_mut() {
+            segment.records.retain(|record| record.offset < offset);
+        }
+        self.segments.retain(|segment| segment.base_offset < offset);
+        self.next_offset = self.next_offset.min(offset);
+        before - self.len()
+    }
+
+    /// Drop every committed record (offset <= `committed`) that is superseded by a newer,
+    /// also committed, record with the same sub-key. Records without a sub-key, and
+    /// anything past `committed`, are never touched.
+    ///
+    /// Returns the number of records removed.
+    pub fn compact(&mut self, committed: usize) -> usize {
+        let mut seen = HashSet::new();
+        let mut superseded = HashSet::new();
+
+        // Walk newest to oldest so the first record we see for a sub-key is the one that survives.
+        for record in self.segments.iter().rev().flat_map(|segment| segment.records.iter().rev()) {
+            if record.offset > committed {
+                continue;
+            }
+            if let Some(sub_key) = &record.sub_key {
+                if !seen.insert(sub_key) {
+                    superseded.insert(record.offset);
+                }
+            }
+        }
+
+        if superseded.is_empty() {
+            return 0;
+        }
+
+        for segment in self.segments.iter_mut() {
+            segment.records.retain(|record| !superseded.contains(&record.offset));
+        }
+        // Keep the last segment around even if it's empty, since it's still the one we append to.
+        let last = self.segments.len().saturating_sub(1);
+        let mut index = 0;
+        self.segments.retain(|segment| {
+            let keep = index == last || !segment.records.is_empty();
+            index += 1;
+            keep
+        });
+
+        superseded.len()
+    }
+}
+
+
+/// How much of one key's log a single read hands back, so a client far behind on a long
+/// log gets it a batch at a time (polling again from the last offset it got) rather
+/// than in one reply too big to arrive in time. At least one record is always handed
+/// back, whatever the limits, so a client always gets somewhere.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadLimits {
+    pub max_records: Option<usize>,
+    /// Counted as the records would be in JSON: `[offset,msg],` each.
+    pub max_bytes: Option<usize>,
+}
+
+impl ReadLimits {
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    /// As many of `records` (`(offset, msg)` pairs, in order) as fit.
+    pub fn apply(self, records: impl Iterator<Item = (usize, usize)>) -> Vec<(usize, usize)> {
+        let mut bytes = 0;
+        records
+            .take(self.max_records.unwrap_or(usize::MAX).max(1))
+            .enumerate()
+            .take_while(|&(read, (offset, msg))| {
+                bytes += json_len(offset, msg);
+                read == 0 || self.max_bytes.map_or(true, |max_bytes| bytes <= max_bytes)
+            })
+            .map(|(_, record)| record)
+            .collect()
+    }
+}
+
+/// How many bytes `[offset,msg],` takes in JSON.
+fn json_len(offset: usize, msg: usize) -> usize {
+    let digits = |n: usize| n.checked_ilog10().unwrap_or(0) as usize + 1;
+    digits(offset) + digits(msg) + 4
+}
+
+
+/// Per-key logs, each split into fixed-size segments.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentStore {
+    logs: HashMap<String, Log>,
+    segment_size: usize,
+}
+
+
+impl Default for SegmentStore {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
+
+
+impl SegmentStore {
+    pub fn new(segment_size: usize) -> Self {
+        Self {
+            logs: HashMap::new(),
+            segment_size: segment_size.max(1),
+        }
+    }
+
+    pub fn append(&mut self, key: &str, msg: usize, sub_key: Option<String>) -> usize {
+        let segment_size = self.segment_size;
+        self.logs.entry(key.to_owned()).or_default().append(msg, sub_key, segment_size)
+    }
+
+    pub fn read_from(&self, key: &str, offset: usize) -> Vec<(usize, usize)> {
+        self.read_limited(key, offset, ReadLimits::unlimited())
+    }
+
+    /// The records at or after `offset`, as far as `limits` allow.
+    pub fn read_limited(&self, key: &str, offset: usize, limits: ReadLimits) -> Vec<(usize, usize)> {
+        let Some(log) = self.logs.get(key) else {
+            return vec![];
+        };
+        limits.apply(log.read_from(offset).map(|record| (record.offset, record.msg)))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Log> {
+        self.logs.get(key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.logs.keys()
+    }
+
+    /// Take `key`'s log out of the store, say, to hand it to another node.
+    pub fn take(&mut self, key: &str) -> Option<Log> {
+        self.logs.remove(key)
+    }
+
+    /// Make `log` `key`'s log, in place of whatever it had.
+    pub fn insert(&mut self, key: &str, log: Log) {
+        self.logs.insert(key.to_owned(), log);
+    }
+
+    /// Drop `key`'s records at or after `offset` (see [`Log::truncate`]).
+    pub fn truncate(&mut self, key: &str, offset: usize) -> usize {
+        self.logs.get_mut(key).map_or(0, |log| log.truncate(offset))
+    }
+
+    /// Compact every log up to its committed offset, as given by `committed`.
+    pub fn compact(&mut self, committed: impl Fn(&str) -> Option<usize>) -> usize {
+        self.logs
+            .iter_mut()
+            .filter_map(|(key, log)| committed(key).map(|offset| log.compact(offset)))
+            .sum()
+    }
+}
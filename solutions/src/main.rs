@@ -1,3 +1,125 @@
+use clap::{Parser, Subcommand};
+use solutions::cli;
+#[cfg(feature = "unique-ids")]
+use solutions::cli::CommonOpts;
+#[cfg(any(feature = "echo", feature = "unique-ids", feature = "broadcast", feature = "counter", feature = "kafka", feature = "txn", feature = "craq", feature = "dynamo"))]
+use solutions::{conformance, workloads};
+#[cfg(feature = "broadcast")]
+use solutions::workloads::broadcast;
+#[cfg(feature = "craq")]
+use solutions::workloads::chain_replication_kv;
+#[cfg(feature = "dynamo")]
+use solutions::workloads::dynamo_kv;
+#[cfg(feature = "echo")]
+use solutions::workloads::echo;
+#[cfg(feature = "counter")]
+use solutions::workloads::grow_only_counter;
+#[cfg(feature = "kafka")]
+use solutions::workloads::kafka;
+#[cfg(feature = "txn")]
+use solutions::workloads::transactions;
+#[cfg(feature = "unique-ids")]
+use solutions::workloads::unique_id_generation;
+
+
+#[derive(Debug, Parser)]
+#[clap(author, version, about = "Every workload's node in one binary: `solutions <workload> [options]`.")]
+pub struct Opts {
+    #[clap(subcommand)]
+    pub workload: Workload,
+}
+
+
+/// Each workload is only here if its cargo feature is on (they all are by default).
+#[derive(Debug, Subcommand)]
+pub enum Workload {
+    /// Echo (challenge 1).
+    #[cfg(feature = "echo")]
+    Echo(echo::Opts),
+    /// Unique ID generation (challenge 2).
+    #[cfg(feature = "unique-ids")]
+    #[clap(alias = "unique-id-generation")]
+    UniqueIds(CommonOpts),
+    /// Broadcast (challenge 3).
+    #[cfg(feature = "broadcast")]
+    Broadcast(broadcast::Opts),
+    /// Grow-only counter (challenge 4).
+    #[cfg(feature = "counter")]
+    #[clap(alias = "grow-only-counter")]
+    Counter(grow_only_counter::Opts),
+    /// Kafka-style log (challenge 5a).
+    #[cfg(feature = "kafka")]
+    Kafka(kafka::Opts),
+    /// Totally-available transactions (challenge 6).
+    #[cfg(feature = "txn")]
+    Txn(transactions::Opts),
+    /// A key-value store replicated down a chain of the nodes (CRAQ), for lin-kv.
+    #[cfg(feature = "craq")]
+    #[clap(alias = "chain-replication")]
+    Craq(chain_replication_kv::Opts),
+    /// A Dynamo-style key-value store: sloppy quorums, read repair and hinted handoff.
+    #[cfg(feature = "dynamo")]
+    Dynamo(dynamo_kv::Opts),
+}
+
+
+// Built without any workload, `Workload` has no variants, so there's nothing to parse and
+// no subcommand to run.
+#[cfg_attr(not(any(feature = "echo", feature = "unique-ids", feature = "broadcast", feature = "counter", feature = "kafka", feature = "txn", feature = "craq", feature = "dynamo")), allow(unreachable_code))]
 fn main() {
-    println!("Hello, world!");
+    let profile = |opts: &Opts| match &opts.workload {
+        #[cfg(feature = "broadcast")]
+        Workload::Broadcast(opts) => opts.profile.map(broadcast::Profile::settings),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    };
+    match cli::parse_with_profile(profile).workload {
+        #[cfg(feature = "echo")]
+        Workload::Echo(opts) => {
+            let copy = opts.clone();
+            workloads::self_test(&opts.common, conformance::Workload::Echo, move || echo::server(copy));
+            workloads::run(opts.common.clone(), echo::server(opts))
+        },
+        #[cfg(feature = "unique-ids")]
+        Workload::UniqueIds(common) => {
+            workloads::self_test(&common, conformance::Workload::UniqueIds, unique_id_generation::server);
+            workloads::run(common, unique_id_generation::server())
+        },
+        #[cfg(feature = "broadcast")]
+        Workload::Broadcast(opts) => {
+            let copy = opts.clone();
+            workloads::self_test(&opts.common, conformance::Workload::Broadcast, move || broadcast::server(copy));
+            workloads::run(opts.common.clone(), broadcast::server(opts))
+        },
+        #[cfg(feature = "counter")]
+        Workload::Counter(opts) => {
+            let copy = opts.clone();
+            workloads::self_test(&opts.common, conformance::Workload::Counter, move || grow_only_counter::server(copy));
+            workloads::run(opts.common.clone(), grow_only_counter::server(opts))
+        },
+        #[cfg(feature = "kafka")]
+        Workload::Kafka(opts) => {
+            let copy = opts.clone();
+            workloads::self_test(&opts.common, conformance::Workload::Kafka, move || kafka::server(copy));
+            workloads::run(opts.common.clone(), kafka::server(opts))
+        },
+        #[cfg(feature = "txn")]
+        Workload::Txn(opts) => {
+            let copy = opts.clone();
+            workloads::self_test(&opts.common, conformance::Workload::Txn, move || transactions::server(copy));
+            workloads::run(opts.common.clone(), transactions::server(opts))
+        },
+        #[cfg(feature = "craq")]
+        Workload::Craq(opts) => {
+            let copy = opts.clone();
+            workloads::self_test(&opts.common, conformance::Workload::LinKv, move || chain_replication_kv::server(copy));
+            workloads::run(opts.common.clone(), chain_replication_kv::server(opts))
+        },
+        #[cfg(feature = "dynamo")]
+        Workload::Dynamo(opts) => {
+            let copy = opts.clone();
+            workloads::self_test(&opts.common, conformance::Workload::LwwKv, move || dynamo_kv::server(copy));
+            workloads::run(opts.common.clone(), dynamo_kv::server(opts))
+        },
+    }
 }
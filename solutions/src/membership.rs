@@ -0,0 +1,406 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc::UnboundedSender, time::Instant};
+use tracing::{debug, info};
+use crate::{message::{self, Body, Envelope, NodeId}, metrics, scheduler::{Job, JobHandle, Period, Scheduler, DEFAULT_JITTER}};
+
+
+/// What a [`Membership`] makes of a member.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Alive,
+    /// Didn't answer a ping, directly or through anyone else; it has a while to say
+    /// otherwise before it's taken for dead.
+    Suspect,
+    Dead,
+}
+
+/// A rumor about a member, piggybacked on whatever the nodes send each other. A member's
+/// incarnation only ever goes up, and only the member itself bumps it, to refute
+/// rumors that it's suspect or dead.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Update {
+    pub member: String,
+    pub incarnation: u64,
+    pub status: Status,
+}
+
+/// What a [`Membership`] wants sent. The workload sends each as a message of its own,
+/// with whatever [`piggyback`](Membership::piggyback) hands it for `to`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Probe {
+    /// "Are you there?": answered with an [`Ack`](Probe::Ack) for the pinged node.
+    Ping { to: NodeId },
+    /// "Ping `target` for me": `to` does, and passes on its ack.
+    PingReq { to: NodeId, target: NodeId },
+    /// "`target` is there".
+    Ack { to: NodeId, target: NodeId },
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// How long a ping has to be acked before others are asked to try.
+    pub ping_timeout: Duration,
+    /// How long a member that's been pinged has to be acked (directly or not) before
+    /// it's suspected.
+    pub probe_timeout: Duration,
+    /// How long a suspect has to refute it before it's taken for dead.
+    pub suspicion_timeout: Duration,
+    /// How many others are asked to ping a member that doesn't ack.
+    pub ping_req_fanout: usize,
+    /// Rumors go out `retransmit_mult` times the bits in the cluster size (about its
+    /// log2) each.
+    pub retransmit_mult: usize,
+    /// How many rumors go with one message, at most.
+    pub max_piggyback: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ping_timeout: Duration::from_millis(300),
+            probe_timeout: Duration::from_secs(1),
+            suspicion_timeout: Duration::from_secs(3),
+            ping_req_fanout: 3,
+            retransmit_mult: 3,
+            max_piggyback: 8,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+struct Member {
+    incarnation: u64,
+    status: Status,
+    /// When it last changed status.
+    since: Instant,
+}
+
+#[derive(Debug, Clone)]
+struct Probing {
+    started: Instant,
+    /// Whether others have been asked to ping it yet.
+    indirect: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Rumor {
+    update: Update,
+    sends_left: usize,
+}
+
+
+/// A SWIM-style view of which nodes in the cluster are alive, kept up by the nodes
+/// probing each other rather than all of them heartbeating everyone. Every
+/// [`tick`](Self::tick) pings one member, round-robin in a shuffled order. A member that
+/// doesn't ack in time gets pinged by a few others on this node's behalf (`ping_req`),
+/// and one nobody hears from is suspected, then taken for dead if it doesn't refute it
+/// in time. Those rumors spread by being [piggybacked](Self::piggyback) on what nodes
+/// send each other anyway (gossip, pings and acks), each a handful of times.
+///
+/// Dead members come back (say, after Maelstrom's kill nemesis restarts them) by
+/// refuting it: anyone they ping tells them they're dead, and they spread word they're
+/// alive with a higher incarnation.
+///
+/// Like the failure detector, it only keeps the books: the workload sends the
+/// [`Probe`]s it hands back, and tells it about pings, acks and rumors as they come in.
+#[derive(Debug, Clone)]
+pub struct Membership {
+    config: Config,
+    me: NodeId,
+    incarnation: u64,
+    members: HashMap<NodeId, Member>,
+    /// The order members are probed in, and where it's got to.
+    order: Vec<NodeId>,
+    next: usize,
+    probing: HashMap<NodeId, Probing>,
+    /// Who asked for each member to be pinged for them, and when.
+    relays: HashMap<NodeId, Vec<(NodeId, Instant)>>,
+    rumors: Vec<Rumor>,
+}
+
+impl Membership {
+    /// `me`, among `members`, every one of which starts out alive at `now`.
+    pub fn new<I: IntoIterator<Item = NodeId>>(config: Config, me: &str, members: I, now: Instant) -> Self {
+        let me = message::intern(me);
+        let members: HashMap<NodeId, Member> =
+            members
+            .into_iter()
+            .filter(|member| *member != me)
+            .map(|member| (member, Member { incarnation: 0, status: Status::Alive, since: now }))
+            .collect();
+        metrics::global().set("membership.live", members.len() as i64);
+        Self { config, me, incarnation: 0, members, order: vec![], next: 0, probing: HashMap::new(), relays: HashMap::new(), rumors: vec![] }
+    }
+
+    /// What to send at `now`: a ping to the next member, and ping-reqs for members that
+    /// haven't acked. Members nobody's heard back from in time are suspected, and
+    /// suspects that haven't refuted it in time taken for dead.
+    pub fn tick(&mut self, now: Instant) -> Vec<Probe> {
+        let mut probes = vec![];
+
+        let overdue: Vec<(NodeId, bool)> =
+            self.probing
+            .iter()
+            .filter(|(_, probing)| now.saturating_duration_since(probing.started) >= self.config.ping_timeout)
+            .map(|(target, probing)| (target.clone(), now.saturating_duration_since(probing.started) >= self.config.probe_timeout))
+            .collect();
+        for (target, given_up) in overdue {
+            if given_up {
+                self.probing.remove(&target);
+                if let Some(incarnation) = self.members.get(&target).filter(|member| member.status == Status::Alive).map(|member| member.incarnation) {
+                    self.apply([Update { member: target.to_string(), incarnation, status: Status::Suspect }], now);
+                }
+            } else if !std::mem::replace(&mut self.probing.get_mut(&target).unwrap().indirect, true) {
+                let mut helpers: Vec<NodeId> = self.live_members().into_iter().filter(|member| *member != target).collect();
+                helpers.shuffle(&mut rand::thread_rng());
+                helpers.truncate(self.config.ping_req_fanout);
+                debug!(%target, helpers = helpers.len(), "no ack, asking others to ping");
+                probes.extend(helpers.into_iter().map(|to| Probe::PingReq { to, target: target.clone() }));
+            }
+        }
+
+        let dead: Vec<Update> =
+            self.members
+            .iter()
+            .filter(|(_, member)| member.status == Status::Suspect && now.saturating_duration_since(member.since) >= self.config.suspicion_timeout)
+            .map(|(member, state)| Update { member: member.to_string(), incarnation: state.incarnation, status: Status::Dead })
+            .collect();
+        self.apply(dead, now);
+
+        let probe_timeout = self.config.probe_timeout;
+        self.relays.retain(|_, requesters| {
+            requesters.retain(|(_, asked)| now.saturating_duration_since(*asked) < probe_timeout);
+            !requesters.is_empty()
+        });
+
+        if let Some(target) = self.next_target() {
+            self.probing.insert(target.clone(), Probing { started: now, indirect: false });
+            probes.push(Probe::Ping { to: target });
+        }
+        probes
+    }
+
+    /// The next member to ping that isn't dead (or already being pinged), starting a
+    /// new shuffled round once everyone's had a turn.
+    fn next_target(&mut self) -> Option<NodeId> {
+        for _ in 0..=self.members.len() {
+            if self.next >= self.order.len() {
+                self.order = self.members.keys().cloned().collect();
+                self.order.shuffle(&mut rand::thread_rng());
+                self.next = 0;
+            }
+            let candidate = self.order.get(self.next)?.clone();
+            self.next += 1;
+            if self.is_live(&candidate) && !self.probing.contains_key(&candidate) {
+                return Some(candidate);
+            }
+        }
+        None
+    }
+
+    /// `requester` asked for `target` to be pinged for it: the ping to send.
+    pub fn ping_req(&mut self, requester: &str, target: &str, now: Instant) -> Probe {
+        self.relays.entry(message::intern(target)).or_default().push((message::intern(requester), now));
+        Probe::Ping { to: message::intern(target) }
+    }
+
+    /// `target` acked a ping (ours, or one we asked for): the acks to pass on to whoever
+    /// asked us to ping it.
+    pub fn acked(&mut self, target: &str) -> Vec<Probe> {
+        self.probing.remove(target);
+        let target = message::intern(target);
+        self.relays
+        .remove(&target)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(to, _)| Probe::Ack { to, target: target.clone() })
+        .collect()
+    }
+
+    /// Take in rumors heard at `now`, spreading the ones that are news.
+    pub fn apply<I: IntoIterator<Item = Update>>(&mut self, updates: I, now: Instant) {
+        for update in updates {
+            if *update.member == *self.me {
+                if update.status != Status::Alive && update.incarnation >= self.incarnation {
+                    self.incarnation = update.incarnation + 1;
+                    info!(incarnation = self.incarnation, rumor = ?update.status, "refuting a rumor about this node");
+                    metrics::global().increment("membership.refuted");
+                    self.spread(Update { member: self.me.to_string(), incarnation: self.incarnation, status: Status::Alive });
+                }
+                continue;
+            }
+
+            let member = self.members.entry(message::intern(&update.member)).or_insert(Member { incarnation: 0, status: Status::Alive, since: now });
+            let newer = update.incarnation > member.incarnation;
+            let same = update.incarnation == member.incarnation;
+            let news = match (member.status, update.status) {
+                (_, Status::Alive) => newer,
+                (Status::Alive, Status::Suspect) => newer || same,
+                (_, Status::Suspect) => newer,
+                (Status::Dead, Status::Dead) => newer,
+                (_, Status::Dead) => newer || same,
+            };
+            if !news {
+                continue;
+            }
+            if member.status != update.status {
+                info!(member = update.member, from = ?member.status, to = ?update.status, incarnation = update.incarnation, "member status changed");
+                metrics::global().increment(&format!("membership.{}", match update.status { Status::Alive => "alive", Status::Suspect => "suspected", Status::Dead => "dead" }));
+                member.since = now;
+            }
+            member.incarnation = update.incarnation;
+            member.status = update.status;
+            self.spread(update);
+        }
+        metrics::global().set("membership.live", self.live_members().len() as i64);
+    }
+
+    fn spread(&mut self, update: Update) {
+        let sends = self.config.retransmit_mult * (usize::BITS - (self.members.len() + 1).leading_zeros()) as usize;
+        self.rumors.retain(|rumor| rumor.update.member != update.member);
+        self.rumors.push(Rumor { update, sends_left: sends.max(1) });
+    }
+
+    /// The rumors to send along with a message to `to`: the ones sent the fewest times so
+    /// far, and always what's said about `to` itself if it's suspect or dead, for it to
+    /// refute.
+    pub fn piggyback(&mut self, to: &str) -> Vec<Update> {
+        self.rumors.sort_by_key(|rumor| std::cmp::Reverse(rumor.sends_left));
+        let mut updates: Vec<Update> = vec![];
+        for rumor in self.rumors.iter_mut().take(self.config.max_piggyback) {
+            rumor.sends_left -= 1;
+            updates.push(rumor.update.clone());
+        }
+        self.rumors.retain(|rumor| rumor.sends_left > 0);
+        if let Some(member) = self.members.get(to).filter(|member| member.status != Status::Alive) {
+            if !updates.iter().any(|update| *update.member == *to) {
+                updates.push(Update { member: to.to_owned(), incarnation: member.incarnation, status: member.status });
+            }
+        }
+        updates
+    }
+
+    /// Take in `member`, which has joined the cluster at `now`, as alive.
+    pub fn add(&mut self, member: &str, now: Instant) {
+        if *member == *self.me {
+            return;
+        }
+        self.members.entry(message::intern(member)).or_insert(Member { incarnation: 0, status: Status::Alive, since: now });
+        metrics::global().set("membership.live", self.live_members().len() as i64);
+    }
+
+    /// Forget `member`, which has left the cluster: it's no longer probed, or rumored
+    /// about.
+    pub fn remove(&mut self, member: &str) {
+        self.members.remove(member);
+        self.probing.remove(member);
+        self.relays.remove(member);
+        self.rumors.retain(|rumor| *rumor.update.member != *member);
+        metrics::global().set("membership.live", self.live_members().len() as i64);
+    }
+
+    pub fn status(&self, member: &str) -> Option<Status> {
+        self.members.get(member).map(|member| member.status)
+    }
+
+    /// Whether `member` is worth sending to: it isn't known to be dead.
+    pub fn is_live(&self, member: &str) -> bool {
+        self.status(member) != Some(Status::Dead)
+    }
+
+    /// The members that aren't dead, this node aside.
+    pub fn live_members(&self) -> Vec<NodeId> {
+        self.members.iter().filter(|(_, member)| member.status != Status::Dead).map(|(member, _)| member.clone()).collect()
+    }
+
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+}
+
+
+
+/// A workload's own `ping`, `ping_req` and `ack` messages, which the helpers below send
+/// and answer for it.
+pub trait Messages: Sized {
+    fn ping(updates: Vec<Update>) -> Self;
+    fn ping_req(target: String, updates: Vec<Update>) -> Self;
+    fn ack(target: String, updates: Vec<Update>) -> Self;
+    /// The `msg_id` for the next message the node sends.
+    fn message_id() -> usize;
+}
+
+/// A workload's node, as far as SWIM goes.
+pub trait Node {
+    /// The node's id, its [`Membership`], and what time its clock says it is, once it has
+    /// a membership.
+    fn membership(&mut self) -> Option<(NodeId, &mut Membership, Instant)>;
+}
+
+/// `probe` as a message from `my_id`, with rumors for whoever it's to.
+pub fn probe<P: Messages>(my_id: &NodeId, membership: &mut Membership, probe: Probe) -> Envelope<P> {
+    let (to, message) = match probe {
+        Probe::Ping { to } => {
+            let updates = membership.piggyback(&to);
+            (to, P::ping(updates))
+        },
+        Probe::PingReq { to, target } => {
+            let updates = membership.piggyback(&to);
+            (to, P::ping_req(target.to_string(), updates))
+        },
+        Probe::Ack { to, target } => {
+            let updates = membership.piggyback(&to);
+            (to, P::ack(target.to_string(), updates))
+        },
+    };
+    Envelope::new(my_id, &to, Body { msg_id: Some(P::message_id()), in_reply_to: None, message })
+}
+
+/// Take in the rumors on a `ping`, and answer it with an `ack`.
+pub fn on_ping<P: Messages>(node: &mut impl Node, ping: &Envelope<P>, updates: &[Update]) -> Option<Envelope<P>> {
+    let (my_id, membership, now) = node.membership()?;
+    membership.apply(updates.to_vec(), now);
+    let updates = membership.piggyback(&ping.source);
+    Some(ping.reply_with(Some(P::message_id()), P::ack(my_id.to_string(), updates)))
+}
+
+/// Take in the rumors on a `ping_req`, and ping its `target` for whoever sent it.
+pub fn on_ping_req<P: Messages>(node: &mut impl Node, ping_req: &Envelope<P>, target: &str, updates: &[Update]) -> Option<Envelope<P>> {
+    let (my_id, membership, now) = node.membership()?;
+    membership.apply(updates.to_vec(), now);
+    let ping = membership.ping_req(&ping_req.source, target, now);
+    Some(probe(&my_id, membership, ping))
+}
+
+/// Take in the rumors on an `ack` for `target`, and pass it on to whoever asked us to
+/// ping it.
+pub fn on_ack<P: Messages>(node: &mut impl Node, target: &str, updates: &[Update]) -> Vec<Envelope<P>> {
+    let Some((my_id, membership, now)) = node.membership() else {
+        return vec![];
+    };
+    membership.apply(updates.to_vec(), now);
+    membership.acked(target).into_iter().map(|ack| probe(&my_id, membership, ack)).collect()
+}
+
+/// Send whatever the node's [`Membership`] says to every tick, as a `membership` job on
+/// `scheduler`, once it has one.
+pub fn probe_every_so_often<N, P>(scheduler: &Scheduler, node: Arc<Mutex<N>>, writer: UnboundedSender<Envelope<P>>) -> JobHandle
+where
+    N: Node + Send + 'static,
+    P: Messages + Send + 'static,
+{
+    scheduler.spawn(Job::new("membership", Period::TickRate).jitter(DEFAULT_JITTER), move |now| {
+        let mut node = node.lock().unwrap();
+        let Some((my_id, membership, _)) = node.membership() else {
+            return;
+        };
+        for next in membership.tick(now) {
+            writer.send(probe(&my_id, membership, next)).unwrap();
+        }
+    })
+}
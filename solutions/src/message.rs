@@ -1,12 +1,85 @@
-use serde::{Serialize, Deserialize};
+use std::{collections::HashSet, fmt, sync::{Arc, OnceLock, RwLock}};
+use serde::{de::{self, DeserializeOwned, Visitor}, Serialize, Deserialize, Deserializer};
+use serde_json::value::RawValue;
+
+
+/// A node's (or client's, or service's) id, shared rather than copied: every envelope
+/// to or from a node carries the same one, [interned](intern) the first time it's seen.
+pub type NodeId = Arc<str>;
+
+static NODE_IDS: OnceLock<RwLock<HashSet<NodeId>>> = OnceLock::new();
+
+/// The shared copy of the node id `id`, which is only allocated the first time around.
+pub fn intern(id: &str) -> NodeId {
+    let node_ids = NODE_IDS.get_or_init(Default::default);
+    if let Some(interned) = node_ids.read().unwrap().get(id) {
+        return interned.clone();
+    }
+    let mut node_ids = node_ids.write().unwrap();
+    if let Some(interned) = node_ids.get(id) {
+        return interned.clone();
+    }
+    let interned = NodeId::from(id);
+    node_ids.insert(interned.clone());
+    interned
+}
+
+/// Anything an [`Envelope`] can be addressed with: an already shared [`NodeId`] is just
+/// cloned, and a string is [interned](intern).
+pub trait ToNodeId {
+    fn to_node_id(&self) -> NodeId;
+}
+
+impl ToNodeId for NodeId {
+    fn to_node_id(&self) -> NodeId {
+        self.clone()
+    }
+}
+
+impl ToNodeId for str {
+    fn to_node_id(&self) -> NodeId {
+        intern(self)
+    }
+}
+
+impl ToNodeId for String {
+    fn to_node_id(&self) -> NodeId {
+        intern(self)
+    }
+}
+
+impl<T: ToNodeId + ?Sized> ToNodeId for &T {
+    fn to_node_id(&self) -> NodeId {
+        (**self).to_node_id()
+    }
+}
+
+/// Deserializes a node id straight into its [interned](intern) copy.
+pub(crate) fn interned<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NodeId, D::Error> {
+    struct Interned;
+
+    impl Visitor<'_> for Interned {
+        type Value = NodeId;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a node id")
+        }
+
+        fn visit_str<E: de::Error>(self, id: &str) -> Result<NodeId, E> {
+            Ok(intern(id))
+        }
+    }
+
+    deserializer.deserialize_str(Interned)
+}
 
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Envelope<M> {
-    #[serde(rename = "src")]
-    pub source: String,
-    #[serde(rename = "dest")]
-    pub destination: String,
+    #[serde(rename = "src", deserialize_with = "interned")]
+    pub source: NodeId,
+    #[serde(rename = "dest", deserialize_with = "interned")]
+    pub destination: NodeId,
     pub body: Body<M>
 }
 
@@ -27,14 +100,23 @@ pub struct Body<M> {
 
 
 impl<M> Envelope<M> {
-    pub fn new(src: &str, dest: &str, body: Body<M>) -> Self {
+    pub fn new<S: ToNodeId + ?Sized, D: ToNodeId + ?Sized>(src: &S, dest: &D, body: Body<M>) -> Self {
+        let source = src.to_node_id();
+        #[cfg(feature = "otel")]
+        if let Some(msg_id) = body.msg_id {
+            crate::otel::sending(&source, msg_id);
+        }
         Self {
-            source: src.to_owned(),
-            destination: dest.to_owned(),
+            source,
+            destination: dest.to_node_id(),
             body
         }
     }
     pub fn reply_with(&self, msg_id: Option<usize>, message: M) -> Self {
+        #[cfg(feature = "otel")]
+        if let Some(msg_id) = msg_id {
+            crate::otel::sending(&self.destination, msg_id);
+        }
         Self {
             source: self.destination.clone(),
             destination: self.source.clone(),
@@ -56,4 +138,120 @@ impl<M> Envelope<M> {
             Body { msg_id: self.body.msg_id, in_reply_to: self.body.in_reply_to, message }
         )
     }
+
+    /// Pass this on to `dest`, from whoever it was sent to: only `src` and `dest` change,
+    /// the body (`msg_id` and all) goes along as is.
+    pub fn forward_to<D: ToNodeId + ?Sized>(self, dest: &D) -> Self {
+        Self {
+            source: self.destination,
+            destination: dest.to_node_id(),
+            body: self.body,
+        }
+    }
+}
+
+
+/// An envelope whose body is kept as the JSON text it came in as, for messages that are
+/// only passed along (by a proxy, or a node forwarding to the leader): nothing in the body
+/// is parsed, and it's written back out byte for byte. [`io_channel`](crate::io::io_channel)
+/// carries these like any other message.
+///
+/// (`Envelope<Box<RawValue>>` can't do this: [`Body`] flattens its message into itself,
+/// and serde can't flatten a raw value.)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RawEnvelope {
+    #[serde(rename = "src", deserialize_with = "interned")]
+    pub source: NodeId,
+    #[serde(rename = "dest", deserialize_with = "interned")]
+    pub destination: NodeId,
+    pub body: Box<RawValue>,
+}
+
+impl RawEnvelope {
+    /// See [`Envelope::forward_to`].
+    pub fn forward_to<D: ToNodeId + ?Sized>(self, dest: &D) -> Self {
+        Self {
+            source: self.destination,
+            destination: dest.to_node_id(),
+            body: self.body,
+        }
+    }
+
+    /// The body's `type`, parsing only that much of it.
+    pub fn kind(&self) -> Option<String> {
+        #[derive(Deserialize)]
+        struct Kind {
+            #[serde(rename = "type")]
+            kind: String,
+        }
+        serde_json::from_str::<Kind>(self.body.get()).ok().map(|body| body.kind)
+    }
+
+    /// Parse the body after all, for a message that turns out to need handling here.
+    pub fn parse<M: DeserializeOwned>(&self) -> serde_json::Result<Envelope<M>> {
+        Ok(Envelope {
+            source: self.source.clone(),
+            destination: self.destination.clone(),
+            body: serde_json::from_str(self.body.get())?,
+        })
+    }
+}
+
+
+/// The error codes Maelstrom defines for `error` bodies.
+///
+/// See <https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+}
+
+impl ErrorCode {
+    pub fn code(self) -> usize {
+        match self {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+        }
+    }
+
+    pub fn from_code(code: usize) -> Option<Self> {
+        Some(match code {
+            0 => ErrorCode::Timeout,
+            1 => ErrorCode::NodeNotFound,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            _ => return None,
+        })
+    }
+
+    /// Definite errors guarantee the operation did not take place.
+    pub fn is_definite(self) -> bool {
+        !matches!(self, ErrorCode::Timeout | ErrorCode::Crash)
+    }
 }
@@ -0,0 +1,190 @@
+use std::{collections::BTreeMap, fmt::Display, future::Future, sync::Mutex, time::{Duration, Instant}};
+use hdrhistogram::Histogram;
+use serde::Serialize;
+use serde_json::{json, Value};
+use crate::{checker::Percentiles, scheduler::{Job, JobHandle, Period, Scheduler}};
+
+
+/// Named counters (things that only go up: messages in/out by type, retries, errors),
+/// gauges (things that go up and down: queue depths, backlogs) and latency histograms
+/// (how long handlers take, how long until a request is answered).
+///
+/// There's one [`global`] registry that the io layer and the workloads record into, and
+/// that [`workloads::run`](crate::workloads::run) dumps to stderr; separate registries
+/// are mostly useful in tests.
+#[derive(Debug, Default)]
+pub struct Registry {
+    counters: Mutex<BTreeMap<String, u64>>,
+    gauges: Mutex<BTreeMap<String, i64>>,
+    /// In microseconds.
+    histograms: Mutex<BTreeMap<String, Histogram<u64>>>,
+}
+
+impl Registry {
+    pub const fn new() -> Self {
+        Self { counters: Mutex::new(BTreeMap::new()), gauges: Mutex::new(BTreeMap::new()), histograms: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn increment(&self, counter: &str) {
+        self.add(counter, 1);
+    }
+
+    pub fn add(&self, counter: &str, amount: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        match counters.get_mut(counter) {
+            Some(value) => *value += amount,
+            None => { counters.insert(counter.to_owned(), amount); },
+        }
+    }
+
+    pub fn set(&self, gauge: &str, value: i64) {
+        let mut gauges = self.gauges.lock().unwrap();
+        match gauges.get_mut(gauge) {
+            Some(current) => *current = value,
+            None => { gauges.insert(gauge.to_owned(), value); },
+        }
+    }
+
+    /// Add one sample to a latency histogram, to the microsecond.
+    pub fn record(&self, histogram: &str, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let mut histograms = self.histograms.lock().unwrap();
+        if !histograms.contains_key(histogram) {
+            // Three significant figures, growing to fit whatever comes in.
+            histograms.insert(histogram.to_owned(), Histogram::new(3).unwrap());
+        }
+        // Only fails for values too big for any histogram to track.
+        histograms.get_mut(histogram).unwrap().record(micros).ok();
+    }
+
+    /// Forget whatever's been recorded under `name`.
+    pub fn remove(&self, name: &str) {
+        self.counters.lock().unwrap().remove(name);
+        self.gauges.lock().unwrap().remove(name);
+        self.histograms.lock().unwrap().remove(name);
+    }
+
+    /// Forget everything recorded so far.
+    pub fn clear(&self) {
+        self.counters.lock().unwrap().clear();
+        self.gauges.lock().unwrap().clear();
+        self.histograms.lock().unwrap().clear();
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let latencies = self.histograms.lock().unwrap().iter().map(|(name, histogram)| {
+            let at = |quantile: f64| Duration::from_micros(histogram.value_at_quantile(quantile));
+            let percentiles = Percentiles { p50: at(0.5), p95: at(0.95), p99: at(0.99), max: Duration::from_micros(histogram.max()) };
+            (name.clone(), Latencies { count: histogram.len(), percentiles })
+        }).collect();
+        Snapshot {
+            counters: self.counters.lock().unwrap().clone(),
+            gauges: self.gauges.lock().unwrap().clone(),
+            latencies,
+        }
+    }
+}
+
+
+static GLOBAL: Registry = Registry::new();
+
+/// The registry everything in this process records into.
+pub fn global() -> &'static Registry {
+    &GLOBAL
+}
+
+
+/// What a latency histogram has seen so far.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Latencies {
+    pub count: u64,
+    pub percentiles: Percentiles,
+}
+
+impl Display for Latencies {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Percentiles { p50, p95, p99, max } = self.percentiles;
+        write!(f, "count={} p50={p50:?} p95={p95:?} p99={p99:?} max={max:?}", self.count)
+    }
+}
+
+
+/// Every metric's value at one point in time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub counters: BTreeMap<String, u64>,
+    pub gauges: BTreeMap<String, i64>,
+    pub latencies: BTreeMap<String, Latencies>,
+}
+
+impl Snapshot {
+    /// A counter's value; zero if it was never incremented.
+    pub fn counter(&self, name: &str) -> u64 {
+        self.counters.get(name).copied().unwrap_or_default()
+    }
+
+    pub fn gauge(&self, name: &str) -> Option<i64> {
+        self.gauges.get(name).copied()
+    }
+
+    pub fn latencies(&self, name: &str) -> Option<&Latencies> {
+        self.latencies.get(name)
+    }
+
+    /// As `{"counters": {...}, "gauges": {...}, "latencies": {...}}`, with each latency
+    /// summary's percentiles in microseconds (`{"count", "p50_us", "p95_us", "p99_us",
+    /// "max_us"}`).
+    pub fn to_json(&self) -> Value {
+        let latencies: serde_json::Map<String, Value> = self.latencies.iter().map(|(name, latencies)| {
+            let Percentiles { p50, p95, p99, max } = latencies.percentiles;
+            let micros = |latency: Duration| Value::from(latency.as_micros() as u64);
+            let summary = json!({"count": latencies.count, "p50_us": micros(p50), "p95_us": micros(p95), "p99_us": micros(p99), "max_us": micros(max)});
+            (name.clone(), summary)
+        }).collect();
+        json!({"counters": self.counters, "gauges": self.gauges, "latencies": latencies})
+    }
+}
+
+/// On one line, as `name=value` pairs sorted by name, counters first. Latencies are
+/// left out; they take a line each (see [`dump_latencies`]).
+impl Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let counters = self.counters.iter().map(|(name, value)| format!("{name}={value}"));
+        let gauges = self.gauges.iter().map(|(name, value)| format!("{name}={value}"));
+        write!(f, "{}", counters.chain(gauges).collect::<Vec<_>>().join(" "))
+    }
+}
+
+
+/// Print the global registry to stderr (as `metrics: name=value ...`).
+pub fn dump() {
+    eprintln!("metrics: {}", global().snapshot());
+}
+
+/// Print a line per latency histogram in the global registry to stderr (as
+/// `latency: name count=... p50=... p95=... p99=... max=...`).
+pub fn dump_latencies() {
+    for (name, latencies) in global().snapshot().latencies {
+        eprintln!("latency: {name} {latencies}");
+    }
+}
+
+/// Dump the global registry every `period`, in the background.
+pub fn dump_every(period: Duration) -> JobHandle {
+    Scheduler::new().spawn(Job::new("metrics dump", Period::Every(period)), |_| dump())
+}
+
+
+/// Run `future`, recording how long it took in the global registry's `histogram`.
+pub async fn timed<F: Future>(histogram: &str, future: F) -> F::Output {
+    let started = Instant::now();
+    let output = future.await;
+    global().record(histogram, started.elapsed());
+    output
+}
+
+/// The `type` a payload goes by on the wire (`"broadcast"`, `"sync_ok"`, ...), to name
+/// metrics after. `"?"` if it doesn't serialize into an object with one.
+pub fn message_type<M: Serialize>(message: &M) -> String {
+    serde_json::to_value(message).ok().and_then(|value| value.get("type")?.as_str().map(str::to_owned)).unwrap_or_else(|| "?".to_owned())
+}
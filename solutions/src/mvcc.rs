@@ -0,0 +1,152 @@
+use std::{borrow::Borrow, collections::{BTreeMap, HashMap}, hash::Hash};
+use serde::{Serialize, Deserialize};
+
+
+pub type Timestamp = u64;
+
+
+/// A multi-version key-value store. Every committed write is kept as a separate
+/// version stamped with its commit timestamp, so a reader can ask for the value a
+/// key had as of any timestamp and get a consistent view even while newer
+/// transactions keep committing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MvccStore<K: Eq + Hash, V> {
+    /// `None` marks a deletion (a tombstone) at that timestamp.
+    versions: HashMap<K, BTreeMap<Timestamp, Option<V>>>,
+    latest_commit_ts: Timestamp,
+}
+
+
+impl<K: Eq + Hash, V> Default for MvccStore<K, V> {
+    fn default() -> Self {
+        Self {
+            versions: HashMap::new(),
+            latest_commit_ts: 0,
+        }
+    }
+}
+
+
+impl<K: Eq + Hash, V> MvccStore<K, V> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The highest timestamp anything has been committed at. Reading at this
+    /// timestamp sees every committed write.
+    pub fn latest_commit_ts(&self) -> Timestamp {
+        self.latest_commit_ts
+    }
+
+    /// Atomically install a set of writes at `commit_ts`. A `None` value deletes the key.
+    pub fn commit(&mut self, commit_ts: Timestamp, writes: impl IntoIterator<Item = (K, Option<V>)>) {
+        for (key, value) in writes {
+            self.versions.entry(key).or_default().insert(commit_ts, value);
+        }
+        self.latest_commit_ts = self.latest_commit_ts.max(commit_ts);
+    }
+
+    pub fn write(&mut self, key: K, value: V, commit_ts: Timestamp) {
+        self.commit(commit_ts, [(key, Some(value))]);
+    }
+
+    pub fn delete(&mut self, key: K, commit_ts: Timestamp) {
+        self.commit(commit_ts, [(key, None)]);
+    }
+
+    /// The value of `key` as of `ts`, i.e. the newest version committed at or before it.
+    pub fn read_at<Q>(&self, key: &Q, ts: Timestamp) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.versions
+            .get(key)?
+            .range(..=ts)
+            .next_back()
+            .and_then(|(_, value)| value.as_ref())
+    }
+
+    pub fn read_latest<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.read_at(key, Timestamp::MAX)
+    }
+
+    /// The commit timestamp of the newest version of `key`, if it was ever written.
+    pub fn last_write_ts<Q>(&self, key: &Q) -> Option<Timestamp>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.versions.get(key)?.keys().next_back().copied()
+    }
+
+    /// Whether anything committed a version of `key` after `ts`. This is the
+    /// write-write conflict check for a transaction whose snapshot was taken at `ts`.
+    pub fn written_since<Q>(&self, key: &Q, ts: Timestamp) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.last_write_ts(key).map(|last| last > ts).unwrap_or(false)
+    }
+
+    /// A read-only view of the store frozen at `ts`.
+    pub fn snapshot(&self, ts: Timestamp) -> Snapshot<'_, K, V> {
+        Snapshot { store: self, ts }
+    }
+
+    /// Discard versions no reader at or after `watermark` can ever see: for each key,
+    /// everything older than the newest version at or before the watermark. Keys whose
+    /// only visible version is a tombstone are dropped entirely.
+    ///
+    /// Returns the number of versions removed.
+    pub fn gc(&mut self, watermark: Timestamp) -> usize {
+        let mut removed = 0;
+        self.versions.retain(|_, versions| {
+            let Some(&visible) = versions.range(..=watermark).next_back().map(|(ts, _)| ts) else {
+                return true;
+            };
+            let newer = versions.split_off(&visible);
+            removed += versions.len();
+            *versions = newer;
+
+            if versions.len() == 1 && versions.get(&visible).map(Option::is_none).unwrap_or(false) {
+                removed += 1;
+                return false;
+            }
+            true
+        });
+        removed
+    }
+
+    pub fn num_versions(&self) -> usize {
+        self.versions.values().map(BTreeMap::len).sum()
+    }
+}
+
+
+/// The store as it looked at a single timestamp.
+#[derive(Debug)]
+pub struct Snapshot<'a, K: Eq + Hash, V> {
+    store: &'a MvccStore<K, V>,
+    ts: Timestamp,
+}
+
+
+impl<'a, K: Eq + Hash, V> Snapshot<'a, K, V> {
+    pub fn ts(&self) -> Timestamp {
+        self.ts
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&'a V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.store.read_at(key, self.ts)
+    }
+}
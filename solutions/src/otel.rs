@@ -0,0 +1,142 @@
+use std::{collections::HashMap, sync::{Mutex, OnceLock}};
+use opentelemetry::{propagation::TextMapPropagator, trace::{TraceContextExt, TracerProvider}, Context};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::{SdkTracer, SdkTracerProvider}, Resource};
+use serde_json::Value;
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+
+/// The body field a message carries its sender's trace context in.
+const TRACEPARENT: &str = "traceparent";
+
+/// Past this many, the contexts waiting in [`SENDING`] or [`RECEIVED`] are dropped
+/// rather than left to pile up (most are taken right away; the rest never will be).
+const FORGET_AT: usize = 10_000;
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// The span each message we're about to send was made in, by who it's from and its
+/// `msg_id`, until the io layer writes it out.
+static SENDING: Mutex<Option<HashMap<(String, usize), Context>>> = Mutex::new(None);
+
+/// The trace context each message we've read came with, by who it's from and its
+/// `msg_id`, until it's handled.
+static RECEIVED: Mutex<Option<HashMap<(String, usize), Context>>> = Mutex::new(None);
+
+
+/// A layer exporting every span over OTLP (HTTP), if `OTEL_EXPORTER_OTLP_ENDPOINT` (or
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`) says where to; `OTEL_SERVICE_NAME` and the
+/// other standard `OTEL_*` variables apply too. `None` if there's nowhere to export to.
+pub fn layer<S>() -> Option<OpenTelemetryLayer<S, SdkTracer>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let configured = ["OTEL_EXPORTER_OTLP_ENDPOINT", "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"].iter().any(|name| std::env::var_os(name).is_some());
+    if !configured {
+        return None;
+    }
+    let exporter = match SpanExporter::builder().with_http().with_protocol(opentelemetry_otlp::Protocol::HttpBinary).build() {
+        Ok(exporter) => exporter,
+        Err(err) => {
+            eprintln!("not exporting spans: {err}");
+            return None;
+        },
+    };
+    let provider =
+        SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().build())
+        .build();
+    let tracer = provider.tracer("solutions");
+    PROVIDER.set(provider).ok()?;
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Whether spans are being exported (and so whether to bother propagating contexts).
+pub fn enabled() -> bool {
+    PROVIDER.get().is_some()
+}
+
+/// Export whatever spans are still buffered. Call once the node is done.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(err) = provider.shutdown() {
+            eprintln!("failed to export the last spans: {err}");
+        }
+    }
+}
+
+
+fn remember(contexts: &Mutex<Option<HashMap<(String, usize), Context>>>, key: (String, usize), context: Context) {
+    let mut contexts = contexts.lock().unwrap();
+    let contexts = contexts.get_or_insert_with(HashMap::new);
+    if contexts.len() >= FORGET_AT {
+        contexts.clear();
+    }
+    contexts.insert(key, context);
+}
+
+fn take(contexts: &Mutex<Option<HashMap<(String, usize), Context>>>, key: &(String, usize)) -> Option<Context> {
+    contexts.lock().unwrap().as_mut()?.remove(key)
+}
+
+
+/// Note that `source` is about to send message `msg_id` from within the current span.
+pub(crate) fn sending(source: &str, msg_id: usize) {
+    if enabled() {
+        remember(&SENDING, (source.to_owned(), msg_id), Span::current().context());
+    }
+}
+
+/// Add the sending span's context to an outgoing line, if it's going to another node.
+/// Clients and Maelstrom's services check their messages' fields, so theirs are left
+/// alone.
+pub(crate) fn inject(line: &mut Vec<u8>) {
+    let Ok(mut envelope) = serde_json::from_slice::<Value>(line) else {
+        return;
+    };
+    let (Some(source), Some(msg_id)) = (envelope["src"].as_str(), envelope["body"]["msg_id"].as_u64()) else {
+        return;
+    };
+    let Some(context) = take(&SENDING, &(source.to_owned(), msg_id as usize)) else {
+        return;
+    };
+    let to_node = envelope["dest"].as_str().is_some_and(|destination| destination.starts_with('n'));
+    if !to_node || !context.span().span_context().is_valid() {
+        return;
+    }
+    let mut fields = HashMap::new();
+    TraceContextPropagator::new().inject_context(&context, &mut fields);
+    let Some(traceparent) = fields.remove(TRACEPARENT) else {
+        return;
+    };
+    envelope["body"][TRACEPARENT] = Value::from(traceparent);
+    line.clear();
+    serde_json::to_writer(line, &envelope).expect("a JSON value always serializes");
+}
+
+/// Keep the trace context an incoming line came with, if any, for its handler to link to.
+pub(crate) fn received(line: &[u8]) {
+    let Ok(envelope) = serde_json::from_slice::<Value>(line) else {
+        return;
+    };
+    let (Some(source), Some(msg_id), Some(traceparent)) = (
+        envelope["src"].as_str(),
+        envelope["body"]["msg_id"].as_u64(),
+        envelope["body"][TRACEPARENT].as_str(),
+    ) else {
+        return;
+    };
+    let fields = HashMap::from([(TRACEPARENT.to_owned(), traceparent.to_owned())]);
+    let context = TraceContextPropagator::new().extract(&fields);
+    remember(&RECEIVED, (source.to_owned(), msg_id as usize), context);
+}
+
+/// Link `span` to the span that sent message `msg_id` from `source`, if it came with one.
+pub(crate) fn link(span: &Span, source: &str, msg_id: usize) {
+    if let Some(context) = take(&RECEIVED, &(source.to_owned(), msg_id)) {
+        span.add_link(context.span().span_context().clone());
+    }
+}
@@ -0,0 +1,119 @@
+use std::{collections::BTreeMap, io};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::debug;
+use crate::{message::Envelope, storage::{Durable, Replay, Storage}};
+
+
+/// What an outbox journals: a message it's about to send, and the ack that means it no
+/// longer has to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutboxRecord<M> {
+    Sent { envelope: Envelope<M> },
+    Acked { msg_id: usize },
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxState<M> {
+    /// Messages sent and not acknowledged yet, by `msg_id`.
+    pub pending: BTreeMap<usize, Envelope<M>>,
+}
+
+impl<M> Default for OutboxState<M> {
+    fn default() -> Self {
+        Self { pending: BTreeMap::new() }
+    }
+}
+
+impl<M: Clone> Replay<OutboxRecord<M>> for OutboxState<M> {
+    fn apply(&mut self, record: &OutboxRecord<M>) {
+        match record {
+            OutboxRecord::Sent { envelope } => {
+                if let Some(msg_id) = envelope.msg_id() {
+                    self.pending.insert(msg_id, envelope.clone());
+                }
+            },
+            OutboxRecord::Acked { msg_id } => {
+                self.pending.remove(msg_id);
+            },
+        }
+    }
+}
+
+
+/// Messages that have to get through even if the node is killed before they're
+/// acknowledged: each is journaled before it's sent, and forgotten once its reply comes
+/// in, so whatever was still unacknowledged when the node went down is sent again when
+/// it comes back up ([`resend`](Self::resend)).
+///
+/// That makes delivery at-least-once: a message whose ack was lost in the crash goes out
+/// twice, so receivers should treat them idempotently (by `msg_id`, say). Resent messages
+/// keep their `msg_id`, so a restarted node should start numbering its messages after
+/// [`last_msg_id`](Self::last_msg_id).
+#[derive(Debug)]
+pub struct Outbox<M, St> {
+    durable: Durable<OutboxState<M>, St>,
+}
+
+impl<M, St> Outbox<M, St>
+where
+    M: Clone,
+    St: Storage<OutboxState<M>, OutboxRecord<M>>,
+{
+    /// Recover whatever was sent but not acknowledged when we last stopped.
+    pub fn open(storage: St) -> io::Result<Self> {
+        let durable = Durable::open(storage)?;
+        Ok(Self { durable })
+    }
+
+    /// Journal `envelope`, then send it with `writer`. It needs a `msg_id` for its ack to
+    /// be recognized; one without is just sent.
+    pub fn send(&mut self, envelope: Envelope<M>, writer: &UnboundedSender<Envelope<M>>) -> io::Result<()> {
+        if envelope.msg_id().is_some() {
+            self.durable.apply(OutboxRecord::Sent { envelope: envelope.clone() })?;
+        }
+        // If the writer is gone, so is the node: the message goes out again on restart.
+        let _ = writer.send(envelope);
+        Ok(())
+    }
+
+    /// Forget the message that `in_reply_to` answers, returning whether it was pending.
+    /// Once nothing is, the journal is compacted down to nothing.
+    pub fn acknowledge(&mut self, in_reply_to: usize) -> io::Result<bool> {
+        if !self.durable.state.pending.contains_key(&in_reply_to) {
+            return Ok(false);
+        }
+        self.durable.apply(OutboxRecord::Acked { msg_id: in_reply_to })?;
+        if self.durable.state.pending.is_empty() {
+            self.durable.checkpoint::<OutboxRecord<M>>()?;
+        }
+        Ok(true)
+    }
+
+    /// Send everything still pending again, oldest first, returning how many that was.
+    pub fn resend(&self, writer: &UnboundedSender<Envelope<M>>) -> usize {
+        for envelope in self.durable.state.pending.values() {
+            let _ = writer.send(envelope.clone());
+        }
+        let resent = self.durable.state.pending.len();
+        debug!(resent, "resent unacknowledged messages");
+        resent
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = &Envelope<M>> {
+        self.durable.state.pending.values()
+    }
+
+    /// The highest `msg_id` still pending.
+    pub fn last_msg_id(&self) -> Option<usize> {
+        self.durable.state.pending.keys().next_back().copied()
+    }
+
+    /// Fold everything journaled so far into a snapshot, dropping acknowledged messages
+    /// from the WAL.
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        self.durable.checkpoint::<OutboxRecord<M>>()
+    }
+}
@@ -0,0 +1,365 @@
+use std::{fmt::Debug, sync::Arc, time::Duration};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{debug, warn};
+use crate::{clock::{Clock, WallClock}, kv::{KvClient, KvError}, message::ErrorCode, txn::{Key, MicroOp, TxnError, Value}};
+
+
+/// Everything lin-kv knows about one key: at most one pending lock plus every
+/// committed version. Since lin-kv only offers single-key CAS, this replaces
+/// Percolator's separate lock/write/data columns with one JSON document per key.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Cell {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock: Option<Lock>,
+    /// Committed versions, ordered by commit timestamp.
+    #[serde(default)]
+    pub writes: Vec<Version>,
+    /// Versions committed before this have been dropped, so snapshots older than it
+    /// can't be read from this key any more.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub horizon: u64,
+}
+
+fn is_zero(ts: &u64) -> bool {
+    *ts == 0
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lock {
+    /// The key whose commit decides the fate of this lock.
+    pub primary: Key,
+    pub start_ts: u64,
+    /// The prewritten value, installed if the transaction commits.
+    pub value: Value,
+    /// When the lock runs out, in milliseconds since the Unix epoch: lin-tso's timestamps
+    /// only count up, so `start_ts` says nothing about how long ago the lock was taken.
+    /// A primary lock past this belongs to a transaction presumed dead.
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Version {
+    pub start_ts: u64,
+    pub commit_ts: u64,
+    pub value: Value,
+    /// When the version was installed, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub committed_at: u64,
+}
+
+impl Cell {
+    /// The newest value committed at or before `ts`.
+    pub fn visible_at(&self, ts: u64) -> Option<Value> {
+        self.writes.iter().rev().find(|version| version.commit_ts <= ts).map(|version| version.value)
+    }
+
+    fn committed(&self, start_ts: u64) -> Option<&Version> {
+        self.writes.iter().find(|version| version.start_ts == start_ts)
+    }
+
+    /// Add `version` where it belongs in the history, and drop the versions nobody
+    /// should need any more: everything older than the newest version installed at or
+    /// before `low_water` (in milliseconds since the Unix epoch), which stays so that
+    /// snapshots since then still see it.
+    fn commit(&mut self, version: Version, low_water: u64) {
+        let at = self.writes.partition_point(|committed| committed.commit_ts <= version.commit_ts);
+        self.writes.insert(at, version);
+        let Some(newest_old) = self.writes.iter().rposition(|committed| committed.committed_at <= low_water) else {
+            return;
+        };
+        if newest_old > 0 {
+            self.writes.drain(..newest_old);
+            self.horizon = self.writes[0].commit_ts;
+        }
+    }
+}
+
+
+fn cell_key(key: Key) -> String {
+    format!("percolator-{key}")
+}
+
+
+/// Percolator-style snapshot-isolated transactions over `lin-kv`, with timestamps
+/// from `lin-tso`.
+///
+/// Reads see the snapshot as of the transaction's start timestamp, writes are
+/// buffered and then prewritten (locked) starting with a primary key, and the
+/// transaction commits the moment the primary's lock turns into a version.
+/// Secondaries are committed afterwards; readers that stumble on a leftover
+/// secondary lock look at the primary to roll it forward or back. A primary lock
+/// that outlives its TTL is rolled back by whoever runs into it, so a transaction
+/// that died between prewrite and commit doesn't keep its keys locked forever.
+#[derive(Debug)]
+pub struct Percolator<M> {
+    kv: KvClient<M>,
+    tso: KvClient<M>,
+    /// How many times to retry a CAS that lost a race before giving up.
+    max_cas_attempts: usize,
+    lock_ttl: Duration,
+    /// How long committed versions are kept once a newer one is older still.
+    history: Duration,
+    clock: WallClock,
+}
+
+impl<M> Clone for Percolator<M> {
+    fn clone(&self) -> Self {
+        Self {
+            kv: self.kv.clone(),
+            tso: self.tso.clone(),
+            max_cas_attempts: self.max_cas_attempts,
+            lock_ttl: self.lock_ttl,
+            history: self.history,
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+
+impl<M> Percolator<M>
+where
+    M: Serialize + DeserializeOwned + Debug,
+{
+    pub fn new(kv: KvClient<M>, tso: KvClient<M>) -> Self {
+        Self {
+            kv,
+            tso,
+            max_cas_attempts: 5,
+            lock_ttl: Duration::from_secs(3),
+            history: Duration::from_secs(30),
+            clock: WallClock::default(),
+        }
+    }
+
+    /// How long our locks are good for. Past that, our transaction may be rolled back
+    /// by anyone who runs into one of its locks.
+    pub fn with_lock_ttl(mut self, lock_ttl: Duration) -> Self {
+        self.lock_ttl = lock_ttl;
+        self
+    }
+
+    /// Keep versions around for `history` after they're overwritten. A transaction
+    /// that's been running longer than that may find the snapshot it reads from gone,
+    /// and conflict; and one that left a secondary lock nobody ran into for that long
+    /// has that lock rolled back, once its primary no longer says whether it committed.
+    pub fn with_history(mut self, history: Duration) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Tell the time (and so whether a lock has run out) by `clock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = WallClock::new(clock);
+        self
+    }
+
+    /// Run `txn` to completion, returning it with every read filled in.
+    pub async fn execute(&self, txn: Vec<MicroOp>) -> Result<Vec<MicroOp>, TxnError> {
+        let start_ts = self.tso.ts().await?;
+        let mut buffered: Vec<(Key, Value)> = vec![];
+        let mut completed = Vec::with_capacity(txn.len());
+
+        for op in txn {
+            match op {
+                MicroOp::Read { key, .. } => {
+                    let value = match buffered.iter().find(|(buffered_key, _)| *buffered_key == key) {
+                        Some((_, value)) => Some(*value),
+                        None => self.read(key, start_ts).await?,
+                    };
+                    completed.push(MicroOp::Read { key, value });
+                },
+                MicroOp::Write { key, value } => {
+                    match buffered.iter_mut().find(|(buffered_key, _)| *buffered_key == key) {
+                        Some(entry) => entry.1 = value,
+                        None => buffered.push((key, value)),
+                    }
+                    completed.push(MicroOp::Write { key, value });
+                },
+            }
+        }
+
+        if buffered.is_empty() {
+            return Ok(completed);
+        }
+
+        let primary = buffered[0].0;
+        let mut locked = vec![];
+        for &(key, value) in &buffered {
+            if let Err(err) = self.prewrite(key, value, primary, start_ts).await {
+                self.rollback(&locked, start_ts).await;
+                return Err(err);
+            }
+            locked.push(key);
+        }
+
+        let commit_ts = match self.tso.ts().await {
+            Ok(ts) => ts,
+            Err(err) => {
+                self.rollback(&locked, start_ts).await;
+                return Err(err.into());
+            }
+        };
+
+        // The commit point: once the primary's lock is swapped for a version, the
+        // transaction is committed no matter what happens to the secondaries.
+        if let Err(err) = self.commit_key(primary, start_ts, commit_ts).await {
+            if let TxnError::Conflict(_) = err {
+                self.rollback(&locked, start_ts).await;
+            }
+            return Err(err);
+        }
+
+        for &key in &locked[1..] {
+            if let Err(err) = self.commit_key(key, start_ts, commit_ts).await {
+                // Readers will roll it forward from the primary when they run into it.
+                warn!(key, start_ts, commit_ts, error = %err, "failed to commit secondary");
+            }
+        }
+        debug!(start_ts, commit_ts, num_writes = locked.len(), "committed transaction");
+        Ok(completed)
+    }
+
+    async fn load(&self, key: Key) -> Result<Option<Cell>, KvError> {
+        self.kv.read(cell_key(key)).await
+    }
+
+    async fn store(&self, key: Key, from: Option<&Cell>, to: &Cell) -> Result<bool, KvError> {
+        let default = Cell::default();
+        match self.kv.cas(cell_key(key), from.unwrap_or(&default), to, from.is_none()).await {
+            Ok(()) => Ok(true),
+            Err(err) if err.is(ErrorCode::PreconditionFailed) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn read(&self, key: Key, start_ts: u64) -> Result<Option<Value>, TxnError> {
+        for _ in 0..self.max_cas_attempts {
+            let cell = self.load(key).await?.unwrap_or_default();
+            match &cell.lock {
+                Some(lock) if lock.start_ts <= start_ts => self.resolve_lock(key, &cell, lock).await?,
+                _ if start_ts < cell.horizon => {
+                    return Err(TxnError::Conflict(format!("key {key} no longer has versions as old as {start_ts}")));
+                },
+                _ => return Ok(cell.visible_at(start_ts)),
+            }
+        }
+        Err(TxnError::Conflict(format!("key {key} stayed locked while reading")))
+    }
+
+    fn expired(&self, lock: &Lock) -> bool {
+        lock.expires_at <= self.clock.now_ms()
+    }
+
+    /// Install a version of `cell` committed at `commit_ts` by the transaction that
+    /// started at `start_ts`, dropping the versions that makes too old to keep.
+    fn install(&self, cell: &mut Cell, start_ts: u64, commit_ts: u64, value: Value) {
+        let now = self.clock.now_ms();
+        cell.commit(Version { start_ts, commit_ts, value, committed_at: now }, now.saturating_sub(self.history.as_millis() as u64));
+    }
+
+    /// Clean up a lock left behind by some other transaction, based on what its primary says.
+    /// Callers should look at the key again afterwards, since losing a race here leaves it as it was.
+    async fn resolve_lock(&self, key: Key, cell: &Cell, lock: &Lock) -> Result<(), TxnError> {
+        if key == lock.primary {
+            if !self.expired(lock) {
+                return Err(TxnError::Conflict(format!("key {key} is being committed by txn {}", lock.start_ts)));
+            }
+            warn!(key, start_ts = lock.start_ts, "rolling back expired primary lock");
+            let mut resolved = cell.clone();
+            resolved.lock = None;
+            self.store(key, Some(cell), &resolved).await?;
+            return Ok(());
+        }
+        let primary = self.load(lock.primary).await?.unwrap_or_default();
+
+        let mut resolved = cell.clone();
+        resolved.lock = None;
+        if let Some(version) = primary.committed(lock.start_ts) {
+            debug!(key, start_ts = lock.start_ts, "rolling secondary lock forward");
+            self.install(&mut resolved, lock.start_ts, version.commit_ts, lock.value);
+        } else if let Some(primary_lock) = primary.lock.as_ref().filter(|primary_lock| primary_lock.start_ts == lock.start_ts) {
+            if !self.expired(primary_lock) {
+                return Err(TxnError::Conflict(format!("key {key} is locked by in-flight txn {}", lock.start_ts)));
+            }
+            // Rolling back the primary is what aborts the transaction. If it commits
+            // first, the CAS fails and the next look rolls this lock forward instead.
+            warn!(key, primary = lock.primary, start_ts = lock.start_ts, "rolling back expired primary lock");
+            let mut rolled_back = primary.clone();
+            rolled_back.lock = None;
+            if !self.store(lock.primary, Some(&primary), &rolled_back).await? {
+                return Ok(());
+            }
+        } else {
+            debug!(key, start_ts = lock.start_ts, "rolling back orphaned secondary lock");
+        }
+        // Losing this race is fine: whoever won resolved it for us.
+        self.store(key, Some(cell), &resolved).await?;
+        Ok(())
+    }
+
+    async fn prewrite(&self, key: Key, value: Value, primary: Key, start_ts: u64) -> Result<(), TxnError> {
+        for _ in 0..self.max_cas_attempts {
+            let current = self.load(key).await?;
+            let cell = current.clone().unwrap_or_default();
+
+            if let Some(lock) = &cell.lock {
+                // Fails with a conflict unless the lock's transaction is over (or presumed dead).
+                self.resolve_lock(key, &cell, lock).await?;
+                continue;
+            }
+            if let Some(latest) = cell.writes.last() {
+                if latest.commit_ts >= start_ts {
+                    return Err(TxnError::Conflict(format!("key {key} was written at {} after we started at {start_ts}", latest.commit_ts)));
+                }
+            }
+
+            let mut locked = cell;
+            locked.lock = Some(Lock { primary, start_ts, value, expires_at: self.clock.now_ms() + self.lock_ttl.as_millis() as u64 });
+            if self.store(key, current.as_ref(), &locked).await? {
+                return Ok(());
+            }
+        }
+        Err(TxnError::Conflict(format!("lost too many races prewriting key {key}")))
+    }
+
+    async fn commit_key(&self, key: Key, start_ts: u64, commit_ts: u64) -> Result<(), TxnError> {
+        let indeterminate = |err: KvError| TxnError::Indeterminate(err);
+        for _ in 0..self.max_cas_attempts {
+            let current = self.load(key).await.map_err(indeterminate)?;
+            let Some(cell) = current else {
+                return Err(TxnError::Conflict(format!("key {key} vanished before commit")));
+            };
+            let lock = match &cell.lock {
+                Some(lock) if lock.start_ts == start_ts => lock.clone(),
+                _ if cell.committed(start_ts).is_some() => return Ok(()),
+                _ => return Err(TxnError::Conflict(format!("lock on key {key} was rolled back"))),
+            };
+
+            let mut committed = cell.clone();
+            committed.lock = None;
+            self.install(&mut committed, start_ts, commit_ts, lock.value);
+            if self.store(key, Some(&cell), &committed).await.map_err(indeterminate)? {
+                return Ok(());
+            }
+        }
+        Err(TxnError::Indeterminate(KvError::GaveUp(format!("lost too many races committing key {key}"))))
+    }
+
+    /// Best-effort removal of our own locks after an abort. Anything left behind is
+    /// rolled back by readers, since the primary will never be committed.
+    async fn rollback(&self, keys: &[Key], start_ts: u64) {
+        for &key in keys {
+            let Ok(Some(cell)) = self.load(key).await else {
+                continue;
+            };
+            if cell.lock.as_ref().map(|lock| lock.start_ts) != Some(start_ts) {
+                continue;
+            }
+            let mut unlocked = cell.clone();
+            unlocked.lock = None;
+            if let Err(err) = self.store(key, Some(&cell), &unlocked).await {
+                warn!(key, start_ts, error = %err, "failed to roll back lock");
+            }
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::{str::FromStr, time::Duration};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use crate::message::RawEnvelope;
+
+
+/// Which way a message is going through the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From Maelstrom to the node.
+    In,
+    /// From the node to Maelstrom.
+    Out,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Drop,
+    Delay(Duration),
+}
+
+
+/// What happens to messages that match, written like `drop type=sync src=n1 p=0.3` or
+/// `delay 250ms dir=out type=broadcast_ok after=10 count=5`.
+///
+/// Filters: `type`, `src`, `dest` and `dir` (`in` or `out`) must all match if given.
+/// Of the messages that do, the rule skips the first `after`, then applies to at most
+/// `count` of them, each with probability `p`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub action: Action,
+    pub kind: Option<String>,
+    pub source: Option<String>,
+    pub destination: Option<String>,
+    pub direction: Option<Direction>,
+    pub probability: f64,
+    pub after: usize,
+    pub count: Option<usize>,
+}
+
+impl Rule {
+    pub fn matches(&self, direction: Direction, envelope: &RawEnvelope) -> bool {
+        self.direction.map_or(true, |expected| expected == direction)
+            && self.kind.as_deref().map_or(true, |expected| envelope.kind().as_deref() == Some(expected))
+            && self.source.as_deref().map_or(true, |expected| *envelope.source == *expected)
+            && self.destination.as_deref().map_or(true, |expected| *envelope.destination == *expected)
+    }
+}
+
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let (number, unit) = duration.split_at(duration.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(duration.len()));
+    let number: f64 = number.parse().map_err(|err| format!("bad duration `{duration}`: {err}"))?;
+    let seconds = match unit {
+        "ms" | "" => number / 1000.,
+        "s" => number,
+        _ => return Err(format!("bad duration `{duration}` (use ms or s)")),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|err| format!("bad duration `{duration}`: {err}"))
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let mut words = rule.split_whitespace();
+        let action = match words.next() {
+            Some("drop") => Action::Drop,
+            Some("delay") => Action::Delay(parse_duration(words.next().ok_or("`delay` needs a duration, e.g. `delay 100ms`")?)?),
+            other => return Err(format!("expected `drop` or `delay`, not {other:?}")),
+        };
+        let mut rule = Rule { action, kind: None, source: None, destination: None, direction: None, probability: 1., after: 0, count: None };
+        for word in words {
+            let Some((key, value)) = word.split_once('=') else {
+                return Err(format!("expected `key=value`, not `{word}`"));
+            };
+            let number = |value: &str| value.parse::<usize>().map_err(|err| format!("bad `{key}`: {err}"));
+            match key {
+                "type" => rule.kind = Some(value.to_owned()),
+                "src" => rule.source = Some(value.to_owned()),
+                "dest" => rule.destination = Some(value.to_owned()),
+                "dir" => rule.direction = Some(match value {
+                    "in" => Direction::In,
+                    "out" => Direction::Out,
+                    _ => return Err(format!("`dir` is `in` or `out`, not `{value}`")),
+                }),
+                "p" => rule.probability = value.parse().ok().filter(|p| (0. ..=1.).contains(p)).ok_or(format!("`p` is a probability, not `{value}`"))?,
+                "after" => rule.after = number(value)?,
+                "count" => rule.count = Some(number(value)?),
+                _ => return Err(format!("unknown filter `{key}` (expected type, src, dest, dir, p, after or count)")),
+            }
+        }
+        Ok(rule)
+    }
+}
+
+
+/// What the proxy does with one message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Forward,
+    Drop,
+    Delay(Duration),
+}
+
+
+/// The rules a proxy runs with, and how far along each one is. The first rule that
+/// fires for a message decides its fate; a message no rule fires for is forwarded.
+#[derive(Debug)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    /// How many messages each rule has matched, and how many it has acted on.
+    progress: Vec<(usize, usize)>,
+    rng: StdRng,
+}
+
+impl Rules {
+    pub fn new(rules: Vec<Rule>, seed: u64) -> Self {
+        let progress = vec![(0, 0); rules.len()];
+        Self { rules, progress, rng: StdRng::seed_from_u64(seed) }
+    }
+
+    pub fn decide(&mut self, direction: Direction, envelope: &RawEnvelope) -> Verdict {
+        for (rule, (matched, acted)) in self.rules.iter().zip(&mut self.progress) {
+            if !rule.matches(direction, envelope) {
+                continue;
+            }
+            *matched += 1;
+            if *matched <= rule.after || rule.count.is_some_and(|count| *acted >= count) {
+                continue;
+            }
+            if rule.probability < 1. && !self.rng.gen_bool(rule.probability) {
+                continue;
+            }
+            *acted += 1;
+            return match rule.action {
+                Action::Drop => Verdict::Drop,
+                Action::Delay(delay) => Verdict::Delay(delay),
+            };
+        }
+        Verdict::Forward
+    }
+}
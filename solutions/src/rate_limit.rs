@@ -0,0 +1,164 @@
+use std::{collections::{HashMap, VecDeque}, sync::OnceLock, time::{Duration, Instant}};
+
+
+/// A token bucket: `rate` messages a second on average, in bursts of up to a second's
+/// worth (and at least one message).
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket, as of `now`.
+    pub fn new(rate: f64, now: Instant) -> Self {
+        let capacity = rate.max(1.);
+        Self { rate, capacity, tokens: capacity, refilled: now }
+    }
+
+    /// How long until there's a token, as of `now` (no time at all if there's one already).
+    pub fn wait(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled = now;
+        if self.tokens >= 1. {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1. - self.tokens) / self.rate)
+    }
+
+    /// Take a token, which [`wait`](Self::wait) just said is there.
+    pub fn take(&mut self) {
+        self.tokens -= 1.;
+    }
+}
+
+
+/// How fast a node may send, in messages a second: in all, and to any one destination.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    pub global: Option<f64>,
+    pub per_destination: Option<f64>,
+}
+
+/// Holds the [`io_channel`](crate::io::io_channel) writer to its [`Limits`]: a message
+/// goes out once both the global bucket and its destination's have a token for it.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: Limits,
+    global: Option<TokenBucket>,
+    destinations: HashMap<String, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> Self {
+        let global = limits.global.map(|rate| TokenBucket::new(rate, Instant::now()));
+        Self { limits, global, destinations: HashMap::new() }
+    }
+
+    /// Take what a message to `destination` at `now` needs, or say how long to wait before
+    /// asking again. Nothing is taken unless the message can go.
+    pub fn take(&mut self, destination: &str, now: Instant) -> Result<(), Duration> {
+        let per_destination = self.limits.per_destination.map(|rate| self.destinations.entry(destination.to_owned()).or_insert_with(|| TokenBucket::new(rate, now)));
+        let mut buckets: Vec<&mut TokenBucket> = self.global.iter_mut().chain(per_destination).collect();
+        let wait = buckets.iter_mut().map(|bucket| bucket.wait(now)).max().unwrap_or_default();
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        buckets.into_iter().for_each(TokenBucket::take);
+        Ok(())
+    }
+}
+
+
+/// Messages a [`RateLimiter`] held back, queued per destination in the order they were
+/// sent, so one destination over its limit doesn't hold up what's going anywhere else.
+#[derive(Debug)]
+pub struct Backlog<T> {
+    queues: HashMap<String, VecDeque<T>>,
+    /// When to ask the limiter again about the front of each destination's queue.
+    due: HashMap<String, Instant>,
+}
+
+impl<T> Default for Backlog<T> {
+    fn default() -> Self {
+        Self { queues: HashMap::new(), due: HashMap::new() }
+    }
+}
+
+impl<T> Backlog<T> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Hand `message` back if it can go to `destination` at `now`: the limiter lets it
+    /// through, and nothing to the same destination is queued ahead of it. Otherwise
+    /// it's queued until [`pop`](Self::pop) lets it go.
+    pub fn offer(&mut self, limiter: &mut RateLimiter, destination: &str, message: T, now: Instant) -> Option<T> {
+        if let Some(queue) = self.queues.get_mut(destination) {
+            queue.push_back(message);
+            return None;
+        }
+        match limiter.take(destination, now) {
+            Ok(()) => Some(message),
+            Err(wait) => {
+                self.queues.insert(destination.to_owned(), VecDeque::from([message]));
+                self.due.insert(destination.to_owned(), now + wait);
+                None
+            },
+        }
+    }
+
+    /// The next queued message the limiter lets through at `now`, if any.
+    pub fn pop(&mut self, limiter: &mut RateLimiter, now: Instant) -> Option<T> {
+        let ready: Vec<String> = self.due.iter().filter(|(_, &due)| due <= now).map(|(destination, _)| destination.clone()).collect();
+        for destination in ready {
+            match limiter.take(&destination, now) {
+                Ok(()) => return self.pop_from(&destination),
+                Err(wait) => {
+                    self.due.insert(destination, now + wait);
+                },
+            }
+        }
+        None
+    }
+
+    /// When [`pop`](Self::pop) might next have something, if anything's queued.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.due.values().min().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.values().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    fn pop_from(&mut self, destination: &str) -> Option<T> {
+        let queue = self.queues.get_mut(destination)?;
+        let message = queue.pop_front();
+        if queue.is_empty() {
+            self.queues.remove(destination);
+            self.due.remove(destination);
+        }
+        message
+    }
+}
+
+
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+/// Have every [`io_channel`](crate::io::io_channel) from here on send no faster than
+/// `limits` allow. Only the first call counts.
+pub fn limit(limits: Limits) {
+    LIMITS.set(limits).ok();
+}
+
+/// The limits this process sends under, if there are any.
+pub fn global() -> Option<Limits> {
+    LIMITS.get().copied().filter(|limits| limits.global.is_some() || limits.per_destination.is_some())
+}
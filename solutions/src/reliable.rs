@@ -0,0 +1,160 @@
+use std::{collections::{BTreeMap, HashMap}, time::{Duration, Instant}};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace};
+use crate::message::{self, NodeId};
+
+
+/// How many messages from a peer are delivered between acks to it (more are acked when
+/// it sends duplicates, which means our acks aren't getting through).
+const ACK_EVERY: u64 = 16;
+
+
+/// What goes over the wire between two nodes in a [`Session`], in place of the bare
+/// message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Frame<M> {
+    /// The `seq`th message to this peer, counting from 1.
+    Data { seq: u64, message: M },
+    /// "Send `from` through `to` again": they never showed up.
+    Nack { from: u64, to: u64 },
+    /// "I have everything up to `upto`": no need to keep it around for retransmits.
+    Ack { upto: u64 },
+}
+
+
+#[derive(Debug)]
+struct Outgoing<M> {
+    next_seq: u64,
+    /// What's been sent but not acked, with when it was last sent.
+    unacked: BTreeMap<u64, (M, Instant)>,
+}
+
+impl<M> Default for Outgoing<M> {
+    fn default() -> Self {
+        Self { next_seq: 1, unacked: BTreeMap::new() }
+    }
+}
+
+#[derive(Debug)]
+struct Incoming<M> {
+    /// Everything up to here has been delivered, in order.
+    delivered: u64,
+    /// What arrived ahead of a gap.
+    buffered: BTreeMap<u64, M>,
+    /// The last `delivered` we acked.
+    acked: u64,
+}
+
+impl<M> Default for Incoming<M> {
+    fn default() -> Self {
+        Self { delivered: 0, buffered: BTreeMap::new(), acked: 0 }
+    }
+}
+
+
+/// What came of a [`Frame`] from a peer: messages ready to be handled, in the order
+/// they were sent, and frames to send back to it.
+#[derive(Debug)]
+pub struct Received<M> {
+    pub delivered: Vec<M>,
+    pub replies: Vec<Frame<M>>,
+}
+
+
+/// Reliable, ordered delivery between nodes on top of Maelstrom's lossy, reordering
+/// network, for workloads that opt into it: every message to a peer is numbered, a peer
+/// that sees a gap asks for what's missing with a [`Frame::Nack`], holds back what
+/// came after it until it's filled, and acks what it has now and then so the sender
+/// can let go of it. Anything left unacked for too long (the last message before a
+/// lull, say, which no later one shows to be missing) is sent again by
+/// [`retransmits`](Session::retransmits).
+///
+/// Like the failure detector, it only keeps the books: the workload wraps what it sends
+/// with [`send`](Session::send), unwraps what it receives with
+/// [`receive`](Session::receive), and sends whatever frames they hand back.
+#[derive(Debug)]
+pub struct Session<M> {
+    retransmit_after: Duration,
+    outgoing: HashMap<NodeId, Outgoing<M>>,
+    incoming: HashMap<NodeId, Incoming<M>>,
+}
+
+impl<M: Clone> Session<M> {
+    /// Unacked messages are sent again every `retransmit_after`.
+    pub fn new(retransmit_after: Duration) -> Self {
+        Self { retransmit_after, outgoing: HashMap::new(), incoming: HashMap::new() }
+    }
+
+    /// Number `message` for `peer`, and keep it until it's acked.
+    pub fn send(&mut self, peer: &str, message: M, now: Instant) -> Frame<M> {
+        let outgoing = self.outgoing.entry(message::intern(peer)).or_default();
+        let seq = outgoing.next_seq;
+        outgoing.next_seq += 1;
+        outgoing.unacked.insert(seq, (message.clone(), now));
+        Frame::Data { seq, message }
+    }
+
+    /// Take in a frame from `peer` at `now`.
+    pub fn receive(&mut self, peer: &str, frame: Frame<M>, now: Instant) -> Received<M> {
+        let mut received = Received { delivered: vec![], replies: vec![] };
+        match frame {
+            Frame::Data { seq, message } => {
+                let incoming = self.incoming.entry(message::intern(peer)).or_default();
+                if seq <= incoming.delivered || incoming.buffered.contains_key(&seq) {
+                    trace!(peer, seq, "dropping duplicate");
+                    incoming.acked = incoming.delivered;
+                    received.replies.push(Frame::Ack { upto: incoming.delivered });
+                    return received;
+                }
+                incoming.buffered.insert(seq, message);
+                while let Some(message) = incoming.buffered.remove(&(incoming.delivered + 1)) {
+                    incoming.delivered += 1;
+                    received.delivered.push(message);
+                }
+                if let Some(&next) = incoming.buffered.keys().next() {
+                    debug!(peer, from = incoming.delivered + 1, to = next - 1, "asking for a gap to be filled");
+                    received.replies.push(Frame::Nack { from: incoming.delivered + 1, to: next - 1 });
+                }
+                if incoming.delivered >= incoming.acked + ACK_EVERY {
+                    incoming.acked = incoming.delivered;
+                    received.replies.push(Frame::Ack { upto: incoming.delivered });
+                }
+            },
+            Frame::Nack { from, to } => {
+                if let Some(outgoing) = self.outgoing.get_mut(peer) {
+                    for (&seq, (message, sent)) in outgoing.unacked.range_mut(from..=to) {
+                        *sent = now;
+                        received.replies.push(Frame::Data { seq, message: message.clone() });
+                    }
+                }
+            },
+            Frame::Ack { upto } => {
+                if let Some(outgoing) = self.outgoing.get_mut(peer) {
+                    outgoing.unacked = outgoing.unacked.split_off(&(upto + 1));
+                }
+            },
+        }
+        received
+    }
+
+    /// What to send again at `now`: whatever's gone unacked for `retransmit_after` since
+    /// it was last sent, to whom.
+    pub fn retransmits(&mut self, now: Instant) -> Vec<(NodeId, Frame<M>)> {
+        let mut retransmits = vec![];
+        for (peer, outgoing) in &mut self.outgoing {
+            for (&seq, (message, sent)) in &mut outgoing.unacked {
+                if now.saturating_duration_since(*sent) >= self.retransmit_after {
+                    *sent = now;
+                    retransmits.push((peer.clone(), Frame::Data { seq, message: message.clone() }));
+                }
+            }
+        }
+        retransmits
+    }
+
+    /// How many messages to `peer` are waiting on an ack.
+    pub fn unacked(&self, peer: &str) -> usize {
+        self.outgoing.get(peer).map_or(0, |outgoing| outgoing.unacked.len())
+    }
+}
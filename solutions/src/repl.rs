@@ -0,0 +1,172 @@
+use serde_json::{Map, Value};
+use crate::message::{Body, Envelope};
+
+
+/// The fields each message type's positional arguments fill in, in order. Any type
+/// (listed or not) also takes `field=value` arguments.
+const POSITIONAL: &[(&str, &[&str])] = &[
+    ("init", &["node_id", "node_ids"]),
+    ("echo", &["echo"]),
+    ("broadcast", &["message"]),
+    ("read", &["key"]),
+    ("topology", &["topology"]),
+    ("add", &["delta"]),
+    ("write", &["key", "value"]),
+    ("cas", &["key", "from", "to"]),
+    ("send", &["key", "msg"]),
+    ("poll", &["offsets"]),
+    ("commit_offsets", &["offsets"]),
+    ("list_committed_offsets", &["keys"]),
+    ("txn", &["txn"]),
+];
+
+
+/// Turns shorthand like `init n1 [n1,n2]`, `broadcast 42` or `txn [[r,1,null]]` into
+/// envelopes, numbering them as it goes. Messages go to the node named by the last
+/// `init` unless prefixed with `@<node>`.
+#[derive(Debug, Clone)]
+pub struct Shorthand {
+    pub source: String,
+    pub destination: String,
+    next_msg_id: usize,
+}
+
+impl Shorthand {
+    pub fn new(source: &str) -> Self {
+        Self { source: source.to_owned(), destination: "n1".to_owned(), next_msg_id: 1 }
+    }
+
+    /// Translate one line of input. Blank lines and `#` comments give `Ok(None)`.
+    ///
+    /// Besides `<type> [args...]`, a line can be a JSON body (which gets wrapped in an
+    /// envelope), or a whole JSON envelope (which is sent as is).
+    pub fn parse(&mut self, line: &str) -> Result<Option<Envelope<Value>>, String> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        if line.starts_with('{') {
+            let value: Value = serde_json::from_str(line).map_err(|err| format!("bad JSON: {err}"))?;
+            if value.get("body").is_some() {
+                return serde_json::from_value(value).map(Some).map_err(|err| format!("bad envelope: {err}"));
+            }
+            let Value::Object(body) = value else {
+                unreachable!("starts with `{{`");
+            };
+            return Ok(Some(self.envelope(None, body)));
+        }
+
+        let mut words = split(line)?.into_iter().peekable();
+        let destination = words.next_if(|word| word.starts_with('@')).map(|word| word[1..].to_owned());
+        let Some(kind) = words.next() else {
+            return Err("expected a message type".to_owned());
+        };
+        let positional = POSITIONAL.iter().find(|(name, _)| *name == kind).map_or(&[][..], |(_, fields)| *fields);
+
+        let mut body = Map::new();
+        body.insert("type".to_owned(), Value::from(kind.as_str()));
+        let mut fields = positional.iter();
+        for word in words {
+            match word.split_once('=').filter(|(field, _)| is_field_name(field)) {
+                Some((field, value)) => body.insert(field.to_owned(), value_of(value)),
+                None => match fields.next() {
+                    Some(field) => body.insert((*field).to_owned(), value_of(&word)),
+                    None => return Err(format!("too many arguments for `{kind}` (takes {})", positional.join(", "))),
+                },
+            };
+        }
+        if kind == "init" {
+            if let Some(node_id) = body.get("node_id").and_then(Value::as_str) {
+                self.destination = node_id.to_owned();
+            }
+        }
+        Ok(Some(self.envelope(destination, body)))
+    }
+
+    fn envelope(&mut self, destination: Option<String>, mut body: Map<String, Value>) -> Envelope<Value> {
+        let msg_id = match body.remove("msg_id").and_then(|msg_id| msg_id.as_u64()) {
+            Some(msg_id) => msg_id as usize,
+            None => {
+                self.next_msg_id += 1;
+                self.next_msg_id - 1
+            },
+        };
+        let in_reply_to = body.remove("in_reply_to").and_then(|id| id.as_u64()).map(|id| id as usize);
+        let destination = destination.unwrap_or_else(|| self.destination.clone());
+        Envelope::new(&self.source, &destination, Body { msg_id: Some(msg_id), in_reply_to, message: Value::Object(body) })
+    }
+}
+
+
+/// What `help` prints: the message types with positional arguments, and the rest of
+/// the syntax.
+pub fn usage() -> String {
+    let mut usage = String::from("<type> [args...] [field=value...]   (prefix with @<node> to send elsewhere)\n");
+    for (kind, fields) in POSITIONAL {
+        usage.push_str(&format!("  {kind}{}\n", fields.iter().map(|field| format!(" <{field}>")).collect::<String>()));
+    }
+    usage.push_str("{...}   a JSON body, or a whole envelope\n");
+    usage
+}
+
+
+fn is_field_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Split on whitespace, except inside quotes and brackets.
+fn split(line: &str) -> Result<Vec<String>, String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let (mut depth, mut quoted, mut escaped) = (0i32, false, false);
+    for c in line.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '[' | '{' if !quoted => depth += 1,
+            ']' | '}' if !quoted => depth -= 1,
+            c if c.is_whitespace() && !quoted && depth == 0 => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                continue;
+            },
+            _ => {},
+        }
+        word.push(c);
+    }
+    if quoted || depth != 0 {
+        return Err(format!("unbalanced quotes or brackets in `{line}`"));
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// A word as JSON if it is JSON, a list if it's a bracketed list of (possibly bare)
+/// words like `[n1,n2]`, and a string otherwise.
+fn value_of(word: &str) -> Value {
+    if let Ok(value) = serde_json::from_str(word) {
+        return value;
+    }
+    let Some(inner) = word.strip_prefix('[').and_then(|word| word.strip_suffix(']')) else {
+        return Value::from(word);
+    };
+    let mut items = vec![];
+    let (mut depth, mut start) = (0, 0);
+    for (index, c) in inner.char_indices() {
+        match c {
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(&inner[start..index]);
+                start = index + 1;
+            },
+            _ => {},
+        }
+    }
+    items.push(&inner[start..]);
+    Value::Array(items.into_iter().map(str::trim).filter(|item| !item.is_empty()).map(value_of).collect())
+}
@@ -0,0 +1,354 @@
+use std::{collections::BTreeMap, fs, io, path::Path, time::Duration};
+use serde_json::Value;
+use crate::{checker::Percentiles, edn::{self, Edn}, message::Envelope};
+
+
+/// Jepsen's `:valid?`, which can also be `:unknown` when a checker couldn't decide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Validity {
+    Valid,
+    Invalid,
+    Unknown,
+}
+
+impl Validity {
+    fn of(result: &Edn) -> Self {
+        match result.get("valid?") {
+            Some(Edn::Bool(true)) => Validity::Valid,
+            Some(Edn::Bool(false)) => Validity::Invalid,
+            _ => Validity::Unknown,
+        }
+    }
+}
+
+
+/// `:stats`: how the client operations turned out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpStats {
+    pub count: u64,
+    pub ok_count: u64,
+    pub fail_count: u64,
+    pub info_count: u64,
+}
+
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NetCounts {
+    pub send_count: u64,
+    pub recv_count: u64,
+    pub msg_count: u64,
+    pub msgs_per_op: Option<f64>,
+}
+
+impl NetCounts {
+    fn of(counts: &Edn) -> Self {
+        let count = |key| counts.get(key).and_then(Edn::as_i64).unwrap_or_default() as u64;
+        Self {
+            send_count: count("send-count"),
+            recv_count: count("recv-count"),
+            msg_count: count("msg-count"),
+            msgs_per_op: counts.get("msgs-per-op").and_then(Edn::as_f64),
+        }
+    }
+}
+
+
+/// `:net`: message counts for all traffic, for traffic to and from clients, and for
+/// traffic between servers, which is what the efficiency targets are measured against.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NetStats {
+    pub all: NetCounts,
+    pub clients: NetCounts,
+    pub servers: NetCounts,
+}
+
+
+/// What Maelstrom concluded about a run, from `results.edn`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Results {
+    pub valid: Validity,
+    /// Whether the workload's own checker (as opposed to, say, the perf or
+    /// exceptions checkers) passed.
+    pub workload_valid: Validity,
+    pub stats: Option<OpStats>,
+    pub net: Option<NetStats>,
+    /// `(quantile, latency)` pairs, for workloads that report how long values took to
+    /// become visible everywhere (e.g. broadcast's `:stable-latencies`).
+    pub stable_latencies: Vec<(f64, Duration)>,
+    /// Everything else that's in the file.
+    pub raw: Edn,
+}
+
+impl Results {
+    pub fn parse(input: &str) -> io::Result<Self> {
+        let raw = edn::parse(input).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let stats = raw.get("stats").map(|stats| {
+            let count = |key| stats.get(key).and_then(Edn::as_i64).unwrap_or_default() as u64;
+            OpStats { count: count("count"), ok_count: count("ok-count"), fail_count: count("fail-count"), info_count: count("info-count") }
+        });
+        let net = raw.get("net").map(|net| NetStats {
+            all: net.get("all").map(NetCounts::of).unwrap_or_default(),
+            clients: net.get("clients").map(NetCounts::of).unwrap_or_default(),
+            servers: net.get("servers").map(NetCounts::of).unwrap_or_default(),
+        });
+        let mut stable_latencies: Vec<_> =
+            raw
+            .get_in(&["workload", "stable-latencies"])
+            .and_then(Edn::as_map)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|(quantile, millis)| Some((quantile.as_f64()?, Duration::from_secs_f64(millis.as_f64()?.max(0.) / 1000.))))
+            .collect();
+        stable_latencies.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+
+        Ok(Self {
+            valid: Validity::of(&raw),
+            workload_valid: raw.get("workload").map(Validity::of).unwrap_or(Validity::Unknown),
+            stats,
+            net,
+            stable_latencies,
+            raw,
+        })
+    }
+
+    /// The stable latency reported for `quantile` (e.g. `0.5` for the median).
+    pub fn stable_latency(&self, quantile: f64) -> Option<Duration> {
+        self.stable_latencies.iter().find(|(q, _)| *q == quantile).map(|(_, latency)| *latency)
+    }
+}
+
+
+/// One client operation from `latency-raw.csv`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyPoint {
+    /// When the operation was invoked, since the start of the test.
+    pub time: Duration,
+    pub latency: Duration,
+    pub f: Option<String>,
+    /// `ok`, `fail` or `info`.
+    pub kind: Option<String>,
+}
+
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Latencies {
+    pub points: Vec<LatencyPoint>,
+}
+
+impl Latencies {
+    /// Parse CSV with a header row. `time` (seconds) and `latency` (milliseconds)
+    /// columns are required; `f` and `type` are picked up if present, and any other
+    /// columns are ignored.
+    pub fn parse(input: &str) -> io::Result<Self> {
+        let invalid = |line: usize, message: String| io::Error::new(io::ErrorKind::InvalidData, format!("line {line}: {message}"));
+        let mut lines = input.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+        let Some((_, header)) = lines.next() else {
+            return Ok(Self::default());
+        };
+        let columns: Vec<_> = header.split(',').map(|column| column.trim().trim_matches('"')).collect();
+        let column = |name| columns.iter().position(|&column| column == name);
+        let (Some(time), Some(latency)) = (column("time"), column("latency")) else {
+            return Err(invalid(1, format!("expected `time` and `latency` columns, found {columns:?}")));
+        };
+        let (f, kind) = (column("f"), column("type"));
+
+        let mut points = vec![];
+        for (index, line) in lines {
+            let fields: Vec<_> = line.split(',').map(|field| field.trim().trim_matches('"').trim_start_matches(':')).collect();
+            let number = |column: usize| {
+                fields
+                .get(column)
+                .and_then(|field| field.parse::<f64>().ok())
+                .filter(|number| number.is_finite() && *number >= 0.)
+                .ok_or_else(|| invalid(index + 1, format!("expected a non-negative number in column {column}")))
+            };
+            let text = |column: Option<usize>| column.and_then(|column| fields.get(column)).map(|field| field.to_string());
+            points.push(LatencyPoint {
+                time: Duration::from_secs_f64(number(time)?),
+                latency: Duration::from_secs_f64(number(latency)? / 1000.),
+                f: text(f),
+                kind: text(kind),
+            });
+        }
+        Ok(Self { points })
+    }
+
+    /// Latency percentiles over the operations that match `f` (or all of them).
+    pub fn percentiles(&self, f: Option<&str>) -> Option<Percentiles> {
+        let samples =
+            self.points
+            .iter()
+            .filter(|point| f.is_none() || point.f.as_deref() == f)
+            .map(|point| point.latency)
+            .collect();
+        Percentiles::from_samples(samples)
+    }
+}
+
+
+/// Messages seen in `messages.log`, where every line that has an envelope in it
+/// (as JSON, possibly after some prefix like a timestamp or direction) is one message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageCounts {
+    pub total: u64,
+    pub by_type: BTreeMap<String, u64>,
+    /// Between two nodes (`n*`).
+    pub between_servers: u64,
+    /// To or from a client (`c*`).
+    pub with_clients: u64,
+}
+
+impl MessageCounts {
+    pub fn parse(input: &str) -> Self {
+        let mut counts = Self::default();
+        for line in input.lines() {
+            let Some(start) = line.find('{') else {
+                continue;
+            };
+            let Ok(envelope) = serde_json::from_str::<Envelope<Value>>(&line[start..]) else {
+                continue;
+            };
+            counts.total += 1;
+            let kind = envelope.body.message.get("type").and_then(Value::as_str).unwrap_or("?");
+            *counts.by_type.entry(kind.to_owned()).or_default() += 1;
+            let is = |node: &str, prefix| node.starts_with(prefix) && node[1..].chars().all(|c| c.is_ascii_digit());
+            if is(&envelope.source, 'n') && is(&envelope.destination, 'n') {
+                counts.between_servers += 1;
+            }
+            if is(&envelope.source, 'c') || is(&envelope.destination, 'c') {
+                counts.with_clients += 1;
+            }
+        }
+        counts
+    }
+}
+
+
+/// Everything we understand from one test's directory in Maelstrom's store (usually
+/// `store/latest`). Only `results.edn` is required.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoreRun {
+    pub results: Results,
+    pub latencies: Option<Latencies>,
+    pub messages: Option<MessageCounts>,
+}
+
+impl StoreRun {
+    pub fn load(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        let optional = |name: &str| match fs::read_to_string(dir.join(name)) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        };
+        Ok(Self {
+            results: Results::parse(&fs::read_to_string(dir.join("results.edn"))?)?,
+            latencies: optional("latency-raw.csv")?.as_deref().map(Latencies::parse).transpose()?,
+            messages: optional("messages.log")?.as_deref().map(MessageCounts::parse),
+        })
+    }
+}
+
+
+/// The efficiency targets published for the Gossip Glomers challenges that have any.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Target {
+    pub name: &'static str,
+    /// Between servers, per client operation.
+    pub max_msgs_per_op: f64,
+    pub max_median_latency: Duration,
+    pub max_max_latency: Duration,
+}
+
+impl Target {
+    pub const BROADCAST_3D: Target = Target {
+        name: "3d",
+        max_msgs_per_op: 30.,
+        max_median_latency: Duration::from_millis(400),
+        max_max_latency: Duration::from_millis(600),
+    };
+    pub const BROADCAST_3E: Target = Target {
+        name: "3e",
+        max_msgs_per_op: 20.,
+        max_median_latency: Duration::from_secs(1),
+        max_max_latency: Duration::from_secs(2),
+    };
+
+    pub fn all() -> &'static [Target] {
+        &[Target::BROADCAST_3D, Target::BROADCAST_3E]
+    }
+
+    /// Look a target up by its challenge name (`3d`, `3e`).
+    pub fn named(name: &str) -> Option<Target> {
+        Target::all().iter().copied().find(|target| target.name.eq_ignore_ascii_case(name))
+    }
+}
+
+
+/// How a run measured up against a [`Target`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    pub target: Target,
+    pub msgs_per_op: Option<f64>,
+    pub median_latency: Option<Duration>,
+    pub max_latency: Option<Duration>,
+    /// Why the run missed the target, if it did. A measurement we couldn't find counts
+    /// as a miss, and so does failing Maelstrom's own checks.
+    pub misses: Vec<String>,
+}
+
+impl Analysis {
+    pub fn passed(&self) -> bool {
+        self.misses.is_empty()
+    }
+}
+
+
+impl StoreRun {
+    /// Messages between servers per client operation: Maelstrom's own figure if it has
+    /// one, and otherwise counted from `messages.log`.
+    pub fn msgs_per_op(&self) -> Option<f64> {
+        let from_log = || {
+            let ops = self.results.stats?.count;
+            let messages = self.messages.as_ref()?.between_servers;
+            (ops > 0).then(|| messages as f64 / ops as f64)
+        };
+        self.results.net.and_then(|net| net.servers.msgs_per_op).or_else(from_log)
+    }
+
+    /// Median and maximum latency: how long values took to become visible everywhere
+    /// for workloads that report it (that's what the broadcast targets are about),
+    /// and per-operation latency otherwise.
+    pub fn latencies(&self) -> (Option<Duration>, Option<Duration>) {
+        if let (Some(median), Some(max)) = (self.results.stable_latency(0.5), self.results.stable_latency(1.)) {
+            return (Some(median), Some(max));
+        }
+        match self.latencies.as_ref().and_then(|latencies| latencies.percentiles(None)) {
+            Some(percentiles) => (Some(percentiles.p50), Some(percentiles.max)),
+            None => (None, None),
+        }
+    }
+
+    pub fn analyze(&self, target: Target) -> Analysis {
+        let msgs_per_op = self.msgs_per_op();
+        let (median_latency, max_latency) = self.latencies();
+        let mut misses = vec![];
+
+        if self.results.valid != Validity::Valid {
+            misses.push(format!("Maelstrom says the run is {:?}", self.results.valid));
+        }
+        match msgs_per_op {
+            None => misses.push("no msgs-per-op to go by".to_owned()),
+            Some(msgs) if msgs > target.max_msgs_per_op => misses.push(format!("{msgs:.2} msgs per op (target {})", target.max_msgs_per_op)),
+            _ => {},
+        }
+        for (label, latency, limit) in [("median", median_latency, target.max_median_latency), ("max", max_latency, target.max_max_latency)] {
+            match latency {
+                None => misses.push(format!("no {label} latency to go by")),
+                Some(latency) if latency > limit => misses.push(format!("{label} latency {latency:?} (target {limit:?})")),
+                _ => {},
+            }
+        }
+        Analysis { target, msgs_per_op, median_latency, max_latency, misses }
+    }
+}
@@ -0,0 +1,215 @@
+use std::{collections::HashMap, fmt, time::Duration};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::{debug, trace};
+use crate::{message::{self, Body, Envelope, NodeId}, metrics};
+
+
+/// How many times a request can be forwarded before it's given up on, unless a
+/// [`Router`] is told otherwise: plenty for "not the leader, try them", without letting
+/// nodes that disagree about who's in charge pass it round forever.
+pub const DEFAULT_TTL: u32 = 8;
+
+
+/// Where a forwarded request started, carried along with it (and back with its reply).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Route {
+    /// The client that sent it.
+    #[serde(deserialize_with = "message::interned")]
+    pub origin: NodeId,
+    /// The `msg_id` the client sent it with.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub origin_msg_id: Option<usize>,
+    /// How many more times it can be forwarded.
+    pub ttl: u32,
+}
+
+/// A workload's message, with the [`Route`] it's on if it's been forwarded. On the wire
+/// it's the message as it would otherwise be, with a `route` field when there is one,
+/// so clients (and anything else not in on it) see nothing different.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Routed<M> {
+    #[serde(flatten)]
+    pub message: M,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub route: Option<Route>,
+}
+
+impl<M> From<M> for Routed<M> {
+    fn from(message: M) -> Self {
+        Self { message, route: None }
+    }
+}
+
+
+/// What to do with a message a [`Router`] has [received](Router::receive).
+#[derive(Debug)]
+pub enum Inbound<M> {
+    /// Handle it: it's the request as the client sent it, forwarded here or not.
+    Handle(Envelope<M>),
+    /// Send it on: a reply, on its way back to the client.
+    Send(Envelope<Routed<M>>),
+    /// A reply to a request that's been forgotten; there's nowhere to send it.
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutingError {
+    /// The request has been forwarded as many times as it can be.
+    TtlExpired,
+}
+
+impl fmt::Display for RoutingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoutingError::TtlExpired => write!(f, "forwarded too many times without reaching a node that handles it"),
+        }
+    }
+}
+
+impl std::error::Error for RoutingError {}
+
+
+/// The hop a request came in from: who to send its reply back to, and as a reply to
+/// which `msg_id`.
+#[derive(Debug, Clone)]
+struct Hop {
+    node: NodeId,
+    msg_id: usize,
+    route: Route,
+    at: Instant,
+}
+
+
+/// Lets a node that isn't the one to handle a request (it's the wrong shard, or not the
+/// leader) [forward](Self::forward) it towards one that is, and gets the reply back to
+/// the client the way it came, one hop at a time. The handler at the end gets the
+/// request just as the client sent it, and replies to the client as usual; it never
+/// knows it was forwarded. Each hop uses up some of the request's TTL, so one that's
+/// passed round in circles is eventually given up on.
+///
+/// Like [`reliable::Session`](crate::reliable::Session), it only keeps the books: the
+/// workload sends and receives [`Routed`] messages, unwraps what it receives with
+/// [`receive`](Self::receive), and wraps what it sends with [`send`](Self::send).
+/// Requests whose replies never come are forgotten after a while (see
+/// [`expire`](Self::expire)).
+#[derive(Debug)]
+pub struct Router {
+    ttl: u32,
+    forget_after: Duration,
+    /// Requests forwarded from here, by the `msg_id` they were forwarded with.
+    forwarded: HashMap<usize, Hop>,
+    /// Requests forwarded to here and handed to the handler, by their client and the
+    /// client's `msg_id`.
+    delivered: HashMap<(NodeId, usize), Hop>,
+}
+
+impl Router {
+    /// Requests can be forwarded `ttl` times, and are forgotten `forget_after` they were
+    /// last forwarded.
+    pub fn new(ttl: u32, forget_after: Duration) -> Self {
+        Self { ttl, forget_after, forwarded: HashMap::new(), delivered: HashMap::new() }
+    }
+
+    /// Take in a message at `now`.
+    pub fn receive<M>(&mut self, envelope: Envelope<Routed<M>>, now: Instant) -> Inbound<M> {
+        let Envelope { source, destination, body: Body { msg_id, in_reply_to, message: Routed { message, route } } } = envelope;
+        let Some(route) = route else {
+            return Inbound::Handle(Envelope { source, destination, body: Body { msg_id, in_reply_to, message } });
+        };
+
+        if let Some(in_reply_to) = in_reply_to {
+            let Some(hop) = self.forwarded.remove(&in_reply_to) else {
+                debug!(%source, in_reply_to, "dropping a routed reply to a forgotten request");
+                metrics::global().increment("routing.orphan_replies");
+                return Inbound::Drop;
+            };
+            trace!(to = %hop.node, origin = %route.origin, "passing a reply back");
+            return Inbound::Send(Self::reply_to(hop, destination, msg_id, message));
+        }
+
+        if let Some(origin_msg_id) = route.origin_msg_id {
+            if let Some(msg_id) = msg_id {
+                let key = (route.origin.clone(), origin_msg_id);
+                self.delivered.insert(key, Hop { node: source, msg_id, route: route.clone(), at: now });
+            }
+        }
+        Inbound::Handle(Envelope {
+            source: route.origin,
+            destination,
+            body: Body { msg_id: route.origin_msg_id, in_reply_to: None, message },
+        })
+    }
+
+    /// Send `envelope` (a request as the handler got it) on to `to`, as `msg_id`, at
+    /// `now`. Fails, leaving the handler to reply with an error, once it's been
+    /// forwarded as many times as it can be.
+    pub fn forward<M, D: message::ToNodeId + ?Sized>(&mut self, envelope: Envelope<M>, to: &D, msg_id: usize, now: Instant) -> Result<Envelope<Routed<M>>, RoutingError> {
+        let came_from = envelope.body.msg_id.and_then(|origin_msg_id| self.delivered.get(&(envelope.source.clone(), origin_msg_id)));
+        let ttl = came_from.map_or(self.ttl, |hop| hop.route.ttl);
+        if ttl == 0 {
+            debug!(origin = %envelope.source, "not forwarding a request that's run out of hops");
+            metrics::global().increment("routing.ttl_expired");
+            return Err(RoutingError::TtlExpired);
+        }
+        let route = Route { origin: envelope.source.clone(), origin_msg_id: envelope.body.msg_id, ttl: ttl - 1 };
+
+        if let Some(origin_msg_id) = envelope.body.msg_id {
+            let hop = self.delivered.remove(&(envelope.source.clone(), origin_msg_id))
+                .unwrap_or(Hop { node: envelope.source.clone(), msg_id: origin_msg_id, route: route.clone(), at: now });
+            self.forwarded.insert(msg_id, Hop { at: now, ..hop });
+        }
+        metrics::global().increment("routing.forwarded");
+        Ok(Envelope::new(
+            &envelope.destination,
+            to,
+            Body { msg_id: Some(msg_id), in_reply_to: None, message: Routed { message: envelope.body.message, route: Some(route) } },
+        ))
+    }
+
+    /// Wrap what the handler sends: a reply to a request that was forwarded here goes
+    /// back the way the request came, and anything else goes where it's addressed.
+    pub fn send<M>(&mut self, envelope: Envelope<M>) -> Envelope<Routed<M>> {
+        let Envelope { source, destination, body: Body { msg_id, in_reply_to, message } } = envelope;
+        if let Some(hop) = in_reply_to.and_then(|in_reply_to| self.delivered.remove(&(destination.clone(), in_reply_to))) {
+            return Self::reply_to(hop, source, msg_id, message);
+        }
+        Envelope { source, destination, body: Body { msg_id, in_reply_to, message: message.into() } }
+    }
+
+    /// Forget requests forwarded `forget_after` ago or more whose replies haven't come
+    /// back (they, or the replies, were probably lost), returning how many.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let before = self.pending();
+        let forget_after = self.forget_after;
+        let fresh = |hop: &Hop| now.saturating_duration_since(hop.at) < forget_after;
+        self.forwarded.retain(|_, hop| fresh(hop));
+        self.delivered.retain(|_, hop| fresh(hop));
+        let expired = before - self.pending();
+        metrics::global().add("routing.expired", expired as u64);
+        expired
+    }
+
+    /// How many requests are waiting on a reply to pass back.
+    pub fn pending(&self) -> usize {
+        self.forwarded.len() + self.delivered.len()
+    }
+
+    /// `message`, a reply, sent from `source` to the hop its request came in from: to the
+    /// client itself (as a plain reply) if that's where the request started.
+    fn reply_to<M>(hop: Hop, source: NodeId, msg_id: Option<usize>, message: M) -> Envelope<Routed<M>> {
+        let route = (hop.node != hop.route.origin).then_some(hop.route);
+        Envelope {
+            source,
+            destination: hop.node,
+            body: Body { msg_id, in_reply_to: Some(hop.msg_id), message: Routed { message, route } },
+        }
+    }
+}
+
+impl Default for Router {
+    /// [`DEFAULT_TTL`] hops, with requests forgotten after a minute.
+    fn default() -> Self {
+        Self::new(DEFAULT_TTL, Duration::from_secs(60))
+    }
+}
@@ -0,0 +1,278 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, fmt::Debug, sync::{Arc, Mutex, OnceLock}, time::Duration};
+use tokio::{sync::{mpsc::UnboundedSender, oneshot}, time::Instant};
+use tracing::{debug, info_span, trace, Instrument};
+use crate::{circuit_breaker::CircuitBreaker, clock::{self, Clock}, deadline, io, message::{self, Body, Envelope, NodeId}, metrics, rtt::RttEstimator};
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RpcError {
+    /// No reply arrived within the deadline. The request may or may not have been applied.
+    Timeout,
+    /// The writer (or the whole node) is shutting down.
+    Disconnected,
+    /// Calls to the destination keep timing out, so this one wasn't even sent (see
+    /// [`CircuitBreaker`]).
+    CircuitOpen,
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "rpc timed out"),
+            RpcError::Disconnected => write!(f, "rpc channel disconnected"),
+            RpcError::CircuitOpen => write!(f, "destination unresponsive, not calling it for now"),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+
+/// How many finished calls are remembered, to recognize late and duplicate replies to
+/// them.
+const FINISHED_HISTORY: usize = 4096;
+
+
+struct Pending<M> {
+    destination: NodeId,
+    sent: Instant,
+    reply: oneshot::Sender<Envelope<M>>,
+}
+
+
+/// The msg_ids of calls that are over (answered, timed out, or given up on), so a reply
+/// to one of them can be told apart from a message that never concerned us.
+#[derive(Default)]
+struct Finished {
+    order: VecDeque<usize>,
+    ids: HashSet<usize>,
+}
+
+impl Finished {
+    fn insert(&mut self, msg_id: usize) {
+        if !self.ids.insert(msg_id) {
+            return;
+        }
+        self.order.push_back(msg_id);
+        if self.order.len() > FINISHED_HISTORY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+}
+
+
+struct Inner<M> {
+    node_id: OnceLock<String>,
+    writer: UnboundedSender<Envelope<M>>,
+    message_id: fn() -> usize,
+    pending: Mutex<HashMap<usize, Pending<M>>>,
+    finished: Mutex<Finished>,
+    /// How long replies from each destination have been taking.
+    rtts: Mutex<HashMap<NodeId, RttEstimator>>,
+    breaker: Mutex<Option<CircuitBreaker>>,
+    /// What calls time out by.
+    clock: Mutex<Arc<dyn Clock>>,
+}
+
+
+/// Request/response on top of the fire-and-forget io channel: remembers which of our
+/// msg_ids are waiting on a reply and hands each reply to whoever is awaiting it.
+///
+/// The main loop has to offer every inbound envelope to [`RpcClient::try_complete`]
+/// first, and handlers that make calls must be spawned rather than awaited inline,
+/// otherwise the reply can never be read.
+pub struct RpcClient<M> {
+    inner: Arc<Inner<M>>,
+}
+
+impl<M> Clone for RpcClient<M> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone() }
+    }
+}
+
+impl<M> Debug for RpcClient<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RpcClient")
+            .field("node_id", &self.inner.node_id.get())
+            .field("pending", &self.inner.pending.lock().unwrap().len())
+            .finish()
+    }
+}
+
+
+impl<M: Debug> RpcClient<M> {
+    /// `message_id` should be the same generator the node uses for everything else it
+    /// sends, so our msg_ids never collide.
+    pub fn new(writer: UnboundedSender<Envelope<M>>, message_id: fn() -> usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                node_id: OnceLock::new(),
+                writer,
+                message_id,
+                pending: Mutex::new(HashMap::new()),
+                finished: Mutex::new(Finished::default()),
+                rtts: Mutex::new(HashMap::new()),
+                breaker: Mutex::new(None),
+                clock: Mutex::new(clock::system()),
+            })
+        }
+    }
+
+    /// We only learn who we are from the `init` message, so this gets filled in late.
+    pub fn set_node_id(&self, node_id: &str) {
+        let _ = self.inner.node_id.set(node_id.to_owned());
+    }
+
+    /// Fail calls fast to destinations that keep timing out, as `breaker` says.
+    pub fn set_circuit_breaker(&self, breaker: CircuitBreaker) {
+        *self.inner.breaker.lock().unwrap() = Some(breaker);
+    }
+
+    /// Time calls out by `clock` rather than the system one.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.inner.clock.lock().unwrap() = clock;
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.inner.clock.lock().unwrap().clone()
+    }
+
+    pub fn node_id(&self) -> &str {
+        self.inner.node_id.get().map(String::as_str).unwrap_or_default()
+    }
+
+    /// Send `message` without expecting a reply.
+    pub fn send(&self, destination: &str, message: M) -> Result<(), RpcError> {
+        let msg_id = (self.inner.message_id)();
+        let envelope = Envelope::new(
+            self.node_id(),
+            destination,
+            Body { msg_id: Some(msg_id), in_reply_to: None, message }
+        );
+        trace!(msg_id, destination, parent = io::current_span_id(), "sending message");
+        self.inner.writer.send(envelope).map_err(|_| RpcError::Disconnected)
+    }
+
+    /// How long a call to `destination` waits on its reply: `initial` until one of its
+    /// replies has come back, then however long they've been taking (see
+    /// [`RttEstimator::timeout`]).
+    pub fn timeout_for(&self, destination: &str, initial: Duration) -> Duration {
+        self.inner.rtts.lock().unwrap().get(destination).map_or(initial, |rtt| rtt.timeout(initial))
+    }
+
+    /// Send `message` and wait for the reply to it, in an `rpc` span: up to `timeout` the
+    /// first time around, then as long as [`timeout_for`](Self::timeout_for) says replies
+    /// from `destination` should take, but never past the [deadline](deadline::current)
+    /// of the request it's made for.
+    pub async fn call(&self, destination: &str, message: M, timeout: Duration) -> Result<Envelope<M>, RpcError> {
+        let timeout = deadline::remaining_or(self.timeout_for(destination, timeout));
+        let clock = self.clock();
+        if let Some(breaker) = self.inner.breaker.lock().unwrap().as_mut() {
+            if !breaker.allow(destination, clock.now()) {
+                trace!(destination, "not calling an unresponsive destination");
+                return Err(RpcError::CircuitOpen);
+            }
+        }
+        self.collect_garbage();
+        let msg_id = (self.inner.message_id)();
+        let (tx, rx) = oneshot::channel();
+        let pending = Pending { destination: message::intern(destination), sent: clock.now(), reply: tx };
+        self.inner.pending.lock().unwrap().insert(msg_id, pending);
+        trace!(msg_id, destination, parent = io::current_span_id(), "sending rpc");
+
+        let span = info_span!("rpc", msg_id, destination);
+        let result = async {
+            let envelope = Envelope::new(
+                self.node_id(),
+                destination,
+                Body { msg_id: Some(msg_id), in_reply_to: None, message }
+            );
+            if self.inner.writer.send(envelope).is_err() {
+                self.inner.pending.lock().unwrap().remove(&msg_id);
+                return Err(RpcError::Disconnected);
+            }
+            match clock.timeout(timeout, rx).await {
+                Some(Ok(reply)) => Ok(reply),
+                Some(Err(_)) => Err(RpcError::Disconnected),
+                None => {
+                    self.inner.pending.lock().unwrap().remove(&msg_id);
+                    self.inner.finished.lock().unwrap().insert(msg_id);
+                    Err(RpcError::Timeout)
+                }
+            }
+        }.instrument(span).await;
+
+        if let Some(breaker) = self.inner.breaker.lock().unwrap().as_mut() {
+            if !matches!(result, Err(RpcError::Disconnected)) {
+                breaker.record(destination, result.is_err(), clock.now());
+            }
+        }
+        match &result {
+            Ok(_) => trace!(msg_id, destination, parent = io::current_span_id(), "got rpc reply"),
+            Err(RpcError::Timeout) => debug!(msg_id, destination, ?timeout, "rpc timed out"),
+            Err(RpcError::Disconnected | RpcError::CircuitOpen) => {},
+        }
+        result
+    }
+
+    /// Deliver `envelope` to the caller waiting on it, if it's a reply to one of our calls.
+    /// A reply to a call that's already over (one that came in after the call timed out,
+    /// or a duplicate) is an orphan: it's counted in `rpc.orphan_replies` and dropped.
+    /// Anything else is handed back for the regular handlers.
+    pub fn try_complete(&self, envelope: Envelope<M>) -> Option<Envelope<M>> {
+        let Some(in_reply_to) = envelope.body.in_reply_to else {
+            return Some(envelope);
+        };
+        let mut pending = self.inner.pending.lock().unwrap();
+        match pending.get(&in_reply_to) {
+            Some(waiting) if waiting.destination == envelope.source => {},
+            Some(_) => return Some(envelope),
+            None if self.inner.finished.lock().unwrap().ids.contains(&in_reply_to) => {
+                debug!(in_reply_to, source = %envelope.source, "dropping a reply to a call that's over");
+                metrics::global().increment("rpc.orphan_replies");
+                return None;
+            },
+            None => return Some(envelope),
+        }
+        let waiting = pending.remove(&in_reply_to).unwrap();
+        drop(pending);
+        self.inner.finished.lock().unwrap().insert(in_reply_to);
+        let took = self.clock().now().saturating_duration_since(waiting.sent);
+        self.inner.rtts.lock().unwrap().entry(waiting.destination).or_default().sample(took);
+
+        trace!(in_reply_to, source = %envelope.source, "completing rpc");
+        // If the caller gave up in the meantime there's nobody left to tell.
+        let _ = waiting.reply.send(envelope);
+        None
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.inner.pending.lock().unwrap().len()
+    }
+
+    /// Forget the calls nobody's waiting on any more, because the caller was dropped
+    /// before they could time out (when its request's [deadline](deadline::within) passed,
+    /// say). A reply to one later is an orphan. Runs before every call; returns how many
+    /// were forgotten.
+    pub fn collect_garbage(&self) -> usize {
+        let mut collected = vec![];
+        self.inner.pending.lock().unwrap().retain(|msg_id, pending| {
+            if pending.reply.is_closed() {
+                collected.push(*msg_id);
+            }
+            !pending.reply.is_closed()
+        });
+        if !collected.is_empty() {
+            debug!(collected = collected.len(), "forgot calls nobody's waiting on");
+            metrics::global().add("rpc.pending_collected", collected.len() as u64);
+            let mut finished = self.inner.finished.lock().unwrap();
+            for msg_id in &collected {
+                finished.insert(*msg_id);
+            }
+        }
+        collected.len()
+    }
+}
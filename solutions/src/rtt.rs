@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+
+/// The shortest timeout an [`RttEstimator`] suggests, however quick the replies: replies
+/// from a node that's busy for a moment shouldn't all time out.
+pub const MIN_TIMEOUT: Duration = Duration::from_millis(50);
+/// The longest timeout an [`RttEstimator`] suggests, however slow the replies.
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(5);
+
+
+/// Estimates how long replies from one destination take, from the round trips seen so
+/// far, the way TCP does (RFC 6298): a moving average of them (each new one weighing
+/// 1/8) and of how far they stray from it (1/4). How long to wait on a reply follows
+/// from those, so calls to a nearby node give up quickly and calls across a slow link
+/// (say, with Maelstrom's `--latency`) don't give up too soon.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RttEstimator {
+    smoothed: Option<Duration>,
+    variation: Duration,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take a round trip `rtt` into account.
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.smoothed {
+            None => {
+                self.smoothed = Some(rtt);
+                self.variation = rtt / 2;
+            },
+            Some(smoothed) => {
+                let deviation = smoothed.max(rtt) - smoothed.min(rtt);
+                self.variation = self.variation * 3 / 4 + deviation / 4;
+                self.smoothed = Some(smoothed * 7 / 8 + rtt / 8);
+            },
+        }
+    }
+
+    /// The smoothed round trip time, once there's been one.
+    pub fn smoothed(&self) -> Option<Duration> {
+        self.smoothed
+    }
+
+    /// How long to wait on a reply: `initial` until a round trip has been seen, then the
+    /// smoothed round trip plus four times its variation, between [`MIN_TIMEOUT`] and
+    /// [`MAX_TIMEOUT`].
+    pub fn timeout(&self, initial: Duration) -> Duration {
+        match self.smoothed {
+            None => initial,
+            Some(smoothed) => (smoothed + self.variation * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT),
+        }
+    }
+}
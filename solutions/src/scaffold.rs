@@ -0,0 +1,51 @@
+use std::{fs, io, path::{Path, PathBuf}};
+
+
+const WORKLOAD: &str = include_str!("../templates/workload/workload.rs");
+const BIN: &str = include_str!("../templates/workload/bin.rs");
+const TEST: &str = include_str!("../templates/workload/test.rs");
+
+
+/// Whether `name` works as a module, binary and test name: snake_case, starting with a
+/// letter.
+pub fn is_valid_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        && !matches!(name, "mod" | "self" | "super" | "crate" | "type" | "match" | "loop" | "move" | "ref" | "static" | "use" | "where")
+}
+
+/// The files a new workload called `name` starts out with, relative to the crate root:
+/// the node itself under `src/workloads`, a binary that runs it, and a test that runs
+/// it on the in-memory network.
+pub fn render(name: &str) -> Vec<(PathBuf, String)> {
+    vec![
+        (PathBuf::from(format!("src/workloads/{name}.rs")), WORKLOAD.to_owned()),
+        (PathBuf::from(format!("src/bin/{name}.rs")), BIN.replace("{{name}}", name)),
+        (PathBuf::from(format!("tests/{name}.rs")), TEST.replace("{{name}}", name)),
+    ]
+}
+
+/// Write a new workload's files under the crate at `root`, and declare its module in
+/// `src/workloads.rs`. Nothing is written if any of the files already exist.
+pub fn generate(root: &Path, name: &str) -> io::Result<Vec<PathBuf>> {
+    if !is_valid_name(name) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("`{name}` isn't a snake_case name")));
+    }
+    let files = render(name);
+    if let Some((path, _)) = files.iter().find(|(path, _)| root.join(path).exists()) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path.display())));
+    }
+
+    let modules = root.join("src/workloads.rs");
+    let mut declarations = fs::read_to_string(&modules)?;
+    let declaration = format!("pub mod {name};\n");
+    // Right after the last module declaration, so it stays with the others.
+    let at = declarations.rfind("pub mod ").map_or(0, |start| start + declarations[start..].find('\n').map_or(declarations.len() - start, |end| end + 1));
+    declarations.insert_str(at, &declaration);
+
+    for (path, contents) in &files {
+        fs::write(root.join(path), contents)?;
+    }
+    fs::write(&modules, declarations)?;
+    Ok(files.into_iter().map(|(path, _)| path).collect())
+}
@@ -0,0 +1,200 @@
+use std::{collections::HashMap, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::Duration};
+use rand::Rng;
+use tokio::{sync::Notify, time::Instant};
+use tracing::{debug, trace};
+use crate::{clock::{self, Clock}, shutdown, task, tuning};
+
+
+/// How much the workloads' background jobs [jitter](Job::jitter): enough that a cluster
+/// started all at once spreads its gossip out over a tick.
+pub const DEFAULT_JITTER: f64 = 0.2;
+
+
+/// How often a [`Job`] runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    /// At the [tick rate](tuning::Tunables::tick_rate), following it when it's configured.
+    TickRate,
+    Every(Duration),
+}
+
+impl Period {
+    pub fn duration(&self) -> Duration {
+        match self {
+            Period::TickRate => tuning::global().tick_rate(),
+            Period::Every(period) => *period,
+        }
+    }
+}
+
+
+/// A periodic job, built up from [`Job::new`] and handed to [`Scheduler::spawn`] along
+/// with what it does.
+#[derive(Debug, Clone)]
+pub struct Job {
+    name: String,
+    period: Period,
+    jitter: f64,
+    run_on_shutdown: bool,
+}
+
+impl Job {
+    pub fn new(name: &str, period: Period) -> Self {
+        Self { name: name.to_owned(), period, jitter: 0., run_on_shutdown: false }
+    }
+
+    /// Make every wait up to `jitter` (a fraction of the period, at most 1) longer or
+    /// shorter, at random, so nodes started together don't all do their work together.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0., 1.);
+        self
+    }
+
+    /// Run one last time when the node is asked to [`shutdown`], and have it wait for
+    /// that, for work that mustn't be cut off (a final checkpoint, say).
+    pub fn run_on_shutdown(mut self) -> Self {
+        self.run_on_shutdown = true;
+        self
+    }
+}
+
+
+#[derive(Debug)]
+struct Shared {
+    period: Mutex<Period>,
+    jitter: f64,
+    cancelled: AtomicBool,
+    /// Wakes the job up early, to pick up a new period or notice it's been cancelled.
+    changed: Notify,
+}
+
+impl Shared {
+    fn next_delay(&self) -> Duration {
+        let period = self.period.lock().unwrap().duration();
+        if self.jitter == 0. {
+            return period;
+        }
+        period.mul_f64(1. + rand::thread_rng().gen_range(-self.jitter..=self.jitter))
+    }
+}
+
+
+/// A job a [`Scheduler`] is running: to change how often it runs, or stop it. Dropping
+/// it leaves the job running.
+#[derive(Debug, Clone)]
+pub struct JobHandle {
+    name: Arc<str>,
+    shared: Arc<Shared>,
+}
+
+impl JobHandle {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn period(&self) -> Period {
+        *self.shared.period.lock().unwrap()
+    }
+
+    /// Run every `period` from now on: the next run is a period from now.
+    pub fn set_period(&self, period: Period) {
+        *self.shared.period.lock().unwrap() = period;
+        self.shared.changed.notify_one();
+    }
+
+    /// Stop running the job. A run that's under way finishes first.
+    pub fn cancel(&self) {
+        self.shared.cancelled.store(true, Ordering::SeqCst);
+        self.shared.changed.notify_one();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.shared.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+
+/// Runs a node's periodic work (gossip, commits, heartbeats, checkpoints) as named jobs,
+/// each in its own task, by a [`Clock`]. Jobs stop when the node is asked to
+/// [`shutdown`], when they're cancelled, or when another one takes their name.
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    clock: Arc<dyn Clock>,
+    jobs: Arc<Mutex<HashMap<String, JobHandle>>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::with_clock(clock::system())
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self { clock, jobs: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Start running `work` as `job`, first one period from now, with the time it runs
+    /// at.
+    pub fn spawn<F>(&self, job: Job, mut work: F) -> JobHandle
+    where
+        F: FnMut(Instant) + Send + 'static,
+    {
+        let handle = JobHandle {
+            name: job.name.as_str().into(),
+            shared: Arc::new(Shared { period: Mutex::new(job.period), jitter: job.jitter, cancelled: AtomicBool::new(false), changed: Notify::new() }),
+        };
+        if let Some(replaced) = self.jobs.lock().unwrap().insert(job.name.clone(), handle.clone()) {
+            replaced.cancel();
+        }
+
+        let clock = self.clock.clone();
+        let shared = handle.shared.clone();
+        let name = handle.name.clone();
+        let cleaning_up = job.run_on_shutdown.then(shutdown::Guard::new);
+        task::spawn(&job.name, async move {
+            let _cleaning_up = cleaning_up;
+            loop {
+                let stopping = tokio::select! {
+                    () = clock.sleep(shared.next_delay()) => false,
+                    () = shared.changed.notified() => {
+                        if shared.cancelled.load(Ordering::SeqCst) {
+                            debug!(job = %name, "job cancelled");
+                            return;
+                        }
+                        continue;
+                    },
+                    () = shutdown::requested() => true,
+                };
+                if stopping && !job.run_on_shutdown {
+                    return;
+                }
+                trace!(job = %name, "running job");
+                work(clock.now());
+                if stopping {
+                    return;
+                }
+            }
+        });
+        handle
+    }
+
+    /// The job running under `name`, if there is one.
+    pub fn job(&self, name: &str) -> Option<JobHandle> {
+        self.jobs.lock().unwrap().get(name).filter(|job| !job.is_cancelled()).cloned()
+    }
+
+    pub fn cancel_all(&self) {
+        for (_, job) in self.jobs.lock().unwrap().drain() {
+            job.cancel();
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,92 @@
+use std::{future::Future, io::{BufRead, BufReader, Write}, sync::mpsc::{self, Receiver, RecvTimeoutError}, time::{Duration, Instant}};
+use serde_json::{json, Value};
+use crate::{conformance::{self, Workload}, io, message::{Body, Envelope}};
+
+
+/// How long [`check`] waits on each reply.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+const NODE: &str = "n1";
+
+
+/// Start a node (whatever `server` starts) on the [loopback](io::loopback) transport and
+/// ask it what Maelstrom would first ask of a `workload` node: `init`, a `topology` if it
+/// takes one, then the workload's [script](Workload::script). Every reply should be what
+/// [`conformance`] expects of it. What's wrong, step by step, if anything: a node that
+/// forgot to handle `topology`, say, is found out here instead of halfway into a
+/// Maelstrom run.
+///
+/// The node runs on a thread and a runtime of its own, and stops once it's out of input.
+pub fn check<F, Fut>(workload: Workload, server: F) -> Vec<String>
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = ()>,
+{
+    let (mut requests, replies) = io::loopback();
+    std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build the tokio runtime")
+        .block_on(server());
+    });
+    let (lines_tx, lines) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in BufReader::new(replies).lines().map_while(Result::ok) {
+            if lines_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut steps = vec![json!({"type": "init", "node_id": NODE, "node_ids": [NODE]})];
+    if workload.takes_topology() {
+        steps.push(json!({"type": "topology", "topology": {NODE: []}}));
+    }
+    steps.extend(workload.script());
+
+    let mut problems = vec![];
+    let mut earlier: Vec<(Value, Value)> = vec![];
+    for (index, body) in steps.into_iter().enumerate() {
+        let step = format!("{} #{}", body["type"].as_str().unwrap_or("?"), index + 1);
+        let request = Envelope::new("c1", NODE, Body { msg_id: Some(index + 1), in_reply_to: None, message: body.clone() });
+        if writeln!(requests, "{}", serde_json::to_string(&request).unwrap()).is_err() {
+            problems.push(format!("{step}: the node stopped reading its input"));
+            break;
+        }
+        let reply = match reply_to(&request, &lines) {
+            Ok(reply) => reply,
+            Err(RecvTimeoutError::Disconnected) => {
+                problems.push(format!("{step}: the node stopped without replying"));
+                break;
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                problems.push(format!("{step}: no reply within {TIMEOUT:?}"));
+                // Without an `init`, there's no point asking for anything else.
+                if index == 0 {
+                    break;
+                }
+                continue;
+            },
+        };
+        problems.extend(conformance::check_reply(&request, &reply).into_iter().map(|problem| format!("{step}: {problem}")));
+        problems.extend(workload.check(&body, &reply.body.message, &earlier).map(|problem| format!("{step}: {problem}")));
+        earlier.push((body, reply.body.message));
+    }
+    problems
+}
+
+/// The node's reply to `request`, skipping anything else it writes (gossip, requests to
+/// Maelstrom's services, ...), if it comes within [`TIMEOUT`] and before the node stops.
+fn reply_to(request: &Envelope<Value>, lines: &Receiver<String>) -> Result<Envelope<Value>, RecvTimeoutError> {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let line = lines.recv_timeout(deadline.saturating_duration_since(Instant::now()))?;
+        let Ok(envelope) = serde_json::from_str::<Envelope<Value>>(&line) else {
+            continue;
+        };
+        if envelope.destination == request.source && envelope.body.in_reply_to == request.body.msg_id {
+            return Ok(envelope);
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::time::Duration;
+use serde_json::Value;
+use crate::message::Envelope;
+
+
+/// One message from a recorded session, and when it was seen, if the recording says.
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub time: Option<Duration>,
+    pub envelope: Envelope<Value>,
+}
+
+impl Recorded {
+    /// Read a message from one line of a recording: an envelope as JSON, optionally with
+    /// a `time` field (seconds since the session started) next to `src`, `dest` and
+    /// `body`. Anything before the first `{` (e.g. a log prefix, as in Maelstrom's
+    /// `messages.log`) is skipped. `None` if there's no envelope on the line.
+    pub fn parse_line(line: &str) -> Option<Self> {
+        let start = line.find('{')?;
+        let value: Value = serde_json::from_str(&line[start..]).ok()?;
+        let time =
+            value
+            .get("time")
+            .and_then(Value::as_f64)
+            .filter(|seconds| seconds.is_finite() && *seconds >= 0.)
+            .map(Duration::from_secs_f64);
+        let envelope = serde_json::from_value(value).ok()?;
+        Some(Self { time, envelope })
+    }
+
+    /// The `type` of the message body.
+    pub fn kind(&self) -> &str {
+        self.envelope.body.message.get("type").and_then(Value::as_str).unwrap_or("?")
+    }
+}
+
+
+/// Every message in a recorded session (NDJSON, or Maelstrom's `messages.log`), in the
+/// order they were recorded. Lines without an envelope are skipped.
+pub fn parse(input: &str) -> Vec<Recorded> {
+    input.lines().filter_map(Recorded::parse_line).collect()
+}
@@ -0,0 +1,109 @@
+use std::{sync::atomic::{AtomicBool, AtomicUsize, Ordering}, time::Duration};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+use crate::clock;
+
+
+/// How long [`finished`] waits on the tasks still cleaning up before giving up on them.
+const GRACE: Duration = Duration::from_secs(5);
+
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static ON_REQUEST: Notify = Notify::const_new();
+/// How many [`Guard`]s are still around.
+static CLEANING_UP: AtomicUsize = AtomicUsize::new(0);
+static ON_CLEANED_UP: Notify = Notify::const_new();
+
+
+/// Ask the node to stop: periodic work stops (see [`Scheduler`](crate::scheduler::Scheduler)),
+/// and tasks waiting on [`requested`] wrap up, like the stdout writer writing out what's
+/// queued. Only the first call counts.
+pub fn request() {
+    if !REQUESTED.swap(true, Ordering::SeqCst) {
+        ON_REQUEST.notify_waiters();
+    }
+}
+
+pub fn is_requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Wait until the node is asked to stop (right away if it already has been).
+pub async fn requested() {
+    let notified = ON_REQUEST.notified();
+    tokio::pin!(notified);
+    notified.as_mut().enable();
+    if is_requested() {
+        return;
+    }
+    notified.await;
+}
+
+
+/// Held by a task with something to finish once the node is asked to stop, so
+/// [`finished`] waits for it: dropping it says it's done.
+#[derive(Debug)]
+#[must_use = "finished only waits on guards that are held"]
+pub struct Guard(());
+
+impl Guard {
+    pub fn new() -> Self {
+        CLEANING_UP.fetch_add(1, Ordering::SeqCst);
+        Self(())
+    }
+}
+
+impl Default for Guard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if CLEANING_UP.fetch_sub(1, Ordering::SeqCst) == 1 {
+            ON_CLEANED_UP.notify_waiters();
+        }
+    }
+}
+
+
+/// Wait for every [`Guard`] to be dropped, or for a few seconds, whichever comes first.
+pub async fn finished() {
+    let cleaned_up = async {
+        loop {
+            let notified = ON_CLEANED_UP.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if CLEANING_UP.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    };
+    if clock::system().timeout(GRACE, cleaned_up).await.is_none() {
+        warn!(tasks = CLEANING_UP.load(Ordering::SeqCst), "gave up waiting on tasks to clean up");
+    }
+}
+
+
+/// Wait for SIGTERM or SIGINT (Ctrl-C), then [`request`] a shutdown.
+pub async fn on_signal() {
+    #[cfg(unix)]
+    let signal = {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        let mut interrupt = signal(SignalKind::interrupt()).expect("failed to listen for SIGINT");
+        tokio::select! {
+            _ = terminate.recv() => "SIGTERM",
+            _ = interrupt.recv() => "SIGINT",
+        }
+    };
+    #[cfg(not(unix))]
+    let signal = {
+        tokio::signal::ctrl_c().await.expect("failed to listen for Ctrl-C");
+        "Ctrl-C"
+    };
+    info!(signal, "shutting down");
+    request();
+}
@@ -0,0 +1,436 @@
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap, HashSet}, fmt::Debug, future::Future, sync::{Arc, Mutex}, time::Duration};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender}, task::JoinHandle, time::Instant};
+use tracing::{debug, info, trace, warn};
+use crate::{message::{self, Envelope, NodeId}, task};
+
+
+/// How long a message spends in flight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Latency {
+    Constant(Duration),
+    Uniform { min: Duration, max: Duration },
+    /// Exponentially distributed around `mean`, which is roughly what Maelstrom's
+    /// `--latency-dist exponential` does.
+    Exponential { mean: Duration },
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Latency::Constant(Duration::ZERO)
+    }
+}
+
+impl Latency {
+    pub fn sample(&self, rng: &mut impl Rng) -> Duration {
+        match *self {
+            Latency::Constant(latency) => latency,
+            Latency::Uniform { min, max } if min >= max => min,
+            Latency::Uniform { min, max } => rng.gen_range(min..=max),
+            Latency::Exponential { mean } => {
+                let uniform: f64 = rng.gen_range(f64::EPSILON..1.0);
+                mean.mul_f64(-uniform.ln())
+            },
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkConfig {
+    pub latency: Latency,
+    /// Chance that any given message is dropped outright.
+    pub loss: f64,
+    /// Chance that a message that isn't dropped is delivered twice.
+    pub duplication: f64,
+    /// Whether messages between the same two nodes may overtake each other. When off,
+    /// every link delivers in the order messages were sent, no matter the latency drawn.
+    pub reorder: bool,
+    /// Seeds every random decision the network makes. Falls back to `$SIM_SEED`, and
+    /// then to a random seed, which is logged so the run can be repeated.
+    pub seed: Option<u64>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            latency: Latency::default(),
+            loss: 0.,
+            duplication: 0.,
+            reorder: true,
+            seed: None,
+        }
+    }
+}
+
+
+/// How far off a node's clock is: it starts `offset_ms` ahead of true time (behind,
+/// if negative) and then gains `drift` seconds per second on top of that.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ClockSkew {
+    pub offset_ms: i64,
+    pub drift: f64,
+}
+
+
+/// Wall-clock time as one simulated node sees it. Every clock counts from the same
+/// (fixed) epoch at the moment the network started, and advances with tokio's clock,
+/// so under a paused runtime it's just as virtual and deterministic as the network.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    started: Instant,
+    skew: ClockSkew,
+}
+
+impl Clock {
+    /// What every simulated wall clock reads when the network starts (2024-01-01T00:00:00Z).
+    pub const EPOCH_MS: u64 = 1_704_067_200_000;
+
+    pub fn skew(&self) -> ClockSkew {
+        self.skew
+    }
+
+    /// Milliseconds since the Unix epoch, according to this node.
+    pub fn now_millis(&self) -> u64 {
+        let elapsed = self.started.elapsed().as_secs_f64() * 1000.;
+        let skewed = elapsed * (1. + self.skew.drift) + self.skew.offset_ms as f64;
+        (Self::EPOCH_MS as f64 + skewed).max(0.) as u64
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetworkStats {
+    pub sent: usize,
+    pub dropped: usize,
+    pub duplicated: usize,
+    pub delivered: usize,
+    /// Messages addressed to a node that was never connected.
+    pub unroutable: usize,
+    /// Messages that arrived over a link that was cut by a partition at the time.
+    pub partitioned: usize,
+}
+
+
+/// Something the nemesis can do to the network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// Cut every link between nodes in different groups, in both directions. Nodes
+    /// that aren't in any group keep talking to everyone.
+    Partition(Vec<Vec<String>>),
+    /// Cut the link from `from` to `to` only, so messages still flow the other way.
+    Block { from: String, to: String },
+    /// Restore every link.
+    Heal,
+}
+
+impl Fault {
+    pub fn partition(groups: &[&[&str]]) -> Self {
+        Fault::Partition(groups.iter().map(|group| group.iter().map(|node| node.to_string()).collect()).collect())
+    }
+
+    pub fn block(from: &str, to: &str) -> Self {
+        Fault::Block { from: from.to_owned(), to: to.to_owned() }
+    }
+}
+
+
+struct Inner<M> {
+    config: NetworkConfig,
+    seed: u64,
+    rng: StdRng,
+    inboxes: HashMap<NodeId, UnboundedSender<Envelope<M>>>,
+    /// When the last message on each (source, destination) link is due, so that links
+    /// can be kept FIFO when reordering is off.
+    last_delivery: HashMap<(NodeId, NodeId), Instant>,
+    /// Directed (source, destination) links that are currently cut.
+    blocked: HashSet<(NodeId, NodeId)>,
+    started: Instant,
+    skews: HashMap<String, ClockSkew>,
+    stats: NetworkStats,
+}
+
+
+/// An in-memory stand-in for Maelstrom's network, for running several nodes inside
+/// one process (and one test).
+///
+/// Every node gets the same pair of channels [`crate::io::io_channel`] would give it,
+/// so anything written against that interface runs here unchanged: envelopes sent on
+/// the writer are routed by their `dest` to the matching node's reader, after passing
+/// through the configured latency, loss, duplication and reordering. Partitions can
+/// be injected on the fly or on a schedule (see [`Fault`]); messages that arrive over
+/// a cut link are lost, just like in Maelstrom.
+///
+/// Runs are reproducible: all randomness comes from the seed, and all timing from
+/// tokio's clock. On a current-thread runtime with the clock paused (e.g.
+/// `#[tokio::test(start_paused = true)]`) time only moves when every task is idle, so
+/// the same seed replays the same deliveries at the same virtual instants, as long
+/// as the nodes themselves don't bring in other randomness.
+pub struct Network<M> {
+    inner: Arc<Mutex<Inner<M>>>,
+    outbound: UnboundedSender<Envelope<M>>,
+    router: JoinHandle<()>,
+}
+
+impl<M> Debug for Network<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        f.debug_struct("Network")
+            .field("config", &inner.config)
+            .field("seed", &inner.seed)
+            .field("nodes", &inner.inboxes.keys().collect::<Vec<_>>())
+            .field("stats", &inner.stats)
+            .finish()
+    }
+}
+
+impl<M> Drop for Network<M> {
+    fn drop(&mut self) {
+        self.router.abort();
+    }
+}
+
+
+impl<M> Network<M>
+where
+    M: Clone + Debug + Send + 'static,
+{
+    /// Start routing messages. Must be called from within a tokio runtime.
+    pub fn new(config: NetworkConfig) -> Self {
+        let seed =
+            config.seed
+            .or_else(|| std::env::var("SIM_SEED").ok()?.parse().ok())
+            .unwrap_or_else(rand::random);
+        info!(seed, "starting simulated network");
+
+        let inner = Arc::new(Mutex::new(Inner {
+            config,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            inboxes: HashMap::new(),
+            last_delivery: HashMap::new(),
+            blocked: HashSet::new(),
+            started: Instant::now(),
+            skews: HashMap::new(),
+            stats: NetworkStats::default(),
+        }));
+        let (outbound, outbound_rx) = unbounded_channel();
+        let router = task::spawn("sim router", route(inner.clone(), outbound_rx));
+        Self { inner, outbound, router }
+    }
+
+    /// Attach a node (or client, or service) called `id`, returning its writer and reader.
+    pub fn connect(&self, id: &str) -> (UnboundedSender<Envelope<M>>, UnboundedReceiver<Envelope<M>>) {
+        let (inbox, reader) = unbounded_channel();
+        if self.inner.lock().unwrap().inboxes.insert(message::intern(id), inbox).is_some() {
+            warn!(id, "reconnected a node that was already on the network");
+        }
+        (self.outbound.clone(), reader)
+    }
+
+    /// Connect `id` and run `server` on its channels in the background.
+    pub fn spawn<F, Fut>(&self, id: &str, server: F) -> JoinHandle<()>
+    where
+        F: FnOnce(UnboundedSender<Envelope<M>>, UnboundedReceiver<Envelope<M>>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (writer, reader) = self.connect(id);
+        task::spawn(id, server(writer, reader))
+    }
+
+    /// Apply a fault right away. Faults accumulate until healed.
+    pub fn inject(&self, fault: Fault) {
+        info!(?fault, "injecting fault");
+        self.inner.lock().unwrap().inject(fault);
+    }
+
+    /// Inject each fault once its offset (from now) has passed.
+    pub fn schedule(&self, faults: Vec<(Duration, Fault)>) -> JoinHandle<()> {
+        let nemesis = Nemesis { inner: self.inner.clone() };
+        let start = Instant::now();
+        task::spawn("nemesis", async move {
+            for (offset, fault) in faults {
+                tokio::time::sleep_until(start + offset).await;
+                nemesis.inject(fault);
+            }
+        })
+    }
+
+    pub fn is_blocked(&self, from: &str, to: &str) -> bool {
+        self.inner.lock().unwrap().blocked.contains(&(message::intern(from), message::intern(to)))
+    }
+
+    /// Skew the clock `node` gets from [`Network::clock`] from now on. Clocks handed
+    /// out earlier keep the skew they were created with.
+    pub fn set_clock_skew(&self, node: &str, skew: ClockSkew) {
+        self.inner.lock().unwrap().skews.insert(node.to_owned(), skew);
+    }
+
+    /// The wall clock `node` should read time from. Unskewed unless set otherwise.
+    pub fn clock(&self, node: &str) -> Clock {
+        let inner = self.inner.lock().unwrap();
+        Clock {
+            started: inner.started,
+            skew: inner.skews.get(node).copied().unwrap_or_default(),
+        }
+    }
+
+    /// The seed this network was started with; rerun with it to get the same run back.
+    pub fn seed(&self) -> u64 {
+        self.inner.lock().unwrap().seed
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        self.inner.lock().unwrap().stats
+    }
+}
+
+
+/// Lets a scheduled task inject faults without holding on to the [`Network`] itself.
+struct Nemesis<M> {
+    inner: Arc<Mutex<Inner<M>>>,
+}
+
+impl<M> Nemesis<M> {
+    fn inject(&self, fault: Fault) {
+        info!(?fault, "injecting scheduled fault");
+        self.inner.lock().unwrap().inject(fault);
+    }
+}
+
+
+/// A message on its way, ordered by when it's due and then by when it was sent, so
+/// messages due at the same instant come out in the order they went in.
+struct InFlight<M> {
+    deliver_at: Instant,
+    seq: u64,
+    envelope: Envelope<M>,
+}
+
+impl<M> PartialEq for InFlight<M> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.deliver_at, self.seq) == (other.deliver_at, other.seq)
+    }
+}
+
+impl<M> Eq for InFlight<M> {}
+
+impl<M> PartialOrd for InFlight<M> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<M> Ord for InFlight<M> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.deliver_at, self.seq).cmp(&(other.deliver_at, other.seq))
+    }
+}
+
+
+impl<M> Inner<M> {
+    fn inject(&mut self, fault: Fault) {
+        match fault {
+            Fault::Partition(groups) => {
+                for (index, group) in groups.iter().enumerate() {
+                    for other in &groups[index + 1..] {
+                        for a in group {
+                            for b in other {
+                                self.blocked.insert((message::intern(a), message::intern(b)));
+                                self.blocked.insert((message::intern(b), message::intern(a)));
+                            }
+                        }
+                    }
+                }
+            },
+            Fault::Block { from, to } => {
+                self.blocked.insert((message::intern(&from), message::intern(&to)));
+            },
+            Fault::Heal => self.blocked.clear(),
+        }
+    }
+}
+
+
+impl<M: Clone + Debug> Inner<M> {
+    /// Decide the fate of a freshly sent message: nothing, or one or two deliveries.
+    fn schedule(&mut self, envelope: Envelope<M>, now: Instant, seq: &mut u64) -> Vec<InFlight<M>> {
+        self.stats.sent += 1;
+        if !self.inboxes.contains_key(&envelope.destination) {
+            warn!(destination = %envelope.destination, "dropping message to unknown node");
+            self.stats.unroutable += 1;
+            return vec![];
+        }
+        if self.rng.gen_bool(self.config.loss.clamp(0., 1.)) {
+            debug!(?envelope, "dropping message");
+            self.stats.dropped += 1;
+            return vec![];
+        }
+        let copies = match self.rng.gen_bool(self.config.duplication.clamp(0., 1.)) {
+            true => {
+                self.stats.duplicated += 1;
+                2
+            },
+            false => 1,
+        };
+
+        (0..copies)
+        .map(|_| {
+            let mut deliver_at = now + self.config.latency.sample(&mut self.rng);
+            if !self.config.reorder {
+                let link = (envelope.source.clone(), envelope.destination.clone());
+                let last = self.last_delivery.entry(link).or_insert(deliver_at);
+                deliver_at = deliver_at.max(*last);
+                *last = deliver_at;
+            }
+            *seq += 1;
+            InFlight { deliver_at, seq: *seq, envelope: envelope.clone() }
+        })
+        .collect()
+    }
+
+    fn deliver(&mut self, envelope: Envelope<M>) {
+        if self.blocked.contains(&(envelope.source.clone(), envelope.destination.clone())) {
+            debug!(?envelope, "dropping message across a partition");
+            self.stats.partitioned += 1;
+            return;
+        }
+        trace!(?envelope, "delivering message");
+        if let Some(inbox) = self.inboxes.get(&envelope.destination) {
+            self.stats.delivered += 1;
+            let _ = inbox.send(envelope);
+        }
+    }
+}
+
+
+async fn route<M>(inner: Arc<Mutex<Inner<M>>>, mut outbound: UnboundedReceiver<Envelope<M>>)
+where
+    M: Clone + Debug + Send + 'static,
+{
+    let mut in_flight: BinaryHeap<Reverse<InFlight<M>>> = BinaryHeap::new();
+    let mut seq = 0;
+
+    loop {
+        let next_due = in_flight.peek().map(|Reverse(message)| message.deliver_at);
+        // Biased, since tokio otherwise picks among ready branches at random.
+        tokio::select! {
+            biased;
+            sent = outbound.recv() => {
+                let Some(envelope) = sent else {
+                    break;
+                };
+                let scheduled = inner.lock().unwrap().schedule(envelope, Instant::now(), &mut seq);
+                in_flight.extend(scheduled.into_iter().map(Reverse));
+            },
+            _ = tokio::time::sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => {
+                let now = Instant::now();
+                let mut inner = inner.lock().unwrap();
+                while in_flight.peek().is_some_and(|Reverse(message)| message.deliver_at <= now) {
+                    let Reverse(message) = in_flight.pop().unwrap();
+                    inner.deliver(message.envelope);
+                }
+            },
+        }
+    }
+}
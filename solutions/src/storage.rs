@@ -0,0 +1,287 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, BufReader, Write}, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration};
+use serde::{de::{DeserializeOwned, IgnoredAny}, Deserialize, Serialize};
+use tracing::{debug, error, warn};
+use crate::scheduler::{Job, JobHandle, Period, Scheduler};
+
+
+/// A durable home for some node state `S` that evolves through records `R`.
+///
+/// Every state transition is appended to a write-ahead log before it is applied,
+/// and the full state is periodically checkpointed into a snapshot. A successful
+/// checkpoint makes every WAL record it covers redundant, so those are dropped.
+pub trait Storage<S, R> {
+    /// Durably record a state transition, returning its sequence number.
+    fn append(&mut self, record: &R) -> io::Result<u64>;
+    /// Persist a snapshot covering every record appended so far and truncate the WAL.
+    fn checkpoint(&mut self, state: &S) -> io::Result<()>;
+    /// Load the latest snapshot (if any) and the WAL records appended after it.
+    ///
+    /// A torn final record is dropped, but anything else that doesn't look like
+    /// a prefix of what was appended is reported as [`io::ErrorKind::InvalidData`].
+    fn recover(&mut self) -> io::Result<Recovered<S, R>>;
+    /// The number of records in the WAL that are not yet covered by a snapshot.
+    fn uncheckpointed(&self) -> u64;
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Recovered<S, R> {
+    pub snapshot: Option<S>,
+    pub records: Vec<R>,
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WalEntry<R> {
+    seq: u64,
+    record: R,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotEntry<S> {
+    /// The sequence number of the last WAL record folded into this snapshot.
+    seq: u64,
+    state: S,
+}
+
+
+/// A [`Storage`] backed by two files in a directory: `wal.jsonl` and `snapshot.json`.
+#[derive(Debug)]
+pub struct FileStorage {
+    dir: PathBuf,
+    wal: File,
+    /// The sequence number the next appended record will get.
+    next_seq: u64,
+    /// The sequence number the most recent snapshot covers up to (inclusive).
+    checkpointed_seq: u64,
+}
+
+
+impl FileStorage {
+    /// Open (or create) the storage in `dir`, picking up the sequence numbers where the
+    /// files there left off, so records appended before [`Storage::recover`] don't reuse
+    /// ones already in the WAL. Fails the way `recover` does on a log it can't trust.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let wal = OpenOptions::new().create(true).append(true).open(dir.join("wal.jsonl"))?;
+        let mut storage = Self { dir, wal, next_seq: 1, checkpointed_seq: 0 };
+        // Only the sequence numbers matter here, so skip over the state and records.
+        let snapshot = storage.read_snapshot::<IgnoredAny>()?;
+        storage.read_wal::<IgnoredAny>(snapshot.map(|entry| entry.seq).unwrap_or(0))?;
+        Ok(storage)
+    }
+
+    pub fn wal_path(&self) -> PathBuf {
+        self.dir.join("wal.jsonl")
+    }
+
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.dir.join("snapshot.json")
+    }
+
+    fn read_snapshot<S: DeserializeOwned>(&self) -> io::Result<Option<SnapshotEntry<S>>> {
+        match File::open(self.snapshot_path()) {
+            Ok(file) => Ok(Some(serde_json::from_reader(BufReader::new(file))?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The WAL records after `checkpointed_seq`, cutting off a torn tail and moving the
+    /// sequence numbers up to where the log ends.
+    fn read_wal<R: DeserializeOwned>(&mut self, checkpointed_seq: u64) -> io::Result<Vec<R>> {
+        // A crash mid-append (or an fsync that only partially landed) can leave a torn
+        // tail: a final line that is cut short or full of garbage. That tail was never
+        // acknowledged, so it's safe to drop. Garbage with valid records *after* it,
+        // or a gap in the sequence numbers, means the log itself can't be trusted.
+        let bytes = fs::read(self.wal_path())?;
+        let mut records = vec![];
+        let mut last_seq = checkpointed_seq;
+        let mut valid_len = 0;
+        let mut torn_at = None;
+        let mut start = 0;
+
+        for line in bytes.split_inclusive(|&byte| byte == b'\n') {
+            let end = start + line.len();
+            let entry = match line.ends_with(b"\n") {
+                true => serde_json::from_slice::<WalEntry<R>>(line).ok(),
+                false => None,
+            };
+            match (entry, torn_at) {
+                (None, None) => torn_at = Some(start),
+                (None, Some(_)) => {},
+                (Some(_), Some(at)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("wal has an unreadable record at byte {at} followed by valid ones"),
+                    ));
+                },
+                (Some(entry), None) => {
+                    valid_len = end;
+                    if entry.seq > checkpointed_seq {
+                        if entry.seq != last_seq + 1 {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("wal jumps from seq {last_seq} to {}", entry.seq),
+                            ));
+                        }
+                        last_seq = entry.seq;
+                        records.push(entry.record);
+                    }
+                },
+            }
+            start = end;
+        }
+
+        if let Some(at) = torn_at {
+            warn!(at, dropped_bytes = bytes.len() - valid_len, "dropping torn tail of wal");
+            // Cut the tail off so new appends don't get glued onto it.
+            self.wal.set_len(valid_len as u64)?;
+            self.wal.sync_all()?;
+        }
+
+        self.checkpointed_seq = checkpointed_seq;
+        self.next_seq = last_seq + 1;
+        Ok(records)
+    }
+}
+
+
+impl<S, R> Storage<S, R> for FileStorage
+where
+    S: Serialize + DeserializeOwned,
+    R: Serialize + DeserializeOwned,
+{
+    fn append(&mut self, record: &R) -> io::Result<u64> {
+        let seq = self.next_seq;
+        let mut line = serde_json::to_vec(&WalEntry { seq, record })?;
+        line.push(b'\n');
+        self.wal.write_all(&line)?;
+        self.wal.sync_data()?;
+        self.next_seq += 1;
+        Ok(seq)
+    }
+
+    fn checkpoint(&mut self, state: &S) -> io::Result<()> {
+        let seq = self.next_seq - 1;
+
+        // Write the snapshot next to the old one and atomically swap it in,
+        // so a crash mid-checkpoint leaves the previous snapshot intact.
+        let tmp_path = self.dir.join("snapshot.json.tmp");
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            serde_json::to_writer(&mut tmp, &SnapshotEntry { seq, state })?;
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, self.snapshot_path())?;
+        self.checkpointed_seq = seq;
+
+        // Everything in the WAL is now covered by the snapshot. If we crash before
+        // the truncation lands, recovery skips the covered records by their seq.
+        self.wal.set_len(0)?;
+        self.wal.sync_all()?;
+        debug!(seq, "checkpointed state and truncated wal");
+        Ok(())
+    }
+
+    fn recover(&mut self) -> io::Result<Recovered<S, R>> {
+        let snapshot = self.read_snapshot::<S>()?;
+        let checkpointed_seq = snapshot.as_ref().map(|entry| entry.seq).unwrap_or(0);
+        let records = self.read_wal::<R>(checkpointed_seq)?;
+        debug!(checkpointed_seq, num_records = records.len(), "recovered from storage");
+
+        Ok(Recovered { snapshot: snapshot.map(|entry| entry.state), records })
+    }
+
+    fn uncheckpointed(&self) -> u64 {
+        self.next_seq - 1 - self.checkpointed_seq
+    }
+}
+
+
+/// State that can be rebuilt by replaying the records it was derived from.
+pub trait Replay<R> {
+    fn apply(&mut self, record: &R);
+}
+
+
+/// Couples some state with the storage that makes it survive restarts.
+#[derive(Debug)]
+pub struct Durable<S, St> {
+    pub state: S,
+    storage: St,
+}
+
+
+impl<S, St> Durable<S, St> {
+    /// Rebuild the state from the latest snapshot plus every WAL record after it.
+    pub fn open<R>(mut storage: St) -> io::Result<Self>
+    where
+        S: Default + Replay<R>,
+        St: Storage<S, R>,
+    {
+        let Recovered { snapshot, records } = storage.recover()?;
+        let mut state = snapshot.unwrap_or_default();
+        for record in &records {
+            state.apply(record);
+        }
+        Ok(Self { state, storage })
+    }
+
+    /// Log the record and then apply it to the state.
+    pub fn apply<R>(&mut self, record: R) -> io::Result<()>
+    where
+        S: Replay<R>,
+        St: Storage<S, R>,
+    {
+        self.storage.append(&record)?;
+        self.state.apply(&record);
+        Ok(())
+    }
+
+    pub fn checkpoint<R>(&mut self) -> io::Result<()>
+    where
+        St: Storage<S, R>,
+    {
+        self.storage.checkpoint(&self.state)
+    }
+
+    pub fn uncheckpointed<R>(&self) -> u64
+    where
+        St: Storage<S, R>,
+    {
+        self.storage.uncheckpointed()
+    }
+}
+
+
+/// Checkpoint the state every `period`, as a `checkpoint` job on `scheduler`, skipping
+/// rounds where the WAL has nothing new, so the WAL never grows past whatever gets
+/// appended within a single period. Once the node is asked to
+/// [`shutdown`](crate::shutdown), it checkpoints one last time and stops.
+pub fn checkpoint_every_so_often<S, R, St>(
+    scheduler: &Scheduler,
+    durable: Arc<Mutex<Durable<S, St>>>,
+    period: Duration,
+) -> JobHandle
+where
+    S: Send + 'static,
+    R: 'static,
+    St: Storage<S, R> + Send + 'static,
+{
+    scheduler.spawn(Job::new("checkpoint", Period::Every(period)).run_on_shutdown(), move |_| {
+        let mut durable = durable.lock().unwrap();
+        let pending = durable.uncheckpointed::<R>();
+        if pending > 0 {
+            match durable.checkpoint::<R>() {
+                Ok(()) => debug!(num_records = pending, "compacted wal into snapshot"),
+                Err(err) => {
+                    // The WAL is left untouched, so nothing is lost; try again next round.
+                    error!(error = ?err, "failed to checkpoint state");
+                }
+            }
+        }
+    })
+}
+
@@ -0,0 +1,39 @@
+use std::future::Future;
+use tokio::task::JoinHandle;
+
+
+/// Spawn `future` as a task called `name`, which is how tokio-console lists it. The name
+/// only sticks with the `console` feature on and `--cfg tokio_unstable` in `RUSTFLAGS`;
+/// otherwise this is plain [`tokio::task::spawn`].
+#[track_caller]
+pub fn spawn<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(feature = "console", tokio_unstable))]
+    return tokio::task::Builder::new().name(name).spawn(future).expect("failed to spawn a task");
+
+    #[cfg(not(all(feature = "console", tokio_unstable)))]
+    {
+        let _ = name;
+        tokio::task::spawn(future)
+    }
+}
+
+/// [`spawn`], for blocking code: runs `f` on tokio's blocking thread pool, named `name`.
+#[track_caller]
+pub fn spawn_blocking<F, R>(name: &str, f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    #[cfg(all(feature = "console", tokio_unstable))]
+    return tokio::task::Builder::new().name(name).spawn_blocking(f).expect("failed to spawn a task");
+
+    #[cfg(not(all(feature = "console", tokio_unstable)))]
+    {
+        let _ = name;
+        tokio::task::spawn_blocking(f)
+    }
+}
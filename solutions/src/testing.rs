@@ -0,0 +1,126 @@
+use std::{fmt::Debug, fs, io, path::Path};
+use proptest::{collection, option, prelude::*, test_runner::TestCaseError};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use crate::{io::parse_line, message::{Body, Envelope}};
+
+
+/// Node ids the way Maelstrom hands them out: `n*` for nodes, `c*` for clients, plus
+/// the built-in services.
+pub fn node_id() -> impl Strategy<Value = String> {
+    prop_oneof![
+        (0..10u8).prop_map(|index| format!("n{index}")),
+        (0..100u8).prop_map(|index| format!("c{index}")),
+        prop::sample::select(vec!["lin-kv", "seq-kv", "lww-kv", "lin-tso"]).prop_map(str::to_owned),
+    ]
+}
+
+pub fn body<M: Debug>(message: impl Strategy<Value = M>) -> impl Strategy<Value = Body<M>> {
+    (option::of(any::<usize>()), option::of(any::<usize>()), message)
+        .prop_map(|(msg_id, in_reply_to, message)| Body { msg_id, in_reply_to, message })
+}
+
+pub fn envelope<M: Debug>(message: impl Strategy<Value = M>) -> impl Strategy<Value = Envelope<M>> {
+    (node_id(), node_id(), body(message))
+        .prop_map(|(source, destination, body)| Envelope::new(&source, &destination, body))
+}
+
+/// Any JSON value, for payloads that carry free-form values (e.g. registers in a kv).
+pub fn json_value() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::from),
+        any::<i64>().prop_map(Value::from),
+        "[a-z0-9 ]{0,8}".prop_map(Value::from),
+    ];
+    leaf.prop_recursive(3, 32, 4, |inner| prop_oneof![
+        collection::vec(inner.clone(), 0..4).prop_map(Value::from),
+        collection::btree_map("[a-z_]{1,6}", inner, 0..4).prop_map(|map| Value::Object(map.into_iter().collect())),
+    ])
+}
+
+
+/// Serializing `value` and deserializing it again must give back the same JSON.
+/// Compares the JSON rather than the values, since most payloads don't implement `PartialEq`.
+pub fn assert_round_trips<T: Serialize + DeserializeOwned>(value: &T) -> Result<(), TestCaseError> {
+    let serialized = serde_json::to_string(value).map_err(|err| TestCaseError::fail(err.to_string()))?;
+    let deserialized: T = serde_json::from_str(&serialized).map_err(|err| TestCaseError::fail(format!("{err}: {serialized}")))?;
+    prop_assert_eq!(serde_json::to_value(&deserialized).unwrap(), serde_json::to_value(value).unwrap());
+    Ok(())
+}
+
+
+/// A state-based CRDT: replicas apply their own operations locally and merge each
+/// other's states, and must all end up in the same state whatever order merges happen in.
+pub trait Crdt: Clone + Debug + PartialEq {
+    type Op: Clone + Debug;
+
+    fn apply(&mut self, op: &Self::Op);
+    fn merge(&mut self, other: &Self);
+}
+
+/// Operations for each of `replicas` replicas, up to `max_ops` each.
+pub fn op_sequences<O: Debug>(op: impl Strategy<Value = O> + Clone, replicas: usize, max_ops: usize) -> impl Strategy<Value = Vec<Vec<O>>> {
+    collection::vec(collection::vec(op, 0..=max_ops), replicas)
+}
+
+/// Apply each replica's operations to its own copy of `initial`, then check that
+/// merging the replicas is order-insensitive and idempotent: merging them all in
+/// either direction, or merging everything twice, has to give the same state.
+pub fn assert_converges<C: Crdt>(initial: &C, ops: &[Vec<C::Op>]) -> Result<(), TestCaseError> {
+    let replicas: Vec<C> =
+        ops
+        .iter()
+        .map(|ops| {
+            let mut replica = initial.clone();
+            for op in ops {
+                replica.apply(op);
+            }
+            replica
+        })
+        .collect();
+
+    let merge_all = |order: &mut dyn Iterator<Item = &C>| {
+        let mut merged = initial.clone();
+        for replica in order {
+            merged.merge(replica);
+        }
+        merged
+    };
+    let forwards = merge_all(&mut replicas.iter());
+    let backwards = merge_all(&mut replicas.iter().rev());
+    let twice = merge_all(&mut replicas.iter().chain(replicas.iter()));
+
+    prop_assert_eq!(&forwards, &backwards);
+    prop_assert_eq!(&forwards, &twice);
+    Ok(())
+}
+
+
+/// What every fuzz target runs on its input: parse `data` as a line of node input
+/// exactly like `io_channel` would. Whatever parses has to survive being serialized
+/// and parsed again, since that's what happens to every message we forward or reply to.
+/// Panics (for the fuzzer to catch) if it doesn't; malformed input is simply ignored.
+pub fn fuzz_line<M: Serialize + DeserializeOwned>(data: &[u8]) {
+    let Ok(message) = parse_line::<M>(data) else {
+        return;
+    };
+    let serialized = serde_json::to_vec(&message).expect("a parsed message should serialize");
+    if let Err(err) = parse_line::<M>(&serialized) {
+        panic!("{err}: {} doesn't parse back after a round trip", String::from_utf8_lossy(&serialized));
+    }
+}
+
+/// Run every file of a fuzz corpus through [`fuzz_line`], so the corpora double as
+/// regression tests on stable, without cargo-fuzz. Returns how many inputs ran.
+pub fn replay_corpus<M: Serialize + DeserializeOwned>(dir: impl AsRef<Path>) -> io::Result<usize> {
+    let mut replayed = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            fuzz_line::<M>(&fs::read(&path)?);
+            replayed += 1;
+        }
+    }
+    Ok(replayed)
+}
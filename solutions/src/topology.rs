@@ -0,0 +1,217 @@
+use std::{collections::{BTreeSet, HashMap}, fmt::Display, hash::{Hash, Hasher}, str::FromStr, time::Duration};
+use siphasher::sip::SipHasher13;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use crate::message::{self, NodeId};
+
+
+/// Who a gossiping node talks to directly, out of the whole cluster, as `--overlay`
+/// spells it. Every overlay is worked out from the `node_ids` Maelstrom sends in `init`
+/// (in the order it sends them), so all nodes agree on it without talking it over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overlay {
+    /// `stride:<n>`: 1 in every `n` nodes, starting after this one (broadcast's original
+    /// overlay, and its default, with `--stride`).
+    Stride(usize),
+    /// `ring`: the nodes before and after this one, wrapping around.
+    Ring,
+    /// `grid`: the nodes above, below and to either side, with the cluster laid out in
+    /// rows as close to square as it gets.
+    Grid,
+    /// `tree:<k>`: the parent and children in a k-ary tree rooted at the first node.
+    Tree(usize),
+    /// `hypercube`: the nodes whose position differs from this one's in one bit.
+    Hypercube,
+    /// `random:<degree>`: a random graph where nodes get `degree` neighbors (or as close
+    /// as the cluster allows), on top of a ring so it's always connected.
+    RandomRegular(usize),
+    /// `maelstrom`: the neighbors Maelstrom's `topology` message gives.
+    Maelstrom,
+    /// `nearest:<near>+<far>`: the `near` nodes with the quickest round trips, and `far`
+    /// others picked at random as long links, so the cluster stays connected however
+    /// the quick ones cluster together (see [`nearest`]). Until round trips have been
+    /// measured, the nearest are the ones closest in the ring.
+    Nearest { near: usize, far: usize },
+}
+
+impl FromStr for Overlay {
+    type Err = String;
+
+    fn from_str(overlay: &str) -> Result<Self, Self::Err> {
+        let number = |n: &str| n.parse::<usize>().ok().filter(|n| *n > 0).ok_or(format!("`{n}` isn't a positive number"));
+        match overlay.split_once(':') {
+            None if overlay == "ring" => Ok(Self::Ring),
+            None if overlay == "grid" => Ok(Self::Grid),
+            None if overlay == "hypercube" => Ok(Self::Hypercube),
+            None if overlay == "maelstrom" => Ok(Self::Maelstrom),
+            Some(("stride", n)) => number(n).map(Self::Stride),
+            Some(("tree", k)) => number(k).map(Self::Tree),
+            Some(("random", degree)) => number(degree).map(Self::RandomRegular),
+            Some(("nearest", links)) => {
+                let (near, far) = links.split_once('+').ok_or(format!("expected `nearest:<near>+<far>`, not `{overlay}`"))?;
+                let far = far.parse::<usize>().map_err(|_| format!("`{far}` isn't a number"))?;
+                number(near).map(|near| Self::Nearest { near, far })
+            },
+            _ => Err(format!("expected `stride:<n>`, `ring`, `grid`, `tree:<k>`, `hypercube`, `random:<degree>`, `nearest:<near>+<far>` or `maelstrom`, not `{overlay}`")),
+        }
+    }
+}
+
+impl Display for Overlay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stride(n) => write!(f, "stride:{n}"),
+            Self::Ring => write!(f, "ring"),
+            Self::Grid => write!(f, "grid"),
+            Self::Tree(k) => write!(f, "tree:{k}"),
+            Self::Hypercube => write!(f, "hypercube"),
+            Self::RandomRegular(degree) => write!(f, "random:{degree}"),
+            Self::Maelstrom => write!(f, "maelstrom"),
+            Self::Nearest { near, far } => write!(f, "nearest:{near}+{far}"),
+        }
+    }
+}
+
+impl Overlay {
+    /// `node`'s neighbors among `node_ids`, never including itself; none if it isn't one
+    /// of them. `topology` is the one Maelstrom sent, for [`Overlay::Maelstrom`].
+    pub fn neighbors(&self, node: &str, node_ids: &[NodeId], topology: &HashMap<String, Vec<String>>) -> Vec<NodeId> {
+        match self {
+            Self::Stride(n) => stride(node, node_ids, *n),
+            Self::Ring => ring(node, node_ids),
+            Self::Grid => grid(node, node_ids),
+            Self::Tree(k) => tree(node, node_ids, *k),
+            Self::Hypercube => hypercube(node, node_ids),
+            Self::RandomRegular(degree) => random_regular(node, node_ids, *degree),
+            Self::Maelstrom => topology.get(node).into_iter().flatten().filter(|&neighbor| neighbor != node).map(|neighbor| message::intern(neighbor)).collect(),
+            Self::Nearest { near, far } => {
+                let mut hasher = SipHasher13::new();
+                (node, node_ids).hash(&mut hasher);
+                nearest(node, node_ids, &HashMap::new(), *near, *far, &mut StdRng::seed_from_u64(hasher.finish()))
+            },
+        }
+    }
+}
+
+
+/// The nodes at `positions`, which may repeat or include `own`.
+fn at(node_ids: &[NodeId], own: usize, positions: impl IntoIterator<Item = usize>) -> Vec<NodeId> {
+    positions
+    .into_iter()
+    .filter(|&position| position != own)
+    .collect::<BTreeSet<_>>()
+    .into_iter()
+    .map(|position| node_ids[position].clone())
+    .collect()
+}
+
+fn position(node: &str, node_ids: &[NodeId]) -> Option<usize> {
+    node_ids.iter().position(|node_id| **node_id == *node)
+}
+
+/// Panics if `stride` is 0.
+pub fn stride(node: &str, node_ids: &[NodeId], stride: usize) -> Vec<NodeId> {
+    let Some(own) = position(node, node_ids) else {
+        return vec![];
+    };
+    at(node_ids, own, ((own + 1) % stride..node_ids.len()).step_by(stride))
+}
+
+pub fn ring(node: &str, node_ids: &[NodeId]) -> Vec<NodeId> {
+    let Some(own) = position(node, node_ids) else {
+        return vec![];
+    };
+    at(node_ids, own, ring_positions(own, node_ids.len()))
+}
+
+pub fn grid(node: &str, node_ids: &[NodeId]) -> Vec<NodeId> {
+    let Some(own) = position(node, node_ids) else {
+        return vec![];
+    };
+    let n = node_ids.len();
+    let width = (1..=n).find(|width| width * width >= n).unwrap_or(1);
+    let (row, column) = (own / width, own % width);
+    let mut positions = vec![own + width];
+    if row > 0 {
+        positions.push(own - width);
+    }
+    if column > 0 {
+        positions.push(own - 1);
+    }
+    if column + 1 < width {
+        positions.push(own + 1);
+    }
+    at(node_ids, own, positions.into_iter().filter(|&position| position < n))
+}
+
+pub fn tree(node: &str, node_ids: &[NodeId], arity: usize) -> Vec<NodeId> {
+    let Some(own) = position(node, node_ids) else {
+        return vec![];
+    };
+    let arity = arity.max(1);
+    let parent = (own > 0).then(|| (own - 1) / arity);
+    let children = (own * arity + 1..=own * arity + arity).filter(|&child| child < node_ids.len());
+    at(node_ids, own, parent.into_iter().chain(children))
+}
+
+pub fn hypercube(node: &str, node_ids: &[NodeId]) -> Vec<NodeId> {
+    let Some(own) = position(node, node_ids) else {
+        return vec![];
+    };
+    let n = node_ids.len();
+    at(node_ids, own, (0..usize::BITS).map(|bit| own ^ (1 << bit)).filter(|&position| position < n))
+}
+
+/// Every node works out the same graph, by seeding its choices from a SipHash of the
+/// cluster's `node_ids` (pinned, unlike `DefaultHasher`, so toolchains don't matter).
+pub fn random_regular(node: &str, node_ids: &[NodeId], degree: usize) -> Vec<NodeId> {
+    let Some(own) = position(node, node_ids) else {
+        return vec![];
+    };
+    let n = node_ids.len();
+    let mut edges: Vec<BTreeSet<usize>> = (0..n).map(|position| ring_positions(position, n)).collect();
+
+    let mut hasher = SipHasher13::new();
+    node_ids.hash(&mut hasher);
+    let mut rng = StdRng::seed_from_u64(hasher.finish());
+    let mut order: Vec<usize> = (0..n).collect();
+    order.shuffle(&mut rng);
+    for &a in &order {
+        let mut candidates: Vec<usize> = (0..n).filter(|&b| b != a && !edges[a].contains(&b)).collect();
+        candidates.shuffle(&mut rng);
+        for b in candidates {
+            if edges[a].len() >= degree {
+                break;
+            }
+            if edges[b].len() < degree {
+                edges[a].insert(b);
+                edges[b].insert(a);
+            }
+        }
+    }
+    at(node_ids, own, edges[own].iter().copied())
+}
+
+/// The `near` nodes with the shortest round trips in `rtts`, then `far` of the rest
+/// picked with `rng`. Nodes with no round trip measured yet come after those with one,
+/// closest in the ring first, so a node that's never been a neighbor is only tried as a
+/// long link (which is how it gets measured) unless there aren't enough measured ones.
+pub fn nearest(node: &str, node_ids: &[NodeId], rtts: &HashMap<NodeId, Duration>, near: usize, far: usize, rng: &mut impl Rng) -> Vec<NodeId> {
+    let Some(own) = position(node, node_ids) else {
+        return vec![];
+    };
+    let n = node_ids.len();
+    let ring_distance = |position: usize| {
+        let clockwise = (position + n - own) % n;
+        (clockwise.min(n - clockwise), clockwise)
+    };
+    let mut others: Vec<usize> = (0..n).filter(|&position| position != own).collect();
+    others.sort_by_key(|&position| (rtts.get(&node_ids[position]).map_or((1, Duration::ZERO), |rtt| (0, *rtt)), ring_distance(position)));
+    let mut rest = others.split_off(near.min(others.len()));
+    rest.shuffle(rng);
+    at(node_ids, own, others.into_iter().chain(rest.into_iter().take(far)))
+}
+
+/// The positions either side of `position` in a ring of `n`.
+fn ring_positions(position: usize, n: usize) -> BTreeSet<usize> {
+    [(position + n - 1) % n, (position + 1) % n].into_iter().filter(|&other| other != position).collect()
+}
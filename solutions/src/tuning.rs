@@ -0,0 +1,137 @@
+use std::{sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, time::Duration};
+use clap::ValueEnum;
+use serde_json::{json, Map, Value};
+use crate::cli::{FanoutStrategy, TICK_RATE_MS};
+
+
+/// The parameters a node can have changed while it runs, by a `configure` message (which
+/// the io layer answers itself): how often background work runs, how many peers each
+/// round of gossip goes to, how many items go in one gossip message, and how many gossip
+/// messages may go unacknowledged by one peer.
+///
+/// There's one [`global`] set, which [`workloads::run`](crate::workloads::run) starts off
+/// from the node's options and the workloads read from as they go.
+#[derive(Debug)]
+pub struct Tunables {
+    tick_rate_ms: AtomicU64,
+    /// `0` for every peer.
+    fanout: AtomicUsize,
+    /// Whether `fanout` is a percentage of the cluster rather than a number of peers.
+    fanout_in_percent: AtomicBool,
+    /// `0` for no limit.
+    batch_size: AtomicUsize,
+    /// `0` for no limit.
+    window: AtomicUsize,
+}
+
+impl Tunables {
+    pub const fn new() -> Self {
+        Self { tick_rate_ms: AtomicU64::new(TICK_RATE_MS), fanout: AtomicUsize::new(0), fanout_in_percent: AtomicBool::new(false), batch_size: AtomicUsize::new(0), window: AtomicUsize::new(0) }
+    }
+
+    pub fn tick_rate(&self) -> Duration {
+        Duration::from_millis(self.tick_rate_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn set_tick_rate(&self, tick_rate: Duration) {
+        self.tick_rate_ms.store((tick_rate.as_millis() as u64).max(1), Ordering::Relaxed);
+    }
+
+    /// How many peers to gossip to each round (as the [`fanout_strategy`](Self::fanout_strategy)
+    /// counts them); `None` for all of them.
+    pub fn fanout(&self) -> Option<usize> {
+        Some(self.fanout.load(Ordering::Relaxed)).filter(|fanout| *fanout > 0)
+    }
+
+    pub fn set_fanout(&self, fanout: Option<usize>) {
+        self.fanout.store(fanout.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn fanout_strategy(&self) -> FanoutStrategy {
+        if self.fanout_in_percent.load(Ordering::Relaxed) { FanoutStrategy::Percent } else { FanoutStrategy::Peers }
+    }
+
+    pub fn set_fanout_strategy(&self, strategy: FanoutStrategy) {
+        self.fanout_in_percent.store(strategy == FanoutStrategy::Percent, Ordering::Relaxed);
+    }
+
+    /// How many items to put in one gossip message at most; `None` for no limit.
+    pub fn batch_size(&self) -> Option<usize> {
+        Some(self.batch_size.load(Ordering::Relaxed)).filter(|batch_size| *batch_size > 0)
+    }
+
+    pub fn set_batch_size(&self, batch_size: Option<usize>) {
+        self.batch_size.store(batch_size.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// How many gossip messages one peer may have yet to acknowledge before it's sent any
+    /// more; `None` for no limit.
+    pub fn window(&self) -> Option<usize> {
+        Some(self.window.load(Ordering::Relaxed)).filter(|window| *window > 0)
+    }
+
+    pub fn set_window(&self, window: Option<usize>) {
+        self.window.store(window.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Apply the fields of a `configure` request's body: `tick_rate_ms` (at least 1),
+    /// `fanout`, `batch_size` and `window` (`null` or `0` to lift the limit) and `fanout_strategy`
+    /// (`peers` or `percent`). Nothing changes unless all of them are good.
+    pub fn configure(&self, body: &Map<String, Value>) -> Result<(), String> {
+        let limit = |field: &str, value: &Value| match value {
+            Value::Null => Ok(None),
+            value => value.as_u64().map(|limit| Some(limit as usize).filter(|limit| *limit > 0)).ok_or(format!("`{field}` should be a whole number or null, not {value}")),
+        };
+        let mut tick_rate = None;
+        let mut fanout = None;
+        let mut batch_size = None;
+        let mut window = None;
+        let mut strategy = None;
+        for (field, value) in body {
+            match field.as_str() {
+                "type" | "msg_id" => {},
+                "tick_rate_ms" => tick_rate = Some(value.as_u64().filter(|ms| *ms > 0).ok_or(format!("`tick_rate_ms` should be a positive whole number, not {value}"))?),
+                "fanout" => fanout = Some(limit(field, value)?),
+                "batch_size" => batch_size = Some(limit(field, value)?),
+                "window" => window = Some(limit(field, value)?),
+                "fanout_strategy" => strategy = Some(value.as_str().and_then(|name| FanoutStrategy::from_str(name, false).ok()).ok_or(format!("`fanout_strategy` should be \"peers\" or \"percent\", not {value}"))?),
+                _ => return Err(format!("unknown parameter `{field}` (expected tick_rate_ms, fanout, fanout_strategy, batch_size or window)")),
+            }
+        }
+        if let Some(ms) = tick_rate {
+            self.set_tick_rate(Duration::from_millis(ms));
+        }
+        if let Some(fanout) = fanout {
+            self.set_fanout(fanout);
+        }
+        if let Some(strategy) = strategy {
+            self.set_fanout_strategy(strategy);
+        }
+        if let Some(batch_size) = batch_size {
+            self.set_batch_size(batch_size);
+        }
+        if let Some(window) = window {
+            self.set_window(window);
+        }
+        Ok(())
+    }
+
+    /// Every parameter's current value, as a `configure_ok` reports them.
+    pub fn to_json(&self) -> Value {
+        json!({"tick_rate_ms": self.tick_rate().as_millis() as u64, "fanout": self.fanout(), "fanout_strategy": self.fanout_strategy().to_possible_value().map(|value| value.get_name().to_string()), "batch_size": self.batch_size(), "window": self.window()})
+    }
+}
+
+impl Default for Tunables {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+static GLOBAL: Tunables = Tunables::new();
+
+/// The parameters this process runs with.
+pub fn global() -> &'static Tunables {
+    &GLOBAL
+}
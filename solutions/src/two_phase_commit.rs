@@ -0,0 +1,375 @@
+use std::{collections::{HashMap, HashSet}, fmt::Display, io, time::{Duration, Instant}};
+use serde::{Serialize, Deserialize};
+use tracing::{debug, warn};
+use crate::storage::{Durable, Replay, Storage};
+
+
+/// Uniquely identifies a transaction across the cluster: the node coordinating it plus
+/// a counter local to that node.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct TxnId {
+    pub coordinator: String,
+    pub seq: u64,
+}
+
+impl Display for TxnId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.coordinator, self.seq)
+    }
+}
+
+
+/// The 2PC wire protocol. `W` is whatever a participant needs to know to prepare
+/// its share of the transaction (usually the writes that land on its keys).
+///
+/// This is meant to be flattened into a workload's own payload enum, hence the
+/// separate `phase` tag instead of `type`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "phase", rename_all = "snake_case")]
+pub enum Message<W> {
+    Prepare {
+        txn: TxnId,
+        writes: W,
+    },
+    Vote {
+        txn: TxnId,
+        commit: bool,
+    },
+    Commit {
+        txn: TxnId,
+    },
+    Abort {
+        txn: TxnId,
+    },
+    Ack {
+        txn: TxnId,
+    },
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Commit,
+    Abort,
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Outgoing<W> {
+    pub destination: String,
+    pub message: Message<W>,
+}
+
+
+/// Whatever a state transition on the coordinator needs the caller to do.
+#[derive(Debug, Clone)]
+pub struct Step<W> {
+    pub outgoing: Vec<Outgoing<W>>,
+    /// Transactions whose outcome became known during this step.
+    pub decided: Vec<(TxnId, Decision)>,
+}
+
+impl<W> Default for Step<W> {
+    fn default() -> Self {
+        Self { outgoing: vec![], decided: vec![] }
+    }
+}
+
+
+#[derive(Debug)]
+struct CoordinatedTxn {
+    participants: HashSet<String>,
+    votes: HashSet<String>,
+    acks: HashSet<String>,
+    decision: Option<Decision>,
+    /// When we last sent something to participants that still owe us a reply.
+    last_sent: Instant,
+    started: Instant,
+}
+
+
+/// Drives transactions through prepare → commit/abort. It never does any I/O itself;
+/// callers feed it incoming messages plus the current time and send out whatever it asks.
+#[derive(Debug)]
+pub struct Coordinator<W> {
+    node_id: String,
+    next_seq: u64,
+    txns: HashMap<TxnId, CoordinatedTxn>,
+    /// Kept around so the decision can be resent to participants that haven't acked.
+    prepared_writes: HashMap<TxnId, HashMap<String, W>>,
+    /// How long to wait for every vote before giving up and aborting.
+    prepare_timeout: Duration,
+    /// How often to resend a decision to participants that haven't acknowledged it.
+    retry_interval: Duration,
+}
+
+
+impl<W: Clone> Coordinator<W> {
+    pub fn new(node_id: &str, prepare_timeout: Duration, retry_interval: Duration) -> Self {
+        Self {
+            node_id: node_id.to_owned(),
+            next_seq: 1,
+            txns: HashMap::new(),
+            prepared_writes: HashMap::new(),
+            prepare_timeout,
+            retry_interval,
+        }
+    }
+
+    /// Start a transaction spanning every node in `writes` by asking each of them to prepare.
+    /// With nobody to ask, it's committed on the spot.
+    pub fn begin(&mut self, writes: HashMap<String, W>, now: Instant) -> (TxnId, Step<W>) {
+        let txn = TxnId { coordinator: self.node_id.clone(), seq: self.next_seq };
+        self.next_seq += 1;
+        if writes.is_empty() {
+            debug!(txn = %txn, "committed transaction without participants");
+            return (txn.clone(), Step { outgoing: vec![], decided: vec![(txn, Decision::Commit)] });
+        }
+
+        let outgoing =
+            writes
+            .iter()
+            .map(|(participant, writes)| Outgoing {
+                destination: participant.clone(),
+                message: Message::Prepare { txn: txn.clone(), writes: writes.clone() },
+            })
+            .collect();
+
+        self.txns.insert(txn.clone(), CoordinatedTxn {
+            participants: writes.keys().cloned().collect(),
+            votes: HashSet::new(),
+            acks: HashSet::new(),
+            decision: None,
+            last_sent: now,
+            started: now,
+        });
+        self.prepared_writes.insert(txn.clone(), writes);
+        debug!(txn = %txn, "started transaction");
+
+        (txn, Step { outgoing, decided: vec![] })
+    }
+
+    /// Take on a transaction whose votes were gathered some other way (say, along with
+    /// what it read) from its decision on: `participants` are told it now, and on every
+    /// tick after until they've all acknowledged it.
+    pub fn announce(&mut self, txn: TxnId, participants: impl IntoIterator<Item = String>, decision: Decision, now: Instant) -> Step<W> {
+        let participants: HashSet<String> = participants.into_iter().collect();
+        if participants.is_empty() {
+            return Step { outgoing: vec![], decided: vec![(txn, decision)] };
+        }
+        self.txns.insert(txn.clone(), CoordinatedTxn {
+            votes: participants.clone(),
+            participants,
+            acks: HashSet::new(),
+            decision: None,
+            last_sent: now,
+            started: now,
+        });
+        self.decide(txn, decision, now)
+    }
+
+    pub fn on_message(&mut self, source: &str, message: Message<W>, now: Instant) -> Step<W> {
+        match message {
+            Message::Vote { txn, commit } => self.on_vote(source, txn, commit, now),
+            Message::Ack { txn } => {
+                let done = match self.txns.get_mut(&txn) {
+                    Some(state) if state.participants.contains(source) => {
+                        state.acks.insert(source.to_owned());
+                        state.acks.len() == state.participants.len()
+                    },
+                    _ => false,
+                };
+                if done {
+                    debug!(txn = %txn, "every participant acknowledged the decision");
+                    self.txns.remove(&txn);
+                    self.prepared_writes.remove(&txn);
+                }
+                Step::default()
+            },
+            _ => {
+                debug!(source, "coordinator ignoring a message meant for participants");
+                Step::default()
+            },
+        }
+    }
+
+    fn on_vote(&mut self, source: &str, txn: TxnId, commit: bool, now: Instant) -> Step<W> {
+        let Some(state) = self.txns.get_mut(&txn) else {
+            return Step::default();
+        };
+        if !state.participants.contains(source) {
+            debug!(txn = %txn, source, "ignoring a vote from a node outside the transaction");
+            return Step::default();
+        }
+        if state.decision.is_some() {
+            // Late or duplicate vote; the participant will hear the decision on the next retry.
+            return Step::default();
+        }
+        if !commit {
+            return self.decide(txn, Decision::Abort, now);
+        }
+        state.votes.insert(source.to_owned());
+        if state.votes.len() == state.participants.len() {
+            return self.decide(txn, Decision::Commit, now);
+        }
+        Step::default()
+    }
+
+    fn decide(&mut self, txn: TxnId, decision: Decision, now: Instant) -> Step<W> {
+        let state = self.txns.get_mut(&txn).unwrap();
+        state.decision = Some(decision);
+        state.last_sent = now;
+        debug!(txn = %txn, decision = ?decision, elapsed = ?now - state.started, "decided transaction");
+
+        let outgoing = Self::decision_messages(&txn, decision, state);
+        Step { outgoing, decided: vec![(txn, decision)] }
+    }
+
+    fn decision_messages(txn: &TxnId, decision: Decision, state: &CoordinatedTxn) -> Vec<Outgoing<W>> {
+        state
+            .participants
+            .difference(&state.acks)
+            .map(|participant| Outgoing {
+                destination: participant.clone(),
+                message: match decision {
+                    Decision::Commit => Message::Commit { txn: txn.clone() },
+                    Decision::Abort => Message::Abort { txn: txn.clone() },
+                },
+            })
+            .collect()
+    }
+
+    /// Abort transactions that have waited too long for votes, and resend decisions
+    /// (or prepares) to participants that haven't replied. Call this periodically.
+    pub fn tick(&mut self, now: Instant) -> Step<W> {
+        let timed_out: Vec<TxnId> =
+            self.txns
+            .iter()
+            .filter(|(_, state)| state.decision.is_none() && now - state.started >= self.prepare_timeout)
+            .map(|(txn, _)| txn.clone())
+            .collect();
+
+        let mut step = Step::default();
+        for txn in timed_out {
+            warn!(txn = %txn, "timed out waiting for votes, aborting");
+            let aborted = self.decide(txn, Decision::Abort, now);
+            step.outgoing.extend(aborted.outgoing);
+            step.decided.extend(aborted.decided);
+        }
+
+        for (txn, state) in self.txns.iter_mut() {
+            if now - state.last_sent < self.retry_interval {
+                continue;
+            }
+            state.last_sent = now;
+            match state.decision {
+                Some(decision) => step.outgoing.extend(Self::decision_messages(txn, decision, state)),
+                None => {
+                    let writes = &self.prepared_writes[txn];
+                    step.outgoing.extend(
+                        state
+                        .participants
+                        .difference(&state.votes)
+                        .map(|participant| Outgoing {
+                            destination: participant.clone(),
+                            message: Message::Prepare { txn: txn.clone(), writes: writes[participant].clone() },
+                        })
+                    );
+                },
+            }
+        }
+        step
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.txns.len()
+    }
+}
+
+
+/// What a participant journals, so a prepared transaction is never forgotten
+/// across a restart: once we vote yes, we're bound to whatever the coordinator decides.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ParticipantRecord<W> {
+    Prepared { txn: TxnId, writes: W },
+    Resolved { txn: TxnId, decision: Decision },
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParticipantState<W> {
+    /// Transactions we voted yes on, still waiting to hear the outcome.
+    pub prepared: HashMap<TxnId, W>,
+}
+
+impl<W> Default for ParticipantState<W> {
+    fn default() -> Self {
+        Self { prepared: HashMap::new() }
+    }
+}
+
+impl<W: Clone> Replay<ParticipantRecord<W>> for ParticipantState<W> {
+    fn apply(&mut self, record: &ParticipantRecord<W>) {
+        match record {
+            ParticipantRecord::Prepared { txn, writes } => {
+                self.prepared.insert(txn.clone(), writes.clone());
+            },
+            ParticipantRecord::Resolved { txn, .. } => {
+                self.prepared.remove(txn);
+            },
+        }
+    }
+}
+
+
+/// The participant half of 2PC. Whether to vote yes is up to the caller (e.g. whether
+/// it managed to lock every key in `writes`); this only makes sure a yes vote is durable
+/// before it is sent and that the prepared writes are handed back once decided.
+#[derive(Debug)]
+pub struct Participant<W, St> {
+    durable: Durable<ParticipantState<W>, St>,
+}
+
+
+impl<W, St> Participant<W, St>
+where
+    W: Clone,
+    St: Storage<ParticipantState<W>, ParticipantRecord<W>>,
+{
+    /// Recover any transactions that were prepared but undecided when we last stopped.
+    pub fn open(storage: St) -> io::Result<Self> {
+        let durable = Durable::open(storage)?;
+        Ok(Self { durable })
+    }
+
+    pub fn in_doubt(&self) -> impl Iterator<Item = (&TxnId, &W)> {
+        self.durable.state.prepared.iter()
+    }
+
+    /// Record our vote and return the reply for the coordinator.
+    pub fn prepare(&mut self, txn: TxnId, writes: W, vote: bool) -> io::Result<Message<W>> {
+        if vote && !self.durable.state.prepared.contains_key(&txn) {
+            self.durable.apply(ParticipantRecord::Prepared { txn: txn.clone(), writes })?;
+        }
+        Ok(Message::Vote { txn, commit: vote })
+    }
+
+    /// Resolve a transaction, returning the writes that were prepared for it (if we still
+    /// had them) along with the ack for the coordinator. On commit, the caller must apply
+    /// the writes before sending the ack.
+    pub fn resolve(&mut self, txn: TxnId, decision: Decision) -> io::Result<(Option<W>, Message<W>)> {
+        let writes = self.durable.state.prepared.get(&txn).cloned();
+        if writes.is_some() {
+            self.durable.apply(ParticipantRecord::Resolved { txn: txn.clone(), decision })?;
+        }
+        Ok((writes, Message::Ack { txn }))
+    }
+
+
+    /// Fold everything journaled so far into a snapshot, dropping resolved transactions from the WAL.
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        self.durable.checkpoint::<ParticipantRecord<W>>()
+    }
+}
@@ -0,0 +1,94 @@
+use serde::{Serialize, Deserialize};
+use crate::{kv::KvError, message::ErrorCode};
+
+
+pub type Key = usize;
+pub type Value = usize;
+
+
+/// One operation within a `txn-rw-register` transaction, which Maelstrom encodes
+/// as `["r", key, null]` or `["w", key, value]`. Replies fill in the value each
+/// read observed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "(String, Key, Option<Value>)", into = "(String, Key, Option<Value>)")]
+pub enum MicroOp {
+    Read {
+        key: Key,
+        value: Option<Value>,
+    },
+    Write {
+        key: Key,
+        value: Value,
+    },
+}
+
+impl MicroOp {
+    pub fn key(&self) -> Key {
+        match self {
+            MicroOp::Read { key, .. } | MicroOp::Write { key, .. } => *key,
+        }
+    }
+
+    pub fn is_read(&self) -> bool {
+        matches!(self, MicroOp::Read { .. })
+    }
+}
+
+impl TryFrom<(String, Key, Option<Value>)> for MicroOp {
+    type Error = String;
+
+    fn try_from((op, key, value): (String, Key, Option<Value>)) -> Result<Self, Self::Error> {
+        match (op.as_str(), value) {
+            ("r", value) => Ok(MicroOp::Read { key, value }),
+            ("w", Some(value)) => Ok(MicroOp::Write { key, value }),
+            ("w", None) => Err(format!("write to key {key} is missing a value")),
+            (other, _) => Err(format!("unknown micro-op {other:?}")),
+        }
+    }
+}
+
+impl From<MicroOp> for (String, Key, Option<Value>) {
+    fn from(op: MicroOp) -> Self {
+        match op {
+            MicroOp::Read { key, value } => ("r".to_owned(), key, value),
+            MicroOp::Write { key, value } => ("w".to_owned(), key, Some(value)),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxnError {
+    /// Another transaction got in the way; it's safe for the client to retry.
+    Conflict(String),
+    /// A service we depend on failed before we reached the commit point, so nothing happened.
+    Unavailable(KvError),
+    /// A service failed while we were committing, so we can't tell whether the transaction took effect.
+    Indeterminate(KvError),
+}
+
+impl TxnError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            TxnError::Conflict(_) => ErrorCode::TxnConflict,
+            TxnError::Unavailable(_) => ErrorCode::TemporarilyUnavailable,
+            TxnError::Indeterminate(_) => ErrorCode::Crash,
+        }
+    }
+}
+
+impl std::fmt::Display for TxnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TxnError::Conflict(reason) => write!(f, "txn conflict: {reason}"),
+            TxnError::Unavailable(err) => write!(f, "kv unavailable: {err}"),
+            TxnError::Indeterminate(err) => write!(f, "txn outcome unknown: {err}"),
+        }
+    }
+}
+
+impl From<KvError> for TxnError {
+    fn from(err: KvError) -> Self {
+        TxnError::Unavailable(err)
+    }
+}
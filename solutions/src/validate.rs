@@ -0,0 +1,127 @@
+use std::{collections::HashSet, fmt::Display, sync::{Mutex, OnceLock}};
+use serde_json::Value;
+
+
+/// Something wrong with one of the messages a node was given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// Which line of the input it's on, counting from 1.
+    pub line: usize,
+    pub problem: String,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.problem)
+    }
+}
+
+
+/// Lints the messages a node is given against the protocol: each should be a JSON
+/// envelope with a `src`, a `dest` and a body `type` the node knows, requests should
+/// have a `msg_id`, and replies should answer a message the node actually sent.
+///
+/// Replies are only checked at the end ([`Validator::finish`]), so one that's read
+/// before the node gets around to sending what it answers isn't held against it.
+#[derive(Debug, Default)]
+pub struct Validator {
+    received: usize,
+    violations: Vec<Violation>,
+    /// Who the node sent messages to, and with which `msg_id`.
+    sent: HashSet<(String, u64)>,
+    /// Replies the node was given: on which line, from whom, and what they answer.
+    replies: Vec<(usize, String, u64)>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Note a message the node sent (or, in a recording, had sent), so replies to it are
+    /// known.
+    pub fn sent(&mut self, line: &[u8]) {
+        let Ok(envelope) = serde_json::from_slice::<Value>(line) else {
+            return;
+        };
+        if let (Some(dest), Some(msg_id)) = (envelope["dest"].as_str(), envelope["body"]["msg_id"].as_u64()) {
+            self.sent.insert((dest.to_owned(), msg_id));
+        }
+    }
+
+    /// Check a message the node was given on input line `line`, along with the error
+    /// from parsing it into one of the node's messages, if it didn't parse.
+    /// Blank lines are skipped.
+    pub fn received(&mut self, line: usize, message: &[u8], parsed: Result<(), &serde_json::Error>) {
+        if message.trim_ascii().is_empty() {
+            return;
+        }
+        self.received += 1;
+        for problem in self.problems(line, message, parsed) {
+            self.violations.push(Violation { line, problem });
+        }
+    }
+
+    fn problems(&mut self, line: usize, message: &[u8], parsed: Result<(), &serde_json::Error>) -> Vec<String> {
+        let Ok(envelope) = serde_json::from_slice::<Value>(message) else {
+            return vec!["not a JSON message".to_owned()];
+        };
+        let mut problems: Vec<String> =
+            ["src", "dest"]
+            .into_iter()
+            .filter(|field| !envelope[field].is_string())
+            .map(|field| format!("no `{field}`"))
+            .collect();
+        let body = &envelope["body"];
+        let Some(kind) = body["type"].as_str() else {
+            problems.push("no body `type`".to_owned());
+            return problems;
+        };
+        match parsed {
+            Err(err) if err.to_string().contains("unknown variant") => problems.push(format!("unknown type `{kind}`")),
+            Err(err) => problems.push(format!("malformed `{kind}`: {err}")),
+            Ok(()) => {},
+        }
+        match (body["msg_id"].is_null(), body["in_reply_to"].as_u64()) {
+            (true, None) => problems.push(format!("`{kind}` request has no `msg_id`")),
+            (_, Some(in_reply_to)) => {
+                let source = envelope["src"].as_str().unwrap_or_default().to_owned();
+                self.replies.push((line, source, in_reply_to));
+            },
+            (false, None) => {},
+        }
+        problems
+    }
+
+    /// How many messages were checked.
+    pub fn num_received(&self) -> usize {
+        self.received
+    }
+
+    /// Every violation, by line, once the replies have been checked against what the node
+    /// sent.
+    pub fn finish(&mut self) -> &[Violation] {
+        for (line, source, in_reply_to) in self.replies.drain(..) {
+            if !self.sent.contains(&(source.clone(), in_reply_to)) {
+                self.violations.push(Violation { line, problem: format!("reply from {source} to unknown msg_id {in_reply_to}") });
+            }
+        }
+        self.violations.sort_by_key(|violation| violation.line);
+        &self.violations
+    }
+}
+
+
+static VALIDATOR: OnceLock<Mutex<Validator>> = OnceLock::new();
+
+/// Have [`io_channel`](crate::io::io_channel) check every message it reads with the
+/// [`global`] validator, and write nothing: the node still handles what it's given (its
+/// state is thrown away at exit), but its replies only go to the validator.
+pub fn enable() {
+    VALIDATOR.set(Mutex::new(Validator::new())).ok();
+}
+
+/// The validator this process checks its input with, if it's [enabled](enable).
+pub fn global() -> Option<&'static Mutex<Validator>> {
+    VALIDATOR.get()
+}
@@ -0,0 +1,58 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+use serde::{Deserialize, Serialize};
+
+
+/// How many writes each node has coordinated that a version of something has seen, so
+/// two versions written on different nodes can be told apart: one that has seen
+/// everything the other has is newer, and two that have each seen something the other
+/// hasn't are concurrent, and both have to be kept (or one picked by some other rule).
+///
+/// Compared with [`PartialOrd`]: `None` means concurrent.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VectorClock(BTreeMap<String, u64>);
+
+impl VectorClock {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// How many of `node`'s writes this has seen.
+    pub fn get(&self, node: &str) -> u64 {
+        self.0.get(node).copied().unwrap_or_default()
+    }
+
+    /// Count one more write coordinated by `node`.
+    pub fn increment(&mut self, node: &str) {
+        *self.0.entry(node.to_owned()).or_default() += 1;
+    }
+
+    /// Take in everything `other` has seen too.
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, &count) in &other.0 {
+            let seen = self.0.entry(node.clone()).or_default();
+            *seen = (*seen).max(count);
+        }
+    }
+
+    /// Whether this has seen everything `other` has (or more).
+    pub fn descends(&self, other: &VectorClock) -> bool {
+        other.0.iter().all(|(node, &count)| self.get(node) >= count)
+    }
+
+    /// Whether neither has seen everything the other has.
+    pub fn concurrent(&self, other: &VectorClock) -> bool {
+        self.partial_cmp(other).is_none()
+    }
+}
+
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self.descends(other), other.descends(self)) {
+            (true, true) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Greater),
+            (false, true) => Some(Ordering::Less),
+            (false, false) => None,
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::{future::Future, time::Duration};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+use crate::{cli::{CommonOpts, LogFormat, Runtime}, conformance::Workload, efficiency, io, metrics, rate_limit::{self, Limits}, self_test, shutdown, tuning, validate};
+
+#[cfg(feature = "echo")]
+pub mod echo;
+#[cfg(feature = "unique-ids")]
+pub mod unique_id_generation;
+#[cfg(feature = "broadcast")]
+pub mod broadcast;
+#[cfg(feature = "counter")]
+pub mod grow_only_counter;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "txn")]
+pub mod transactions;
+#[cfg(feature = "craq")]
+pub mod chain_replication_kv;
+#[cfg(feature = "dynamo")]
+pub mod dynamo_kv;
+
+
+/// How often [`run`] dumps the metrics, unless `METRICS_INTERVAL_MS` says otherwise
+/// (`0` turns the periodic dumps off).
+const METRICS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often [`run`] prints the messages-per-operation estimate, if there's one.
+const MSGS_PER_OP_INTERVAL: Duration = Duration::from_secs(2);
+
+
+/// With `--self-test`, make sure a `workload` node is wired up right before it starts for
+/// real: run a copy of it (whatever `server` starts) through its first few requests (see
+/// [`self_test::check`]), and exit with status 1 if any reply is off. Node binaries call
+/// this just before [`run`].
+pub fn self_test<F, Fut>(common: &CommonOpts, workload: Workload, server: F)
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = ()>,
+{
+    if !common.self_test {
+        return;
+    }
+    let problems = self_test::check(workload, server);
+    for problem in &problems {
+        eprintln!("self-test: {problem}");
+    }
+    if !problems.is_empty() {
+        eprintln!("self-test failed");
+        std::process::exit(1);
+    }
+    eprintln!("self-test passed");
+    // The real node starts counting from zero.
+    metrics::global().clear();
+}
+
+
+/// Put a node's [`CommonOpts`] into effect: log to stderr (filtered by `--log-level`,
+/// as `--log-format` says), start the [`tuning`] parameters off at its tick rate and
+/// fanout, read and write its messages from and to files instead of stdin and stdout if
+/// asked to, and sample or record them too. Serve tokio-console with the `console`
+/// feature (on `TOKIO_CONSOLE_BIND`, `127.0.0.1:6669` by default) and export spans with
+/// the `otel` one (see [`otel::layer`](crate::otel::layer)).
+///
+/// Then run a node's `server` to completion on the tokio runtime `--runtime` asks for
+/// (multi-threaded by default), or until SIGTERM or SIGINT, when its queued replies are
+/// written out and its periodic work stopped (see [`shutdown`]). Its [`metrics`] are
+/// dumped to stderr every so often and once more when it's done, along with a summary of
+/// its latencies (and, given `--client-ops`, its [messages per operation](efficiency)
+/// every couple of seconds).
+///
+/// With `--validate`, the node's input is [linted](validate) instead of answered, and it
+/// exits with status 1 if anything's wrong with it. What every node binary's `main`
+/// comes down to.
+pub fn run(common: CommonOpts, server: impl Future<Output = ()>) {
+    let logs = fmt::layer().with_writer(std::io::stderr).with_ansi(false);
+    let logs = match common.log_format {
+        LogFormat::Full => logs.boxed(),
+        LogFormat::Compact => logs.compact().boxed(),
+        LogFormat::Pretty => logs.pretty().boxed(),
+    };
+    let filter =
+        EnvFilter::builder()
+        .with_default_directive(LevelFilter::ERROR.into())
+        .parse_lossy(common.log_level.as_deref().unwrap_or_default());
+    // Each layer filters for itself: `--log-level` is only about the logs, while the console
+    // wants every task and resource event, and exported traces want every handler and RPC.
+    let subscriber = tracing_subscriber::registry().with(logs.with_filter(filter));
+    #[cfg(feature = "console")]
+    let subscriber = subscriber.with(console_subscriber::spawn());
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(crate::otel::layer().map(|layer| layer.with_filter(LevelFilter::INFO)));
+    subscriber.init();
+
+    tuning::global().set_tick_rate(common.tick_rate());
+    tuning::global().set_fanout(common.fanout);
+    tuning::global().set_fanout_strategy(common.fanout_strategy);
+    tuning::global().set_window(common.gossip_window);
+    tuning::global().set_batch_size(common.batch_size);
+    rate_limit::limit(Limits { global: common.rate_limit, per_destination: common.rate_limit_per_dest });
+    if let Some(every) = common.log_sample {
+        io::sample_logs(every);
+    }
+    if !common.client_ops.is_empty() {
+        efficiency::count_client_ops(common.client_ops);
+    }
+    if let Some(path) = &common.input {
+        if let Err(err) = io::read_from(path) {
+            eprintln!("can't read {}: {err}", path.display());
+            std::process::exit(1);
+        }
+    }
+    if let Some(path) = &common.output {
+        if let Err(err) = io::write_to(path) {
+            eprintln!("can't write {}: {err}", path.display());
+            std::process::exit(1);
+        }
+    }
+    if common.validate {
+        validate::enable();
+    }
+    if let Some(path) = &common.record {
+        if let Err(err) = io::record_to(path) {
+            eprintln!("not recording to {}: {err}", path.display());
+        }
+    }
+
+    let metrics_interval =
+        std::env::var("METRICS_INTERVAL_MS")
+        .ok()
+        .and_then(|ms| ms.parse().ok())
+        .map_or(METRICS_INTERVAL, Duration::from_millis);
+
+    let mut builder = match common.runtime {
+        Runtime::Single => tokio::runtime::Builder::new_current_thread(),
+        Runtime::Multi => tokio::runtime::Builder::new_multi_thread(),
+        Runtime::MultiWith(threads) => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            builder.worker_threads(threads.get());
+            builder
+        },
+    };
+    let runtime =
+        builder
+        .enable_all()
+        .build()
+        .expect("failed to build the tokio runtime");
+    runtime.block_on(async move {
+        if !metrics_interval.is_zero() {
+            metrics::dump_every(metrics_interval);
+        }
+        if efficiency::enabled() {
+            efficiency::log_every(MSGS_PER_OP_INTERVAL);
+        }
+        tokio::select! {
+            () = server => {},
+            () = shutdown::on_signal() => {},
+        }
+        // However it ended, let the tasks that have something to finish (writing out
+        // queued replies, a last checkpoint) finish it.
+        shutdown::request();
+        shutdown::finished().await;
+    });
+    // After a signal, the stdin reader is still blocked on a line that may never come.
+    runtime.shutdown_background();
+    metrics::dump();
+    metrics::dump_latencies();
+    if let Some(estimate) = efficiency::estimate() {
+        eprintln!("msgs-per-op: {estimate}");
+    }
+    #[cfg(feature = "otel")]
+    crate::otel::shutdown();
+    if let Some(validator) = validate::global() {
+        let mut validator = validator.lock().unwrap();
+        let num_received = validator.num_received();
+        let violations = validator.finish();
+        for violation in violations {
+            eprintln!("violation: {violation}");
+        }
+        eprintln!("validate: {num_received} messages, {} violations", violations.len());
+        if !violations.is_empty() {
+            std::process::exit(1);
+        }
+    }
+}
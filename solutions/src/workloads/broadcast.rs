@@ -0,0 +1,949 @@
+use serde::{Serialize, Deserialize};
+use crate::{cli::CommonOpts, clock::{Clock, SharedClock}, gossip, io::{self, io_channel_with_background}, membership::{self, Membership, Update}, message::{self, Body, Envelope, ErrorCode, NodeId}, metrics, rtt::RttEstimator, scheduler::{Job, JobHandle, Period, Scheduler, DEFAULT_JITTER}, storage::{FileStorage, Recovered, Replay, Storage}, topology::{self, Overlay}, tuning};
+use tokio::sync::mpsc::UnboundedSender;
+use rand::seq::SliceRandom;
+use tracing::{debug, error, info, info_span, trace, Instrument};
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::atomic::{AtomicUsize, Ordering}, time::{Duration, Instant}};
+use std::sync::{Arc, Mutex};
+use clap::{Parser, ValueEnum};
+use roaring::RoaringTreemap;
+
+#[derive(Debug, Clone, Parser)]
+#[clap(author, version)]
+pub struct Opts {
+    #[clap(short, long, help = "choose 1 out of every STRIDE nodes as a direct neighbor (the same as `--overlay stride:STRIDE`; every node if neither is given)", env = "STRIDE")]
+    pub stride: Option<usize>,
+    #[clap(long, value_enum, help = "Start from a bundled set of settings (which the config file, environment variables and flags take precedence over).", env = "PROFILE")]
+    pub profile: Option<Profile>,
+    #[clap(long, value_enum, default_value_t = Gossip::Backlog, help = "How messages spread.", env = "GOSSIP")]
+    pub gossip: Gossip,
+    #[clap(long, default_value_t = 3, help = "With `--gossip rumor`, how many random neighbors each round of rumors goes to.", env = "RUMOR_PEERS")]
+    pub rumor_peers: usize,
+    #[clap(long, default_value_t = 3, help = "With `--gossip rumor`, how many rounds a new message is pushed for before it goes quiet.", env = "RUMOR_ROUNDS")]
+    pub rumor_rounds: usize,
+    #[clap(long, default_value_t = 10, help = "With `--gossip rumor`, sync neighbors whatever they haven't acknowledged every this many rounds, for the ones rumors missed (0 never does).", env = "ANTI_ENTROPY_EVERY")]
+    pub anti_entropy_every: usize,
+    #[clap(long, help = "Keep the messages seen, and which of them each neighbor has, in this directory (a write-ahead log plus snapshots), so they survive the node restarting.", env = "DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    #[clap(flatten)]
+    pub common: CommonOpts,
+}
+
+
+/// How messages spread from one node to the next.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Gossip {
+    /// Every round, sync each neighbor whatever it hasn't acknowledged yet.
+    #[default]
+    Backlog,
+    /// Push each new message to a few random neighbors for a few rounds, and leave the
+    /// ones it misses to an occasional backlog round.
+    Rumor,
+    /// Every round, swap digests with one random neighbor, and each side sends the other
+    /// whatever it's missing.
+    PushPull,
+}
+
+
+/// How rumors spread, with `--gossip rumor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rumors {
+    /// How many random neighbors each round goes to.
+    pub peers: usize,
+    /// How many rounds a message is pushed for.
+    pub rounds: usize,
+    /// Every how many rounds to sync neighbors their backlog too; `0` for never.
+    pub anti_entropy_every: usize,
+}
+
+
+/// Tuned settings for broadcast, kept in [`configs`](https://github.com/aalekhpatel07/maelstrom-ci/blob/main/solutions/configs)
+/// and built into the binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Profile {
+    /// Challenge 3d: under 30 messages per operation, with a median latency under 400ms.
+    #[value(name = "3d")]
+    Challenge3d,
+    /// Challenge 3e: under 20 messages per operation, with a median latency under 1s.
+    #[value(name = "3e")]
+    Challenge3e,
+    /// As quick as possible, however many messages it takes.
+    LowLatency,
+    /// As few messages as possible, however long they take.
+    LowBandwidth,
+}
+
+impl Profile {
+    /// The profile's settings, written like a config file.
+    pub fn settings(self) -> &'static str {
+        match self {
+            Profile::Challenge3d => include_str!("../../configs/broadcast-3d.toml"),
+            Profile::Challenge3e => include_str!("../../configs/broadcast-3e.toml"),
+            Profile::LowLatency => include_str!("../../configs/broadcast-low-latency.toml"),
+            Profile::LowBandwidth => include_str!("../../configs/broadcast-low-bandwidth.toml"),
+        }
+    }
+}
+
+impl Opts {
+    /// How rumors spread, with `--gossip rumor`.
+    pub fn rumors(&self) -> Option<Rumors> {
+        let rumors = Rumors { peers: self.rumor_peers, rounds: self.rumor_rounds, anti_entropy_every: self.anti_entropy_every };
+        (self.gossip == Gossip::Rumor).then_some(rumors)
+    }
+
+    /// How the node picks its neighbors: `--overlay`, or else 1 in every `--stride` nodes.
+    pub fn overlay(&self) -> Overlay {
+        self.common.overlay.unwrap_or(Overlay::Stride(self.stride.unwrap_or(1)))
+    }
+}
+
+
+static MSG_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// How long a sync may go unacknowledged before it's taken for lost, and no longer counts
+/// against its neighbor's window, until the neighbor's acks say how long they take.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often a node on a `nearest` overlay picks its neighbors again, by the round trips
+/// it's measured since.
+pub const REEVALUATE_NEIGHBORS_EVERY: Duration = Duration::from_secs(2);
+
+/// How many syncs in a row a neighbor can leave unacknowledged before it's taken for cut
+/// off from us.
+pub const CUT_OFF_AFTER: usize = 2;
+
+/// How many records a node with `--data-dir` journals before folding them into a snapshot.
+const CHECKPOINT_AFTER: u64 = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Payload {
+    Init { 
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+    Broadcast {
+        message: usize,
+    },
+    BroadcastOk,
+    /// Maelstrom's clients only ever send a plain `read`, and get everything in one
+    /// `read_ok`. Clients of our own can ask for less per line.
+    Read {
+        /// Put at most this many messages (or ranges) in one `read_ok`, and send as many
+        /// as it takes.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chunk_size: Option<usize>,
+        /// Send runs of consecutive messages as `ranges` rather than one by one.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        ranges: bool,
+    },
+    ReadOk {
+        messages: Vec<usize>,
+        /// Inclusive `[first, last]` runs of messages, if the `read` asked for them.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        ranges: Vec<(usize, usize)>,
+        /// Which of the `parts` `read_ok`s to the same `read` this is (from 0), if there's
+        /// more than one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        part: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        parts: Option<usize>,
+    },
+    Topology {
+        topology: HashMap<String, Vec<String>>
+    },
+    TopologyOk,
+    /// From an admin: `node_id` has joined the cluster.
+    Join {
+        node_id: String,
+    },
+    JoinOk,
+    /// From an admin: `node_id` has left the cluster.
+    Leave {
+        node_id: String,
+    },
+    LeaveOk,
+    Sync {
+        messages: Vec<usize>,
+        /// Membership rumors, with `--swim`.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        updates: Vec<Update>,
+    },
+    SyncOk {
+        messages: Vec<usize>,
+    },
+    /// With `--gossip push-pull`: everything the sender has, as inclusive `[first, last]`
+    /// runs.
+    Digest {
+        ranges: Vec<(usize, usize)>,
+    },
+    /// What the digest's sender is missing, and what it has that we want.
+    DigestOk {
+        messages: Vec<usize>,
+        wants: Vec<(usize, usize)>,
+    },
+    /// What a `digest_ok` asked for. Nothing comes back; the next round catches anything
+    /// lost.
+    Push {
+        messages: Vec<usize>,
+    },
+    Error {
+        code: usize,
+        text: String,
+    },
+    Ping {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        updates: Vec<Update>,
+    },
+    PingReq {
+        target: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        updates: Vec<Update>,
+    },
+    Ack {
+        target: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        updates: Vec<Update>,
+    },
+}
+
+fn message_id() -> usize {
+    MSG_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+impl membership::Messages for Payload {
+    fn ping(updates: Vec<Update>) -> Self {
+        Payload::Ping { updates }
+    }
+
+    fn ping_req(target: String, updates: Vec<Update>) -> Self {
+        Payload::PingReq { target, updates }
+    }
+
+    fn ack(target: String, updates: Vec<Update>) -> Self {
+        Payload::Ack { target, updates }
+    }
+
+    fn message_id() -> usize {
+        message_id()
+    }
+}
+
+
+/// What we know of a neighbor: which messages it has. What it still needs is whatever
+/// we've seen that it doesn't, so nothing has to be queued up per neighbor as messages
+/// come in. Sets of messages are roaring bitmaps, which stay small (and quick to take
+/// the difference of) even with 100k messages around.
+#[derive(Debug, Clone, Default)]
+pub struct RemoteNode {
+    pub node_id: String,
+    /// The messages it has acknowledged, or sent us itself.
+    pub known: RoaringTreemap,
+    /// The syncs it has yet to acknowledge, by `msg_id`, and when they were sent.
+    pub in_flight: HashMap<usize, Instant>,
+    /// How long its acks take.
+    pub rtt: RttEstimator,
+    /// How many syncs in a row it's left unacknowledged, up to now.
+    pub timed_out: usize,
+}
+
+impl RemoteNode {
+    pub fn acknowledge_synced(&mut self, messages: &[usize]) {
+        self.known.extend(messages.iter().map(|&message| message as u64));
+        trace!(node_id = self.node_id, "remote node has acknowledged these messages: {:?}", messages);
+    }
+
+    /// Which of the `seen` messages it hasn't acknowledged yet.
+    pub fn unacknowledged(&self, seen: &RoaringTreemap) -> RoaringTreemap {
+        seen - &self.known
+    }
+
+    /// Note the ack to sync `msg_id`, received at `now`. Returns whether it had been cut
+    /// off until then.
+    pub fn acknowledged(&mut self, msg_id: usize, now: Instant) -> bool {
+        if let Some(sent) = self.in_flight.remove(&msg_id) {
+            self.rtt.sample(now.saturating_duration_since(sent));
+        }
+        let was_cut_off = self.is_cut_off();
+        self.timed_out = 0;
+        was_cut_off
+    }
+
+    /// Whether its last [`CUT_OFF_AFTER`] syncs all went unacknowledged.
+    pub fn is_cut_off(&self) -> bool {
+        self.timed_out >= CUT_OFF_AFTER
+    }
+
+    /// Whether it can be sent another sync at `now`, with at most `window` of them in
+    /// flight. Syncs that have gone unacknowledged for longer than its acks take (see
+    /// [`RttEstimator::timeout`]) are given up on.
+    pub fn has_room(&mut self, window: Option<usize>, now: Instant) -> bool {
+        let timeout = self.rtt.timeout(SYNC_TIMEOUT);
+        let before = self.in_flight.len();
+        self.in_flight.retain(|_, sent| now.saturating_duration_since(*sent) < timeout);
+        self.timed_out += before - self.in_flight.len();
+        window.map_or(true, |window| self.in_flight.len() < window)
+    }
+}
+
+
+/// What a node with `--data-dir` journals before acting on it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Learned {
+    /// Messages it hadn't seen before, from a client or a neighbor.
+    Seen { messages: Vec<usize> },
+    /// Messages `node` has, since it acknowledged or sent them.
+    Known { node: String, messages: Vec<usize> },
+}
+
+
+/// Everything a node with `--data-dir` comes back to after a restart: the messages it's
+/// seen, and which of them each neighbor has, so it neither loses the broadcasts it
+/// acknowledged nor syncs its neighbors everything again.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Knowledge {
+    pub seen: RoaringTreemap,
+    pub known: HashMap<String, RoaringTreemap>,
+}
+
+impl Replay<Learned> for Knowledge {
+    fn apply(&mut self, learned: &Learned) {
+        match learned {
+            Learned::Seen { messages } => self.seen.extend(messages.iter().map(|&message| message as u64)),
+            Learned::Known { node, messages } => self.known.entry(node.clone()).or_default().extend(messages.iter().map(|&message| message as u64)),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Default)]
+pub struct State {
+    my_id: NodeId,
+    all_node_ids: Vec<NodeId>,
+    neighbors: Vec<NodeId>,
+    /// The topology Maelstrom sent, for picking neighbors again as nodes come and go.
+    topology: HashMap<String, Vec<String>>,
+    nodes: HashMap<NodeId, RemoteNode>,
+    messages: RoaringTreemap,
+    overlay: Option<Overlay>,
+    /// How to keep track of who's alive, with `--swim`.
+    swim: Option<membership::Config>,
+    membership: Option<Membership>,
+    /// Whether enough neighbors are cut off at once to make a partition likely.
+    partitioned: bool,
+    /// Where what the node learns is journaled, with `--data-dir`.
+    storage: Option<Arc<Mutex<FileStorage>>>,
+    /// How rumors spread, with `--gossip rumor`.
+    rumors: Option<Rumors>,
+    /// The messages still being pushed, with how many rounds they have left.
+    hot: HashMap<u64, usize>,
+    /// How many rounds of gossip there have been.
+    rounds: usize,
+    /// Whether to swap digests with a neighbor each round, with `--gossip push-pull`.
+    push_pull: bool,
+    /// What acks and membership are timed by.
+    clock: SharedClock,
+}
+
+
+impl membership::Node for State {
+    fn membership(&mut self) -> Option<(NodeId, &mut Membership, tokio::time::Instant)> {
+        let now = self.clock.now();
+        Some((self.my_id.clone(), self.membership.as_mut()?, now))
+    }
+}
+
+
+impl State {
+    /// A node yet to be initialized, that'll pick its neighbors by `overlay`.
+    pub fn new(overlay: Overlay) -> Self {
+        Self { overlay: Some(overlay), ..Default::default() }
+    }
+
+    /// Keep a SWIM-style view of which peers are alive, once initialized, and stop
+    /// syncing ones taken for dead.
+    pub fn with_membership(mut self, config: membership::Config) -> Self {
+        self.swim = Some(config);
+        self
+    }
+
+    /// Time acks and membership by `clock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = SharedClock::new(clock);
+        self
+    }
+
+    /// Swap digests with one random neighbor each round, rather than syncing every
+    /// neighbor its backlog.
+    pub fn with_push_pull(mut self) -> Self {
+        self.push_pull = true;
+        self
+    }
+
+    /// Spread new messages as rumors rather than syncing every neighbor its backlog.
+    pub fn with_rumors(mut self, rumors: Rumors) -> Self {
+        self.rumors = Some(rumors);
+        self
+    }
+
+    /// Pick neighbors from the cluster as it stands: new ones start out knowing nothing
+    /// (so gossip brings them up to date), and nodes that have left are forgotten. Ones
+    /// that are just no longer neighbors are remembered (what they know, and how long
+    /// their acks took), in case they're picked again.
+    fn pick_neighbors(&mut self) {
+        self.neighbors = match self.overlay.unwrap_or(Overlay::Stride(1)) {
+            Overlay::Nearest { near, far } => {
+                let rtts = self.nodes.iter().filter_map(|(node, remote)| Some((node.clone(), remote.rtt.smoothed()?))).collect();
+                topology::nearest(&self.my_id, &self.all_node_ids, &rtts, near, far, &mut rand::thread_rng())
+            },
+            overlay => overlay.neighbors(&self.my_id, &self.all_node_ids, &self.topology),
+        };
+        let all_node_ids = &self.all_node_ids;
+        self.nodes.retain(|node, _| all_node_ids.contains(node));
+        for neighbor in &self.neighbors {
+            self.nodes.entry(neighbor.clone()).or_default();
+        }
+    }
+
+    /// Pick neighbors again, by the round trips measured so far on a `nearest` overlay
+    /// (any other overlay picks the same ones). Returns whether they changed.
+    pub fn reevaluate_neighbors(&mut self) -> bool {
+        let before = std::mem::take(&mut self.neighbors);
+        self.pick_neighbors();
+        let changed = before.len() != self.neighbors.len() || before.iter().any(|neighbor| !self.neighbors.contains(neighbor));
+        if changed {
+            debug!(neighbors = ?self.neighbors, "picked new neighbors");
+            metrics::global().increment("broadcast.neighbors_changed");
+        }
+        changed
+    }
+
+    pub fn neighbors(&self) -> &[NodeId] {
+        &self.neighbors
+    }
+
+    /// Whether enough neighbors have been cut off at once, as of the last round of
+    /// gossip, for the node to be on the wrong side of a partition.
+    pub fn is_partitioned(&self) -> bool {
+        self.partitioned
+    }
+
+    /// Take a partition for likely when at least half the neighbors (and at least two of
+    /// them) are cut off at once, and for healed once none are.
+    fn detect_partition(&mut self) {
+        let cut_off = self.neighbors.iter().filter(|neighbor| self.nodes.get(*neighbor).is_some_and(RemoteNode::is_cut_off)).count();
+        metrics::global().set("broadcast.cut_off", cut_off as i64);
+        if !self.partitioned && cut_off >= 2 && cut_off * 2 >= self.neighbors.len() {
+            info!(cut_off, neighbors = self.neighbors.len(), "neighbors are timing out together, likely a partition");
+            metrics::global().increment("broadcast.partitions_detected");
+            self.partitioned = true;
+        } else if self.partitioned && cut_off == 0 {
+            info!("partition healed");
+            self.partitioned = false;
+        }
+    }
+
+    pub fn seen_messages(&self) -> Vec<usize> {
+        self.messages.iter().map(|message| message as usize).collect()
+    }
+
+    /// Journal what the node learns in `dir`, picking up whatever it knew there before it
+    /// last stopped.
+    pub fn with_storage(mut self, dir: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut storage = FileStorage::open(dir)?;
+        let Recovered { snapshot, records } = Storage::<Knowledge, Learned>::recover(&mut storage)?;
+        let mut knowledge: Knowledge = snapshot.unwrap_or_default();
+        for learned in &records {
+            knowledge.apply(learned);
+        }
+        info!(seen = knowledge.seen.len(), neighbors = knowledge.known.len(), "recovered what the node knew");
+        self.messages = knowledge.seen;
+        for (node, known) in knowledge.known {
+            self.nodes.entry(message::intern(&node)).or_default().known = known;
+        }
+        metrics::global().set("broadcast.seen", self.messages.len() as i64);
+        self.storage = Some(Arc::new(Mutex::new(storage)));
+        Ok(self)
+    }
+
+    /// Journal `learned` (if there's anywhere to), then apply it.
+    fn learn(&mut self, learned: Learned) -> std::io::Result<()> {
+        let Some(storage) = self.storage.clone() else {
+            self.apply(&learned);
+            return Ok(());
+        };
+        let mut storage = storage.lock().unwrap();
+        Storage::<Knowledge, Learned>::append(&mut *storage, &learned)?;
+        self.apply(&learned);
+        if Storage::<Knowledge, Learned>::uncheckpointed(&*storage) >= CHECKPOINT_AFTER {
+            // The journal still has everything if this fails; it's tried again next time.
+            if let Err(err) = Storage::<Knowledge, Learned>::checkpoint(&mut *storage, &self.knowledge()) {
+                error!(error = ?err, "failed to checkpoint what the node knows");
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, learned: &Learned) {
+        match learned {
+            Learned::Seen { messages } => {
+                self.messages.extend(messages.iter().map(|&message| message as u64));
+                if let Some(rumors) = self.rumors {
+                    self.hot.extend(messages.iter().map(|&message| (message as u64, rumors.rounds)));
+                }
+            },
+            Learned::Known { node, messages } => if let Some(remote) = self.nodes.get_mut(node.as_str()) {
+                remote.acknowledge_synced(messages);
+            },
+        }
+    }
+
+    /// What's worth remembering across a restart.
+    pub fn knowledge(&self) -> Knowledge {
+        Knowledge {
+            seen: self.messages.clone(),
+            known: self.nodes.iter().map(|(node, remote)| (node.to_string(), remote.known.clone())).collect(),
+        }
+    }
+
+    /// Which of `messages` the node hasn't seen yet.
+    fn unseen(&self, messages: impl IntoIterator<Item = usize>) -> Vec<usize> {
+        messages.into_iter().filter(|&message| !self.messages.contains(message as u64)).collect()
+    }
+}
+
+
+/// The `read_ok`s that answer a `read` for `messages`: one, unless `chunk_size` splits
+/// them up, in which case they're numbered so the reader knows when it has them all.
+pub fn read_replies(messages: &RoaringTreemap, chunk_size: Option<usize>, ranges: bool) -> Vec<Payload> {
+    let chunk_size = chunk_size.filter(|size| *size > 0).unwrap_or(usize::MAX);
+    let mut replies: Vec<Payload> = if ranges {
+        runs(messages).chunks(chunk_size).map(|ranges| Payload::ReadOk { messages: vec![], ranges: ranges.to_vec(), part: None, parts: None }).collect()
+    } else {
+        let messages: Vec<usize> = messages.iter().map(|message| message as usize).collect();
+        messages.chunks(chunk_size).map(|messages| Payload::ReadOk { messages: messages.to_vec(), ranges: vec![], part: None, parts: None }).collect()
+    };
+    if replies.is_empty() {
+        replies.push(Payload::ReadOk { messages: vec![], ranges: vec![], part: None, parts: None });
+    }
+    let count = replies.len();
+    if count > 1 {
+        for (index, reply) in replies.iter_mut().enumerate() {
+            if let Payload::ReadOk { part, parts, .. } = reply {
+                (*part, *parts) = (Some(index), Some(count));
+            }
+        }
+    }
+    replies
+}
+
+/// The messages in inclusive `runs`.
+fn from_runs(runs: &[(usize, usize)]) -> RoaringTreemap {
+    let mut messages = RoaringTreemap::new();
+    for &(first, last) in runs {
+        messages.insert_range(first as u64..=last as u64);
+    }
+    messages
+}
+
+/// `messages` as inclusive runs of consecutive ones.
+fn runs(messages: &RoaringTreemap) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = vec![];
+    for message in messages.iter().map(|message| message as usize) {
+        match runs.last_mut() {
+            Some((_, last)) if *last + 1 == message => *last = message,
+            _ => runs.push((message, message)),
+        }
+    }
+    runs
+}
+
+pub async fn handle_envelope(
+    state: Arc<Mutex<State>>,
+    envelope: Envelope<Payload>, 
+    writer: UnboundedSender<Envelope<Payload>>
+) {
+    match &envelope.body.message {
+        Payload::Init { node_id, node_ids } => {
+            let mut state = state.lock().unwrap();
+            state.my_id = message::intern(node_id);
+            state.all_node_ids = node_ids.iter().map(|node_id| message::intern(node_id)).collect();
+            if let Some(config) = state.swim {
+                let now = state.clock.now();
+                state.membership = Some(Membership::new(config, node_id, state.all_node_ids.clone(), now));
+            }
+
+            let reply = envelope.reply_with(
+                Some(message_id()),
+                Payload::InitOk
+            );
+            writer.send(reply).unwrap();
+        },
+        Payload::Topology { topology } => {
+            let mut state = state.lock().unwrap();
+            state.topology = topology.clone();
+            state.pick_neighbors();
+
+            let reply = envelope.reply_with(
+                Some(message_id()),
+                Payload::TopologyOk
+            );
+            writer.send(reply).unwrap();
+        },
+        Payload::Join { node_id } => {
+            let mut state = state.lock().unwrap();
+            let joined = message::intern(node_id);
+            if !state.all_node_ids.contains(&joined) {
+                info!(node = %joined, "node joined the cluster");
+                state.all_node_ids.push(joined);
+                state.pick_neighbors();
+                let now = state.clock.now();
+                if let Some(membership) = state.membership.as_mut() {
+                    membership.add(node_id, now);
+                }
+            }
+            // A round of gossip now, rather than at the next tick, to bring the new
+            // member up to date.
+            let now = state.clock.now().into_std();
+            for sync in syncs(&mut state, now) {
+                writer.send(sync).unwrap();
+            }
+            writer.send(envelope.reply_with(Some(message_id()), Payload::JoinOk)).unwrap();
+        },
+        Payload::Leave { node_id } => {
+            let mut state = state.lock().unwrap();
+            info!(node = node_id, "node left the cluster");
+            state.all_node_ids.retain(|member| **member != **node_id);
+            state.pick_neighbors();
+            if let Some(membership) = state.membership.as_mut() {
+                membership.remove(node_id);
+            }
+            writer.send(envelope.reply_with(Some(message_id()), Payload::LeaveOk)).unwrap();
+        },
+        Payload::Broadcast { message } => {
+            // Our neighbors hear about it on the next round of gossip, unless they already
+            // know it by then.
+            let mut state = state.lock().unwrap();
+            let messages = state.unseen([*message]);
+            let reply = match messages.is_empty() {
+                true => Payload::BroadcastOk,
+                false => match state.learn(Learned::Seen { messages }) {
+                    Ok(()) => Payload::BroadcastOk,
+                    Err(err) => {
+                        // Not acknowledged, so the client can try again.
+                        error!(error = ?err, "failed to journal a broadcast");
+                        Payload::Error { code: ErrorCode::TemporarilyUnavailable.code(), text: format!("couldn't journal the broadcast: {err}") }
+                    },
+                },
+            };
+            metrics::global().set("broadcast.seen", state.messages.len() as i64);
+            writer.send(envelope.reply_with(Some(message_id()), reply)).unwrap();
+        },
+        Payload::Read { chunk_size, ranges } => {
+            let state = state.lock().unwrap();
+            for read_ok in read_replies(&state.messages, *chunk_size, *ranges) {
+                writer.send(envelope.reply_with(Some(message_id()), read_ok)).unwrap();
+            }
+        },
+        Payload::Sync { messages: inbound, updates } => {
+            let mut state = state.lock().unwrap();
+            let now = state.clock.now();
+            if let Some(membership) = state.membership.as_mut() {
+                membership.apply(updates.clone(), now);
+            }
+            let messages = state.unseen(inbound.iter().copied());
+            if !messages.is_empty() {
+                if let Err(err) = state.learn(Learned::Seen { messages }) {
+                    // Left unacknowledged, so the neighbor syncs them again.
+                    error!(error = ?err, "failed to journal a sync");
+                    return;
+                }
+            }
+            // No use syncing them back to whoever sent them.
+            if state.nodes.contains_key(&envelope.source) {
+                if let Err(err) = state.learn(Learned::Known { node: envelope.source.to_string(), messages: inbound.clone() }) {
+                    error!(error = ?err, "failed to journal what a neighbor has");
+                }
+            }
+            metrics::global().set("broadcast.seen", state.messages.len() as i64);
+            let reply = envelope.reply_with(Some(message_id()), Payload::SyncOk { messages: inbound.clone() });
+            writer.send(reply).unwrap();
+        },
+        Payload::SyncOk { messages: acknowledged_messages } => {
+            // Update our knowledge that this specific node
+            // has acknowledged our messages.
+            let mut state = state.lock().unwrap();
+            let neighbor = envelope.source.clone();
+            // It may have left the cluster since.
+            if !state.nodes.contains_key(&neighbor) {
+                return;
+            }
+            // At worst, it's synced these again after a restart.
+            if let Err(err) = state.learn(Learned::Known { node: neighbor.to_string(), messages: acknowledged_messages.clone() }) {
+                error!(error = ?err, "failed to journal what a neighbor has");
+            }
+            let now = state.clock.now().into_std();
+            let Some(node) = state.nodes.get_mut(&neighbor) else {
+                return;
+            };
+            let was_cut_off = envelope.body.in_reply_to.is_some_and(|msg_id| node.acknowledged(msg_id, now));
+            debug!(node = %neighbor, "cleared buffered messages for node");
+            // Back from the other side of a partition: catch it up on everything now, rather
+            // than a batch a tick.
+            if was_cut_off && state.partitioned {
+                if let Some(sync) = catch_up(&mut state, &neighbor, now) {
+                    writer.send(sync).unwrap();
+                }
+                state.detect_partition();
+            }
+        },
+        Payload::Digest { ranges } => {
+            let state = state.lock().unwrap();
+            let theirs = from_runs(ranges);
+            let messages = (&state.messages - &theirs).iter().map(|message| message as usize).collect();
+            let wants = runs(&(&theirs - &state.messages));
+            writer.send(envelope.reply_with(Some(message_id()), Payload::DigestOk { messages, wants })).unwrap();
+        },
+        Payload::DigestOk { messages: inbound, wants } => {
+            let mut state = state.lock().unwrap();
+            let messages = state.unseen(inbound.iter().copied());
+            if !messages.is_empty() {
+                if let Err(err) = state.learn(Learned::Seen { messages }) {
+                    error!(error = ?err, "failed to journal what a neighbor sent");
+                }
+            }
+            let wanted: Vec<usize> = (&from_runs(wants) & &state.messages).iter().map(|message| message as usize).collect();
+            if !wanted.is_empty() {
+                metrics::global().increment("broadcast.pushes_sent");
+                let push = Envelope::new(&state.my_id, &envelope.source, Body { msg_id: Some(message_id()), in_reply_to: None, message: Payload::Push { messages: wanted } });
+                writer.send(push).unwrap();
+            }
+            metrics::global().set("broadcast.seen", state.messages.len() as i64);
+        },
+        Payload::Push { messages: inbound } => {
+            let mut state = state.lock().unwrap();
+            let messages = state.unseen(inbound.iter().copied());
+            if !messages.is_empty() {
+                if let Err(err) = state.learn(Learned::Seen { messages }) {
+                    error!(error = ?err, "failed to journal what a neighbor pushed");
+                }
+            }
+            metrics::global().set("broadcast.seen", state.messages.len() as i64);
+        },
+        Payload::Ping { updates } => {
+            if let Some(ack) = membership::on_ping(&mut *state.lock().unwrap(), &envelope, updates) {
+                writer.send(ack).unwrap();
+            }
+        },
+        Payload::PingReq { target, updates } => {
+            if let Some(ping) = membership::on_ping_req(&mut *state.lock().unwrap(), &envelope, target, updates) {
+                writer.send(ping).unwrap();
+            }
+        },
+        Payload::Ack { target, updates } => {
+            for ack in membership::on_ack(&mut *state.lock().unwrap(), target, updates) {
+                writer.send(ack).unwrap();
+            }
+        },
+
+        _ => {}
+    }
+}
+
+
+/// The syncs one round of gossip sends at `now`: each neighbor that's behind (as many of
+/// them as the fanout allows) gets what it hasn't acknowledged yet (as much of it as the
+/// batch size allows), unless it already has as many syncs to acknowledge as the window
+/// allows. Those wait for an ack (or a timeout), rather than have their whole backlog
+/// sent again every round while, say, they're cut off by a partition. Neighbors whose
+/// syncs keep timing out are taken for cut off, and enough of them at once for a
+/// partition (see [`State::is_partitioned`]).
+pub fn syncs(state: &mut State, now: Instant) -> Vec<Envelope<Payload>> {
+    let window = tuning::global().window();
+    let State { my_id, all_node_ids, neighbors, nodes, messages, membership, .. } = state;
+    let mut held_back = 0;
+    let mut behind: Vec<(&NodeId, &mut RemoteNode, RoaringTreemap)> = vec![];
+    let mut backlog = 0;
+    for (neighbor, node) in nodes.iter_mut().filter(|(node, _)| neighbors.contains(node)) {
+        if membership.as_ref().is_some_and(|membership| !membership.is_live(neighbor)) {
+            metrics::global().increment("broadcast.skipped_dead");
+            continue;
+        }
+        let unacknowledged = node.unacknowledged(messages);
+        if unacknowledged.is_empty() {
+            continue;
+        }
+        backlog += unacknowledged.len();
+        if node.has_room(window, now) {
+            behind.push((neighbor, node, unacknowledged));
+        } else {
+            held_back += 1;
+        }
+    }
+    metrics::global().set("broadcast.unacknowledged", backlog as i64);
+    metrics::global().set("broadcast.held_back", held_back);
+    let behind = gossip::choose(behind, all_node_ids.len().saturating_sub(1));
+    let batch_size = tuning::global().batch_size().unwrap_or(usize::MAX);
+    let syncs = behind
+    .into_iter()
+    .map(|(neighbor, node, unacknowledged)| {
+        let updates = membership.as_mut().map(|membership| membership.piggyback(neighbor)).unwrap_or_default();
+        sync(my_id, neighbor, node, unacknowledged.iter().take(batch_size), updates, now)
+    })
+    .collect();
+    state.detect_partition();
+    syncs
+}
+
+/// A round of gossip at `now`: [`syncs`], unless the node spreads [`Rumors`], in which
+/// case it's [`rumors`], with `syncs` only every so often for the neighbors they miss, or
+/// swaps digests, in which case it's a [`digest`].
+pub fn gossip(state: &mut State, now: Instant) -> Vec<Envelope<Payload>> {
+    if state.push_pull {
+        return digest(state).into_iter().collect();
+    }
+    let Some(config) = state.rumors else {
+        return syncs(state, now);
+    };
+    state.rounds += 1;
+    let mut sends = rumors(state, config);
+    if config.anti_entropy_every > 0 && state.rounds % config.anti_entropy_every == 0 {
+        sends.extend(syncs(state, now));
+    }
+    sends
+}
+
+/// A round of push-pull: a digest of every message seen, to one neighbor picked at
+/// random. Its `digest_ok` brings back what the node is missing and asks for what the
+/// neighbor is, so both catch up on each other in the same round.
+pub fn digest(state: &mut State) -> Option<Envelope<Payload>> {
+    let State { my_id, neighbors, membership, messages, .. } = state;
+    let live: Vec<&NodeId> = neighbors.iter().filter(|neighbor| membership.as_ref().map_or(true, |membership| membership.is_live(neighbor))).collect();
+    let neighbor = *live.choose(&mut rand::thread_rng())?;
+    metrics::global().increment("broadcast.digests_sent");
+    Some(Envelope::new(my_id, neighbor, Body { msg_id: Some(message_id()), in_reply_to: None, message: Payload::Digest { ranges: runs(messages) } }))
+}
+
+/// A round of rumors: every message still being pushed goes to `peers` neighbors picked
+/// at random (those not known to have it already), and is pushed one round less.
+pub fn rumors(state: &mut State, config: Rumors) -> Vec<Envelope<Payload>> {
+    let State { my_id, neighbors, nodes, membership, hot, .. } = state;
+    if hot.is_empty() {
+        return vec![];
+    }
+    let rumors: RoaringTreemap = hot.keys().copied().collect();
+    hot.retain(|_, rounds| {
+        *rounds = rounds.saturating_sub(1);
+        *rounds > 0
+    });
+    let live: Vec<&NodeId> = neighbors.iter().filter(|neighbor| membership.as_ref().map_or(true, |membership| membership.is_live(neighbor))).collect();
+    let sends: Vec<Envelope<Payload>> =
+        live
+        .choose_multiple(&mut rand::thread_rng(), config.peers)
+        .filter_map(|&neighbor| {
+            let unknown = nodes.get(neighbor).map_or_else(|| rumors.clone(), |node| node.unacknowledged(&rumors));
+            if unknown.is_empty() {
+                return None;
+            }
+            let updates = membership.as_mut().map(|membership| membership.piggyback(neighbor)).unwrap_or_default();
+            let messages = unknown.iter().map(|message| message as usize).collect();
+            Some(Envelope::new(my_id, neighbor, Body { msg_id: Some(message_id()), in_reply_to: None, message: Payload::Sync { messages, updates } }))
+        })
+        .collect();
+    metrics::global().add("broadcast.rumors_sent", sends.len() as u64);
+    sends
+}
+
+/// A sync of everything `neighbor` hasn't acknowledged yet, however many messages that
+/// is, and whatever the window: for a neighbor just back from a partition.
+pub fn catch_up(state: &mut State, neighbor: &NodeId, now: Instant) -> Option<Envelope<Payload>> {
+    let State { my_id, nodes, messages, membership, .. } = state;
+    let node = nodes.get_mut(neighbor)?;
+    let unacknowledged = node.unacknowledged(messages);
+    if unacknowledged.is_empty() {
+        return None;
+    }
+    info!(node = %neighbor, messages = unacknowledged.len(), "catching up a neighbor after a partition");
+    metrics::global().increment("broadcast.catch_ups");
+    let updates = membership.as_mut().map(|membership| membership.piggyback(neighbor)).unwrap_or_default();
+    Some(sync(my_id, neighbor, node, unacknowledged.iter(), updates, now))
+}
+
+/// A sync of `messages` to `neighbor`, noted as in flight since `now`.
+fn sync(my_id: &NodeId, neighbor: &NodeId, node: &mut RemoteNode, messages: impl Iterator<Item = u64>, updates: Vec<Update>, now: Instant) -> Envelope<Payload> {
+    let envelope = Envelope::new(
+        my_id, 
+        neighbor, 
+        Body { 
+            msg_id: Some(message_id()), 
+            in_reply_to: None, 
+            message: Payload::Sync { messages: messages.map(|message| message as usize).collect(), updates }
+        }
+    );
+    node.in_flight.insert(envelope.msg_id().unwrap(), now);
+    envelope
+}
+
+
+/// Send a round of [`gossip`] every tick, as a `gossip` job on `scheduler`.
+pub fn gossip_every_so_often(
+    scheduler: &Scheduler,
+    state: Arc<Mutex<State>>,
+    writer: UnboundedSender<Envelope<Payload>>
+) -> JobHandle {
+    scheduler.spawn(Job::new("gossip", Period::TickRate).jitter(DEFAULT_JITTER), move |now| {
+        // A span per round, for the syncs it sends to be traced back to.
+        let _round = info_span!("gossip round").entered();
+        let syncs = gossip(&mut state.lock().unwrap(), now.into_std());
+        for envelope in syncs {
+            metrics::global().increment("broadcast.syncs_sent");
+            writer.send(envelope).unwrap();
+        }
+    })
+}
+
+/// Pick neighbors again every `every`, as a `neighbors` job on `scheduler`, for a
+/// `nearest` overlay to move towards the quickest ones.
+pub fn reevaluate_neighbors_every_so_often(scheduler: &Scheduler, state: Arc<Mutex<State>>, every: Duration) -> JobHandle {
+    scheduler.spawn(Job::new("neighbors", Period::Every(every)).jitter(DEFAULT_JITTER), move |_| {
+        state.lock().unwrap().reevaluate_neighbors();
+    })
+}
+
+pub async fn server(opts: Opts) {
+    debug!(opts = ?opts, "starting server...");
+    let mut state = State::new(opts.overlay());
+    if opts.common.swim {
+        state = state.with_membership(membership::Config::default());
+    }
+    if let Some(rumors) = opts.rumors() {
+        state = state.with_rumors(rumors);
+    }
+    if opts.gossip == Gossip::PushPull {
+        state = state.with_push_pull();
+    }
+    if let Some(dir) = &opts.data_dir {
+        state = state.with_storage(dir).unwrap_or_else(|err| panic!("can't recover what the node knew from {}: {err}", dir.display()));
+    }
+    let state = Arc::new(Mutex::new(state));
+    let (writer, background, mut reader, _) = io_channel_with_background::<Envelope<Payload>>();
+
+    let scheduler = Scheduler::new();
+    gossip_every_so_often(&scheduler, state.clone(), background.clone());
+    if opts.common.swim {
+        membership::probe_every_so_often(&scheduler, state.clone(), background);
+    }
+    if let Overlay::Nearest { .. } = opts.overlay() {
+        reevaluate_neighbors_every_so_often(&scheduler, state.clone(), REEVALUATE_NEIGHBORS_EVERY);
+    }
+
+    while let Some(envelope) = reader.recv().await {
+        let handler = format!("handler.{}", metrics::message_type(&envelope.body.message));
+        let span = io::handler_span(&envelope);
+        metrics::timed(&handler, handle_envelope(state.clone(), envelope, writer.clone())).instrument(span).await;
+    }
+}
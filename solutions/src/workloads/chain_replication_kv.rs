@@ -0,0 +1,630 @@
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use crate::{backoff::{Backoff, Jitter}, cli::CommonOpts, failure_detector::{heartbeat, Config as DetectorConfig, FailureDetector, Status}, io::{self, io_channel}, message::{self, Body, Envelope, ErrorCode, NodeId}, metrics, rpc::RpcClient, scheduler::{Job, JobHandle, Period, Scheduler}, task};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, info, warn, Instrument};
+use std::{collections::{BTreeMap, HashMap, HashSet}, sync::atomic::{AtomicUsize, Ordering}, time::Duration};
+use std::sync::{Arc, Mutex};
+use clap::{Parser, ValueEnum};
+
+
+/// Which nodes answer reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Reads {
+    /// Any of them (CRAQ): a node answers from its own copy when it's clean, and asks the
+    /// tail which version is committed when it isn't.
+    Any,
+    /// Only the tail, as in plain chain replication: every other node passes reads on.
+    Tail,
+}
+
+
+#[derive(Debug, Clone, Parser)]
+#[clap(author, version)]
+pub struct Opts {
+    #[clap(long, value_enum, default_value_t = Reads::Any, help = "Which nodes answer reads.", env = "READS")]
+    pub reads: Reads,
+    #[clap(long, default_value_t = 1000, help = "Number of milliseconds to wait on another node before giving up on a request.", env = "RPC_TIMEOUT_MS")]
+    pub rpc_timeout_ms: u64,
+    #[clap(flatten)]
+    pub common: CommonOpts,
+}
+
+
+static MSG_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Payload {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        create_if_not_exists: Option<bool>,
+    },
+    CasOk,
+    Error {
+        code: usize,
+        text: String,
+    },
+
+    // Between nodes.
+    /// A write on its way down the chain, from the head: `key` is `value` as of
+    /// `version`. Answered once the tail has it, so every node it's answered by can take
+    /// the version to be clean.
+    Propagate {
+        key: Value,
+        version: u64,
+        value: Value,
+    },
+    PropagateOk,
+    /// To the tail, from a node with a dirty copy of `key`: which version is committed.
+    Version {
+        key: Value,
+    },
+    VersionOk {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u64>,
+    },
+    /// To tell whether nodes are up.
+    Ping,
+    PingOk,
+    /// To a node the others have taken out of the chain, and in answer to anything it
+    /// sends them after that: it's out for good, and stops answering reads and writes.
+    Removed,
+}
+
+fn message_id() -> usize {
+    MSG_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+fn error(code: ErrorCode, text: String) -> Payload {
+    Payload::Error { code: code.code(), text }
+}
+
+
+/// How many times a node sends a write down the chain (to whoever's after it at the
+/// time) before it gives up on it.
+const PROPAGATE_ATTEMPTS: usize = 10;
+
+/// How often writes a node gave up on sending down the chain are sent on again.
+const RETRY_STALLED_EVERY: Duration = Duration::from_secs(1);
+
+
+/// A key's versions on one node: the newest one it knows is committed, and any newer
+/// ones on their way down the chain that the tail hasn't acknowledged yet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Versions {
+    pub clean: Option<(u64, Value)>,
+    pub dirty: BTreeMap<u64, Value>,
+}
+
+impl Versions {
+    /// The newest version there is, committed or not, which writes at the head go on
+    /// top of.
+    pub fn latest(&self) -> Option<(u64, &Value)> {
+        self.dirty.iter().next_back().map(|(&version, value)| (version, value)).or(self.clean.as_ref().map(|(version, value)| (*version, value)))
+    }
+
+    /// The value as of `version`, or of a newer committed one if this node has moved
+    /// on past it already (which is just as committed).
+    pub fn at(&self, version: u64) -> Option<&Value> {
+        match &self.clean {
+            Some((clean, value)) if *clean >= version => Some(value),
+            _ => self.dirty.get(&version),
+        }
+    }
+}
+
+
+/// What a node can tell about a key by itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Local {
+    /// Nothing's been written to it, as far as the node knows.
+    Missing,
+    /// The newest version it has is committed: this.
+    Clean(Value),
+    /// It has versions the tail hasn't acknowledged yet, so only the tail can say which
+    /// one counts.
+    Dirty,
+}
+
+
+#[derive(Debug, Default)]
+pub struct State {
+    my_id: NodeId,
+    /// Every node, in the order the chain runs through them: the head first, the tail
+    /// last.
+    chain: Vec<NodeId>,
+    /// Nodes taken for dead, which the chain skips. They stay out of it for good: one
+    /// that comes back may have missed writes.
+    down: HashSet<NodeId>,
+    /// Keyed by each key's JSON.
+    keys: HashMap<String, Versions>,
+    /// Whether the others have taken this node out of the chain, as far as it's heard.
+    removed: bool,
+    /// Versions (by their key's JSON) this node gave up on sending down the chain, to
+    /// be sent on again by [`retry_stalled_every_so_often`].
+    stalled: HashSet<(String, u64)>,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn init(&mut self, node_id: &str, node_ids: &[String]) {
+        self.my_id = message::intern(node_id);
+        self.chain = node_ids.iter().map(|node| message::intern(node)).collect();
+    }
+
+    /// The nodes the chain runs through as things stand, head first.
+    pub fn chain(&self) -> Vec<NodeId> {
+        self.chain.iter().filter(|node| !self.down.contains(*node)).cloned().collect()
+    }
+
+    pub fn head(&self) -> Option<NodeId> {
+        self.chain().first().cloned()
+    }
+
+    pub fn tail(&self) -> Option<NodeId> {
+        self.chain().last().cloned()
+    }
+
+    pub fn is_head(&self) -> bool {
+        self.head().map_or(true, |head| head == self.my_id)
+    }
+
+    pub fn is_tail(&self) -> bool {
+        self.tail().map_or(true, |tail| tail == self.my_id)
+    }
+
+    /// Who writes go to after this node: `None` if it's the tail.
+    pub fn successor(&self) -> Option<NodeId> {
+        let chain = self.chain();
+        let at = chain.iter().position(|node| *node == self.my_id)?;
+        chain.get(at + 1).cloned()
+    }
+
+    /// Take `peer` to be down, as the failure detector says: whether that changed the
+    /// chain. Once down, a node stays down.
+    pub fn set_down(&mut self, peer: &str) -> bool {
+        self.chain.iter().any(|node| **node == *peer) && self.down.insert(message::intern(peer))
+    }
+
+    /// Whether `peer` has been taken for dead.
+    pub fn is_down(&self, peer: &str) -> bool {
+        self.down.contains(peer)
+    }
+
+    /// The others have taken this node out of the chain: it may have missed writes
+    /// since, so it answers no more reads or writes itself.
+    pub fn remove(&mut self) {
+        if !self.removed {
+            warn!("taken out of the chain");
+            metrics::global().increment("craq.removed");
+        }
+        self.removed = true;
+    }
+
+    pub fn is_removed(&self) -> bool {
+        self.removed
+    }
+
+    /// Remember that sending `key`'s `version` down the chain was given up on.
+    pub fn stall(&mut self, key: &Value, version: u64) {
+        self.stalled.insert((key.to_string(), version));
+    }
+
+    /// The versions given up on since the last time, to send on again.
+    pub fn take_stalled(&mut self) -> Vec<(Value, u64)> {
+        self.stalled.drain().map(|(key, version)| (serde_json::from_str(&key).unwrap_or(Value::Null), version)).collect()
+    }
+
+    pub fn versions(&self, key: &Value) -> Option<&Versions> {
+        self.keys.get(&key.to_string())
+    }
+
+    /// What this node can tell about `key` by itself.
+    pub fn local(&self, key: &Value) -> Local {
+        match self.versions(key) {
+            None => Local::Missing,
+            Some(versions) if !versions.dirty.is_empty() => Local::Dirty,
+            Some(Versions { clean: Some((_, value)), .. }) => Local::Clean(value.clone()),
+            Some(_) => Local::Missing,
+        }
+    }
+
+    /// The committed version of `key`, as far as this node knows: what the tail answers
+    /// [`Version`](Payload::Version) with.
+    pub fn clean_version(&self, key: &Value) -> Option<u64> {
+        self.versions(key)?.clean.as_ref().map(|(version, _)| *version)
+    }
+
+    /// At the head: take a client's `write` or `cas`, staging the new version of its key
+    /// on top of the newest one there is. The key and version to send down the chain,
+    /// or the error to answer with.
+    pub fn accept(&mut self, request: &Payload) -> Result<(Value, u64), Payload> {
+        let (key, value) = match request {
+            Payload::Write { key, value } => (key, value),
+            Payload::Cas { key, from, to, create_if_not_exists } => {
+                match self.versions(key).and_then(Versions::latest) {
+                    None if *create_if_not_exists != Some(true) => return Err(error(ErrorCode::KeyDoesNotExist, format!("no key {key}"))),
+                    Some((_, current)) if current != from => return Err(error(ErrorCode::PreconditionFailed, format!("{key} is {current}, not {from}"))),
+                    _ => {},
+                }
+                (key, to)
+            },
+            other => return Err(error(ErrorCode::NotSupported, format!("the head doesn't take {other:?}"))),
+        };
+        let version = self.versions(key).and_then(Versions::latest).map_or(1, |(version, _)| version + 1);
+        self.stage(key, version, value.clone());
+        Ok((key.clone(), version))
+    }
+
+    /// Keep `value` as `key`'s `version`, dirty until [`commit`](Self::commit)ted. A
+    /// version that's committed already is left alone.
+    pub fn stage(&mut self, key: &Value, version: u64, value: Value) {
+        let versions = self.keys.entry(key.to_string()).or_default();
+        if versions.clean.as_ref().is_some_and(|(clean, _)| *clean >= version) {
+            return;
+        }
+        versions.dirty.insert(version, value);
+    }
+
+    /// The tail has `key`'s `version`: it's clean, and any older versions can go.
+    pub fn commit(&mut self, key: &Value, version: u64) {
+        let Some(versions) = self.keys.get_mut(&key.to_string()) else {
+            return;
+        };
+        let Some(value) = versions.dirty.remove(&version) else {
+            return;
+        };
+        versions.dirty.retain(|&dirty, _| dirty > version);
+        if versions.clean.as_ref().map_or(true, |(clean, _)| *clean < version) {
+            versions.clean = Some((version, value));
+        }
+    }
+
+    /// Every version this node has that isn't committed yet, oldest first by key: what
+    /// it sends on again when whoever's after it changes.
+    pub fn dirty(&self) -> Vec<(Value, u64, Value)> {
+        self.keys
+        .iter()
+        .flat_map(|(key, versions)| {
+            let key: Value = serde_json::from_str(key).unwrap_or(Value::Null);
+            versions.dirty.iter().map(move |(&version, value)| (key.clone(), version, value.clone()))
+        })
+        .collect()
+    }
+
+    /// Commit every version this node has: what a node does on becoming the tail, since
+    /// whatever's reached the tail is committed.
+    pub fn commit_all(&mut self) {
+        for (key, version, _) in self.dirty() {
+            self.commit(&key, version);
+        }
+    }
+}
+
+
+/// What a node needs to handle a request: handlers wait on other nodes, so they're
+/// spawned rather than run inline.
+#[derive(Debug, Clone)]
+pub struct Shared {
+    state: Arc<Mutex<State>>,
+    rpc: RpcClient<Payload>,
+    timeout: Duration,
+    reads: Reads,
+}
+
+impl Shared {
+    pub fn new(state: Arc<Mutex<State>>, rpc: RpcClient<Payload>, timeout: Duration) -> Self {
+        Self { state, rpc, timeout, reads: Reads::Any }
+    }
+
+    pub fn with_reads(mut self, reads: Reads) -> Self {
+        self.reads = reads;
+        self
+    }
+
+    pub fn state(&self) -> &Arc<Mutex<State>> {
+        &self.state
+    }
+}
+
+
+pub async fn handle_envelope(shared: Shared, envelope: Envelope<Payload>, writer: UnboundedSender<Envelope<Payload>>) {
+    let (from_removed, removed) = {
+        let state = shared.state.lock().unwrap();
+        (state.is_down(&envelope.source), state.is_removed())
+    };
+    let reply = match &envelope.body.message {
+        Payload::Init { node_id, node_ids } => {
+            shared.state.lock().unwrap().init(node_id, node_ids);
+            Payload::InitOk
+        },
+        Payload::Ping => Payload::PingOk,
+        Payload::Removed => {
+            shared.state.lock().unwrap().remove();
+            return;
+        },
+        // It missed whatever happened since it was taken for dead.
+        Payload::Read { .. } | Payload::Write { .. } | Payload::Cas { .. } | Payload::Propagate { .. } | Payload::Version { .. } if from_removed => Payload::Removed,
+        Payload::Read { .. } | Payload::Write { .. } | Payload::Cas { .. } if removed => error(ErrorCode::TemporarilyUnavailable, "this node has been taken out of the chain".to_owned()),
+        Payload::Read { key } => read(&shared, key).await,
+        Payload::Write { .. } | Payload::Cas { .. } => write(&shared, &envelope).await,
+        Payload::Propagate { key, version, value } => {
+            shared.state.lock().unwrap().stage(key, *version, value.clone());
+            if !pass_on(&shared, key, *version).await {
+                return;
+            }
+            Payload::PropagateOk
+        },
+        Payload::Version { key } => Payload::VersionOk { version: shared.state.lock().unwrap().clean_version(key) },
+        _ => return,
+    };
+    writer.send(envelope.reply_with(Some(message_id()), reply)).unwrap();
+}
+
+
+/// A client's `write` or `cas`. The head stages it and sends it down the chain,
+/// answering once the tail has it. Anyone else passes it on to the head, unless it came
+/// from another node, which took this one for the head: it's handled here rather than
+/// passed around again.
+async fn write(shared: &Shared, envelope: &Envelope<Payload>) -> Payload {
+    let head = {
+        let state = shared.state.lock().unwrap();
+        let from_peer = state.chain.contains(&envelope.source);
+        state.head().filter(|_| !state.is_head() && !from_peer)
+    };
+    if let Some(head) = head {
+        metrics::global().increment("craq.writes_forwarded");
+        return match shared.rpc.call(&head, envelope.body.message.clone(), shared.timeout).await {
+            Ok(reply) => unless_removed(shared, reply.body.message),
+            // It may have been applied all the same.
+            Err(err) => error(ErrorCode::Timeout, format!("couldn't reach {head}, the head: {err}")),
+        };
+    }
+    let accepted = shared.state.lock().unwrap().accept(&envelope.body.message);
+    let (key, version) = match accepted {
+        Ok(accepted) => accepted,
+        Err(reply) => return reply,
+    };
+    if !pass_on(shared, &key, version).await {
+        return error(ErrorCode::Timeout, format!("couldn't get version {version} of {key} down the chain; it may be applied all the same"));
+    }
+    match envelope.body.message {
+        Payload::Cas { .. } => Payload::CasOk,
+        _ => Payload::WriteOk,
+    }
+}
+
+/// Send `key`'s `version` (staged here already) on to whoever's after this node in the
+/// chain, and commit it once they've acknowledged it; the tail commits it straight
+/// away. Whoever's next is asked again (with backoff) until they answer, for up to
+/// [`PROPAGATE_ATTEMPTS`] tries, and if they're taken for dead in the meantime, it
+/// goes to whoever's after them instead. Whether it was committed.
+async fn pass_on(shared: &Shared, key: &Value, version: u64) -> bool {
+    let mut backoff = Backoff::new(Duration::from_millis(50)).max(shared.timeout).jitter(Jitter::Full);
+    for _ in 0..PROPAGATE_ATTEMPTS {
+        let (successor, value) = {
+            let state = shared.state.lock().unwrap();
+            let Some(value) = state.versions(key).and_then(|versions| versions.at(version)).cloned() else {
+                // Committed and superseded already.
+                return true;
+            };
+            (state.successor(), value)
+        };
+        let Some(successor) = successor else {
+            shared.state.lock().unwrap().commit(key, version);
+            return true;
+        };
+        let propagate = Payload::Propagate { key: key.clone(), version, value };
+        match shared.rpc.call(&successor, propagate, shared.timeout).await.map(|reply| reply.body.message) {
+            Ok(Payload::PropagateOk) => {
+                shared.state.lock().unwrap().commit(key, version);
+                return true;
+            },
+            Ok(Payload::Removed) => {
+                shared.state.lock().unwrap().remove();
+                return false;
+            },
+            _ => {},
+        }
+        metrics::global().increment("craq.propagate_retries");
+        backoff.wait().await;
+    }
+    warn!(%key, version, "giving up on sending a write down the chain for now");
+    metrics::global().increment("craq.propagate_abandoned");
+    shared.state.lock().unwrap().stall(key, version);
+    false
+}
+
+/// Every `period`, send each version [`pass_on`] gave up on down the chain again
+/// (`craq.stalled_retries`), so it's committed once whoever's after this node answers
+/// rather than left dirty for good.
+pub fn retry_stalled_every_so_often(scheduler: &Scheduler, shared: Shared, period: Duration) -> JobHandle {
+    scheduler.spawn(Job::new("craq.retry_stalled", Period::Every(period)), move |_| {
+        let stalled = {
+            let mut state = shared.state.lock().unwrap();
+            if state.is_removed() {
+                return;
+            }
+            state.take_stalled()
+        };
+        for (key, version) in stalled {
+            metrics::global().increment("craq.stalled_retries");
+            let shared = shared.clone();
+            task::spawn("craq.propagate", async move { pass_on(&shared, &key, version).await; });
+        }
+    })
+}
+
+/// A client's `read`. With `--reads any`, a node whose copy of the key is clean answers
+/// from it. One with a dirty copy asks the tail which version's committed (counted in
+/// `craq.dirty_reads`), and answers with that one, which it's bound to have: writes
+/// reach the tail through every node before it. With `--reads tail`, only the tail
+/// answers reads.
+async fn read(shared: &Shared, key: &Value) -> Payload {
+    let (local, tail) = {
+        let state = shared.state.lock().unwrap();
+        (state.local(key), state.tail().filter(|_| !state.is_tail()))
+    };
+    let Some(tail) = tail else {
+        return answer(local, key);
+    };
+    match (shared.reads, local) {
+        (Reads::Any, Local::Dirty) => {},
+        (Reads::Any, local) => {
+            metrics::global().increment("craq.clean_reads");
+            return answer(local, key);
+        },
+        (Reads::Tail, _) => {
+            metrics::global().increment("craq.reads_forwarded");
+            return match shared.rpc.call(&tail, Payload::Read { key: key.clone() }, shared.timeout).await {
+                Ok(reply) => unless_removed(shared, reply.body.message),
+                Err(err) => error(ErrorCode::TemporarilyUnavailable, format!("couldn't reach {tail}, the tail: {err}")),
+            };
+        },
+    }
+    metrics::global().increment("craq.dirty_reads");
+    let version = match shared.rpc.call(&tail, Payload::Version { key: key.clone() }, shared.timeout).await {
+        Ok(Envelope { body: Body { message: Payload::VersionOk { version }, .. }, .. }) => version,
+        Ok(Envelope { body: Body { message: message @ Payload::Removed, .. }, .. }) => return unless_removed(shared, message),
+        Ok(other) => return error(ErrorCode::Crash, format!("unexpected reply from the tail: {:?}", other.body.message)),
+        Err(err) => return error(ErrorCode::TemporarilyUnavailable, format!("couldn't ask {tail}, the tail, about {key}: {err}")),
+    };
+    let Some(version) = version else {
+        return error(ErrorCode::KeyDoesNotExist, format!("no key {key}"));
+    };
+    match shared.state.lock().unwrap().versions(key).and_then(|versions| versions.at(version)) {
+        Some(value) => Payload::ReadOk { value: value.clone() },
+        // Only after the chain's changed under a write.
+        None => error(ErrorCode::TemporarilyUnavailable, format!("don't have version {version} of {key} the tail has")),
+    }
+}
+
+/// `reply`, unless it's another node saying this one's been taken out of the chain: then
+/// it's out, and the client's told to go elsewhere.
+fn unless_removed(shared: &Shared, reply: Payload) -> Payload {
+    if !matches!(reply, Payload::Removed) {
+        return reply;
+    }
+    shared.state.lock().unwrap().remove();
+    error(ErrorCode::TemporarilyUnavailable, "this node has been taken out of the chain".to_owned())
+}
+
+fn answer(local: Local, key: &Value) -> Payload {
+    match local {
+        Local::Clean(value) => Payload::ReadOk { value },
+        Local::Missing => error(ErrorCode::KeyDoesNotExist, format!("no key {key}")),
+        Local::Dirty => error(ErrorCode::Crash, format!("the tail has a dirty copy of {key}")),
+    }
+}
+
+
+/// `peer` has been taken for dead: drop it from the chain, and tell it so, in case it's
+/// only slow (see [`Payload::Removed`]). If that changes who's after this node, send
+/// them everything it has that isn't committed yet, since they may not have it; a node
+/// that becomes the tail commits all of it instead. `craq.reconfigurations` counts the
+/// changes.
+pub fn node_down(shared: &Shared, peer: &str) {
+    let dirty = {
+        let mut state = shared.state.lock().unwrap();
+        let successor = state.successor();
+        if !state.set_down(peer) {
+            return;
+        }
+        info!(peer, chain = ?state.chain(), "node down, chain reconfigured");
+        shared.rpc.send(peer, Payload::Removed).ok();
+        metrics::global().increment("craq.reconfigurations");
+        if state.is_tail() {
+            state.commit_all();
+            return;
+        }
+        if state.successor() == successor {
+            return;
+        }
+        state.dirty()
+    };
+    debug!(versions = dirty.len(), "sending dirty versions to the new successor");
+    for (key, version, _) in dirty {
+        let shared = shared.clone();
+        task::spawn("craq.propagate", async move { pass_on(&shared, &key, version).await; });
+    }
+}
+
+/// Keep the chain up to date as nodes are taken for dead (see [`node_down`]), pinging
+/// them as need be, by `scheduler`'s clock.
+pub fn watch_chain(scheduler: &Scheduler, shared: Shared, writer: UnboundedSender<Envelope<Payload>>, node_id: &str, node_ids: &[String]) -> Arc<Mutex<FailureDetector>> {
+    let peers = node_ids.iter().filter(|&other| other != node_id).map(|other| message::intern(other));
+    let detector = Arc::new(Mutex::new(FailureDetector::new(DetectorConfig::default(), peers, scheduler.clock().now())));
+    let mut events = detector.lock().unwrap().subscribe();
+    task::spawn("craq.chain", async move {
+        while let Ok(event) = events.recv().await {
+            if event.to == Status::Down {
+                node_down(&shared, &event.peer);
+            }
+        }
+    });
+    let node_id = node_id.to_owned();
+    heartbeat(scheduler, detector.clone(), move |peer| {
+        let ping = Body { msg_id: Some(message_id()), in_reply_to: None, message: Payload::Ping };
+        writer.send(Envelope::new(&node_id, peer, ping)).ok();
+    });
+    detector
+}
+
+
+pub async fn server(opts: Opts) {
+    debug!(opts = ?opts, "starting server...");
+    let state = Arc::new(Mutex::new(State::new()));
+    let (writer, mut reader, _) = io_channel::<Envelope<Payload>>();
+    let rpc = RpcClient::new(writer.clone(), message_id);
+    let shared = Shared::new(state, rpc.clone(), Duration::from_millis(opts.rpc_timeout_ms)).with_reads(opts.reads);
+    // Who's down decides the chain; set up once the peers are known.
+    let mut detector: Option<Arc<Mutex<FailureDetector>>> = None;
+    let scheduler = Scheduler::new();
+
+    while let Some(envelope) = reader.recv().await {
+        if let Some(detector) = &detector {
+            detector.lock().unwrap().heard_from(&envelope.source, scheduler.clock().now());
+        }
+        let Some(envelope) = rpc.try_complete(envelope) else {
+            continue;
+        };
+        if let Payload::Init { node_id, node_ids } = &envelope.body.message {
+            rpc.set_node_id(node_id);
+            if node_ids.len() > 1 {
+                detector = Some(watch_chain(&scheduler, shared.clone(), writer.clone(), node_id, node_ids));
+                retry_stalled_every_so_often(&scheduler, shared.clone(), RETRY_STALLED_EVERY);
+            }
+        }
+        // Writes and some reads wait on other nodes, whose replies only arrive through
+        // this loop, so handlers can't be awaited inline.
+        let handler = format!("handler.{}", metrics::message_type(&envelope.body.message));
+        let span = io::handler_span(&envelope);
+        let handled = handle_envelope(shared.clone(), envelope, writer.clone()).instrument(span);
+        let name = handler.clone();
+        task::spawn(&name, async move { metrics::timed(&handler, handled).await });
+    }
+}
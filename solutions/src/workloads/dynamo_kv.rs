@@ -0,0 +1,607 @@
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use crate::{anti_entropy::{self, Replica}, cli::CommonOpts, clock::{self, Clock, WallClock}, failure_detector::{heartbeat, Config as DetectorConfig, FailureDetector, Status}, hash_ring::HashRing, io::{self, io_channel}, message::{self, Body, Envelope, ErrorCode, NodeId}, metrics, rpc::RpcClient, scheduler::{Job, JobHandle, Period, Scheduler}, task, vector_clock::VectorClock};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, Instrument};
+use std::{collections::{HashMap, HashSet}, hash::Hasher, sync::atomic::{AtomicUsize, Ordering}, time::Duration};
+use std::sync::{Arc, Mutex};
+use clap::Parser;
+use siphasher::sip::SipHasher13;
+
+
+#[derive(Debug, Clone, Parser)]
+#[clap(author, version)]
+pub struct Opts {
+    #[clap(long, default_value_t = 3, help = "How many nodes keep a copy of each key (N).", env = "REPLICAS")]
+    pub replicas: usize,
+    #[clap(long, default_value_t = 2, help = "How many copies a read waits for (R).", env = "READ_QUORUM")]
+    pub read_quorum: usize,
+    #[clap(long, default_value_t = 2, help = "How many copies a write waits for (W).", env = "WRITE_QUORUM")]
+    pub write_quorum: usize,
+    #[clap(long, default_value_t = 1000, help = "Number of milliseconds to wait on other nodes before giving up on a request.", env = "RPC_TIMEOUT_MS")]
+    pub rpc_timeout_ms: u64,
+    #[clap(long, help = "Every this many milliseconds, compare the keys this node and a peer picked at random both keep, and copy over whatever versions either is missing. Off if not given.", env = "REPAIR_INTERVAL_MS")]
+    pub repair_interval_ms: Option<u64>,
+    #[clap(flatten)]
+    pub common: CommonOpts,
+}
+
+
+static MSG_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Payload {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        create_if_not_exists: Option<bool>,
+    },
+    CasOk,
+    Error {
+        code: usize,
+        text: String,
+    },
+
+    // Between nodes.
+    /// From a coordinator, a read repair or a hinted handoff: keep these versions of
+    /// `key`, on top of whatever's there. With a `hint`, they're kept for that node,
+    /// which is down, and handed over to it once it's back.
+    Store {
+        key: Value,
+        versions: Vec<Version>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hint: Option<String>,
+    },
+    StoreOk,
+    /// From a coordinator: every version of `key` there is here.
+    Fetch {
+        key: Value,
+    },
+    FetchOk {
+        versions: Vec<Version>,
+    },
+    /// With `--repair-interval-ms`, a step of a round of repair of the keys two nodes
+    /// both keep, and the answer to one.
+    Repair {
+        repair: anti_entropy::Message<(Value, Vec<Version>)>,
+    },
+    RepairOk {
+        repair: anti_entropy::Message<(Value, Vec<Version>)>,
+    },
+    /// To tell whether nodes are up.
+    Ping,
+    PingOk,
+}
+
+fn message_id() -> usize {
+    MSG_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+fn error(code: ErrorCode, text: String) -> Payload {
+    Payload::Error { code: code.code(), text }
+}
+
+
+/// How often hints are offered to the nodes they're for, once they're up again.
+pub const HANDOFF_EVERY: Duration = Duration::from_millis(500);
+
+
+/// One version of a key's value: its vector clock, and when and where it was written,
+/// which pick between versions that are concurrent (the latest one wins, as in lww-kv).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    pub clock: VectorClock,
+    /// Milliseconds since the epoch, by the coordinator's clock.
+    pub ts: u64,
+    pub node: String,
+    pub value: Value,
+}
+
+/// Take `incoming` into `versions`, keeping only the ones no other version has seen
+/// everything of: what's left is one version, or several concurrent ones (siblings).
+/// Whether anything changed.
+pub fn merge(versions: &mut Vec<Version>, incoming: impl IntoIterator<Item = Version>) -> bool {
+    let mut changed = false;
+    for version in incoming {
+        if versions.iter().any(|kept| kept.clock.descends(&version.clock)) {
+            continue;
+        }
+        versions.retain(|kept| !version.clock.descends(&kept.clock));
+        versions.push(version);
+        changed = true;
+    }
+    changed
+}
+
+/// The sibling a read answers with: the last one written.
+pub fn resolve(versions: &[Version]) -> Option<&Version> {
+    versions.iter().max_by(|a, b| (a.ts, &a.node).cmp(&(b.ts, &b.node)))
+}
+
+
+#[derive(Debug, Default)]
+pub struct State {
+    my_id: NodeId,
+    /// Who keeps which keys, once initialized.
+    ring: HashRing,
+    /// How many nodes keep each key.
+    replicas: usize,
+    /// Peers the failure detector takes to be down.
+    down: HashSet<NodeId>,
+    /// Each key's versions, keyed by its JSON.
+    keys: HashMap<String, Vec<Version>>,
+    /// Keys kept here for nodes that were down when they were written, by node.
+    hints: HashMap<NodeId, HashSet<String>>,
+    /// The newest `ts` handed out here, so this node's versions are never older than the
+    /// ones before them, and what it's told by.
+    last_ts: u64,
+    clock: WallClock,
+}
+
+impl State {
+    pub fn new(replicas: usize) -> Self {
+        Self { replicas: replicas.max(1), ..Default::default() }
+    }
+
+    /// Timestamp versions by `clock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = WallClock::new(clock);
+        self
+    }
+
+    pub fn init(&mut self, node_id: &str, node_ids: &[String]) {
+        self.my_id = message::intern(node_id);
+        self.ring = HashRing::new(node_ids);
+    }
+
+    pub fn my_id(&self) -> &NodeId {
+        &self.my_id
+    }
+
+    /// Take `peer` to be down (or up again), as the failure detector says.
+    pub fn set_down(&mut self, peer: &str, down: bool) {
+        if down {
+            self.down.insert(message::intern(peer));
+        } else {
+            self.down.remove(peer);
+        }
+    }
+
+    /// How many copies of each key there are: [`replicas`](Self::new), or every node if
+    /// there aren't that many.
+    pub fn copies(&self) -> usize {
+        self.replicas.min(self.ring.len()).max(1)
+    }
+
+    /// The nodes `key` belongs on: its owner on the ring, and the next ones after it.
+    pub fn home(&self, key: &Value) -> Vec<NodeId> {
+        self.ring.owners(&key.to_string(), self.copies()).into_iter().cloned().collect()
+    }
+
+    /// Who gets `key`'s copies as things stand (its preference list), each with the
+    /// node it's standing in for, if any. That's its home nodes, except that one that's
+    /// down is stood in for by the next node round the ring that's up and isn't a home
+    /// node (a sloppy quorum). Fewer if there aren't enough nodes up.
+    pub fn preference_list(&self, key: &Value) -> Vec<(NodeId, Option<NodeId>)> {
+        let copies = self.copies();
+        let everyone = self.ring.owners(&key.to_string(), self.ring.len());
+        let (home, rest) = everyone.split_at(copies.min(everyone.len()));
+        let mut stand_ins = rest.iter().filter(|node| !self.down.contains(**node));
+        home
+        .iter()
+        .filter_map(|&node| match self.down.contains(node) {
+            false => Some((node.clone(), None)),
+            true => stand_ins.next().map(|&stand_in| (stand_in.clone(), Some(node.clone()))),
+        })
+        .collect()
+    }
+
+    /// Every version of `key` there is here.
+    pub fn versions(&self, key: &Value) -> Vec<Version> {
+        self.keys.get(&key.to_string()).cloned().unwrap_or_default()
+    }
+
+    /// Keep `versions` of `key`, for `hint` if it's for a node that's down. Whether
+    /// anything changed.
+    pub fn store(&mut self, key: &Value, versions: Vec<Version>, hint: Option<&str>) -> bool {
+        if let Some(hint) = hint {
+            if self.hints.entry(message::intern(hint)).or_default().insert(key.to_string()) {
+                metrics::global().increment("dynamo.hints_stored");
+            }
+        }
+        merge(self.keys.entry(key.to_string()).or_default(), versions)
+    }
+
+    /// A new version of `key` written here, on top of `context` (the clock of whatever it
+    /// replaces).
+    pub fn version(&mut self, context: VectorClock, value: Value) -> Version {
+        let mut clock = context;
+        clock.increment(&self.my_id);
+        self.last_ts = self.clock.now_ms().max(self.last_ts + 1);
+        Version { clock, ts: self.last_ts, node: self.my_id.to_string(), value }
+    }
+
+    /// What this node's copy of `key` has seen: what a write coordinated here goes on
+    /// top of.
+    pub fn context(&self, key: &Value) -> VectorClock {
+        let mut context = VectorClock::new();
+        for version in self.keys.get(&key.to_string()).into_iter().flatten() {
+            context.merge(&version.clock);
+        }
+        context
+    }
+
+    /// The keys kept here for nodes that are up again: what to hand over to them.
+    pub fn hinted(&self) -> Vec<(NodeId, Value, Vec<Version>)> {
+        self.hints
+        .iter()
+        .filter(|(node, _)| !self.down.contains(*node))
+        .flat_map(|(node, keys)| keys.iter().map(move |key| (node.clone(), key)))
+        .filter_map(|(node, key)| Some((node, serde_json::from_str(key).ok()?, self.keys.get(key)?.clone())))
+        .collect()
+    }
+
+    /// `node` has taken in what this node kept of `key` for it: forget the hint, and the
+    /// key too, unless it belongs here anyway or is kept for another node.
+    pub fn handed_off(&mut self, node: &str, key: &Value) {
+        let key_json = key.to_string();
+        if let Some(keys) = self.hints.get_mut(node) {
+            keys.remove(&key_json);
+            if keys.is_empty() {
+                self.hints.remove(node);
+            }
+        }
+        let still_hinted = self.hints.values().any(|keys| keys.contains(&key_json));
+        if !still_hinted && !self.home(key).contains(&self.my_id) {
+            self.keys.remove(&key_json);
+        }
+        metrics::global().increment("dynamo.hints_delivered");
+    }
+
+    /// The peers that are up: who to repair keys with.
+    pub fn live_peers(&self) -> Vec<NodeId> {
+        self.ring.nodes().iter().filter(|node| **node != self.my_id && !self.down.contains(*node)).cloned().collect()
+    }
+}
+
+/// Each key's home nodes repair it between them. A key's digest covers every version
+/// of it, in whatever order they're kept.
+impl Replica for State {
+    type Entry = (Value, Vec<Version>);
+
+    fn digests(&self, peer: &str) -> Vec<(String, u64)> {
+        self.keys
+        .iter()
+        .filter_map(|(key_json, versions)| {
+            let key: Value = serde_json::from_str(key_json).ok()?;
+            let home = self.home(&key);
+            (home.contains(&self.my_id) && home.iter().any(|node| **node == *peer)).then_some((key_json, versions))
+        })
+        .map(|(key_json, versions)| {
+            // The peer compares these with its own, so hash the version's bytes with
+            // a hasher every build agrees on.
+            let digest = versions.iter().fold(0u64, |digest, version| {
+                let mut hasher = SipHasher13::new();
+                hasher.write(serde_json::to_string(version).unwrap_or_default().as_bytes());
+                digest.wrapping_add(hasher.finish())
+            });
+            (key_json.clone(), digest)
+        })
+        .collect()
+    }
+
+    fn entry(&self, key: &str, _theirs: Option<u64>) -> Option<Self::Entry> {
+        Some((serde_json::from_str(key).ok()?, self.keys.get(key)?.clone()))
+    }
+
+    fn take(&mut self, (key, versions): Self::Entry) -> bool {
+        self.store(&key, versions, None)
+    }
+}
+
+
+/// What a node needs to coordinate requests: they wait on other nodes, so handlers are
+/// spawned rather than run inline.
+#[derive(Debug, Clone)]
+pub struct Shared {
+    state: Arc<Mutex<State>>,
+    rpc: RpcClient<Payload>,
+    timeout: Duration,
+    read_quorum: usize,
+    write_quorum: usize,
+    clock: Arc<dyn Clock>,
+}
+
+impl Shared {
+    pub fn new(state: Arc<Mutex<State>>, rpc: RpcClient<Payload>, timeout: Duration) -> Self {
+        Self { state, rpc, timeout, read_quorum: 2, write_quorum: 2, clock: clock::system() }
+    }
+
+    /// Wait on quorums, and timestamp versions, by `clock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.state.lock().unwrap().clock = WallClock::new(clock.clone());
+        self.clock = clock;
+        self
+    }
+
+    /// Wait for `read` copies on reads, and `write` on writes.
+    pub fn with_quorums(mut self, read: usize, write: usize) -> Self {
+        self.read_quorum = read;
+        self.write_quorum = write;
+        self
+    }
+
+    pub fn state(&self) -> &Arc<Mutex<State>> {
+        &self.state
+    }
+}
+
+
+pub async fn handle_envelope(shared: Shared, envelope: Envelope<Payload>, writer: UnboundedSender<Envelope<Payload>>) {
+    let reply = match &envelope.body.message {
+        Payload::Init { node_id, node_ids } => {
+            shared.state.lock().unwrap().init(node_id, node_ids);
+            Payload::InitOk
+        },
+        Payload::Ping => Payload::PingOk,
+        Payload::Read { key } => match read(&shared, key).await {
+            Ok(versions) => match resolve(&versions) {
+                Some(version) => Payload::ReadOk { value: version.value.clone() },
+                None => error(ErrorCode::KeyDoesNotExist, format!("no key {key}")),
+            },
+            Err(reply) => reply,
+        },
+        Payload::Write { key, value } => {
+            let context = shared.state.lock().unwrap().context(key);
+            write(&shared, key, value.clone(), context).await.unwrap_or(Payload::WriteOk)
+        },
+        Payload::Cas { key, from, to, create_if_not_exists } => cas(&shared, key, from, to, *create_if_not_exists == Some(true)).await,
+        Payload::Store { key, versions, hint } => {
+            shared.state.lock().unwrap().store(key, versions.clone(), hint.as_deref());
+            Payload::StoreOk
+        },
+        Payload::Fetch { key } => Payload::FetchOk { versions: shared.state.lock().unwrap().versions(key) },
+        Payload::Repair { repair } => match anti_entropy::answer(&mut *shared.state.lock().unwrap(), &envelope.source, repair.clone()) {
+            Some(repair) => Payload::RepairOk { repair },
+            None => return,
+        },
+        _ => return,
+    };
+    writer.send(envelope.reply_with(Some(message_id()), reply)).unwrap();
+}
+
+
+/// Send each of `key`'s preference list what `message` makes for it (given who it's
+/// standing in for), this node handling its own part itself, and wait (up to the
+/// timeout) for `needed` of them to answer. Who answered, and what they said.
+async fn quorum(shared: &Shared, key: &Value, needed: usize, message: impl Fn(Option<String>) -> Payload) -> Vec<(NodeId, Payload)> {
+    let (me, targets) = {
+        let state = shared.state.lock().unwrap();
+        (state.my_id.clone(), state.preference_list(key))
+    };
+    let (answered, mut answers) = tokio::sync::mpsc::unbounded_channel();
+    for (target, stand_in_for) in targets {
+        let message = message(stand_in_for.map(|node| node.to_string()));
+        if target == me {
+            let reply = match message {
+                Payload::Store { key, versions, hint } => {
+                    shared.state.lock().unwrap().store(&key, versions, hint.as_deref());
+                    Payload::StoreOk
+                },
+                Payload::Fetch { key } => Payload::FetchOk { versions: shared.state.lock().unwrap().versions(&key) },
+                other => other,
+            };
+            answered.send((target, reply)).ok();
+            continue;
+        }
+        let (rpc, timeout, answered) = (shared.rpc.clone(), shared.timeout, answered.clone());
+        task::spawn("dynamo.replica", async move {
+            if let Ok(reply) = rpc.call(&target, message, timeout).await {
+                answered.send((target, reply.body.message)).ok();
+            }
+        });
+    }
+    drop(answered);
+    let mut replies = vec![];
+    let gathered = async {
+        while replies.len() < needed {
+            let Some(answer) = answers.recv().await else {
+                break;
+            };
+            replies.push(answer);
+        }
+    };
+    shared.clock.timeout(shared.timeout, gathered).await;
+    if replies.len() < needed {
+        metrics::global().increment("dynamo.quorum_failures");
+    }
+    replies
+}
+
+/// Ask `key`'s preference list for its versions, and put together what the first
+/// `--read-quorum` of them say. Any of them that's missing something the others have is
+/// sent it (read repair, counted in `dynamo.read_repairs`). The versions there are, or
+/// the error to answer with if too few answered.
+async fn read(shared: &Shared, key: &Value) -> Result<Vec<Version>, Payload> {
+    let needed = shared.read_quorum.min(shared.state.lock().unwrap().copies()).max(1);
+    let replies = quorum(shared, key, needed, |_| Payload::Fetch { key: key.clone() }).await;
+    if replies.len() < needed {
+        return Err(error(ErrorCode::TemporarilyUnavailable, format!("only {} of the {needed} copies of {key} needed answered", replies.len())));
+    }
+    let mut merged = vec![];
+    let mut seen = vec![];
+    for (node, reply) in replies {
+        if let Payload::FetchOk { versions } = reply {
+            merge(&mut merged, versions.clone());
+            seen.push((node, versions));
+        }
+    }
+    if merged.len() > 1 {
+        metrics::global().increment("dynamo.siblings_resolved");
+    }
+    let me = shared.state.lock().unwrap().my_id.clone();
+    for (node, versions) in seen {
+        let mut repaired = versions;
+        if !merge(&mut repaired, merged.clone()) {
+            continue;
+        }
+        debug!(%node, %key, "repairing a stale copy");
+        metrics::global().increment("dynamo.read_repairs");
+        let repair = Payload::Store { key: key.clone(), versions: merged.clone(), hint: None };
+        if node == me {
+            shared.state.lock().unwrap().store(key, merged.clone(), None);
+        } else {
+            shared.rpc.send(&node, repair).ok();
+        }
+    }
+    Ok(merged)
+}
+
+/// Write `value` to `key` as a new version on top of `context`, waiting for
+/// `--write-quorum` copies of it. `None` once it has them, or the error to answer with.
+async fn write(shared: &Shared, key: &Value, value: Value, context: VectorClock) -> Option<Payload> {
+    let (version, needed) = {
+        let mut state = shared.state.lock().unwrap();
+        (state.version(context, value), shared.write_quorum.min(state.copies()).max(1))
+    };
+    let stored = quorum(shared, key, needed, |hint| Payload::Store { key: key.clone(), versions: vec![version.clone()], hint }).await;
+    // It may have been stored all the same.
+    (stored.len() < needed).then(|| error(ErrorCode::Timeout, format!("only {} of the {needed} copies of {key} needed were written", stored.len())))
+}
+
+/// A compare-and-set: a quorum read, then a write on top of everything it saw if the
+/// value read is `from`. That's two steps, not one, so two of them at once can both
+/// succeed: a quorum store has no atomic CAS.
+async fn cas(shared: &Shared, key: &Value, from: &Value, to: &Value, create_if_not_exists: bool) -> Payload {
+    let versions = match read(shared, key).await {
+        Ok(versions) => versions,
+        Err(reply) => return reply,
+    };
+    match resolve(&versions) {
+        None if !create_if_not_exists => return error(ErrorCode::KeyDoesNotExist, format!("no key {key}")),
+        Some(current) if current.value != *from => return error(ErrorCode::PreconditionFailed, format!("{key} is {}, not {from}", current.value)),
+        _ => {},
+    }
+    let mut context = VectorClock::new();
+    for version in &versions {
+        context.merge(&version.clock);
+    }
+    write(shared, key, to.clone(), context).await.unwrap_or(Payload::CasOk)
+}
+
+
+/// Offer every hint this node keeps for a node that's up again to that node, forgetting
+/// the ones it takes in.
+pub fn hand_off(shared: &Shared) {
+    let hinted = shared.state.lock().unwrap().hinted();
+    for (node, key, versions) in hinted {
+        let shared = shared.clone();
+        task::spawn("dynamo.handoff", async move {
+            let store = Payload::Store { key: key.clone(), versions, hint: None };
+            if let Ok(Envelope { body: Body { message: Payload::StoreOk, .. }, .. }) = shared.rpc.call(&node, store, shared.timeout).await {
+                shared.state.lock().unwrap().handed_off(&node, &key);
+            }
+        });
+    }
+}
+
+/// [Hand off](hand_off) hints every [`HANDOFF_EVERY`], as a `handoff` job on
+/// `scheduler`.
+pub fn hand_off_every_so_often(scheduler: &Scheduler, shared: Shared) -> JobHandle {
+    scheduler.spawn(Job::new("handoff", Period::Every(HANDOFF_EVERY)), move |_| hand_off(&shared))
+}
+
+
+/// With `--repair-interval-ms`, every `interval`, as a `repair` job on `scheduler`:
+/// repair the keys this node keeps along with a peer that's up, picked at random.
+pub fn repair_every_so_often(scheduler: &Scheduler, interval: Duration, shared: Shared) -> JobHandle {
+    let (state, rpc, timeout) = (shared.state.clone(), shared.rpc.clone(), shared.timeout);
+    let peers = move || state.lock().unwrap().live_peers();
+    anti_entropy::repair_every_so_often(scheduler, interval, shared.state.clone(), peers, move |peer, repair| {
+        let rpc = rpc.clone();
+        async move {
+            match rpc.call(&peer, Payload::Repair { repair }, timeout).await.ok()?.body.message {
+                Payload::RepairOk { repair } => Some(repair),
+                _ => None,
+            }
+        }
+    })
+}
+
+
+/// Keep `state` up to date on which of `node_ids` are down, pinging them as need be,
+/// by `scheduler`'s clock.
+pub fn watch_peers(scheduler: &Scheduler, state: Arc<Mutex<State>>, writer: UnboundedSender<Envelope<Payload>>, node_id: &str, node_ids: &[String]) -> Arc<Mutex<FailureDetector>> {
+    let peers = node_ids.iter().filter(|&other| other != node_id).map(|other| message::intern(other));
+    let detector = Arc::new(Mutex::new(FailureDetector::new(DetectorConfig::default(), peers, scheduler.clock().now())));
+    let mut events = detector.lock().unwrap().subscribe();
+    task::spawn("dynamo.peer_status", async move {
+        while let Ok(event) = events.recv().await {
+            state.lock().unwrap().set_down(&event.peer, event.to == Status::Down);
+        }
+    });
+    let node_id = node_id.to_owned();
+    heartbeat(scheduler, detector.clone(), move |peer| {
+        let ping = Body { msg_id: Some(message_id()), in_reply_to: None, message: Payload::Ping };
+        writer.send(Envelope::new(&node_id, peer, ping)).ok();
+    });
+    detector
+}
+
+
+pub async fn server(opts: Opts) {
+    debug!(opts = ?opts, "starting server...");
+    let state = Arc::new(Mutex::new(State::new(opts.replicas)));
+    let (writer, mut reader, _) = io_channel::<Envelope<Payload>>();
+    let rpc = RpcClient::new(writer.clone(), message_id);
+    let shared = Shared::new(state.clone(), rpc.clone(), Duration::from_millis(opts.rpc_timeout_ms)).with_quorums(opts.read_quorum, opts.write_quorum);
+    // Who's down decides who stands in for whom; set up once the peers are known.
+    let mut detector: Option<Arc<Mutex<FailureDetector>>> = None;
+    let scheduler = Scheduler::new();
+    hand_off_every_so_often(&scheduler, shared.clone());
+    if let Some(interval) = opts.repair_interval_ms {
+        repair_every_so_often(&scheduler, Duration::from_millis(interval), shared.clone());
+    }
+
+    while let Some(envelope) = reader.recv().await {
+        if let Some(detector) = &detector {
+            detector.lock().unwrap().heard_from(&envelope.source, scheduler.clock().now());
+        }
+        let Some(envelope) = rpc.try_complete(envelope) else {
+            continue;
+        };
+        if let Payload::Init { node_id, node_ids } = &envelope.body.message {
+            rpc.set_node_id(node_id);
+            if node_ids.len() > 1 {
+                detector = Some(watch_peers(&scheduler, state.clone(), writer.clone(), node_id, node_ids));
+            }
+        }
+        // Coordinating a request waits on other nodes, whose replies only arrive through
+        // this loop, so handlers can't be awaited inline.
+        let handler = format!("handler.{}", metrics::message_type(&envelope.body.message));
+        let span = io::handler_span(&envelope);
+        let handled = handle_envelope(shared.clone(), envelope, writer.clone()).instrument(span);
+        let name = handler.clone();
+        task::spawn(&name, async move { metrics::timed(&handler, handled).await });
+    }
+}
@@ -0,0 +1,93 @@
+use serde::{Serialize, Deserialize};
+use crate::{chaos::{self, Chaos}, cli::CommonOpts, message::Envelope, io::{self, io_channel}, metrics};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::Instrument;
+use std::{sync::atomic::{AtomicUsize, Ordering}, time::Duration};
+use clap::Parser;
+
+
+/// An echo node can misbehave on purpose, to check that the tools testing nodes notice.
+#[derive(Debug, Clone, Default, Parser)]
+#[clap(author, version)]
+pub struct Opts {
+    #[clap(long, default_value_t = 0, help = "Hold every reply back this long.", env = "DELAY_MS")]
+    pub delay_ms: u64,
+    #[clap(long, default_value_t = 0, help = "Hold each reply back up to this many more milliseconds, picked at random.", env = "DELAY_JITTER")]
+    pub delay_jitter: u64,
+    #[clap(long, default_value_t = 0., value_parser = chaos::parse_rate, help = "Chance of never sending a reply, from 0 to 1.", env = "DROP_RATE")]
+    pub drop_rate: f64,
+    #[clap(long, default_value_t = 0., value_parser = chaos::parse_rate, help = "Chance of sending a reply twice, from 0 to 1.", env = "DUPLICATE_RATE")]
+    pub duplicate_rate: f64,
+    #[clap(flatten)]
+    pub common: CommonOpts,
+}
+
+impl Opts {
+    pub fn chaos(&self) -> Chaos {
+        Chaos {
+            delay: Duration::from_millis(self.delay_ms),
+            jitter: Duration::from_millis(self.delay_jitter),
+            drop_rate: self.drop_rate,
+            duplicate_rate: self.duplicate_rate,
+        }
+    }
+}
+
+static MSG_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Payload {
+    Init { 
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+    Echo {
+        echo: String,
+    },
+    EchoOk {
+        echo: String
+    }
+}
+
+fn message_id() -> usize {
+    MSG_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+
+pub async fn handle_envelope(envelope: Envelope<Payload>, writer: UnboundedSender<Envelope<Payload>>) {
+    match &envelope.body.message {
+        Payload::Echo { echo } => {
+            let reply = envelope.reply_with(
+                Some(message_id()),
+                Payload::EchoOk { echo: echo.clone() }
+            );
+            writer.send(reply).unwrap();
+        },
+        Payload::Init { .. } => {
+            let reply = envelope.reply_with(
+                Some(message_id()),
+                Payload::InitOk
+            );
+            writer.send(reply).unwrap();
+        },
+        _ => {}
+    }
+}
+
+pub async fn server(opts: Opts) {
+    let (writer, mut reader, _) = io_channel::<Envelope<Payload>>();
+    // `init_ok` always goes out untouched: a node that never starts isn't interesting to test against.
+    let chaotic = chaos::layer(opts.chaos(), writer.clone());
+    while let Some(envelope) = reader.recv().await {
+        let handler = format!("handler.{}", metrics::message_type(&envelope.body.message));
+        let span = io::handler_span(&envelope);
+        let writer = match envelope.body.message {
+            Payload::Init { .. } => writer.clone(),
+            _ => chaotic.clone(),
+        };
+        metrics::timed(&handler, handle_envelope(envelope, writer)).instrument(span).await;
+    }
+}
@@ -0,0 +1,712 @@
+use serde::{Serialize, Deserialize};
+use crate::{cli::CommonOpts, clock::{Clock, SharedClock}, gossip, hyparview::{self, HyParView}, io::{self, io_channel_with_background}, membership::{self, Membership, Update}, message::{self, Body, Envelope, ErrorCode, NodeId}, metrics, routing::{self, Inbound, Routed, Router}, scheduler::{Job, JobHandle, Period, Scheduler, DEFAULT_JITTER}, task, topology::Overlay};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, error, info, Instrument};
+use std::{collections::HashMap, sync::atomic::{AtomicUsize, Ordering}, time::Duration};
+use rand::seq::SliceRandom;
+use tokio::time::Instant;
+use std::sync::{Arc, Mutex};
+use clap::Parser;
+
+
+#[derive(Debug, Clone, Parser)]
+#[clap(author, version)]
+pub struct Opts {
+    #[clap(long, value_delimiter = ',', help = "These nodes only answer reads, and forward adds to one of the others (e.g. `n1,n2`).", env = "READ_ONLY")]
+    pub read_only: Vec<String>,
+    #[clap(flatten)]
+    pub common: CommonOpts,
+}
+
+
+static MSG_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// How long an `add` forwarded by a read-only node is waited on before it's forgotten.
+const FORGET_AFTER: Duration = Duration::from_secs(10);
+
+/// How long a `cas` to seq-kv is waited on before it's in doubt: seq-kv may or may not
+/// have applied it.
+pub const CAS_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Payload {
+    Init { 
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+    Topology {
+        topology: HashMap<String, Vec<String>>
+    },
+    TopologyOk,
+    /// From an admin: `node_id` has joined the cluster.
+    Join {
+        node_id: String,
+    },
+    JoinOk,
+    /// From an admin: `node_id` has left the cluster.
+    Leave {
+        node_id: String,
+    },
+    LeaveOk,
+
+    Read {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    ReadOk {
+        value: usize
+    },
+    Write {
+        key: String,
+        value: usize
+    },
+    WriteOk,
+    Cas {
+        key: String,
+        from: usize,
+        to: usize,
+        create_if_not_exists: Option<bool>
+    },
+    CasOk,
+    Add {
+        delta: usize,
+    },
+    UpdateCounter {
+        value: usize,
+        /// Membership rumors, with `--swim`.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        updates: Vec<Update>,
+    },
+    AddOk,
+    Error {
+        code: usize,
+        text: String
+    },
+    Ping {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        updates: Vec<Update>,
+    },
+    PingReq {
+        target: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        updates: Vec<Update>,
+    },
+    Ack {
+        target: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        updates: Vec<Update>,
+    },
+    /// Keeping partial views up, with `--hyparview`.
+    View(hyparview::Message),
+}
+
+impl TryFrom<serde_json::Value> for Payload {
+    type Error = serde_json::Error;
+
+    fn try_from(value: serde_json::Value) -> Result<Self, serde_json::Error> {
+        let serialized = serde_json::to_vec(&value)?;
+        serde_json::from_slice::<Self>(&serialized)
+    }
+}
+
+
+fn message_id() -> usize {
+    MSG_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+impl membership::Messages for Payload {
+    fn ping(updates: Vec<Update>) -> Self {
+        Payload::Ping { updates }
+    }
+
+    fn ping_req(target: String, updates: Vec<Update>) -> Self {
+        Payload::PingReq { target, updates }
+    }
+
+    fn ack(target: String, updates: Vec<Update>) -> Self {
+        Payload::Ack { target, updates }
+    }
+
+    fn message_id() -> usize {
+        message_id()
+    }
+}
+
+
+/// A delta sent to seq-kv in a `cas`, and maybe again in others with the same `from` and
+/// `to`. Since the total only grows, seq-kv applies one of them at most.
+#[derive(Debug, Clone, Copy)]
+struct Commit {
+    from: usize,
+    to: usize,
+    /// When it was last sent.
+    sent: Instant,
+    /// How many of the `cas`es it went out in seq-kv hasn't answered. Until every one has
+    /// failed, seq-kv may have applied it.
+    unanswered: usize,
+    /// Whether it's gone [`CAS_TIMEOUT`] without an answer since it was last sent.
+    in_doubt: bool,
+}
+
+impl Commit {
+    fn delta(&self) -> usize {
+        self.to - self.from
+    }
+}
+
+
+#[derive(Debug, Default)]
+pub struct State {
+    my_id: NodeId,
+    all_node_ids: Vec<NodeId>,
+    neighbors: Vec<NodeId>,
+    /// The topology Maelstrom sent, for picking neighbors again as nodes come and go.
+    topology: HashMap<String, Vec<String>>,
+    /// What's been added here and isn't in any commit yet.
+    uncommitted_total: usize,
+    last_known_committed_total: usize,
+    /// The commits seq-kv hasn't acknowledged, by the msg id of the first `cas` each went
+    /// out in. A commit's delta is only added back to `uncommitted_total` once we know
+    /// seq-kv didn't apply it, so it's never committed twice.
+    commits: HashMap<usize, Commit>,
+    /// Which commit each unanswered `cas` is for, by its msg id.
+    cas_commits: HashMap<usize, usize>,
+    /// The highest total each peer is known to have: what we last told it, or what it
+    /// told us.
+    peer_totals: HashMap<NodeId, usize>,
+    /// Who updates go to: every peer without one.
+    overlay: Option<Overlay>,
+    /// How to keep track of who's alive, with `--swim`.
+    swim: Option<membership::Config>,
+    membership: Option<Membership>,
+    /// How to keep a partial view of the cluster to gossip with, with `--hyparview`.
+    hyparview: Option<hyparview::Config>,
+    view: Option<HyParView>,
+    /// The nodes that forward adds rather than take them, with `--read-only`.
+    read_only: Vec<String>,
+    // messages: HashSet<usize>,
+    /// What membership is timed by.
+    clock: SharedClock,
+}
+
+
+impl membership::Node for State {
+    fn membership(&mut self) -> Option<(NodeId, &mut Membership, Instant)> {
+        let now = self.clock.now();
+        Some((self.my_id.clone(), self.membership.as_mut()?, now))
+    }
+}
+
+
+impl State {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// A node yet to be initialized, that'll only send updates to its neighbors by
+    /// `overlay`.
+    pub fn with_overlay(overlay: Overlay) -> Self {
+        Self { overlay: Some(overlay), ..Default::default() }
+    }
+
+    /// Time membership by `clock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = SharedClock::new(clock);
+        self
+    }
+
+    /// Pick neighbors from the cluster as it stands.
+    fn pick_neighbors(&mut self) {
+        let overlay = self.overlay.unwrap_or(Overlay::Maelstrom);
+        self.neighbors = overlay.neighbors(&self.my_id, &self.all_node_ids, &self.topology);
+    }
+
+    /// Keep a SWIM-style view of which peers are alive, once initialized, and stop
+    /// sending updates to ones taken for dead.
+    pub fn with_membership(mut self, config: membership::Config) -> Self {
+        self.swim = Some(config);
+        self
+    }
+
+    /// Only send updates to the active view of a [`HyParView`], once initialized,
+    /// joining the cluster through its first node.
+    pub fn with_partial_view(mut self, config: hyparview::Config) -> Self {
+        self.hyparview = Some(config);
+        self
+    }
+
+    /// Only answer reads if this node is one of `nodes`, forwarding adds to one of the
+    /// nodes that aren't.
+    pub fn with_read_only(mut self, nodes: Vec<String>) -> Self {
+        self.read_only = nodes;
+        self
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.iter().any(|node| **node == *self.my_id)
+    }
+
+    /// The nodes adds can go to.
+    pub fn writable(&self) -> Vec<NodeId> {
+        self.all_node_ids.iter().filter(|node| !self.read_only.iter().any(|read_only| **read_only == ***node)).cloned().collect()
+    }
+
+    /// Who updates go to, as things stand.
+    pub fn gossiped_to(&self) -> &[NodeId] {
+        match (&self.view, &self.overlay) {
+            (Some(view), _) => view.active(),
+            (None, Some(_)) => &self.neighbors,
+            (None, None) => &self.all_node_ids,
+        }
+    }
+}
+
+
+pub async fn handle_envelope(
+    state: Arc<Mutex<State>>,
+    envelope: Envelope<Payload>, 
+    writer: UnboundedSender<Envelope<Payload>>
+) {
+    match &envelope.body.message {
+        Payload::Init { node_id, node_ids } => {
+            let mut state = state.lock().unwrap();
+            state.my_id = message::intern(node_id);
+            state.all_node_ids = node_ids.iter().map(|node_id| message::intern(node_id)).collect();
+            if let Some(config) = state.swim {
+                let now = state.clock.now();
+                state.membership = Some(Membership::new(config, node_id, state.all_node_ids.clone(), now));
+            }
+            if let Some(config) = state.hyparview {
+                let mut view = HyParView::new(config, node_id, state.all_node_ids.clone());
+                // Everyone else joins through the first node; it's the one to let them in.
+                let contact = state.all_node_ids.first().filter(|&contact| *contact != state.my_id).cloned();
+                let sends = contact.map(|contact| view.join(&contact)).unwrap_or_default();
+                state.view = Some(view);
+                for envelope in partial_view(&state.my_id, sends) {
+                    writer.send(envelope).unwrap();
+                }
+            }
+
+            let reply = envelope.reply_with(
+                Some(message_id()),
+                Payload::InitOk
+            );
+            writer.send(reply).unwrap();
+        },
+        Payload::Topology { topology } => {
+            let mut state = state.lock().unwrap();
+            state.topology = topology.clone();
+            state.pick_neighbors();
+
+            let reply = envelope.reply_with(
+                Some(message_id()),
+                Payload::TopologyOk
+            );
+            writer.send(reply).unwrap();
+        },
+        Payload::Join { node_id } => {
+            let mut state = state.lock().unwrap();
+            let joined = message::intern(node_id);
+            if !state.all_node_ids.contains(&joined) {
+                info!(node = %joined, "node joined the cluster");
+                state.all_node_ids.push(joined);
+                state.pick_neighbors();
+                let now = state.clock.now();
+                if let Some(membership) = state.membership.as_mut() {
+                    membership.add(node_id, now);
+                }
+                if let Some(view) = state.view.as_mut() {
+                    view.learn(node_id);
+                }
+            }
+            // Tell the new member our total now, rather than after the next commit.
+            for update in updates(&mut state) {
+                writer.send(update).unwrap();
+            }
+            writer.send(envelope.reply_with(Some(message_id()), Payload::JoinOk)).unwrap();
+        },
+        Payload::Leave { node_id } => {
+            let mut state = state.lock().unwrap();
+            info!(node = node_id, "node left the cluster");
+            state.all_node_ids.retain(|member| **member != **node_id);
+            state.pick_neighbors();
+            state.peer_totals.remove(node_id.as_str());
+            if let Some(membership) = state.membership.as_mut() {
+                membership.remove(node_id);
+            }
+            let sends = state.view.as_mut().map(|view| view.failed(node_id)).unwrap_or_default();
+            for view_message in partial_view(&state.my_id, sends) {
+                writer.send(view_message).unwrap();
+            }
+            writer.send(envelope.reply_with(Some(message_id()), Payload::LeaveOk)).unwrap();
+        },
+        Payload::Add { delta } => {
+            let mut state = state.lock().unwrap();
+            state.uncommitted_total += delta;
+
+            let reply = envelope.reply_with(
+                Some(message_id()),
+                Payload::AddOk
+            );
+            writer.send(reply).unwrap();
+        },
+        Payload::Read { key } => {
+            assert!(key.is_none(), "Clients should not send us read payloads.");
+            let state = state.lock().unwrap();
+            
+            let reply = envelope.reply_with(
+                Some(message_id()),
+                Payload::ReadOk { value: state.last_known_committed_total }
+            );
+            writer.send(reply).unwrap();
+        },
+        Payload::CasOk => {
+            let mut state = state.lock().unwrap();
+            let state = &mut *state;
+            // A duplicate, or a reply to a cas whose commit has been acknowledged already,
+            // changes nothing. A late reply to one in doubt commits it all the same.
+            let Some(commit) = envelope.body.in_reply_to
+                .and_then(|msg_id| state.cas_commits.remove(&msg_id))
+                .and_then(|id| state.commits.remove(&id)) else {
+                debug!(in_reply_to = ?envelope.body.in_reply_to, "ignoring a cas_ok we aren't waiting on");
+                metrics::global().increment("counter.orphan_replies");
+                return;
+            };
+            // Only one cas from a given total can succeed, so no other commit from this one's
+            // was applied, or ever will be.
+            let mut failed = 0;
+            state.commits.retain(|_, other| {
+                let failed_too = other.from == commit.from;
+                if failed_too {
+                    failed += other.delta();
+                }
+                !failed_too
+            });
+            state.uncommitted_total += failed;
+            state.cas_commits.retain(|_, id| state.commits.contains_key(id));
+            // A read may have seen this commit already.
+            state.last_known_committed_total = state.last_known_committed_total.max(commit.to);
+
+            for update in updates(state) {
+                writer.send(update).unwrap();
+            }
+        },
+        Payload::Error { code, text } => {
+            error!("KVError: [{code}] {text}");
+            metrics::global().increment("counter.failed_commits");
+            // We couldn't commit updates. so we gotta sync our last known committed state by issuing a read.
+            let mut state = state.lock().unwrap();
+            // This cas wasn't applied, but its delta is only sent again once every cas its
+            // commit went out in has failed.
+            if let Some(id) = envelope.body.in_reply_to.and_then(|msg_id| state.cas_commits.remove(&msg_id)) {
+                if let Some(commit) = state.commits.get_mut(&id) {
+                    commit.unanswered -= 1;
+                    if commit.unanswered == 0 {
+                        let delta = commit.delta();
+                        state.commits.remove(&id);
+                        state.uncommitted_total += delta;
+                    }
+                }
+            }
+
+            let envelope = Envelope::new(
+                &state.my_id,
+                "seq-kv",
+                Body {
+                    msg_id: Some(message_id()),
+                    in_reply_to: None,
+                    message: Payload::Read { 
+                        key: Some("counter".to_string()), 
+                    }
+                }
+            );
+            writer.send(envelope).unwrap();
+        },
+        Payload::ReadOk { value } => {
+            debug!("KVReadOk: {value}");
+            let mut state = state.lock().unwrap();
+            if *value >= state.last_known_committed_total {
+                state.last_known_committed_total = *value;
+            }
+        },
+        Payload::UpdateCounter { value, updates } => {
+            debug!("UpdateCounter: {value}");
+            let mut state = state.lock().unwrap();
+            let now = state.clock.now();
+            if let Some(membership) = state.membership.as_mut() {
+                membership.apply(updates.clone(), now);
+            }
+            let known = state.peer_totals.entry(envelope.source.clone()).or_default();
+            *known = (*known).max(*value);
+            if *value >= state.last_known_committed_total {
+                state.last_known_committed_total = *value;
+            }
+        },
+        Payload::Ping { updates } => {
+            if let Some(ack) = membership::on_ping(&mut *state.lock().unwrap(), &envelope, updates) {
+                writer.send(ack).unwrap();
+            }
+        },
+        Payload::PingReq { target, updates } => {
+            if let Some(ping) = membership::on_ping_req(&mut *state.lock().unwrap(), &envelope, target, updates) {
+                writer.send(ping).unwrap();
+            }
+        },
+        Payload::Ack { target, updates } => {
+            for ack in membership::on_ack(&mut *state.lock().unwrap(), target, updates) {
+                writer.send(ack).unwrap();
+            }
+        },
+        Payload::View(message) => {
+            let mut state = state.lock().unwrap();
+            let sends = state.view.as_mut().map(|view| view.receive(&envelope.source, message.clone())).unwrap_or_default();
+            for view_message in partial_view(&state.my_id, sends) {
+                writer.send(view_message).unwrap();
+            }
+        },
+        _ => {}
+    }
+}
+
+
+/// The `update_counter`s telling peers about our last known committed total, after a
+/// commit: to every peer, or every neighbor with an overlay or in the active view with
+/// `--hyparview` (or, with a fanout, some of
+/// them, the rest catching up from seq-kv on their next tick), that isn't already known
+/// to have it, or (with `--swim`) taken for dead. The ones skipped are counted in
+/// `counter.updates_suppressed`.
+pub fn updates(state: &mut State) -> Vec<Envelope<Payload>> {
+    let gossiped_to = state.gossiped_to().to_vec();
+    let State { my_id, last_known_committed_total: total, peer_totals, membership, .. } = state;
+    let peers = gossiped_to.iter().filter(|&node_id| node_id != my_id).count();
+    let candidates: Vec<&NodeId> =
+        gossiped_to
+        .iter()
+        .filter(|&node_id| node_id != my_id)
+        .filter(|&node_id| peer_totals.get(node_id).map_or(true, |known| known < total))
+        .filter(|&node_id| membership.as_ref().map_or(true, |membership| membership.is_live(node_id)))
+        .collect();
+    metrics::global().add("counter.updates_suppressed", (peers - candidates.len()) as u64);
+    gossip::choose(candidates, peers)
+    .into_iter()
+    .map(|peer| {
+        peer_totals.insert(peer.clone(), *total);
+        Envelope::new(
+            my_id,
+            peer,
+            Body {
+                msg_id: Some(message_id()),
+                in_reply_to: None,
+                message: Payload::UpdateCounter {
+                    value: *total,
+                    updates: membership.as_mut().map(|membership| membership.piggyback(peer)).unwrap_or_default(),
+                },
+            }
+        )
+    })
+    .collect()
+}
+
+
+/// Try to commit what's been added here since the last commit to seq-kv, and ask it for
+/// the latest committed total, as of `now`. A commit seq-kv hasn't answered in
+/// [`CAS_TIMEOUT`] is in doubt: it's sent again as it was while the total it's from is
+/// still the latest we know of, and its delta is held back from new commits until we
+/// know whether it was applied.
+pub fn commit_buffered_delta(
+    state: &Mutex<State>,
+    writer: &UnboundedSender<Envelope<Payload>>,
+    now: Instant,
+) {
+    let mut state = state.lock().unwrap();
+    let state = &mut *state;
+    let mut expired = 0;
+    for commit in state.commits.values_mut() {
+        if !commit.in_doubt && now.saturating_duration_since(commit.sent) >= CAS_TIMEOUT {
+            commit.in_doubt = true;
+            expired += 1;
+        }
+    }
+    if expired > 0 {
+        debug!(expired, "cas requests seq-kv never answered are in doubt");
+        metrics::global().add("counter.cas_timeouts", expired as u64);
+    }
+    // So the thing with seq-kv's is that an acknowledged 
+    // commit from a node X is not necessarily reflected in a commit 
+    // from a node Y.
+    let cas = |my_id: &NodeId, from: usize, to: usize| Envelope::new(
+        my_id,
+        "seq-kv",
+        Body {
+            msg_id: Some(message_id()),
+            in_reply_to: None,
+            message: Payload::Cas { 
+                key: "counter".to_string(), 
+                from, 
+                to, 
+                create_if_not_exists: Some(true)
+            }
+        }
+    );
+    let mut retried = false;
+    for (id, commit) in state.commits.iter_mut() {
+        if commit.in_doubt && commit.from == state.last_known_committed_total {
+            let envelope = cas(&state.my_id, commit.from, commit.to);
+            state.cas_commits.insert(envelope.msg_id().unwrap(), *id);
+            commit.unanswered += 1;
+            commit.sent = now;
+            commit.in_doubt = false;
+            retried = true;
+            writer.send(envelope).unwrap();
+        }
+    }
+    // A new commit would be from the same total as the retries, so it waits for them.
+    if !retried && state.uncommitted_total > 0 {
+        // Try to commit unbuffered counter updates to a last known committed value.
+        let (from, to) = (state.last_known_committed_total, state.last_known_committed_total + state.uncommitted_total);
+        let envelope = cas(&state.my_id, from, to);
+        let msg_id = envelope.msg_id().unwrap();
+        state.commits.insert(msg_id, Commit { from, to, sent: now, unanswered: 1, in_doubt: false });
+        state.cas_commits.insert(msg_id, msg_id);
+        state.uncommitted_total = 0;
+        writer.send(envelope).unwrap();
+
+    }
+    // Ask for the most recent committed value.
+    let envelope = Envelope::new(
+        &state.my_id,
+        "seq-kv",
+        Body {
+            msg_id: Some(message_id()),
+            in_reply_to: None,
+            message: Payload::Read { 
+                key: Some("counter".to_string()), 
+            }
+        }
+    );
+    writer.send(envelope).unwrap();
+}
+
+
+/// An `add` sent to a read-only node, forwarded at `now` to a writable one picked at
+/// random (or answered with an error if there's nowhere to send it). `None` for anything
+/// else, or an `add` to a node that takes them, which is left to [`handle_envelope`].
+pub fn forward_add(state: &State, router: &mut Router, envelope: &Envelope<Payload>, now: Instant) -> Option<Envelope<Routed<Payload>>> {
+    if !matches!(envelope.body.message, Payload::Add { .. }) || !state.is_read_only() {
+        return None;
+    }
+    let unavailable = |text: String| Payload::Error { code: ErrorCode::TemporarilyUnavailable.code(), text };
+    let Some(writer) = state.writable().choose(&mut rand::thread_rng()).cloned() else {
+        return Some(router.send(envelope.reply_with(Some(message_id()), unavailable("every node is read-only".to_owned()))));
+    };
+    debug!(to = %writer, "forwarding an add from a read-only node");
+    metrics::global().increment("counter.adds_forwarded");
+    Some(match router.forward(envelope.clone(), &writer, message_id(), now) {
+        Ok(forwarded) => forwarded,
+        Err(err) => router.send(envelope.reply_with(Some(message_id()), unavailable(err.to_string()))),
+    })
+}
+
+
+/// A writer for the handlers, who don't know about routing: what they send goes out
+/// through `router`, so replies to forwarded requests find their way back.
+fn routed(router: Arc<Mutex<Router>>, wire: UnboundedSender<Envelope<Routed<Payload>>>) -> UnboundedSender<Envelope<Payload>> {
+    let (writer, mut outbox) = tokio::sync::mpsc::unbounded_channel::<Envelope<Payload>>();
+    task::spawn("counter.routing", async move {
+        while let Some(envelope) = outbox.recv().await {
+            let envelope = router.lock().unwrap().send(envelope);
+            if wire.send(envelope).is_err() {
+                break;
+            }
+        }
+    });
+    writer
+}
+
+
+/// What a [`HyParView`] wants sent, as messages from `my_id`.
+fn partial_view(my_id: &NodeId, sends: Vec<(NodeId, hyparview::Message)>) -> Vec<Envelope<Payload>> {
+    sends
+    .into_iter()
+    .map(|(to, message)| Envelope::new(my_id, &to, Body { msg_id: Some(message_id()), in_reply_to: None, message: Payload::View(message) }))
+    .collect()
+}
+
+/// Shuffle the node's [`HyParView`] with a random peer's, and replace neighbors that
+/// have failed (taken for dead, with `--swim`), every [`hyparview::SHUFFLE_EVERY`], as a
+/// `hyparview` job on `scheduler`, once it has one.
+pub fn shuffle_every_so_often(
+    scheduler: &Scheduler,
+    state: Arc<Mutex<State>>,
+    writer: UnboundedSender<Envelope<Payload>>
+) -> JobHandle {
+    scheduler.spawn(Job::new("hyparview", Period::Every(hyparview::SHUFFLE_EVERY)).jitter(DEFAULT_JITTER), move |_| {
+        let mut state = state.lock().unwrap();
+        let State { my_id, membership, view, .. } = &mut *state;
+        let Some(view) = view.as_mut() else {
+            return;
+        };
+        let failed: Vec<NodeId> = view.active().iter().filter(|&neighbor| membership.as_ref().is_some_and(|membership| !membership.is_live(neighbor))).cloned().collect();
+        let mut sends: Vec<_> = failed.iter().flat_map(|neighbor| view.failed(neighbor)).collect();
+        sends.extend(view.tick());
+        for envelope in partial_view(my_id, sends) {
+            writer.send(envelope).unwrap();
+        }
+    })
+}
+
+pub async fn server(opts: Opts) {
+    debug!(opts = ?opts, "starting server...");
+    let mut state = opts.common.overlay.map_or_else(State::default, State::with_overlay);
+    if opts.common.swim {
+        state = state.with_membership(membership::Config::default());
+    }
+    if opts.common.hyparview {
+        state = state.with_partial_view(hyparview::Config::default());
+    }
+    state = state.with_read_only(opts.read_only);
+    let state = Arc::new(Mutex::new(state));
+    let (wire, background, mut reader, _) = io_channel_with_background::<Envelope<Routed<Payload>>>();
+    let router = Arc::new(Mutex::new(Router::new(routing::DEFAULT_TTL, FORGET_AFTER)));
+    let writer = routed(router.clone(), wire.clone());
+    let background = routed(router.clone(), background);
+
+    let state_cp = state.clone();
+
+    let scheduler = Scheduler::new();
+    let committing = background.clone();
+    scheduler.spawn(Job::new("commit deltas", Period::TickRate).jitter(DEFAULT_JITTER), move |now| commit_buffered_delta(&state_cp, &committing, now));
+    if opts.common.swim {
+        membership::probe_every_so_often(&scheduler, state.clone(), background.clone());
+    }
+    if opts.common.hyparview {
+        shuffle_every_so_often(&scheduler, state.clone(), background);
+    }
+    let expiring = router.clone();
+    scheduler.spawn(Job::new("routing", Period::Every(FORGET_AFTER / 5)), move |now| {
+        expiring.lock().unwrap().expire(now);
+    });
+
+    while let Some(envelope) = reader.recv().await {
+        let now = scheduler.clock().now();
+        let envelope = match router.lock().unwrap().receive(envelope, now) {
+            Inbound::Handle(envelope) => envelope,
+            Inbound::Send(reply) => {
+                wire.send(reply).unwrap();
+                continue;
+            },
+            Inbound::Drop => continue,
+        };
+        let forwarded = forward_add(&state.lock().unwrap(), &mut router.lock().unwrap(), &envelope, now);
+        if let Some(forwarded) = forwarded {
+            wire.send(forwarded).unwrap();
+            continue;
+        }
+        let handler = format!("handler.{}", metrics::message_type(&envelope.body.message));
+        let span = io::handler_span(&envelope);
+        metrics::timed(&handler, handle_envelope(state.clone(), envelope, writer.clone())).instrument(span).await;
+    }
+}
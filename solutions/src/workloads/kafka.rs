@@ -0,0 +1,790 @@
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use crate::{anti_entropy, cli::CommonOpts, hash_ring::HashRing, io::{self, io_channel}, lease::FencingToken, failure_detector::FailureDetector, log::{Log, ReadLimits, Record, SegmentStore}, message::{self, Envelope, ErrorCode, NodeId}, metrics, routing::Routed, rpc::RpcClient, scheduler::{Job, JobHandle, Period, Scheduler}, storage::{FileStorage, Recovered, Replay, Storage}, task};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, error, info, Instrument};
+use std::{collections::{BTreeMap, HashMap, HashSet, VecDeque}, path::{Path, PathBuf}, sync::atomic::{AtomicUsize, Ordering}, time::Duration};
+use std::sync::{Arc, Mutex};
+use clap::{Parser, ValueEnum};
+
+/// Who owns which keys, and getting requests to them.
+mod partition;
+/// Keys moving to a new owner as nodes join and leave, or for being hot.
+mod handoff;
+/// `--offsets replicated`: copying records to followers, and leaders taking keys over.
+mod replication;
+/// Anti-entropy between replicas of a key's log, with `--repair-interval-ms`.
+mod repair;
+/// Offsets kept in lin-kv, with `--offsets lin-kv`: every record has its own lin-kv key,
+/// taken by whichever `send` creates it first, every key a counter that's never ahead
+/// of the first free offset (so sends know where to start looking), and every client's
+/// committed offset for every key its own lin-kv key too. Nothing's kept on the node,
+/// so clients can send to any of them.
+mod lin_kv;
+
+use partition::FORGET_AFTER;
+pub use partition::{split, Forwarder};
+pub use handoff::REBALANCE_EVERY;
+pub use replication::{handle_shared, watch_peers, Shared};
+pub use repair::{repair_every_so_often, RepairEntry};
+
+
+/// Where committed offsets, and the counters offsets are handed out from, live.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Offsets {
+    /// In this node's memory: fine for one node, or for several with `--partition`.
+    Local,
+    /// In lin-kv, which every node reads and CASes: records, counters and commits alike.
+    LinKv,
+    /// On every node: each key's owner hands out its offsets, and every record and
+    /// commit is copied to all the others, so any node can answer polls.
+    Replicated,
+}
+
+
+/// How many copies of a record, with `--offsets replicated`, its key's leader waits for
+/// before acknowledging the send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Acks {
+    /// Every other node keeping the key, or as many as have taken it in by the timeout.
+    All,
+    /// A majority of the nodes keeping the key (its leader included), or the send times
+    /// out. Any majority has every acknowledged record, so a follower taking over can
+    /// catch up on them from the others.
+    Quorum,
+    /// None: the copies go out in the background, and a leader that dies may take
+    /// acknowledged sends with it.
+    Async,
+}
+
+
+#[derive(Debug, Clone, Parser)]
+#[clap(author, version)]
+pub struct Opts {
+    #[clap(long, help = "Drop committed records that are superseded by a newer committed record with the same sub_key.", env = "COMPACTION")]
+    pub compaction: bool,
+    #[clap(long, default_value_t = 1000, help = "Number of milliseconds to wait between compaction passes.", env = "COMPACTION_INTERVAL_MS")]
+    pub compaction_interval_ms: u64,
+    #[clap(long, default_value_t = 1024, help = "Number of offsets per log segment.", env = "SEGMENT_SIZE")]
+    pub segment_size: usize,
+    #[clap(long, help = "Send at most this many messages per key in a `poll_ok`; clients poll again from where it left off for the rest. No limit if not given.", env = "POLL_MAX_MESSAGES")]
+    pub poll_max_messages: Option<usize>,
+    #[clap(long, help = "Send at most this many bytes of messages per key in a `poll_ok` (though always at least one message). No limit if not given.", env = "POLL_MAX_BYTES")]
+    pub poll_max_bytes: Option<usize>,
+    #[clap(long, value_enum, default_value_t = Offsets::Local, help = "Where committed offsets and the next offset of each key are kept.", env = "OFFSETS")]
+    pub offsets: Offsets,
+    #[clap(long, default_value_t = 1000, help = "Number of milliseconds to wait on lin-kv, or on another node, before giving up on a request.", env = "RPC_TIMEOUT_MS")]
+    pub rpc_timeout_ms: u64,
+    #[clap(long, help = "Give every key an owner among the nodes (by consistent hashing), and forward requests for keys this node doesn't own to their owners.", env = "PARTITION")]
+    pub partition: bool,
+    #[clap(long, help = "With --partition, move keys sent to this many times or more in half a second off a node that owns other such hot keys, so they're spread out. Off if not given.", env = "HOT_KEY_SENDS")]
+    pub hot_key_sends: Option<u64>,
+    #[clap(long, help = "With --offsets replicated, keep each key on its owner on the ring and only this many nodes after it (its followers), rather than on every node. When the owner goes down, the first follower that's up takes over. Every node, if not given.", env = "FOLLOWERS")]
+    pub followers: Option<usize>,
+    #[clap(long, value_enum, default_value_t = Acks::All, help = "With --offsets replicated, how many copies of a record to wait for before acknowledging a send.", env = "ACKS")]
+    pub acks: Acks,
+    #[clap(long, help = "With --offsets replicated, every this many milliseconds, compare the logs this node and a peer picked at random both keep, and copy over whatever records either is missing. Off if not given.", env = "REPAIR_INTERVAL_MS")]
+    pub repair_interval_ms: Option<u64>,
+    #[clap(long, help = "With --offsets lin-kv or replicated, turn away sends to a key that already has this many waiting on lin-kv or other nodes, until some are answered. No limit if not given.", env = "MAX_PENDING_SENDS")]
+    pub max_pending_sends: Option<usize>,
+    #[clap(long, help = "Keep committed offsets in this directory (a write-ahead log plus snapshots), so they survive the node restarting.", env = "DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    #[clap(flatten)]
+    pub common: CommonOpts,
+}
+
+
+static MSG_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Payload {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+    Send {
+        key: String,
+        msg: usize,
+        /// Only meaningful in compaction mode: a newer committed record with
+        /// the same sub_key replaces this one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sub_key: Option<String>,
+        /// Names this send, so that if the client retries it (with the same token) it's
+        /// only appended once, and the retry gets the offset the first one did. Clients
+        /// should make them unique across clients, too. Without one, only duplicate
+        /// deliveries of a send (same client, same `msg_id`) are caught.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    SendOk {
+        offset: usize,
+    },
+    Poll {
+        offsets: HashMap<String, usize>,
+    },
+    PollOk {
+        msgs: HashMap<String, Vec<(usize, usize)>>,
+    },
+    CommitOffsets {
+        offsets: HashMap<String, usize>,
+    },
+    CommitOffsetsOk,
+    ListCommittedOffsets {
+        keys: Vec<String>,
+    },
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, usize>,
+    },
+    Error {
+        code: usize,
+        text: String,
+    },
+
+    // What we speak with lin-kv, with `--offsets lin-kv`.
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        create_if_not_exists: Option<bool>,
+    },
+    CasOk,
+
+    // Between nodes, with `--offsets replicated`: a key's owner copying a record it's
+    // appended, and any node copying a commit.
+    Replicate {
+        key: String,
+        offset: usize,
+        msg: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sub_key: Option<String>,
+        /// With `--followers`, the epoch of the leader that appended it, and the offset
+        /// it's led the key since (see [`State::replicate_from`]).
+        #[serde(default)]
+        epoch: FencingToken,
+        #[serde(default)]
+        since: usize,
+    },
+    ReplicateOk,
+    ReplicateCommit {
+        client: String,
+        offsets: HashMap<String, usize>,
+    },
+    ReplicateCommitOk,
+    /// From a follower that's taking over a key (as its leader at `epoch`), or missed
+    /// some of its records: the records from `from` on.
+    CatchUp {
+        key: String,
+        from: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        epoch: Option<FencingToken>,
+    },
+    CatchUpOk {
+        records: Vec<Record>,
+    },
+    /// From a replica that's heard of a newer leader of `key`, at `epoch`, than a
+    /// `replicate` or takeover came from: turned away.
+    Fenced {
+        key: String,
+        epoch: FencingToken,
+    },
+    /// With `--repair-interval-ms`, a step of a round of repair of the keys two nodes
+    /// both keep, and the answer to one.
+    Repair {
+        repair: anti_entropy::Message<RepairEntry>,
+    },
+    RepairOk {
+        repair: anti_entropy::Message<RepairEntry>,
+    },
+    /// With `--followers`, to tell whether nodes are up.
+    Ping,
+    PingOk,
+
+    /// From an admin: `node_id` has joined the cluster.
+    Join {
+        node_id: String,
+    },
+    JoinOk,
+    /// From an admin: `node_id` has left the cluster.
+    Leave {
+        node_id: String,
+    },
+    LeaveOk,
+
+    // Between nodes, with `--partition`: a key moving to a new owner.
+    HandOff {
+        key: String,
+        /// How many times the key's changed hands, so a late copy of an earlier handoff
+        /// is told apart from a newer one.
+        generation: u64,
+        /// Its log, if the sender has one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        log: Option<Box<Log>>,
+        /// What each client's committed for it.
+        #[serde(default)]
+        committed: HashMap<String, usize>,
+        /// Where the key is, if the sender doesn't have it but knows who it moved it to
+        /// for being hot: the receiver, its new owner on the ring, sends requests there.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        moved_to: Option<String>,
+        /// Whether it's moving for being hot, so the receiver keeps it (rather than
+        /// handing it to whoever the ring says owns it).
+        #[serde(default)]
+        hot: bool,
+    },
+    HandOffOk {
+        key: String,
+    },
+}
+
+fn message_id() -> usize {
+    MSG_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+/// How many sends' tokens (and the offsets they got) a node remembers, to answer
+/// retries of them.
+const REMEMBERED_SENDS: usize = 100_000;
+
+/// How many offset commits are journaled before they're folded into a snapshot.
+const CHECKPOINT_AFTER: u64 = 1000;
+
+
+/// A client committing offsets: what's journaled before the commit is acknowledged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OffsetCommit {
+    pub client: String,
+    pub offsets: HashMap<String, usize>,
+}
+
+
+/// The offsets each client has committed, by key. Every client has its own, so one
+/// committing (or lagging behind) doesn't move another's; a commit never moves a
+/// client's offset for a key backwards.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommittedOffsets {
+    by_client: HashMap<String, HashMap<String, usize>>,
+}
+
+impl Replay<OffsetCommit> for CommittedOffsets {
+    fn apply(&mut self, commit: &OffsetCommit) {
+        let committed = self.by_client.entry(commit.client.clone()).or_default();
+        for (key, &offset) in &commit.offsets {
+            let current = committed.entry(key.clone()).or_default();
+            *current = (*current).max(offset);
+        }
+    }
+}
+
+impl CommittedOffsets {
+    /// The offsets `client` has committed, for whichever of `keys` it has.
+    pub fn list(&self, client: &str, keys: &[String]) -> HashMap<String, usize> {
+        let Some(committed) = self.by_client.get(client) else {
+            return HashMap::new();
+        };
+        keys.iter().filter_map(|key| committed.get(key).map(|&offset| (key.clone(), offset))).collect()
+    }
+
+    /// The lowest offset any client has committed for `key`: what every client that's
+    /// committed it is done with.
+    pub fn lowest(&self, key: &str) -> Option<usize> {
+        self.by_client.values().filter_map(|committed| committed.get(key).copied()).min()
+    }
+
+    /// Whether any client has committed an offset for `key`.
+    pub fn has(&self, key: &str) -> bool {
+        self.by_client.values().any(|committed| committed.contains_key(key))
+    }
+
+    /// Every key some client has committed an offset for.
+    pub fn keys(&self) -> HashSet<&String> {
+        self.by_client.values().flat_map(|committed| committed.keys()).collect()
+    }
+
+    /// What each client has committed for `key`, forgetting it here.
+    pub fn take(&mut self, key: &str) -> HashMap<String, usize> {
+        self.by_client.iter_mut().filter_map(|(client, committed)| committed.remove(key).map(|offset| (client.clone(), offset))).collect()
+    }
+}
+
+
+/// The offsets recent sends got, by their tokens (see [`send_token`]), so a retry gets
+/// the same one rather than being appended again. The oldest are forgotten past
+/// [`REMEMBERED_SENDS`].
+#[derive(Debug, Default)]
+struct RecentSends {
+    offsets: HashMap<String, usize>,
+    order: VecDeque<String>,
+}
+
+impl RecentSends {
+    fn get(&self, token: &str) -> Option<usize> {
+        self.offsets.get(token).copied()
+    }
+
+    fn forget(&mut self, token: &str) {
+        if self.offsets.remove(token).is_some() {
+            self.order.retain(|remembered| remembered != token);
+        }
+    }
+
+    fn insert(&mut self, token: String, offset: usize) {
+        if self.offsets.insert(token.clone(), offset).is_some() {
+            return;
+        }
+        self.order.push_back(token);
+        if self.order.len() > REMEMBERED_SENDS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.offsets.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// What a `send` in `envelope` is remembered by: its token, or who sent it and its
+/// `msg_id` if it hasn't got one. `None` if it has neither a token nor a `msg_id`.
+pub fn send_token(envelope: &Envelope<Payload>) -> Option<String> {
+    match &envelope.body.message {
+        Payload::Send { token: Some(token), .. } => Some(token.clone()),
+        Payload::Send { .. } => envelope.body.msg_id.map(|msg_id| format!("{}#{msg_id}", envelope.source)),
+        _ => None,
+    }
+}
+
+
+/// How often each key's rates and lag are worked out.
+pub const KEY_STATS_EVERY: Duration = Duration::from_secs(1);
+
+/// How many keys, busiest first, get metrics of their own.
+pub const REPORTED_KEYS: usize = 32;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct KeyActivity {
+    appends: u64,
+    polls: u64,
+}
+
+
+#[derive(Debug, Default)]
+pub struct State {
+    my_id: String,
+    logs: SegmentStore,
+    committed_offsets: CommittedOffsets,
+    /// Where committed offsets are journaled, with `--data-dir`.
+    storage: Option<FileStorage>,
+    /// How much of each key's log a `poll` gets back.
+    poll_limits: ReadLimits,
+    /// Whether keys have owners, with `--partition`, and who, once initialized.
+    partition: bool,
+    ring: Option<HashRing>,
+    /// Every other node.
+    peers: Vec<NodeId>,
+    /// Records copied here by their keys' owners (with `--offsets replicated`) that
+    /// arrived ahead of an earlier one, by key and offset, held back until the gap's
+    /// filled so polls never skip anything.
+    ahead: HashMap<String, BTreeMap<usize, (usize, Option<String>)>>,
+    recent_sends: RecentSends,
+    /// With `--partition`, the ring as it was before the last node joined or left: keys
+    /// it gave to another node may not have been handed over yet.
+    previous_ring: Option<HashRing>,
+    /// Keys being handed over, with the handoff (sent again until it's acknowledged)
+    /// and who to. Sends and commits for them are turned away until then, so nobody
+    /// hands out an offset the new owner doesn't know about.
+    handing_off: HashMap<String, (NodeId, Payload)>,
+    /// Where keys this node's handed over went, so requests for them can follow. For
+    /// hot keys moved off their owner on the ring, the owner's is the one that counts.
+    moved_to: HashMap<String, NodeId>,
+    /// How many times each key this node has had has changed hands.
+    generations: HashMap<String, u64>,
+    /// Hot keys moved here, kept here rather than handed to their owner on the ring.
+    kept: HashSet<String>,
+    /// Sends to each key since the last rebalance, and how many make it hot, with
+    /// `--hot-key-sends`.
+    heat: HashMap<String, u64>,
+    hot_key_sends: Option<u64>,
+    /// Appends and polls of each key since [`record_key_stats`](Self::record_key_stats)
+    /// last ran, and before that, and the keys it reported on then.
+    activity: HashMap<String, KeyActivity>,
+    key_totals: HashMap<String, KeyActivity>,
+    reported: HashSet<String>,
+    /// Sends to each key that haven't been answered yet, with `--offsets lin-kv` or
+    /// `replicated`, and how many there can be, with `--max-pending-sends`.
+    pending_sends: HashMap<String, usize>,
+    max_pending_sends: Option<usize>,
+    /// With `--followers`, how many nodes after its owner on the ring each key's kept
+    /// on, the peers taken to be down, and the keys this node has taken over as leader
+    /// (having caught up on them first), with the offset it's led each since.
+    followers: Option<usize>,
+    down: HashSet<NodeId>,
+    leading: HashMap<String, usize>,
+    /// With `--followers`, the newest leader epoch heard of for each key (every takeover
+    /// starts a new one), and the epoch of the leader each key's log was last copied
+    /// from, with the offset that leader's led it since.
+    epochs: HashMap<String, FencingToken>,
+    log_epochs: HashMap<String, (FencingToken, usize)>,
+}
+
+impl State {
+    pub fn new(segment_size: usize) -> Self {
+        Self { logs: SegmentStore::new(segment_size), ..Default::default() }
+    }
+
+    /// Give every key an owner among the nodes, by consistent hashing, once initialized.
+    /// Requests for keys owned elsewhere are left to a [`Forwarder`] to get there.
+    pub fn with_partitioning(mut self) -> Self {
+        self.partition = true;
+        self
+    }
+
+    /// Move hot keys off a node that owns others: a key sent to `sends` times or more
+    /// between rebalances is hot.
+    pub fn with_hot_keys(mut self, sends: u64) -> Self {
+        self.hot_key_sends = Some(sends);
+        self
+    }
+
+    /// Turn away sends to a key that already has `sends` waiting to be answered.
+    pub fn with_max_pending_sends(mut self, sends: usize) -> Self {
+        self.max_pending_sends = Some(sends);
+        self
+    }
+
+    /// Keep each key on its owner and `followers` nodes after it on the ring, rather
+    /// than on every node, with `--offsets replicated`.
+    pub fn with_followers(mut self, followers: usize) -> Self {
+        self.followers = Some(followers);
+        self
+    }
+
+    /// The records of `key` from `from` on, to catch another node up.
+    fn records_from(&self, key: &str, from: usize) -> Vec<Record> {
+        self.logs.get(key).map_or(vec![], |log| log.read_from(from).cloned().collect())
+    }
+
+    /// Append `msg` to `key`'s log, unless a send with the same `token` already has:
+    /// the offset it got, either way.
+    pub fn append(&mut self, key: &str, msg: usize, sub_key: Option<String>, token: Option<String>) -> usize {
+        if let Some(offset) = token.as_deref().and_then(|token| self.recent_sends.get(token)) {
+            debug!(key, offset, "send already appended, not appending it again");
+            metrics::global().increment("kafka.duplicate_sends");
+            return offset;
+        }
+        let offset = self.logs.append(key, msg, sub_key);
+        *self.heat.entry(key.to_owned()).or_default() += 1;
+        self.appended(key);
+        if let Some(token) = token {
+            self.recent_sends.insert(token, offset);
+        }
+        offset
+    }
+
+    /// Count an append to `key` towards its stats.
+    pub fn appended(&mut self, key: &str) {
+        self.activity.entry(key.to_owned()).or_default().appends += 1;
+    }
+
+    /// Count a poll of `keys` towards their stats.
+    pub fn polled<'a>(&mut self, keys: impl IntoIterator<Item = &'a String>) {
+        for key in keys {
+            self.activity.entry(key.clone()).or_default().polls += 1;
+        }
+    }
+
+    /// Publish the metrics of the [`REPORTED_KEYS`] busiest keys over the `elapsed` since
+    /// the last time: how many appends and polls each has had, how many a second, and how
+    /// many of its records the furthest-behind client that's committed it hasn't (all of
+    /// them, if none has). Keys that have gone quiet drop to zero, and the metrics of keys
+    /// no longer among the busiest, or that this node no longer has, are forgotten.
+    pub fn record_key_stats(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+        let per_second = |count: u64| (count as f64 / seconds).round() as i64;
+        let activity = std::mem::take(&mut self.activity);
+        let keys: HashSet<String> = self.logs.keys().chain(activity.keys()).cloned().collect();
+        self.key_totals.retain(|key, _| keys.contains(key));
+        for (key, KeyActivity { appends, polls }) in &activity {
+            let total = self.key_totals.entry(key.clone()).or_default();
+            total.appends += appends;
+            total.polls += polls;
+        }
+        let busyness = |key: &String| activity.get(key).map_or(0, |activity| activity.appends + activity.polls);
+        let mut busiest: Vec<&String> = keys.iter().collect();
+        busiest.sort_by(|a, b| busyness(b).cmp(&busyness(a)).then(a.cmp(b)));
+        let reported: HashSet<String> = busiest.into_iter().take(REPORTED_KEYS).cloned().collect();
+
+        let registry = metrics::global();
+        for key in self.reported.difference(&reported) {
+            for metric in ["appends", "polls", "appends_per_sec", "polls_per_sec", "lag"] {
+                registry.remove(&format!("kafka.key.{key}.{metric}"));
+            }
+        }
+        for key in &reported {
+            let KeyActivity { appends, polls } = activity.get(key).copied().unwrap_or_default();
+            // A key that's just made the cut counts from its first append or poll.
+            let counted = if self.reported.contains(key) {
+                KeyActivity { appends, polls }
+            } else {
+                self.key_totals.get(key).copied().unwrap_or_default()
+            };
+            let next = self.logs.get(key).map_or(0, |log| log.next_offset());
+            let lag = match self.committed_offsets.lowest(key) {
+                Some(committed) => next.saturating_sub(committed + 1),
+                None => next,
+            };
+            registry.add(&format!("kafka.key.{key}.appends"), counted.appends);
+            registry.add(&format!("kafka.key.{key}.polls"), counted.polls);
+            registry.set(&format!("kafka.key.{key}.appends_per_sec"), per_second(appends));
+            registry.set(&format!("kafka.key.{key}.polls_per_sec"), per_second(polls));
+            registry.set(&format!("kafka.key.{key}.lag"), lag as i64);
+        }
+        self.reported = reported;
+    }
+
+    /// Answer polls with no more of each key's log than `limits` allow.
+    pub fn with_poll_limits(mut self, limits: ReadLimits) -> Self {
+        self.poll_limits = limits;
+        self
+    }
+
+    /// Journal committed offsets in `dir`, picking up whatever was committed there before
+    /// the node last stopped.
+    pub fn with_storage(mut self, dir: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut storage = FileStorage::open(dir)?;
+        let Recovered { snapshot, records } = Storage::<CommittedOffsets, OffsetCommit>::recover(&mut storage)?;
+        self.committed_offsets = snapshot.unwrap_or_default();
+        for commit in &records {
+            self.committed_offsets.apply(commit);
+        }
+        self.storage = Some(storage);
+        Ok(self)
+    }
+
+    /// Journal `commit` (if there's anywhere to), then apply it.
+    fn commit(&mut self, commit: OffsetCommit) -> std::io::Result<()> {
+        if let Some(storage) = self.storage.as_mut() {
+            Storage::<CommittedOffsets, OffsetCommit>::append(storage, &commit)?;
+        }
+        self.committed_offsets.apply(&commit);
+        if let Some(storage) = self.storage.as_mut().filter(|storage| Storage::<CommittedOffsets, OffsetCommit>::uncheckpointed(*storage) >= CHECKPOINT_AFTER) {
+            // The journal still has every commit if this fails; it's tried again on the next.
+            if let Err(err) = Storage::<CommittedOffsets, OffsetCommit>::checkpoint(storage, &self.committed_offsets) {
+                error!(error = ?err, "failed to checkpoint committed offsets");
+            }
+        }
+        Ok(())
+    }
+}
+
+
+pub async fn handle_envelope(
+    state: Arc<Mutex<State>>,
+    envelope: Envelope<Payload>,
+    writer: UnboundedSender<Envelope<Payload>>
+) {
+    let reply = reply(&mut state.lock().unwrap(), &envelope);
+    if let Some(reply) = reply {
+        writer.send(envelope.reply_with(Some(message_id()), reply)).unwrap();
+    }
+}
+
+/// What to answer `envelope` with, having done what it asks (on this node, whoever
+/// owns its keys).
+pub fn reply(state: &mut State, envelope: &Envelope<Payload>) -> Option<Payload> {
+    if let Some(error) = state.frozen(&envelope.body.message) {
+        return Some(error);
+    }
+    match &envelope.body.message {
+        Payload::Init { node_id, node_ids } => {
+            state.my_id = node_id.clone();
+            state.peers = node_ids.iter().filter(|&other| other != node_id).map(|other| message::intern(other)).collect();
+            if state.partition {
+                state.ring = Some(HashRing::new(node_ids));
+            }
+            Some(Payload::InitOk)
+        },
+        Payload::Send { key, msg, sub_key, .. } => {
+            let offset = state.append(key, *msg, sub_key.clone(), send_token(envelope));
+            Some(Payload::SendOk { offset })
+        },
+        Payload::Poll { offsets } => {
+            state.polled(offsets.keys());
+            let msgs: HashMap<String, Vec<(usize, usize)>> =
+                offsets
+                .iter()
+                .map(|(key, &offset)| (key.clone(), state.logs.read_limited(key, offset, state.poll_limits)))
+                .collect();
+            let truncated = msgs.iter().filter(|(key, read)| {
+                let next = read.last().map_or(offsets[*key], |&(offset, _)| offset + 1);
+                state.logs.get(key).is_some_and(|log| log.read_from(next).next().is_some())
+            }).count();
+            metrics::global().add("kafka.polls_truncated", truncated as u64);
+            Some(Payload::PollOk { msgs })
+        },
+        Payload::CommitOffsets { offsets } => {
+            let commit = OffsetCommit { client: envelope.source.to_string(), offsets: offsets.clone() };
+            match state.commit(commit) {
+                Ok(()) => Some(Payload::CommitOffsetsOk),
+                Err(err) => {
+                    // Not committed, so the client can try again.
+                    error!(error = ?err, "failed to journal committed offsets");
+                    Some(Payload::Error { code: ErrorCode::TemporarilyUnavailable.code(), text: format!("couldn't journal the commit: {err}") })
+                },
+            }
+        },
+        Payload::ListCommittedOffsets { keys } => {
+            let offsets = state.committed_offsets.list(&envelope.source, keys);
+            Some(Payload::ListCommittedOffsetsOk { offsets })
+        },
+        Payload::Replicate { key, offset, msg, sub_key, epoch, since } => {
+            if !state.observe_epoch(key, *epoch) {
+                debug!(key, offset, %epoch, "turning away a record from a deposed leader");
+                return Some(Payload::Fenced { key: key.clone(), epoch: state.epoch(key) });
+            }
+            // Whoever sent it is leading the key now.
+            state.leading.remove(key);
+            state.replicate_from(key, *epoch, *since, *offset, *msg, sub_key.clone());
+            Some(Payload::ReplicateOk)
+        },
+        Payload::CatchUp { key, epoch: Some(epoch), .. } if !state.promise(key, *epoch) => Some(Payload::Fenced { key: key.clone(), epoch: state.epoch(key) }),
+        Payload::CatchUp { key, from, .. } => Some(Payload::CatchUpOk { records: state.records_from(key, *from) }),
+        Payload::Repair { repair } => anti_entropy::answer(state, &envelope.source, repair.clone()).map(|repair| Payload::RepairOk { repair }),
+        Payload::Ping => Some(Payload::PingOk),
+        Payload::Join { node_id } => {
+            info!(node = node_id, "node joined the cluster");
+            state.change_membership(node_id, true);
+            Some(Payload::JoinOk)
+        },
+        Payload::Leave { node_id } => {
+            info!(node = node_id, "node left the cluster");
+            state.change_membership(node_id, false);
+            Some(Payload::LeaveOk)
+        },
+        handoff @ Payload::HandOff { key, .. } => match state.take_over(&envelope.source, handoff) {
+            Ok(()) => Some(Payload::HandOffOk { key: key.clone() }),
+            Err(err) => Some(Payload::Error { code: ErrorCode::TemporarilyUnavailable.code(), text: format!("couldn't journal the committed offsets: {err}") }),
+        },
+        Payload::HandOffOk { key } => {
+            state.handed_off(key, &envelope.source);
+            None
+        },
+        Payload::ReplicateCommit { client, offsets } => {
+            let commit = OffsetCommit { client: client.clone(), offsets: offsets.clone() };
+            match state.commit(commit) {
+                Ok(()) => Some(Payload::ReplicateCommitOk),
+                Err(err) => Some(Payload::Error { code: ErrorCode::TemporarilyUnavailable.code(), text: format!("couldn't journal the commit: {err}") }),
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Compact the logs every `interval`, as a `compaction` job on `scheduler`.
+pub fn compact_every_so_often(
+    scheduler: &Scheduler,
+    state: Arc<Mutex<State>>,
+    interval: Duration,
+) -> JobHandle {
+    scheduler.spawn(Job::new("compaction", Period::Every(interval)), move |_| {
+        let mut state = state.lock().unwrap();
+        let State { logs, committed_offsets, .. } = &mut *state;
+        let removed = logs.compact(|key| committed_offsets.lowest(key));
+        if removed > 0 {
+            debug!(removed, "compacted superseded records");
+        }
+    })
+}
+
+pub async fn server(opts: Opts) {
+    debug!(opts = ?opts, "starting server...");
+    let mut state = State::new(opts.segment_size).with_poll_limits(ReadLimits { max_records: opts.poll_max_messages, max_bytes: opts.poll_max_bytes });
+    if let Some(dir) = &opts.data_dir {
+        state = state.with_storage(dir).unwrap_or_else(|err| panic!("can't recover committed offsets from {}: {err}", dir.display()));
+    }
+    // With `--offsets replicated`, every key's owner is who hands out its offsets.
+    if opts.partition || opts.offsets == Offsets::Replicated {
+        state = state.with_partitioning();
+    }
+    if let Some(sends) = opts.hot_key_sends {
+        state = state.with_hot_keys(sends);
+    }
+    if let Some(sends) = opts.max_pending_sends {
+        state = state.with_max_pending_sends(sends);
+    }
+    if let Some(followers) = opts.followers.filter(|_| opts.offsets == Offsets::Replicated) {
+        state = state.with_followers(followers);
+    }
+    let state = Arc::new(Mutex::new(state));
+    let forwarder = Arc::new(Mutex::new(Forwarder::default()));
+    let (writer, mut reader, _) = io_channel::<Envelope<Routed<Payload>>>();
+    let rpc = RpcClient::new(writer.clone(), message_id);
+    let shared = Shared::new(opts.offsets, state.clone(), rpc.clone(), Duration::from_millis(opts.rpc_timeout_ms)).with_acks(opts.acks);
+    // With `--followers`, who's down decides who leads each key; set up once the peers
+    // are known.
+    let mut detector: Option<Arc<Mutex<FailureDetector>>> = None;
+
+    let scheduler = Scheduler::new();
+    if opts.compaction {
+        compact_every_so_often(&scheduler, state.clone(), Duration::from_millis(opts.compaction_interval_ms));
+    }
+    if let Some(interval) = opts.repair_interval_ms.filter(|_| opts.offsets == Offsets::Replicated) {
+        repair_every_so_often(&scheduler, Duration::from_millis(interval), shared.clone());
+    }
+    let expiring = forwarder.clone();
+    scheduler.spawn(Job::new("routing", Period::Every(FORGET_AFTER / 6)), move |now| expiring.lock().unwrap().expire(now));
+    let (stats, mut last) = (state.clone(), scheduler.clock().now());
+    scheduler.spawn(Job::new("key stats", Period::Every(KEY_STATS_EVERY)), move |now| {
+        stats.lock().unwrap().record_key_stats(now.saturating_duration_since(last));
+        last = now;
+    });
+    if opts.partition && opts.offsets == Offsets::Local {
+        let (forwarder, state, writer) = (forwarder.clone(), state.clone(), writer.clone());
+        scheduler.spawn(Job::new("rebalance", Period::Every(REBALANCE_EVERY)), move |_| {
+            for handoff in forwarder.lock().unwrap().rebalance(&mut state.lock().unwrap()) {
+                writer.send(handoff).unwrap();
+            }
+        });
+    }
+
+    while let Some(envelope) = reader.recv().await {
+        if let Some(detector) = &detector {
+            detector.lock().unwrap().heard_from(&envelope.source, scheduler.clock().now());
+        }
+        let Some(envelope) = rpc.try_complete(envelope) else {
+            continue;
+        };
+        if let Payload::Init { node_id, node_ids } = &envelope.body.message.message {
+            rpc.set_node_id(node_id);
+            if opts.followers.is_some() && opts.offsets == Offsets::Replicated {
+                detector = Some(watch_peers(&scheduler, state.clone(), writer.clone(), node_id, node_ids));
+            }
+        }
+        let handler = format!("handler.{}", metrics::message_type(&envelope.body.message));
+        let span = io::handler_span(&envelope);
+        if opts.offsets != Offsets::Local {
+            // These wait on lin-kv or other nodes, whose replies only arrive through
+            // this loop, so they can't be awaited inline.
+            let handled = handle_shared(shared.clone(), envelope.with_message(envelope.body.message.message.clone()), writer.clone()).instrument(span);
+            let name = handler.clone();
+            task::spawn(&name, async move { metrics::timed(&handler, handled).await });
+            continue;
+        }
+        let sends = metrics::timed(&handler, async {
+            forwarder.lock().unwrap().dispatch(&mut state.lock().unwrap(), envelope, scheduler.clock().now())
+        }).instrument(span).await;
+        for envelope in sends {
+            writer.send(envelope).unwrap();
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{message::{self, ErrorCode, NodeId}, metrics};
+use tracing::{debug, info};
+use std::{collections::{HashMap, HashSet}, time::Duration};
+use super::{OffsetCommit, Payload, State};
+
+
+/// How often, with `--partition`, nodes hand keys that belong elsewhere over to their
+/// owners (and send handoffs that haven't been acknowledged again), and look for hot
+/// keys.
+pub const REBALANCE_EVERY: Duration = Duration::from_millis(500);
+
+
+impl State {
+    /// Make room for a node that's joined, or drop one that's left, handing keys over
+    /// to their new owners on the next rebalance. The joining node's told too, and may
+    /// have been on the ring from the start; it's taken not to have been before.
+    pub(super) fn change_membership(&mut self, node: &str, joined: bool) {
+        let Some(ring) = self.ring.as_mut() else {
+            return;
+        };
+        let mut previous = ring.clone();
+        if joined {
+            previous.remove(node);
+            ring.add(node);
+        } else {
+            previous.add(node);
+            ring.remove(node);
+        }
+        let ring = ring.clone();
+        self.moved_to.retain(|_, moved_to| ring.contains(moved_to));
+        self.previous_ring = Some(previous);
+    }
+
+    /// The handoffs to send: keys this node has that belong elsewhere, hot keys it has
+    /// too many of, and where hot keys it moved have gone if it's no longer their
+    /// owner on the ring, plus any sent before that haven't been acknowledged.
+    pub fn rebalance(&mut self) -> Vec<(NodeId, Payload)> {
+        let Some(ring) = self.ring.clone() else {
+            return vec![];
+        };
+        if !ring.contains(&self.my_id) {
+            self.kept.clear();
+        }
+        let mut held: Vec<String> = self.logs.keys().chain(self.committed_offsets.keys()).cloned().collect::<HashSet<_>>().into_iter()
+            .filter(|key| !self.handing_off.contains_key(key))
+            .collect();
+        held.sort_by_key(|key| std::cmp::Reverse(self.heat.get(key).copied().unwrap_or(0)));
+
+        // The hottest key stays put; any others go to the next node along the ring each.
+        let hot_key_sends = self.hot_key_sends.unwrap_or(u64::MAX);
+        let hot: Vec<String> = held.iter().filter(|key| !self.kept.contains(*key) && self.heat.get(*key).is_some_and(|&sends| sends >= hot_key_sends)).cloned().collect();
+        self.heat.clear();
+        let mut taken = vec![];
+        for key in hot.iter().skip(1) {
+            let Some(to) = ring.owners(key, ring.len()).into_iter().find(|node| self.other(node).is_some() && !taken.contains(*node)).cloned() else {
+                continue;
+            };
+            info!(key, to = %to, "moving a hot key");
+            metrics::global().increment("kafka.hot_keys_moved");
+            taken.push(to.clone());
+            self.hand_off(key, to, true);
+        }
+        let misplaced: Vec<(String, NodeId)> = held.iter()
+            .filter(|&key| !self.kept.contains(key) && !self.handing_off.contains_key(key))
+            .filter_map(|key| self.ring_owner(key).map(|owner| (key.clone(), owner)))
+            .collect();
+        for (key, owner) in misplaced {
+            debug!(key, to = %owner, "handing a key over to its owner");
+            self.hand_off(&key, owner, false);
+        }
+
+        let mut sends: Vec<(NodeId, Payload)> = self.handing_off.values().map(|(to, handoff)| (to.clone(), handoff.clone())).collect();
+        for (key, moved_to) in &self.moved_to {
+            let Some(owner) = self.ring_owner(key) else {
+                continue;
+            };
+            if owner != *moved_to {
+                let handoff = Payload::HandOff { key: key.clone(), generation: 0, log: None, committed: HashMap::new(), moved_to: Some(moved_to.to_string()), hot: false };
+                sends.push((owner, handoff));
+            }
+        }
+        sends
+    }
+
+    /// Start handing `key` over to `to`: it's frozen here until they acknowledge it.
+    fn hand_off(&mut self, key: &str, to: NodeId, hot: bool) {
+        let generation = self.generations.get(key).copied().unwrap_or(0) + 1;
+        let log = self.logs.get(key).cloned().map(Box::new);
+        let committed = self.committed_offsets.by_client.iter().filter_map(|(client, committed)| committed.get(key).map(|&offset| (client.clone(), offset))).collect();
+        let handoff = Payload::HandOff { key: key.to_owned(), generation, log, committed, moved_to: None, hot };
+        self.handing_off.insert(key.to_owned(), (to, handoff));
+    }
+
+    /// Take over `key` from `from`, if this handoff is newer than any this node's seen.
+    /// Fails if its committed offsets can't be journaled.
+    pub(super) fn take_over(&mut self, from: &NodeId, handoff: &Payload) -> std::io::Result<()> {
+        let Payload::HandOff { key, generation, log, committed, moved_to, hot } = handoff else {
+            return Ok(());
+        };
+        if let Some(moved_to) = moved_to {
+            // Only where a hot key's gone: this node's its owner on the ring now.
+            if !self.holds(key) && *moved_to != self.my_id {
+                self.moved_to.insert(key.clone(), message::intern(moved_to));
+            }
+            return Ok(());
+        }
+        if self.generations.get(key).is_some_and(|&known| known >= *generation) {
+            debug!(key, generation, from = %from, "already had this handoff");
+            return Ok(());
+        }
+        let commit = |client: &String, &offset| OffsetCommit { client: client.clone(), offsets: HashMap::from([(key.clone(), offset)]) };
+        for (client, offset) in committed {
+            self.commit(commit(client, offset))?;
+        }
+        if let Some(log) = log {
+            self.logs.insert(key, (**log).clone());
+        }
+        info!(key, generation, from = %from, "took over a key");
+        self.generations.insert(key.clone(), *generation);
+        self.moved_to.remove(key);
+        if *hot {
+            self.kept.insert(key.clone());
+        }
+        Ok(())
+    }
+
+    /// `to` has taken over `key`: forget it here, and send requests for it their way.
+    pub(super) fn handed_off(&mut self, key: &str, to: &NodeId) {
+        if !self.handing_off.get(key).is_some_and(|(handing_to, _)| handing_to == to) {
+            // Where a hot key's gone has been passed on to its new owner on the ring,
+            // who'll point requests the right way from now on.
+            if self.moved_to.contains_key(key) && !self.holds(key) {
+                self.moved_to.insert(key.to_owned(), to.clone());
+            }
+            return;
+        }
+        if let Some((_, Payload::HandOff { generation, .. })) = self.handing_off.remove(key) {
+            self.generations.insert(key.to_owned(), generation);
+        }
+        // Their committed offsets stay in the journal, with `--data-dir`, so a node that
+        // restarts after handing a key over takes it for its own again.
+        self.logs.take(key);
+        self.committed_offsets.take(key);
+        self.kept.remove(key);
+        self.moved_to.insert(key.to_owned(), to.clone());
+        metrics::global().increment("kafka.keys_handed_off");
+    }
+
+    /// An error for a send or commit touching a key that's being handed over.
+    pub(super) fn frozen(&self, request: &Payload) -> Option<Payload> {
+        let keys: Vec<&String> = match request {
+            Payload::Send { key, .. } => vec![key],
+            Payload::CommitOffsets { offsets } => offsets.keys().collect(),
+            _ => vec![],
+        };
+        let (key, (to, _)) = keys.into_iter().find_map(|key| self.handing_off.get(key).map(|handoff| (key, handoff)))?;
+        Some(Payload::Error { code: ErrorCode::TemporarilyUnavailable.code(), text: format!("{key} is being handed over to {to}") })
+    }
+}
@@ -0,0 +1,114 @@
+use serde_json::json;
+use crate::{kv::{KvClient, KvError}, log::ReadLimits, message::ErrorCode, metrics, routing::Routed};
+use tracing::warn;
+use std::collections::HashMap;
+use super::Payload;
+
+
+/// With `--offsets lin-kv`, how much of a key's log a `poll` reads unless
+/// `--poll-max-messages` says otherwise: each record is a read of its own.
+const LIN_KV_POLL_BATCH: usize = 32;
+
+
+fn next_offset(key: &str) -> String {
+    format!("next/{key}")
+}
+
+fn record(key: &str, offset: usize) -> String {
+    format!("msg/{key}/{offset}")
+}
+
+fn committed(client: &str, key: &str) -> String {
+    format!("committed/{client}/{key}")
+}
+
+fn sent(token: &str) -> String {
+    format!("sent/{token}")
+}
+
+/// Append `msg` to `key`'s log, unless a send with the same `token` already has.
+/// The record is created at the first offset from the key's counter on that isn't
+/// taken, and only then is the counter moved past it, so a send that fails partway
+/// leaves a whole record or nothing, never an offset without a record. Two tries at
+/// the same send that are in flight at once can still both be appended; it's retries
+/// after a timeout this catches.
+pub async fn send(kv: &KvClient<Routed<Payload>>, key: &str, msg: usize, token: Option<String>) -> Result<usize, KvError> {
+    if let Some(token) = &token {
+        if let Some(offset) = kv.read(sent(token)).await? {
+            metrics::global().increment("kafka.duplicate_sends");
+            return Ok(offset);
+        }
+    }
+    let mut offset = kv.read(next_offset(key)).await?.unwrap_or(0);
+    loop {
+        // Records are never negative, so this only goes through if there's no record yet.
+        match kv.cas(record(key, offset), json!(-1), json!(msg), true).await {
+            Ok(()) => break,
+            Err(err) if err.is(ErrorCode::PreconditionFailed) => {
+                metrics::global().increment("kafka.offset_conflicts");
+                offset += 1;
+            },
+            Err(err) => return Err(err),
+        }
+    }
+    // The record's in the log either way; a counter left behind only costs the next
+    // send to this key a few more tries.
+    if let Err(err) = raise(kv, next_offset(key), offset + 1).await {
+        warn!(key, offset, error = %err, "failed to move the next offset past a record");
+    }
+    if let Some(token) = &token {
+        kv.write(sent(token), offset).await?;
+    }
+    Ok(offset)
+}
+
+/// The records at or after each key's offset, as far as `limits` allow (and no
+/// further than [`LIN_KV_POLL_BATCH`] if they don't say). Records are only ever
+/// created at the first free offset, so the first one missing is the end of the log.
+pub async fn poll(kv: &KvClient<Routed<Payload>>, offsets: &HashMap<String, usize>, limits: ReadLimits) -> Result<HashMap<String, Vec<(usize, usize)>>, KvError> {
+    let batch = limits.max_records.unwrap_or(LIN_KV_POLL_BATCH);
+    let mut msgs = HashMap::new();
+    for (key, &from) in offsets {
+        let mut records = vec![];
+        for offset in (from..).take(batch.max(1)) {
+            let Some(msg) = kv.read(record(key, offset)).await? else {
+                break;
+            };
+            records.push((offset, msg));
+        }
+        msgs.insert(key.clone(), limits.apply(records.into_iter()));
+    }
+    Ok(msgs)
+}
+
+pub async fn commit(kv: &KvClient<Routed<Payload>>, client: &str, offsets: &HashMap<String, usize>) -> Result<(), KvError> {
+    for (key, &offset) in offsets {
+        raise(kv, committed(client, key), offset).await?;
+    }
+    Ok(())
+}
+
+/// CAS the number under `name` up to `to`, unless it's there already.
+async fn raise(kv: &KvClient<Routed<Payload>>, name: String, to: usize) -> Result<(), KvError> {
+    loop {
+        let current: Option<usize> = kv.read(&name).await?;
+        if current.is_some_and(|current| current >= to) {
+            return Ok(());
+        }
+        match kv.cas(&name, current.unwrap_or(0), to, current.is_none()).await {
+            Ok(()) => return Ok(()),
+            Err(err) if err.is(ErrorCode::PreconditionFailed) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+pub async fn list(kv: &KvClient<Routed<Payload>>, client: &str, keys: &[String]) -> Result<HashMap<String, usize>, KvError> {
+    let mut offsets = HashMap::new();
+    for key in keys {
+        if let Some(offset) = kv.read(committed(client, key)).await? {
+            offsets.insert(key.clone(), offset);
+        }
+    }
+    Ok(offsets)
+}
@@ -0,0 +1,232 @@
+use crate::{message::{self, Body, Envelope, ErrorCode, NodeId}, routing::{Inbound, Routed, Router}};
+use tokio::time::Instant;
+use std::{collections::{BTreeMap, HashMap}, time::Duration};
+use super::{message_id, reply, Payload, State};
+
+
+/// How long requests forwarded to their keys' owners are waited on before they're
+/// forgotten.
+pub(super) const FORGET_AFTER: Duration = Duration::from_secs(60);
+
+
+impl State {
+    /// The nodes that keep `key`, its owner on the ring first: every node, without
+    /// `--followers`.
+    pub fn replicas(&self, key: &str) -> Vec<NodeId> {
+        match (&self.ring, self.followers) {
+            (Some(ring), Some(followers)) => ring.owners(key, followers + 1).into_iter().cloned().collect(),
+            _ => std::iter::once(message::intern(&self.my_id)).chain(self.peers.iter().cloned()).collect(),
+        }
+    }
+
+    /// Who hands out `key`'s offsets: its owner on the ring, or with `--followers`, the
+    /// first of its replicas that isn't down. `None` if it's this node.
+    pub fn leader(&self, key: &str) -> Option<NodeId> {
+        if self.followers.is_none() {
+            return self.ring_owner(key);
+        }
+        let replicas = self.replicas(key);
+        let leader = replicas.iter().find(|node| !self.down.contains(*node)).or(replicas.first())?;
+        self.other(leader)
+    }
+
+    /// Who owns `key` on the ring: `None` if it's this node (or keys don't have owners).
+    pub fn ring_owner(&self, key: &str) -> Option<NodeId> {
+        self.ring.as_ref()?.owner(key).filter(|owner| ***owner != *self.my_id).cloned()
+    }
+
+    /// Who to ask about `key`: `None` if it's this node (or keys don't have owners).
+    /// That's whoever has it, as far as this node can tell, which isn't always its owner
+    /// on the ring: it may be hot and have been moved, or not have been handed over to
+    /// its owner yet since a node joined or left.
+    pub fn owner(&self, key: &str) -> Option<NodeId> {
+        let ring = self.ring.as_ref()?;
+        if self.holds(key) {
+            return None;
+        }
+        if let Some(moved_to) = self.moved_to.get(key) {
+            return self.other(moved_to);
+        }
+        // Whoever had it before the last node joined or left has it until they've
+        // handed it over, and keys new since then are handed over like the rest.
+        let previous = self.previous_ring.as_ref().unwrap_or(ring).owner(key)?;
+        self.other(previous)
+    }
+
+    /// `node`, unless it's this one.
+    pub(super) fn other(&self, node: &NodeId) -> Option<NodeId> {
+        (**node != *self.my_id).then(|| node.clone())
+    }
+
+    /// Whether this node has `key`'s log or committed offsets.
+    pub(super) fn holds(&self, key: &str) -> bool {
+        self.logs.get(key).is_some() || self.committed_offsets.has(key) || self.handing_off.contains_key(key)
+    }
+}
+
+
+/// How `request` splits up among the nodes that own its keys: the part for each owner,
+/// `None` being this node. Requests that aren't about keys are this node's.
+pub fn split(state: &State, request: &Payload) -> Vec<(Option<NodeId>, Payload)> {
+    fn by_owner<T>(state: &State, keyed: impl IntoIterator<Item = (String, T)>) -> BTreeMap<Option<NodeId>, Vec<(String, T)>> {
+        let mut parts: BTreeMap<Option<NodeId>, Vec<(String, T)>> = BTreeMap::new();
+        for (key, value) in keyed {
+            parts.entry(state.owner(&key)).or_default().push((key, value));
+        }
+        parts
+    }
+    match request {
+        Payload::Send { key, .. } => vec![(state.owner(key), request.clone())],
+        Payload::Poll { offsets } => by_owner(state, offsets.clone()).into_iter().map(|(owner, offsets)| (owner, Payload::Poll { offsets: offsets.into_iter().collect() })).collect(),
+        Payload::CommitOffsets { offsets } => by_owner(state, offsets.clone()).into_iter().map(|(owner, offsets)| (owner, Payload::CommitOffsets { offsets: offsets.into_iter().collect() })).collect(),
+        Payload::ListCommittedOffsets { keys } => by_owner(state, keys.iter().map(|key| (key.clone(), ()))).into_iter().map(|(owner, keys)| (owner, Payload::ListCommittedOffsets { keys: keys.into_iter().map(|(key, _)| key).collect() })).collect(),
+        _ => vec![(None, request.clone())],
+    }
+}
+
+
+/// A request split among several owners: the reply to it so far, and how many of their
+/// replies are still to come.
+#[derive(Debug)]
+struct Gather {
+    request: Envelope<Payload>,
+    reply: Payload,
+    parts_left: usize,
+    started: Instant,
+}
+
+impl Gather {
+    /// Take one owner's reply into account: what they hand back is put together, and an
+    /// error from any of them is the reply.
+    fn merge(&mut self, part: Payload) {
+        self.parts_left = self.parts_left.saturating_sub(1);
+        match (&mut self.reply, part) {
+            (Payload::Error { .. }, _) => {},
+            (reply, error @ Payload::Error { .. }) => *reply = error,
+            (Payload::PollOk { msgs }, Payload::PollOk { msgs: more }) => msgs.extend(more),
+            (Payload::ListCommittedOffsetsOk { offsets }, Payload::ListCommittedOffsetsOk { offsets: more }) => offsets.extend(more),
+            _ => {},
+        }
+    }
+}
+
+
+/// Gets requests to the nodes that own their keys, with `--partition`, so clients can
+/// talk to any node while every key's log (and its offsets) is kept by one. A request
+/// for keys another node owns is [forwarded](Router::forward) there, and its reply
+/// passed back; one for keys owned by several is split up among them (this node doing
+/// its own part), and their replies put together into one for the client.
+#[derive(Debug, Default)]
+pub struct Forwarder {
+    router: Router,
+    /// Requests split among several owners, by an id of their own.
+    gathers: HashMap<usize, Gather>,
+    /// Which of them each part sent to another node is of, by its client and the
+    /// `msg_id` it was sent as. Every part's sent as a request of its own, so its reply
+    /// comes back here even if this node was forwarded the request to begin with.
+    parts: HashMap<(NodeId, usize), usize>,
+}
+
+impl Forwarder {
+    /// What to send on account of `envelope`, received at `now`: replies to the client,
+    /// requests forwarded to owners, or replies on their way back.
+    pub fn dispatch(&mut self, state: &mut State, envelope: Envelope<Routed<Payload>>, now: Instant) -> Vec<Envelope<Routed<Payload>>> {
+        let envelope = match self.router.receive(envelope, now) {
+            Inbound::Handle(envelope) => envelope,
+            Inbound::Send(reply) => return self.gather(reply).into_iter().collect(),
+            Inbound::Drop => return vec![],
+        };
+        if matches!(envelope.body.message, Payload::Join { .. } | Payload::Leave { .. }) {
+            let mut sends = self.dispatch_local(state, envelope);
+            sends.extend(self.rebalance(state));
+            return sends;
+        }
+        let parts = split(state, &envelope.body.message);
+        if let [(owner, _)] = parts.as_slice() {
+            let Some(owner) = owner.clone() else {
+                return self.dispatch_local(state, envelope);
+            };
+            let forwarded = self.forward(envelope.clone(), &owner, now);
+            return vec![forwarded.unwrap_or_else(|error| self.router.send(envelope.reply_with(Some(message_id()), error)))];
+        }
+
+        let empty = match &envelope.body.message {
+            Payload::Poll { .. } => Payload::PollOk { msgs: HashMap::new() },
+            Payload::ListCommittedOffsets { .. } => Payload::ListCommittedOffsetsOk { offsets: HashMap::new() },
+            _ => Payload::CommitOffsetsOk,
+        };
+        let id = message_id();
+        let mut gather = Gather { request: envelope.clone(), reply: empty, parts_left: parts.len(), started: now };
+        let mut sends = vec![];
+        for (owner, part) in parts {
+            let part_id = envelope.body.msg_id.map(|_| message_id());
+            let part = Envelope { body: Body { msg_id: part_id, in_reply_to: None, message: part }, ..envelope.clone() };
+            match owner {
+                None => gather.merge(reply(state, &part).unwrap_or(Payload::CommitOffsetsOk)),
+                Some(owner) => match self.forward(part, &owner, now) {
+                    Ok(forwarded) => {
+                        if let Some(part_id) = part_id {
+                            self.parts.insert((envelope.source.clone(), part_id), id);
+                        }
+                        sends.push(forwarded);
+                    },
+                    Err(error) => gather.merge(error),
+                },
+            }
+        }
+        if envelope.body.msg_id.is_none() {
+            return sends;
+        }
+        if gather.parts_left == 0 {
+            sends.push(self.router.send(envelope.reply_with(Some(message_id()), gather.reply)));
+        } else {
+            self.gathers.insert(id, gather);
+        }
+        sends
+    }
+
+    /// The handoffs `state` has to send (see [`State::rebalance`]).
+    pub fn rebalance(&mut self, state: &mut State) -> Vec<Envelope<Routed<Payload>>> {
+        let me = state.my_id.clone();
+        state.rebalance().into_iter().map(|(to, handoff)| {
+            self.router.send(Envelope::new(&me, &to, Body { msg_id: Some(message_id()), in_reply_to: None, message: handoff }))
+        }).collect()
+    }
+
+    /// `envelope` handled on this node, and the reply to it.
+    fn dispatch_local(&mut self, state: &mut State, envelope: Envelope<Payload>) -> Vec<Envelope<Routed<Payload>>> {
+        reply(state, &envelope).map(|reply| self.router.send(envelope.reply_with(Some(message_id()), reply))).into_iter().collect()
+    }
+
+    /// Forget requests whose replies haven't come back within [`FORGET_AFTER`].
+    pub fn expire(&mut self, now: Instant) {
+        self.router.expire(now);
+        self.gathers.retain(|_, gather| now.saturating_duration_since(gather.started) < FORGET_AFTER);
+        let gathers = &self.gathers;
+        self.parts.retain(|_, id| gathers.contains_key(id));
+    }
+
+    /// `envelope` forwarded to `owner`, or (if it's been forwarded too many times) the
+    /// error to reply with instead.
+    fn forward(&mut self, envelope: Envelope<Payload>, owner: &NodeId, now: Instant) -> Result<Envelope<Routed<Payload>>, Payload> {
+        self.router.forward(envelope, owner, message_id(), now).map_err(|err| {
+            Payload::Error { code: ErrorCode::TemporarilyUnavailable.code(), text: err.to_string() }
+        })
+    }
+
+    /// A reply on its way back to a client, unless it's one owner's part of a split
+    /// request, which waits for the rest.
+    fn gather(&mut self, reply: Envelope<Routed<Payload>>) -> Option<Envelope<Routed<Payload>>> {
+        let part = reply.body.in_reply_to.filter(|_| reply.body.message.route.is_none()).map(|in_reply_to| (reply.destination.clone(), in_reply_to));
+        let Some(id) = part.and_then(|part| self.parts.remove(&part)) else {
+            return Some(reply);
+        };
+        let gather = self.gathers.get_mut(&id)?;
+        gather.merge(reply.body.message.message);
+        if gather.parts_left > 0 {
+            return None;
+        }
+        let gather = self.gathers.remove(&id)?;
+        Some(self.router.send(gather.request.reply_with(Some(message_id()), gather.reply)))
+    }
+}
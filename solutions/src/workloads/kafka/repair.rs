@@ -0,0 +1,90 @@
+use serde::{Serialize, Deserialize};
+use crate::{anti_entropy::{self, Replica}, lease::FencingToken, log::Record, scheduler::{JobHandle, Scheduler}};
+use tracing::debug;
+use std::time::Duration;
+use super::{Payload, Shared, State};
+
+
+impl State {
+    /// What repair compares of `key`'s log: the epoch it was last copied from and where
+    /// it ends. Logs copied from the same leader are the same up to the shorter's end.
+    fn digest(&self, key: &str) -> u64 {
+        let next = self.logs.get(key).map_or(0, |log| log.next_offset());
+        (self.log_epoch(key).0.0 << 32) | next as u64
+    }
+}
+
+
+/// What repair sends of a key's log: its records from some offset on, as copied from
+/// the leader at `epoch`, who's led the key since `since`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepairEntry {
+    pub key: String,
+    pub epoch: FencingToken,
+    pub since: usize,
+    pub records: Vec<Record>,
+}
+
+/// With `--offsets replicated`, a key's log on one node is behind its copy on another
+/// if it was copied from an older leader, or from the same one and ends sooner: offsets
+/// are only handed out by the key's leader. What a deposed leader appended is dropped
+/// as it is for a [`Payload::Replicate`], never copied on.
+impl Replica for State {
+    type Entry = RepairEntry;
+
+    fn digests(&self, peer: &str) -> Vec<(String, u64)> {
+        self.logs
+        .keys()
+        .filter(|key| {
+            let replicas = self.replicas(key);
+            replicas.iter().any(|node| **node == *peer) && replicas.iter().any(|node| **node == *self.my_id)
+        })
+        .map(|key| (key.clone(), self.digest(key)))
+        .collect()
+    }
+
+    fn entry(&self, key: &str, theirs: Option<u64>) -> Option<Self::Entry> {
+        let (epoch, since) = self.log_epoch(key);
+        let (their_epoch, their_next) = theirs.map_or((0, 0), |theirs| (theirs >> 32, (theirs & u64::from(u32::MAX)) as usize));
+        if their_epoch > epoch.0 {
+            return None;
+        }
+        let behind = their_epoch < epoch.0;
+        let records = self.records_from(key, if behind { their_next.min(since) } else { their_next });
+        // Sent to a peer that's behind even with no records, for it to drop what it has
+        // from `since` on.
+        (behind || !records.is_empty()).then(|| RepairEntry { key: key.to_owned(), epoch, since, records })
+    }
+
+    fn take(&mut self, RepairEntry { key, epoch, since, records }: Self::Entry) -> bool {
+        if epoch < self.log_epoch(&key).0 {
+            debug!(key, %epoch, "turning away repair from a deposed leader's log");
+            return false;
+        }
+        let before = self.digest(&key);
+        self.follow(&key, epoch, since);
+        for Record { offset, msg, sub_key } in records {
+            self.replicate_from(&key, epoch, since, offset, msg, sub_key);
+        }
+        self.digest(&key) != before
+    }
+}
+
+/// With `--repair-interval-ms`, every `interval`, as a `repair` job on `scheduler`:
+/// repair the logs this node keeps along with a peer that's up, picked at random.
+pub fn repair_every_so_often(scheduler: &Scheduler, interval: Duration, shared: Shared) -> JobHandle {
+    let (state, rpc, timeout) = (shared.state.clone(), shared.rpc.clone(), shared.timeout);
+    let peers = move || {
+        let state = state.lock().unwrap();
+        state.peers.iter().filter(|peer| !state.down.contains(*peer)).cloned().collect()
+    };
+    anti_entropy::repair_every_so_often(scheduler, interval, shared.state.clone(), peers, move |peer, repair| {
+        let rpc = rpc.clone();
+        async move {
+            match rpc.call(&peer, Payload::Repair { repair }.into(), timeout).await.ok()?.body.message.message {
+                Payload::RepairOk { repair } => Some(repair),
+                _ => None,
+            }
+        }
+    })
+}
@@ -0,0 +1,480 @@
+use crate::{backoff::{Backoff, Jitter}, failure_detector::{heartbeat, Config as DetectorConfig, FailureDetector, Status}, kv::KvClient, lease::FencingToken, log::Record, message::{self, Body, Envelope, ErrorCode, NodeId}, metrics, routing::Routed, rpc::RpcClient, scheduler::Scheduler, task};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, error, info, warn};
+use std::{collections::HashMap, time::Duration};
+use std::sync::{Arc, Mutex};
+use super::{lin_kv, message_id, reply, send_token, Acks, OffsetCommit, Offsets, Payload, State};
+
+
+/// How many times a record or commit is sent to a node, with `--offsets replicated`,
+/// before it's given up on.
+const REPLICATE_ATTEMPTS: usize = 20;
+
+
+impl State {
+    /// The newest leader epoch heard of for `key`.
+    pub fn epoch(&self, key: &str) -> FencingToken {
+        self.epochs.get(key).copied().unwrap_or_default()
+    }
+
+    /// Hear of a leader of `key` at `epoch`: whether it's current, i.e. no older than
+    /// the newest heard of. A newer one deposes whoever led the key before, so if this
+    /// node did, it steps down.
+    pub fn observe_epoch(&mut self, key: &str, epoch: FencingToken) -> bool {
+        let newest = self.epochs.entry(key.to_owned()).or_default();
+        if epoch < *newest {
+            return false;
+        }
+        if epoch > *newest {
+            *newest = epoch;
+            if self.leading.remove(key).is_some() {
+                info!(key, %epoch, "stepping down: another node leads the key now");
+                metrics::global().increment("kafka.leaders_fenced");
+            }
+        }
+        true
+    }
+
+    /// A node taking `key` over as its leader at `epoch` (this one included): granted
+    /// only if `epoch` is newer than any heard of, so no two takeovers get the same one.
+    pub fn promise(&mut self, key: &str, epoch: FencingToken) -> bool {
+        epoch > self.epoch(key) && self.observe_epoch(key, epoch)
+    }
+
+    /// Take `peer` to be down (or up again), as the failure detector says.
+    pub fn set_down(&mut self, peer: &str, down: bool) {
+        if down {
+            self.down.insert(message::intern(peer));
+        } else {
+            self.down.remove(peer);
+        }
+    }
+
+    /// Take in a record `key`'s owner appended at `offset`, in order: one that's arrived
+    /// ahead of an earlier one waits for it, and one that's been taken in already (a
+    /// retried copy, or one caught up on twice) is ignored.
+    pub fn replicate(&mut self, key: &str, offset: usize, msg: usize, sub_key: Option<String>) {
+        let next = self.logs.get(key).map_or(0, |log| log.next_offset());
+        if offset < next {
+            debug!(key, offset, next, "already have the record");
+            return;
+        }
+        let ahead = self.ahead.entry(key.to_owned()).or_default();
+        ahead.insert(offset, (msg, sub_key));
+        let mut next = next;
+        while let Some((msg, sub_key)) = ahead.remove(&next) {
+            next = self.logs.append(key, msg, sub_key) + 1;
+        }
+        if ahead.is_empty() {
+            self.ahead.remove(key);
+        }
+    }
+
+    /// Take in a record `key`'s leader at `epoch` appended at `offset`, having led the
+    /// key since offset `since`, as [`replicate`](Self::replicate) does. The first from a
+    /// newer leader than the log was last copied from replaces whatever this node has
+    /// from `since` on: a deposed leader appended it, and it was never acknowledged (the
+    /// new leader caught up on every record that was before taking over).
+    pub fn replicate_from(&mut self, key: &str, epoch: FencingToken, since: usize, offset: usize, msg: usize, sub_key: Option<String>) {
+        self.follow(key, epoch, since);
+        self.replicate(key, offset, msg, sub_key);
+    }
+
+    /// The epoch of the leader `key`'s log was last copied from, and the offset it's led
+    /// the key since.
+    pub fn log_epoch(&self, key: &str) -> (FencingToken, usize) {
+        self.log_epochs.get(key).copied().unwrap_or_default()
+    }
+
+    /// Go on with `key`'s log as copied from the leader at `epoch`, who's led it since
+    /// `since`: if that's newer, dropping what a deposed leader appended from then on.
+    pub(super) fn follow(&mut self, key: &str, epoch: FencingToken, since: usize) {
+        if epoch > self.log_epoch(key).0 {
+            let dropped = self.logs.truncate(key, since);
+            self.ahead.remove(key);
+            if dropped > 0 {
+                warn!(key, since, dropped, %epoch, "dropping records a deposed leader appended");
+                metrics::global().add("kafka.records_truncated", dropped as u64);
+            }
+            self.log_epochs.insert(key.to_owned(), (epoch, since));
+        }
+    }
+}
+
+
+/// What `--offsets lin-kv` and `--offsets replicated` need to handle a request: they
+/// wait on lin-kv, or on other nodes, so their handlers are spawned rather than run
+/// inline like the local ones.
+#[derive(Debug, Clone)]
+pub struct Shared {
+    offsets: Offsets,
+    pub(super) state: Arc<Mutex<State>>,
+    pub(super) rpc: RpcClient<Routed<Payload>>,
+    kv: KvClient<Routed<Payload>>,
+    pub(super) timeout: Duration,
+    acks: Acks,
+}
+
+impl Shared {
+    pub fn new(offsets: Offsets, state: Arc<Mutex<State>>, rpc: RpcClient<Routed<Payload>>, timeout: Duration) -> Self {
+        let kv = KvClient::lin_kv(rpc.clone(), timeout);
+        Self { offsets, state, rpc, kv, timeout, acks: Acks::All }
+    }
+
+    /// Wait for `acks` copies of records and commits, with `--offsets replicated`.
+    pub fn with_acks(mut self, acks: Acks) -> Self {
+        self.acks = acks;
+        self
+    }
+
+    /// How many of `copies` other nodes to hear back from, out of `replicas` that keep
+    /// something (this one included).
+    fn needed(&self, replicas: usize, copies: usize) -> usize {
+        match self.acks {
+            Acks::All => copies,
+            Acks::Quorum => (replicas / 2).min(copies),
+            Acks::Async => 0,
+        }
+    }
+}
+
+
+/// A send to `key` that's been taken on but not answered yet; it stops counting against
+/// the key's [`max_pending_sends`](State::with_max_pending_sends) when dropped.
+struct PendingSend {
+    state: Arc<Mutex<State>>,
+    key: String,
+}
+
+impl PendingSend {
+    /// `None` if `key` has as many sends waiting as it can.
+    fn take_on(state: &Arc<Mutex<State>>, key: &str) -> Option<Self> {
+        let mut locked = state.lock().unwrap();
+        let max = locked.max_pending_sends.unwrap_or(usize::MAX);
+        let pending = locked.pending_sends.entry(key.to_owned()).or_default();
+        if *pending >= max {
+            return None;
+        }
+        *pending += 1;
+        Some(Self { state: state.clone(), key: key.to_owned() })
+    }
+}
+
+impl Drop for PendingSend {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pending) = state.pending_sends.get_mut(&self.key) {
+            *pending -= 1;
+            if *pending == 0 {
+                state.pending_sends.remove(&self.key);
+            }
+        }
+    }
+}
+
+
+pub async fn handle_shared(shared: Shared, envelope: Envelope<Payload>, writer: UnboundedSender<Envelope<Routed<Payload>>>) {
+    // Held until the send's answered.
+    let _pending = match &envelope.body.message {
+        Payload::Send { key, .. } => match PendingSend::take_on(&shared.state, key) {
+            Some(pending) => Some(pending),
+            None => {
+                debug!(key, "too many sends waiting already, turning this one away");
+                metrics::global().increment("kafka.sends_turned_away");
+                let error = Payload::Error { code: ErrorCode::TemporarilyUnavailable.code(), text: format!("too many sends to {key} waiting already") };
+                let reply = envelope.reply_with(Some(message_id()), error);
+                writer.send(reply.with_message(reply.body.message.clone().into())).unwrap();
+                return;
+            },
+        },
+        _ => None,
+    };
+    let reply = match (shared.offsets, &envelope.body.message) {
+        (Offsets::LinKv, Payload::Send { key, msg, .. }) => {
+            let sent = lin_kv::send(&shared.kv, key, *msg, send_token(&envelope)).await;
+            if sent.is_ok() {
+                shared.state.lock().unwrap().appended(key);
+            }
+            sent.map(|offset| Payload::SendOk { offset })
+        },
+        (Offsets::LinKv, Payload::Poll { offsets }) => {
+            let limits = {
+                let mut state = shared.state.lock().unwrap();
+                state.polled(offsets.keys());
+                state.poll_limits
+            };
+            lin_kv::poll(&shared.kv, offsets, limits).await.map(|msgs| Payload::PollOk { msgs })
+        },
+        (Offsets::LinKv, Payload::CommitOffsets { offsets }) => lin_kv::commit(&shared.kv, &envelope.source, offsets).await.map(|()| Payload::CommitOffsetsOk),
+        (Offsets::LinKv, Payload::ListCommittedOffsets { keys }) => lin_kv::list(&shared.kv, &envelope.source, keys).await.map(|offsets| Payload::ListCommittedOffsetsOk { offsets }),
+        (Offsets::Replicated, Payload::Send { key, msg, sub_key, .. }) => Ok(replicated_send(&shared, key, *msg, sub_key.clone(), send_token(&envelope)).await),
+        (Offsets::Replicated, Payload::CommitOffsets { offsets }) => Ok(replicated_commit(&shared, &envelope.source, offsets).await),
+        (Offsets::Replicated, Payload::Poll { offsets }) if shared.state.lock().unwrap().followers.is_some() => Ok(replicated_poll(&shared, &envelope, offsets).await),
+        (Offsets::Replicated, Payload::Replicate { key, offset, .. }) => {
+            let reply = reply(&mut shared.state.lock().unwrap(), &envelope);
+            if !matches!(reply, Some(Payload::Fenced { .. })) {
+                catch_up(&shared, key, *offset, &envelope.source).await;
+            }
+            Ok(reply.unwrap_or(Payload::ReplicateOk))
+        },
+        // Polls and listing committed offsets, with `--offsets replicated`, are answered
+        // from this node's own copy (polls from the leader's, with `--followers`).
+        _ => match reply(&mut shared.state.lock().unwrap(), &envelope) {
+            Some(reply) => Ok(reply),
+            None => return,
+        },
+    };
+    let reply = reply.unwrap_or_else(|err| {
+        let code = err.code().unwrap_or(ErrorCode::Crash);
+        Payload::Error { code: code.code(), text: err.to_string() }
+    });
+    let reply = envelope.reply_with(Some(message_id()), reply);
+    writer.send(reply.with_message(reply.body.message.clone().into())).unwrap();
+}
+
+
+/// Appends with `--offsets replicated`: the key's leader (its owner, unless that's down)
+/// hands out the offset, and copies the record to the key's other replicas before the
+/// client hears back, so whichever of them it polls next has it. Anyone else asks the
+/// leader to do it, passing the send's token along so the leader can tell a retry
+/// (through whichever node) from a new send.
+async fn replicated_send(shared: &Shared, key: &str, msg: usize, sub_key: Option<String>, token: Option<String>) -> Payload {
+    let send = Payload::Send { key: key.to_owned(), msg, sub_key: sub_key.clone(), token: token.clone() };
+    let (leader, caught_up) = {
+        let state = shared.state.lock().unwrap();
+        (state.leader(key), state.followers.is_none() || state.leading.contains_key(key))
+    };
+    if let Some(leader) = leader {
+        shared.state.lock().unwrap().leading.remove(key);
+        return match shared.rpc.call(&leader, send.into(), shared.timeout).await {
+            Ok(reply) => reply.body.message.message,
+            // It may have been appended all the same.
+            Err(err) => Payload::Error { code: ErrorCode::Timeout.code(), text: format!("couldn't reach {leader}, who leads {key}: {err}") },
+        };
+    }
+    if !caught_up && !take_over(shared, key).await {
+        return Payload::Error { code: ErrorCode::TemporarilyUnavailable.code(), text: format!("couldn't catch up on {key} from enough of its followers to take it over") };
+    }
+    let (offset, replicas, copies, epoch, since) = {
+        let mut state = shared.state.lock().unwrap();
+        let replicas = state.replicas(key);
+        let copies: Vec<NodeId> = replicas.iter().filter_map(|node| state.other(node)).collect();
+        let since = state.leading.get(key).copied().unwrap_or_default();
+        (state.append(key, msg, sub_key.clone(), token.clone()), replicas.len(), copies, state.epoch(key), since)
+    };
+    let needed = shared.needed(replicas, copies.len());
+    let copied = replicate(shared, copies, Payload::Replicate { key: key.to_owned(), offset, msg, sub_key, epoch, since }, needed).await;
+    {
+        let mut state = shared.state.lock().unwrap();
+        if state.epoch(key) != epoch {
+            // A newer leader will hand the offset out again, so a retry mustn't get it.
+            if let Some(token) = &token {
+                state.recent_sends.forget(token);
+            }
+            return Payload::Error { code: ErrorCode::Timeout.code(), text: format!("{key} was taken over by another node; it may have been appended all the same") };
+        }
+    }
+    if !copied && shared.acks == Acks::Quorum {
+        return Payload::Error { code: ErrorCode::Timeout.code(), text: format!("too few of {key}'s followers took the record in; it may have been appended all the same") };
+    }
+    Payload::SendOk { offset }
+}
+
+/// Before handing out any of `key`'s offsets as its new leader, at a newer epoch than
+/// any it's heard of, catch up on whatever records its other replicas that are up have
+/// and this node hasn't. With `--acks quorum`, enough of them to make a majority with
+/// this one have to answer: between them, they have every acknowledged record. Those
+/// that answer won't take records from an older leader any more, and one that's heard
+/// of a newer epoch turns the takeover away. Whether it's caught up.
+async fn take_over(shared: &Shared, key: &str) -> bool {
+    let (others, replicas, from, epoch) = {
+        let mut state = shared.state.lock().unwrap();
+        let replicas = state.replicas(key);
+        let others: Vec<NodeId> = replicas.iter().filter(|node| state.other(node).is_some() && !state.down.contains(*node)).cloned().collect();
+        let epoch = state.epoch(key).next();
+        state.promise(key, epoch);
+        (others, replicas.len(), state.logs.get(key).map_or(0, |log| log.next_offset()), epoch)
+    };
+    let needed = shared.needed(replicas, replicas - 1);
+    let mut answered = 0;
+    for other in others {
+        let Ok(reply) = shared.rpc.call(&other, Payload::CatchUp { key: key.to_owned(), from, epoch: Some(epoch) }.into(), shared.timeout).await else {
+            continue;
+        };
+        let mut state = shared.state.lock().unwrap();
+        match reply.body.message.message {
+            Payload::CatchUpOk { records } => {
+                for Record { offset, msg, sub_key } in records {
+                    state.replicate(key, offset, msg, sub_key);
+                }
+                answered += 1;
+            },
+            Payload::Fenced { epoch: newer, .. } => {
+                state.observe_epoch(key, newer);
+            },
+            _ => {},
+        }
+    }
+    let mut state = shared.state.lock().unwrap();
+    if state.epoch(key) != epoch {
+        warn!(key, %epoch, newer = %state.epoch(key), "another node took the key over first");
+        return false;
+    }
+    if answered < needed {
+        warn!(key, answered, needed, "couldn't catch up on a key to take it over");
+        return false;
+    }
+    info!(key, %epoch, "took over as leader");
+    metrics::global().increment("kafka.leaders_promoted");
+    let since = state.logs.get(key).map_or(0, |log| log.next_offset());
+    state.leading.insert(key.to_owned(), since);
+    state.log_epochs.insert(key.to_owned(), (epoch, since));
+    true
+}
+
+/// A record of `key` at `offset` has come in from `leader`: if it's ahead of what this
+/// node has (it missed some, say, for being down), ask the leader for the ones before.
+async fn catch_up(shared: &Shared, key: &str, offset: usize, leader: &NodeId) {
+    let from = shared.state.lock().unwrap().logs.get(key).map_or(0, |log| log.next_offset());
+    if from >= offset {
+        return;
+    }
+    debug!(key, from, offset, "missing records, catching up");
+    if let Ok(reply) = shared.rpc.call(leader, Payload::CatchUp { key: key.to_owned(), from, epoch: None }.into(), shared.timeout).await {
+        if let Payload::CatchUpOk { records } = reply.body.message.message {
+            let mut state = shared.state.lock().unwrap();
+            for Record { offset, msg, sub_key } in records {
+                state.replicate(key, offset, msg, sub_key);
+            }
+        }
+    }
+}
+
+/// Polls with `--followers`, where only a key's replicas have it: each key's part goes
+/// to its leader (this node doing its own), and their replies are put together. A part
+/// sent here by another node is answered here, even if this node doesn't take itself
+/// to be the leader, rather than sent on again.
+async fn replicated_poll(shared: &Shared, envelope: &Envelope<Payload>, offsets: &HashMap<String, usize>) -> Payload {
+    let mut parts: HashMap<Option<NodeId>, HashMap<String, usize>> = HashMap::new();
+    {
+        let state = shared.state.lock().unwrap();
+        let from_peer = state.peers.contains(&envelope.source);
+        for (key, &offset) in offsets {
+            let leader = if from_peer { None } else { state.leader(key) };
+            parts.entry(leader).or_default().insert(key.clone(), offset);
+        }
+    }
+    let mut msgs = HashMap::new();
+    for (leader, offsets) in parts {
+        if leader.is_none() {
+            // Nothing acknowledged is left out, even just after taking a key over.
+            for key in offsets.keys() {
+                let caught_up = shared.state.lock().unwrap().leading.contains_key(key);
+                if !caught_up && !take_over(shared, key).await {
+                    return Payload::Error { code: ErrorCode::TemporarilyUnavailable.code(), text: format!("couldn't catch up on {key} from enough of its followers to take it over") };
+                }
+            }
+        }
+        let part = Payload::Poll { offsets };
+        let reply = match leader {
+            None => reply(&mut shared.state.lock().unwrap(), &envelope.with_message(part)),
+            Some(leader) => match shared.rpc.call(&leader, part.into(), shared.timeout).await {
+                Ok(reply) => Some(reply.body.message.message),
+                Err(err) => return Payload::Error { code: ErrorCode::Timeout.code(), text: format!("couldn't reach {leader}: {err}") },
+            },
+        };
+        match reply {
+            Some(Payload::PollOk { msgs: more }) => msgs.extend(more),
+            Some(error @ Payload::Error { .. }) => return error,
+            _ => {},
+        }
+    }
+    Payload::PollOk { msgs }
+}
+
+/// Commits with `--offsets replicated`: committed offsets only ever move forwards, so
+/// whichever node a client commits to applies it and copies it to the others, in
+/// whatever order they get them.
+async fn replicated_commit(shared: &Shared, client: &str, offsets: &HashMap<String, usize>) -> Payload {
+    let commit = OffsetCommit { client: client.to_owned(), offsets: offsets.clone() };
+    let (committed, peers) = {
+        let mut state = shared.state.lock().unwrap();
+        (state.commit(commit), state.peers.clone())
+    };
+    if let Err(err) = committed {
+        error!(error = ?err, "failed to journal committed offsets");
+        return Payload::Error { code: ErrorCode::TemporarilyUnavailable.code(), text: format!("couldn't journal the commit: {err}") };
+    }
+    let needed = shared.needed(peers.len() + 1, peers.len());
+    if !replicate(shared, peers, Payload::ReplicateCommit { client: client.to_owned(), offsets: offsets.clone() }, needed).await && shared.acks == Acks::Quorum {
+        return Payload::Error { code: ErrorCode::Timeout.code(), text: "too few nodes took the commit in; it may have been committed all the same".to_owned() };
+    }
+    Payload::CommitOffsetsOk
+}
+
+/// Send `message` to every one of `peers`, waiting (up to the timeout) for `needed` of
+/// them to take it in: whether they did. Those that haven't by then keep being sent it
+/// in the background, for up to [`REPLICATE_ATTEMPTS`] tries; until they take it in,
+/// they're behind. One that's heard of a newer leader of the key turns a record away
+/// for good, and this node steps down if it was leading it.
+async fn replicate(shared: &Shared, peers: Vec<NodeId>, message: Payload, needed: usize) -> bool {
+    let all = peers.len();
+    let (copied, mut copies) = tokio::sync::mpsc::unbounded_channel();
+    for peer in peers {
+        let (state, rpc, timeout, message, copied) = (shared.state.clone(), shared.rpc.clone(), shared.timeout, message.clone(), copied.clone());
+        task::spawn("kafka.replicate", async move {
+            let mut backoff = Backoff::new(Duration::from_millis(50)).max(timeout).jitter(Jitter::Full);
+            for _ in 0..REPLICATE_ATTEMPTS {
+                match rpc.call(&peer, message.clone().into(), timeout).await.map(|reply| reply.body.message.message) {
+                    Ok(Payload::Fenced { key, epoch }) => {
+                        debug!(%peer, key, %epoch, "record turned away by a newer leader's follower");
+                        state.lock().unwrap().observe_epoch(&key, epoch);
+                        return;
+                    },
+                    Ok(_) => {
+                        copied.send(()).ok();
+                        return;
+                    },
+                    Err(_) => {},
+                }
+                backoff.wait().await;
+            }
+            warn!(%peer, "giving up on copying a record or commit");
+            metrics::global().increment("kafka.replication_abandoned");
+        });
+    }
+    drop(copied);
+    let enough_copied = async {
+        for _ in 0..needed {
+            if copies.recv().await.is_none() {
+                return false;
+            }
+        }
+        true
+    };
+    let enough = tokio::time::timeout(shared.timeout, enough_copied).await.unwrap_or(false);
+    if !enough || needed < all {
+        debug!(needed, all, "replying before every node has a copy");
+        metrics::global().increment("kafka.replication_lagging");
+    }
+    enough
+}
+
+/// Keep `state` up to date on which of `node_ids` are down, pinging them as need be,
+/// by `scheduler`'s clock.
+pub fn watch_peers(scheduler: &Scheduler, state: Arc<Mutex<State>>, writer: UnboundedSender<Envelope<Routed<Payload>>>, node_id: &str, node_ids: &[String]) -> Arc<Mutex<FailureDetector>> {
+    let peers = node_ids.iter().filter(|&other| other != node_id).map(|other| message::intern(other));
+    let detector = Arc::new(Mutex::new(FailureDetector::new(DetectorConfig::default(), peers, scheduler.clock().now())));
+    let mut events = detector.lock().unwrap().subscribe();
+    task::spawn("kafka.peer_status", async move {
+        while let Ok(event) = events.recv().await {
+            state.lock().unwrap().set_down(&event.peer, event.to == Status::Down);
+        }
+    });
+    let node_id = node_id.to_owned();
+    heartbeat(scheduler, detector.clone(), move |peer| {
+        let ping = Body { msg_id: Some(message_id()), in_reply_to: None, message: Payload::Ping.into() };
+        writer.send(Envelope::new(&node_id, peer, ping)).ok();
+    });
+    detector
+}
@@ -0,0 +1,421 @@
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use crate::{circuit_breaker::CircuitBreaker, cli::CommonOpts, clock::{self, Clock}, deadline::{self, Deadline}, hash_ring::HashRing, io::{self, io_channel}, metrics, task, kv::KvClient, lock_manager::LockManager, message::Envelope, mvcc::{MvccStore, Timestamp}, percolator::Percolator, rpc::RpcClient, scheduler::Scheduler, two_phase_commit::Coordinator, txn::{Key, MicroOp, TxnError}};
+use tokio::{sync::mpsc::UnboundedSender, time::Instant};
+use tracing::{debug, warn, Instrument};
+use std::{collections::{BTreeMap, HashMap, HashSet, VecDeque}, fmt::Display, sync::atomic::{AtomicUsize, Ordering}, time::Duration};
+use std::sync::{Arc, Mutex};
+use clap::{Parser, ValueEnum};
+
+/// Picking an engine and level for each transaction, and retrying it on conflicts.
+mod engine;
+/// The local engine's isolation and consistency levels.
+mod isolation;
+/// Transactions across shards, with `--sharding`, committed by two-phase commit.
+mod sharded;
+
+pub use engine::{execute, execute_with_retries};
+pub use isolation::{execute_locking, execute_occ, execute_read_committed, execute_read_uncommitted, execute_snapshot};
+pub use sharded::{execute_sharded, finish_every_so_often, shard_execute, shard_finish, FINISHED_TTL};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Engine {
+    /// Apply each transaction atomically to an in-memory map.
+    Local,
+    /// Percolator-style snapshot isolation over lin-kv, with timestamps from lin-tso.
+    Percolator,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Isolation {
+    /// Run each transaction start to finish while holding the state lock.
+    Serializable,
+    /// Read from an MVCC snapshot taken when the transaction starts, and abort at
+    /// commit time if anything we wrote was committed by someone else since then.
+    Snapshot,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Cc {
+    /// Run against a snapshot, then validate that nothing we read was overwritten
+    /// before applying our writes.
+    Occ,
+    /// Strict two-phase locking: shared locks for reads, exclusive locks for writes,
+    /// all held until commit. Deadlock victims abort.
+    Locking,
+}
+
+
+/// Weaker-than-serializable levels for the local engine, named after the Maelstrom
+/// consistency models they're meant to pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Consistency {
+    /// Writes go straight to the registers under long exclusive locks, and reads take
+    /// no locks at all. Prevents G0 (dirty writes).
+    ReadUncommitted,
+    /// Writes are buffered and installed all at once at commit, and reads only ever
+    /// see committed state. Prevents G0, G1a, G1b and G1c.
+    ReadCommitted,
+    /// Long shared locks for reads and long exclusive locks for writes, so anything
+    /// a transaction read stays put until it commits. Also prevents G2-item.
+    RepeatableRead,
+}
+
+
+#[derive(Debug, Clone, Parser)]
+#[clap(author, version)]
+pub struct Opts {
+    #[clap(long, value_enum, default_value_t = Engine::Local, help = "Where transactions are executed.", env = "ENGINE")]
+    pub engine: Engine,
+    #[clap(long, value_enum, default_value_t = Isolation::Serializable, help = "Isolation level for the local engine. The percolator engine always provides snapshot isolation.", env = "ISOLATION")]
+    pub isolation: Isolation,
+    #[clap(long, value_enum, help = "Run the local engine at a weaker consistency level instead. Takes precedence over --isolation and --cc.", env = "CONSISTENCY")]
+    pub consistency: Option<Consistency>,
+    #[clap(long, value_enum, help = "Concurrency control for serializable transactions on the local engine. Without it, each transaction holds the state lock from start to finish.", env = "CC")]
+    pub cc: Option<Cc>,
+    #[clap(long, default_value_t = 5, help = "How many times a transaction is attempted before a conflict is reported to the client. 1 disables retries.", env = "TXN_ATTEMPTS")]
+    pub txn_attempts: usize,
+    #[clap(long, default_value_t = 5, help = "Number of milliseconds to back off before the first retry of a conflicted transaction. Doubles with every retry.", env = "RETRY_BACKOFF_MS")]
+    pub retry_backoff_ms: u64,
+    #[clap(long, default_value_t = 200, help = "Upper bound on the backoff between retries, in milliseconds.", env = "MAX_RETRY_BACKOFF_MS")]
+    pub max_retry_backoff_ms: u64,
+    #[clap(long, default_value_t = 1000, help = "Number of milliseconds to wait on lin-kv/lin-tso before giving up on a request.", env = "KV_TIMEOUT_MS")]
+    pub kv_timeout_ms: u64,
+    #[clap(long, default_value_t = 3000, help = "Number of milliseconds a percolator transaction's locks are good for. Whoever runs into a lock whose transaction's primary lock is older than this rolls that transaction back.", env = "LOCK_TTL_MS")]
+    pub lock_ttl_ms: u64,
+    #[clap(long, help = "Partition keys across the cluster with consistent hashing, and forward micro-ops to whichever node owns their key. Only applies to the local engine, and always uses two-phase locking.", env = "SHARDING")]
+    pub sharding: bool,
+    #[clap(long, default_value_t = 1000, help = "Number of milliseconds to wait on another shard before aborting a sharded transaction.", env = "SHARD_TIMEOUT_MS")]
+    pub shard_timeout_ms: u64,
+    #[clap(long, default_value_t = 3, help = "How many times the owner of a shard is told how a sharded transaction ended before the client is told the outcome is unknown. The owner keeps being told after that, every --shard-timeout-ms.", env = "FINISH_ATTEMPTS")]
+    pub finish_attempts: usize,
+    #[clap(long, default_value_t = 5000, help = "Number of milliseconds clients wait on a transaction before giving up on it. Nothing is retried, and no shard is waited on, past that.", env = "CLIENT_TIMEOUT_MS")]
+    pub client_timeout_ms: u64,
+    #[clap(long, help = "Fail requests to lin-kv, lin-tso or another shard straight away with temporarily_unavailable once this many in a row have timed out, until the cool-down is over.", env = "CIRCUIT_BREAKER_THRESHOLD")]
+    pub circuit_breaker_threshold: Option<u32>,
+    #[clap(long, default_value_t = 1000, help = "Number of milliseconds to fail requests to an unresponsive destination for, before trying it again.", env = "CIRCUIT_BREAKER_COOL_DOWN_MS")]
+    pub circuit_breaker_cool_down_ms: u64,
+    #[clap(flatten)]
+    pub common: CommonOpts,
+}
+
+
+impl Opts {
+    /// Whether the local engine keeps its data in the MVCC store rather than in
+    /// plain registers.
+    pub fn versioned(&self) -> bool {
+        !self.sharded() && self.consistency.is_none() && (self.isolation == Isolation::Snapshot || self.cc == Some(Cc::Occ))
+    }
+
+    pub fn sharded(&self) -> bool {
+        self.sharding && self.engine == Engine::Local
+    }
+}
+
+
+static MSG_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Payload {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+    Txn {
+        txn: Vec<MicroOp>,
+    },
+    TxnOk {
+        txn: Vec<MicroOp>,
+    },
+    Error {
+        code: usize,
+        #[serde(default)]
+        text: String,
+    },
+
+    // What we speak with lin-kv and lin-tso when running the percolator engine.
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        create_if_not_exists: Option<bool>,
+    },
+    CasOk,
+    Ts,
+    TsOk {
+        ts: u64,
+    },
+
+    // Between shards, when a transaction touches keys owned by other nodes.
+    ShardExecute {
+        id: TxnId,
+        txn: Vec<MicroOp>,
+        /// How many milliseconds are left before the client gives up on the transaction.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        budget_ms: Option<u64>,
+    },
+    ShardExecuteOk {
+        txn: Vec<MicroOp>,
+    },
+    ShardFinish {
+        id: TxnId,
+        commit: bool,
+    },
+    ShardFinishOk,
+}
+
+fn message_id() -> usize {
+    MSG_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+
+/// Identifies a transaction to the lock manager of every shard it touches: a count of
+/// the transactions its node has started, and that node's place among the node ids at
+/// init, so transactions coordinated by different nodes never collide. Later
+/// transactions from a node get larger ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct TxnId {
+    pub seq: u64,
+    pub node: usize,
+}
+
+impl Display for TxnId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.node, self.seq)
+    }
+}
+
+
+#[derive(Debug)]
+pub struct State {
+    my_id: String,
+    registers: HashMap<Key, usize>,
+    versions: MvccStore<Key, usize>,
+    /// Start timestamps of in-flight snapshot transactions, so we know which versions
+    /// are still visible to someone.
+    active: BTreeMap<Timestamp, usize>,
+    ring: HashRing,
+    /// Writes buffered by sharded transactions we've executed ops for, waiting to hear
+    /// whether to apply them.
+    pending: HashMap<TxnId, Vec<(Key, usize)>>,
+    /// Sharded transactions we've already been told the outcome of, so anything about
+    /// them that arrives late is turned away instead of taking locks nobody will release.
+    /// Forgotten after [`FINISHED_TTL`], oldest first from `finished_at`.
+    finished: HashSet<TxnId>,
+    finished_at: VecDeque<(Instant, TxnId)>,
+    /// The outcomes of sharded transactions we coordinated that some shard has yet to
+    /// acknowledge, resent by [`finish_every_so_often`] until it does.
+    finishing: Coordinator<()>,
+    /// Where this node is among the node ids at init, and how many transactions it's
+    /// started, for their [`TxnId`]s.
+    node_index: usize,
+    txn_seq: u64,
+    /// What deadlines, retries and finished transactions are timed by.
+    clock: Arc<dyn Clock>,
+}
+
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            my_id: String::new(),
+            registers: HashMap::new(),
+            versions: MvccStore::new(),
+            active: BTreeMap::new(),
+            ring: HashRing::default(),
+            pending: HashMap::new(),
+            finished: HashSet::new(),
+            finished_at: VecDeque::new(),
+            // Set up properly once we know who we are.
+            finishing: Coordinator::new("", Duration::from_millis(1000), Duration::from_millis(1000)),
+            node_index: 0,
+            txn_seq: 0,
+            clock: clock::system(),
+        }
+    }
+}
+
+
+impl State {
+    /// Time deadlines, retries and finished transactions by `clock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    /// An id for a new transaction coordinated by this node.
+    pub fn next_txn_id(&mut self) -> TxnId {
+        self.txn_seq += 1;
+        TxnId { seq: self.txn_seq, node: self.node_index }
+    }
+
+    /// Run the whole transaction against our registers in one go. We hold the lock
+    /// throughout, so transactions are trivially serializable on a single node.
+    pub fn apply(&mut self, txn: Vec<MicroOp>) -> Vec<MicroOp> {
+        txn
+        .into_iter()
+        .map(|op| match op {
+            MicroOp::Read { key, .. } => MicroOp::Read { key, value: self.registers.get(&key).copied() },
+            MicroOp::Write { key, value } => {
+                self.registers.insert(key, value);
+                MicroOp::Write { key, value }
+            },
+        })
+        .collect()
+    }
+
+    /// Answer a transaction that only reads. Every read happens under the one borrow of
+    /// the state, and writers only ever install their writes while holding the state
+    /// lock, so this is a consistent snapshot without any locks or validation.
+    pub fn read_only(&self, txn: Vec<MicroOp>, versioned: bool) -> Vec<MicroOp> {
+        let snapshot = self.versions.snapshot(self.versions.latest_commit_ts());
+        txn
+        .into_iter()
+        .map(|op| {
+            let key = op.key();
+            let value = match versioned {
+                true => snapshot.get(&key).copied(),
+                false => self.registers.get(&key).copied(),
+            };
+            MicroOp::Read { key, value }
+        })
+        .collect()
+    }
+}
+
+
+pub async fn handle_envelope(
+    state: Arc<Mutex<State>>,
+    percolator: Percolator<Payload>,
+    locks: Arc<Mutex<LockManager<Key, TxnId>>>,
+    rpc: RpcClient<Payload>,
+    opts: Arc<Opts>,
+    envelope: Envelope<Payload>,
+    writer: UnboundedSender<Envelope<Payload>>
+) {
+    match &envelope.body.message {
+        Payload::Init { node_id, node_ids } => {
+            let mut state = state.lock().unwrap();
+            state.my_id = node_id.clone();
+            state.ring = HashRing::new(node_ids);
+            let timeout = Duration::from_millis(opts.shard_timeout_ms);
+            state.finishing = Coordinator::new(node_id, timeout, timeout);
+            state.node_index = node_ids.iter().position(|id| id == node_id).unwrap_or_default();
+
+            let reply = envelope.reply_with(
+                Some(message_id()),
+                Payload::InitOk
+            );
+            writer.send(reply).unwrap();
+        },
+        Payload::Txn { txn } => {
+            let deadline = Deadline::after(state.lock().unwrap().clock(), Duration::from_millis(opts.client_timeout_ms));
+            let result = deadline::scope(Some(deadline), execute_with_retries(&state, &percolator, &locks, &rpc, &opts, txn)).await;
+            let payload = match result {
+                Ok(txn) => Payload::TxnOk { txn },
+                Err(err) => {
+                    warn!(error = %err, "transaction failed");
+                    Payload::Error { code: err.code().code(), text: err.to_string() }
+                }
+            };
+
+            let reply = envelope.reply_with(
+                Some(message_id()),
+                payload
+            );
+            writer.send(reply).unwrap();
+        },
+        Payload::ShardExecute { id, txn, budget_ms } => {
+            let deadline = budget_ms.map(|budget_ms| Deadline::from_budget_ms(state.lock().unwrap().clock(), budget_ms));
+            let executed = deadline::scope(deadline, deadline::within(shard_execute(&state, &locks, *id, txn.clone()))).await;
+            let payload = match executed {
+                Some(Ok(txn)) => Payload::ShardExecuteOk { txn },
+                Some(Err(err)) => Payload::Error { code: err.code().code(), text: err.to_string() },
+                None => {
+                    // The coordinator has stopped waiting by now, and will abort it.
+                    debug!(%id, "abandoning shard of a transaction that ran out of time");
+                    metrics::global().increment("txn.abandoned");
+                    shard_finish(&state, &locks, *id, false);
+                    let err = TxnError::Conflict(format!("ran out of time executing txn {id}"));
+                    Payload::Error { code: err.code().code(), text: err.to_string() }
+                },
+            };
+            let reply = envelope.reply_with(
+                Some(message_id()),
+                payload
+            );
+            writer.send(reply).unwrap();
+        },
+        Payload::ShardFinish { id, commit } => {
+            shard_finish(&state, &locks, *id, *commit);
+            let reply = envelope.reply_with(
+                Some(message_id()),
+                Payload::ShardFinishOk
+            );
+            writer.send(reply).unwrap();
+        },
+        _ => {}
+    }
+}
+
+
+pub async fn server(opts: Opts) {
+    debug!(opts = ?opts, "starting server...");
+    let state = Arc::new(Mutex::new(State::default()));
+    let locks = Arc::new(Mutex::new(LockManager::new()));
+    let (writer, mut reader, _) = io_channel::<Envelope<Payload>>();
+
+    let rpc = RpcClient::new(writer.clone(), message_id);
+    if let Some(threshold) = opts.circuit_breaker_threshold {
+        rpc.set_circuit_breaker(CircuitBreaker::new(threshold, Duration::from_millis(opts.circuit_breaker_cool_down_ms)));
+    }
+    let scheduler = Scheduler::new();
+    if opts.sharded() {
+        finish_every_so_often(&scheduler, state.clone(), rpc.clone(), Duration::from_millis(opts.shard_timeout_ms));
+    }
+    let timeout = Duration::from_millis(opts.kv_timeout_ms);
+    let opts = Arc::new(opts);
+    let percolator = Percolator::new(
+        KvClient::lin_kv(rpc.clone(), timeout),
+        KvClient::lin_tso(rpc.clone(), timeout),
+    )
+    .with_lock_ttl(Duration::from_millis(opts.lock_ttl_ms));
+
+    while let Some(envelope) = reader.recv().await {
+        let Some(envelope) = rpc.try_complete(envelope) else {
+            continue;
+        };
+        if let Payload::Init { node_id, .. } = &envelope.body.message {
+            rpc.set_node_id(node_id);
+        }
+        // Percolator transactions wait on lin-kv replies, which only arrive through this
+        // loop, so handlers can't be awaited inline.
+        let handler = format!("handler.{}", metrics::message_type(&envelope.body.message));
+        let span = io::handler_span(&envelope);
+        let handled = handle_envelope(state.clone(), percolator.clone(), locks.clone(), rpc.clone(), opts.clone(), envelope, writer.clone()).instrument(span);
+        let name = handler.clone();
+        task::spawn(&name, async move { metrics::timed(&handler, handled).await });
+    }
+}
@@ -0,0 +1,77 @@
+use crate::{backoff::{Backoff, Jitter}, deadline, lock_manager::LockManager, metrics, percolator::Percolator, rpc::RpcClient, txn::{Key, MicroOp, TxnError}};
+use tracing::debug;
+use std::{sync::Mutex, time::Duration};
+use super::{execute_locking, execute_occ, execute_read_committed, execute_read_uncommitted, execute_sharded, execute_snapshot, Cc, Consistency, Engine, Isolation, Opts, Payload, State, TxnId};
+
+
+/// Run a transaction once with whichever engine and level we were started with.
+pub async fn execute(
+    state: &Mutex<State>,
+    percolator: &Percolator<Payload>,
+    locks: &Mutex<LockManager<Key, TxnId>>,
+    rpc: &RpcClient<Payload>,
+    opts: &Opts,
+    txn: Vec<MicroOp>,
+) -> Result<Vec<MicroOp>, TxnError> {
+    if opts.sharded() {
+        return execute_sharded(state, locks, rpc, opts, txn).await;
+    }
+    if opts.engine == Engine::Local && txn.iter().all(MicroOp::is_read) {
+        return Ok(state.lock().unwrap().read_only(txn, opts.versioned()));
+    }
+    match opts.engine {
+        Engine::Local => match (opts.consistency, opts.isolation, opts.cc) {
+            (Some(Consistency::ReadUncommitted), _, _) => execute_read_uncommitted(state, locks, txn).await,
+            (Some(Consistency::ReadCommitted), _, _) => Ok(execute_read_committed(state, txn)),
+            (Some(Consistency::RepeatableRead), _, _) => execute_locking(state, locks, txn).await,
+            (None, Isolation::Snapshot, _) => execute_snapshot(state, txn),
+            (None, Isolation::Serializable, None) => Ok(state.lock().unwrap().apply(txn)),
+            (None, Isolation::Serializable, Some(Cc::Occ)) => execute_occ(state, txn),
+            (None, Isolation::Serializable, Some(Cc::Locking)) => execute_locking(state, locks, txn).await,
+        },
+        Engine::Percolator => percolator.execute(txn).await,
+    }
+}
+
+
+/// Run a transaction, retrying it with exponential backoff (plus jitter) for as long
+/// as it aborts on a conflict, up to `--txn-attempts` times. Conflicts mean nothing was
+/// committed, so it's always safe to try again before bothering the client.
+pub async fn execute_with_retries(
+    state: &Mutex<State>,
+    percolator: &Percolator<Payload>,
+    locks: &Mutex<LockManager<Key, TxnId>>,
+    rpc: &RpcClient<Payload>,
+    opts: &Opts,
+    txn: &[MicroOp],
+) -> Result<Vec<MicroOp>, TxnError> {
+    let mut backoff =
+        Backoff::new(Duration::from_millis(opts.retry_backoff_ms))
+        .max(Duration::from_millis(opts.max_retry_backoff_ms))
+        .jitter(Jitter::Equal);
+    let mut attempt = 1;
+
+    loop {
+        match execute(state, percolator, locks, rpc, opts, txn.to_vec()).await {
+            Err(TxnError::Conflict(reason)) if attempt < opts.txn_attempts => {
+                let delay = backoff.next_delay();
+                if deadline::current().is_some_and(|deadline| deadline.remaining() <= delay) {
+                    debug!(attempt, reason, "out of time to retry conflicted transaction");
+                    metrics::global().increment("txn.conflicts");
+                    return Err(TxnError::Conflict(reason));
+                }
+                debug!(attempt, reason, ?delay, "retrying conflicted transaction");
+                metrics::global().increment("txn.retries");
+                let clock = state.lock().unwrap().clock();
+                clock.sleep(delay).await;
+                attempt += 1;
+            },
+            result => {
+                if let Err(TxnError::Conflict(_)) = &result {
+                    metrics::global().increment("txn.conflicts");
+                }
+                return result;
+            },
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use crate::{lock_manager::{self, LockManager, LockMode}, mvcc::Timestamp, txn::{Key, MicroOp, TxnError}};
+use std::{collections::HashSet, sync::Mutex};
+use super::{State, TxnId};
+
+
+impl State {
+    pub fn begin_snapshot(&mut self) -> Timestamp {
+        let start_ts = self.versions.latest_commit_ts();
+        *self.active.entry(start_ts).or_default() += 1;
+        start_ts
+    }
+
+    pub fn end_snapshot(&mut self, start_ts: Timestamp) {
+        if let Some(count) = self.active.get_mut(&start_ts) {
+            *count -= 1;
+            if *count == 0 {
+                self.active.remove(&start_ts);
+            }
+        }
+        let watermark = self.active.keys().next().copied().unwrap_or(self.versions.latest_commit_ts());
+        self.versions.gc(watermark);
+    }
+
+    /// First-committer-wins: the writes only land if none of their keys were
+    /// committed by anyone else after `start_ts`.
+    pub fn commit_snapshot(&mut self, start_ts: Timestamp, writes: Vec<(Key, usize)>) -> Result<(), TxnError> {
+        let written: Vec<Key> = writes.iter().map(|(key, _)| *key).collect();
+        self.commit_validated(start_ts, &written, writes)
+    }
+
+    /// Apply `writes` as a new version, as long as none of the `validate` keys were
+    /// committed by anyone else after `start_ts`.
+    pub fn commit_validated(&mut self, start_ts: Timestamp, validate: &[Key], writes: Vec<(Key, usize)>) -> Result<(), TxnError> {
+        if let Some(key) = validate.iter().find(|key| self.versions.written_since(key, start_ts)) {
+            return Err(TxnError::Conflict(format!("key {key} was written after our snapshot at {start_ts}")));
+        }
+        if !writes.is_empty() {
+            let commit_ts = self.versions.latest_commit_ts() + 1;
+            self.versions.commit(commit_ts, writes.into_iter().map(|(key, value)| (key, Some(value))));
+        }
+        Ok(())
+    }
+}
+
+
+/// Run a transaction under snapshot isolation. The state lock is only held for each
+/// individual step, so other transactions are free to commit in between.
+pub fn execute_snapshot(state: &Mutex<State>, txn: Vec<MicroOp>) -> Result<Vec<MicroOp>, TxnError> {
+    let start_ts = state.lock().unwrap().begin_snapshot();
+    let mut writes: Vec<(Key, usize)> = vec![];
+
+    let completed =
+        txn
+        .into_iter()
+        .map(|op| match op {
+            MicroOp::Read { key, .. } => {
+                let value = match writes.iter().rev().find(|(written, _)| *written == key) {
+                    Some((_, value)) => Some(*value),
+                    None => state.lock().unwrap().versions.snapshot(start_ts).get(&key).copied(),
+                };
+                MicroOp::Read { key, value }
+            },
+            MicroOp::Write { key, value } => {
+                writes.push((key, value));
+                MicroOp::Write { key, value }
+            },
+        })
+        .collect();
+
+    let mut state = state.lock().unwrap();
+    let result = state.commit_snapshot(start_ts, writes);
+    state.end_snapshot(start_ts);
+    result.map(|_| completed)
+}
+
+
+/// Run a transaction optimistically: read from a snapshot while recording what we
+/// read, then validate that none of it changed before committing.
+pub fn execute_occ(state: &Mutex<State>, txn: Vec<MicroOp>) -> Result<Vec<MicroOp>, TxnError> {
+    let start_ts = state.lock().unwrap().begin_snapshot();
+    let mut reads: HashSet<Key> = HashSet::new();
+    let mut writes: Vec<(Key, usize)> = vec![];
+
+    let completed =
+        txn
+        .into_iter()
+        .map(|op| match op {
+            MicroOp::Read { key, .. } => {
+                let value = match writes.iter().rev().find(|(written, _)| *written == key) {
+                    Some((_, value)) => Some(*value),
+                    None => {
+                        reads.insert(key);
+                        state.lock().unwrap().versions.snapshot(start_ts).get(&key).copied()
+                    },
+                };
+                MicroOp::Read { key, value }
+            },
+            MicroOp::Write { key, value } => {
+                writes.push((key, value));
+                MicroOp::Write { key, value }
+            },
+        })
+        .collect();
+
+    let mut state = state.lock().unwrap();
+    let reads: Vec<Key> = reads.into_iter().collect();
+    let result = state.commit_validated(start_ts, &reads, writes);
+    state.end_snapshot(start_ts);
+    result.map(|_| completed)
+}
+
+
+/// Run a transaction at read uncommitted: each write is applied in place once we hold
+/// an exclusive lock on its key, and those locks are held until the end so no two
+/// transactions ever interleave writes to the same key. Reads don't lock, so they may
+/// well see someone else's uncommitted writes.
+pub async fn execute_read_uncommitted(state: &Mutex<State>, locks: &Mutex<LockManager<Key, TxnId>>, txn: Vec<MicroOp>) -> Result<Vec<MicroOp>, TxnError> {
+    let id = state.lock().unwrap().next_txn_id();
+    let mut undo: Vec<(Key, Option<usize>)> = vec![];
+    let mut completed = Vec::with_capacity(txn.len());
+
+    for op in txn {
+        match op {
+            MicroOp::Read { key, .. } => {
+                let value = state.lock().unwrap().registers.get(&key).copied();
+                completed.push(MicroOp::Read { key, value });
+            },
+            MicroOp::Write { key, value } => {
+                if let Err(err) = lock_manager::lock(locks, id, key, LockMode::Exclusive).await {
+                    // Put back what we overwrote before anyone else can write those keys.
+                    let mut state = state.lock().unwrap();
+                    for (key, previous) in undo.into_iter().rev() {
+                        match previous {
+                            Some(previous) => state.registers.insert(key, previous),
+                            None => state.registers.remove(&key),
+                        };
+                    }
+                    drop(state);
+                    locks.lock().unwrap().release_all(id);
+                    return Err(TxnError::Conflict(err.to_string()));
+                }
+                let previous = state.lock().unwrap().registers.insert(key, value);
+                undo.push((key, previous));
+                completed.push(MicroOp::Write { key, value });
+            },
+        }
+    }
+
+    locks.lock().unwrap().release_all(id);
+    Ok(completed)
+}
+
+
+/// Run a transaction at read committed: reads see whatever is committed at the time,
+/// and writes are buffered and installed in one step at commit. Since every
+/// transaction's writes become visible at once and only after it's done reading,
+/// nobody can observe an intermediate or aborted write.
+pub fn execute_read_committed(state: &Mutex<State>, txn: Vec<MicroOp>) -> Vec<MicroOp> {
+    let mut writes: Vec<(Key, usize)> = vec![];
+    let completed =
+        txn
+        .into_iter()
+        .map(|op| match op {
+            MicroOp::Read { key, .. } => {
+                let value = match writes.iter().rev().find(|(written, _)| *written == key) {
+                    Some((_, value)) => Some(*value),
+                    None => state.lock().unwrap().registers.get(&key).copied(),
+                };
+                MicroOp::Read { key, value }
+            },
+            MicroOp::Write { key, value } => {
+                writes.push((key, value));
+                MicroOp::Write { key, value }
+            },
+        })
+        .collect();
+
+    state.lock().unwrap().registers.extend(writes);
+    completed
+}
+
+
+/// Run a transaction under strict two-phase locking: every key is locked before it's
+/// touched, writes are buffered, and nothing is released until they've been applied.
+pub async fn execute_locking(state: &Mutex<State>, locks: &Mutex<LockManager<Key, TxnId>>, txn: Vec<MicroOp>) -> Result<Vec<MicroOp>, TxnError> {
+    let id = state.lock().unwrap().next_txn_id();
+    let mut writes: Vec<(Key, usize)> = vec![];
+    let mut completed = Vec::with_capacity(txn.len());
+
+    for op in txn {
+        let mode = if op.is_read() { LockMode::Shared } else { LockMode::Exclusive };
+        if let Err(err) = lock_manager::lock(locks, id, op.key(), mode).await {
+            locks.lock().unwrap().release_all(id);
+            return Err(TxnError::Conflict(err.to_string()));
+        }
+        match op {
+            MicroOp::Read { key, .. } => {
+                let value = match writes.iter().rev().find(|(written, _)| *written == key) {
+                    Some((_, value)) => Some(*value),
+                    None => state.lock().unwrap().registers.get(&key).copied(),
+                };
+                completed.push(MicroOp::Read { key, value });
+            },
+            MicroOp::Write { key, value } => {
+                writes.push((key, value));
+                completed.push(MicroOp::Write { key, value });
+            },
+        }
+    }
+
+    state.lock().unwrap().registers.extend(writes);
+    locks.lock().unwrap().release_all(id);
+    Ok(completed)
+}
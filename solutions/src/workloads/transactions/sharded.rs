@@ -0,0 +1,249 @@
+use crate::{deadline, kv::KvError, lock_manager::{self, LockManager, LockMode}, metrics, rpc::{RpcClient, RpcError}, scheduler::{Job, JobHandle, Period, Scheduler}, task, two_phase_commit::{self, Decision, Outgoing}, txn::{Key, MicroOp, TxnError}};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+use std::{collections::BTreeMap, sync::{Arc, Mutex}, time::Duration};
+use super::{execute_locking, Opts, Payload, State, TxnId};
+
+
+/// How long a shard remembers that a transaction finished. Far longer than any
+/// `shard_execute` could be held up for, so late ones are still turned away, without
+/// keeping every transaction ever finished.
+pub const FINISHED_TTL: Duration = Duration::from_secs(60);
+
+
+impl State {
+    /// Remember that sharded transaction `id` finished at `now`, forgetting those that
+    /// finished more than [`FINISHED_TTL`] before.
+    pub fn finish(&mut self, id: TxnId, now: Instant) {
+        while let Some(&(at, old)) = self.finished_at.front() {
+            if now.saturating_duration_since(at) < FINISHED_TTL {
+                break;
+            }
+            self.finished_at.pop_front();
+            self.finished.remove(&old);
+        }
+        if self.finished.insert(id) {
+            self.finished_at.push_back((now, id));
+        }
+    }
+
+    /// How many finished sharded transactions we still remember.
+    pub fn num_finished(&self) -> usize {
+        self.finished.len()
+    }
+}
+
+
+/// Our share of a sharded transaction: lock and run `txn` (every key of which we own),
+/// keeping its writes pending until the coordinator tells us how it ended.
+pub async fn shard_execute(state: &Mutex<State>, locks: &Mutex<LockManager<Key, TxnId>>, id: TxnId, txn: Vec<MicroOp>) -> Result<Vec<MicroOp>, TxnError> {
+    let mut completed = Vec::with_capacity(txn.len());
+    for op in txn {
+        let mode = if op.is_read() { LockMode::Shared } else { LockMode::Exclusive };
+        let locked = lock_manager::lock(locks, id, op.key(), mode).await;
+
+        let mut state = state.lock().unwrap();
+        let failed = match (state.finished.contains(&id), locked) {
+            (true, _) => Some(TxnError::Conflict(format!("txn {id} already finished"))),
+            (false, Err(err)) => Some(TxnError::Conflict(err.to_string())),
+            (false, Ok(())) => None,
+        };
+        if let Some(err) = failed {
+            drop(state);
+            locks.lock().unwrap().release_all(id);
+            return Err(err);
+        }
+
+        match op {
+            MicroOp::Read { key, .. } => {
+                let pending = state.pending.get(&id).and_then(|writes| writes.iter().rev().find(|(written, _)| *written == key));
+                let value = match pending {
+                    Some((_, value)) => Some(*value),
+                    None => state.registers.get(&key).copied(),
+                };
+                completed.push(MicroOp::Read { key, value });
+            },
+            MicroOp::Write { key, value } => {
+                state.pending.entry(id).or_default().push((key, value));
+                completed.push(MicroOp::Write { key, value });
+            },
+        }
+    }
+    Ok(completed)
+}
+
+
+/// Apply (or throw away) a sharded transaction's pending writes and release its locks.
+/// Hearing it again (the coordinator retrying) changes nothing.
+pub fn shard_finish(state: &Mutex<State>, locks: &Mutex<LockManager<Key, TxnId>>, id: TxnId, commit: bool) {
+    let mut state = state.lock().unwrap();
+    let now = state.clock.now();
+    state.finish(id, now);
+    if let Some(writes) = state.pending.remove(&id) {
+        if commit {
+            state.registers.extend(writes);
+        }
+    }
+    drop(state);
+    locks.lock().unwrap().release_all(id);
+}
+
+
+/// Coordinate a transaction across every shard it touches: each owner locks and runs its
+/// ops (two-phase locking, with writes held back), and only once all of them succeeded
+/// are they told to apply their writes. Any failure aborts the lot, so it's safe to retry.
+pub async fn execute_sharded(
+    state: &Mutex<State>,
+    locks: &Mutex<LockManager<Key, TxnId>>,
+    rpc: &RpcClient<Payload>,
+    opts: &Opts,
+    txn: Vec<MicroOp>,
+) -> Result<Vec<MicroOp>, TxnError> {
+    let (my_id, shards) = {
+        let state = state.lock().unwrap();
+        let mut shards: BTreeMap<String, Vec<(usize, MicroOp)>> = BTreeMap::new();
+        for (index, op) in txn.iter().enumerate() {
+            let owner = state.ring.owner(&op.key()).map_or(state.my_id.as_str(), |owner| owner);
+            shards.entry(owner.to_owned()).or_default().push((index, op.clone()));
+        }
+        (state.my_id.clone(), shards)
+    };
+
+    if shards.keys().all(|owner| *owner == my_id) {
+        if txn.iter().all(MicroOp::is_read) {
+            return Ok(state.lock().unwrap().read_only(txn, false));
+        }
+        return execute_locking(state, locks, txn).await;
+    }
+
+    let id = state.lock().unwrap().next_txn_id();
+    let timeout = Duration::from_millis(opts.shard_timeout_ms);
+    let mut completed: Vec<Option<MicroOp>> = vec![None; txn.len()];
+    let mut result = Ok(());
+
+    for (owner, ops) in &shards {
+        let (indices, ops): (Vec<usize>, Vec<MicroOp>) = ops.iter().cloned().unzip();
+        let budget_ms = deadline::current().map(|deadline| deadline.budget_ms());
+        let executed = match (*owner == my_id, budget_ms) {
+            (_, Some(0)) => Err(TxnError::Conflict(format!("ran out of time before {owner} could execute txn {id}"))),
+            (true, _) => shard_execute(state, locks, id, ops).await,
+            (false, _) => match rpc.call(owner, Payload::ShardExecute { id, txn: ops, budget_ms }, timeout).await {
+                Ok(reply) => match reply.body.message {
+                    Payload::ShardExecuteOk { txn } => Ok(txn),
+                    Payload::Error { text, .. } => Err(TxnError::Conflict(format!("{owner}: {text}"))),
+                    other => Err(TxnError::Conflict(format!("unexpected reply from {owner}: {other:?}"))),
+                },
+                // Nothing was sent, so there's nothing to retry against either.
+                Err(RpcError::CircuitOpen) => Err(TxnError::Unavailable(KvError::Rpc(RpcError::CircuitOpen))),
+                // Most likely a deadlock across shards, which no single lock manager can see.
+                Err(err) => Err(TxnError::Conflict(format!("{owner} didn't execute txn {id}: {err}"))),
+            },
+        };
+        match executed {
+            Ok(ops) => {
+                for (index, op) in indices.into_iter().zip(ops) {
+                    completed[index] = Some(op);
+                }
+            },
+            Err(err) => {
+                result = Err(err);
+                break;
+            },
+        }
+    }
+
+    let commit = result.is_ok();
+    debug!(%id, commit, shards = ?shards.keys().collect::<Vec<_>>(), "finishing sharded transaction");
+    // Every shard has to hear how it ended, whether or not the client is still waiting.
+    let finished = deadline::scope(None, finish_sharded(state, locks, rpc, opts, id, shards.keys(), commit)).await;
+    result.and(finished).map(|_| completed.into_iter().flatten().collect())
+}
+
+
+/// Tell every one of the `owners` of transaction `id`'s shards whether to apply its
+/// writes. The outcome is unknown if one of them never acknowledges a commit in time,
+/// but either way the decision stays with `finishing` until every owner acknowledges
+/// it, so no shard is left holding locks, or without a commit the others applied.
+async fn finish_sharded<'a>(
+    state: &Mutex<State>,
+    locks: &Mutex<LockManager<Key, TxnId>>,
+    rpc: &RpcClient<Payload>,
+    opts: &Opts,
+    id: TxnId,
+    owners: impl Iterator<Item = &'a String>,
+    commit: bool,
+) -> Result<(), TxnError> {
+    let my_id = state.lock().unwrap().my_id.clone();
+    let (local, remote): (Vec<&String>, Vec<&String>) = owners.partition(|owner| **owner == my_id);
+    if !local.is_empty() {
+        shard_finish(state, locks, id, commit);
+    }
+
+    let txn = two_phase_commit::TxnId { coordinator: my_id, seq: id.seq };
+    let decision = if commit { Decision::Commit } else { Decision::Abort };
+    let step = {
+        let mut state = state.lock().unwrap();
+        let now = state.clock.now().into_std();
+        state.finishing.announce(txn.clone(), remote.into_iter().cloned(), decision, now)
+    };
+    let timeout = Duration::from_millis(opts.shard_timeout_ms);
+    let mut result = Ok(());
+    for Outgoing { destination: owner, .. } in step.outgoing {
+        let mut acked = false;
+        for _ in 0..opts.finish_attempts.max(1) {
+            if tell_finished(rpc, &owner, id, commit, timeout).await {
+                let mut state = state.lock().unwrap();
+                let now = state.clock.now().into_std();
+                state.finishing.on_message(&owner, two_phase_commit::Message::Ack { txn: txn.clone() }, now);
+                acked = true;
+                break;
+            }
+        }
+        if acked {
+            continue;
+        }
+        warn!(%id, owner, commit, "shard hasn't acknowledged how the txn ended; leaving it to the finish job");
+        metrics::global().increment("txn.unacknowledged_finishes");
+        if commit {
+            result = Err(TxnError::Indeterminate(KvError::GaveUp(format!("{owner} never acknowledged txn {id}"))));
+        }
+    }
+    result
+}
+
+/// Send `owner` the outcome of transaction `id`, and whether it acknowledged it.
+async fn tell_finished(rpc: &RpcClient<Payload>, owner: &str, id: TxnId, commit: bool, timeout: Duration) -> bool {
+    matches!(
+        rpc.call(owner, Payload::ShardFinish { id, commit }, timeout).await.map(|reply| reply.body.message),
+        Ok(Payload::ShardFinishOk)
+    )
+}
+
+
+/// Every `period`, as a `finish` job on `scheduler`: tell each shard that has yet to
+/// acknowledge how a sharded transaction we coordinated ended, again.
+pub fn finish_every_so_often(scheduler: &Scheduler, state: Arc<Mutex<State>>, rpc: RpcClient<Payload>, period: Duration) -> JobHandle {
+    scheduler.spawn(Job::new("finish", Period::Every(period)), move |now| {
+        let (step, node) = {
+            let mut state = state.lock().unwrap();
+            (state.finishing.tick(now.into_std()), state.node_index)
+        };
+        for Outgoing { destination: owner, message } in step.outgoing {
+            let (txn, commit) = match message {
+                two_phase_commit::Message::Commit { txn } => (txn, true),
+                two_phase_commit::Message::Abort { txn } => (txn, false),
+                _ => continue,
+            };
+            let (state, rpc) = (state.clone(), rpc.clone());
+            task::spawn("txn.finish", async move {
+                let id = TxnId { seq: txn.seq, node };
+                if tell_finished(&rpc, &owner, id, commit, period).await {
+                    debug!(%id, owner, commit, "shard acknowledged how the txn ended");
+                    let mut state = state.lock().unwrap();
+                    let now = state.clock.now().into_std();
+                    state.finishing.on_message(&owner, two_phase_commit::Message::Ack { txn }, now);
+                }
+            });
+        }
+    })
+}
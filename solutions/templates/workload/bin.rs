@@ -0,0 +1,6 @@
+use solutions::{cli, workloads::{self, {{name}}}};
+
+fn main() {
+    let opts = cli::parse::<{{name}}::Opts>();
+    workloads::run(opts.common.clone(), {{name}}::server(opts));
+}
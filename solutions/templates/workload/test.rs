@@ -0,0 +1,32 @@
+//! Runs the {{name}} node on the in-memory network.
+
+use std::time::Duration;
+use solutions::{cli::CommonOpts, message::{Body, Envelope}, sim::{Network, NetworkConfig}, workloads::{{name}}::{serve, Opts, Payload}};
+
+
+fn request(destination: &str, msg_id: usize, message: Payload) -> Envelope<Payload> {
+    Envelope::new("c1", destination, Body { msg_id: Some(msg_id), in_reply_to: None, message })
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn nodes_answer_init_and_read() {
+    let network = Network::new(NetworkConfig::default());
+    let nodes = ["n1", "n2"];
+    for node in nodes {
+        network.spawn(node, |writer, reader| serve(Opts { common: CommonOpts::default() }, writer, reader.into()));
+    }
+    let (client, mut replies) = network.connect("c1");
+
+    for node in nodes {
+        let init = Payload::Init { node_id: node.to_owned(), node_ids: nodes.map(str::to_owned).to_vec() };
+        client.send(request(node, 1, init)).unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(1), replies.recv()).await.unwrap().unwrap();
+        assert!(matches!(reply.body.message, Payload::InitOk), "{reply:?}");
+    }
+
+    client.send(request("n1", 2, Payload::Read)).unwrap();
+    let reply = tokio::time::timeout(Duration::from_secs(1), replies.recv()).await.unwrap().unwrap();
+    assert_eq!(reply.body.in_reply_to, Some(2));
+    assert!(matches!(reply.body.message, Payload::ReadOk { value: 0 }), "{reply:?}");
+}
@@ -0,0 +1,127 @@
+use serde::{Serialize, Deserialize};
+use crate::{cli::CommonOpts, io::{self, io_channel, Inbox}, message::Envelope, metrics, task};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, Instrument};
+use std::{collections::HashMap, sync::atomic::{AtomicUsize, Ordering}, time::Duration};
+use std::sync::{Arc, Mutex};
+use clap::Parser;
+
+#[derive(Debug, Clone, Parser)]
+#[clap(author, version)]
+pub struct Opts {
+    #[clap(flatten)]
+    pub common: CommonOpts,
+}
+
+
+static MSG_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Payload {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+    Topology {
+        topology: HashMap<String, Vec<String>>
+    },
+    TopologyOk,
+    Read,
+    ReadOk {
+        value: usize,
+    },
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+fn message_id() -> usize {
+    MSG_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+
+#[derive(Debug, Clone, Default)]
+pub struct State {
+    pub node_id: String,
+    pub node_ids: Vec<String>,
+    pub neighbors: Vec<String>,
+    pub tick_rate: Duration,
+    pub value: usize,
+}
+
+
+pub async fn handle_envelope(
+    state: Arc<Mutex<State>>,
+    envelope: Envelope<Payload>,
+    writer: UnboundedSender<Envelope<Payload>>
+) {
+    match &envelope.body.message {
+        Payload::Init { node_id, node_ids } => {
+            let mut state = state.lock().unwrap();
+            state.node_id = node_id.clone();
+            state.node_ids = node_ids.clone();
+
+            let reply = envelope.reply_with(
+                Some(message_id()),
+                Payload::InitOk
+            );
+            writer.send(reply).unwrap();
+        },
+        Payload::Topology { topology } => {
+            let mut state = state.lock().unwrap();
+            state.neighbors = topology.get(&state.node_id).cloned().unwrap_or_default();
+
+            let reply = envelope.reply_with(
+                Some(message_id()),
+                Payload::TopologyOk
+            );
+            writer.send(reply).unwrap();
+        },
+        Payload::Read => {
+            let state = state.lock().unwrap();
+            let reply = envelope.reply_with(
+                Some(message_id()),
+                Payload::ReadOk { value: state.value }
+            );
+            writer.send(reply).unwrap();
+        },
+        _ => {}
+    }
+}
+
+
+/// Whatever the node does in the background, once every tick.
+pub async fn tick_every_so_often(state: Arc<Mutex<State>>, _writer: UnboundedSender<Envelope<Payload>>) {
+    let mut interval = tokio::time::interval(state.lock().unwrap().tick_rate);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+    }
+}
+
+
+/// Run the node on any transport: stdio (see `server`), or the in-memory network in tests.
+pub async fn serve(opts: Opts, writer: UnboundedSender<Envelope<Payload>>, mut reader: Inbox<Envelope<Payload>>) {
+    let state = Arc::new(Mutex::new(State {
+        tick_rate: opts.common.tick_rate(),
+        ..Default::default()
+    }));
+
+    task::spawn("tick", tick_every_so_often(state.clone(), writer.clone()));
+
+    while let Some(envelope) = reader.recv().await {
+        let handler = format!("handler.{}", metrics::message_type(&envelope.body.message));
+        let span = io::handler_span(&envelope);
+        metrics::timed(&handler, handle_envelope(state.clone(), envelope, writer.clone())).instrument(span).await;
+    }
+}
+
+pub async fn server(opts: Opts) {
+    debug!(opts = ?opts, "starting server...");
+    let (writer, reader, _) = io_channel::<Envelope<Payload>>();
+    serve(opts, writer, reader).await;
+}
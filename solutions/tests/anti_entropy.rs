@@ -0,0 +1,111 @@
+//! Anti-entropy repair: Merkle trees narrow two nodes' differences down to a few
+//! buckets, and a round of repair copies whatever either is missing in them.
+
+use std::{collections::BTreeMap, future::ready, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, time::Duration};
+use solutions::{anti_entropy::{self, bucket, MerkleTree, Message, Replica}, scheduler::Scheduler};
+
+
+/// Each key's value and how many times it's been written: more writes is newer.
+#[derive(Debug, Default, PartialEq)]
+struct Store(BTreeMap<String, (u64, String)>);
+
+impl Replica for Store {
+    type Entry = (String, u64, String);
+
+    fn digests(&self, _peer: &str) -> Vec<(String, u64)> {
+        self.0.iter().map(|(key, (writes, _))| (key.clone(), *writes)).collect()
+    }
+
+    fn entry(&self, key: &str, theirs: Option<u64>) -> Option<Self::Entry> {
+        let (writes, value) = self.0.get(key)?;
+        (theirs < Some(*writes)).then(|| (key.to_owned(), *writes, value.clone()))
+    }
+
+    fn take(&mut self, (key, writes, value): Self::Entry) -> bool {
+        let kept = self.0.entry(key).or_default();
+        if kept.0 >= writes {
+            return false;
+        }
+        *kept = (writes, value);
+        true
+    }
+}
+
+fn store(entries: &[(&str, u64)]) -> Store {
+    Store(entries.iter().map(|&(key, writes)| (key.to_owned(), (writes, format!("{key}@{writes}")))).collect())
+}
+
+fn tree(store: &Store) -> MerkleTree {
+    MerkleTree::build(store.0.iter().map(|(key, (writes, _))| (&**key, *writes)))
+}
+
+#[test]
+fn trees_differ_only_in_the_buckets_whose_keys_do() {
+    let keys: Vec<String> = (0..200).map(|key| format!("k{key}")).collect();
+    let forwards = MerkleTree::build(keys.iter().map(|key| (&**key, 1)));
+    let backwards = MerkleTree::build(keys.iter().rev().map(|key| (&**key, 1)));
+    assert_eq!(forwards, backwards);
+    assert!(forwards.diff(&backwards).is_empty());
+
+    let changed = MerkleTree::build(keys.iter().map(|key| (&**key, if key == "k7" { 2 } else { 1 })));
+    assert_ne!(changed.root(), forwards.root());
+    assert_eq!(changed.diff(&forwards), [bucket("k7")]);
+    let missing = MerkleTree::build(keys.iter().filter(|key| *key != "k9").map(|key| (&**key, 1)));
+    assert_eq!(missing.diff(&forwards), [bucket("k9")]);
+}
+
+#[test]
+fn buckets_are_pinned_so_nodes_on_other_toolchains_agree() {
+    // What zero-keyed SipHash-1-3 gives today; a new toolchain mustn't move them.
+    assert_eq!(bucket("a"), 51);
+    assert_eq!(bucket("jepsen"), 42);
+    assert_eq!(bucket("x"), 33);
+}
+
+#[tokio::test]
+async fn a_round_of_repair_copies_whats_missing_or_older_both_ways() {
+    let mine = Mutex::new(store(&[("a", 1), ("b", 3), ("c", 1)]));
+    let theirs = Mutex::new(store(&[("b", 2), ("c", 4), ("d", 1)]));
+    let call = |message| ready(anti_entropy::answer(&mut *theirs.lock().unwrap(), "n1", message));
+    assert!(anti_entropy::repair(&mine, "n2", call).await);
+
+    let expected = store(&[("a", 1), ("b", 3), ("c", 4), ("d", 1)]);
+    assert_eq!(*mine.lock().unwrap(), expected);
+    assert_eq!(*theirs.lock().unwrap(), expected);
+
+    // Back in sync, the next round stops at the trees.
+    let digest = Message::Digest { tree: tree(&mine.lock().unwrap()) };
+    let answer = anti_entropy::answer(&mut *theirs.lock().unwrap(), "n1", digest);
+    assert_eq!(answer, Some(Message::DigestOk { buckets: vec![], digests: vec![] }));
+}
+
+#[tokio::test]
+async fn a_round_that_goes_unanswered_changes_nothing() {
+    let mine = Mutex::new(store(&[("a", 1)]));
+    assert!(!anti_entropy::repair(&mine, "n2", |_| ready(None)).await);
+    assert_eq!(*mine.lock().unwrap(), store(&[("a", 1)]));
+}
+
+#[tokio::test]
+async fn a_round_that_panics_doesnt_hold_back_the_next() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let call = {
+        let calls = calls.clone();
+        move |_, _| {
+            if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("the first round falls over");
+            }
+            ready(None)
+        }
+    };
+    let scheduler = Scheduler::new();
+    let _job = anti_entropy::repair_every_so_often(&scheduler, Duration::from_millis(5), Arc::new(Mutex::new(store(&[("a", 1)]))), || vec!["n2".into()], call);
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while calls.load(Ordering::SeqCst) < 2 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    })
+    .await
+    .expect("no round of repair ran after the one that panicked");
+}
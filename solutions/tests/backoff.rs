@@ -0,0 +1,34 @@
+//! How delays grow, are capped, jittered and reset.
+
+mod common;
+
+use std::time::Duration;
+use rand::{rngs::StdRng, SeedableRng};
+use solutions::backoff::{Backoff, Jitter};
+use common::millis;
+
+
+#[test]
+fn delays_grow_up_to_the_max_and_start_over_on_reset() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut backoff = Backoff::new(millis(5)).max(millis(30));
+    let delays: Vec<Duration> = (0..5).map(|_| backoff.next_delay_with(&mut rng)).collect();
+    assert_eq!(delays, [5, 10, 20, 30, 30].map(millis));
+
+    backoff.reset();
+    assert_eq!(backoff.next_delay_with(&mut rng), millis(5));
+
+    let mut backoff = Backoff::new(millis(100)).multiplier(1.5).max(millis(40));
+    assert_eq!([backoff.next_delay_with(&mut rng), backoff.next_delay_with(&mut rng)], [millis(40); 2]);
+}
+
+#[test]
+fn jitter_stays_within_the_delay() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut full = Backoff::new(millis(80)).multiplier(1.).jitter(Jitter::Full);
+    let mut equal = Backoff::new(millis(80)).multiplier(1.).jitter(Jitter::Equal);
+    for _ in 0..100 {
+        assert!(full.next_delay_with(&mut rng) <= millis(80));
+        assert!((millis(40)..=millis(80)).contains(&equal.next_delay_with(&mut rng)));
+    }
+}
@@ -0,0 +1,288 @@
+//! Which messages a broadcast node syncs to its neighbors, given what they've acknowledged
+//! and what they've sent it.
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use solutions::{clock::{Clock, ManualClock}, membership::{self, Status, Update}, message::{Body, Envelope}, topology::Overlay, tuning, workloads::broadcast::{self, Payload, State}};
+use tokio::sync::mpsc;
+
+
+fn from(src: &str, message: Payload) -> Envelope<Payload> {
+    Envelope::new(src, "n1", Body { msg_id: Some(1), in_reply_to: None, message })
+}
+
+/// What each neighbor is sent on a round of gossip at `now`, and with what `msg_id`.
+fn round(state: &Arc<Mutex<State>>, now: Instant) -> HashMap<String, (usize, Vec<usize>)> {
+    broadcast::syncs(&mut state.lock().unwrap(), now)
+    .into_iter()
+    .map(|envelope| match envelope.body.message {
+        Payload::Sync { messages, .. } => (envelope.destination.to_string(), (envelope.body.msg_id.unwrap(), messages)),
+        other => panic!("expected a sync, got {other:?}"),
+    })
+    .collect()
+}
+
+fn sent(round: &HashMap<String, (usize, Vec<usize>)>) -> HashMap<&str, &[usize]> {
+    round.iter().map(|(neighbor, (_, messages))| (neighbor.as_str(), messages.as_slice())).collect()
+}
+
+fn ack(neighbor: &str, msg_id: usize, messages: Vec<usize>) -> Envelope<Payload> {
+    Envelope::new(neighbor, "n1", Body { msg_id: Some(1), in_reply_to: Some(msg_id), message: Payload::SyncOk { messages } })
+}
+
+/// A node whose neighbors are n2 and n4 (with a stride of 2), with what it's told first.
+async fn node(told: Vec<Envelope<Payload>>) -> Arc<Mutex<State>> {
+    node_with(State::new(Overlay::Stride(2)), told).await
+}
+
+async fn node_with(state: State, told: Vec<Envelope<Payload>>) -> Arc<Mutex<State>> {
+    let state = Arc::new(Mutex::new(state));
+    let (writer, _replies) = mpsc::unbounded_channel();
+    let node_ids = ["n1", "n2", "n3", "n4"].map(String::from).to_vec();
+    let setup = [
+        from("c1", Payload::Init { node_id: "n1".to_owned(), node_ids }),
+        from("c1", Payload::Topology { topology: HashMap::new() }),
+    ];
+    for message in setup.into_iter().chain(told) {
+        broadcast::handle_envelope(state.clone(), message, writer.clone()).await;
+    }
+    state
+}
+
+async fn tell(state: &Arc<Mutex<State>>, message: Envelope<Payload>) {
+    broadcast::handle_envelope(state.clone(), message, mpsc::unbounded_channel().0).await;
+}
+
+#[tokio::test]
+async fn neighbors_are_synced_what_they_dont_know() {
+    let state = node(vec![
+        from("c1", Payload::Broadcast { message: 30 }),
+        from("c1", Payload::Broadcast { message: 10 }),
+        // Syncing a message to us is as good as acknowledging it.
+        from("n2", Payload::Sync { messages: vec![20, 30], updates: vec![] }),
+    ]).await;
+    let now = Instant::now();
+    let first = round(&state, now);
+    assert_eq!(sent(&first), HashMap::from([("n2", &[10][..]), ("n4", &[10, 20, 30][..])]));
+
+    tell(&state, ack("n4", first["n4"].0, vec![10, 20])).await;
+    tell(&state, ack("n2", first["n2"].0, vec![10])).await;
+    assert_eq!(sent(&round(&state, now)), HashMap::from([("n4", &[30][..])]));
+    assert_eq!(state.lock().unwrap().seen_messages(), [10, 20, 30]);
+}
+
+#[tokio::test]
+async fn neighbors_with_a_full_window_wait_for_an_ack_or_their_timeout() {
+    tuning::global().set_window(Some(1));
+    let state = node(vec![from("c1", Payload::Broadcast { message: 5 })]).await;
+    let now = Instant::now();
+    let first = round(&state, now);
+    assert_eq!(sent(&first), HashMap::from([("n2", &[5][..]), ("n4", &[5][..])]));
+
+    // Neither has acknowledged its sync, so neither hears about 6 yet.
+    tell(&state, from("c1", Payload::Broadcast { message: 6 })).await;
+    assert!(round(&state, now + Duration::from_millis(100)).is_empty());
+
+    // n2 acknowledges its sync and is sent the rest; n4's is given up on eventually.
+    tell(&state, ack("n2", first["n2"].0, vec![5])).await;
+    assert_eq!(sent(&round(&state, now + Duration::from_millis(200))), HashMap::from([("n2", &[6][..])]));
+
+    // n2 acks quickly, so its syncs are taken for lost well before n4's.
+    let resent = round(&state, now + Duration::from_millis(400));
+    assert_eq!(sent(&resent), HashMap::from([("n2", &[6][..])]));
+    tell(&state, ack("n2", resent["n2"].0, vec![6])).await;
+    assert_eq!(sent(&round(&state, now + Duration::from_millis(1100))), HashMap::from([("n4", &[5, 6][..])]));
+}
+
+#[tokio::test]
+async fn neighbors_taken_for_dead_are_not_synced_until_they_are_back() {
+    let rumor = |incarnation, status| Update { member: "n4".to_owned(), incarnation, status };
+    let state = node_with(State::new(Overlay::Stride(2)).with_membership(membership::Config::default()), vec![
+        from("c1", Payload::Broadcast { message: 5 }),
+        from("n2", Payload::Sync { messages: vec![], updates: vec![rumor(0, Status::Dead)] }),
+    ]).await;
+    let now = Instant::now();
+    assert_eq!(sent(&round(&state, now)), HashMap::from([("n2", &[5][..])]));
+
+    // n4 was restarted, and has refuted it.
+    tell(&state, from("n2", Payload::Sync { messages: vec![], updates: vec![rumor(1, Status::Alive)] })).await;
+    assert!(round(&state, now).contains_key("n4"));
+}
+
+#[tokio::test]
+async fn joining_nodes_are_synced_everything_and_leavers_forgotten() {
+    let state = node_with(State::new(Overlay::Ring), vec![from("c1", Payload::Broadcast { message: 5 })]).await;
+    let (writer, mut sent) = mpsc::unbounded_channel();
+    broadcast::handle_envelope(state.clone(), from("admin", Payload::Join { node_id: "n5".to_owned() }), writer).await;
+    // n5 takes n4's place next to n1 in the ring, and is synced right away.
+    let synced: Vec<String> = std::iter::from_fn(|| sent.try_recv().ok()).filter(|envelope| matches!(envelope.body.message, Payload::Sync { .. })).map(|envelope| envelope.destination.to_string()).collect();
+    assert!(synced.contains(&"n5".to_owned()), "{synced:?}");
+    assert!(!synced.contains(&"n4".to_owned()), "{synced:?}");
+
+    tell(&state, from("admin", Payload::Leave { node_id: "n2".to_owned() })).await;
+    tell(&state, from("c1", Payload::Broadcast { message: 6 })).await;
+    assert!(!round(&state, Instant::now() + Duration::from_secs(10)).contains_key("n2"));
+}
+
+#[tokio::test]
+async fn nearest_overlays_move_towards_the_neighbors_that_ack_quickest() {
+    let clock = ManualClock::new();
+    let state = node_with(State::new(Overlay::Nearest { near: 1, far: 1 }).with_clock(Arc::new(clock.clone())), vec![from("c1", Payload::Broadcast { message: 5 })]).await;
+    // n2 to begin with (the nearest in the ring), and one long link, which acks quicker.
+    let first = round(&state, clock.now().into_std());
+    let quick = first.keys().find(|neighbor| *neighbor != "n2").unwrap().clone();
+    clock.advance(Duration::from_millis(10));
+    tell(&state, ack(&quick, first[&quick].0, vec![5])).await;
+    clock.advance(Duration::from_millis(50));
+    tell(&state, ack("n2", first["n2"].0, vec![5])).await;
+
+    let mut state = state.lock().unwrap();
+    state.reevaluate_neighbors();
+    let neighbors: Vec<String> = state.neighbors().iter().map(|neighbor| neighbor.to_string()).collect();
+    assert_eq!(neighbors.len(), 2);
+    assert!(neighbors.contains(&quick), "{neighbors:?}");
+}
+
+#[tokio::test]
+async fn neighbors_back_from_a_partition_are_caught_up_straight_away() {
+    let state = node(vec![from("c1", Payload::Broadcast { message: 5 }), from("c1", Payload::Broadcast { message: 6 })]).await;
+    let now = Instant::now();
+    round(&state, now);
+    round(&state, now + Duration::from_millis(1100));
+    // Both neighbors have now let two syncs in a row time out.
+    let last = round(&state, now + Duration::from_millis(2200));
+    assert!(state.lock().unwrap().is_partitioned());
+
+    // n2 comes back, having only got 5, and is sent the rest without waiting for a tick.
+    let (writer, mut sent) = mpsc::unbounded_channel();
+    broadcast::handle_envelope(state.clone(), ack("n2", last["n2"].0, vec![5]), writer).await;
+    let caught_up = sent.try_recv().unwrap();
+    assert_eq!(caught_up.destination.to_string(), "n2");
+    assert!(matches!(caught_up.body.message, Payload::Sync { ref messages, .. } if *messages == [6]), "{caught_up:?}");
+    assert!(state.lock().unwrap().is_partitioned());
+
+    tell(&state, ack("n4", last["n4"].0, vec![5, 6])).await;
+    assert!(!state.lock().unwrap().is_partitioned());
+}
+
+#[tokio::test]
+async fn big_reads_can_be_split_up_or_sent_as_ranges() {
+    let broadcasts = [1, 2, 3, 4, 7, 8, 10].map(|message| from("c1", Payload::Broadcast { message }));
+    let state = node(broadcasts.into()).await;
+    let read = |chunk_size, ranges| {
+        let state = state.clone();
+        async move {
+            let (writer, mut replies) = mpsc::unbounded_channel();
+            broadcast::handle_envelope(state, from("c1", Payload::Read { chunk_size, ranges }), writer).await;
+            let mut sent = vec![];
+            while let Ok(reply) = replies.try_recv() {
+                assert_eq!(reply.body.in_reply_to, Some(1));
+                match reply.body.message {
+                    Payload::ReadOk { messages, ranges, part, parts } => sent.push((messages, ranges, part, parts)),
+                    other => panic!("expected a read_ok, got {other:?}"),
+                }
+            }
+            sent
+        }
+    };
+
+    // What Maelstrom's clients get.
+    assert_eq!(read(None, false).await, [(vec![1, 2, 3, 4, 7, 8, 10], vec![], None, None)]);
+    assert_eq!(read(Some(3), false).await, [
+        (vec![1, 2, 3], vec![], Some(0), Some(3)),
+        (vec![4, 7, 8], vec![], Some(1), Some(3)),
+        (vec![10], vec![], Some(2), Some(3)),
+    ]);
+    assert_eq!(read(None, true).await, [(vec![], vec![(1, 4), (7, 8), (10, 10)], None, None)]);
+    assert_eq!(read(Some(2), true).await, [
+        (vec![], vec![(1, 4), (7, 8)], Some(0), Some(2)),
+        (vec![], vec![(10, 10)], Some(1), Some(2)),
+    ]);
+
+    let plain = serde_json::to_value(&broadcast::read_replies(&Default::default(), Some(3), true)[0]).unwrap();
+    assert_eq!(plain, serde_json::json!({"type": "read_ok", "messages": []}));
+}
+
+#[tokio::test]
+async fn a_restarted_node_remembers_what_it_saw_and_what_its_neighbors_have() {
+    let dir = tempfile::tempdir().unwrap();
+    let before = node_with(State::new(Overlay::Stride(2)).with_storage(dir.path()).unwrap(), vec![
+        from("c1", Payload::Broadcast { message: 10 }),
+        from("c1", Payload::Broadcast { message: 20 }),
+        from("n4", Payload::Sync { messages: vec![30], updates: vec![] }),
+    ]).await;
+    let first = round(&before, Instant::now());
+    tell(&before, ack("n2", first["n2"].0, vec![10, 20, 30])).await;
+    drop(before);
+
+    let after = node_with(State::new(Overlay::Stride(2)).with_storage(dir.path()).unwrap(), vec![]).await;
+    assert_eq!(after.lock().unwrap().seen_messages(), [10, 20, 30]);
+    // n2 acknowledged everything, and n4 sent 30 itself.
+    assert_eq!(sent(&round(&after, Instant::now())), HashMap::from([("n4", &[10, 20][..])]));
+}
+
+#[tokio::test]
+async fn rumors_are_pushed_for_a_few_rounds_then_left_to_anti_entropy() {
+    let rumors = broadcast::Rumors { peers: 1, rounds: 2, anti_entropy_every: 6 };
+    let state = node_with(State::new(Overlay::Stride(2)).with_rumors(rumors), vec![from("c1", Payload::Broadcast { message: 5 })]).await;
+    let now = Instant::now();
+    let gossip = |state: &Arc<Mutex<State>>| -> Vec<(String, Vec<usize>)> {
+        broadcast::gossip(&mut state.lock().unwrap(), now)
+        .into_iter()
+        .map(|envelope| match envelope.body.message {
+            Payload::Sync { messages, .. } => (envelope.destination.to_string(), messages),
+            other => panic!("expected a sync, got {other:?}"),
+        })
+        .collect()
+    };
+
+    // Pushed to one random neighbor a round, for two rounds.
+    for _ in 0..2 {
+        let round = gossip(&state);
+        assert_eq!(round.len(), 1, "{round:?}");
+        assert!(["n2", "n4"].contains(&round[0].0.as_str()) && round[0].1 == [5], "{round:?}");
+    }
+    // Then quiet, until the anti-entropy round syncs whoever hasn't acknowledged it.
+    assert_eq!(gossip(&state), []);
+    tell(&state, from("n2", Payload::Sync { messages: vec![5], updates: vec![] })).await;
+    assert_eq!(gossip(&state), []);
+    assert_eq!(gossip(&state), []);
+    assert_eq!(gossip(&state), [("n4".to_owned(), vec![5])]);
+}
+
+#[tokio::test]
+async fn push_pull_rounds_catch_both_sides_up_on_each_other() {
+    // Two nodes, each the other's only neighbor, that have heard different broadcasts.
+    let node = |node_id: &str, messages: [usize; 2]| {
+        let node_ids = ["n1", "n2"].map(String::from).to_vec();
+        let to = |message| Envelope::new("c1", node_id, Body { msg_id: Some(1), in_reply_to: None, message });
+        let mut told = vec![to(Payload::Init { node_id: node_id.to_owned(), node_ids }), to(Payload::Topology { topology: HashMap::new() })];
+        told.extend(messages.map(|message| to(Payload::Broadcast { message })));
+        async move {
+            let state = Arc::new(Mutex::new(State::new(Overlay::Stride(1)).with_push_pull()));
+            for message in told {
+                broadcast::handle_envelope(state.clone(), message, mpsc::unbounded_channel().0).await;
+            }
+            state
+        }
+    };
+    let (n1, n2) = (node("n1", [1, 2]).await, node("n2", [2, 3]).await);
+    let (writer, mut sent) = mpsc::unbounded_channel();
+    let deliver = |envelope: Envelope<Payload>| {
+        let to = if &*envelope.destination == "n1" { n1.clone() } else { n2.clone() };
+        broadcast::handle_envelope(to, envelope, writer.clone())
+    };
+
+    let digest = broadcast::gossip(&mut n1.lock().unwrap(), Instant::now()).pop().unwrap();
+    assert!(matches!(&digest.body.message, Payload::Digest { ranges } if ranges == &[(1, 2)]));
+    deliver(digest).await;
+    let digest_ok = sent.try_recv().unwrap();
+    assert!(matches!(&digest_ok.body.message, Payload::DigestOk { messages, wants } if messages == &[3] && wants == &[(1, 1)]));
+    deliver(digest_ok).await;
+    let push = sent.try_recv().unwrap();
+    assert!(matches!(&push.body.message, Payload::Push { messages } if messages == &[1]));
+    deliver(push).await;
+    assert!(sent.try_recv().is_err());
+
+    assert_eq!(n1.lock().unwrap().seen_messages(), [1, 2, 3]);
+    assert_eq!(n2.lock().unwrap().seen_messages(), [1, 2, 3]);
+}
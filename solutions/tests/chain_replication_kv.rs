@@ -0,0 +1,153 @@
+//! The chain-replicated key-value store: writes go down the chain from the head, reads
+//! are answered by any node (asking the tail when their copy is dirty), the chain
+//! closes up around nodes that die, and a node taken for dead stays out of it.
+
+mod common;
+
+use std::{sync::{Arc, Mutex}, time::Duration};
+use serde_json::{json, Value};
+use solutions::{scheduler::Scheduler, workloads::chain_replication_kv::{self, Local, Payload, Reads, Shared, State}};
+
+
+type Cluster = common::Cluster<Payload, Shared>;
+
+impl Cluster {
+    /// `n` nodes, chained in order, answering reads as `reads` says.
+    async fn new(n: usize, reads: Reads) -> Self {
+        let node = |rpc| Shared::new(Arc::new(Mutex::new(State::new())), rpc, Duration::from_millis(200)).with_reads(reads);
+        let init = |node_id: &str, node_ids| Payload::Init { node_id: node_id.to_owned(), node_ids };
+        Self::start(n, node, chain_replication_kv::handle_envelope, init).await
+    }
+
+    async fn read(&mut self, node: &str, key: u64) -> Option<Value> {
+        match self.ask(node, Payload::Read { key: json!(key) }).await {
+            Payload::ReadOk { value } => Some(value),
+            Payload::Error { code: 20, .. } => None,
+            other => panic!("expected a read_ok from {node}, got {other:?}"),
+        }
+    }
+
+    /// `node` crashes, and every other node notices.
+    fn kill(&mut self, node: &str) {
+        self.dead.lock().unwrap().insert(node.to_owned());
+        for shared in self.nodes.values() {
+            chain_replication_kv::node_down(shared, node);
+        }
+    }
+}
+
+#[tokio::test]
+async fn writes_through_any_node_are_read_back_from_every_node() {
+    for reads in [Reads::Any, Reads::Tail] {
+        let mut cluster = Cluster::new(3, reads).await;
+        assert_eq!(cluster.read("n2", 1).await, None);
+        for (key, node) in [(1, "n1"), (2, "n2"), (3, "n3")] {
+            assert!(matches!(cluster.ask(node, Payload::Write { key: json!(key), value: json!(key * 10) }).await, Payload::WriteOk), "{reads:?}");
+        }
+        for node in ["n1", "n2", "n3"] {
+            for key in 1..=3 {
+                assert_eq!(cluster.read(node, key).await, Some(json!(key * 10)), "{reads:?}, {node}");
+            }
+        }
+
+        let cas = |from: u64, to: u64| Payload::Cas { key: json!(1), from: json!(from), to: json!(to), create_if_not_exists: None };
+        assert!(matches!(cluster.ask("n3", cas(10, 11)).await, Payload::CasOk), "{reads:?}");
+        assert!(matches!(cluster.ask("n2", cas(10, 12)).await, Payload::Error { code: 22, .. }), "{reads:?}");
+        let missing = Payload::Cas { key: json!(9), from: json!(0), to: json!(1), create_if_not_exists: None };
+        assert!(matches!(cluster.ask("n1", missing).await, Payload::Error { code: 20, .. }), "{reads:?}");
+        assert_eq!(cluster.read("n2", 1).await, Some(json!(11)), "{reads:?}");
+    }
+}
+
+#[test]
+fn a_copy_stays_dirty_until_the_tail_has_it() {
+    let mut state = State::new();
+    state.init("n2", &["n1".to_owned(), "n2".to_owned(), "n3".to_owned()]);
+    let key = json!(1);
+    state.stage(&key, 1, json!(10));
+    state.commit(&key, 1);
+    assert_eq!(state.local(&key), Local::Clean(json!(10)));
+
+    state.stage(&key, 2, json!(20));
+    state.stage(&key, 3, json!(30));
+    assert_eq!(state.local(&key), Local::Dirty);
+    assert_eq!(state.clean_version(&key), Some(1));
+
+    // Whatever the tail says is committed is what's read, and older versions go.
+    state.commit(&key, 2);
+    assert_eq!(state.clean_version(&key), Some(2));
+    assert_eq!(state.versions(&key).unwrap().at(2), Some(&json!(20)));
+    state.commit(&key, 3);
+    assert_eq!(state.local(&key), Local::Clean(json!(30)));
+    assert_eq!(state.versions(&key).unwrap().at(2), Some(&json!(30)));
+}
+
+#[test]
+fn the_chain_closes_up_around_nodes_that_die() {
+    let mut state = State::new();
+    let node_ids: Vec<String> = (1..=4).map(|i| format!("n{i}")).collect();
+    state.init("n3", &node_ids);
+    assert_eq!(state.successor().as_deref(), Some("n4"));
+    state.stage(&json!(1), 1, json!(10));
+
+    assert!(state.set_down("n1"));
+    assert_eq!(state.head().as_deref(), Some("n2"));
+    assert!(!state.set_down("n1"));
+
+    // With the tail gone, this node's the tail, and what's reached it is committed.
+    assert!(state.set_down("n4"));
+    assert!(state.is_tail());
+    state.commit_all();
+    assert_eq!(state.local(&json!(1)), Local::Clean(json!(10)));
+}
+
+#[tokio::test]
+async fn writes_carry_on_when_the_head_or_tail_dies() {
+    let mut cluster = Cluster::new(4, Reads::Any).await;
+    assert!(matches!(cluster.ask("n2", Payload::Write { key: json!(1), value: json!(1) }).await, Payload::WriteOk));
+
+    cluster.kill("n4");
+    assert!(matches!(cluster.ask("n3", Payload::Write { key: json!(1), value: json!(2) }).await, Payload::WriteOk));
+    cluster.kill("n1");
+    // The new head goes on from the newest version there is.
+    let cas = Payload::Cas { key: json!(1), from: json!(2), to: json!(3), create_if_not_exists: None };
+    assert!(matches!(cluster.ask("n3", cas).await, Payload::CasOk));
+    for node in ["n2", "n3"] {
+        assert_eq!(cluster.read(node, 1).await, Some(json!(3)), "{node}");
+    }
+}
+
+#[tokio::test]
+async fn a_node_taken_out_of_the_chain_stops_answering_reads() {
+    let mut cluster = Cluster::new(3, Reads::Any).await;
+    assert!(matches!(cluster.ask("n1", Payload::Write { key: json!(1), value: json!(1) }).await, Payload::WriteOk));
+
+    // The others take n2 for dead, though it's only slow, and carry on without it.
+    for node in ["n1", "n3"] {
+        chain_replication_kv::node_down(&cluster.nodes[node], "n2");
+    }
+    assert!(matches!(cluster.ask("n1", Payload::Write { key: json!(1), value: json!(2) }).await, Payload::WriteOk));
+    let reply = cluster.ask("n2", Payload::Read { key: json!(1) }).await;
+    assert!(matches!(reply, Payload::Error { code: 11, .. }), "{reply:?}");
+    assert_eq!(cluster.read("n3", 1).await, Some(json!(2)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_write_given_up_on_goes_down_the_chain_once_the_tail_answers_again() {
+    let mut cluster = Cluster::new(3, Reads::Any).await;
+    // The tail stops answering for a while, though nobody takes it for dead.
+    cluster.dead.lock().unwrap().insert("n3".to_owned());
+    let reply = cluster.ask("n1", Payload::Write { key: json!(1), value: json!(1) }).await;
+    assert!(matches!(reply, Payload::Error { code: 0, .. }), "{reply:?}");
+    assert_eq!(cluster.nodes["n2"].state().lock().unwrap().local(&json!(1)), Local::Dirty);
+
+    cluster.dead.lock().unwrap().remove("n3");
+    let schedulers = [Scheduler::new(), Scheduler::new()];
+    for (scheduler, node) in schedulers.iter().zip(["n1", "n2"]) {
+        chain_replication_kv::retry_stalled_every_so_often(scheduler, cluster.nodes[node].clone(), Duration::from_secs(1));
+    }
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    for node in ["n1", "n2", "n3"] {
+        assert_eq!(cluster.nodes[node].state().lock().unwrap().local(&json!(1)), Local::Clean(json!(1)), "{node}");
+    }
+}
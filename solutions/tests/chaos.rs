@@ -0,0 +1,53 @@
+//! Misbehaving on purpose.
+
+use std::{process::Command, time::Duration};
+use serde_json::{json, Value};
+use solutions::{chaos::{self, Chaos}, message::{Body, Envelope}};
+use tokio::sync::mpsc::{error::TryRecvError, unbounded_channel};
+
+
+fn message(msg_id: usize) -> Envelope<Value> {
+    Envelope::new("n1", "c1", Body { msg_id: Some(msg_id), in_reply_to: None, message: json!({"type": "echo_ok"}) })
+}
+
+#[tokio::test(start_paused = true)]
+async fn chaos_drops_duplicates_and_delays_what_a_node_sends() {
+    let (writer, mut sent) = unbounded_channel();
+    let dropping = chaos::layer(Chaos { drop_rate: 1., ..Default::default() }, writer.clone());
+    dropping.send(message(1)).unwrap();
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    assert!(matches!(sent.try_recv(), Err(TryRecvError::Empty)));
+
+    let duplicating = chaos::layer(Chaos { duplicate_rate: 1., ..Default::default() }, writer.clone());
+    duplicating.send(message(2)).unwrap();
+    assert_eq!(sent.recv().await.unwrap().body.msg_id, Some(2));
+    assert_eq!(sent.recv().await.unwrap().body.msg_id, Some(2));
+
+    let delaying = chaos::layer(Chaos { delay: Duration::from_millis(100), jitter: Duration::from_millis(50), ..Default::default() }, writer);
+    let start = tokio::time::Instant::now();
+    delaying.send(message(3)).unwrap();
+    assert_eq!(sent.recv().await.unwrap().body.msg_id, Some(3));
+    let waited = start.elapsed();
+    assert!(waited >= Duration::from_millis(100) && waited <= Duration::from_millis(151), "{waited:?}");
+}
+
+#[test]
+fn rates_outside_zero_to_one_are_refused() {
+    assert_eq!(chaos::parse_rate("0.25"), Ok(0.25));
+    assert!(chaos::parse_rate("1.5").is_err());
+    assert!(chaos::parse_rate("-0.1").is_err());
+}
+
+#[test]
+fn the_conformance_checker_catches_an_echo_node_that_drops_its_replies() {
+    let output =
+        Command::new(env!("CARGO_BIN_EXE_conformance"))
+        .args(["--workload", "echo", "--timeout-ms", "200"])
+        .arg(env!("CARGO_BIN_EXE_echo"))
+        .args(["--drop-rate", "1"])
+        .env("TOKIO_WORKER_THREADS", "2")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(!output.status.success(), "{stdout}");
+}
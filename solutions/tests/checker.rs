@@ -0,0 +1,475 @@
+//! Runs the checkers against hand-written histories with known anomalies, and
+//! against histories recorded from small clusters running on the simulator.
+
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, sync::Arc, time::Duration};
+use serde_json::{json, Value};
+use solutions::{checker, history::{History, Op, OpType, Recorder}, message::{Body, Envelope}, sim::{Latency, Network, NetworkConfig}, workloads::kafka};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+
+fn op(process: usize, kind: OpType, f: &str, value: Value, node: &str, millis: u64) -> Op {
+    Op { process, kind, f: f.to_owned(), value, node: node.to_owned(), time: Duration::from_millis(millis) }
+}
+
+/// A whole operation: invoked at `start`, completed `Ok` at `end`.
+fn ok(history: &mut History, process: usize, f: &str, invoked: Value, completed: Value, node: &str, (start, end): (u64, u64)) {
+    history.push(op(process, OpType::Invoke, f, invoked, node, start));
+    history.push(op(process, OpType::Ok, f, completed, node, end));
+}
+
+
+#[test]
+fn broadcast_reports_when_every_value_became_stable() {
+    let mut history = History::new();
+    ok(&mut history, 0, "broadcast", json!(1), json!(null), "n1", (0, 5));
+    ok(&mut history, 1, "broadcast", json!(2), json!(null), "n2", (10, 15));
+    ok(&mut history, 0, "read", json!(null), json!([1]), "n1", (20, 20));
+    ok(&mut history, 1, "read", json!(null), json!([2]), "n2", (20, 30));
+    ok(&mut history, 0, "read", json!(null), json!([1, 2]), "n1", (40, 50));
+    ok(&mut history, 1, "read", json!(null), json!([1, 2]), "n2", (40, 60));
+
+    let report = checker::broadcast(&history);
+
+    assert!(report.is_valid());
+    assert_eq!((report.attempted, report.acknowledged), (2, 2));
+    // 1 is on n1 from 20ms but only reaches n2 at 60ms; 2 settles on n1 at 50ms.
+    let latencies = report.stable_latencies.unwrap();
+    assert_eq!(latencies.max, Duration::from_millis(60));
+    assert_eq!(latencies.p50, Duration::from_millis(60));
+}
+
+
+#[test]
+fn broadcast_flags_lost_and_invented_values() {
+    let mut history = History::new();
+    ok(&mut history, 0, "broadcast", json!(1), json!(null), "n1", (0, 5));
+    ok(&mut history, 1, "broadcast", json!(2), json!(null), "n2", (0, 5));
+    // Never acknowledged, so it's fine for it to go missing.
+    history.push(op(2, OpType::Invoke, "broadcast", json!(3), "n2", 0));
+    history.push(op(2, OpType::Info, "broadcast", json!(null), "n2", 100));
+    ok(&mut history, 0, "read", json!(null), json!([1, 2]), "n1", (10, 10));
+    ok(&mut history, 1, "read", json!(null), json!([1, 2, 3, 99]), "n2", (10, 10));
+    // n1 forgets about 2 later on.
+    ok(&mut history, 0, "read", json!(null), json!([1]), "n1", (20, 20));
+
+    let report = checker::broadcast(&history);
+
+    assert!(!report.is_valid());
+    assert_eq!((report.attempted, report.acknowledged), (3, 2));
+    assert_eq!(report.lost, BTreeSet::from([2]));
+    assert_eq!(report.unexpected, BTreeSet::from([99]));
+}
+
+
+#[test]
+fn histories_round_trip_through_json_lines() {
+    let mut history = History::new();
+    ok(&mut history, 0, "broadcast", json!(1), json!(null), "n1", (0, 5));
+    history.push(op(1, OpType::Invoke, "read", json!(null), "n2", 7));
+
+    let mut saved = vec![];
+    history.write_json(&mut saved).unwrap();
+
+    assert_eq!(History::read_json(saved.as_slice()).unwrap(), history);
+}
+
+
+#[test]
+fn counter_accepts_final_reads_within_what_indeterminate_adds_allow() {
+    let mut history = History::new();
+    ok(&mut history, 0, "add", json!(3), json!(null), "n1", (0, 5));
+    ok(&mut history, 1, "add", json!(4), json!(null), "n2", (0, 5));
+    history.push(op(2, OpType::Invoke, "add", json!(10), "n2", 0));
+    history.push(op(2, OpType::Info, "add", json!(null), "n2", 100));
+    history.push(op(3, OpType::Invoke, "add", json!(100), "n1", 0));
+    history.push(op(3, OpType::Fail, "add", json!(null), "n1", 5));
+    ok(&mut history, 0, "read", json!(null), json!(7), "n1", (200, 200));
+    ok(&mut history, 1, "read", json!(null), json!(17), "n2", (200, 200));
+
+    let report = checker::counter(&history);
+
+    assert!(report.is_valid(), "{report:?}");
+    assert_eq!((report.acknowledged, report.lower, report.upper), (7, 7, 17));
+    assert_eq!(report.final_reads, BTreeMap::from([("n1".to_owned(), 7), ("n2".to_owned(), 17)]));
+}
+
+
+#[test]
+fn counter_flags_lost_adds_and_reads_from_the_future() {
+    let mut history = History::new();
+    ok(&mut history, 0, "add", json!(3), json!(null), "n1", (0, 5));
+    // Reads the add before it was ever invoked.
+    ok(&mut history, 1, "read", json!(null), json!(8), "n2", (1, 2));
+    ok(&mut history, 1, "add", json!(5), json!(null), "n2", (10, 15));
+    ok(&mut history, 0, "read", json!(null), json!(3), "n1", (200, 200));
+    ok(&mut history, 1, "read", json!(null), json!(8), "n2", (200, 200));
+
+    let report = checker::counter(&history);
+
+    assert!(!report.is_valid());
+    assert_eq!(report.wrong_final_reads, BTreeMap::from([("n1".to_owned(), 3)]));
+    assert_eq!(report.impossible_reads, vec![("n2".to_owned(), 8)]);
+}
+
+
+#[test]
+fn histories_export_as_jepsen_edn() {
+    let mut history = History::new();
+    ok(&mut history, 0, "read", json!(null), json!({"messages": [1, 2], "weird key": "a \"b\""}), "n1", (0, 5));
+    history.push(op(1, OpType::Invoke, "cas!", json!([true, 1.5]), "n2", 7));
+    ok(&mut history, 2, "txn", json!([["r", 1, null], ["w", 2, 3]]), json!([["r", 1, 4], ["w", 2, 3]]), "n1", (8, 9));
+
+    let mut saved = vec![];
+    history.write_edn(&mut saved).unwrap();
+
+    assert_eq!(
+        String::from_utf8(saved).unwrap(),
+        [
+            r#"{:index 0, :type :invoke, :f :read, :value nil, :process 0, :node "n1", :time 0}"#,
+            r#"{:index 1, :type :ok, :f :read, :value {:messages [1 2], "weird key" "a \"b\""}, :process 0, :node "n1", :time 5000000}"#,
+            r#"{:index 2, :type :invoke, :f :cas!, :value [true 1.5], :process 1, :node "n2", :time 7000000}"#,
+            r#"{:index 3, :type :invoke, :f :txn, :value [[:r 1 nil] [:w 2 3]], :process 2, :node "n1", :time 8000000}"#,
+            r#"{:index 4, :type :ok, :f :txn, :value [[:r 1 4] [:w 2 3]], :process 2, :node "n1", :time 9000000}"#,
+            "",
+        ].join("\n"),
+    );
+}
+
+
+#[test]
+fn kafka_accepts_gaps_nobody_was_acknowledged_in() {
+    let mut history = History::new();
+    ok(&mut history, 0, "send", json!(["k1", 10]), json!(0), "n1", (0, 5));
+    // Timed out, but took offset 1 all the same: polls may or may not see it.
+    history.push(op(1, OpType::Invoke, "send", json!(["k1", 11]), "n1", 6));
+    history.push(op(1, OpType::Info, "send", json!(null), "n1", 100));
+    ok(&mut history, 0, "send", json!(["k1", 12]), json!(2), "n1", (10, 15));
+    ok(&mut history, 0, "poll", json!({"k1": 0}), json!({"k1": [[0, 10], [2, 12]]}), "n1", (20, 25));
+    ok(&mut history, 0, "commit_offsets", json!({"k1": 2}), json!(null), "n1", (30, 35));
+    ok(&mut history, 0, "list_committed_offsets", json!(["k1"]), json!({"k1": 2}), "n1", (40, 45));
+
+    let report = checker::kafka(&history);
+
+    assert!(report.is_valid(), "{report:?}");
+    assert_eq!((report.acknowledged, report.polls), (2, 1));
+}
+
+
+#[test]
+fn kafka_flags_reused_offsets_skipped_sends_and_commits_going_back() {
+    let mut history = History::new();
+    ok(&mut history, 0, "send", json!(["k1", 10]), json!(0), "n1", (0, 5));
+    ok(&mut history, 0, "send", json!(["k1", 11]), json!(1), "n1", (10, 15));
+    ok(&mut history, 1, "send", json!(["k1", 12]), json!(2), "n2", (10, 15));
+    // Handed an offset lower than one acknowledged before it was even sent, and one
+    // already taken.
+    ok(&mut history, 0, "send", json!(["k1", 13]), json!(1), "n1", (20, 25));
+    // Skips 11 and 12, acknowledged well before.
+    ok(&mut history, 1, "poll", json!({"k1": 0}), json!({"k1": [[0, 10], [3, 14]]}), "n2", (30, 35));
+    ok(&mut history, 0, "commit_offsets", json!({"k1": 3}), json!(null), "n1", (40, 45));
+    ok(&mut history, 0, "list_committed_offsets", json!(["k1"]), json!({"k1": 1}), "n2", (50, 55));
+    // Another process's commits are its own.
+    ok(&mut history, 1, "list_committed_offsets", json!(["k1"]), json!({}), "n2", (50, 55));
+
+    let report = checker::kafka(&history);
+
+    assert!(!report.is_valid());
+    assert_eq!(report.duplicate_offsets, vec![("k1".to_owned(), 1)]);
+    assert_eq!(report.nonmonotonic_sends, vec![("k1".to_owned(), 2, 1)]);
+    assert_eq!(report.skipped, vec![("k1".to_owned(), 1), ("k1".to_owned(), 2)]);
+    assert_eq!(report.regressed_commits, vec![(0, "k1".to_owned(), 3, Some(1))]);
+}
+
+
+#[derive(Debug, Clone)]
+enum Payload {
+    Broadcast { message: u64 },
+    BroadcastOk,
+    Read,
+    ReadOk { messages: BTreeSet<u64> },
+    Gossip { messages: BTreeSet<u64> },
+    Add { delta: i64 },
+    AddOk,
+    CounterReadOk { value: i64 },
+    Counts { counts: BTreeMap<&'static str, i64> },
+}
+
+
+/// Acknowledges broadcasts right away and pushes everything it knows to its peers
+/// every 10ms.
+async fn broadcast_server(
+    id: &'static str,
+    peers: Vec<&'static str>,
+    writer: UnboundedSender<Envelope<Payload>>,
+    mut reader: UnboundedReceiver<Envelope<Payload>>,
+) {
+    let mut seen = BTreeSet::new();
+    let mut interval = tokio::time::interval(Duration::from_millis(10));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => for peer in &peers {
+                let body = Body { msg_id: None, in_reply_to: None, message: Payload::Gossip { messages: seen.clone() } };
+                writer.send(Envelope::new(id, peer, body)).unwrap();
+            },
+            received = reader.recv() => {
+                let Some(envelope) = received else {
+                    break;
+                };
+                match &envelope.body.message {
+                    Payload::Broadcast { message } => {
+                        seen.insert(*message);
+                        writer.send(envelope.reply_with(None, Payload::BroadcastOk)).unwrap();
+                    },
+                    Payload::Read => writer.send(envelope.reply_with(None, Payload::ReadOk { messages: seen.clone() })).unwrap(),
+                    Payload::Gossip { messages } => seen.extend(messages),
+                    _ => {},
+                }
+            },
+        }
+    }
+}
+
+/// Sends one request from client `process` and records it in the history until the
+/// reply comes back. Requests that time out are recorded as indeterminate.
+async fn call(
+    network: &Network<Payload>,
+    recorder: &Recorder,
+    process: usize,
+    node: &str,
+    request: Payload,
+) {
+    let (f, value) = match &request {
+        Payload::Broadcast { message } => ("broadcast", json!(message)),
+        Payload::Add { delta } => ("add", json!(delta)),
+        _ => ("read", json!(null)),
+    };
+    let client = format!("c{process}");
+    let (writer, mut reader) = network.connect(&client);
+    recorder.invoke(process, node, f, value);
+    writer.send(Envelope::new(&client, node, Body { msg_id: Some(0), in_reply_to: None, message: request })).unwrap();
+
+    match tokio::time::timeout(Duration::from_millis(100), reader.recv()).await {
+        Ok(Some(reply)) => {
+            let value = match &reply.body.message {
+                Payload::ReadOk { messages } => json!(messages),
+                Payload::CounterReadOk { value } => json!(value),
+                _ => json!(null),
+            };
+            recorder.complete(process, OpType::Ok, value);
+        },
+        _ => recorder.complete(process, OpType::Info, json!(null)),
+    }
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn simulated_broadcast_cluster_passes_the_broadcast_checker() {
+    let network = Network::new(NetworkConfig {
+        latency: Latency::Uniform { min: Duration::from_millis(1), max: Duration::from_millis(5) },
+        seed: Some(3),
+        ..Default::default()
+    });
+    let nodes = ["n1", "n2", "n3"];
+    for node in nodes {
+        let peers = nodes.into_iter().filter(|&peer| peer != node).collect();
+        network.spawn(node, move |writer, reader| broadcast_server(node, peers, writer, reader));
+    }
+    let recorder = Recorder::new();
+    let network = Arc::new(network);
+
+    let clients: Vec<_> =
+        (0..3)
+        .map(|process| {
+            let (network, recorder) = (network.clone(), recorder.clone());
+            tokio::spawn(async move {
+                for message in 0..10 {
+                    let node = nodes[(process + message) % 3];
+                    call(&network, &recorder, process, node, Payload::Broadcast { message: (process * 100 + message) as u64 }).await;
+                    call(&network, &recorder, process, node, Payload::Read).await;
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.await.unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    for (process, node) in nodes.into_iter().enumerate() {
+        call(&network, &recorder, process, node, Payload::Read).await;
+    }
+
+    let report = checker::broadcast(&recorder.history());
+
+    assert!(report.is_valid(), "{report:?}");
+    assert_eq!((report.attempted, report.acknowledged), (30, 30));
+    assert!(report.stable_latencies.unwrap().max < Duration::from_millis(150));
+}
+
+
+/// A grow-only counter: each node counts what was added through it, and gossips its
+/// view of everyone's counts to its peers every 10ms.
+async fn counter_server(
+    id: &'static str,
+    peers: Vec<&'static str>,
+    writer: UnboundedSender<Envelope<Payload>>,
+    mut reader: UnboundedReceiver<Envelope<Payload>>,
+) {
+    let mut counts = BTreeMap::new();
+    let mut interval = tokio::time::interval(Duration::from_millis(10));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => for peer in &peers {
+                let body = Body { msg_id: None, in_reply_to: None, message: Payload::Counts { counts: counts.clone() } };
+                writer.send(Envelope::new(id, peer, body)).unwrap();
+            },
+            received = reader.recv() => {
+                let Some(envelope) = received else {
+                    break;
+                };
+                match &envelope.body.message {
+                    Payload::Add { delta } => {
+                        *counts.entry(id).or_default() += delta;
+                        writer.send(envelope.reply_with(None, Payload::AddOk)).unwrap();
+                    },
+                    Payload::Read => {
+                        let value = counts.values().sum();
+                        writer.send(envelope.reply_with(None, Payload::CounterReadOk { value })).unwrap();
+                    },
+                    Payload::Counts { counts: theirs } => for (&node, &count) in theirs {
+                        let ours = counts.entry(node).or_default();
+                        *ours = count.max(*ours);
+                    },
+                    _ => {},
+                }
+            },
+        }
+    }
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn simulated_counter_cluster_passes_the_counter_checker() {
+    let network = Network::new(NetworkConfig {
+        latency: Latency::Uniform { min: Duration::from_millis(1), max: Duration::from_millis(5) },
+        seed: Some(5),
+        ..Default::default()
+    });
+    let nodes = ["n1", "n2", "n3"];
+    for node in nodes {
+        let peers = nodes.into_iter().filter(|&peer| peer != node).collect();
+        network.spawn(node, move |writer, reader| counter_server(node, peers, writer, reader));
+    }
+    let recorder = Recorder::new();
+    let network = Arc::new(network);
+
+    let clients: Vec<_> =
+        (0..3)
+        .map(|process| {
+            let (network, recorder) = (network.clone(), recorder.clone());
+            tokio::spawn(async move {
+                for delta in 0..10 {
+                    let node = nodes[(process + delta) % 3];
+                    call(&network, &recorder, process, node, Payload::Add { delta: delta as i64 }).await;
+                    call(&network, &recorder, process, node, Payload::Read).await;
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.await.unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    for (process, node) in nodes.into_iter().enumerate() {
+        call(&network, &recorder, process, node, Payload::Read).await;
+    }
+
+    let report = checker::counter(&recorder.history());
+
+    assert!(report.is_valid(), "{report:?}");
+    assert_eq!(report.acknowledged, 3 * 45);
+    assert!(report.final_reads.values().all(|&value| value == 3 * 45));
+}
+
+
+/// A single kafka node, answering whatever it's sent.
+async fn kafka_server(writer: UnboundedSender<Envelope<kafka::Payload>>, mut reader: UnboundedReceiver<Envelope<kafka::Payload>>) {
+    let mut state = kafka::State::new(8);
+    while let Some(envelope) = reader.recv().await {
+        if let Some(reply) = kafka::reply(&mut state, &envelope) {
+            writer.send(envelope.reply_with(None, reply)).unwrap();
+        }
+    }
+}
+
+/// Like [`call`], for kafka requests; each client's requests get msg_ids of their own,
+/// since sends are told apart by them.
+async fn kafka_call(network: &Network<kafka::Payload>, recorder: &Recorder, process: usize, msg_id: usize, request: kafka::Payload) {
+    let (f, value) = match &request {
+        kafka::Payload::Send { key, msg, .. } => ("send", json!([key, msg])),
+        kafka::Payload::Poll { offsets } => ("poll", json!(offsets)),
+        kafka::Payload::CommitOffsets { offsets } => ("commit_offsets", json!(offsets)),
+        kafka::Payload::ListCommittedOffsets { keys } => ("list_committed_offsets", json!(keys)),
+        _ => unreachable!(),
+    };
+    let client = format!("c{process}");
+    let (writer, mut reader) = network.connect(&client);
+    recorder.invoke(process, "n1", f, value);
+    writer.send(Envelope::new(&client, "n1", Body { msg_id: Some(msg_id), in_reply_to: None, message: request })).unwrap();
+
+    match tokio::time::timeout(Duration::from_millis(100), reader.recv()).await {
+        Ok(Some(reply)) => {
+            let value = match reply.body.message {
+                kafka::Payload::SendOk { offset } => json!(offset),
+                kafka::Payload::PollOk { msgs } => json!(msgs),
+                kafka::Payload::ListCommittedOffsetsOk { offsets } => json!(offsets),
+                _ => json!(null),
+            };
+            recorder.complete(process, OpType::Ok, value);
+        },
+        _ => recorder.complete(process, OpType::Info, json!(null)),
+    }
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn simulated_kafka_node_passes_the_kafka_checker() {
+    let network = Arc::new(Network::new(NetworkConfig {
+        latency: Latency::Uniform { min: Duration::from_millis(1), max: Duration::from_millis(5) },
+        seed: Some(7),
+        ..Default::default()
+    }));
+    network.spawn("n1", kafka_server);
+    let recorder = Recorder::new();
+
+    let clients: Vec<_> =
+        (0..3)
+        .map(|process| {
+            let (network, recorder) = (network.clone(), recorder.clone());
+            tokio::spawn(async move {
+                let mut msg_id = 0;
+                let mut next = HashMap::new();
+                for msg in 0..10 {
+                    let key = format!("k{}", (process + msg) % 2);
+                    msg_id += 1;
+                    kafka_call(&network, &recorder, process, msg_id, kafka::Payload::Send { key: key.clone(), msg: process * 100 + msg, sub_key: None, token: None }).await;
+                    msg_id += 1;
+                    let offset = *next.get(&key).unwrap_or(&0);
+                    kafka_call(&network, &recorder, process, msg_id, kafka::Payload::Poll { offsets: HashMap::from([(key.clone(), offset)]) }).await;
+                    next.insert(key.clone(), offset + 1);
+                    msg_id += 1;
+                    kafka_call(&network, &recorder, process, msg_id, kafka::Payload::CommitOffsets { offsets: HashMap::from([(key.clone(), offset)]) }).await;
+                    msg_id += 1;
+                    kafka_call(&network, &recorder, process, msg_id, kafka::Payload::ListCommittedOffsets { keys: vec![key] }).await;
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.await.unwrap();
+    }
+
+    let report = checker::kafka(&recorder.history());
+
+    assert!(report.is_valid(), "{report:?}");
+    assert_eq!((report.acknowledged, report.polls), (30, 30));
+}
@@ -0,0 +1,62 @@
+//! When calls to a destination that keeps timing out are let through, and when they're
+//! failed straight away.
+
+mod common;
+
+use tokio::time::Instant;
+use solutions::circuit_breaker::CircuitBreaker;
+use common::millis;
+
+
+#[test]
+fn opens_after_enough_timeouts_in_a_row() {
+    let start = Instant::now();
+    let mut breaker = CircuitBreaker::new(3, millis(1000));
+
+    breaker.record("seq-kv", true, start);
+    breaker.record("seq-kv", true, start);
+    // An answer in between starts the count over.
+    breaker.record("seq-kv", false, start);
+    breaker.record("seq-kv", true, start);
+    breaker.record("seq-kv", true, start);
+    assert!(breaker.allow("seq-kv", start));
+
+    breaker.record("seq-kv", true, start);
+    assert!(breaker.is_open("seq-kv", start));
+    assert!(!breaker.allow("seq-kv", start + millis(999)));
+    // Other destinations aren't affected.
+    assert!(breaker.allow("lin-kv", start));
+}
+
+#[test]
+fn one_call_tries_again_after_the_cool_down() {
+    let start = Instant::now();
+    let mut breaker = CircuitBreaker::new(1, millis(1000));
+    breaker.record("seq-kv", true, start);
+
+    let later = start + millis(1000);
+    assert!(breaker.allow("seq-kv", later));
+    assert!(!breaker.allow("seq-kv", later), "only one call at a time tries a destination again");
+    // Another timeout opens it for another cool-down.
+    breaker.record("seq-kv", true, later);
+    assert!(!breaker.allow("seq-kv", later + millis(999)));
+
+    let even_later = later + millis(1000);
+    assert!(breaker.allow("seq-kv", even_later));
+    // An answer closes it.
+    breaker.record("seq-kv", false, even_later);
+    assert!(!breaker.is_open("seq-kv", even_later));
+    assert!(breaker.allow("seq-kv", even_later));
+    assert!(breaker.allow("seq-kv", even_later));
+}
+
+#[test]
+fn a_call_that_never_reports_back_does_not_keep_it_shut() {
+    let start = Instant::now();
+    let mut breaker = CircuitBreaker::new(1, millis(1000));
+    breaker.record("seq-kv", true, start);
+
+    assert!(breaker.allow("seq-kv", start + millis(1000)));
+    assert!(!breaker.allow("seq-kv", start + millis(1500)));
+    assert!(breaker.allow("seq-kv", start + millis(2000)));
+}
@@ -0,0 +1,218 @@
+//! The options every node takes.
+
+use std::{io::{BufRead, BufReader, Write}, process::{Command, Stdio}};
+use clap::{CommandFactory, Parser, ValueEnum};
+use solutions::{cli::{self, CommonOpts, FanoutStrategy, LogFormat, Runtime}, session, workloads::broadcast};
+
+
+#[test]
+fn workloads_take_the_common_options_next_to_their_own() {
+    let opts = broadcast::Opts::try_parse_from(["broadcast", "--stride", "3", "-t", "155", "--log-format", "compact", "--client-ops", "broadcast,read", "--fanout", "20", "--fanout-strategy", "percent"]).unwrap();
+    assert_eq!(opts.stride, Some(3));
+    assert_eq!(opts.common.tick_rate_ms, 155);
+    assert_eq!(opts.common.log_format, LogFormat::Compact);
+    assert_eq!(opts.common.client_ops, ["broadcast", "read"]);
+    assert_eq!((opts.common.fanout, opts.common.fanout_strategy), (Some(20), FanoutStrategy::Percent));
+
+    let common = CommonOpts::try_parse_from(["echo"]).unwrap();
+    assert_eq!(common.tick_rate_ms, CommonOpts::default().tick_rate_ms);
+    assert_eq!(common.record, None);
+    assert_eq!((common.fanout, common.fanout_strategy), (None, FanoutStrategy::Peers));
+}
+
+#[test]
+fn runtimes_are_single_multi_or_multi_with_threads() {
+    assert_eq!("single".parse(), Ok(Runtime::Single));
+    assert_eq!("multi".parse(), Ok(Runtime::Multi));
+    assert_eq!("multi:3".parse(), Ok(Runtime::MultiWith(3.try_into().unwrap())));
+    assert!("multi:0".parse::<Runtime>().is_err());
+    assert!("threaded".parse::<Runtime>().is_err());
+    assert_eq!(Runtime::MultiWith(3.try_into().unwrap()).to_string(), "multi:3");
+
+    let common = CommonOpts::try_parse_from(["echo", "--runtime", "multi:2"]).unwrap();
+    assert_eq!(common.runtime.to_string(), "multi:2");
+    assert_eq!(CommonOpts::try_parse_from(["echo"]).unwrap().runtime, Runtime::Multi);
+}
+
+#[test]
+fn nodes_run_on_a_single_threaded_runtime() {
+    let mut node =
+        Command::new(env!("CARGO_BIN_EXE_echo"))
+        .args(["--runtime", "single"])
+        .env("METRICS_INTERVAL_MS", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = node.stdin.take().unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}}}"#).unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "echo", "msg_id": 2, "echo": "hi"}}}}"#).unwrap();
+    drop(stdin);
+    let output = node.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.lines().nth(1).is_some_and(|line| line.contains("echo_ok")), "{stdout}");
+}
+
+#[test]
+fn config_settings_stand_for_environment_variables() {
+    let command = broadcast::Opts::command();
+    let settings = cli::settings("stride = 3\ntick-rate-ms = 155\nclient_ops = [\"broadcast\", \"read\"]\nlog_format = \"compact\"", &command).unwrap();
+    let settings: Vec<(&str, &str)> = settings.iter().map(|(env, value)| (env.as_str(), value.as_str())).collect();
+    assert_eq!(settings, [("CLIENT_OPS", "broadcast,read"), ("LOG_FORMAT", "compact"), ("STRIDE", "3"), ("TICK_RATE_MS", "155")]);
+
+    assert_eq!(cli::settings("strid = 3", &command).unwrap_err(), "unknown setting `strid`");
+    assert!(cli::settings("[stride]\nvalue = 3", &command).is_err());
+    assert!(cli::settings("stride = ", &command).is_err());
+}
+
+/// Run the multiplexed echo node with `args` and `envs`, feed it an `init` and three
+/// `echo`s, and return the operations its messages-per-operation estimate counted.
+fn ops_counted(args: &[&str], envs: &[(&str, &str)]) -> String {
+    let mut node =
+        Command::new(env!("CARGO_BIN_EXE_solutions"))
+        .args(args)
+        .env_remove("CLIENT_OPS")
+        .env_remove("CONFIG")
+        .envs(envs.iter().copied())
+        .env("METRICS_INTERVAL_MS", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = node.stdin.take().unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}}}"#).unwrap();
+    for msg_id in 2..=4 {
+        writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "echo", "msg_id": {msg_id}, "echo": "hi"}}}}"#).unwrap();
+    }
+    let mut stdout = BufReader::new(node.stdout.take().unwrap()).lines();
+    for _ in 1..=4 {
+        stdout.next().unwrap().unwrap();
+    }
+    drop(stdin);
+    let stderr = String::from_utf8(node.wait_with_output().unwrap().stderr).unwrap();
+    let ops = stderr.split("ops=").nth(1).unwrap_or_else(|| panic!("{stderr}"));
+    ops.split_whitespace().next().unwrap().to_owned()
+}
+
+#[test]
+fn environment_overrides_config_and_flags_override_both() {
+    let mut config = tempfile::NamedTempFile::new().unwrap();
+    writeln!(config, "client_ops = [\"echo\"]").unwrap();
+    let path = config.path().to_str().unwrap();
+
+    assert_eq!(ops_counted(&["echo", "--config", path], &[]), "3");
+    assert_eq!(ops_counted(&["echo"], &[("CONFIG", path)]), "3");
+    assert_eq!(ops_counted(&["echo", "--config", path], &[("CLIENT_OPS", "init")]), "1");
+    assert_eq!(ops_counted(&["echo", "--config", path, "--client-ops", "init,echo"], &[("CLIENT_OPS", "init")]), "4");
+}
+
+#[test]
+fn broadcast_profiles_bundle_settings_that_flags_still_override() {
+    let command = broadcast::Opts::command();
+    for profile in broadcast::Profile::value_variants() {
+        assert!(cli::settings(profile.settings(), &command).is_ok(), "{profile:?}");
+    }
+
+    let mut node =
+        Command::new(env!("CARGO_BIN_EXE_broadcast"))
+        .args(["--profile", "low-bandwidth", "--tick-rate-ms", "300"])
+        .env_remove("CONFIG")
+        .env("METRICS_INTERVAL_MS", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = node.stdin.take().unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}}}"#).unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "configure", "msg_id": 2}}}}"#).unwrap();
+    let mut stdout = BufReader::new(node.stdout.take().unwrap()).lines();
+    let parameters: serde_json::Value = loop {
+        let line = stdout.next().unwrap().unwrap();
+        if line.contains("configure_ok") {
+            break serde_json::from_str(&line).unwrap();
+        }
+    };
+    drop(stdin);
+    node.wait().unwrap();
+    assert_eq!(parameters["body"]["tick_rate_ms"], 300);
+    assert_eq!(parameters["body"]["batch_size"], 500);
+    assert_eq!(parameters["body"]["window"], 1);
+}
+
+#[test]
+fn nodes_refuse_configs_with_unknown_settings() {
+    let mut config = tempfile::NamedTempFile::new().unwrap();
+    writeln!(config, "tick_rate = 100").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_broadcast")).arg("--config").arg(config.path()).stdin(Stdio::null()).output().unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("unknown setting `tick_rate`"), "{stderr}");
+}
+
+#[test]
+fn nodes_record_what_they_read_and_write() {
+    let directory = tempfile::tempdir().unwrap();
+    let recording = directory.path().join("recording.jsonl");
+    let mut node =
+        Command::new(env!("CARGO_BIN_EXE_solutions"))
+        .args(["echo", "--record"])
+        .arg(&recording)
+        .env("METRICS_INTERVAL_MS", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = node.stdin.take().unwrap();
+    let mut stdout = BufReader::new(node.stdout.take().unwrap()).lines();
+    // Each reply is waited on before the next request, so they're recorded in order.
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}}}"#).unwrap();
+    stdout.next().unwrap().unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "echo", "msg_id": 2, "echo": "hi"}}}}"#).unwrap();
+    stdout.next().unwrap().unwrap();
+    drop(stdin);
+    node.wait().unwrap();
+
+    let recorded = std::fs::read_to_string(&recording).unwrap();
+    let messages = session::parse(&recorded);
+    let kinds: Vec<&str> = messages.iter().map(|message| message.kind()).collect();
+    assert_eq!(kinds, ["init", "init_ok", "echo", "echo_ok"], "{recorded}");
+    assert!(messages.iter().all(|message| message.time.is_some()), "{recorded}");
+    assert!(recorded.lines().nth(1).unwrap().contains(r#""direction":"out""#), "{recorded}");
+}
+
+#[test]
+fn nodes_replay_messages_from_a_file() {
+    let directory = tempfile::tempdir().unwrap();
+    let (input, output) = (directory.path().join("input.jsonl"), directory.path().join("output.jsonl"));
+    // As `--record` writes it: what the node wrote back is skipped on the way in.
+    std::fs::write(&input, [
+        r#"{"src": "c1", "dest": "n1", "body": {"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}, "time": 0.0, "direction": "in"}"#,
+        r#"{"src": "n1", "dest": "c1", "body": {"type": "init_ok", "in_reply_to": 1}, "time": 0.1, "direction": "out"}"#,
+        r#"{"src": "c1", "dest": "n1", "body": {"type": "echo", "msg_id": 2, "echo": "hi"}, "time": 0.2, "direction": "in"}"#,
+        "",
+    ].join("\n")).unwrap();
+
+    for _ in 1..=2 {
+        let status =
+            Command::new(env!("CARGO_BIN_EXE_echo"))
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .env("METRICS_INTERVAL_MS", "0")
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let replies = std::fs::read_to_string(&output).unwrap();
+        let messages = session::parse(&replies);
+        let kinds: Vec<&str> = messages.iter().map(|message| message.kind()).collect();
+        assert_eq!(kinds, ["init_ok", "echo_ok"], "{replies}");
+    }
+}
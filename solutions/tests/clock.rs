@@ -0,0 +1,86 @@
+//! Waiting on a clock that only moves when told to, and timeouts that go by it.
+
+mod common;
+
+use std::{future::Future, pin::Pin, sync::Arc, task::Poll};
+use solutions::{backoff::Backoff, clock::{Clock, ManualClock, WallClock}, rpc::{RpcClient, RpcError}};
+use tokio::sync::mpsc;
+use common::millis;
+
+
+/// Poll `future` once, returning whether it's still waiting.
+async fn is_pending<F: Future + Unpin>(future: &mut F) -> bool {
+    std::future::poll_fn(|cx| Poll::Ready(Pin::new(&mut *future).poll(cx).is_pending())).await
+}
+
+#[tokio::test]
+async fn sleeps_wake_once_the_clock_is_advanced_past_them() {
+    let clock = ManualClock::new();
+    let start = clock.now();
+    let mut sleep = clock.sleep(millis(1000));
+    assert!(is_pending(&mut sleep).await);
+
+    clock.advance(millis(999));
+    assert!(is_pending(&mut sleep).await);
+    clock.advance(millis(1));
+    assert!(!is_pending(&mut sleep).await);
+    assert_eq!(clock.now() - start, millis(1000));
+}
+
+#[tokio::test]
+async fn intervals_tick_by_the_clock_and_do_not_burst_when_late() {
+    let manual = ManualClock::new();
+    let clock: Arc<dyn Clock> = Arc::new(manual.clone());
+    let start = clock.now();
+    let mut interval = clock.interval(millis(100));
+    assert_eq!(interval.tick().await, start);
+
+    let mut tick = Box::pin(interval.tick());
+    assert!(is_pending(&mut tick).await);
+    manual.advance(millis(100));
+    assert_eq!(tick.await, start + millis(100));
+
+    // Three periods late: one tick, then the next a whole period on.
+    manual.advance(millis(300));
+    assert_eq!(interval.tick().await, start + millis(200));
+    let mut tick = Box::pin(interval.tick());
+    manual.advance(millis(99));
+    assert!(is_pending(&mut tick).await);
+    manual.advance(millis(1));
+    assert_eq!(tick.await, start + millis(500));
+}
+
+#[tokio::test]
+async fn timeouts_go_by_the_clock() {
+    let manual = ManualClock::new();
+    let clock: Arc<dyn Clock> = Arc::new(manual.clone());
+
+    let (writer, mut sent) = mpsc::unbounded_channel();
+    let rpc = RpcClient::<String>::new(writer, || 1);
+    rpc.set_node_id("n1");
+    rpc.set_clock(clock.clone());
+    let mut call = Box::pin(rpc.call("lin-kv", "read".to_string(), millis(1000)));
+    assert!(is_pending(&mut call).await);
+    assert_eq!(sent.try_recv().unwrap().body.message, "read");
+
+    manual.advance(millis(999));
+    assert!(is_pending(&mut call).await);
+    manual.advance(millis(1));
+    assert_eq!(call.await.unwrap_err(), RpcError::Timeout);
+
+    let mut backoff = Backoff::new(millis(50)).clock(clock);
+    let mut wait = Box::pin(backoff.wait());
+    assert!(is_pending(&mut wait).await);
+    manual.advance(millis(50));
+    assert!(!is_pending(&mut wait).await);
+}
+
+#[test]
+fn wall_clock_time_moves_with_the_clock_it_is_told_by() {
+    let clock = ManualClock::new();
+    let wall = WallClock::new(Arc::new(clock.clone()));
+    let start = wall.now_ms();
+    assert_eq!(wall.now_ms(), start);
+    clock.advance(millis(1500));
+    assert_eq!(wall.now_ms(), start + 1500);
+}
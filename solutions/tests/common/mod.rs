@@ -0,0 +1,147 @@
+//! What the integration tests share: message ids, a fake lin-kv (and lin-tso) for nodes
+//! to talk to, and a cluster of nodes passing messages to one another in-process.
+
+// Each test binary uses only some of it.
+#![allow(dead_code)]
+
+use std::{collections::{HashMap, HashSet}, fmt::Debug, future::Future, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, time::Duration};
+use serde_json::{json, Value};
+use solutions::{message::{Body, Envelope}, rpc::RpcClient};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+
+pub fn message_id() -> usize {
+    static MSG_ID: AtomicUsize = AtomicUsize::new(1);
+    MSG_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+pub fn millis(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+
+/// What's in a fake lin-kv, by each key's JSON.
+pub type Store = Arc<Mutex<HashMap<String, Value>>>;
+
+/// Where [`answer`] keeps the last timestamp lin-tso handed out, in its store.
+pub const TSO: &str = "lin-tso";
+
+/// What lin-kv (or lin-tso) would say to `request`, given what's in `store`.
+pub fn answer(store: &mut HashMap<String, Value>, request: &Value) -> Value {
+    let key = request["key"].to_string();
+    match (request["type"].as_str().unwrap(), store.get(&key)) {
+        ("ts", _) => {
+            let ts = store.get(TSO).and_then(Value::as_u64).unwrap_or_default() + 1;
+            store.insert(TSO.to_owned(), json!(ts));
+            json!({"type": "ts_ok", "ts": ts})
+        },
+        ("read", Some(value)) => json!({"type": "read_ok", "value": value}),
+        ("write", _) => {
+            store.insert(key, request["value"].clone());
+            json!({"type": "write_ok"})
+        },
+        ("cas", None) if request["create_if_not_exists"] == json!(true) => {
+            store.insert(key, request["to"].clone());
+            json!({"type": "cas_ok"})
+        },
+        ("cas", Some(value)) if *value == request["from"] => {
+            store.insert(key, request["to"].clone());
+            json!({"type": "cas_ok"})
+        },
+        ("cas", Some(_)) => json!({"type": "error", "code": 22, "text": "not what you thought"}),
+        _ => json!({"type": "error", "code": 20, "text": "no such key"}),
+    }
+}
+
+/// `node_id`'s RPC client, whose requests (to lin-kv, lin-tso or anyone else) are all
+/// answered from `store`.
+pub fn lin_kv(node_id: &str, store: &Store) -> RpcClient<Value> {
+    let (writer, mut sent) = mpsc::unbounded_channel::<Envelope<Value>>();
+    let rpc = RpcClient::new(writer, message_id);
+    rpc.set_node_id(node_id);
+    let (store, service) = (store.clone(), rpc.clone());
+    tokio::spawn(async move {
+        while let Some(request) = sent.recv().await {
+            let reply = answer(&mut store.lock().unwrap(), &request.body.message);
+            service.try_complete(Envelope::new(&request.destination, &request.source, Body { msg_id: None, in_reply_to: request.msg_id(), message: reply }));
+        }
+    });
+    rpc
+}
+
+
+/// Nodes passing messages of type `P` to one another, each handled by a node `S`:
+/// clients' requests go in through `requests`, and their replies come out of `replies`.
+pub struct Cluster<P, S> {
+    pub requests: UnboundedSender<Envelope<P>>,
+    pub replies: UnboundedReceiver<Envelope<P>>,
+    pub nodes: HashMap<String, S>,
+    /// Nodes that have crashed: nothing gets to or from them.
+    pub dead: Arc<Mutex<HashSet<String>>>,
+}
+
+impl<P, S> Cluster<P, S>
+where
+    P: Debug + Send + 'static,
+    S: Clone + Send + 'static,
+{
+    /// `n` nodes, `n1` to `n{n}`, each made by `node` from its RPC client, which
+    /// `handle` whatever they're sent that isn't a reply to one of their own calls.
+    /// Each is sent the `init` that `init` makes from its id and everyone's, and the
+    /// cluster's ready once they've all answered it.
+    pub async fn start<Fut>(
+        n: usize,
+        node: impl Fn(RpcClient<P>) -> S,
+        handle: fn(S, Envelope<P>, UnboundedSender<Envelope<P>>) -> Fut,
+        init: impl Fn(&str, Vec<String>) -> P,
+    ) -> Self
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (network, mut sent) = mpsc::unbounded_channel::<Envelope<P>>();
+        let (to_clients, mut replies) = mpsc::unbounded_channel();
+        let node_ids: Vec<String> = (1..=n).map(|i| format!("n{i}")).collect();
+        let dead = Arc::new(Mutex::new(HashSet::<String>::new()));
+        let mut nodes = HashMap::new();
+        let mut routes = HashMap::new();
+        for node_id in &node_ids {
+            let rpc = RpcClient::new(network.clone(), message_id);
+            rpc.set_node_id(node_id);
+            let shared = node(rpc.clone());
+            nodes.insert(node_id.clone(), shared.clone());
+            routes.insert(node_id.clone(), (shared, rpc));
+        }
+        let (writer, crashed) = (network.clone(), dead.clone());
+        tokio::spawn(async move {
+            while let Some(envelope) = sent.recv().await {
+                if [&envelope.source, &envelope.destination].iter().any(|node| crashed.lock().unwrap().contains(&***node)) {
+                    continue;
+                }
+                let Some((shared, rpc)) = routes.get(&*envelope.destination) else {
+                    to_clients.send(envelope).unwrap();
+                    continue;
+                };
+                if let Some(envelope) = rpc.try_complete(envelope) {
+                    tokio::spawn(handle(shared.clone(), envelope, network.clone()));
+                }
+            }
+        });
+        for node_id in &node_ids {
+            let message = init(node_id, node_ids.clone());
+            writer.send(Envelope::new("c0", node_id, Body { msg_id: Some(1), in_reply_to: None, message })).unwrap();
+        }
+        for _ in &node_ids {
+            replies.recv().await.unwrap();
+        }
+        Self { requests: writer, replies, nodes, dead }
+    }
+
+    /// What a client is told when it sends `message` to `node`.
+    pub async fn ask(&mut self, node: &str, message: P) -> P {
+        let msg_id = message_id();
+        self.requests.send(Envelope::new("c1", node, Body { msg_id: Some(msg_id), in_reply_to: None, message })).unwrap();
+        let reply = self.replies.recv().await.unwrap();
+        assert_eq!(reply.body.in_reply_to, Some(msg_id));
+        reply.body.message
+    }
+}
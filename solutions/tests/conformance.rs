@@ -0,0 +1,96 @@
+//! Holds replies to the protocol's obligations, and runs the `conformance` binary against the echo node and the multiplexed `solutions` binary.
+
+use std::process::Command;
+use serde_json::{json, Value};
+use solutions::{conformance::{check_reply, check_unsupported, Workload}, message::{Body, Envelope}};
+
+
+fn envelope(src: &str, dest: &str, msg_id: Option<usize>, in_reply_to: Option<usize>, message: Value) -> Envelope<Value> {
+    Envelope::new(src, dest, Body { msg_id, in_reply_to, message })
+}
+
+
+#[test]
+fn replies_must_come_back_the_way_the_request_went() {
+    let request = envelope("c1", "n1", Some(4), None, json!({"type": "read"}));
+    let good = request.reply_with(Some(9), json!({"type": "read_ok", "messages": []}));
+    assert!(check_reply(&request, &good).is_empty());
+
+    let misrouted = envelope("n2", "c2", None, Some(5), json!({"type": "read_ok"}));
+    assert_eq!(check_reply(&request, &misrouted).len(), 3);
+    let wrong_type = request.reply_with(None, json!({"type": "write_ok"}));
+    assert_eq!(check_reply(&request, &wrong_type), vec!["reply has type `write_ok`, not `read_ok`"]);
+    let bad_error = request.reply_with(None, json!({"type": "error"}));
+    assert_eq!(check_reply(&request, &bad_error), vec!["error reply without an integer `code`"]);
+
+    let unknown = envelope("c1", "n1", Some(5), None, json!({"type": "frobnicate"}));
+    assert_eq!(check_unsupported(Some(&unknown.reply_with(None, json!({"type": "error", "code": 10})))), None);
+    assert!(check_unsupported(Some(&unknown.reply_with(None, json!({"type": "error", "code": 13})))).is_some());
+    assert!(check_unsupported(None).is_some());
+}
+
+#[test]
+fn workload_checks_catch_wrong_answers() {
+    let broadcast = Workload::Broadcast;
+    let earlier = vec![(json!({"type": "broadcast", "message": 1}), json!({"type": "broadcast_ok"}))];
+    assert_eq!(broadcast.check(&json!({"type": "read"}), &json!({"type": "read_ok", "messages": [1]}), &earlier), None);
+    assert!(broadcast.check(&json!({"type": "read"}), &json!({"type": "read_ok", "messages": []}), &earlier).is_some());
+
+    let generate = json!({"type": "generate"});
+    let earlier = vec![(generate.clone(), json!({"type": "generate_ok", "id": "n1-1"}))];
+    assert!(Workload::UniqueIds.check(&generate, &json!({"type": "generate_ok", "id": "n1-1"}), &earlier).is_some());
+    assert_eq!(Workload::UniqueIds.check(&generate, &json!({"type": "generate_ok", "id": "n1-2"}), &earlier), None);
+
+    let earlier = vec![(json!({"type": "send", "key": "k1", "msg": 10}), json!({"type": "send_ok", "offset": 3}))];
+    let poll = json!({"type": "poll", "offsets": {"k1": 0}});
+    assert_eq!(Workload::Kafka.check(&poll, &json!({"type": "poll_ok", "msgs": {"k1": [[3, 10]]}}), &earlier), None);
+    assert!(Workload::Kafka.check(&poll, &json!({"type": "poll_ok", "msgs": {"k1": [[3, 11]]}}), &earlier).is_some());
+
+    let txn = json!({"type": "txn", "txn": [["r", 1, null], ["w", 2, 6]]});
+    assert_eq!(Workload::Txn.check(&txn, &json!({"type": "txn_ok", "txn": [["r", 1, 5], ["w", 2, 6]]}), &[]), None);
+    assert!(Workload::Txn.check(&txn, &json!({"type": "txn_ok", "txn": [["r", 1, 5]]}), &[]).is_some());
+
+    let earlier = vec![(json!({"type": "cas", "key": 0, "from": 1, "to": 2}), json!({"type": "cas_ok"}))];
+    let read = json!({"type": "read", "key": 0});
+    assert_eq!(Workload::LinKv.check(&read, &json!({"type": "read_ok", "value": 2}), &earlier), None);
+    assert!(Workload::LinKv.check(&read, &json!({"type": "read_ok", "value": 1}), &earlier).is_some());
+}
+
+#[test]
+fn echo_node_conforms() {
+    let output =
+        Command::new(env!("CARGO_BIN_EXE_conformance"))
+        .args(["--workload", "echo", "--timeout-ms", "500"])
+        .arg(env!("CARGO_BIN_EXE_echo"))
+        .env("TOKIO_WORKER_THREADS", "2")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success(), "{stdout}");
+    assert!(stdout.contains("ok    same msg_id from two clients"), "{stdout}");
+    assert!(stdout.contains("ok    still answering after malformed input"), "{stdout}");
+}
+
+#[test]
+fn multiplexed_binary_runs_every_single_node_workload() {
+    let workloads: [(&str, &[&str]); 7] = [
+        ("echo", &["echo"]),
+        ("unique-ids", &["unique-ids"]),
+        ("broadcast", &["broadcast", "--stride", "1", "--tick-rate-ms", "100"]),
+        ("kafka", &["kafka"]),
+        ("txn-rw-register", &["txn"]),
+        ("lin-kv", &["craq"]),
+        ("lww-kv", &["dynamo"]),
+    ];
+    for (workload, subcommand) in workloads {
+        let output =
+            Command::new(env!("CARGO_BIN_EXE_conformance"))
+            .args(["--workload", workload, "--timeout-ms", "500"])
+            .arg(env!("CARGO_BIN_EXE_solutions"))
+            .args(subcommand)
+            .env("TOKIO_WORKER_THREADS", "2")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{workload}: {}", String::from_utf8_lossy(&output.stdout));
+    }
+}
@@ -0,0 +1,245 @@
+//! Simulates torn writes and partial fsyncs against `FileStorage` by mangling the
+//! tail of its files at random points, and checks that recovery either restores a
+//! prefix of what was appended or refuses to recover at all.
+
+use std::{fs, io, path::Path};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use solutions::storage::{Durable, FileStorage, Replay};
+
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct Ledger {
+    applied: Vec<u64>,
+}
+
+impl Replay<u64> for Ledger {
+    fn apply(&mut self, record: &u64) {
+        self.applied.push(*record);
+    }
+}
+
+type DurableLedger = Durable<Ledger, FileStorage>;
+
+
+/// Append `count` records, checkpointing at random, and return how many
+/// records the last checkpoint covers.
+fn run_workload(dir: &Path, rng: &mut StdRng, count: u64) -> usize {
+    let mut ledger = DurableLedger::open(FileStorage::open(dir).unwrap()).unwrap();
+    let mut checkpointed = 0;
+    for record in 0..count {
+        ledger.apply(record).unwrap();
+        if rng.gen_bool(0.1) {
+            ledger.checkpoint::<u64>().unwrap();
+            checkpointed = ledger.state.applied.len();
+        }
+    }
+    checkpointed
+}
+
+fn recover(dir: &Path) -> io::Result<Ledger> {
+    DurableLedger::open::<u64>(FileStorage::open(dir)?).map(|ledger| ledger.state)
+}
+
+fn assert_prefix_consistent(recovered: &Ledger, checkpointed: usize, count: u64) {
+    let expected: Vec<u64> = (0..count).collect();
+    assert!(
+        expected.starts_with(&recovered.applied),
+        "recovered state {:?} is not a prefix of what was appended",
+        recovered.applied
+    );
+    assert!(
+        recovered.applied.len() >= checkpointed,
+        "lost records covered by a checkpoint: recovered {}, checkpointed {}",
+        recovered.applied.len(),
+        checkpointed
+    );
+}
+
+fn truncate_at(path: &Path, len: u64) {
+    fs::OpenOptions::new().write(true).open(path).unwrap().set_len(len).unwrap();
+}
+
+fn append_bytes(path: &Path, bytes: &[u8]) {
+    let mut contents = fs::read(path).unwrap();
+    contents.extend_from_slice(bytes);
+    fs::write(path, contents).unwrap();
+}
+
+
+#[test]
+fn torn_wal_tail_recovers_a_prefix() {
+    for seed in 0..200 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let dir = tempfile::tempdir().unwrap();
+        let count = rng.gen_range(1..50);
+        let checkpointed = run_workload(dir.path(), &mut rng, count);
+
+        let wal = dir.path().join("wal.jsonl");
+        let len = fs::metadata(&wal).unwrap().len();
+        truncate_at(&wal, rng.gen_range(0..=len));
+
+        let recovered = recover(dir.path()).unwrap_or_else(|err| panic!("seed {seed}: {err}"));
+        assert_prefix_consistent(&recovered, checkpointed, count);
+    }
+}
+
+
+#[test]
+fn partially_synced_wal_tail_recovers_a_prefix() {
+    // An fsync that only partially lands can leave the file extended with
+    // zeroes or stale bytes instead of the record that was being written.
+    for seed in 0..200 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let dir = tempfile::tempdir().unwrap();
+        let count = rng.gen_range(1..50);
+        let checkpointed = run_workload(dir.path(), &mut rng, count);
+
+        let wal = dir.path().join("wal.jsonl");
+        let len = fs::metadata(&wal).unwrap().len();
+        truncate_at(&wal, rng.gen_range(0..=len));
+        let garbage: Vec<u8> = match rng.gen_bool(0.5) {
+            true => vec![0; rng.gen_range(1..64)],
+            false => (0..rng.gen_range(1..64)).map(|_| rng.gen()).collect(),
+        };
+        append_bytes(&wal, &garbage);
+
+        let recovered = recover(dir.path()).unwrap_or_else(|err| panic!("seed {seed}: {err}"));
+        assert_prefix_consistent(&recovered, checkpointed, count);
+    }
+}
+
+
+#[test]
+fn appends_after_recovering_from_a_torn_tail_survive() {
+    for seed in 0..50 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let dir = tempfile::tempdir().unwrap();
+        let count = rng.gen_range(1..50);
+        run_workload(dir.path(), &mut rng, count);
+
+        let wal = dir.path().join("wal.jsonl");
+        let len = fs::metadata(&wal).unwrap().len();
+        truncate_at(&wal, rng.gen_range(0..=len));
+
+        let mut ledger = DurableLedger::open(FileStorage::open(dir.path()).unwrap()).unwrap();
+        let mut expected = ledger.state.clone();
+        for record in 1000..1010 {
+            ledger.apply(record).unwrap();
+            expected.apply(&record);
+        }
+        drop(ledger);
+
+        assert_eq!(recover(dir.path()).unwrap(), expected, "seed {seed}");
+    }
+}
+
+
+#[test]
+fn unlanded_wal_truncation_does_not_replay_checkpointed_records() {
+    let dir = tempfile::tempdir().unwrap();
+    let wal = dir.path().join("wal.jsonl");
+
+    let mut ledger = DurableLedger::open(FileStorage::open(dir.path()).unwrap()).unwrap();
+    for record in 0..10 {
+        ledger.apply(record).unwrap();
+    }
+    let before_checkpoint = fs::read(&wal).unwrap();
+    ledger.checkpoint::<u64>().unwrap();
+    drop(ledger);
+
+    // Pretend we crashed after the snapshot was swapped in but before the WAL was truncated.
+    fs::write(&wal, before_checkpoint).unwrap();
+
+    assert_eq!(recover(dir.path()).unwrap().applied, (0..10).collect::<Vec<_>>());
+}
+
+
+#[test]
+fn torn_snapshot_checkpoint_keeps_the_previous_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rng = StdRng::seed_from_u64(7);
+    let checkpointed = run_workload(dir.path(), &mut rng, 30);
+
+    // A crash while writing the next snapshot leaves a torn temp file behind.
+    fs::write(dir.path().join("snapshot.json.tmp"), b"{\"seq\":99,\"sta").unwrap();
+
+    let recovered = recover(dir.path()).unwrap();
+    assert_prefix_consistent(&recovered, checkpointed, 30);
+    assert_eq!(recovered.applied.len(), 30);
+}
+
+
+#[test]
+fn corruption_before_the_tail_fails_loudly() {
+    for seed in 0..50 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let dir = tempfile::tempdir().unwrap();
+        let mut ledger = DurableLedger::open(FileStorage::open(dir.path()).unwrap()).unwrap();
+        for record in 0..20 {
+            ledger.apply(record).unwrap();
+        }
+        drop(ledger);
+
+        // Zero out a whole record somewhere before the last one, as if its block never made it to disk.
+        let wal = dir.path().join("wal.jsonl");
+        let contents = fs::read(&wal).unwrap();
+        let lines: Vec<&[u8]> = contents.split_inclusive(|&byte| byte == b'\n').collect();
+        let victim = rng.gen_range(0..lines.len() - 1);
+        let mangled: Vec<u8> =
+            lines
+            .iter()
+            .enumerate()
+            .flat_map(|(index, line)| match index == victim {
+                true => [vec![0; line.len() - 1], vec![b'\n']].concat(),
+                false => line.to_vec(),
+            })
+            .collect();
+        fs::write(&wal, mangled).unwrap();
+
+        let err = recover(dir.path()).expect_err(&format!("seed {seed}: recovered from a corrupt wal"));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
+
+
+#[test]
+fn missing_records_fail_loudly() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut ledger = DurableLedger::open(FileStorage::open(dir.path()).unwrap()).unwrap();
+    for record in 0..5 {
+        ledger.apply(record).unwrap();
+    }
+    drop(ledger);
+
+    let wal = dir.path().join("wal.jsonl");
+    let contents = fs::read_to_string(&wal).unwrap();
+    let without_third: Vec<&str> =
+        contents
+        .lines()
+        .enumerate()
+        .filter(|(index, _)| *index != 2)
+        .map(|(_, line)| line)
+        .collect();
+    fs::write(&wal, without_third.join("\n") + "\n").unwrap();
+
+    let err = recover(dir.path()).expect_err("recovered from a wal with a gap");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+
+#[test]
+fn torn_snapshot_fails_loudly() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rng = StdRng::seed_from_u64(3);
+    run_workload(dir.path(), &mut rng, 30);
+    let mut ledger = DurableLedger::open(FileStorage::open(dir.path()).unwrap()).unwrap();
+    ledger.checkpoint::<u64>().unwrap();
+    drop(ledger);
+
+    let snapshot = dir.path().join("snapshot.json");
+    let len = fs::metadata(&snapshot).unwrap().len();
+    truncate_at(&snapshot, len / 2);
+
+    assert!(recover(dir.path()).is_err());
+}
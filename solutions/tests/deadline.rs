@@ -0,0 +1,64 @@
+//! Deadlines handed down to whatever a request calls, and shards that drop work its
+//! coordinator has stopped waiting on.
+
+use std::{sync::{Arc, Mutex}, time::Duration};
+use clap::Parser;
+use solutions::{clock::{self, ManualClock}, deadline::{self, Deadline}, kv::KvClient, lock_manager::LockManager, message::{Body, Envelope}, percolator::Percolator, rpc::RpcClient, txn::MicroOp, workloads::transactions::{self as txn, Opts, Payload, State, TxnId}};
+use tokio::sync::mpsc;
+
+
+#[tokio::test(start_paused = true)]
+async fn handlers_see_how_long_they_have_left() {
+    assert!(deadline::current().is_none());
+    assert_eq!(deadline::remaining_or(Duration::from_secs(1)), Duration::from_secs(1));
+
+    let handled = deadline::scope(Some(Deadline::after(clock::system(), Duration::from_millis(300))), async {
+        assert_eq!(deadline::remaining_or(Duration::from_secs(1)), Duration::from_millis(300));
+        let quick = deadline::within(tokio::time::sleep(Duration::from_millis(100))).await;
+        let slow = deadline::within(tokio::time::sleep(Duration::from_secs(1))).await;
+        (quick, slow, deadline::current().unwrap().budget_ms())
+    }).await;
+    assert_eq!(handled, (Some(()), None, 0));
+}
+
+#[tokio::test]
+async fn deadlines_pass_when_their_clock_says_so() {
+    let clock = ManualClock::new();
+    let deadline = Deadline::after(Arc::new(clock.clone()), Duration::from_millis(300));
+    clock.advance(Duration::from_millis(200));
+    assert_eq!(deadline.budget_ms(), 100);
+
+    let waiting = deadline::scope(Some(deadline.clone()), deadline::within(std::future::pending::<()>()));
+    let passing = async {
+        tokio::task::yield_now().await;
+        clock.advance(Duration::from_millis(100));
+    };
+    assert_eq!(tokio::join!(waiting, passing), (None, ()));
+    assert!(deadline.is_expired());
+}
+
+
+async fn handle(state: &Arc<Mutex<State>>, message: Payload) -> Payload {
+    let (writer, mut replies) = mpsc::unbounded_channel();
+    let rpc = RpcClient::new(writer.clone(), || 1);
+    let timeout = Duration::from_secs(1);
+    let percolator = Percolator::new(KvClient::lin_kv(rpc.clone(), timeout), KvClient::lin_tso(rpc.clone(), timeout));
+    let opts = Arc::new(Opts::parse_from(["txn", "--sharding"]));
+    let envelope = Envelope::new("n1", "n2", Body { msg_id: Some(1), in_reply_to: None, message });
+    txn::handle_envelope(state.clone(), percolator, Arc::new(Mutex::new(LockManager::new())), rpc, opts, envelope, writer).await;
+    replies.try_recv().unwrap().body.message
+}
+
+#[tokio::test]
+async fn shards_out_of_time_drop_their_part_of_the_transaction() {
+    let state = Arc::new(Mutex::new(State::default()));
+    let write = vec![MicroOp::Write { key: 1, value: 10 }];
+    let reply = handle(&state, Payload::ShardExecute { id: TxnId { seq: 1, node: 0 }, txn: write, budget_ms: Some(0) }).await;
+    assert!(matches!(reply, Payload::Error { code: 30, .. }), "{reply:?}");
+
+    // Even if it were told to commit after all, there's nothing to apply.
+    handle(&state, Payload::ShardFinish { id: TxnId { seq: 1, node: 0 }, commit: true }).await;
+    let read = vec![MicroOp::Read { key: 1, value: None }];
+    let reply = handle(&state, Payload::ShardExecute { id: TxnId { seq: 2, node: 0 }, txn: read, budget_ms: Some(1000) }).await;
+    assert!(matches!(&reply, Payload::ShardExecuteOk { txn } if txn == &[MicroOp::Read { key: 1, value: None }]), "{reply:?}");
+}
@@ -0,0 +1,74 @@
+//! Draws sequence diagrams of a small recorded session, with and without filters.
+
+use solutions::{diagram::{render, Filter, Format}, session};
+
+
+const SESSION: &str = r#"
+{"time": 0.0, "src": "c1", "dest": "n1", "body": {"type": "txn", "msg_id": 1, "txn": [["r", 1, null]]}}
+{"time": 0.1, "src": "n1", "dest": "lin-kv", "body": {"type": "read", "msg_id": 1, "key": 1}}
+{"time": 0.2, "src": "c2", "dest": "n2", "body": {"type": "txn", "msg_id": 1, "txn": [["w", 2, 3]]}}
+{"time": 0.3, "src": "lin-kv", "dest": "n1", "body": {"type": "read_ok", "in_reply_to": 1, "value": 5}}
+{"time": 0.4, "src": "n1", "dest": "c1", "body": {"type": "txn_ok", "msg_id": 2, "in_reply_to": 1, "txn": [["r", 1, 5]]}}
+2024-05-01 12:00:00,500 {"src": "n2", "dest": "c2", "body": {"type": "txn_ok", "in_reply_to": 1, "txn": [["w", 2, 3]]}}
+not a message
+"#;
+
+
+fn kinds(filter: &Filter) -> Vec<String> {
+    let session = session::parse(SESSION);
+    filter.apply(&session).iter().map(|recorded| format!("{}:{}", recorded.envelope.source, recorded.kind())).collect()
+}
+
+
+#[test]
+fn session_parses_ndjson_and_log_lines() {
+    let session = session::parse(SESSION);
+
+    assert_eq!(session.len(), 6);
+    assert_eq!(session[1].time, Some(std::time::Duration::from_millis(100)));
+    assert_eq!(session[5].time, None);
+    assert_eq!(session[5].kind(), "txn_ok");
+}
+
+
+#[test]
+fn filters_pick_out_nodes_chains_and_windows() {
+    assert_eq!(kinds(&Filter { nodes: vec!["n2".into()], ..Default::default() }), ["c2:txn", "n2:txn_ok"]);
+
+    // Both clients used msg_id 1, so only the sender tells their chains apart. n1's own
+    // read to lin-kv also happens to be msg_id 1, but it's a different sender.
+    let chain = Filter { chain: Some((Some("c1".into()), 1)), ..Default::default() };
+    assert_eq!(kinds(&chain), ["c1:txn", "n1:txn_ok"]);
+    let chain = Filter { chain: Some((Some("n1".into()), 1)), ..Default::default() };
+    assert_eq!(kinds(&chain), ["n1:read", "lin-kv:read_ok"]);
+    let chain = Filter { chain: Some((None, 1)), ..Default::default() };
+    assert_eq!(kinds(&chain).len(), 6);
+
+    let window = Filter { since: Some(std::time::Duration::from_millis(150)), until: Some(std::time::Duration::from_millis(350)), ..Default::default() };
+    assert_eq!(kinds(&window), ["c2:txn", "lin-kv:read_ok"]);
+}
+
+
+#[test]
+fn diagrams_render_in_mermaid_and_plantuml() {
+    let session = session::parse(SESSION);
+    let messages = Filter { chain: Some((Some("n1".into()), 1)), ..Default::default() }.apply(&session);
+
+    assert_eq!(render(&messages, Format::Mermaid), [
+        "sequenceDiagram",
+        "    participant n1 as n1",
+        "    participant lin_kv as lin-kv",
+        r#"    n1->>lin_kv: read #35;1 {"key":1}"#,
+        r#"    lin_kv-->>n1: read_ok re #35;1 {"value":5}"#,
+        "",
+    ].join("\n"));
+    assert_eq!(render(&messages, Format::PlantUml), [
+        "@startuml",
+        r#"participant "n1" as n1"#,
+        r#"participant "lin-kv" as lin_kv"#,
+        r#"n1 -> lin_kv: read #1 {"key":1}"#,
+        r#"lin_kv --> n1: read_ok re #1 {"value":5}"#,
+        "@enduml",
+        "",
+    ].join("\n"));
+}
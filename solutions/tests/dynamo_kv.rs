@@ -0,0 +1,203 @@
+//! The Dynamo-style key-value store: writes and reads wait on quorums of a key's
+//! replicas, stale replicas are repaired by reads, and nodes standing in for ones that
+//! are down hand what they kept over once they're back.
+
+mod common;
+
+use std::{sync::{Arc, Mutex}, time::Duration};
+use serde_json::{json, Value};
+use solutions::{anti_entropy, clock::ManualClock, vector_clock::VectorClock, workloads::dynamo_kv::{self, merge, resolve, Payload, Shared, State, Version}};
+
+
+fn version(clock: &[(&str, u64)], ts: u64, value: u64) -> Version {
+    let mut vector = VectorClock::new();
+    for &(node, count) in clock {
+        for _ in 0..count {
+            vector.increment(node);
+        }
+    }
+    Version { clock: vector, ts, node: clock[0].0.to_owned(), value: json!(value) }
+}
+
+type Cluster = common::Cluster<Payload, Shared>;
+
+impl Cluster {
+    /// `n` nodes keeping 3 copies of each key, waiting on `read` of them for reads and
+    /// 2 for writes.
+    async fn new(n: usize, read: usize) -> Self {
+        let node = |rpc| Shared::new(Arc::new(Mutex::new(State::new(3))), rpc, Duration::from_millis(200)).with_quorums(read, 2);
+        let init = |node_id: &str, node_ids| Payload::Init { node_id: node_id.to_owned(), node_ids };
+        Self::start(n, node, dynamo_kv::handle_envelope, init).await
+    }
+
+    async fn read(&mut self, node: &str, key: u64) -> Option<Value> {
+        match self.ask(node, Payload::Read { key: json!(key) }).await {
+            Payload::ReadOk { value } => Some(value),
+            Payload::Error { code: 20, .. } => None,
+            other => panic!("expected a read_ok from {node}, got {other:?}"),
+        }
+    }
+
+    /// The values of `key` kept on `node`.
+    fn kept(&self, node: &str, key: u64) -> Vec<Value> {
+        self.nodes[node].state().lock().unwrap().versions(&json!(key)).into_iter().map(|version| version.value).collect()
+    }
+
+    /// `node` crashes (or comes back), and every other node notices.
+    fn set_dead(&mut self, node: &str, dead: bool) {
+        match dead {
+            true => self.dead.lock().unwrap().insert(node.to_owned()),
+            false => self.dead.lock().unwrap().remove(node),
+        };
+        for shared in self.nodes.values() {
+            shared.state().lock().unwrap().set_down(node, dead);
+        }
+    }
+}
+
+#[test]
+fn versions_that_have_seen_others_replace_them_and_the_latest_sibling_is_read() {
+    let mut versions = vec![];
+    assert!(merge(&mut versions, [version(&[("n1", 1)], 10, 1)]));
+    assert!(merge(&mut versions, [version(&[("n1", 2)], 5, 2)]));
+    assert_eq!(versions.len(), 1);
+    assert!(!merge(&mut versions, [version(&[("n1", 1)], 10, 1)]), "an older version changes nothing");
+
+    // Written elsewhere without seeing n1's second write: concurrent, so both are kept,
+    // and the one written last is read.
+    assert!(merge(&mut versions, [version(&[("n2", 1), ("n1", 1)], 20, 3)]));
+    assert_eq!(versions.len(), 2);
+    assert_eq!(resolve(&versions).unwrap().value, json!(3));
+    assert_eq!(resolve(&[]), None);
+}
+
+#[test]
+fn versions_written_here_are_timestamped_by_the_clock_and_never_go_back() {
+    let clock = ManualClock::new();
+    let mut state = State::new(3).with_clock(Arc::new(clock.clone()));
+    state.init("n1", &["n1".to_owned()]);
+    let first = state.version(VectorClock::new(), json!(1));
+    clock.advance(Duration::from_secs(5));
+    let later = state.version(first.clock.clone(), json!(2));
+    assert_eq!(later.ts, first.ts + 5000);
+    // Two in the same millisecond still tell which came last.
+    let again = state.version(later.clock.clone(), json!(3));
+    assert_eq!(again.ts, later.ts + 1);
+}
+
+#[test]
+fn nodes_that_are_down_are_stood_in_for_by_the_next_ones_up() {
+    let mut state = State::new(3);
+    let node_ids: Vec<String> = (1..=5).map(|i| format!("n{i}")).collect();
+    state.init("n1", &node_ids);
+    let key = json!(1);
+    let home = state.home(&key);
+    assert_eq!(home.len(), 3);
+    assert_eq!(state.preference_list(&key), home.iter().map(|node| (node.clone(), None)).collect::<Vec<_>>());
+
+    state.set_down(&home[1], true);
+    let list = state.preference_list(&key);
+    assert_eq!(list.len(), 3);
+    let (stand_in, hint) = &list[1];
+    assert!(!home.contains(stand_in));
+    assert_eq!(hint.as_deref(), Some(&*home[1]));
+
+    state.set_down(&home[1], false);
+    assert!(state.preference_list(&key).iter().all(|(_, hint)| hint.is_none()));
+}
+
+#[tokio::test]
+async fn writes_through_any_node_are_read_back_from_every_node() {
+    let mut cluster = Cluster::new(4, 2).await;
+    assert_eq!(cluster.read("n2", 1).await, None);
+    for (key, node) in [(1, "n1"), (2, "n2"), (3, "n3"), (4, "n4")] {
+        assert!(matches!(cluster.ask(node, Payload::Write { key: json!(key), value: json!(key * 10) }).await, Payload::WriteOk));
+    }
+    for node in ["n1", "n2", "n3", "n4"] {
+        for key in 1..=4 {
+            assert_eq!(cluster.read(node, key).await, Some(json!(key * 10)), "{node}");
+        }
+    }
+
+    let cas = |from: u64, to: u64| Payload::Cas { key: json!(1), from: json!(from), to: json!(to), create_if_not_exists: None };
+    assert!(matches!(cluster.ask("n3", cas(10, 11)).await, Payload::CasOk));
+    assert!(matches!(cluster.ask("n2", cas(10, 12)).await, Payload::Error { code: 22, .. }));
+    let missing = Payload::Cas { key: json!(9), from: json!(0), to: json!(1), create_if_not_exists: None };
+    assert!(matches!(cluster.ask("n1", missing).await, Payload::Error { code: 20, .. }));
+    let created = Payload::Cas { key: json!(9), from: json!(0), to: json!(1), create_if_not_exists: Some(true) };
+    assert!(matches!(cluster.ask("n1", created).await, Payload::CasOk));
+    assert_eq!(cluster.read("n2", 1).await, Some(json!(11)));
+    assert_eq!(cluster.read("n4", 9).await, Some(json!(1)));
+}
+
+#[tokio::test]
+async fn reads_repair_replicas_that_missed_a_write() {
+    let mut cluster = Cluster::new(4, 3).await;
+    assert!(matches!(cluster.ask("n1", Payload::Write { key: json!(1), value: json!(1) }).await, Payload::WriteOk));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // One replica alone takes in a newer write.
+    let home = cluster.nodes["n1"].state().lock().unwrap().home(&json!(1));
+    let newer = {
+        let mut state = cluster.nodes[&*home[0]].state().lock().unwrap();
+        let context = state.context(&json!(1));
+        let newer = state.version(context, json!(2));
+        state.store(&json!(1), vec![newer.clone()], None);
+        newer
+    };
+    assert_eq!(cluster.kept(&home[1], 1), [json!(1)]);
+
+    assert_eq!(cluster.read("n1", 1).await, Some(json!(2)));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    for node in &home {
+        assert_eq!(cluster.nodes[&**node].state().lock().unwrap().versions(&json!(1)), vec![newer.clone()], "{node}");
+    }
+}
+
+#[tokio::test]
+async fn writes_to_a_node_thats_down_are_handed_over_once_its_back() {
+    let mut cluster = Cluster::new(4, 2).await;
+    let home = cluster.nodes["n1"].state().lock().unwrap().home(&json!(1));
+    let stand_in = ["n1", "n2", "n3", "n4"].into_iter().find(|node| !home.iter().any(|home| &**home == *node)).unwrap();
+    let (away, coordinator) = (home[0].to_string(), home[1].to_string());
+
+    cluster.set_dead(&away, true);
+    assert!(matches!(cluster.ask(&coordinator, Payload::Write { key: json!(1), value: json!(1) }).await, Payload::WriteOk));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(cluster.kept(stand_in, 1), [json!(1)]);
+    assert!(cluster.kept(&away, 1).is_empty());
+    // Nothing's handed over while it's down.
+    assert!(cluster.nodes[stand_in].state().lock().unwrap().hinted().is_empty());
+
+    cluster.set_dead(&away, false);
+    assert_eq!(cluster.nodes[stand_in].state().lock().unwrap().hinted().len(), 1);
+    dynamo_kv::hand_off(&cluster.nodes[stand_in]);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(cluster.kept(&away, 1), [json!(1)]);
+    assert!(cluster.kept(stand_in, 1).is_empty(), "the stand-in lets go once it's handed over");
+    assert!(cluster.nodes[stand_in].state().lock().unwrap().hinted().is_empty());
+}
+
+#[tokio::test]
+async fn repair_copies_the_versions_either_node_is_missing() {
+    let node_ids: Vec<String> = (1..=3).map(|i| format!("n{i}")).collect();
+    let nodes: Vec<Mutex<State>> = ["n1", "n2"].into_iter().map(|node_id| {
+        let mut state = State::new(3);
+        state.init(node_id, &node_ids);
+        Mutex::new(state)
+    }).collect();
+    nodes[0].lock().unwrap().store(&json!(1), vec![version(&[("n1", 1)], 1, 10)], None);
+    nodes[0].lock().unwrap().store(&json!(2), vec![version(&[("n1", 1)], 1, 20)], None);
+    nodes[1].lock().unwrap().store(&json!(2), vec![version(&[("n2", 1)], 2, 21)], None);
+    nodes[1].lock().unwrap().store(&json!(3), vec![version(&[("n2", 1)], 1, 30)], None);
+
+    let call = |message| std::future::ready(anti_entropy::answer(&mut *nodes[1].lock().unwrap(), "n1", message));
+    assert!(anti_entropy::repair(&nodes[0], "n2", call).await);
+    for node in &nodes {
+        let node = node.lock().unwrap();
+        let read = |key: u64| resolve(&node.versions(&json!(key))).map(|version| version.value.clone());
+        assert_eq!((read(1), read(2), read(3)), (Some(json!(10)), Some(json!(21)), Some(json!(30))));
+        // The concurrent writes to 2 are both kept.
+        assert_eq!(node.versions(&json!(2)).len(), 2);
+    }
+}
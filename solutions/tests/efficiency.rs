@@ -0,0 +1,55 @@
+//! Estimating messages per operation.
+
+use std::{io::{BufRead, BufReader, Write}, process::{Command, Stdio}, time::{Duration, Instant}};
+use solutions::efficiency::MsgsPerOp;
+
+
+#[test]
+fn estimate_covers_only_the_last_window() {
+    let start = Instant::now();
+    let mut estimator = MsgsPerOp::new(Duration::from_secs(10));
+    assert_eq!(estimator.estimate(start).msgs_per_op(), None);
+
+    estimator.op(start);
+    for _ in 0..20 {
+        estimator.message(start);
+    }
+    let later = start + Duration::from_secs(6);
+    estimator.op(later);
+    estimator.message(later);
+    estimator.message(later);
+    let estimate = estimator.estimate(later);
+    assert_eq!((estimate.ops, estimate.messages), (2, 22));
+    assert_eq!(estimate.msgs_per_op(), Some(11.));
+
+    // The first burst has slid out of the window.
+    let estimate = estimator.estimate(start + Duration::from_secs(12));
+    assert_eq!((estimate.ops, estimate.messages), (1, 2));
+    assert_eq!(estimate.to_string(), "2.00 (messages=2 ops=1 window=10s)");
+}
+
+#[test]
+fn nodes_print_their_estimate_given_client_ops() {
+    let mut node =
+        Command::new(env!("CARGO_BIN_EXE_solutions"))
+        .args(["echo", "--client-ops", "echo"])
+        .env("METRICS_INTERVAL_MS", "0")
+        .env_remove("RUST_LOG")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = node.stdin.take().unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}}}"#).unwrap();
+    for msg_id in 2..=4 {
+        writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "echo", "msg_id": {msg_id}, "echo": "hi"}}}}"#).unwrap();
+    }
+    let mut stdout = BufReader::new(node.stdout.take().unwrap()).lines();
+    for _ in 1..=4 {
+        stdout.next().unwrap().unwrap();
+    }
+    drop(stdin);
+    let stderr = String::from_utf8(node.wait_with_output().unwrap().stderr).unwrap();
+    assert!(stderr.contains("msgs-per-op: 0.00 (messages=0 ops=3 window=10s)"), "{stderr}");
+}
@@ -0,0 +1,62 @@
+//! How a failure detector's view of its peers follows what it hears from them.
+
+mod common;
+
+use solutions::{failure_detector::{Config, Event, FailureDetector, Status}, message};
+use tokio::time::Instant;
+use common::millis;
+
+
+fn event(peer: &str, from: Status, to: Status) -> Event {
+    Event { peer: message::intern(peer), from, to }
+}
+
+#[test]
+fn quiet_peers_are_suspected_then_taken_down_and_come_back_when_heard_from() {
+    let start = Instant::now();
+    let mut detector = FailureDetector::new(Config::default(), ["n2", "n3"].map(message::intern), start);
+    let mut events = detector.subscribe();
+    // n2 keeps talking, n3 goes quiet.
+    for ms in (50..=1500).step_by(50) {
+        detector.heard_from("n2", start + millis(ms));
+    }
+
+    assert_eq!(detector.check(start + millis(300)), []);
+    assert_eq!(detector.check(start + millis(500)), [event("n3", Status::Up, Status::Suspect)]);
+    assert_eq!(detector.check(start + millis(1000)), [event("n3", Status::Suspect, Status::Down)]);
+    assert_eq!((detector.status("n2"), detector.peers(Status::Down)), (Some(Status::Up), vec![message::intern("n3")]));
+
+    assert_eq!(detector.heard_from("n3", start + millis(1500)), Some(event("n3", Status::Down, Status::Up)));
+    let published: Vec<Event> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+    assert_eq!(published, [
+        event("n3", Status::Up, Status::Suspect),
+        event("n3", Status::Suspect, Status::Down),
+        event("n3", Status::Down, Status::Up),
+    ]);
+}
+
+#[test]
+fn peers_that_are_usually_slow_are_given_longer() {
+    let start = Instant::now();
+    let mut detector = FailureDetector::new(Config::default(), ["n2", "n3"].map(message::intern), start);
+    for n in 1..=10 {
+        detector.heard_from("n2", start + millis(100 * n));
+        detector.heard_from("n3", start + millis(400 * n));
+    }
+    // Both quiet for 800ms since they were last heard from: n2 (at 1800ms), n3 (at 4800ms).
+    assert_eq!(detector.check(start + millis(1800)), [event("n2", Status::Up, Status::Suspect)]);
+    assert_eq!(detector.status("n3"), Some(Status::Up));
+    assert!(detector.phi("n3", start + millis(4800)).unwrap() < 1.);
+}
+
+#[test]
+fn only_quiet_peers_are_pinged_once_an_interval() {
+    let start = Instant::now();
+    let mut detector = FailureDetector::new(Config::default(), ["n2", "n3"].map(message::intern), start);
+    detector.heard_from("n2", start + millis(60));
+    assert_eq!(detector.to_ping(start + millis(120)), [message::intern("n3")]);
+    assert_eq!(detector.to_ping(start + millis(150)), []);
+    let mut pinged = detector.to_ping(start + millis(220));
+    pinged.sort();
+    assert_eq!(pinged, ["n2", "n3"].map(message::intern));
+}
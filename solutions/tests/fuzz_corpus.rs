@@ -0,0 +1,16 @@
+//! Replays the fuzz corpora shipped in `fuzz/corpus`, so every input in them (the
+//! seeds, and any crash the fuzzer once found) keeps getting checked on stable.
+
+use std::fs;
+use serde_json::Value;
+use solutions::{message::Envelope, testing::replay_corpus};
+
+
+#[test]
+fn fuzz_corpora_replay_cleanly() {
+    let mut replayed = 0;
+    for target in fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus")).unwrap() {
+        replayed += replay_corpus::<Envelope<Value>>(target.unwrap().path()).unwrap();
+    }
+    assert!(replayed > 0);
+}
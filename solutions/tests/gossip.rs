@@ -0,0 +1,38 @@
+//! Choosing who a round of gossip goes to.
+
+use std::collections::HashSet;
+use solutions::{cli::FanoutStrategy, gossip, tuning};
+
+
+#[test]
+fn fanout_counts_peers_or_a_percentage_of_the_cluster() {
+    assert_eq!(gossip::fanout(None, FanoutStrategy::Peers, 24), 24);
+    assert_eq!(gossip::fanout(Some(3), FanoutStrategy::Peers, 24), 3);
+    assert_eq!(gossip::fanout(Some(30), FanoutStrategy::Peers, 24), 24);
+    assert_eq!(gossip::fanout(Some(25), FanoutStrategy::Percent, 24), 6);
+    assert_eq!(gossip::fanout(Some(10), FanoutStrategy::Percent, 24), 3);
+    assert_eq!(gossip::fanout(Some(1), FanoutStrategy::Percent, 4), 1);
+    assert_eq!(gossip::fanout(Some(500), FanoutStrategy::Percent, 4), 4);
+}
+
+#[test]
+fn choose_picks_a_random_subset_of_the_candidates() {
+    // The only test in this binary touching the global tunables, so nothing races it.
+    let candidates: Vec<usize> = (0..10).collect();
+    assert_eq!(gossip::choose(candidates.clone(), 20), candidates);
+
+    tuning::global().set_fanout(Some(3));
+    let mut seen = HashSet::new();
+    for _ in 0..100 {
+        let chosen = gossip::choose(candidates.clone(), 20);
+        assert_eq!(chosen.len(), 3);
+        assert!(chosen.iter().all(|candidate| candidates.contains(candidate)));
+        seen.extend(chosen);
+    }
+    assert_eq!(seen.len(), 10, "every candidate should get its turn");
+
+    tuning::global().set_fanout_strategy(FanoutStrategy::Percent);
+    tuning::global().set_fanout(Some(25));
+    assert_eq!(gossip::choose(candidates.clone(), 20).len(), 5);
+    tuning::global().set_fanout(None);
+}
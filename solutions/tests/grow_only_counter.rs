@@ -0,0 +1,214 @@
+//! Which peers a counter node tells about its committed total, given what they already
+//! know.
+
+use std::{sync::{Arc, Mutex}, time::Duration};
+use solutions::{hyparview, message::{Body, Envelope}, metrics, routing::{self, Inbound, Router}, workloads::grow_only_counter::{self, Payload, State}};
+use tokio::{sync::mpsc, time::Instant};
+
+
+fn from(src: &str, message: Payload) -> Envelope<Payload> {
+    Envelope::new(src, "n1", Body { msg_id: Some(1), in_reply_to: None, message })
+}
+
+/// Who the node would tell what, after a commit.
+fn updates(state: &Arc<Mutex<State>>) -> Vec<(String, usize)> {
+    let mut updates: Vec<(String, usize)> =
+        grow_only_counter::updates(&mut state.lock().unwrap())
+        .into_iter()
+        .map(|envelope| match envelope.body.message {
+            Payload::UpdateCounter { value, .. } => (envelope.destination.to_string(), value),
+            other => panic!("expected an update, got {other:?}"),
+        })
+        .collect();
+    updates.sort();
+    updates
+}
+
+#[tokio::test]
+async fn peers_are_only_told_totals_they_dont_know() {
+    let state = Arc::new(Mutex::new(State::new()));
+    let (writer, _replies) = mpsc::unbounded_channel();
+    let node_ids = ["n1", "n2", "n3"].map(String::from).to_vec();
+    for message in [
+        from("c1", Payload::Init { node_id: "n1".to_owned(), node_ids }),
+        // n2 knows the total it tells us about.
+        from("n2", Payload::UpdateCounter { value: 7, updates: vec![] }),
+    ] {
+        grow_only_counter::handle_envelope(state.clone(), message, writer.clone()).await;
+    }
+    assert_eq!(updates(&state), [("n3".to_owned(), 7)]);
+    // n3 was told just now.
+    assert_eq!(updates(&state), []);
+}
+
+#[tokio::test]
+async fn a_duplicate_cas_ok_is_only_counted_once() {
+    let state = Arc::new(Mutex::new(State::new()));
+    let (writer, mut sent) = mpsc::unbounded_channel();
+    let node_ids = ["n1", "n2"].map(String::from).to_vec();
+    for message in [from("c1", Payload::Init { node_id: "n1".to_owned(), node_ids }), from("c1", Payload::Add { delta: 5 })] {
+        grow_only_counter::handle_envelope(state.clone(), message, writer.clone()).await;
+    }
+    while sent.try_recv().is_ok() {}
+
+    grow_only_counter::commit_buffered_delta(&state, &writer, Instant::now());
+    let cas = sent.try_recv().unwrap();
+    assert!(matches!(cas.body.message, Payload::Cas { .. }), "{cas:?}");
+    let cas_ok = Envelope::new("seq-kv", "n1", Body { msg_id: Some(2), in_reply_to: cas.msg_id(), message: Payload::CasOk });
+    for reply in [cas_ok.clone(), cas_ok] {
+        grow_only_counter::handle_envelope(state.clone(), reply, writer.clone()).await;
+    }
+    while sent.try_recv().is_ok() {}
+
+    grow_only_counter::handle_envelope(state.clone(), from("c1", Payload::Read { key: None }), writer.clone()).await;
+    assert!(matches!(sent.try_recv().unwrap().body.message, Payload::ReadOk { value: 5 }));
+}
+
+#[tokio::test]
+async fn a_cas_seq_kv_never_answers_is_sent_again_as_it_was() {
+    let state = Arc::new(Mutex::new(State::new()));
+    let (writer, mut sent) = mpsc::unbounded_channel();
+    let node_ids = ["n1", "n2"].map(String::from).to_vec();
+    for message in [from("c1", Payload::Init { node_id: "n1".to_owned(), node_ids }), from("c1", Payload::Add { delta: 5 })] {
+        grow_only_counter::handle_envelope(state.clone(), message, writer.clone()).await;
+    }
+    while sent.try_recv().is_ok() {}
+
+    let start = Instant::now();
+    grow_only_counter::commit_buffered_delta(&state, &writer, start);
+    let lost = sent.try_recv().unwrap();
+    while sent.try_recv().is_ok() {}
+    grow_only_counter::commit_buffered_delta(&state, &writer, start + grow_only_counter::CAS_TIMEOUT);
+    let retried = sent.try_recv().unwrap();
+    assert!(matches!(retried.body.message, Payload::Cas { from: 0, to: 5, .. }), "{retried:?}");
+    while sent.try_recv().is_ok() {}
+
+    let cas_ok = |cas: &Envelope<Payload>| Envelope::new("seq-kv", "n1", Body { msg_id: Some(2), in_reply_to: cas.msg_id(), message: Payload::CasOk });
+    let read = || from("c1", Payload::Read { key: None });
+    grow_only_counter::handle_envelope(state.clone(), cas_ok(&retried), writer.clone()).await;
+    while sent.try_recv().is_ok() {}
+    grow_only_counter::handle_envelope(state.clone(), read(), writer.clone()).await;
+    assert!(matches!(sent.try_recv().unwrap().body.message, Payload::ReadOk { value: 5 }));
+
+    // Only one of the two could have been applied, so a reply to the first is an orphan.
+    grow_only_counter::handle_envelope(state.clone(), cas_ok(&lost), writer.clone()).await;
+    grow_only_counter::handle_envelope(state.clone(), read(), writer.clone()).await;
+    assert!(matches!(sent.try_recv().unwrap().body.message, Payload::ReadOk { value: 5 }));
+}
+
+#[tokio::test]
+async fn a_cas_ok_after_the_timeout_is_only_counted_once() {
+    let state = Arc::new(Mutex::new(State::new()));
+    let (writer, mut sent) = mpsc::unbounded_channel();
+    let node_ids = ["n1", "n2"].map(String::from).to_vec();
+    for message in [from("c1", Payload::Init { node_id: "n1".to_owned(), node_ids }), from("c1", Payload::Add { delta: 5 })] {
+        grow_only_counter::handle_envelope(state.clone(), message, writer.clone()).await;
+    }
+    while sent.try_recv().is_ok() {}
+
+    let start = Instant::now();
+    grow_only_counter::commit_buffered_delta(&state, &writer, start);
+    let delayed = sent.try_recv().unwrap();
+    while sent.try_recv().is_ok() {}
+    // seq-kv applied it, but its cas_ok is slow to arrive.
+    for message in [from("seq-kv", Payload::ReadOk { value: 5 }), from("c1", Payload::Add { delta: 2 })] {
+        grow_only_counter::handle_envelope(state.clone(), message, writer.clone()).await;
+    }
+    while sent.try_recv().is_ok() {}
+
+    // The delta in doubt isn't sent again on top of the total that has it.
+    grow_only_counter::commit_buffered_delta(&state, &writer, start + grow_only_counter::CAS_TIMEOUT);
+    let next = sent.try_recv().unwrap();
+    assert!(matches!(next.body.message, Payload::Cas { from: 5, to: 7, .. }), "{next:?}");
+    while sent.try_recv().is_ok() {}
+
+    let cas_ok = |cas: &Envelope<Payload>| Envelope::new("seq-kv", "n1", Body { msg_id: Some(2), in_reply_to: cas.msg_id(), message: Payload::CasOk });
+    for reply in [cas_ok(&delayed), cas_ok(&next)] {
+        grow_only_counter::handle_envelope(state.clone(), reply, writer.clone()).await;
+    }
+    while sent.try_recv().is_ok() {}
+
+    grow_only_counter::commit_buffered_delta(&state, &writer, start + grow_only_counter::CAS_TIMEOUT * 2);
+    let sent_now: Vec<_> = std::iter::from_fn(|| sent.try_recv().ok()).map(|envelope| envelope.body.message).collect();
+    assert!(matches!(sent_now[..], [Payload::Read { .. }]), "nothing's left to commit: {sent_now:?}");
+    grow_only_counter::handle_envelope(state.clone(), from("c1", Payload::Read { key: None }), writer.clone()).await;
+    assert!(matches!(sent.try_recv().unwrap().body.message, Payload::ReadOk { value: 7 }));
+}
+
+#[tokio::test]
+async fn new_members_are_told_the_total_straight_away_and_leavers_forgotten() {
+    let state = Arc::new(Mutex::new(State::new()));
+    let (writer, mut sent) = mpsc::unbounded_channel();
+    let node_ids = ["n1", "n2"].map(String::from).to_vec();
+    for message in [
+        from("c1", Payload::Init { node_id: "n1".to_owned(), node_ids }),
+        from("n2", Payload::UpdateCounter { value: 7, updates: vec![] }),
+    ] {
+        grow_only_counter::handle_envelope(state.clone(), message, writer.clone()).await;
+    }
+    while sent.try_recv().is_ok() {}
+
+    grow_only_counter::handle_envelope(state.clone(), from("admin", Payload::Join { node_id: "n3".to_owned() }), writer.clone()).await;
+    let told: Vec<(String, String)> = std::iter::from_fn(|| sent.try_recv().ok()).map(|envelope| (envelope.destination.to_string(), metrics::message_type(&envelope.body.message))).collect();
+    assert_eq!(told, [("n3".to_owned(), "update_counter".to_owned()), ("admin".to_owned(), "join_ok".to_owned())]);
+
+    grow_only_counter::handle_envelope(state.clone(), from("admin", Payload::Leave { node_id: "n2".to_owned() }), writer.clone()).await;
+    // A newer total from seq-kv goes to n3, and not to n2.
+    grow_only_counter::handle_envelope(state.clone(), from("seq-kv", Payload::ReadOk { value: 10 }), writer.clone()).await;
+    assert_eq!(updates(&state), [("n3".to_owned(), 10)]);
+}
+
+#[tokio::test]
+async fn with_partial_views_only_the_active_view_is_told() {
+    let state = Arc::new(Mutex::new(State::new().with_partial_view(hyparview::Config::default())));
+    let (writer, _replies) = mpsc::unbounded_channel();
+    let node_ids = ["n1", "n2", "n3", "n4", "n5", "n6"].map(String::from).to_vec();
+    let join = serde_json::from_str::<Payload>(r#"{"type": "view", "view": "join"}"#).unwrap();
+    for message in [
+        from("c1", Payload::Init { node_id: "n1".to_owned(), node_ids }),
+        // Everyone joins through n1, the first node.
+        from("n2", join.clone()),
+        from("n3", join),
+        from("n4", Payload::UpdateCounter { value: 7, updates: vec![] }),
+    ] {
+        grow_only_counter::handle_envelope(state.clone(), message, writer.clone()).await;
+    }
+    assert_eq!(updates(&state), [("n2".to_owned(), 7), ("n3".to_owned(), 7)]);
+}
+
+#[tokio::test]
+async fn read_only_nodes_forward_adds_to_writable_ones_and_pass_the_replies_back() {
+    let (writer, mut sent) = mpsc::unbounded_channel();
+    let node = |node_id: &'static str| {
+        let writer = writer.clone();
+        async move {
+            let state = Arc::new(Mutex::new(State::new().with_read_only(vec!["n2".to_owned()])));
+            let node_ids = ["n1", "n2"].map(String::from).to_vec();
+            let init = Envelope::new("c1", node_id, Body { msg_id: Some(1), in_reply_to: None, message: Payload::Init { node_id: node_id.to_owned(), node_ids } });
+            grow_only_counter::handle_envelope(state.clone(), init, writer).await;
+            state
+        }
+    };
+    let (n1, n2) = (node("n1").await, node("n2").await);
+    while sent.try_recv().is_ok() {}
+    let [mut at_n1, mut at_n2] = [(); 2].map(|_| Router::new(routing::DEFAULT_TTL, Duration::from_secs(10)));
+    let now = tokio::time::Instant::now();
+    let add = |to: &str| Envelope::new("c1", to, Body { msg_id: Some(5), in_reply_to: None, message: Payload::Add { delta: 3 } });
+
+    assert!(grow_only_counter::forward_add(&n1.lock().unwrap(), &mut at_n1, &add("n1"), now).is_none());
+    let forwarded = grow_only_counter::forward_add(&n2.lock().unwrap(), &mut at_n2, &add("n2"), now).unwrap();
+    assert_eq!(&*forwarded.destination, "n1");
+    // n1 sees the client's add, and answers the client as usual.
+    let Inbound::Handle(request) = at_n1.receive(forwarded, now) else { panic!("n1 should handle the add") };
+    assert_eq!((&*request.source, request.body.msg_id), ("c1", Some(5)));
+    grow_only_counter::handle_envelope(n1.clone(), request, writer.clone()).await;
+    let reply = at_n1.send(sent.try_recv().unwrap());
+    assert_eq!(&*reply.destination, "n2");
+    let Inbound::Send(reply) = at_n2.receive(reply, now) else { panic!("n2 should pass the reply on") };
+    assert_eq!((&*reply.destination, reply.body.in_reply_to), ("c1", Some(5)));
+    assert!(matches!(reply.body.message.message, Payload::AddOk));
+
+    // Reads are answered where they're sent.
+    let read = Envelope::new("c1", "n2", Body { msg_id: Some(6), in_reply_to: None, message: Payload::Read { key: None } });
+    assert!(grow_only_counter::forward_add(&n2.lock().unwrap(), &mut at_n2, &read, now).is_none());
+}
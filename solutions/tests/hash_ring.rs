@@ -0,0 +1,55 @@
+//! Who owns which keys on a consistent-hash ring, and how little moves when the
+//! cluster changes.
+
+use std::collections::HashMap;
+use solutions::hash_ring::HashRing;
+
+
+fn owners_of(ring: &HashRing, keys: std::ops::Range<u64>) -> HashMap<u64, String> {
+    keys.map(|key| (key, ring.owner(&key).unwrap().to_string())).collect()
+}
+
+#[test]
+fn every_node_agrees_and_the_keys_are_spread_out() {
+    let nodes = ["n1", "n2", "n3", "n4"];
+    let ring = HashRing::new(&nodes);
+    assert_eq!(owners_of(&ring, 0..1000), owners_of(&HashRing::new(&nodes), 0..1000));
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for owner in owners_of(&ring, 0..4000).into_values() {
+        *counts.entry(owner).or_default() += 1;
+    }
+    assert_eq!(counts.len(), 4);
+    assert!(counts.values().all(|&count| count > 500), "{counts:?}");
+    assert!(HashRing::new(&[] as &[&str]).owner(&1).is_none());
+}
+
+#[test]
+fn replicas_are_distinct_nodes_starting_with_the_owner() {
+    let ring = HashRing::with_virtual_nodes(&["n1", "n2", "n3"], 8);
+    for key in ["x", "y", "z"] {
+        let owners = ring.owners(key, 2);
+        assert_eq!(owners.len(), 2);
+        assert_eq!(owners[0], ring.owner(key).unwrap());
+        assert_ne!(owners[0], owners[1]);
+        assert_eq!(ring.owners(key, 5).len(), 3);
+    }
+}
+
+#[test]
+fn changing_the_cluster_only_moves_the_keys_it_has_to() {
+    let mut ring = HashRing::new(&["n1", "n2", "n3"]);
+    let before = owners_of(&ring, 0..1000);
+
+    ring.add("n4");
+    let grown = owners_of(&ring, 0..1000);
+    assert!(before.iter().all(|(key, owner)| grown[key] == *owner || grown[key] == "n4"));
+    assert!(grown.values().any(|owner| owner == "n4"));
+
+    ring.remove("n4");
+    assert_eq!(owners_of(&ring, 0..1000), before);
+    ring.remove("n2");
+    let shrunk = owners_of(&ring, 0..1000);
+    assert!(before.iter().all(|(key, owner)| owner == "n2" || shrunk[key] == *owner));
+    assert_eq!(ring.nodes().len(), 2);
+}
@@ -0,0 +1,116 @@
+//! HyParView partial views: nodes joining through one another, keeping a few neighbors
+//! each, and staying connected as neighbors fail.
+
+use std::collections::{HashMap, VecDeque};
+use solutions::{hyparview::{Config, HyParView, Message}, message::{self, NodeId}};
+
+
+/// A cluster of `n` nodes, all joined through n1, with every message delivered.
+struct Cluster {
+    views: HashMap<NodeId, HyParView>,
+    in_flight: VecDeque<(NodeId, NodeId, Message)>,
+}
+
+impl Cluster {
+    fn new(n: usize) -> Self {
+        let nodes: Vec<NodeId> = (1..=n).map(|i| message::intern(&format!("n{i}"))).collect();
+        let mut cluster = Self { views: HashMap::new(), in_flight: VecDeque::new() };
+        for node in &nodes {
+            let mut view = HyParView::new(Config::default(), node, nodes.clone());
+            let sends = if **node == *"n1" { vec![] } else { view.join("n1") };
+            cluster.views.insert(node.clone(), view);
+            cluster.send(node, sends);
+            cluster.deliver();
+        }
+        cluster
+    }
+
+    fn send(&mut self, from: &NodeId, sends: Vec<(NodeId, Message)>) {
+        self.in_flight.extend(sends.into_iter().map(|(to, message)| (from.clone(), to, message)));
+    }
+
+    /// Deliver everything, including whatever's sent on account of it; messages to nodes
+    /// that have failed are lost.
+    fn deliver(&mut self) {
+        while let Some((from, to, message)) = self.in_flight.pop_front() {
+            let Some(view) = self.views.get_mut(&to) else {
+                continue;
+            };
+            let sends = view.receive(&from, message);
+            self.send(&to, sends);
+        }
+    }
+
+    fn tick(&mut self) {
+        let nodes: Vec<NodeId> = self.views.keys().cloned().collect();
+        for node in nodes {
+            let sends = self.views.get_mut(&node).unwrap().tick();
+            self.send(&node, sends);
+        }
+        self.deliver();
+    }
+
+    fn is_connected(&self) -> bool {
+        let start = self.views.keys().next().unwrap().clone();
+        let mut reached = vec![start.clone()];
+        let mut frontier = vec![start];
+        while let Some(node) = frontier.pop() {
+            let linked = self.views.iter().filter(|(other, view)| view.active().contains(&node) || self.views[&node].active().contains(other)).map(|(other, _)| other.clone());
+            for other in linked.collect::<Vec<_>>() {
+                if !reached.contains(&other) {
+                    reached.push(other.clone());
+                    frontier.push(other);
+                }
+            }
+        }
+        reached.len() == self.views.len()
+    }
+}
+
+
+#[test]
+fn joining_nodes_get_a_few_neighbors_each_and_the_cluster_stays_connected() {
+    let mut cluster = Cluster::new(30);
+    for _ in 0..5 {
+        cluster.tick();
+    }
+    let config = Config::default();
+    for (node, view) in &cluster.views {
+        assert!(!view.active().is_empty() && view.active().len() <= config.active_size, "{node}: {:?}", view.active());
+        assert!(view.passive().len() <= config.passive_size);
+        assert!(!view.active().contains(node) && !view.passive().contains(node));
+        assert!(view.active().iter().all(|neighbor| !view.passive().contains(neighbor)));
+    }
+    assert!(cluster.is_connected());
+}
+
+#[test]
+fn failed_neighbors_are_replaced_from_the_passive_view() {
+    let mut cluster = Cluster::new(20);
+    let failed: Vec<NodeId> = ["n1", "n2", "n3"].map(message::intern).to_vec();
+    for node in &failed {
+        cluster.views.remove(node);
+    }
+    let nodes: Vec<NodeId> = cluster.views.keys().cloned().collect();
+    for node in &nodes {
+        let sends: Vec<_> = failed.iter().flat_map(|failed| cluster.views.get_mut(node).unwrap().failed(failed)).collect();
+        cluster.send(node, sends);
+    }
+    cluster.deliver();
+    for _ in 0..5 {
+        cluster.tick();
+    }
+    for (node, view) in &cluster.views {
+        assert!(!view.active().is_empty(), "{node} has no neighbors left");
+        assert!(view.active().iter().chain(view.passive()).all(|other| !failed.contains(other)), "{node}: {view:?}");
+    }
+    assert!(cluster.is_connected());
+}
+
+#[test]
+fn view_messages_nest_in_a_workload_message() {
+    let message = Message::Shuffle { origin: "n1".to_owned(), nodes: vec!["n2".to_owned()], ttl: 3 };
+    let json = serde_json::to_value(&message).unwrap();
+    assert_eq!(json["view"], "shuffle");
+    assert_eq!(serde_json::from_value::<Message>(json).unwrap(), message);
+}
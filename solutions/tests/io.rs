@@ -0,0 +1,126 @@
+//! Sampling the io layer's per-message logs, watching its queues, and the order it writes in.
+
+use std::{io::{BufRead, BufReader, Write}, process::{Command, Stdio}, time::Duration};
+use serde_json::Value;
+use solutions::{io::{self, Inbox}, message::{Body, Envelope}, metrics, workloads::broadcast::Payload};
+use tokio::sync::mpsc;
+
+
+/// Run the multiplexed echo node with trace logs on and `args`, feed it an `init`, four
+/// `echo`s and a line that isn't a message, and return its stderr.
+fn stderr_of_echo(args: &[&str]) -> String {
+    let mut node =
+        Command::new(env!("CARGO_BIN_EXE_solutions"))
+        .args(args)
+        .env("TOKIO_WORKER_THREADS", "2")
+        .env("METRICS_INTERVAL_MS", "0")
+        .env("RUST_LOG", "solutions::io=trace")
+        .env_remove("LOG_SAMPLE")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = node.stdin.take().unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}}}"#).unwrap();
+    for msg_id in 2..=5 {
+        writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "echo", "msg_id": {msg_id}, "echo": "hi"}}}}"#).unwrap();
+    }
+    writeln!(stdin, "not json").unwrap();
+    let mut stdout = BufReader::new(node.stdout.take().unwrap()).lines();
+    for _ in 1..=5 {
+        stdout.next().unwrap().unwrap();
+    }
+    drop(stdin);
+    String::from_utf8(node.wait_with_output().unwrap().stderr).unwrap()
+}
+
+fn count(stderr: &str, event: &str) -> usize {
+    stderr.lines().filter(|line| line.contains(event)).count()
+}
+
+
+#[test]
+fn every_message_is_traced_by_default() {
+    let stderr = stderr_of_echo(&["echo"]);
+    assert_eq!(count(&stderr, "read message"), 5, "{stderr}");
+    assert_eq!(count(&stderr, "writing line"), 5, "{stderr}");
+}
+
+#[test]
+fn sampling_traces_one_in_n_but_every_warning() {
+    let stderr = stderr_of_echo(&["echo", "--log-sample", "2"]);
+    // The 1st, 3rd and 5th each way.
+    assert_eq!(count(&stderr, "read message"), 3, "{stderr}");
+    assert_eq!(count(&stderr, "writing line"), 3, "{stderr}");
+    assert_eq!(count(&stderr, "skipping line that doesn't deserialize"), 1, "{stderr}");
+}
+
+#[tokio::test]
+async fn inbox_reports_its_depth_and_warns_once_when_backed_up() {
+    let (tx, rx) = mpsc::unbounded_channel();
+    for message in 0..1500 {
+        tx.send(message).unwrap();
+    }
+    let mut inbox = Inbox::from(rx);
+    assert_eq!(inbox.recv().await, Some(0));
+    let snapshot = metrics::global().snapshot();
+    assert_eq!(snapshot.gauge("queue.stdin"), Some(1499));
+    assert_eq!(snapshot.counter("warnings.queue.stdin"), 1);
+
+    // Still backed up: no more warnings until it has drained to half the threshold.
+    for _ in 0..1000 {
+        inbox.recv().await.unwrap();
+    }
+    assert_eq!(metrics::global().snapshot().counter("warnings.queue.stdin"), 1);
+    for message in 0..2000 {
+        tx.send(message).unwrap();
+    }
+    inbox.recv().await.unwrap();
+    let snapshot = metrics::global().snapshot();
+    assert_eq!(snapshot.counter("warnings.queue.stdin"), 2);
+    assert_eq!(snapshot.gauge("queue.stdin.max"), Some(2498));
+}
+
+#[test]
+fn lines_parse_the_same_however_theyre_parsed() {
+    // With `--features simd-json`, simd-json goes first: it should agree with serde_json on
+    // what parses, and leave errors to serde_json.
+    let mut scratch = vec![];
+    for line in [
+        r#"{"src":"c1","dest":"n1","body":{"type":"sync","msg_id":3,"messages":[1,2,3]}}"#,
+        r#"  {"src":"n2","dest":"n1","body":{"type":"sync_ok","in_reply_to":3,"messages":[]}}  "#,
+        r#"{"src":"c1","dest":"n1","body":{"type":"topology","msg_id":1,"topology":{"n1":["n2"]}}}"#,
+    ] {
+        let expected = serde_json::from_str::<Envelope<Payload>>(line).unwrap();
+        let parsed = io::parse_line_with::<Envelope<Payload>>(line.as_bytes(), &mut scratch).unwrap();
+        assert_eq!(serde_json::to_value(parsed).unwrap(), serde_json::to_value(expected).unwrap());
+    }
+    let line = br#"{"src":"c1","dest":"n1","body":{"type":"sync","msg_id":3,"messages":"all"}}"#;
+    let err = io::parse_line::<Envelope<Payload>>(line).unwrap_err();
+    assert_eq!(err.to_string(), serde_json::from_slice::<Envelope<Payload>>(line).unwrap_err().to_string());
+}
+
+#[tokio::test]
+async fn replies_are_written_ahead_of_background_traffic() {
+    let (input, output) = (tempfile::NamedTempFile::new().unwrap(), tempfile::NamedTempFile::new().unwrap());
+    io::read_from(input.path()).unwrap();
+    io::write_to(output.path()).unwrap();
+    let (writer, background, _inbox, _) = io::io_channel_with_background::<Envelope<Payload>>();
+    let message = |dest: &str, message| Envelope::new("n1", dest, Body { msg_id: None, in_reply_to: Some(1), message });
+    for dest in ["n2", "n3", "n4"] {
+        background.send(message(dest, Payload::Sync { messages: (0..1000).collect(), updates: vec![] })).unwrap();
+    }
+    writer.send(message("c1", Payload::BroadcastOk)).unwrap();
+    drop(background);
+
+    let mut written = vec![];
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        written = std::fs::read_to_string(output.path()).unwrap().lines().map(|line| serde_json::from_str::<Value>(line).unwrap()["dest"].clone()).collect();
+        if written.len() == 4 {
+            break;
+        }
+    }
+    assert_eq!(written, ["c1", "n2", "n3", "n4"]);
+}
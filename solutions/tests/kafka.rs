@@ -0,0 +1,797 @@
+//! The kafka-style log: polls a batch at a time, committed offsets kept apart per client
+//! (and across restarts, with a data directory), and the same log kept on several nodes
+//! each of the ways `--partition` and `--offsets` allow.
+
+mod common;
+
+use std::{collections::{HashMap, HashSet, VecDeque}, io::{BufRead, BufReader, Write}, process::{Command, Stdio}, sync::{Arc, Mutex}, time::Duration};
+use solutions::{anti_entropy, clock::ManualClock, lease::FencingToken, log::ReadLimits, message::{Body, Envelope}, metrics, routing::Routed, rpc::RpcClient, scheduler::Scheduler, workloads::kafka::{self, Acks, Forwarder, Offsets, Payload, Shared, State}};
+use tokio::{sync::mpsc, time::Instant};
+use common::{answer, message_id};
+
+
+fn from(client: &str, message: Payload) -> Envelope<Payload> {
+    Envelope::new(client, "n1", Body { msg_id: Some(1), in_reply_to: None, message })
+}
+
+fn offsets(committed: &[(&str, usize)]) -> HashMap<String, usize> {
+    committed.iter().map(|(key, offset)| (key.to_string(), *offset)).collect()
+}
+
+/// What `client` is told it's committed for `keys`.
+async fn listed(state: &Arc<Mutex<State>>, client: &str, keys: &[&str]) -> HashMap<String, usize> {
+    let (writer, mut replies) = mpsc::unbounded_channel();
+    let keys = keys.iter().map(|key| key.to_string()).collect();
+    kafka::handle_envelope(state.clone(), from(client, Payload::ListCommittedOffsets { keys }), writer).await;
+    match replies.try_recv().unwrap().body.message {
+        Payload::ListCommittedOffsetsOk { offsets } => offsets,
+        other => panic!("expected committed offsets, got {other:?}"),
+    }
+}
+
+async fn commit(state: &Arc<Mutex<State>>, client: &str, committed: &[(&str, usize)]) {
+    let (writer, mut replies) = mpsc::unbounded_channel();
+    kafka::handle_envelope(state.clone(), from(client, Payload::CommitOffsets { offsets: offsets(committed) }), writer).await;
+    assert!(matches!(replies.try_recv().unwrap().body.message, Payload::CommitOffsetsOk));
+}
+
+#[tokio::test]
+async fn clients_commits_dont_clobber_each_other() {
+    let state = Arc::new(Mutex::new(State::new(16)));
+    commit(&state, "c1", &[("k1", 5), ("k2", 3)]).await;
+    commit(&state, "c2", &[("k1", 2)]).await;
+    // Offsets only ever move forwards.
+    commit(&state, "c1", &[("k1", 4)]).await;
+
+    assert_eq!(listed(&state, "c1", &["k1", "k2", "k3"]).await, offsets(&[("k1", 5), ("k2", 3)]));
+    assert_eq!(listed(&state, "c2", &["k1", "k2"]).await, offsets(&[("k1", 2)]));
+    assert!(listed(&state, "c3", &["k1"]).await.is_empty());
+}
+
+#[tokio::test]
+async fn committed_offsets_survive_a_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = Arc::new(Mutex::new(State::new(16).with_storage(dir.path()).unwrap()));
+    commit(&state, "c1", &[("k1", 5)]).await;
+    commit(&state, "c2", &[("k1", 7)]).await;
+    drop(state);
+
+    let restarted = Arc::new(Mutex::new(State::new(16).with_storage(dir.path()).unwrap()));
+    assert_eq!(listed(&restarted, "c1", &["k1"]).await, offsets(&[("k1", 5)]));
+    assert_eq!(listed(&restarted, "c2", &["k1"]).await, offsets(&[("k1", 7)]));
+}
+
+#[test]
+fn retried_sends_get_the_offset_the_first_try_did() {
+    let mut state = State::new(16);
+    let send = |msg_id, msg, token: Option<&str>| {
+        let message = Payload::Send { key: "k1".to_owned(), msg, sub_key: None, token: token.map(str::to_owned) };
+        Envelope::new("c1", "n1", Body { msg_id: Some(msg_id), in_reply_to: None, message })
+    };
+    let mut offset_of = |envelope| match kafka::reply(&mut state, &envelope) {
+        Some(Payload::SendOk { offset }) => offset,
+        other => panic!("expected a send_ok, got {other:?}"),
+    };
+    assert_eq!(offset_of(send(1, 10, Some("t1"))), 0);
+    // Retried under a new msg_id, as Maelstrom's clients do.
+    assert_eq!(offset_of(send(2, 10, Some("t1"))), 0);
+    // Without a token, only the same msg_id (a duplicate delivery) is the same send.
+    assert_eq!(offset_of(send(3, 11, None)), 1);
+    assert_eq!(offset_of(send(3, 11, None)), 1);
+    assert_eq!(offset_of(send(4, 11, None)), 2);
+}
+
+#[tokio::test]
+async fn polls_hand_back_a_batch_at_a_time_from_where_they_asked() {
+    let state = Arc::new(Mutex::new(State::new(4).with_poll_limits(ReadLimits { max_records: Some(4), max_bytes: Some(20) })));
+    let (writer, mut replies) = mpsc::unbounded_channel();
+    for msg in 0..10 {
+        // Each its own send, not retries of the same one.
+        let send = Payload::Send { key: "k1".to_owned(), msg: msg * 1000, sub_key: None, token: None };
+        kafka::handle_envelope(state.clone(), Envelope::new("c1", "n1", Body { msg_id: Some(msg + 1), in_reply_to: None, message: send }), writer.clone()).await;
+    }
+    while replies.try_recv().is_ok() {}
+
+    let mut polled = vec![];
+    let mut next = 0;
+    while next < 10 {
+        kafka::handle_envelope(state.clone(), from("c1", Payload::Poll { offsets: offsets(&[("k1", next)]) }), writer.clone()).await;
+        let Payload::PollOk { msgs } = replies.try_recv().unwrap().body.message else {
+            panic!("expected a poll_ok");
+        };
+        let batch = &msgs["k1"];
+        // `[1,1000],` and the like are 9 bytes each, so only 2 fit in 20.
+        assert_eq!(batch.len(), 2, "{batch:?}");
+        next = batch.last().unwrap().0 + 1;
+        polled.extend(batch.iter().map(|&(_, msg)| msg));
+    }
+    assert_eq!(polled, (0..10).map(|msg| msg * 1000).collect::<Vec<_>>());
+}
+
+#[test]
+fn every_key_reports_its_rates_and_how_far_behind_its_consumers_are() {
+    let mut state = State::new(16);
+    for msg in 0..6 {
+        let send = Payload::Send { key: "stats1".to_owned(), msg, sub_key: None, token: None };
+        kafka::reply(&mut state, &Envelope::new("c1", "n1", Body { msg_id: Some(msg + 1), in_reply_to: None, message: send }));
+    }
+    for _ in 0..2 {
+        kafka::reply(&mut state, &from("c1", Payload::Poll { offsets: offsets(&[("stats1", 0), ("stats2", 0)]) }));
+    }
+    kafka::reply(&mut state, &from("c1", Payload::CommitOffsets { offsets: offsets(&[("stats1", 3)]) }));
+    kafka::reply(&mut state, &from("c2", Payload::CommitOffsets { offsets: offsets(&[("stats1", 1)]) }));
+    state.record_key_stats(Duration::from_secs(2));
+
+    let snapshot = metrics::global().snapshot();
+    assert_eq!(snapshot.counter("kafka.key.stats1.appends"), 6);
+    assert_eq!(snapshot.gauge("kafka.key.stats1.appends_per_sec"), Some(3));
+    assert_eq!(snapshot.gauge("kafka.key.stats1.polls_per_sec"), Some(1));
+    assert_eq!(snapshot.gauge("kafka.key.stats2.polls_per_sec"), Some(1));
+    // c2's furthest behind: offsets 2 through 5 are past what it's committed.
+    assert_eq!(snapshot.gauge("kafka.key.stats1.lag"), Some(4));
+
+    // Quiet keys drop back to nothing, and ones this node doesn't have are forgotten.
+    state.record_key_stats(Duration::from_secs(1));
+    let snapshot = metrics::global().snapshot();
+    assert_eq!(snapshot.gauge("kafka.key.stats1.appends_per_sec"), Some(0));
+    assert_eq!(snapshot.counter("kafka.key.stats1.appends"), 6);
+    assert_eq!(snapshot.gauge("kafka.key.stats2.polls_per_sec"), None);
+}
+
+#[test]
+fn only_the_busiest_keys_get_metrics_of_their_own() {
+    let mut state = State::new(16);
+    let mut msg_id = 0;
+    let mut send = |state: &mut State, key: String| {
+        msg_id += 1;
+        let send = Payload::Send { key, msg: 0, sub_key: None, token: None };
+        kafka::reply(state, &Envelope::new("c1", "n1", Body { msg_id: Some(msg_id), in_reply_to: None, message: send }));
+    };
+    for key in 0..kafka::REPORTED_KEYS {
+        send(&mut state, format!("many{key}"));
+    }
+    state.record_key_stats(Duration::from_secs(1));
+    // A busier key pushes the quietest out.
+    for _ in 0..2 {
+        send(&mut state, "busy".to_owned());
+    }
+    state.record_key_stats(Duration::from_secs(1));
+
+    let snapshot = metrics::global().snapshot();
+    assert_eq!(snapshot.counter("kafka.key.busy.appends"), 2);
+    let reported = snapshot.to_json()["gauges"].as_object().unwrap().keys()
+        .filter(|name| name.starts_with("kafka.key.many") && name.ends_with(".lag"))
+        .count();
+    assert_eq!(reported, kafka::REPORTED_KEYS - 1);
+}
+
+
+/// Nodes with `--partition`, each with its own forwarder, passing messages between them
+/// until only messages for clients are left.
+struct Cluster {
+    nodes: HashMap<String, (State, Forwarder)>,
+    /// Whether handoffs are held back (in `parked`) rather than delivered.
+    parking: bool,
+    parked: Vec<Envelope<Routed<Payload>>>,
+}
+
+impl Cluster {
+    fn new() -> Self {
+        Self::with(3, |state| state)
+    }
+
+    /// `n` nodes, each set up by `setup`.
+    fn with(n: usize, setup: impl Fn(State) -> State) -> Self {
+        let node_ids: Vec<String> = (1..=n).map(|i| format!("n{i}")).collect();
+        let mut cluster = Self { nodes: HashMap::new(), parking: false, parked: vec![] };
+        for node_id in &node_ids {
+            cluster.start(node_id, &node_ids, &setup);
+        }
+        cluster
+    }
+
+    fn start(&mut self, node_id: &str, node_ids: &[String], setup: impl Fn(State) -> State) {
+        let mut node = (setup(State::new(16).with_partitioning()), Forwarder::default());
+        let init = Payload::Init { node_id: node_id.to_owned(), node_ids: node_ids.to_vec() };
+        node.1.dispatch(&mut node.0, Envelope::new("c0", node_id, Body { msg_id: Some(1), in_reply_to: None, message: init.into() }), Instant::now());
+        self.nodes.insert(node_id.to_owned(), node);
+    }
+
+    /// Deliver `in_flight`, and whatever's sent on account of it, handing back what's
+    /// for clients.
+    fn run(&mut self, mut in_flight: VecDeque<Envelope<Routed<Payload>>>) -> Vec<Envelope<Routed<Payload>>> {
+        let mut replies = vec![];
+        while let Some(envelope) = in_flight.pop_front() {
+            if self.parking && matches!(envelope.body.message.message, Payload::HandOff { .. }) {
+                self.parked.push(envelope);
+                continue;
+            }
+            let Some((state, forwarder)) = self.nodes.get_mut(&*envelope.destination) else {
+                replies.push(envelope);
+                continue;
+            };
+            in_flight.extend(forwarder.dispatch(state, envelope, Instant::now()));
+        }
+        replies
+    }
+
+    /// Every node's rebalance, delivered.
+    fn rebalance(&mut self) {
+        let handoffs: VecDeque<_> = self.nodes.values_mut().flat_map(|(state, forwarder)| forwarder.rebalance(state)).collect();
+        self.run(handoffs);
+    }
+
+    /// What `client` is told when it sends `message` to `node`.
+    fn request(&mut self, client: &str, node: &str, msg_id: usize, message: Payload) -> Payload {
+        let request = Envelope::new(client, node, Body { msg_id: Some(msg_id), in_reply_to: None, message: Routed::from(message) });
+        let mut replies = self.run(VecDeque::from([request]));
+        assert_eq!(replies.len(), 1, "{replies:?}");
+        let reply = replies.pop().unwrap();
+        assert_eq!(reply.body.in_reply_to, Some(msg_id));
+        assert!(reply.body.message.route.is_none());
+        reply.body.message.message
+    }
+
+    /// Every node told `message`, by an admin.
+    fn announce(&mut self, message: Payload) {
+        let announcements = self.nodes.keys().map(|node| Envelope::new("admin", node, Body { msg_id: Some(1), in_reply_to: None, message: Routed::from(message.clone()) })).collect();
+        self.run(announcements);
+    }
+}
+
+/// Sends `msg` to `key` through `node`, keeping track of what was acknowledged in `sent`;
+/// a send turned away while its key's being handed over isn't.
+fn send_tracked(cluster: &mut Cluster, sent: &mut HashMap<String, Vec<usize>>, node: &str, key: &str, msg: usize) {
+    match cluster.request("c1", node, msg, Payload::Send { key: key.to_owned(), msg, sub_key: None, token: None }) {
+        Payload::SendOk { offset } => {
+            let acknowledged = sent.entry(key.to_owned()).or_default();
+            // Whoever has the key now carries on from where it left off.
+            assert_eq!(offset, acknowledged.len(), "{key} through {node}");
+            acknowledged.push(msg);
+        },
+        Payload::Error { code: 11, .. } => {},
+        other => panic!("expected a send_ok, got {other:?}"),
+    }
+}
+
+/// Every key's log, as polled through `node`, matches what was acknowledged.
+fn assert_logs(cluster: &mut Cluster, node: &str, sent: &HashMap<String, Vec<usize>>) {
+    let everything = sent.keys().map(|key| (key.clone(), 0)).collect();
+    let Payload::PollOk { msgs } = cluster.request("c1", node, usize::MAX, Payload::Poll { offsets: everything }) else {
+        panic!("expected a poll_ok");
+    };
+    for (key, acknowledged) in sent {
+        let polled: Vec<usize> = msgs[key].iter().map(|&(_, msg)| msg).collect();
+        assert_eq!(polled, *acknowledged, "{key} through {node}");
+    }
+}
+
+#[test]
+fn any_node_can_be_asked_about_any_key() {
+    let mut cluster = Cluster::new();
+    let keys: Vec<String> = (0..12).map(|key| format!("k{key}")).collect();
+    let mut msg_id = 0;
+    for round in 0..3 {
+        for (i, key) in keys.iter().enumerate() {
+            msg_id += 1;
+            let node = format!("n{}", (i + round) % 3 + 1);
+            let Payload::SendOk { offset } = cluster.request("c1", &node, msg_id, Payload::Send { key: key.clone(), msg: round, sub_key: None, token: None }) else {
+                panic!("expected a send_ok");
+            };
+            // Every key's offsets come from its one owner, however it's reached.
+            assert_eq!(offset, round);
+        }
+    }
+    let owners: HashSet<_> = keys.iter().filter_map(|key| cluster.nodes["n1"].0.owner(key)).collect();
+    assert!(owners.len() > 1, "keys should be spread out, not all owned by one node");
+
+    let everything: HashMap<String, usize> = keys.iter().map(|key| (key.clone(), 0)).collect();
+    let Payload::PollOk { msgs } = cluster.request("c1", "n2", 100, Payload::Poll { offsets: everything.clone() }) else {
+        panic!("expected a poll_ok");
+    };
+    assert_eq!(msgs.len(), keys.len());
+    assert!(msgs.values().all(|msgs| *msgs == vec![(0, 0), (1, 1), (2, 2)]), "{msgs:?}");
+
+    assert!(matches!(cluster.request("c1", "n3", 101, Payload::CommitOffsets { offsets: everything.clone() }), Payload::CommitOffsetsOk));
+    let Payload::ListCommittedOffsetsOk { offsets } = cluster.request("c1", "n1", 102, Payload::ListCommittedOffsets { keys }) else {
+        panic!("expected committed offsets");
+    };
+    assert_eq!(offsets, everything);
+}
+
+
+/// Nodes passing messages to one another, and to lin-kv: clients' requests go in
+/// through `requests`, and their replies come out of `replies`.
+struct SharedCluster {
+    requests: mpsc::UnboundedSender<Envelope<Routed<Payload>>>,
+    replies: mpsc::UnboundedReceiver<Envelope<Routed<Payload>>>,
+    states: HashMap<String, Arc<Mutex<State>>>,
+    /// Nodes that have crashed: nothing gets to or from them.
+    dead: Arc<Mutex<HashSet<String>>>,
+}
+
+impl SharedCluster {
+    /// `node` crashes, and every other node notices.
+    fn kill(&mut self, node: &str) {
+        self.dead.lock().unwrap().insert(node.to_owned());
+        for state in self.states.values() {
+            state.lock().unwrap().set_down(node, true);
+        }
+    }
+}
+
+/// Three nodes with `--offsets offsets`, and lin-kv.
+async fn shared_cluster(offsets: Offsets) -> SharedCluster {
+    shared_cluster_with(offsets, 3, Acks::All, |state| state).await
+}
+
+/// `n` nodes with `--offsets offsets` and `--acks acks`, each set up by `setup`, and
+/// lin-kv.
+async fn shared_cluster_with(offsets: Offsets, n: usize, acks: Acks, setup: impl Fn(State) -> State) -> SharedCluster {
+    let (network, mut sent) = mpsc::unbounded_channel::<Envelope<Routed<Payload>>>();
+    let (to_clients, mut replies) = mpsc::unbounded_channel();
+    let node_ids: Vec<String> = (1..=n).map(|i| format!("n{i}")).collect();
+    let dead = Arc::new(Mutex::new(HashSet::<String>::new()));
+    let mut nodes = HashMap::new();
+    let mut states = HashMap::new();
+    for node_id in &node_ids {
+        let rpc = RpcClient::new(network.clone(), message_id);
+        rpc.set_node_id(node_id);
+        let state = Arc::new(Mutex::new(setup(State::new(16).with_partitioning())));
+        states.insert(node_id.clone(), state.clone());
+        nodes.insert(node_id.clone(), (Shared::new(offsets, state, rpc.clone(), Duration::from_secs(1)).with_acks(acks), rpc));
+    }
+    let (writer, crashed) = (network.clone(), dead.clone());
+    tokio::spawn(async move {
+        let mut lin_kv = HashMap::new();
+        while let Some(envelope) = sent.recv().await {
+            if [&envelope.source, &envelope.destination].iter().any(|node| crashed.lock().unwrap().contains(&***node)) {
+                continue;
+            }
+            if &*envelope.destination == "lin-kv" {
+                let reply = answer(&mut lin_kv, &serde_json::to_value(&envelope.body.message).unwrap());
+                let reply = Envelope::new("lin-kv", &envelope.source, Body { msg_id: None, in_reply_to: envelope.msg_id(), message: serde_json::from_value(reply).unwrap() });
+                network.send(reply).unwrap();
+                continue;
+            }
+            let Some((shared, rpc)) = nodes.get(&*envelope.destination) else {
+                to_clients.send(envelope).unwrap();
+                continue;
+            };
+            if let Some(envelope) = rpc.try_complete(envelope) {
+                let envelope = envelope.with_message(envelope.body.message.message.clone());
+                tokio::spawn(kafka::handle_shared(shared.clone(), envelope, network.clone()));
+            }
+        }
+    });
+    for node_id in &node_ids {
+        let init = Payload::Init { node_id: node_id.clone(), node_ids: node_ids.clone() };
+        writer.send(Envelope::new("c0", node_id, Body { msg_id: Some(1), in_reply_to: None, message: init.into() })).unwrap();
+    }
+    for _ in &node_ids {
+        replies.recv().await.unwrap();
+    }
+    SharedCluster { requests: writer, replies, states, dead }
+}
+
+/// What `client` is told when it sends `message` to `node` in a [`shared_cluster`].
+async fn ask(cluster: &mut SharedCluster, client: &str, node: &str, message: Payload) -> Payload {
+    let msg_id = message_id();
+    cluster.requests.send(Envelope::new(client, node, Body { msg_id: Some(msg_id), in_reply_to: None, message: message.into() })).unwrap();
+    let reply = cluster.replies.recv().await.unwrap();
+    assert_eq!(reply.body.in_reply_to, Some(msg_id));
+    reply.body.message.message
+}
+
+#[tokio::test]
+async fn offsets_in_lin_kv_or_replicated_agree_whichever_node_is_asked() {
+    for offsets in [Offsets::LinKv, Offsets::Replicated] {
+        let mut cluster = shared_cluster(offsets).await;
+        let keys: Vec<String> = (0..4).map(|key| format!("k{key}")).collect();
+        for round in 0..3 {
+            for (i, key) in keys.iter().enumerate() {
+                let node = format!("n{}", (i + round) % 3 + 1);
+                let Payload::SendOk { offset } = ask(&mut cluster, "c1", &node, Payload::Send { key: key.clone(), msg: round * 10, sub_key: None, token: None }).await else {
+                    panic!("{offsets:?}: expected a send_ok");
+                };
+                assert_eq!(offset, round, "{offsets:?}");
+            }
+        }
+
+        // A retry through another node, with the same token, isn't appended again.
+        let retried = Payload::Send { key: "k0".to_owned(), msg: 30, sub_key: None, token: Some("c1-t1".to_owned()) };
+        let first = ask(&mut cluster, "c1", "n1", retried.clone()).await;
+        let again = ask(&mut cluster, "c1", "n2", retried).await;
+        assert!(matches!((first, again), (Payload::SendOk { offset: 3 }, Payload::SendOk { offset: 3 })), "{offsets:?}");
+
+        let everything: HashMap<String, usize> = keys.iter().map(|key| (key.clone(), 0)).collect();
+        for node in ["n1", "n2", "n3"] {
+            let Payload::PollOk { msgs } = ask(&mut cluster, "c1", node, Payload::Poll { offsets: everything.clone() }).await else {
+                panic!("{offsets:?}: expected a poll_ok");
+            };
+            assert_eq!(msgs["k0"], vec![(0, 0), (1, 10), (2, 20), (3, 30)], "{offsets:?}, {node}");
+            assert!(keys[1..].iter().all(|key| msgs[key] == vec![(0, 0), (1, 10), (2, 20)]), "{offsets:?}, {node}: {msgs:?}");
+        }
+
+        let committed = HashMap::from([("k0".to_owned(), 2), ("k1".to_owned(), 1)]);
+        assert!(matches!(ask(&mut cluster, "c1", "n1", Payload::CommitOffsets { offsets: committed.clone() }).await, Payload::CommitOffsetsOk));
+        let Payload::ListCommittedOffsetsOk { offsets: listed } = ask(&mut cluster, "c1", "n3", Payload::ListCommittedOffsets { keys: keys.clone() }).await else {
+            panic!("{offsets:?}: expected committed offsets");
+        };
+        assert_eq!(listed, committed, "{offsets:?}");
+    }
+}
+
+#[test]
+fn copies_of_records_that_arrive_out_of_order_wait_for_the_ones_before() {
+    let mut state = State::new(4);
+    state.replicate("k1", 1, 10, None);
+    state.replicate("k1", 2, 20, None);
+    let poll = || from("c1", Payload::Poll { offsets: offsets(&[("k1", 0)]) });
+    let Some(Payload::PollOk { msgs }) = kafka::reply(&mut state, &poll()) else {
+        panic!("expected a poll_ok");
+    };
+    assert!(msgs["k1"].is_empty());
+
+    state.replicate("k1", 0, 0, None);
+    state.replicate("k1", 1, 10, None);
+    let Some(Payload::PollOk { msgs }) = kafka::reply(&mut state, &poll()) else {
+        panic!("expected a poll_ok");
+    };
+    assert_eq!(msgs["k1"], vec![(0, 0), (1, 10), (2, 20)]);
+}
+
+#[tokio::test]
+async fn repair_copies_the_records_either_node_is_missing() {
+    let node_ids = vec!["n1".to_owned(), "n2".to_owned()];
+    let nodes: Vec<Mutex<State>> = ["n1", "n2"].into_iter().map(|node_id| {
+        let mut state = State::new(4).with_partitioning();
+        kafka::reply(&mut state, &from("c0", Payload::Init { node_id: node_id.to_owned(), node_ids: node_ids.clone() }));
+        Mutex::new(state)
+    }).collect();
+    for offset in 0..6 {
+        nodes[0].lock().unwrap().replicate("k1", offset, offset * 10, None);
+    }
+    for offset in 0..2 {
+        nodes[1].lock().unwrap().replicate("k1", offset, offset * 10, None);
+        nodes[1].lock().unwrap().replicate("k2", offset, offset, None);
+    }
+
+    let call = |message| std::future::ready(anti_entropy::answer(&mut *nodes[1].lock().unwrap(), "n1", message));
+    assert!(anti_entropy::repair(&nodes[0], "n2", call).await);
+    for node in &nodes {
+        let poll = from("c1", Payload::Poll { offsets: offsets(&[("k1", 0), ("k2", 0)]) });
+        let Some(Payload::PollOk { msgs }) = kafka::reply(&mut node.lock().unwrap(), &poll) else {
+            panic!("expected a poll_ok");
+        };
+        assert_eq!(msgs["k1"], (0..6).map(|offset| (offset, offset * 10)).collect::<Vec<_>>());
+        assert_eq!(msgs["k2"], vec![(0, 0), (1, 1)]);
+    }
+}
+
+#[tokio::test]
+async fn repair_never_copies_on_what_a_deposed_leader_appended() {
+    let node_ids = vec!["n1".to_owned(), "n2".to_owned()];
+    let nodes: Vec<Mutex<State>> = ["n1", "n2"].into_iter().map(|node_id| {
+        let mut state = State::new(4).with_partitioning();
+        kafka::reply(&mut state, &from("c0", Payload::Init { node_id: node_id.to_owned(), node_ids: node_ids.clone() }));
+        Mutex::new(state)
+    }).collect();
+    // n1 led k1 at epoch 1 and appended 2..5 without them being acknowledged; the
+    // leader at epoch 2 took over from offset 2, and n2 has its first record.
+    for offset in 0..5 {
+        nodes[0].lock().unwrap().replicate_from("k1", FencingToken(1), 0, offset, offset, None);
+    }
+    for offset in 0..2 {
+        nodes[1].lock().unwrap().replicate_from("k1", FencingToken(1), 0, offset, offset, None);
+    }
+    nodes[1].lock().unwrap().replicate_from("k1", FencingToken(2), 2, 2, 20, None);
+
+    let call = |message| std::future::ready(anti_entropy::answer(&mut *nodes[1].lock().unwrap(), "n1", message));
+    assert!(anti_entropy::repair(&nodes[0], "n2", call).await);
+    // The new leader's next record is taken in everywhere, not mistaken for one that's
+    // there already.
+    for node in &nodes {
+        node.lock().unwrap().replicate_from("k1", FencingToken(2), 2, 3, 30, None);
+        let poll = from("c1", Payload::Poll { offsets: offsets(&[("k1", 0)]) });
+        let Some(Payload::PollOk { msgs }) = kafka::reply(&mut node.lock().unwrap(), &poll) else {
+            panic!("expected a poll_ok");
+        };
+        assert_eq!(msgs["k1"], vec![(0, 0), (1, 1), (2, 20), (3, 30)]);
+    }
+}
+
+#[test]
+fn keys_are_handed_over_when_a_node_joins_without_offsets_going_stale() {
+    let mut cluster = Cluster::new();
+    let keys: Vec<String> = (0..12).map(|key| format!("k{key}")).collect();
+    let mut sent = HashMap::new();
+    let mut msg = 0;
+    let mut send_everywhere = |cluster: &mut Cluster, sent: &mut HashMap<String, Vec<usize>>| {
+        let nodes: Vec<String> = cluster.nodes.keys().cloned().collect();
+        for node in &nodes {
+            for key in &keys {
+                msg += 1;
+                send_tracked(cluster, sent, node, key, msg);
+            }
+        }
+    };
+    send_everywhere(&mut cluster, &mut sent);
+
+    // n4 joins, and the handoffs to it are held up: sends for the keys going its way are
+    // turned away until they get there, rather than taking offsets it won't know about.
+    let node_ids: Vec<String> = (1..=4).map(|i| format!("n{i}")).collect();
+    cluster.start("n4", &node_ids, |state| state);
+    cluster.parking = true;
+    cluster.announce(Payload::Join { node_id: "n4".to_owned() });
+    let moving = cluster.parked.len();
+    assert!(moving > 0, "some keys should move to n4");
+    send_everywhere(&mut cluster, &mut sent);
+
+    cluster.parking = false;
+    let parked = std::mem::take(&mut cluster.parked);
+    cluster.run(parked.into());
+    cluster.rebalance();
+    send_everywhere(&mut cluster, &mut sent);
+    assert!(keys.iter().any(|key| cluster.nodes["n4"].0.owner(key).is_none()), "n4 should have some keys now");
+    for node in ["n1", "n4"] {
+        assert_logs(&mut cluster, node, &sent);
+    }
+}
+
+#[test]
+fn hot_keys_are_spread_out() {
+    let mut cluster = Cluster::with(3, |state| state.with_hot_keys(5));
+    let owner_of = |cluster: &Cluster, key: &str| cluster.nodes["n1"].0.ring_owner(key).map_or("n1".to_owned(), |owner| owner.to_string());
+    // Two keys with the same owner.
+    let keys: Vec<String> = (0..).map(|key| format!("k{key}")).take(20).collect();
+    let (hot1, hot2) = keys.iter().flat_map(|a| keys.iter().map(move |b| (a, b))).find(|(a, b)| a != b && owner_of(&cluster, a) == owner_of(&cluster, b)).unwrap();
+    let owner = owner_of(&cluster, hot1);
+
+    let mut sent = HashMap::new();
+    for msg in 0..10 {
+        send_tracked(&mut cluster, &mut sent, "n1", hot1, 2 * msg);
+        send_tracked(&mut cluster, &mut sent, "n2", hot2, 2 * msg + 1);
+    }
+    cluster.rebalance();
+    let holder = |cluster: &Cluster, key: &str| cluster.nodes.iter().find(|(_, (state, _))| state.owner(key).is_none()).map(|(node, _)| node.clone()).unwrap();
+    assert_ne!(holder(&cluster, hot1), holder(&cluster, hot2), "one of them should have moved off {owner}");
+    assert!([hot1, hot2].iter().any(|key| holder(&cluster, key) == owner));
+
+    // And they stay spread out, with sends carrying on where they left off wherever
+    // they're sent.
+    for msg in 10..20 {
+        send_tracked(&mut cluster, &mut sent, "n3", hot1, 2 * msg);
+        send_tracked(&mut cluster, &mut sent, "n3", hot2, 2 * msg + 1);
+    }
+    cluster.rebalance();
+    for node in ["n1", "n2", "n3"] {
+        assert_logs(&mut cluster, node, &sent);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn sends_are_turned_away_while_too_many_to_the_same_key_are_waiting() {
+    // Nobody answers lin-kv, so sends wait on it until they time out.
+    let (network, _unanswered) = mpsc::unbounded_channel();
+    let rpc = RpcClient::new(network, message_id);
+    rpc.set_node_id("n1");
+    let state = Arc::new(Mutex::new(State::new(16).with_max_pending_sends(2)));
+    let shared = Shared::new(Offsets::LinKv, state, rpc, Duration::from_secs(1));
+    let (writer, mut replies) = mpsc::unbounded_channel();
+    let send = |key: &str| {
+        let message = Payload::Send { key: key.to_owned(), msg: 1, sub_key: None, token: None };
+        kafka::handle_shared(shared.clone(), Envelope::new("c1", "n1", Body { msg_id: Some(message_id()), in_reply_to: None, message }), writer.clone())
+    };
+    let waiting = [tokio::spawn(send("k1")), tokio::spawn(send("k1")), tokio::spawn(send("k2"))];
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(replies.try_recv().is_err());
+
+    send("k1").await;
+    assert!(matches!(replies.try_recv().unwrap().body.message.message, Payload::Error { code: 11, .. }));
+
+    // Once they've given up, there's room again.
+    for waiting in waiting {
+        waiting.await.unwrap();
+    }
+    while replies.try_recv().is_ok() {}
+    let again = tokio::spawn(send("k1"));
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(replies.try_recv().is_err());
+    again.abort();
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_send_that_never_moves_the_counter_leaves_no_hole_behind() {
+    // lin-kv takes the first CAS on k1's counter, but its reply is lost.
+    let (network, mut sent) = mpsc::unbounded_channel::<Envelope<Routed<Payload>>>();
+    let rpc = RpcClient::new(network, message_id);
+    rpc.set_node_id("n1");
+    let service = rpc.clone();
+    tokio::spawn(async move {
+        let mut lin_kv = HashMap::new();
+        let mut lost = false;
+        while let Some(envelope) = sent.recv().await {
+            let request = serde_json::to_value(&envelope.body.message).unwrap();
+            let reply = answer(&mut lin_kv, &request);
+            if !lost && request["type"] == "cas" && request["key"] == "next/k1" {
+                lost = true;
+                continue;
+            }
+            service.try_complete(Envelope::new("lin-kv", "n1", Body { msg_id: None, in_reply_to: envelope.msg_id(), message: serde_json::from_value(reply).unwrap() }));
+        }
+    });
+    let shared = Shared::new(Offsets::LinKv, Arc::new(Mutex::new(State::new(16))), rpc, Duration::from_secs(1));
+    let (writer, mut replies) = mpsc::unbounded_channel();
+    let ask = |message: Payload| {
+        let (shared, writer) = (shared.clone(), writer.clone());
+        async move {
+            kafka::handle_shared(shared, Envelope::new("c1", "n1", Body { msg_id: Some(message_id()), in_reply_to: None, message }), writer).await;
+        }
+    };
+    let send = |msg| Payload::Send { key: "k1".to_owned(), msg, sub_key: None, token: None };
+
+    for msg in [10, 11] {
+        ask(send(msg)).await;
+    }
+    ask(Payload::Poll { offsets: offsets(&[("k1", 0)]) }).await;
+    let replies: Vec<Payload> = std::iter::from_fn(|| replies.try_recv().ok()).map(|reply| reply.body.message.message).collect();
+    assert!(matches!(replies[..2], [Payload::SendOk { offset: 0 }, Payload::SendOk { offset: 1 }]), "{replies:?}");
+    let Payload::PollOk { msgs } = &replies[2] else {
+        panic!("expected a poll_ok, got {:?}", replies[2]);
+    };
+    assert_eq!(msgs["k1"], vec![(0, 10), (1, 11)]);
+}
+
+#[tokio::test]
+async fn a_follower_takes_over_when_a_keys_leader_dies_without_losing_acknowledged_sends() {
+    let mut cluster = shared_cluster_with(Offsets::Replicated, 4, Acks::Quorum, |state| state.with_followers(2)).await;
+    let keys: Vec<String> = (0..4).map(|key| format!("k{key}")).collect();
+    let mut sent: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+    for round in 0..3 {
+        for (i, key) in keys.iter().enumerate() {
+            let node = format!("n{}", (i + round) % 4 + 1);
+            let Payload::SendOk { offset } = ask(&mut cluster, "c1", &node, Payload::Send { key: key.clone(), msg: round, sub_key: None, token: None }).await else {
+                panic!("expected a send_ok");
+            };
+            sent.entry(key.clone()).or_default().push((offset, round));
+        }
+    }
+    let replicas = cluster.states["n1"].lock().unwrap().replicas("k0");
+    assert_eq!(replicas.len(), 3, "k0 should be on its owner and 2 followers");
+    let leader = replicas[0].to_string();
+    cluster.kill(&leader);
+
+    let alive: Vec<String> = (1..=4).map(|i| format!("n{i}")).filter(|node| *node != leader).collect();
+    for (round, node) in (3..).zip(&alive) {
+        // Whoever's taken over carries on from where the old leader left off.
+        let reply = ask(&mut cluster, "c1", node, Payload::Send { key: "k0".to_owned(), msg: round, sub_key: None, token: None }).await;
+        assert!(matches!(reply, Payload::SendOk { offset } if offset == round), "{reply:?}");
+        sent.get_mut("k0").unwrap().push((round, round));
+    }
+    let everything: HashMap<String, usize> = keys.iter().map(|key| (key.clone(), 0)).collect();
+    let Payload::PollOk { msgs } = ask(&mut cluster, "c1", &alive[0], Payload::Poll { offsets: everything }).await else {
+        panic!("expected a poll_ok");
+    };
+    assert_eq!(msgs, sent);
+}
+
+#[tokio::test]
+async fn a_cut_off_leader_is_fenced_rather_than_handing_out_an_offset_again() {
+    let mut cluster = shared_cluster_with(Offsets::Replicated, 3, Acks::Quorum, |state| state.with_followers(2)).await;
+    let replicas = cluster.states["n1"].lock().unwrap().replicas("k0");
+    let (leader, follower) = (replicas[0].to_string(), replicas[1].to_string());
+    let send = |msg| Payload::Send { key: "k0".to_owned(), msg, sub_key: None, token: None };
+    assert!(matches!(ask(&mut cluster, "c1", &leader, send(0)).await, Payload::SendOk { offset: 0 }));
+
+    // The others take the leader for dead, and one of them takes the key over, while
+    // the old leader hears nothing of it.
+    cluster.dead.lock().unwrap().insert(leader.clone());
+    for node in &replicas[1..] {
+        cluster.states[&**node].lock().unwrap().set_down(&leader, true);
+    }
+    assert!(matches!(ask(&mut cluster, "c1", &follower, send(1)).await, Payload::SendOk { offset: 1 }));
+    cluster.dead.lock().unwrap().remove(&leader);
+
+    let reply = ask(&mut cluster, "c1", &leader, send(2)).await;
+    assert!(matches!(reply, Payload::Error { .. }), "{reply:?}");
+    assert_eq!(cluster.states[&leader].lock().unwrap().epoch("k0"), FencingToken(2));
+}
+
+#[test]
+fn replicas_turn_away_records_and_takeovers_from_older_epochs() {
+    let mut state = State::new(4);
+    let catch_up = |epoch| from("n2", Payload::CatchUp { key: "k1".to_owned(), from: 0, epoch: Some(FencingToken(epoch)) });
+    let replicate = |epoch, offset| from("n3", Payload::Replicate { key: "k1".to_owned(), offset, msg: offset, sub_key: None, epoch: FencingToken(epoch), since: 0 });
+    assert!(matches!(kafka::reply(&mut state, &replicate(1, 0)), Some(Payload::ReplicateOk)));
+    assert!(matches!(kafka::reply(&mut state, &catch_up(2)), Some(Payload::CatchUpOk { .. })));
+
+    // Neither the old leader nor a second takeover at the same epoch get anywhere.
+    for turned_away in [replicate(1, 1), catch_up(2)] {
+        let reply = kafka::reply(&mut state, &turned_away);
+        assert!(matches!(reply, Some(Payload::Fenced { epoch: FencingToken(2), .. })), "{reply:?}");
+    }
+    assert!(matches!(kafka::reply(&mut state, &replicate(2, 1)), Some(Payload::ReplicateOk)));
+}
+
+#[test]
+fn a_newer_leaders_records_replace_what_a_deposed_one_appended() {
+    let mut state = State::new(4);
+    for offset in 0..3 {
+        state.replicate_from("k1", FencingToken(1), 0, offset, offset, None);
+    }
+    // The new leader took over with offsets 0 and 1: 2 was never acknowledged.
+    state.replicate_from("k1", FencingToken(2), 2, 3, 30, None);
+    state.replicate_from("k1", FencingToken(2), 2, 2, 20, None);
+    let Some(Payload::PollOk { msgs }) = kafka::reply(&mut state, &from("c1", Payload::Poll { offsets: offsets(&[("k1", 0)]) })) else {
+        panic!("expected a poll_ok");
+    };
+    assert_eq!(msgs["k1"], vec![(0, 0), (1, 1), (2, 20), (3, 30)]);
+}
+
+/// What the node `binary` sends first once it's asked to append to a key, as the first
+/// of two nodes.
+fn first_sent(binary: &str, args: &[&str]) -> serde_json::Value {
+    let mut node =
+        Command::new(binary)
+        .args(args)
+        .env_remove("OFFSETS")
+        .env_remove("CONFIG")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = node.stdin.take().unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"]}}}}"#).unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "send", "msg_id": 2, "key": "k1", "msg": 10}}}}"#).unwrap();
+    let mut stdout = BufReader::new(node.stdout.take().unwrap()).lines();
+    stdout.next().unwrap().unwrap();
+    let sent = serde_json::from_str(&stdout.next().unwrap().unwrap()).unwrap();
+    node.kill().unwrap();
+    node.wait().unwrap();
+    sent
+}
+
+#[test]
+fn the_multi_node_and_efficient_binaries_keep_offsets_in_lin_kv_and_on_every_node() {
+    let multi_node = env!("CARGO_BIN_EXE_kafka_style_log_multi_node");
+    assert_eq!(first_sent(multi_node, &[])["dest"], "lin-kv");
+    let efficient = env!("CARGO_BIN_EXE_kafka_style_log_efficient");
+    // Copied to (or sent on to the leader on) the other node, rather than answered.
+    assert_eq!(first_sent(efficient, &[])["dest"], "n2");
+    // Either can still be told otherwise.
+    assert_eq!(first_sent(efficient, &["--offsets", "lin-kv"])["dest"], "lin-kv");
+}
+
+#[tokio::test]
+async fn peers_are_taken_for_down_by_the_schedulers_clock() {
+    let clock = ManualClock::new();
+    let scheduler = Scheduler::with_clock(Arc::new(clock.clone()));
+    let node_ids = vec!["n1".to_owned(), "n2".to_owned()];
+    let mut state = State::new(4).with_partitioning().with_followers(1);
+    kafka::reply(&mut state, &from("c0", Payload::Init { node_id: "n1".to_owned(), node_ids: node_ids.clone() }));
+    let key = (0..).map(|i| format!("k{i}")).find(|key| state.leader(key).is_some_and(|leader| *leader == *"n2")).unwrap();
+    let state = Arc::new(Mutex::new(state));
+    let (writer, _pings) = mpsc::unbounded_channel();
+    // Well ahead of the real time, which n2 shouldn't be taken to have been silent since.
+    clock.advance(Duration::from_secs(10));
+    let _detector = kafka::watch_peers(&scheduler, state.clone(), writer, "n1", &node_ids);
+    let go_by = |ticks| {
+        let clock = clock.clone();
+        async move {
+            for _ in 0..ticks {
+                clock.advance(Duration::from_millis(100));
+                for _ in 0..5 {
+                    tokio::task::yield_now().await;
+                }
+            }
+        }
+    };
+    go_by(3).await;
+    assert!(state.lock().unwrap().leader(&key).is_some());
+
+    // n2 never answers a ping.
+    go_by(20).await;
+    assert_eq!(state.lock().unwrap().leader(&key), None);
+}
@@ -0,0 +1,110 @@
+//! Leases in lin-kv (taking them, keeping them, and losing them), fencing off a deposed
+//! holder, and electing a leader with one.
+
+mod common;
+
+use std::{sync::Arc, time::Duration};
+use serde_json::{json, Value};
+use solutions::{clock::ManualClock, election::{Config, Election, Event}, kv::KvClient, lease::{Fence, FencingToken, Lease, LeaseError}, message};
+use common::Store;
+
+
+/// A node's client for the lin-kv `store` every node shares.
+fn kv(id: &str, store: &Store) -> KvClient<Value> {
+    KvClient::lin_kv(common::lin_kv(id, store), Duration::from_secs(1))
+}
+
+/// A node campaigning against `store`, by `clock`.
+fn node(id: &str, store: &Store, clock: &ManualClock) -> Election<Value> {
+    Election::with_clock(kv(id, store), id, Config::default(), Arc::new(clock.clone()))
+}
+
+fn lease(id: &str, store: &Store, clock: &ManualClock) -> Lease<Value> {
+    Lease::with_clock(kv(id, store), "lock", id, Duration::from_secs(1), Arc::new(clock.clone()))
+}
+
+#[tokio::test]
+async fn a_lease_has_one_holder_at_a_time_and_a_new_token_for_each() {
+    let (store, clock) = (Store::default(), ManualClock::new());
+    let (n1, n2) = (lease("n1", &store, &clock), lease("n2", &store, &clock));
+
+    assert_eq!(n1.acquire().await.unwrap(), FencingToken(1));
+    assert_eq!(n2.acquire().await.unwrap_err(), LeaseError::Taken);
+    assert_eq!(n2.renew().await.unwrap_err(), LeaseError::NotHeld);
+    clock.advance(Duration::from_millis(500));
+    assert_eq!(n1.renew().await.unwrap(), FencingToken(1));
+
+    n1.release().await.unwrap();
+    assert!(!n1.is_held());
+    assert_eq!(n2.acquire().await.unwrap(), FencingToken(2));
+    assert_eq!(n2.holder(), Some(message::intern("n2")));
+    assert_eq!(n1.renew().await.unwrap_err(), LeaseError::NotHeld);
+}
+
+#[tokio::test]
+async fn a_deposed_holders_writes_are_fenced_off() {
+    let (store, clock) = (Store::default(), ManualClock::new());
+    let (n1, n2) = (lease("n1", &store, &clock), lease("n2", &store, &clock));
+
+    let old = n1.acquire().await.unwrap();
+    n1.write_fenced("config", 1).await.unwrap();
+    // n1 stalls long enough for its lease to run out, and n2 takes over.
+    clock.advance(Duration::from_millis(1001));
+    let new = n2.acquire().await.unwrap();
+    n2.write_fenced("config", 2).await.unwrap();
+    assert_eq!(n1.write_fenced("config", 3).await.unwrap_err(), LeaseError::NotHeld);
+
+    // Even with the token in hand, n1's late write doesn't land.
+    let stale = solutions::lease::write_fenced(&kv("n1", &store), "config", 3, old).await;
+    assert_eq!(stale.unwrap_err(), LeaseError::Stale { token: old, newer: new });
+    assert_eq!(store.lock().unwrap()["\"config\""], json!({"token": 2, "value": 2}));
+
+    let mut fence = Fence::new();
+    fence.check(new).unwrap();
+    assert_eq!(fence.check(old).unwrap_err(), LeaseError::Stale { token: old, newer: new });
+}
+
+#[tokio::test]
+async fn the_first_to_campaign_leads_until_it_stops_renewing() {
+    let (store, clock) = (Store::default(), ManualClock::new());
+    let (n1, n2) = (node("n1", &store, &clock), node("n2", &store, &clock));
+    let mut n2_events = n2.subscribe();
+
+    assert!(n1.campaign().await.unwrap());
+    assert!(!n2.campaign().await.unwrap());
+    assert!(n1.is_leader() && !n2.is_leader());
+    assert_eq!(n2_events.try_recv().unwrap(), Event { leader: Some(message::intern("n1")), term: 1 });
+
+    // Renewing keeps it.
+    clock.advance(Duration::from_millis(800));
+    assert!(n1.campaign().await.unwrap());
+    clock.advance(Duration::from_millis(800));
+    assert!(!n2.campaign().await.unwrap());
+    assert!(n1.is_leader());
+
+    // Until n1 goes quiet: it steps down a little before the lease runs out, and n2
+    // takes over once it has.
+    clock.advance(Duration::from_millis(100));
+    assert!(!n1.is_leader());
+    assert!(!n2.campaign().await.unwrap());
+    clock.advance(Duration::from_millis(200));
+    assert!(n2.campaign().await.unwrap());
+    assert_eq!((n2.leader(), n2.term()), (Some(message::intern("n2")), 2));
+    assert!(!n1.campaign().await.unwrap());
+    assert_eq!(n1.leader(), Some(message::intern("n2")));
+}
+
+#[tokio::test]
+async fn a_leader_that_resigns_can_be_replaced_straight_away() {
+    let (store, clock) = (Store::default(), ManualClock::new());
+    let (n1, n2) = (node("n1", &store, &clock), node("n2", &store, &clock));
+    let mut n1_events = n1.subscribe();
+
+    assert!(n1.campaign().await.unwrap());
+    n1.resign().await.unwrap();
+    assert!(!n1.is_leader());
+    assert!(n2.campaign().await.unwrap());
+
+    let events: Vec<Event> = std::iter::from_fn(|| n1_events.try_recv().ok()).collect();
+    assert_eq!(events, [Event { leader: Some(message::intern("n1")), term: 1 }, Event { leader: None, term: 1 }]);
+}
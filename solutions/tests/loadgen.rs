@@ -0,0 +1,50 @@
+//! Parses op mixes, and puts a couple of echo nodes under load through the `loadgen` binary.
+
+use std::process::Command;
+use rand::{rngs::StdRng, SeedableRng};
+use solutions::loadgen::{Mix, Op};
+
+
+#[test]
+fn mix_picks_ops_by_weight() {
+    let mix: Mix = "broadcast=3, read, add=0".parse().unwrap();
+    assert!(mix.contains(Op::Broadcast) && mix.contains(Op::Read) && !mix.contains(Op::Add));
+
+    let mut rng = StdRng::seed_from_u64(7);
+    let broadcasts = (0..4000).filter(|_| mix.pick(&mut rng) == Op::Broadcast).count();
+    assert!((2800..3200).contains(&broadcasts), "{broadcasts}");
+
+    assert!("broadcast=x".parse::<Mix>().is_err());
+    assert!("gossip".parse::<Mix>().is_err());
+    assert!("read=0".parse::<Mix>().is_err());
+}
+
+#[test]
+fn op_bodies_look_like_maelstrom_requests() {
+    let mut rng = StdRng::seed_from_u64(7);
+    for op in Op::ALL {
+        let body = op.body(&mut rng, 12);
+        assert_eq!(body["type"], op.name());
+    }
+    assert_eq!(Op::Broadcast.body(&mut rng, 12)["message"], 12);
+    assert_eq!(Op::Txn.body(&mut rng, 12)["txn"][1][2], 12);
+    let delta = Op::Add.body(&mut rng, 12)["delta"].as_i64().unwrap();
+    assert!((1..=10).contains(&delta));
+}
+
+#[test]
+fn loadgen_summarizes_a_run() {
+    let output =
+        Command::new(env!("CARGO_BIN_EXE_loadgen"))
+        .args(["--nodes", "2", "--rate", "200", "--duration", "0.5", "--mix", "echo", "--seed", "1"])
+        .arg(env!("CARGO_BIN_EXE_echo"))
+        .env("TOKIO_WORKER_THREADS", "2")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let output = String::from_utf8(output.stdout).unwrap();
+    let echo = output.lines().find(|line| line.starts_with("echo")).unwrap_or_else(|| panic!("{output}"));
+    let columns: Vec<&str> = echo.split_whitespace().collect();
+    assert_eq!(columns[1], columns[2], "every echo should be answered: {output}");
+    assert_eq!(columns[4], "0", "{output}");
+}
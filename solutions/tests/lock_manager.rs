@@ -0,0 +1,133 @@
+//! The lock manager behind two-phase locking: which requests are granted straight away,
+//! who waits behind whom, and how deadlocks (and transactions released mid-wait) end.
+
+use std::{sync::{Arc, Mutex}, time::Duration};
+use solutions::lock_manager::{self, Acquire, LockError, LockManager, LockMode};
+use tokio::sync::oneshot::Receiver;
+
+
+fn granted(acquired: Result<Acquire, LockError>) {
+    assert!(matches!(acquired, Ok(Acquire::Granted)), "expected the lock to be granted, got {acquired:?}");
+}
+
+fn waits(acquired: Result<Acquire, LockError>) -> Receiver<Result<(), LockError>> {
+    match acquired {
+        Ok(Acquire::Wait(granted)) => granted,
+        other => panic!("expected to wait for the lock, got {other:?}"),
+    }
+}
+
+
+#[test]
+fn shared_locks_are_shared_and_exclusive_ones_wait() {
+    let mut locks = LockManager::new();
+    granted(locks.acquire(1, "a", LockMode::Shared));
+    granted(locks.acquire(2, "a", LockMode::Shared));
+    let mut third = waits(locks.acquire(3, "a", LockMode::Exclusive));
+    assert_eq!(locks.num_waiting(), 1);
+
+    locks.release_all(1);
+    assert!(third.try_recv().is_err(), "granted while txn 2 still holds the lock");
+    locks.release_all(2);
+    assert_eq!(third.try_recv().unwrap(), Ok(()));
+    assert_eq!(locks.holders(&"a"), vec![(3, LockMode::Exclusive)]);
+}
+
+
+#[test]
+fn waiters_are_granted_in_the_order_they_came() {
+    let mut locks = LockManager::new();
+    granted(locks.acquire(1, "a", LockMode::Shared));
+    let mut writer = waits(locks.acquire(2, "a", LockMode::Exclusive));
+    // Compatible with the holder, but not allowed to overtake the writer.
+    let mut reader = waits(locks.acquire(3, "a", LockMode::Shared));
+
+    locks.release_all(1);
+    assert_eq!(writer.try_recv().unwrap(), Ok(()));
+    assert!(reader.try_recv().is_err());
+    locks.release_all(2);
+    assert_eq!(reader.try_recv().unwrap(), Ok(()));
+}
+
+
+#[test]
+fn upgrades_jump_the_queue() {
+    let mut locks = LockManager::new();
+    granted(locks.acquire(1, "a", LockMode::Shared));
+    granted(locks.acquire(2, "a", LockMode::Shared));
+    let mut writer = waits(locks.acquire(3, "a", LockMode::Exclusive));
+    let mut upgrade = waits(locks.acquire(1, "a", LockMode::Exclusive));
+
+    locks.release_all(2);
+    assert_eq!(upgrade.try_recv().unwrap(), Ok(()));
+    assert!(writer.try_recv().is_err());
+}
+
+
+#[test]
+fn the_youngest_transaction_in_a_deadlock_is_aborted() {
+    let mut locks = LockManager::new();
+    granted(locks.acquire(1, "a", LockMode::Exclusive));
+    granted(locks.acquire(2, "b", LockMode::Exclusive));
+    let mut older = waits(locks.acquire(1, "b", LockMode::Exclusive));
+
+    // Txn 2 closes the cycle and, being younger, is turned away.
+    assert_eq!(locks.acquire(2, "a", LockMode::Exclusive).unwrap_err(), LockError::Deadlock { victim: 2 });
+    locks.release_all(2);
+    assert_eq!(older.try_recv().unwrap(), Ok(()));
+}
+
+
+#[test]
+fn a_waiter_is_aborted_when_an_older_transaction_closes_the_cycle() {
+    let mut locks = LockManager::new();
+    granted(locks.acquire(2, "a", LockMode::Exclusive));
+    granted(locks.acquire(1, "b", LockMode::Exclusive));
+    let mut younger = waits(locks.acquire(2, "b", LockMode::Exclusive));
+
+    let mut older = waits(locks.acquire(1, "a", LockMode::Exclusive));
+    assert_eq!(younger.try_recv().unwrap(), Err(LockError::Deadlock { victim: 2 }));
+    locks.release_all(2);
+    assert_eq!(older.try_recv().unwrap(), Ok(()));
+}
+
+
+#[test]
+fn every_cycle_a_wait_closes_is_broken() {
+    let mut locks = LockManager::new();
+    granted(locks.acquire(2, "a", LockMode::Shared));
+    granted(locks.acquire(3, "a", LockMode::Shared));
+    granted(locks.acquire(1, "b", LockMode::Exclusive));
+    granted(locks.acquire(1, "c", LockMode::Exclusive));
+    let mut second = waits(locks.acquire(2, "b", LockMode::Exclusive));
+    let mut third = waits(locks.acquire(3, "c", LockMode::Exclusive));
+
+    // Waiting on `a` puts txn 1 in a cycle with 2 and in another with 3.
+    let mut first = waits(locks.acquire(1, "a", LockMode::Exclusive));
+    assert_eq!(second.try_recv().unwrap(), Err(LockError::Deadlock { victim: 2 }));
+    assert_eq!(third.try_recv().unwrap(), Err(LockError::Deadlock { victim: 3 }));
+    assert_eq!(locks.num_waiting(), 1);
+
+    locks.release_all(2);
+    locks.release_all(3);
+    assert_eq!(first.try_recv().unwrap(), Ok(()));
+}
+
+
+#[tokio::test]
+async fn a_transaction_released_while_it_waits_is_not_granted_the_lock() {
+    let locks = Arc::new(Mutex::new(LockManager::new()));
+    granted(locks.lock().unwrap().acquire(1, "a", LockMode::Exclusive));
+
+    let waiting = tokio::spawn({
+        let locks = locks.clone();
+        async move { lock_manager::lock(&locks, 2, "a", LockMode::Exclusive).await }
+    });
+    while locks.lock().unwrap().num_waiting() == 0 {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    locks.lock().unwrap().release_all(2);
+
+    assert_eq!(waiting.await.unwrap(), Err(LockError::Released { txn: 2 }));
+    assert_eq!(locks.lock().unwrap().holders(&"a"), vec![(1, LockMode::Exclusive)]);
+}
@@ -0,0 +1,71 @@
+//! The segmented per-key logs behind the kafka workload: offsets, reads across segment
+//! boundaries, and compacting records superseded by a newer one with the same sub-key.
+
+use solutions::log::{Log, SegmentStore};
+
+
+#[test]
+fn offsets_go_up_per_key() {
+    let mut store = SegmentStore::new(2);
+    assert_eq!(store.append("k1", 10, None), 0);
+    assert_eq!(store.append("k1", 11, None), 1);
+    assert_eq!(store.append("k2", 20, None), 0);
+    assert_eq!(store.append("k1", 12, None), 2);
+
+    assert_eq!(store.read_from("k1", 0), vec![(0, 10), (1, 11), (2, 12)]);
+    assert_eq!(store.read_from("k2", 0), vec![(0, 20)]);
+    assert!(store.read_from("k3", 0).is_empty());
+}
+
+
+#[test]
+fn reads_start_partway_through_a_segment() {
+    let mut store = SegmentStore::new(3);
+    for msg in 0..10 {
+        store.append("k", msg, None);
+    }
+    assert_eq!(store.read_from("k", 4), (4..10).map(|offset| (offset, offset)).collect::<Vec<_>>());
+    assert_eq!(store.read_from("k", 9), vec![(9, 9)]);
+    assert!(store.read_from("k", 10).is_empty());
+    assert_eq!(store.get("k").unwrap().next_offset(), 10);
+}
+
+
+#[test]
+fn compaction_keeps_the_newest_committed_record_per_sub_key() {
+    let mut log = Log::default();
+    let sub_key = |key: &str| Some(key.to_string());
+    log.append(1, sub_key("a"), 2);
+    log.append(2, sub_key("b"), 2);
+    log.append(3, sub_key("a"), 2);
+    log.append(4, None, 2);
+    log.append(5, None, 2);
+    log.append(6, sub_key("b"), 2);
+
+    // Only offsets up to 4 are committed, so `b` at 5 doesn't supersede `b` at 1 yet.
+    assert_eq!(log.compact(4), 1);
+    let left: Vec<usize> = log.read_from(0).map(|record| record.offset).collect();
+    assert_eq!(left, vec![1, 2, 3, 4, 5]);
+
+    assert_eq!(log.compact(5), 1);
+    let left: Vec<usize> = log.read_from(0).map(|record| record.offset).collect();
+    assert_eq!(left, vec![2, 3, 4, 5]);
+    assert_eq!(log.len(), 4);
+    // Holes don't move offsets: the next record still goes at the end.
+    assert_eq!(log.append(7, None, 2), 6);
+}
+
+
+#[test]
+fn compaction_only_touches_keys_with_a_committed_offset() {
+    let mut store = SegmentStore::new(4);
+    for key in ["k1", "k2"] {
+        store.append(key, 1, Some("x".to_string()));
+        store.append(key, 2, Some("x".to_string()));
+    }
+
+    let removed = store.compact(|key| (key == "k1").then_some(1));
+    assert_eq!(removed, 1);
+    assert_eq!(store.read_from("k1", 0), vec![(1, 2)]);
+    assert_eq!(store.read_from("k2", 0), vec![(0, 1), (1, 2)]);
+}
@@ -0,0 +1,140 @@
+//! SWIM-style membership: probing members, suspecting the quiet ones, and spreading
+//! (and refuting) the rumors.
+
+mod common;
+
+use solutions::{membership::{self, Config, Membership, Probe, Status, Update}, message::{self, Body, Envelope, NodeId}};
+use tokio::time::Instant;
+use common::millis;
+
+
+fn cluster(me: &str, now: Instant) -> Membership {
+    Membership::new(Config::default(), me, ["n1", "n2", "n3", "n4"].map(message::intern), now)
+}
+
+/// Who `me` pings on its next tick at `now`.
+fn pinged(membership: &mut Membership, now: Instant) -> message::NodeId {
+    match membership.tick(now).as_slice() {
+        [Probe::Ping { to }] => to.clone(),
+        probes => panic!("expected one ping, got {probes:?}"),
+    }
+}
+
+/// A workload with nothing to say but SWIM.
+#[derive(Debug)]
+enum Swim {
+    Ping,
+    PingReq { target: String },
+    Ack { target: String },
+}
+
+impl membership::Messages for Swim {
+    fn ping(_: Vec<Update>) -> Self {
+        Swim::Ping
+    }
+
+    fn ping_req(target: String, _: Vec<Update>) -> Self {
+        Swim::PingReq { target }
+    }
+
+    fn ack(target: String, _: Vec<Update>) -> Self {
+        Swim::Ack { target }
+    }
+
+    fn message_id() -> usize {
+        1
+    }
+}
+
+struct Node(Membership, Instant);
+
+impl membership::Node for Node {
+    fn membership(&mut self) -> Option<(NodeId, &mut Membership, Instant)> {
+        Some((message::intern("n2"), &mut self.0, self.1))
+    }
+}
+
+/// Who's pinged among `probes`.
+fn next_pinged(probes: &[Probe]) -> message::NodeId {
+    probes.iter().find_map(|probe| match probe { Probe::Ping { to } => Some(to.clone()), _ => None }).unwrap()
+}
+
+#[test]
+fn members_that_never_ack_are_suspected_then_dead() {
+    let start = Instant::now();
+    let mut n1 = cluster("n1", start);
+    let quiet = pinged(&mut n1, start);
+
+    // Others are asked to ping it once the ping times out, while the next member is pinged.
+    let probes = n1.tick(start + millis(300));
+    let ping_reqs: Vec<_> = probes.iter().filter(|probe| matches!(probe, Probe::PingReq { target, .. } if *target == quiet)).collect();
+    assert_eq!(ping_reqs.len(), 2);
+    n1.acked(&next_pinged(&probes));
+
+    n1.tick(start + millis(1000));
+    assert_eq!(n1.status(&quiet), Some(Status::Suspect));
+    assert!(n1.is_live(&quiet));
+    n1.tick(start + millis(3999));
+    assert_eq!(n1.status(&quiet), Some(Status::Suspect));
+    n1.tick(start + millis(4000));
+    assert_eq!(n1.status(&quiet), Some(Status::Dead));
+    assert!(!n1.live_members().contains(&quiet));
+    assert_eq!(n1.live_members().len(), 2);
+}
+
+#[test]
+fn pings_asked_for_by_others_have_their_acks_passed_back() {
+    let now = Instant::now();
+    let mut n2 = cluster("n2", now);
+    assert_eq!(n2.ping_req("n1", "n3", now), Probe::Ping { to: message::intern("n3") });
+    assert_eq!(n2.acked("n3"), [Probe::Ack { to: message::intern("n1"), target: message::intern("n3") }]);
+    assert!(n2.acked("n3").is_empty());
+}
+
+#[test]
+fn suspects_refute_rumors_about_themselves() {
+    let now = Instant::now();
+    let (mut n1, mut n2) = (cluster("n1", now), cluster("n2", now));
+    n1.apply([Update { member: "n2".to_owned(), incarnation: 0, status: Status::Suspect }], now);
+
+    // n1 tells n2 what it thinks of it whenever they talk, and n2 sets it straight.
+    let rumors = n1.piggyback("n2");
+    assert!(rumors.contains(&Update { member: "n2".to_owned(), incarnation: 0, status: Status::Suspect }));
+    n2.apply(rumors, now);
+    assert_eq!(n2.incarnation(), 1);
+    n1.apply(n2.piggyback("n1"), now);
+    assert_eq!(n1.status("n2"), Some(Status::Alive));
+
+    // Older rumors don't undo it.
+    n1.apply([Update { member: "n2".to_owned(), incarnation: 0, status: Status::Dead }], now);
+    assert_eq!(n1.status("n2"), Some(Status::Alive));
+}
+
+#[test]
+fn rumors_are_only_passed_on_so_many_times() {
+    let now = Instant::now();
+    let mut n1 = cluster("n1", now);
+    n1.apply([Update { member: "n3".to_owned(), incarnation: 2, status: Status::Alive }], now);
+    let sends = std::iter::from_fn(|| Some(n1.piggyback("n2"))).take_while(|rumors| !rumors.is_empty()).count();
+    // 3 times the bits in the cluster size (4).
+    assert_eq!(sends, 9);
+}
+
+#[test]
+fn workloads_relay_pings_asked_for_in_their_own_messages() {
+    let start = Instant::now();
+    let mut n2 = Node(cluster("n2", start), start);
+    let from = |src: &str, message| Envelope::new(src, "n2", Body { msg_id: Some(7), in_reply_to: None, message });
+
+    let ack = membership::on_ping(&mut n2, &from("n1", Swim::Ping), &[]).unwrap();
+    assert_eq!((&*ack.destination, ack.body.in_reply_to), ("n1", Some(7)));
+    assert!(matches!(&ack.body.message, Swim::Ack { target } if target == "n2"), "{ack:?}");
+
+    let ping_req = from("n1", Swim::PingReq { target: "n3".to_owned() });
+    let Swim::PingReq { target } = &ping_req.body.message else { unreachable!() };
+    let ping = membership::on_ping_req(&mut n2, &ping_req, target, &[]).unwrap();
+    assert_eq!(&*ping.destination, "n3");
+    assert!(matches!(ping.body.message, Swim::Ping), "{ping:?}");
+    let relayed: Vec<Envelope<Swim>> = membership::on_ack(&mut n2, "n3", &[]);
+    assert!(matches!(relayed.as_slice(), [ack] if &*ack.destination == "n1" && matches!(&ack.body.message, Swim::Ack { target } if target == "n3")), "{relayed:?}");
+}
@@ -0,0 +1,41 @@
+//! Forwards envelopes, typed and raw, and checks raw bodies go back out untouched and node
+//! ids are shared.
+
+use std::sync::Arc;
+use serde_json::{json, Value};
+use solutions::{message::{Body, Envelope, RawEnvelope}, workloads::broadcast::Payload};
+
+
+#[test]
+fn forwarding_only_rewrites_src_and_dest() {
+    let envelope = Envelope::new("c1", "n1", Body { msg_id: Some(4), in_reply_to: None, message: Payload::Broadcast { message: 7 } });
+    let forwarded = envelope.forward_to("n2");
+    assert_eq!((&*forwarded.source, &*forwarded.destination, forwarded.msg_id()), ("n1", "n2", Some(4)));
+    assert_eq!(serde_json::to_value(&forwarded).unwrap()["body"], json!({"type": "broadcast", "msg_id": 4, "message": 7}));
+}
+
+#[test]
+fn raw_bodies_pass_through_byte_for_byte() {
+    // Odd spacing, key order and number formatting would all be lost on a round trip
+    // through a typed (or `Value`) body.
+    let body = r#"{ "type":"sync",  "messages":[3,1, 2], "msg_id":9, "weight":1.50 }"#;
+    let line = format!(r#"{{"src":"n1","dest":"n2","body":{body}}}"#);
+    let envelope: RawEnvelope = serde_json::from_str(&line).unwrap();
+    assert_eq!(envelope.kind().as_deref(), Some("sync"));
+
+    let forwarded = envelope.forward_to("n3");
+    assert_eq!(serde_json::to_string(&forwarded).unwrap(), format!(r#"{{"src":"n2","dest":"n3","body":{body}}}"#));
+
+    let parsed = forwarded.parse::<Value>().unwrap();
+    assert_eq!((parsed.msg_id(), &parsed.body.message["messages"]), (Some(9), &json!([3, 1, 2])));
+    assert!(matches!(forwarded.parse::<Payload>().unwrap().body.message, Payload::Sync { messages, .. } if messages == [3, 1, 2]));
+}
+
+#[test]
+fn node_ids_are_shared_between_envelopes() {
+    let read: Envelope<Payload> = serde_json::from_str(r#"{"src":"c7","dest":"n9","body":{"type":"read","msg_id":1}}"#).unwrap();
+    let reply = read.reply_with(Some(2), Payload::ReadOk { messages: vec![], ranges: vec![], part: None, parts: None });
+    let sent = Envelope::new("n9", "c7", Body { msg_id: Some(3), in_reply_to: None, message: Payload::Read { chunk_size: None, ranges: false } });
+    assert!(Arc::ptr_eq(&read.destination, &reply.source) && Arc::ptr_eq(&reply.source, &sent.source));
+    assert!(Arc::ptr_eq(&read.source, &sent.destination));
+}
@@ -0,0 +1,112 @@
+//! Counts, gauges and latencies in a registry, and the metrics a node dumps to stderr when it shuts down or reports when asked.
+
+use std::{io::{BufRead, BufReader, Write}, process::{Command, Stdio}, time::Duration};
+use solutions::metrics::Registry;
+
+
+#[test]
+fn registry_counts_and_gauges() {
+    let registry = Registry::new();
+    registry.increment("messages_in.read");
+    registry.add("messages_in.read", 2);
+    registry.increment("messages_in.broadcast");
+    registry.set("queue.stdout", 7);
+    registry.set("queue.stdout", 3);
+
+    let snapshot = registry.snapshot();
+    assert_eq!(snapshot.counter("messages_in.read"), 3);
+    assert_eq!(snapshot.counter("messages_in.write"), 0);
+    assert_eq!(snapshot.gauge("queue.stdout"), Some(3));
+    assert_eq!(snapshot.gauge("queue.stdin"), None);
+    assert_eq!(snapshot.to_string(), "messages_in.broadcast=1 messages_in.read=3 queue.stdout=3");
+}
+
+#[test]
+fn registry_summarizes_latencies() {
+    let registry = Registry::new();
+    for millis in 1..=100 {
+        registry.record("reply.broadcast", Duration::from_millis(millis));
+    }
+
+    let snapshot = registry.snapshot();
+    assert!(snapshot.latencies("reply.read").is_none());
+    let latencies = snapshot.latencies("reply.broadcast").unwrap();
+    assert_eq!(latencies.count, 100);
+    // Histograms keep three significant figures.
+    let close_to = |latency: Duration, millis: f64| (latency.as_secs_f64() * 1000. - millis).abs() < millis / 100.;
+    assert!(close_to(latencies.percentiles.p50, 50.), "{latencies}");
+    assert!(close_to(latencies.percentiles.p99, 99.), "{latencies}");
+    assert!(close_to(latencies.percentiles.max, 100.), "{latencies}");
+    // Latencies don't crowd the one-line dump.
+    assert_eq!(snapshot.to_string(), "");
+}
+
+#[test]
+fn nodes_dump_their_metrics_at_shutdown() {
+    let mut node =
+        Command::new(env!("CARGO_BIN_EXE_echo"))
+        .env("TOKIO_WORKER_THREADS", "2")
+        .env("METRICS_INTERVAL_MS", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = node.stdin.take().unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}}}"#).unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "echo", "msg_id": 2, "echo": "hi"}}}}"#).unwrap();
+    writeln!(stdin, "not json").unwrap();
+    // Let both replies out before hanging up.
+    let mut stdout = BufReader::new(node.stdout.take().unwrap()).lines();
+    stdout.next().unwrap().unwrap();
+    stdout.next().unwrap().unwrap();
+    drop(stdin);
+
+    let output = node.wait_with_output().unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let dump = stderr.lines().find_map(|line| line.strip_prefix("metrics: ")).unwrap_or_else(|| panic!("{stderr}"));
+    for metric in ["errors.unparseable_input=1", "messages_in.echo=1", "messages_in.init=1", "messages_out.echo_ok=1", "messages_out.init_ok=1"] {
+        assert!(dump.split(' ').any(|pair| pair == metric), "{metric} not in {dump}");
+    }
+    for histogram in ["handler.echo", "handler.init", "reply.echo", "reply.init"] {
+        let summary = stderr.lines().find_map(|line| line.strip_prefix(&format!("latency: {histogram} "))).unwrap_or_else(|| panic!("no {histogram} in {stderr}"));
+        assert!(summary.starts_with("count=1 p50="), "{summary}");
+    }
+}
+
+#[test]
+fn nodes_answer_stats_requests_with_their_metrics() {
+    let mut node =
+        Command::new(env!("CARGO_BIN_EXE_broadcast"))
+        .args(["--stride", "2", "--tick-rate-ms", "1000"])
+        .env("METRICS_INTERVAL_MS", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = node.stdin.take().unwrap();
+    let mut stdout = BufReader::new(node.stdout.take().unwrap()).lines().map(|line| serde_json::from_str::<serde_json::Value>(&line.unwrap()).unwrap());
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"]}}}}"#).unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "topology", "msg_id": 2, "topology": {{}}}}}}"#).unwrap();
+    for (msg_id, message) in [(3, 10), (4, 11)] {
+        writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "broadcast", "msg_id": {msg_id}, "message": {message}}}}}"#).unwrap();
+    }
+    for _ in 0..4 {
+        stdout.next().unwrap();
+    }
+    // From anyone at all, not just clients or other nodes, and however they escape their name.
+    writeln!(stdin, r#"{{"src": "pro\u0062e", "dest": "n1", "body": {{"type": "stats", "msg_id": 9}}}}"#).unwrap();
+    let reply = stdout.find(|envelope| envelope["body"]["type"] == "stats_ok").unwrap();
+    drop(stdin);
+    node.wait().unwrap();
+
+    assert_eq!((&reply["src"], &reply["dest"], &reply["body"]["in_reply_to"]), (&"n1".into(), &"probe".into(), &9.into()));
+    let body = &reply["body"];
+    assert_eq!(body["counters"]["messages_in.broadcast"], 2, "{body}");
+    assert_eq!(body["counters"]["messages_in.stats"], 1, "{body}");
+    assert_eq!(body["counters"]["messages_out.broadcast_ok"], 2, "{body}");
+    assert_eq!(body["gauges"]["broadcast.seen"], 2, "{body}");
+    assert_eq!(body["latencies"]["reply.broadcast"]["count"], 2, "{body}");
+    assert!(body["latencies"]["reply.broadcast"]["p99_us"].is_u64(), "{body}");
+}
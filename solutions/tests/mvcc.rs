@@ -0,0 +1,101 @@
+//! `MvccStore`: what a snapshot taken at some timestamp sees, write-write conflict checks
+//! between transactions, and garbage-collecting versions nobody can see anymore.
+
+use solutions::mvcc::MvccStore;
+
+
+fn store() -> MvccStore<String, u64> {
+    let mut store = MvccStore::new();
+    store.write("x".to_string(), 1, 10);
+    store.write("x".to_string(), 2, 20);
+    store.write("y".to_string(), 7, 15);
+    store.delete("y".to_string(), 25);
+    store
+}
+
+
+#[test]
+fn reads_see_the_newest_version_at_or_before_their_timestamp() {
+    let store = store();
+    assert_eq!(store.read_at("x", 5), None);
+    assert_eq!(store.read_at("x", 10), Some(&1));
+    assert_eq!(store.read_at("x", 19), Some(&1));
+    assert_eq!(store.read_at("x", 20), Some(&2));
+    assert_eq!(store.read_latest("x"), Some(&2));
+    assert_eq!(store.latest_commit_ts(), 25);
+}
+
+
+#[test]
+fn deletes_hide_older_versions_only_from_later_snapshots() {
+    let store = store();
+    assert_eq!(store.read_at("y", 24), Some(&7));
+    assert_eq!(store.read_at("y", 25), None);
+    assert_eq!(store.read_latest("y"), None);
+}
+
+
+#[test]
+fn a_snapshot_does_not_see_later_commits() {
+    let mut store = store();
+    let ts = store.latest_commit_ts();
+    store.write("x".to_string(), 3, 30);
+    store.write("z".to_string(), 9, 30);
+
+    let snapshot = store.snapshot(ts);
+    assert_eq!(snapshot.ts(), 25);
+    assert_eq!(snapshot.get("x"), Some(&2));
+    assert_eq!(snapshot.get("z"), None);
+    assert_eq!(store.snapshot(30).get("x"), Some(&3));
+}
+
+
+#[test]
+fn a_commit_installs_all_its_writes_at_once() {
+    let mut store = store();
+    store.commit(30, [("x".to_string(), Some(5)), ("y".to_string(), Some(6)), ("z".to_string(), None)]);
+
+    let before = store.snapshot(29);
+    let after = store.snapshot(30);
+    assert_eq!((before.get("x"), before.get("y")), (Some(&2), None));
+    assert_eq!((after.get("x"), after.get("y"), after.get("z")), (Some(&5), Some(&6), None));
+}
+
+
+#[test]
+fn writes_committed_after_a_snapshot_conflict_with_it() {
+    let mut store = store();
+    // Two transactions both start from the snapshot at 20 and both write `x`.
+    let start_ts = 20;
+    assert!(!store.written_since("x", start_ts));
+    store.write("x".to_string(), 3, 30);
+
+    // The first to commit wins; the second now sees a write it didn't read past.
+    assert!(store.written_since("x", start_ts));
+    assert!(!store.written_since("x", 30));
+    // Keys nobody wrote, or that were only written before the snapshot, don't conflict.
+    assert!(!store.written_since("never", start_ts));
+    assert!(!store.written_since("y", 25));
+    // A delete is a write too.
+    assert!(store.written_since("y", 20));
+    assert_eq!(store.last_write_ts("y"), Some(25));
+}
+
+
+#[test]
+fn gc_keeps_what_readers_at_the_watermark_can_see() {
+    let mut store = store();
+    assert_eq!(store.num_versions(), 4);
+
+    // At 22, `x` shows the version from 20 and `y` the one from 15; the version of `x`
+    // from 10 is unreachable, but `y`'s tombstone at 25 is still ahead of the watermark.
+    assert_eq!(store.gc(22), 1);
+    assert_eq!(store.read_at("x", 22), Some(&2));
+    assert_eq!(store.read_at("y", 22), Some(&7));
+
+    // Past the tombstone, `y` is gone altogether.
+    assert_eq!(store.gc(30), 2);
+    assert_eq!(store.num_versions(), 1);
+    assert_eq!(store.read_latest("x"), Some(&2));
+    assert_eq!(store.last_write_ts("y"), None);
+}
@@ -0,0 +1,101 @@
+//! Exporting spans over OTLP, and carrying trace contexts between nodes.
+
+use std::{io::{BufRead, BufReader, Read, Write}, net::TcpListener, process::{Command, Stdio}, sync::mpsc, thread, time::Duration};
+use serde_json::Value;
+
+
+/// A stand-in collector: answers every request with an empty 200, and reports each
+/// request's first line.
+fn collector() -> (String, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            stream.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+            let mut request = vec![];
+            let mut buffer = [0; 4096];
+            while let Ok(read @ 1..) = stream.read(&mut buffer) {
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let request_line = String::from_utf8_lossy(&request).lines().next().unwrap_or_default().to_owned();
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").ok();
+            tx.send(request_line).ok();
+        }
+    });
+    (endpoint, rx)
+}
+
+
+#[test]
+fn node_to_node_messages_carry_a_trace_context_and_spans_get_exported() {
+    let (endpoint, requests) = collector();
+    let mut node =
+        Command::new(env!("CARGO_BIN_EXE_broadcast"))
+        .args(["--stride", "2", "--tick-rate-ms", "50"])
+        .env("TOKIO_WORKER_THREADS", "2")
+        .env("METRICS_INTERVAL_MS", "0")
+        .env("OTEL_EXPORTER_OTLP_ENDPOINT", &endpoint)
+        .env("OTEL_SERVICE_NAME", "broadcast")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = node.stdin.take().unwrap();
+    let mut stdout = BufReader::new(node.stdout.take().unwrap()).lines().map(|line| serde_json::from_str::<Value>(&line.unwrap()).unwrap());
+    let mut next = |kind: &str| stdout.find(|envelope| envelope["body"]["type"] == kind).unwrap();
+
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"]}}}}"#).unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "topology", "msg_id": 2, "topology": {{}}}}}}"#).unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "broadcast", "msg_id": 3, "message": 5}}}}"#).unwrap();
+    writeln!(stdin, r#"{{"src": "n2", "dest": "n1", "body": {{"type": "sync", "msg_id": 9, "messages": [6], "traceparent": "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"}}}}"#).unwrap();
+
+    // Clients check their replies' fields, so those go out untouched.
+    for kind in ["init_ok", "topology_ok", "broadcast_ok"] {
+        let reply = next(kind);
+        assert!(reply["body"].get("traceparent").is_none(), "{reply}");
+    }
+    let sync_ok = next("sync_ok");
+    assert_eq!(sync_ok["dest"], "n2");
+    let traceparent = sync_ok["body"]["traceparent"].as_str().unwrap_or_else(|| panic!("{sync_ok}"));
+    assert_eq!(traceparent.split('-').count(), 4, "{traceparent}");
+    let sync = next("sync");
+    assert_eq!(sync["dest"], "n2");
+    assert!(sync["body"]["traceparent"].is_string(), "{sync}");
+
+    drop(stdin);
+    node.wait().unwrap();
+    let exported = requests.recv_timeout(Duration::from_secs(10)).expect("no spans were exported");
+    assert!(exported.starts_with("POST /v1/traces"), "{exported}");
+}
+
+#[test]
+fn without_an_endpoint_nothing_changes() {
+    let mut node =
+        Command::new(env!("CARGO_BIN_EXE_broadcast"))
+        .args(["--stride", "2", "--tick-rate-ms", "50"])
+        .env("TOKIO_WORKER_THREADS", "2")
+        .env("METRICS_INTERVAL_MS", "0")
+        .env_remove("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .env_remove("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = node.stdin.take().unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"]}}}}"#).unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "topology", "msg_id": 2, "topology": {{}}}}}}"#).unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "broadcast", "msg_id": 3, "message": 5}}}}"#).unwrap();
+    let sync = BufReader::new(node.stdout.take().unwrap()).lines()
+        .map(|line| serde_json::from_str::<Value>(&line.unwrap()).unwrap())
+        .find(|envelope| envelope["body"]["type"] == "sync")
+        .unwrap();
+    assert!(sync["body"].get("traceparent").is_none(), "{sync}");
+    drop(stdin);
+    node.wait().unwrap();
+}
@@ -0,0 +1,58 @@
+//! Messages journaled in an outbox until they're acknowledged, and sent again after a
+//! restart if they weren't.
+
+use std::path::Path;
+use solutions::{message::{Body, Envelope}, outbox::Outbox, storage::FileStorage, workloads::broadcast::Payload};
+use tokio::sync::mpsc;
+
+
+type SyncOutbox = Outbox<Payload, FileStorage>;
+
+fn open(dir: &Path) -> SyncOutbox {
+    Outbox::open(FileStorage::open(dir).unwrap()).unwrap()
+}
+
+fn sync(msg_id: usize, messages: Vec<usize>) -> Envelope<Payload> {
+    Envelope::new("n1", "n2", Body { msg_id: Some(msg_id), in_reply_to: None, message: Payload::Sync { messages, updates: vec![] } })
+}
+
+fn msg_ids<'a>(envelopes: impl Iterator<Item = &'a Envelope<Payload>>) -> Vec<usize> {
+    envelopes.map(|envelope| envelope.msg_id().unwrap()).collect()
+}
+
+#[test]
+fn unacknowledged_messages_are_resent_after_a_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let (writer, mut sent) = mpsc::unbounded_channel();
+    {
+        let mut outbox = open(dir.path());
+        for msg_id in 1..=3 {
+            outbox.send(sync(msg_id, vec![msg_id * 10]), &writer).unwrap();
+        }
+        assert!(outbox.acknowledge(2).unwrap());
+        assert!(!outbox.acknowledge(2).unwrap());
+        assert_eq!(std::iter::from_fn(|| sent.try_recv().ok()).count(), 3);
+    }
+
+    // Killed, and back up.
+    let outbox = open(dir.path());
+    assert_eq!((msg_ids(outbox.pending()), outbox.last_msg_id()), (vec![1, 3], Some(3)));
+    assert_eq!(outbox.resend(&writer), 2);
+    let resent: Vec<Envelope<Payload>> = std::iter::from_fn(|| sent.try_recv().ok()).collect();
+    assert_eq!(msg_ids(resent.iter()), [1, 3]);
+    assert!(matches!(&resent[1].body.message, Payload::Sync { messages, .. } if messages == &[30]));
+}
+
+#[test]
+fn a_drained_outbox_leaves_nothing_to_recover() {
+    let dir = tempfile::tempdir().unwrap();
+    let (writer, _sent) = mpsc::unbounded_channel();
+    let mut outbox = open(dir.path());
+    outbox.send(sync(1, vec![10]), &writer).unwrap();
+    outbox.acknowledge(1).unwrap();
+
+    let storage = FileStorage::open(dir.path()).unwrap();
+    assert_eq!(std::fs::metadata(storage.wal_path()).unwrap().len(), 0);
+    let recovered: Outbox<Payload, FileStorage> = Outbox::open(storage).unwrap();
+    assert_eq!(recovered.pending().count(), 0);
+}
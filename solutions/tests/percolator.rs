@@ -0,0 +1,141 @@
+//! Percolator transactions against a fake lin-kv and lin-tso: committing, rolling back
+//! a transaction that ran into a conflict, and resolving locks other transactions left
+//! behind, including ones whose transaction ran out of time.
+
+mod common;
+
+use std::{sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+use serde_json::{json, Value};
+use solutions::{clock::ManualClock, kv::KvClient, percolator::{Cell, Lock, Percolator, Version}, txn::{MicroOp, TxnError}};
+use common::Store;
+
+
+const TTL: Duration = Duration::from_secs(1);
+
+/// A client for the lin-kv `store` (and a lin-tso) that tells the time by `clock`.
+fn percolator(store: &Store, clock: &ManualClock) -> Percolator<Value> {
+    // Well past the transactions the tests make up.
+    store.lock().unwrap().insert(common::TSO.to_owned(), json!(1000));
+    let rpc = common::lin_kv("n1", store);
+    Percolator::new(KvClient::lin_kv(rpc.clone(), Duration::from_secs(1)), KvClient::lin_tso(rpc, Duration::from_secs(1)))
+        .with_lock_ttl(TTL)
+        .with_clock(Arc::new(clock.clone()))
+}
+
+fn cell(store: &Store, key: usize) -> Cell {
+    store.lock().unwrap().get(&json!(format!("percolator-{key}")).to_string()).map(|cell| serde_json::from_value(cell.clone()).unwrap()).unwrap_or_default()
+}
+
+fn put(store: &Store, key: usize, cell: Cell) {
+    store.lock().unwrap().insert(json!(format!("percolator-{key}")).to_string(), serde_json::to_value(cell).unwrap());
+}
+
+/// A lock some other transaction (started at 100) took on `key`, good for another `ttl`.
+fn locked(key: usize, primary: usize, value: usize, ttl: Duration) -> Cell {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    Cell { lock: Some(Lock { primary, start_ts: 100, value, expires_at: now + ttl.as_millis() as u64 }), writes: vec![version(key, 1)], horizon: 0 }
+}
+
+fn version(value: usize, commit_ts: u64) -> Version {
+    Version { start_ts: commit_ts - 1, commit_ts, value, committed_at: 0 }
+}
+
+fn r(key: usize) -> MicroOp {
+    MicroOp::Read { key, value: None }
+}
+
+fn w(key: usize, value: usize) -> MicroOp {
+    MicroOp::Write { key, value }
+}
+
+fn read(key: usize, value: Option<usize>) -> MicroOp {
+    MicroOp::Read { key, value }
+}
+
+
+#[tokio::test]
+async fn a_committed_transaction_is_seen_whole_by_later_ones() {
+    let (store, clock) = (Store::default(), ManualClock::new());
+    let percolator = percolator(&store, &clock);
+
+    percolator.execute(vec![w(1, 10), w(2, 20), r(1)]).await.unwrap();
+    let done = percolator.execute(vec![r(1), r(2), r(3)]).await.unwrap();
+    assert_eq!(done, vec![read(1, Some(10)), read(2, Some(20)), read(3, None)]);
+    assert_eq!(cell(&store, 2).lock, None);
+}
+
+
+#[tokio::test]
+async fn a_transaction_that_runs_into_a_live_lock_rolls_back_its_own() {
+    let (store, clock) = (Store::default(), ManualClock::new());
+    let percolator = percolator(&store, &clock);
+    put(&store, 2, locked(2, 2, 99, TTL));
+
+    let err = percolator.execute(vec![w(1, 10), w(2, 20)]).await.unwrap_err();
+    assert!(matches!(err, TxnError::Conflict(_)), "expected a conflict, got {err:?}");
+    assert_eq!(cell(&store, 1), Cell::default());
+    assert_eq!(cell(&store, 2).lock.map(|lock| (lock.start_ts, lock.value)), Some((100, 99)));
+}
+
+
+#[tokio::test]
+async fn leftover_secondary_locks_follow_their_primary() {
+    let (store, clock) = (Store::default(), ManualClock::new());
+    let percolator = percolator(&store, &clock);
+    // Txn 100's primary (key 1) committed at 101; txn 200's primary (key 3) was rolled back.
+    put(&store, 1, Cell { lock: None, writes: vec![version(1, 2), Version { start_ts: 100, commit_ts: 101, value: 10, committed_at: 0 }], horizon: 0 });
+    put(&store, 2, locked(2, 1, 20, TTL));
+    put(&store, 4, Cell { lock: Some(Lock { start_ts: 200, ..locked(4, 3, 40, TTL).lock.unwrap() }), writes: vec![version(4, 2)], horizon: 0 });
+
+    let done = percolator.execute(vec![r(2), r(4)]).await.unwrap();
+    assert_eq!(done, vec![read(2, Some(20)), read(4, Some(4))]);
+    assert_eq!(cell(&store, 2).lock, None);
+    assert_eq!(cell(&store, 4), Cell { lock: None, writes: vec![version(4, 2)], horizon: 0 });
+}
+
+
+#[tokio::test]
+async fn a_transaction_that_outlives_its_locks_is_rolled_back() {
+    let (store, clock) = (Store::default(), ManualClock::new());
+    let percolator = percolator(&store, &clock);
+    // Txn 100 locked both keys, with key 1 as its primary, and was never heard from again.
+    put(&store, 1, locked(1, 1, 10, TTL));
+    put(&store, 2, locked(2, 1, 20, TTL));
+
+    let err = percolator.execute(vec![r(2)]).await.unwrap_err();
+    assert!(matches!(err, TxnError::Conflict(_)), "expected a conflict, got {err:?}");
+
+    clock.advance(TTL * 2);
+    let done = percolator.execute(vec![r(2)]).await.unwrap();
+    assert_eq!(done, vec![read(2, Some(2))]);
+    assert_eq!(cell(&store, 1).lock, None);
+    assert_eq!(cell(&store, 2).lock, None);
+
+    // With its primary rolled back, nothing of txn 100 is ever committed.
+    percolator.execute(vec![w(1, 11)]).await.unwrap();
+    let done = percolator.execute(vec![r(1), r(2)]).await.unwrap();
+    assert_eq!(done, vec![read(1, Some(11)), read(2, Some(2))]);
+}
+
+
+#[tokio::test]
+async fn overwritten_versions_are_dropped_once_they_are_old_enough() {
+    const HISTORY: Duration = Duration::from_secs(10);
+    let (store, clock) = (Store::default(), ManualClock::new());
+    let percolator = percolator(&store, &clock).with_history(HISTORY);
+    percolator.execute(vec![w(1, 10)]).await.unwrap();
+    percolator.execute(vec![w(1, 11)]).await.unwrap();
+
+    // 11 is the newest version old enough to drop, so it's kept for snapshots taken
+    // since; 10 isn't needed by any of them.
+    clock.advance(HISTORY * 2);
+    percolator.execute(vec![w(1, 12)]).await.unwrap();
+    let cell = cell(&store, 1);
+    assert_eq!(cell.writes.iter().map(|version| version.value).collect::<Vec<_>>(), vec![11, 12]);
+    assert_eq!(cell.horizon, cell.writes[0].commit_ts);
+
+    // A snapshot older than what's left conflicts, rather than reading nothing.
+    put(&store, 2, Cell { lock: None, writes: vec![version(20, 5000)], horizon: 5000 });
+    let err = percolator.execute(vec![r(2)]).await.unwrap_err();
+    assert!(matches!(err, TxnError::Conflict(_)), "expected a conflict, got {err:?}");
+}
@@ -0,0 +1,96 @@
+//! Property-based tests of envelope (de)serialization and CRDT convergence, using
+//! the strategies from `solutions::testing`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use proptest::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use solutions::{message::Envelope, testing::{assert_converges, assert_round_trips, envelope, json_value, node_id, op_sequences, Crdt}};
+
+
+/// Shaped like the workloads' payloads, with a bit of everything serde has to handle.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Payload {
+    Init { node_id: String, node_ids: Vec<String> },
+    InitOk,
+    Add { delta: u64 },
+    Read {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    ReadOk { value: Value },
+    Error { code: usize, text: String },
+}
+
+fn payload() -> impl Strategy<Value = Payload> {
+    prop_oneof![
+        (node_id(), prop::collection::vec(node_id(), 0..5)).prop_map(|(node_id, node_ids)| Payload::Init { node_id, node_ids }),
+        Just(Payload::InitOk),
+        any::<u64>().prop_map(|delta| Payload::Add { delta }),
+        proptest::option::of("[a-z]{1,4}").prop_map(|key| Payload::Read { key }),
+        json_value().prop_map(|value| Payload::ReadOk { value }),
+        (any::<usize>(), ".*").prop_map(|(code, text)| Payload::Error { code, text }),
+    ]
+}
+
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct GCounter(BTreeMap<usize, u64>);
+
+impl Crdt for GCounter {
+    /// (replica, delta)
+    type Op = (usize, u64);
+
+    fn apply(&mut self, &(replica, delta): &Self::Op) {
+        *self.0.entry(replica).or_default() += delta;
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (&replica, &count) in &other.0 {
+            let ours = self.0.entry(replica).or_default();
+            *ours = count.max(*ours);
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct GSet(BTreeSet<u8>);
+
+impl Crdt for GSet {
+    type Op = u8;
+
+    fn apply(&mut self, op: &u8) {
+        self.0.insert(*op);
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.0.extend(&other.0);
+    }
+}
+
+
+proptest! {
+    #[test]
+    fn envelopes_round_trip_through_json(envelope in envelope(payload())) {
+        assert_round_trips::<Envelope<Payload>>(&envelope)?;
+    }
+
+    #[test]
+    fn g_counters_converge(deltas in op_sequences(0..100u64, 3, 20)) {
+        // Each replica only ever bumps its own count.
+        let ops: Vec<Vec<_>> =
+            deltas
+            .into_iter()
+            .enumerate()
+            .map(|(replica, deltas)| deltas.into_iter().map(|delta| (replica, delta)).collect())
+            .collect();
+        assert_converges(&GCounter::default(), &ops)?;
+    }
+
+    #[test]
+    fn g_sets_converge(ops in op_sequences(any::<u8>(), 4, 20)) {
+        assert_converges(&GSet::default(), &ops)?;
+    }
+}
@@ -0,0 +1,82 @@
+//! Parses proxy rules, applies them to a stream of messages, and runs the `proxy` binary in front of the echo node.
+
+use std::{io::{BufRead, BufReader, Write}, process::{Command, Stdio}, time::Duration};
+use serde_json::{json, Value};
+use solutions::{message::RawEnvelope, proxy::{Action, Direction, Rule, Rules, Verdict}};
+
+
+fn message(src: &str, dest: &str, kind: &str) -> RawEnvelope {
+    serde_json::from_value(json!({"src": src, "dest": dest, "body": {"type": kind}})).unwrap()
+}
+
+
+#[test]
+fn rules_parse_from_their_short_form() {
+    let rule: Rule = "delay 1.5s type=gossip src=n1 dest=n2 dir=out p=0.5 after=3 count=2".parse().unwrap();
+    assert_eq!(rule.action, Action::Delay(Duration::from_millis(1500)));
+    assert_eq!((rule.kind.as_deref(), rule.source.as_deref(), rule.destination.as_deref()), (Some("gossip"), Some("n1"), Some("n2")));
+    assert_eq!((rule.direction, rule.probability, rule.after, rule.count), (Some(Direction::Out), 0.5, 3, Some(2)));
+    assert_eq!("delay 20".parse::<Rule>().unwrap().action, Action::Delay(Duration::from_millis(20)));
+
+    for bad in ["", "corrupt", "delay", "delay 5m", "drop p=2", "drop dir=sideways", "drop nth=1", "drop type"] {
+        assert!(bad.parse::<Rule>().is_err(), "{bad}");
+    }
+}
+
+#[test]
+fn rules_fire_on_the_window_of_matches_they_name() {
+    let mut rules = Rules::new(vec![
+        "drop type=gossip src=n1 after=1 count=2".parse().unwrap(),
+        "delay 50ms dir=in".parse().unwrap(),
+    ], 0);
+    let gossip = message("n1", "n2", "gossip");
+    let verdicts: Vec<Verdict> = (0..4).map(|_| rules.decide(Direction::Out, &gossip)).collect();
+    assert_eq!(verdicts, vec![Verdict::Forward, Verdict::Drop, Verdict::Drop, Verdict::Forward]);
+    assert_eq!(rules.decide(Direction::Out, &message("n2", "n1", "gossip")), Verdict::Forward);
+    assert_eq!(rules.decide(Direction::In, &message("c1", "n1", "read")), Verdict::Delay(Duration::from_millis(50)));
+
+    let mut coin = Rules::new(vec!["drop p=0.5".parse().unwrap()], 42);
+    let dropped = (0..1000).filter(|_| coin.decide(Direction::In, &gossip) == Verdict::Drop).count();
+    assert!((400..600).contains(&dropped), "{dropped}");
+}
+
+#[test]
+fn proxy_drops_and_records_in_front_of_a_node() {
+    let record = tempfile::NamedTempFile::new().unwrap();
+    let mut proxy =
+        Command::new(env!("CARGO_BIN_EXE_proxy"))
+        .arg("--record").arg(record.path())
+        .args(["--rule", "drop type=echo after=1 count=1", "--rule", "delay 20ms dir=out"])
+        .arg(env!("CARGO_BIN_EXE_echo"))
+        .env("TOKIO_WORKER_THREADS", "2")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = proxy.stdin.take().unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}}}"#).unwrap();
+    for msg_id in 2..5 {
+        writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "echo", "msg_id": {msg_id}, "echo": "hi"}}}}"#).unwrap();
+    }
+    // The node winds down as soon as its input closes, so wait for its replies first.
+    let replies: Vec<Value> =
+        BufReader::new(proxy.stdout.take().unwrap())
+        .lines()
+        .take(3)
+        .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+        .collect();
+    drop(stdin);
+    proxy.wait().unwrap();
+
+    let mut answered: Vec<u64> = replies.iter().filter_map(|reply| reply["body"]["in_reply_to"].as_u64()).collect();
+    answered.sort();
+    assert_eq!(answered, vec![1, 2, 4]);
+
+    let recorded: Vec<Value> = std::fs::read_to_string(record.path()).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(recorded.len(), 7);
+    let dropped: Vec<&Value> = recorded.iter().filter(|record| record["dropped"] == true).collect();
+    assert_eq!(dropped.len(), 1);
+    assert_eq!(dropped[0]["body"]["msg_id"], 3);
+    assert!(recorded.iter().filter(|record| record["direction"] == "out").all(|record| record["delay_ms"] == 20.));
+}
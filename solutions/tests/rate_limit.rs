@@ -0,0 +1,85 @@
+//! Token buckets, and holding a node's output to the limits it's given.
+
+use std::{io::Write, process::{Command, Stdio}, time::{Duration, Instant}};
+use solutions::rate_limit::{Backlog, Limits, RateLimiter, TokenBucket};
+
+
+#[test]
+fn buckets_allow_a_seconds_burst_then_refill_at_their_rate() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(4., start);
+    for _ in 0..4 {
+        assert_eq!(bucket.wait(start), Duration::ZERO);
+        bucket.take();
+    }
+    assert_eq!(bucket.wait(start), Duration::from_millis(250));
+    assert_eq!(bucket.wait(start + Duration::from_millis(100)), Duration::from_millis(150));
+    assert_eq!(bucket.wait(start + Duration::from_millis(250)), Duration::ZERO);
+
+    // Slower than a message a second still lets one through at a time.
+    let mut slow = TokenBucket::new(0.5, start);
+    assert_eq!(slow.wait(start), Duration::ZERO);
+    slow.take();
+    assert_eq!(slow.wait(start), Duration::from_secs(2));
+}
+
+#[test]
+fn destinations_have_buckets_of_their_own_under_the_global_one() {
+    let now = Instant::now();
+    let mut limiter = RateLimiter::new(Limits { global: Some(3.), per_destination: Some(1.) });
+    assert_eq!(limiter.take("n2", now), Ok(()));
+    assert_eq!(limiter.take("n2", now), Err(Duration::from_secs(1)));
+    assert_eq!(limiter.take("n3", now), Ok(()));
+    assert_eq!(limiter.take("c1", now), Ok(()));
+    // The global bucket is empty now, and nothing was taken from n4's.
+    assert!(limiter.take("n4", now).is_err());
+    assert_eq!(limiter.take("n4", now + Duration::from_secs(1)), Ok(()));
+}
+
+#[test]
+fn nodes_send_no_faster_than_their_limit() {
+    let mut input = r#"{"src": "c1", "dest": "n1", "body": {"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}"#.to_owned();
+    for msg_id in 2..=12 {
+        input += &format!("\n{{\"src\": \"c1\", \"dest\": \"n1\", \"body\": {{\"type\": \"echo\", \"msg_id\": {msg_id}, \"echo\": \"{msg_id}\"}}}}");
+    }
+    let started = Instant::now();
+    let mut node =
+        Command::new(env!("CARGO_BIN_EXE_echo"))
+        .args(["--rate-limit", "10"])
+        .env("METRICS_INTERVAL_MS", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    writeln!(node.stdin.take().unwrap(), "{input}").unwrap();
+    let output = node.wait_with_output().unwrap();
+    // 12 replies, 10 of them right away and the other 2 at 10 a second.
+    assert_eq!(String::from_utf8(output.stdout).unwrap().lines().count(), 12);
+    assert!(started.elapsed() >= Duration::from_millis(200), "{:?}", started.elapsed());
+}
+
+#[test]
+fn a_destination_over_its_limit_only_holds_up_its_own_messages() {
+    let now = Instant::now();
+    let mut limiter = RateLimiter::new(Limits { global: None, per_destination: Some(1.) });
+    let mut backlog = Backlog::new();
+    assert_eq!(backlog.offer(&mut limiter, "n2", 1, now), Some(1));
+    assert_eq!(backlog.offer(&mut limiter, "n2", 2, now), None);
+    assert_eq!(backlog.offer(&mut limiter, "n2", 3, now), None);
+    // n3 has tokens to spare, and nothing queued ahead of it.
+    assert_eq!(backlog.offer(&mut limiter, "n3", 4, now), Some(4));
+    assert_eq!(backlog.len(), 2);
+
+    assert_eq!(backlog.next_due(), Some(now + Duration::from_secs(1)));
+    assert_eq!(backlog.pop(&mut limiter, now), None);
+    let later = now + Duration::from_secs(1);
+    assert_eq!(backlog.pop(&mut limiter, later), Some(2));
+    assert_eq!(backlog.pop(&mut limiter, later), None);
+    // Until 3 has gone, later messages to n2 line up behind it, tokens or not.
+    assert_eq!(backlog.offer(&mut limiter, "n2", 5, now + Duration::from_secs(5)), None);
+    assert_eq!(backlog.pop(&mut limiter, now + Duration::from_secs(5)), Some(3));
+    assert_eq!(backlog.pop(&mut limiter, now + Duration::from_secs(6)), Some(5));
+    assert!(backlog.is_empty());
+    assert_eq!(backlog.next_due(), None);
+}
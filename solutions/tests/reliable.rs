@@ -0,0 +1,64 @@
+//! Messages between two nodes over a network that drops and reorders them are still
+//! delivered, once each and in order.
+
+use std::time::{Duration, Instant};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use solutions::reliable::{Frame, Session};
+
+
+#[test]
+fn gaps_are_filled_and_delivery_is_in_order() {
+    let start = Instant::now();
+    let (mut n1, mut n2) = (Session::<u64>::new(Duration::from_millis(100)), Session::<u64>::new(Duration::from_millis(100)));
+    // 2, 4 and 5 go missing, and 6 overtakes 3.
+    let sent: Vec<Frame<u64>> = (1..=6).map(|message| n1.send("n2", message, start)).collect();
+    let mut delivered = vec![];
+    let mut nacks = vec![];
+    for index in [0, 5, 2] {
+        let received = n2.receive("n1", sent[index].clone(), start);
+        delivered.extend(received.delivered);
+        nacks.extend(received.replies);
+    }
+    assert_eq!(delivered, [1]);
+    assert_eq!(nacks, [Frame::Nack { from: 2, to: 5 }, Frame::Nack { from: 2, to: 2 }]);
+
+    let resent = n1.receive("n2", nacks[0].clone(), start).replies;
+    assert_eq!(resent.len(), 4);
+    for frame in resent {
+        delivered.extend(n2.receive("n1", frame, start).delivered);
+    }
+    assert_eq!(delivered, [1, 2, 3, 4, 5, 6]);
+}
+
+#[test]
+fn everything_gets_through_a_lossy_network_in_order() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut now = Instant::now();
+    let mut sessions = [Session::<u64>::new(Duration::from_millis(50)), Session::<u64>::new(Duration::from_millis(50))];
+    let names = ["n1", "n2"];
+    let mut in_flight: Vec<(usize, Frame<u64>)> = (1..=200).map(|message| (1, sessions[0].send("n2", message, now))).collect();
+    let mut delivered = vec![];
+
+    for _ in 0..1000 {
+        in_flight.shuffle(&mut rng);
+        for (to, frame) in std::mem::take(&mut in_flight) {
+            // A third of everything, data and acks and nacks alike, is lost.
+            if rng.gen_bool(1. / 3.) {
+                continue;
+            }
+            let from = 1 - to;
+            let received = sessions[to].receive(names[from], frame, now);
+            if to == 1 {
+                delivered.extend(received.delivered);
+            }
+            in_flight.extend(received.replies.into_iter().map(|reply| (from, reply)));
+        }
+        now += Duration::from_millis(10);
+        in_flight.extend(sessions[0].retransmits(now).into_iter().map(|(_, frame)| (1, frame)));
+        if delivered.len() == 200 && sessions[0].unacked("n2") == 0 {
+            break;
+        }
+    }
+    assert_eq!(delivered, (1..=200).collect::<Vec<u64>>());
+    assert_eq!(sessions[0].unacked("n2"), 0);
+}
@@ -0,0 +1,72 @@
+//! Translates REPL shorthand into envelopes, and drives the echo node through the `repl` binary.
+
+use std::{io::Write, process::{Command, Stdio}};
+use serde_json::{json, Value};
+use solutions::repl::Shorthand;
+
+
+fn parse(shorthand: &mut Shorthand, line: &str) -> Value {
+    serde_json::to_value(shorthand.parse(line).unwrap().unwrap()).unwrap()
+}
+
+
+#[test]
+fn shorthand_fills_in_positional_fields_and_numbers_messages() {
+    let mut shorthand = Shorthand::new("c1");
+    assert_eq!(
+        parse(&mut shorthand, "init n2 [n1,n2]"),
+        json!({"src": "c1", "dest": "n2", "body": {"type": "init", "msg_id": 1, "node_id": "n2", "node_ids": ["n1", "n2"]}}),
+    );
+    assert_eq!(
+        parse(&mut shorthand, "broadcast 42"),
+        json!({"src": "c1", "dest": "n2", "body": {"type": "broadcast", "msg_id": 2, "message": 42}}),
+    );
+    assert_eq!(
+        parse(&mut shorthand, "@n1 txn [[r, 1, null],[w,1,6]]"),
+        json!({"src": "c1", "dest": "n1", "body": {"type": "txn", "msg_id": 3, "txn": [["r", 1, null], ["w", 1, 6]]}}),
+    );
+    assert_eq!(
+        parse(&mut shorthand, r#"topology {"n1": ["n2"], "n2": ["n1"]}"#),
+        json!({"src": "c1", "dest": "n2", "body": {"type": "topology", "msg_id": 4, "topology": {"n1": ["n2"], "n2": ["n1"]}}}),
+    );
+    assert_eq!(
+        parse(&mut shorthand, r#"echo "hello there" extra=true"#),
+        json!({"src": "c1", "dest": "n2", "body": {"type": "echo", "msg_id": 5, "echo": "hello there", "extra": true}}),
+    );
+}
+
+#[test]
+fn shorthand_takes_raw_json_and_rejects_nonsense() {
+    let mut shorthand = Shorthand::new("c1");
+    assert_eq!(
+        parse(&mut shorthand, r#"{"type": "read", "msg_id": 10}"#),
+        json!({"src": "c1", "dest": "n1", "body": {"type": "read", "msg_id": 10}}),
+    );
+    assert_eq!(
+        parse(&mut shorthand, r#"{"src": "n2", "dest": "n1", "body": {"type": "gossip"}}"#),
+        json!({"src": "n2", "dest": "n1", "body": {"type": "gossip"}}),
+    );
+    assert!(shorthand.parse("  # a comment").unwrap().is_none());
+    assert!(shorthand.parse("broadcast 1 2").is_err());
+    assert!(shorthand.parse("txn [[r,1,null]").is_err());
+    assert!(shorthand.parse("{not json").is_err());
+}
+
+#[test]
+fn repl_talks_to_a_node() {
+    let mut repl =
+        Command::new(env!("CARGO_BIN_EXE_repl"))
+        .arg(env!("CARGO_BIN_EXE_echo"))
+        .env("TOKIO_WORKER_THREADS", "2")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    repl.stdin.take().unwrap().write_all(b"init n1 [n1]\necho hi\n").unwrap();
+    let output = repl.wait_with_output().unwrap();
+    let output = String::from_utf8(output.stdout).unwrap();
+    assert!(output.contains(r#""type": "init_ok""#), "{output}");
+    assert!(output.contains(r#""echo": "hi""#), "{output}");
+    assert!(output.contains(r#""in_reply_to": 2"#), "{output}");
+}
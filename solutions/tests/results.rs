@@ -0,0 +1,100 @@
+//! Parses EDN, and the results Maelstrom leaves in its store, using the fixture
+//! stores in `tests/fixtures/store`.
+
+use std::time::Duration;
+use solutions::{edn::{self, Edn}, results::{StoreRun, Target, Validity}};
+
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/store/{name}", env!("CARGO_MANIFEST_DIR"))
+}
+
+
+#[test]
+fn edn_reads_clojure_data() {
+    let value = edn::parse(r#"
+        ; comment
+        {:a [1 -2 +3 4.5 1/4 12N 1.5M], "b" #{nil true \c \newline}, :c/d (sym ##Inf) #_ :ignored :e #inst "2024-01-01", :f "tab\thereé"}
+    "#).unwrap();
+
+    assert_eq!(value.get("a"), Some(&Edn::Vector(vec![
+        Edn::Int(1), Edn::Int(-2), Edn::Int(3), Edn::Float(4.5), Edn::Float(0.25), Edn::Int(12), Edn::Float(1.5),
+    ])));
+    assert_eq!(value.as_map().unwrap()[1], (
+        Edn::String("b".into()),
+        Edn::Set(vec![Edn::Nil, Edn::Bool(true), Edn::Char('c'), Edn::Char('\n')]),
+    ));
+    assert_eq!(value.get("c/d"), Some(&Edn::List(vec![Edn::Symbol("sym".into()), Edn::Float(f64::INFINITY)])));
+    assert_eq!(value.get("e"), Some(&Edn::Tagged("inst".into(), Box::new(Edn::String("2024-01-01".into())))));
+    assert_eq!(value.get("f").and_then(Edn::as_str), Some("tab\there\u{e9}"));
+}
+
+
+#[test]
+fn edn_rejects_malformed_input() {
+    for input in ["{:a 1", "{:a}", "[1 2))", "\"unterminated", "#", "1 2"] {
+        assert!(edn::parse(input).is_err(), "{input}");
+    }
+}
+
+
+#[test]
+fn store_run_reads_a_passing_broadcast_test() {
+    let run = StoreRun::load(fixture("broadcast-efficient")).unwrap();
+    let results = &run.results;
+
+    assert_eq!((results.valid, results.workload_valid), (Validity::Valid, Validity::Valid));
+    let stats = results.stats.unwrap();
+    assert_eq!((stats.count, stats.ok_count, stats.info_count), (1973, 1973, 0));
+    let net = results.net.unwrap();
+    assert_eq!(net.servers.msg_count, 29170);
+    assert_eq!(net.servers.msgs_per_op, Some(14.784592));
+    assert_eq!(net.clients.msgs_per_op, None);
+    assert_eq!(results.stable_latency(0.5), Some(Duration::from_millis(772)));
+    assert_eq!(results.stable_latency(1.), Some(Duration::from_millis(901)));
+
+    let latencies = run.latencies.unwrap();
+    assert_eq!(latencies.points.len(), 4);
+    assert_eq!(latencies.points[0].f.as_deref(), Some("broadcast"));
+    assert_eq!(latencies.percentiles(Some("read")).unwrap().max, Duration::from_micros(800));
+
+    let messages = run.messages.unwrap();
+    assert_eq!((messages.total, messages.between_servers, messages.with_clients), (6, 2, 4));
+    assert_eq!(messages.by_type["sync"], 1);
+}
+
+
+#[test]
+fn store_run_reads_a_failing_test_without_the_optional_files() {
+    let run = StoreRun::load(fixture("counter-invalid")).unwrap();
+
+    assert_eq!((run.results.valid, run.results.workload_valid), (Validity::Invalid, Validity::Invalid));
+    assert_eq!(run.results.stats, None);
+    assert_eq!(run.results.net.unwrap().all.msgs_per_op, Some(f64::INFINITY));
+    assert_eq!((run.latencies, run.messages), (None, None));
+}
+
+
+#[test]
+fn broadcast_run_meets_3e_but_not_3d() {
+    let run = StoreRun::load(fixture("broadcast-efficient")).unwrap();
+
+    let analysis = run.analyze(Target::named("3e").unwrap());
+    assert!(analysis.passed(), "{:?}", analysis.misses);
+    assert_eq!(analysis.msgs_per_op, Some(14.784592));
+    assert_eq!((analysis.median_latency, analysis.max_latency), (Some(Duration::from_millis(772)), Some(Duration::from_millis(901))));
+
+    let analysis = run.analyze(Target::BROADCAST_3D);
+    assert!(!analysis.passed());
+    assert_eq!(analysis.misses.len(), 2, "{:?}", analysis.misses);
+}
+
+
+#[test]
+fn invalid_runs_and_missing_measurements_miss_every_target() {
+    let run = StoreRun::load(fixture("counter-invalid")).unwrap();
+
+    let analysis = run.analyze(Target::BROADCAST_3E);
+    // Invalid, infinitely many msgs per op, and no latencies at all.
+    assert_eq!(analysis.misses.len(), 4, "{:?}", analysis.misses);
+}
@@ -0,0 +1,84 @@
+//! Forwarding requests to the node that handles them, and getting the replies back to
+//! the client.
+
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use solutions::{message::Envelope, routing::{Inbound, Routed, Router, RoutingError}};
+use tokio::time::Instant;
+
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Payload {
+    Read { key: u64 },
+    ReadOk { value: u64 },
+}
+
+/// Send `envelope` over the wire, as JSON.
+fn wire<M: Serialize>(envelope: Envelope<M>) -> Envelope<Routed<Payload>> {
+    serde_json::from_str(&serde_json::to_string(&envelope).unwrap()).unwrap()
+}
+
+fn handle(inbound: Inbound<Payload>) -> Envelope<Payload> {
+    match inbound {
+        Inbound::Handle(envelope) => envelope,
+        other => panic!("expected a request to handle, got {other:?}"),
+    }
+}
+
+fn send(inbound: Inbound<Payload>) -> Envelope<Routed<Payload>> {
+    match inbound {
+        Inbound::Send(envelope) => envelope,
+        other => panic!("expected a reply to send on, got {other:?}"),
+    }
+}
+
+#[test]
+fn replies_find_their_way_back_to_the_client() {
+    let now = Instant::now();
+    let (mut n1, mut n2, mut n3) = (Router::default(), Router::default(), Router::default());
+    let request: Envelope<Payload> = serde_json::from_str(r#"{"src": "c1", "dest": "n1", "body": {"type": "read", "key": 7, "msg_id": 5}}"#).unwrap();
+
+    // n1 and n2 don't have key 7; n3 does.
+    let at_n1 = handle(n1.receive(wire(request), now));
+    let at_n2 = handle(n2.receive(wire(n1.forward(at_n1, "n2", 100, now).unwrap()), now));
+    let at_n3 = handle(n3.receive(wire(n2.forward(at_n2, "n3", 200, now).unwrap()), now));
+    assert_eq!((&*at_n3.source, &*at_n3.destination, at_n3.msg_id()), ("c1", "n3", Some(5)));
+    assert_eq!(at_n3.body.message, Payload::Read { key: 7 });
+
+    let reply = n3.send(at_n3.reply_with(Some(300), Payload::ReadOk { value: 42 }));
+    let reply = send(n2.receive(wire(reply), now));
+    let reply = send(n1.receive(wire(reply), now));
+    assert_eq!((&*reply.source, &*reply.destination, reply.body.in_reply_to), ("n1", "c1", Some(5)));
+    assert_eq!(reply.body.message.message, Payload::ReadOk { value: 42 });
+    let json = serde_json::to_value(&reply).unwrap();
+    assert!(json["body"].get("route").is_none(), "{json}");
+    assert_eq!((n1.pending(), n2.pending(), n3.pending()), (0, 0, 0));
+}
+
+#[test]
+fn requests_stop_being_forwarded_once_out_of_hops() {
+    let now = Instant::now();
+    let (mut n1, mut n2) = (Router::new(1, Duration::from_secs(1)), Router::new(1, Duration::from_secs(1)));
+    let request: Envelope<Payload> = serde_json::from_str(r#"{"src": "c1", "dest": "n1", "body": {"type": "read", "key": 7, "msg_id": 1}}"#).unwrap();
+
+    let at_n2 = handle(n2.receive(wire(n1.forward(request, "n2", 10, now).unwrap()), now));
+    assert_eq!(n2.forward(at_n2.clone(), "n1", 20, now).unwrap_err(), RoutingError::TtlExpired);
+
+    // The error reply still goes back through n1.
+    let reply = send(n1.receive(wire(n2.send(at_n2.reply_with(None, Payload::ReadOk { value: 0 }))), now));
+    assert_eq!((&*reply.destination, reply.body.in_reply_to), ("c1", Some(1)));
+}
+
+#[test]
+fn replies_to_forgotten_requests_are_dropped() {
+    let now = Instant::now();
+    let (mut n1, mut n2) = (Router::new(4, Duration::from_secs(1)), Router::default());
+    let request: Envelope<Payload> = serde_json::from_str(r#"{"src": "c1", "dest": "n1", "body": {"type": "read", "key": 7, "msg_id": 1}}"#).unwrap();
+    let at_n2 = handle(n2.receive(wire(n1.forward(request, "n2", 10, now).unwrap()), now));
+
+    assert_eq!(n1.expire(now + Duration::from_millis(999)), 0);
+    assert_eq!(n1.expire(now + Duration::from_secs(1)), 1);
+    let reply = n2.send(at_n2.reply_with(None, Payload::ReadOk { value: 0 }));
+    assert!(matches!(n1.receive(wire(reply), now), Inbound::Drop));
+}
@@ -0,0 +1,67 @@
+//! Replies that find nobody waiting on them: late ones, duplicates, and ones to calls
+//! whose caller went away.
+
+mod common;
+
+use std::{future::Future, pin::Pin, sync::Arc, task::Poll, time::Duration};
+use solutions::{clock::ManualClock, message::{Body, Envelope}, metrics, rpc::{RpcClient, RpcError}};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use common::message_id;
+
+
+fn client() -> (RpcClient<String>, UnboundedReceiver<Envelope<String>>, ManualClock) {
+    let (writer, sent) = mpsc::unbounded_channel();
+    let rpc = RpcClient::new(writer, message_id);
+    rpc.set_node_id("n1");
+    let clock = ManualClock::new();
+    rpc.set_clock(Arc::new(clock.clone()));
+    (rpc, sent, clock)
+}
+
+fn reply_to(request: &Envelope<String>, message: &str) -> Envelope<String> {
+    Envelope::new(&request.destination, &request.source, Body { msg_id: None, in_reply_to: request.msg_id(), message: message.to_owned() })
+}
+
+async fn is_pending<F: Future + Unpin>(future: &mut F) -> bool {
+    std::future::poll_fn(|cx| Poll::Ready(Pin::new(&mut *future).poll(cx).is_pending())).await
+}
+
+#[tokio::test]
+async fn late_and_duplicate_replies_are_dropped() {
+    let (rpc, mut sent, clock) = client();
+    let orphans = metrics::global().snapshot().counter("rpc.orphan_replies");
+
+    let mut call = Box::pin(rpc.call("lin-kv", "read".to_owned(), Duration::from_millis(100)));
+    assert!(is_pending(&mut call).await);
+    let request = sent.try_recv().unwrap();
+    assert!(rpc.try_complete(reply_to(&request, "read_ok")).is_none());
+    assert_eq!(call.await.unwrap().body.message, "read_ok");
+    // The same reply again.
+    assert!(rpc.try_complete(reply_to(&request, "read_ok")).is_none());
+
+    let mut call = Box::pin(rpc.call("lin-kv", "read".to_owned(), Duration::from_millis(100)));
+    assert!(is_pending(&mut call).await);
+    let request = sent.try_recv().unwrap();
+    clock.advance(Duration::from_millis(100));
+    assert_eq!(call.await.unwrap_err(), RpcError::Timeout);
+    // Too late.
+    assert!(rpc.try_complete(reply_to(&request, "read_ok")).is_none());
+
+    assert!(metrics::global().snapshot().counter("rpc.orphan_replies") >= orphans + 2);
+    // A reply to something the client never sent is the handlers' business.
+    let unrelated = Envelope::new("n2", "n1", Body { msg_id: None, in_reply_to: Some(1 << 40), message: "cas_ok".to_owned() });
+    assert!(rpc.try_complete(unrelated).is_some());
+}
+
+#[tokio::test]
+async fn calls_whose_caller_went_away_are_forgotten() {
+    let (rpc, mut sent, _clock) = client();
+    let mut call = Box::pin(rpc.call("lin-kv", "read".to_owned(), Duration::from_secs(1)));
+    assert!(is_pending(&mut call).await);
+    let request = sent.try_recv().unwrap();
+    assert_eq!(rpc.in_flight(), 1);
+
+    drop(call);
+    assert_eq!((rpc.collect_garbage(), rpc.in_flight()), (1, 0));
+    assert!(rpc.try_complete(reply_to(&request, "read_ok")).is_none());
+}
@@ -0,0 +1,43 @@
+//! How round trip estimates, and the timeouts that follow from them, track the replies.
+
+mod common;
+
+use std::time::Duration;
+use solutions::rtt::{RttEstimator, MAX_TIMEOUT, MIN_TIMEOUT};
+use common::millis;
+
+
+#[test]
+fn timeouts_follow_round_trips_within_bounds() {
+    let mut rtt = RttEstimator::new();
+    assert_eq!(rtt.timeout(millis(1000)), millis(1000));
+
+    // 100ms, give or take 50ms.
+    rtt.sample(millis(100));
+    assert_eq!((rtt.smoothed(), rtt.timeout(millis(1000))), (Some(millis(100)), millis(300)));
+    // Steady replies narrow it down.
+    for _ in 0..50 {
+        rtt.sample(millis(100));
+    }
+    assert!(rtt.timeout(millis(1000)) < millis(110));
+
+    let mut quick = RttEstimator::new();
+    quick.sample(millis(1));
+    assert_eq!(quick.timeout(millis(1000)), MIN_TIMEOUT);
+    let mut slow = RttEstimator::new();
+    slow.sample(Duration::from_secs(10));
+    assert_eq!(slow.timeout(millis(1000)), MAX_TIMEOUT);
+}
+
+#[test]
+fn a_slower_link_raises_the_timeout() {
+    let mut rtt = RttEstimator::new();
+    for _ in 0..20 {
+        rtt.sample(millis(100));
+    }
+    let before = rtt.timeout(millis(1000));
+    for _ in 0..5 {
+        rtt.sample(millis(400));
+    }
+    assert!(rtt.timeout(millis(1000)) > before * 2, "{:?} vs {before:?}", rtt.timeout(millis(1000)));
+}
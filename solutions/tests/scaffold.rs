@@ -0,0 +1,40 @@
+//! Generates a workload skeleton into a scratch crate layout.
+
+use std::fs;
+use solutions::scaffold::{generate, is_valid_name};
+
+
+#[test]
+fn names_must_be_snake_case() {
+    assert!(is_valid_name("lin_kv") && is_valid_name("raft2"));
+    for bad in ["", "LinKv", "lin-kv", "2pc", "_kv", "mod", "kv/raft"] {
+        assert!(!is_valid_name(bad), "{bad}");
+    }
+}
+
+#[test]
+fn generates_a_workload_and_declares_it() {
+    let root = tempfile::tempdir().unwrap();
+    for dir in ["src/workloads", "src/bin", "tests"] {
+        fs::create_dir_all(root.path().join(dir)).unwrap();
+    }
+    fs::write(root.path().join("src/workloads.rs"), "use std::future::Future;\n\npub mod echo;\npub mod broadcast;\n\n\npub fn run() {}\n").unwrap();
+
+    let files = generate(root.path(), "lin_kv").unwrap();
+    assert_eq!(files.len(), 3);
+    let bin = fs::read_to_string(root.path().join("src/bin/lin_kv.rs")).unwrap();
+    assert!(bin.contains("workloads::run(opts.common.clone(), lin_kv::server(opts))"), "{bin}");
+    let test = fs::read_to_string(root.path().join("tests/lin_kv.rs")).unwrap();
+    assert!(test.contains("workloads::lin_kv::{serve, Opts, Payload}") && !test.contains("{{"), "{test}");
+    assert!(fs::read_to_string(root.path().join("src/workloads/lin_kv.rs")).unwrap().contains("pub async fn serve("));
+    assert_eq!(
+        fs::read_to_string(root.path().join("src/workloads.rs")).unwrap(),
+        "use std::future::Future;\n\npub mod echo;\npub mod broadcast;\npub mod lin_kv;\n\n\npub fn run() {}\n",
+    );
+
+    // A second run leaves everything alone.
+    fs::write(root.path().join("src/bin/lin_kv.rs"), "// edited").unwrap();
+    assert!(generate(root.path(), "lin_kv").is_err());
+    assert_eq!(fs::read_to_string(root.path().join("src/bin/lin_kv.rs")).unwrap(), "// edited");
+    assert!(generate(root.path(), "Lin-Kv").is_err());
+}
@@ -0,0 +1,115 @@
+//! Periodic jobs: when they run, and changing or stopping them while they do.
+
+mod common;
+
+use std::{sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Duration};
+use solutions::{clock::ManualClock, scheduler::{Job, JobHandle, Period, Scheduler}, tuning};
+use common::millis;
+
+
+/// Let the jobs woken up by the clock run.
+async fn settle() {
+    for _ in 0..10 {
+        tokio::task::yield_now().await;
+    }
+}
+
+fn counting(scheduler: &Scheduler, job: Job) -> (Arc<AtomicUsize>, JobHandle) {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let counted = runs.clone();
+    let handle = scheduler.spawn(job, move |_| {
+        counted.fetch_add(1, Ordering::SeqCst);
+    });
+    (runs, handle)
+}
+
+#[tokio::test]
+async fn jobs_run_every_period_until_cancelled() {
+    let clock = ManualClock::new();
+    let scheduler = Scheduler::with_clock(Arc::new(clock.clone()));
+    let (runs, gossip) = counting(&scheduler, Job::new("gossip", Period::Every(millis(100))));
+    settle().await;
+
+    for expected in 1..=3 {
+        clock.advance(millis(100));
+        settle().await;
+        assert_eq!(runs.load(Ordering::SeqCst), expected);
+    }
+    assert_eq!(scheduler.job("gossip").unwrap().name(), "gossip");
+
+    gossip.cancel();
+    settle().await;
+    clock.advance(millis(1000));
+    settle().await;
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+    assert!(scheduler.job("gossip").is_none());
+}
+
+#[tokio::test]
+async fn jobs_at_the_tick_rate_pick_up_a_new_one_at_their_next_run() {
+    tuning::global().set_tick_rate(millis(100));
+    let clock = ManualClock::new();
+    let scheduler = Scheduler::with_clock(Arc::new(clock.clone()));
+    let (runs, _gossip) = counting(&scheduler, Job::new("gossip", Period::TickRate));
+    settle().await;
+    clock.advance(millis(100));
+    settle().await;
+
+    // The wait already under way keeps to the old tick rate.
+    tuning::global().set_tick_rate(millis(1000));
+    clock.advance(millis(100));
+    settle().await;
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+    clock.advance(millis(500));
+    settle().await;
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+    clock.advance(millis(500));
+    settle().await;
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn a_new_period_takes_effect_straight_away() {
+    let clock = ManualClock::new();
+    let scheduler = Scheduler::with_clock(Arc::new(clock.clone()));
+    let (runs, commit) = counting(&scheduler, Job::new("commit", Period::Every(millis(1000))));
+    settle().await;
+
+    clock.advance(millis(500));
+    commit.set_period(Period::Every(millis(100)));
+    settle().await;
+    clock.advance(millis(100));
+    settle().await;
+    assert_eq!((runs.load(Ordering::SeqCst), commit.period()), (1, Period::Every(millis(100))));
+}
+
+#[tokio::test]
+async fn a_job_taking_over_a_name_stops_the_old_one() {
+    let clock = ManualClock::new();
+    let scheduler = Scheduler::with_clock(Arc::new(clock.clone()));
+    let (old, _) = counting(&scheduler, Job::new("anti-entropy", Period::Every(millis(100))));
+    let (new, _) = counting(&scheduler, Job::new("anti-entropy", Period::Every(millis(100))));
+    settle().await;
+
+    clock.advance(millis(100));
+    settle().await;
+    assert_eq!((old.load(Ordering::SeqCst), new.load(Ordering::SeqCst)), (0, 1));
+}
+
+#[tokio::test(start_paused = true)]
+async fn jitter_spreads_runs_around_the_period() {
+    let scheduler = Scheduler::new();
+    let times = Arc::new(std::sync::Mutex::new(vec![]));
+    let recorded = times.clone();
+    scheduler.spawn(Job::new("heartbeat", Period::Every(millis(100))).jitter(0.5), move |now| {
+        recorded.lock().unwrap().push(now);
+    });
+    let start = scheduler.clock().now();
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    let times = times.lock().unwrap();
+    let gaps: Vec<Duration> = std::iter::once(start).chain(times.iter().copied()).zip(times.iter()).map(|(a, b)| *b - a).collect();
+    assert!(gaps.len() > 60, "{} runs", gaps.len());
+    assert!(gaps.iter().all(|gap| *gap >= millis(50) && *gap <= millis(150)), "{gaps:?}");
+    assert!(gaps.iter().any(|gap| *gap != gaps[0]), "runs should be jittered");
+}
@@ -0,0 +1,63 @@
+//! Checking a node's wiring before it starts.
+
+use std::{io::Write, process::{Command, Stdio}};
+use clap::Parser;
+use serde_json::{json, Value};
+use solutions::{conformance::Workload, io::io_channel, message::Envelope, self_test, workloads::{broadcast, echo}};
+
+
+/// A broadcast node that forgot about `topology`.
+async fn forgetful_server() {
+    let (writer, mut reader, _) = io_channel::<Envelope<Value>>();
+    while let Some(envelope) = reader.recv().await {
+        if envelope.body.message["type"] == "init" {
+            writer.send(envelope.reply_with(Some(1), json!({"type": "init_ok"}))).unwrap();
+        }
+    }
+}
+
+#[test]
+fn self_tests_catch_wiring_mistakes() {
+    // One at a time: each check takes over the loopback transport.
+    assert_eq!(self_test::check(Workload::Echo, || echo::server(Default::default())), Vec::<String>::new());
+    let opts = broadcast::Opts::parse_from(["broadcast", "--stride", "1"]);
+    assert_eq!(self_test::check(Workload::Broadcast, move || broadcast::server(opts)), Vec::<String>::new());
+
+    let problems = self_test::check(Workload::Broadcast, forgetful_server);
+    assert_eq!(problems.first().map(String::as_str), Some("topology #2: no reply within 2s"), "{problems:?}");
+}
+
+#[test]
+fn nodes_that_fail_their_self_test_never_start() {
+    let output =
+        Command::new(env!("CARGO_BIN_EXE_broadcast"))
+        .args(["--stride", "0", "--self-test"])
+        .env("METRICS_INTERVAL_MS", "0")
+        .env("RUST_BACKTRACE", "0")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+    assert!(stderr.contains("self-test: topology #2: the node stopped without replying"), "{stderr}");
+
+    let mut node =
+        Command::new(env!("CARGO_BIN_EXE_echo"))
+        .arg("--self-test")
+        .env("METRICS_INTERVAL_MS", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = node.stdin.take().unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}}}"#).unwrap();
+    drop(stdin);
+    let output = node.wait_with_output().unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("self-test passed"), "{stderr}");
+    // Only what the real node did is counted.
+    assert!(stderr.contains("metrics: messages_in.init=1 messages_out.init_ok=1"), "{stderr}");
+    assert!(String::from_utf8(output.stdout).unwrap().contains("init_ok"));
+}
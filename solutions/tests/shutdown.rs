@@ -0,0 +1,48 @@
+//! Stopping a node with a signal.
+
+use std::{io::{BufRead, BufReader, Read, Write}, process::{Command, Stdio}};
+use serde_json::Value;
+
+
+#[test]
+fn nodes_flush_and_dump_metrics_on_sigterm() {
+    let directory = tempfile::tempdir().unwrap();
+    let recording = directory.path().join("recording.jsonl");
+    let mut node =
+        Command::new(env!("CARGO_BIN_EXE_broadcast"))
+        .args(["--stride", "1", "--record"])
+        .arg(&recording)
+        .env("METRICS_INTERVAL_MS", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = node.stdin.take().unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"]}}}}"#).unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "topology", "msg_id": 2, "topology": {{}}}}}}"#).unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "broadcast", "msg_id": 3, "message": 7}}}}"#).unwrap();
+    let mut stdout = BufReader::new(node.stdout.take().unwrap());
+    let mut line = String::new();
+    for _ in 1..=3 {
+        line.clear();
+        stdout.read_line(&mut line).unwrap();
+    }
+
+    // Stdin stays open: only the signal tells it to stop.
+    let killed = Command::new("kill").args(["-TERM", &node.id().to_string()]).status().unwrap();
+    assert!(killed.success());
+    let status = node.wait().unwrap();
+    assert!(status.success(), "{status}");
+    drop(stdin);
+
+    let mut rest = String::new();
+    stdout.read_to_string(&mut rest).unwrap();
+    assert!(rest.lines().all(|line| serde_json::from_str::<Value>(line).is_ok()), "{rest}");
+    let mut stderr = String::new();
+    node.stderr.take().unwrap().read_to_string(&mut stderr).unwrap();
+    assert!(stderr.contains("metrics: ") && stderr.contains("messages_in.broadcast=1"), "{stderr}");
+    let recorded = std::fs::read_to_string(&recording).unwrap();
+    assert!(recorded.ends_with('\n'), "{recorded}");
+    assert!(recorded.lines().all(|line| serde_json::from_str::<Value>(line).is_ok()), "{recorded}");
+}
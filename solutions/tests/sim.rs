@@ -0,0 +1,363 @@
+//! Runs small echo-style nodes on the in-memory network and checks that latency,
+//! loss, duplication and reordering behave as configured, and that a seed replays
+//! the exact same run, that partitions hold and heal, and that skewed clocks skew.
+//! Everything runs on tokio's paused clock, so no test actually waits on real time.
+
+use std::{collections::BTreeSet, sync::{Arc, Mutex}, time::Duration};
+use solutions::{message::{Body, Envelope}, sim::{Clock, ClockSkew, Fault, Latency, Network, NetworkConfig}};
+use tokio::{sync::mpsc::{UnboundedReceiver, UnboundedSender}, time::Instant};
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Payload {
+    Echo { echo: usize },
+    EchoOk { echo: usize },
+    Add { value: usize },
+    Gossip { values: BTreeSet<usize> },
+    Write { value: usize },
+    Replicate { timestamp: u64, node: String, value: usize },
+}
+
+
+async fn echo_server(writer: UnboundedSender<Envelope<Payload>>, mut reader: UnboundedReceiver<Envelope<Payload>>) {
+    while let Some(envelope) = reader.recv().await {
+        if let Payload::Echo { echo } = envelope.body.message {
+            writer.send(envelope.reply_with(None, Payload::EchoOk { echo })).unwrap();
+        }
+    }
+}
+
+fn echo(source: &str, destination: &str, echo: usize) -> Envelope<Payload> {
+    Envelope::new(source, destination, Body { msg_id: Some(echo), in_reply_to: None, message: Payload::Echo { echo } })
+}
+
+/// Everything that arrives within `window`.
+async fn drain(reader: &mut UnboundedReceiver<Envelope<Payload>>, window: Duration) -> Vec<Envelope<Payload>> {
+    let mut received = vec![];
+    while let Ok(Some(envelope)) = tokio::time::timeout(window, reader.recv()).await {
+        received.push(envelope);
+    }
+    received
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn echoes_round_trip_between_nodes() {
+    let network = Network::new(NetworkConfig {
+        latency: Latency::Uniform { min: Duration::from_millis(1), max: Duration::from_millis(10) },
+        ..Default::default()
+    });
+    for node in ["n1", "n2", "n3"] {
+        network.spawn(node, echo_server);
+    }
+    let (client, mut replies) = network.connect("c1");
+
+    for (index, node) in ["n1", "n2", "n3"].into_iter().enumerate() {
+        client.send(echo("c1", node, index)).unwrap();
+    }
+    let mut echoed: Vec<usize> =
+        drain(&mut replies, Duration::from_millis(100))
+        .await
+        .into_iter()
+        .map(|envelope| match envelope.body.message {
+            Payload::EchoOk { echo } => echo,
+            other => panic!("unexpected reply {other:?}"),
+        })
+        .collect();
+    echoed.sort();
+
+    assert_eq!(echoed, vec![0, 1, 2]);
+    let stats = network.stats();
+    assert_eq!((stats.sent, stats.delivered, stats.dropped), (6, 6, 0));
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn lossy_network_drops_messages() {
+    let network = Network::new(NetworkConfig { loss: 1., ..Default::default() });
+    network.spawn("n1", echo_server);
+    let (client, mut replies) = network.connect("c1");
+
+    for index in 0..10 {
+        client.send(echo("c1", "n1", index)).unwrap();
+    }
+
+    assert!(drain(&mut replies, Duration::from_millis(50)).await.is_empty());
+    assert_eq!(network.stats().dropped, 10);
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn duplicating_network_delivers_twice() {
+    let network = Network::new(NetworkConfig { duplication: 1., ..Default::default() });
+    network.spawn("n1", echo_server);
+    let (client, mut replies) = network.connect("c1");
+
+    client.send(echo("c1", "n1", 7)).unwrap();
+
+    // The request arrives twice, and each of the two replies is duplicated too.
+    let replies = drain(&mut replies, Duration::from_millis(50)).await;
+    assert_eq!(replies.len(), 4);
+    assert!(replies.iter().all(|envelope| envelope.body.message == Payload::EchoOk { echo: 7 }));
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn links_stay_fifo_unless_reordering_is_allowed() {
+    let latency = Latency::Uniform { min: Duration::ZERO, max: Duration::from_millis(20) };
+
+    let network = Network::new(NetworkConfig { latency, reorder: false, ..Default::default() });
+    let (client, _) = network.connect("c1");
+    let (_, mut sink) = network.connect("sink");
+    for index in 0..200 {
+        client.send(echo("c1", "sink", index)).unwrap();
+    }
+    let order: Vec<_> = drain(&mut sink, Duration::from_millis(100)).await.into_iter().map(|envelope| envelope.body.msg_id.unwrap()).collect();
+    assert_eq!(order, (0..200).collect::<Vec<_>>());
+
+    let network = Network::new(NetworkConfig { latency, reorder: true, seed: Some(1), ..Default::default() });
+    let (client, _) = network.connect("c1");
+    let (_, mut sink) = network.connect("sink");
+    for index in 0..200 {
+        client.send(echo("c1", "sink", index)).unwrap();
+    }
+    let order: Vec<_> = drain(&mut sink, Duration::from_millis(100)).await.into_iter().map(|envelope| envelope.body.msg_id.unwrap()).collect();
+    assert_eq!(order.len(), 200);
+    assert_ne!(order, (0..200).collect::<Vec<_>>());
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn messages_to_unknown_nodes_are_unroutable() {
+    let network = Network::<Payload>::new(NetworkConfig::default());
+    let (client, _) = network.connect("c1");
+
+    client.send(echo("c1", "nobody", 0)).unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    assert_eq!(network.stats().unroutable, 1);
+}
+
+
+/// Every reply the client sees, with the virtual time it arrived at.
+async fn run_faulty_echoes(seed: u64) -> Vec<(Duration, usize)> {
+    let network = Network::new(NetworkConfig {
+        latency: Latency::Exponential { mean: Duration::from_millis(5) },
+        loss: 0.2,
+        duplication: 0.1,
+        reorder: true,
+        seed: Some(seed),
+    });
+    for node in ["n1", "n2", "n3"] {
+        network.spawn(node, echo_server);
+    }
+    let (client, mut replies) = network.connect("c1");
+
+    let start = Instant::now();
+    for index in 0..100 {
+        client.send(echo("c1", ["n1", "n2", "n3"][index % 3], index)).unwrap();
+    }
+    let mut received = vec![];
+    while let Ok(Some(envelope)) = tokio::time::timeout(Duration::from_millis(500), replies.recv()).await {
+        received.push((start.elapsed(), envelope.body.in_reply_to.unwrap()));
+    }
+    received
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn same_seed_replays_the_same_run() {
+    let first = run_faulty_echoes(42).await;
+    let second = run_faulty_echoes(42).await;
+    let other = run_faulty_echoes(43).await;
+
+    assert!(!first.is_empty());
+    assert_eq!(first, second);
+    assert_ne!(first, other);
+}
+
+
+type Seen = Arc<Mutex<BTreeSet<usize>>>;
+
+/// Remembers every value it's been given and periodically pushes everything it knows
+/// to all of its peers, so the whole cluster converges once messages get through.
+async fn gossip_server(
+    id: &'static str,
+    peers: Vec<&'static str>,
+    seen: Seen,
+    writer: UnboundedSender<Envelope<Payload>>,
+    mut reader: UnboundedReceiver<Envelope<Payload>>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_millis(10));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let values = seen.lock().unwrap().clone();
+                for peer in &peers {
+                    let body = Body { msg_id: None, in_reply_to: None, message: Payload::Gossip { values: values.clone() } };
+                    writer.send(Envelope::new(id, peer, body)).unwrap();
+                }
+            },
+            received = reader.recv() => match received.map(|envelope| envelope.body.message) {
+                Some(Payload::Add { value }) => { seen.lock().unwrap().insert(value); },
+                Some(Payload::Gossip { values }) => seen.lock().unwrap().extend(values),
+                Some(_) => {},
+                None => break,
+            },
+        }
+    }
+}
+
+fn start_gossip_cluster(network: &Network<Payload>, nodes: &[&'static str]) -> Vec<Seen> {
+    nodes
+        .iter()
+        .map(|&node| {
+            let seen = Seen::default();
+            let peers = nodes.iter().copied().filter(|&peer| peer != node).collect();
+            let state = seen.clone();
+            network.spawn(node, move |writer, reader| gossip_server(node, peers, state, writer, reader));
+            seen
+        })
+        .collect()
+}
+
+fn add(destination: &str, value: usize) -> Envelope<Payload> {
+    Envelope::new("c1", destination, Body { msg_id: None, in_reply_to: None, message: Payload::Add { value } })
+}
+
+fn snapshot(seen: &[Seen]) -> Vec<BTreeSet<usize>> {
+    seen.iter().map(|seen| seen.lock().unwrap().clone()).collect()
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn gossip_converges_once_a_partition_heals() {
+    let network = Network::new(NetworkConfig {
+        latency: Latency::Uniform { min: Duration::from_millis(1), max: Duration::from_millis(5) },
+        seed: Some(7),
+        ..Default::default()
+    });
+    let seen = start_gossip_cluster(&network, &["n1", "n2", "n3"]);
+    let (client, _) = network.connect("c1");
+
+    network.schedule(vec![
+        (Duration::ZERO, Fault::partition(&[&["n1"], &["n2", "n3"]])),
+        (Duration::from_millis(200), Fault::Heal),
+    ]);
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    client.send(add("n1", 1)).unwrap();
+    client.send(add("n2", 2)).unwrap();
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let split = snapshot(&seen);
+    assert_eq!(split[0], BTreeSet::from([1]));
+    assert_eq!(split[1], BTreeSet::from([2]));
+    assert_eq!(split[2], BTreeSet::from([2]));
+    assert!(network.stats().partitioned > 0);
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(snapshot(&seen).iter().all(|values| *values == BTreeSet::from([1, 2])));
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn asymmetric_partitions_only_cut_one_direction() {
+    let network = Network::new(NetworkConfig { seed: Some(7), ..Default::default() });
+    let seen = start_gossip_cluster(&network, &["n1", "n2"]);
+    let (client, _) = network.connect("c1");
+
+    network.inject(Fault::block("n1", "n2"));
+    assert!(network.is_blocked("n1", "n2"));
+    assert!(!network.is_blocked("n2", "n1"));
+    client.send(add("n1", 1)).unwrap();
+    client.send(add("n2", 2)).unwrap();
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let values = snapshot(&seen);
+    assert_eq!(values[0], BTreeSet::from([1, 2]));
+    assert_eq!(values[1], BTreeSet::from([2]));
+
+    network.inject(Fault::Heal);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(snapshot(&seen).iter().all(|values| *values == BTreeSet::from([1, 2])));
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn clocks_run_with_their_configured_offset_and_drift() {
+    let network = Network::<Payload>::new(NetworkConfig::default());
+    network.set_clock_skew("ahead", ClockSkew { offset_ms: 100, drift: 0. });
+    network.set_clock_skew("behind", ClockSkew { offset_ms: -100, drift: 0. });
+    network.set_clock_skew("fast", ClockSkew { offset_ms: 0, drift: 0.5 });
+    let clocks = ["true", "ahead", "behind", "fast"].map(|node| network.clock(node));
+
+    let readings = |clocks: &[Clock]| clocks.iter().map(|clock| clock.now_millis() + 100 - Clock::EPOCH_MS).collect::<Vec<_>>();
+    assert_eq!(readings(&clocks), vec![100, 200, 0, 100]);
+
+    tokio::time::sleep(Duration::from_millis(1000)).await;
+    assert_eq!(readings(&clocks), vec![1100, 1200, 1000, 1600]);
+}
+
+
+type Register = Arc<Mutex<Option<(u64, String, usize)>>>;
+
+/// A last-write-wins register: every write is stamped with this node's wall clock and
+/// replicated to its peers, and everyone keeps the write with the largest stamp.
+async fn lww_server(
+    id: &'static str,
+    peers: Vec<&'static str>,
+    clock: Clock,
+    register: Register,
+    writer: UnboundedSender<Envelope<Payload>>,
+    mut reader: UnboundedReceiver<Envelope<Payload>>,
+) {
+    while let Some(envelope) = reader.recv().await {
+        let (timestamp, node, value) = match envelope.body.message {
+            Payload::Write { value } => {
+                let timestamp = clock.now_millis();
+                for peer in &peers {
+                    let message = Payload::Replicate { timestamp, node: id.to_owned(), value };
+                    writer.send(Envelope::new(id, peer, Body { msg_id: None, in_reply_to: None, message })).unwrap();
+                }
+                (timestamp, id.to_owned(), value)
+            },
+            Payload::Replicate { timestamp, node, value } => (timestamp, node, value),
+            _ => continue,
+        };
+        let mut register = register.lock().unwrap();
+        if register.as_ref().map_or(true, |current| (timestamp, &node) > (current.0, &current.1)) {
+            *register = Some((timestamp, node, value));
+        }
+    }
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn lww_register_loses_the_newer_write_to_a_clock_running_ahead() {
+    let network = Network::new(NetworkConfig { seed: Some(7), ..Default::default() });
+    network.set_clock_skew("n1", ClockSkew { offset_ms: 500, drift: 0. });
+    let registers: Vec<Register> =
+        ["n1", "n2"]
+        .into_iter()
+        .map(|node| {
+            let register = Register::default();
+            let peers = vec![if node == "n1" { "n2" } else { "n1" }];
+            let (clock, state) = (network.clock(node), register.clone());
+            network.spawn(node, move |writer, reader| lww_server(node, peers, clock, state, writer, reader));
+            register
+        })
+        .collect();
+    let (client, _) = network.connect("c1");
+
+    let write = |destination: &str, value| Envelope::new("c1", destination, Body { msg_id: None, in_reply_to: None, message: Payload::Write { value } });
+    client.send(write("n1", 1)).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    client.send(write("n2", 2)).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // n2's write really happened 100ms later, but n1's clock is 500ms ahead, so its
+    // older write carries the larger stamp and wins everywhere.
+    for register in registers {
+        assert_eq!(register.lock().unwrap().as_ref().map(|(_, node, value)| (node.as_str(), *value)), Some(("n1", 1)));
+    }
+}
@@ -0,0 +1,127 @@
+//! `FileStorage`'s write-ahead log: sequence numbers, reopening a directory that already
+//! has a log in it, and recovering what was appended.
+
+use std::fs;
+use serde_json::Value;
+use solutions::storage::{FileStorage, Recovered, Storage};
+
+
+type Ledger = Vec<u64>;
+
+fn append(storage: &mut FileStorage, record: u64) -> u64 {
+    Storage::<Ledger, u64>::append(storage, &record).unwrap()
+}
+
+fn recover(storage: &mut FileStorage) -> Recovered<Ledger, u64> {
+    Storage::<Ledger, u64>::recover(storage).unwrap()
+}
+
+fn uncheckpointed(storage: &FileStorage) -> u64 {
+    Storage::<Ledger, u64>::uncheckpointed(storage)
+}
+
+fn seqs(storage: &FileStorage) -> Vec<u64> {
+    fs::read_to_string(storage.wal_path())
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap()["seq"].as_u64().unwrap())
+        .collect()
+}
+
+
+#[test]
+fn appends_get_consecutive_seqs() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut storage = FileStorage::open(dir.path()).unwrap();
+    assert_eq!((0..5).map(|record| append(&mut storage, record)).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+    assert_eq!(seqs(&storage), vec![1, 2, 3, 4, 5]);
+    assert_eq!(uncheckpointed(&storage), 5);
+}
+
+
+#[test]
+fn reopening_carries_on_from_the_last_seq_without_recovering() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut storage = FileStorage::open(dir.path()).unwrap();
+    for record in 0..3 {
+        append(&mut storage, record);
+    }
+    drop(storage);
+
+    let mut storage = FileStorage::open(dir.path()).unwrap();
+    assert_eq!(uncheckpointed(&storage), 3);
+    assert_eq!(append(&mut storage, 3), 4);
+    assert_eq!(seqs(&storage), vec![1, 2, 3, 4]);
+
+    let recovered = recover(&mut FileStorage::open(dir.path()).unwrap());
+    assert_eq!(recovered.records, vec![0, 1, 2, 3]);
+}
+
+
+#[test]
+fn reopening_after_a_checkpoint_carries_on_from_the_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut storage = FileStorage::open(dir.path()).unwrap();
+    for record in 0..3 {
+        append(&mut storage, record);
+    }
+    Storage::<Ledger, u64>::checkpoint(&mut storage, &vec![0, 1, 2]).unwrap();
+    drop(storage);
+
+    let mut storage = FileStorage::open(dir.path()).unwrap();
+    assert_eq!(uncheckpointed(&storage), 0);
+    assert_eq!(append(&mut storage, 3), 4);
+
+    let recovered = recover(&mut FileStorage::open(dir.path()).unwrap());
+    assert_eq!(recovered.snapshot, Some(vec![0, 1, 2]));
+    assert_eq!(recovered.records, vec![3]);
+}
+
+
+#[test]
+fn opening_drops_a_torn_tail_before_appending() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut storage = FileStorage::open(dir.path()).unwrap();
+    for record in 0..3 {
+        append(&mut storage, record);
+    }
+    let wal = storage.wal_path();
+    drop(storage);
+    let mut contents = fs::read(&wal).unwrap();
+    contents.extend_from_slice(b"{\"seq\":4,\"rec");
+    fs::write(&wal, contents).unwrap();
+
+    let mut storage = FileStorage::open(dir.path()).unwrap();
+    assert_eq!(append(&mut storage, 3), 4);
+    assert_eq!(seqs(&storage), vec![1, 2, 3, 4]);
+    assert_eq!(recover(&mut storage).records, vec![0, 1, 2, 3]);
+}
+
+
+#[test]
+fn opening_refuses_a_log_with_a_gap() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut storage = FileStorage::open(dir.path()).unwrap();
+    for record in 0..3 {
+        append(&mut storage, record);
+    }
+    let wal = storage.wal_path();
+    drop(storage);
+    let contents = fs::read_to_string(&wal).unwrap();
+    let without_second: Vec<&str> = contents.lines().enumerate().filter(|(index, _)| *index != 1).map(|(_, line)| line).collect();
+    fs::write(&wal, without_second.join("\n") + "\n").unwrap();
+
+    let err = FileStorage::open(dir.path()).expect_err("opened a wal with a gap");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+
+#[test]
+fn recovering_an_empty_directory_finds_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut storage = FileStorage::open(dir.path()).unwrap();
+    let recovered = recover(&mut storage);
+    assert_eq!(recovered.snapshot, None);
+    assert!(recovered.records.is_empty());
+    assert_eq!(append(&mut storage, 0), 1);
+}
@@ -0,0 +1,103 @@
+//! The overlays gossiping nodes pick their neighbors by.
+
+use std::{collections::HashMap, time::Duration};
+use rand::{rngs::StdRng, SeedableRng};
+use solutions::{message::{self, NodeId}, topology::{self, Overlay}};
+
+
+fn cluster(n: usize) -> Vec<NodeId> {
+    (1..=n).map(|i| message::intern(&format!("n{i}"))).collect()
+}
+
+fn neighbors(overlay: Overlay, node: &str, n: usize) -> Vec<String> {
+    overlay.neighbors(node, &cluster(n), &HashMap::new()).iter().map(|node| node.to_string()).collect()
+}
+
+/// Every node's neighbors, by position.
+fn graph(overlay: Overlay, n: usize) -> Vec<Vec<usize>> {
+    let nodes = cluster(n);
+    nodes.iter().map(|node| {
+        overlay.neighbors(node, &nodes, &HashMap::new()).iter().map(|neighbor| nodes.iter().position(|node| node == neighbor).unwrap()).collect()
+    }).collect()
+}
+
+fn is_connected(graph: &[Vec<usize>]) -> bool {
+    let mut reached = vec![false; graph.len()];
+    let mut frontier = vec![0];
+    reached[0] = true;
+    while let Some(node) = frontier.pop() {
+        for &neighbor in &graph[node] {
+            if !reached[neighbor] {
+                reached[neighbor] = true;
+                frontier.push(neighbor);
+            }
+        }
+    }
+    reached.into_iter().all(|reached| reached)
+}
+
+#[test]
+fn overlays_pick_the_neighbors_they_say() {
+    assert_eq!(neighbors(Overlay::Stride(2), "n1", 4), ["n2", "n4"]);
+    assert_eq!(neighbors(Overlay::Stride(1), "n1", 3), ["n2", "n3"]);
+    assert_eq!(neighbors(Overlay::Ring, "n1", 5), ["n2", "n5"]);
+    // 3 by 3.
+    assert_eq!(neighbors(Overlay::Grid, "n5", 9), ["n2", "n4", "n6", "n8"]);
+    assert_eq!(neighbors(Overlay::Grid, "n1", 9), ["n2", "n4"]);
+    assert_eq!(neighbors(Overlay::Tree(2), "n2", 7), ["n1", "n4", "n5"]);
+    assert_eq!(neighbors(Overlay::Hypercube, "n1", 8), ["n2", "n3", "n5"]);
+    assert!(neighbors(Overlay::Ring, "n9", 5).is_empty());
+
+    let topology = HashMap::from([("n1".to_owned(), vec!["n3".to_owned()])]);
+    assert_eq!(Overlay::Maelstrom.neighbors("n1", &cluster(3), &topology), [message::intern("n3")]);
+}
+
+#[test]
+fn symmetric_overlays_stay_connected_whatever_the_cluster_size() {
+    for n in 2..=25 {
+        for overlay in [Overlay::Ring, Overlay::Grid, Overlay::Tree(3), Overlay::Hypercube, Overlay::RandomRegular(4)] {
+            let graph = graph(overlay, n);
+            assert!(is_connected(&graph), "{overlay} on {n} nodes: {graph:?}");
+            for (node, neighbors) in graph.iter().enumerate() {
+                assert!(neighbors.iter().all(|&neighbor| neighbor != node && graph[neighbor].contains(&node)), "{overlay} on {n} nodes: {graph:?}");
+            }
+        }
+    }
+}
+
+#[test]
+fn random_overlays_agree_between_nodes_and_keep_to_their_degree() {
+    let graph = graph(Overlay::RandomRegular(4), 25);
+    assert_eq!(graph, self::graph(Overlay::RandomRegular(4), 25));
+    assert!(graph.iter().all(|neighbors| (2..=4).contains(&neighbors.len())), "{graph:?}");
+    assert!(graph.iter().filter(|neighbors| neighbors.len() == 4).count() >= 20, "{graph:?}");
+}
+
+#[test]
+fn overlays_parse_from_their_flag() {
+    for overlay in ["stride:3", "ring", "grid", "tree:2", "hypercube", "random:3", "nearest:3+1", "maelstrom"] {
+        assert_eq!(overlay.parse::<Overlay>().unwrap().to_string(), overlay);
+    }
+    assert!("tree:0".parse::<Overlay>().is_err());
+    assert!("nearest:3".parse::<Overlay>().is_err());
+    assert!("star".parse::<Overlay>().unwrap_err().contains("expected"));
+}
+
+#[test]
+fn nearest_overlays_prefer_the_quickest_round_trips_plus_a_few_long_links() {
+    let nodes = cluster(8);
+    let rtts = HashMap::from([
+        (message::intern("n5"), Duration::from_millis(10)),
+        (message::intern("n7"), Duration::from_millis(20)),
+        (message::intern("n2"), Duration::from_millis(300)),
+    ]);
+    let mut rng = StdRng::seed_from_u64(7);
+    let picked = topology::nearest("n1", &nodes, &rtts, 2, 2, &mut rng);
+    assert_eq!(picked.len(), 4);
+    assert!(picked.contains(&message::intern("n5")) && picked.contains(&message::intern("n7")), "{picked:?}");
+
+    // With nothing measured yet, the nearest are the closest in the ring.
+    let picked = topology::nearest("n1", &nodes, &HashMap::new(), 2, 0, &mut rng);
+    assert_eq!(picked, [message::intern("n2"), message::intern("n8")]);
+    assert_eq!(neighbors(Overlay::Nearest { near: 2, far: 0 }, "n3", 8), ["n2", "n4"]);
+}
@@ -0,0 +1,110 @@
+//! Following one client operation through the logs, from the span that handles it to the RPCs it causes.
+
+use std::{io::Write, sync::{Arc, Mutex}, time::Duration};
+use serde_json::{json, Value};
+use solutions::{io, message::{Body, Envelope}, rpc::RpcClient};
+use tokio::sync::mpsc::unbounded_channel;
+use tracing::{level_filters::LevelFilter, Instrument};
+
+
+/// Everything logged while it's the default subscriber, as plain text.
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Captured {
+    fn install(&self) -> tracing::subscriber::DefaultGuard {
+        let captured = self.clone();
+        let subscriber =
+            tracing_subscriber::fmt()
+            .with_max_level(LevelFilter::TRACE)
+            .with_ansi(false)
+            .with_writer(move || captured.clone())
+            .finish();
+        tracing::subscriber::set_default(subscriber)
+    }
+
+    fn lines(&self) -> Vec<String> {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap().lines().map(str::to_owned).collect()
+    }
+}
+
+fn message_id() -> usize {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static MSG_ID: AtomicUsize = AtomicUsize::new(100);
+    MSG_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+fn request(source: &str, msg_id: usize) -> Envelope<Value> {
+    Envelope::new(source, "n1", Body { msg_id: Some(msg_id), in_reply_to: None, message: json!({"type": "txn"}) })
+}
+
+/// The `id` its handler span recorded, from a line logged inside it.
+fn span_id(line: &str) -> u64 {
+    let start = line.find(" id=").unwrap_or_else(|| panic!("no span id in {line}")) + " id=".len();
+    line[start..].split(|c: char| !c.is_ascii_digit()).next().unwrap().parse().unwrap()
+}
+
+
+#[tokio::test]
+async fn rpcs_are_logged_with_the_span_that_caused_them() {
+    let captured = Captured::default();
+    let _guard = captured.install();
+    let (writer, mut sent) = unbounded_channel();
+    let rpc = RpcClient::new(writer, message_id);
+    rpc.set_node_id("n1");
+
+    // Two client operations in flight at once, each making a call to lin-kv.
+    let first = tokio::spawn({
+        let rpc = rpc.clone();
+        async move { rpc.call("lin-kv", json!({"type": "read", "key": 1}), Duration::from_secs(5)).await }.instrument(io::handler_span(&request("c1", 7)))
+    });
+    let second = tokio::spawn({
+        let rpc = rpc.clone();
+        async move { rpc.call("lin-kv", json!({"type": "read", "key": 2}), Duration::from_secs(5)).await }.instrument(io::handler_span(&request("c2", 7)))
+    });
+    for _ in 0..2 {
+        let call: Envelope<Value> = sent.recv().await.unwrap();
+        let reply = Envelope::new("lin-kv", "n1", Body { msg_id: None, in_reply_to: call.body.msg_id, message: json!({"type": "read_ok"}) });
+        assert!(rpc.try_complete(reply).is_none());
+    }
+    first.await.unwrap().unwrap();
+    second.await.unwrap().unwrap();
+
+    let lines = captured.lines();
+    for client in ["c1", "c2"] {
+        let ours: Vec<&String> = lines.iter().filter(|line| line.contains(&format!("handle{{src={client} msg_id=7 "))).collect();
+        let sending = ours.iter().find(|line| line.contains("sending rpc")).unwrap_or_else(|| panic!("{lines:#?}"));
+        let replied = ours.iter().find(|line| line.contains("got rpc reply")).unwrap_or_else(|| panic!("{lines:#?}"));
+        let id = span_id(sending);
+        assert!(sending.contains(&format!("parent={id}")), "{sending}");
+        assert!(replied.contains(&format!("parent={id}")), "{replied}");
+    }
+    let ids: Vec<u64> = ["c1", "c2"].iter().map(|client| {
+        span_id(lines.iter().find(|line| line.contains(&format!("handle{{src={client} "))).unwrap())
+    }).collect();
+    assert_ne!(ids[0], ids[1]);
+}
+
+#[test]
+fn rpcs_sent_outside_a_handler_have_no_parent() {
+    let captured = Captured::default();
+    let _guard = captured.install();
+    let (writer, _sent) = unbounded_channel();
+    let rpc = RpcClient::<Value>::new(writer, message_id);
+    rpc.send("n2", json!({"type": "sync"})).unwrap();
+
+    let lines = captured.lines();
+    let sending = lines.iter().find(|line| line.contains("sending message")).unwrap();
+    assert!(!sending.contains("parent="), "{sending}");
+    assert!(io::current_span_id().is_none());
+}
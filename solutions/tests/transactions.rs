@@ -0,0 +1,385 @@
+//! The transaction workload's local engine, at each of the isolation levels, concurrency
+//! controls and consistency levels it can be started with.
+
+mod common;
+
+use std::{collections::HashSet, io::{BufRead, BufReader, Write}, process::{Command, Stdio}, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, time::Duration};
+use clap::Parser;
+use solutions::{hash_ring::HashRing, kv::{KvClient, KvError}, lock_manager::{Acquire, LockError, LockManager, LockMode}, message::{Body, Envelope}, percolator::Percolator, rpc::RpcClient, scheduler::Scheduler, txn::{MicroOp, TxnError}, workloads::transactions::{self as txn, Opts, Payload, State, TxnId, FINISHED_TTL}};
+use tokio::{sync::mpsc, time::Instant};
+use common::message_id;
+
+
+fn r(key: usize) -> MicroOp {
+    MicroOp::Read { key, value: None }
+}
+
+fn w(key: usize, value: usize) -> MicroOp {
+    MicroOp::Write { key, value }
+}
+
+fn read(key: usize, value: Option<usize>) -> MicroOp {
+    MicroOp::Read { key, value }
+}
+
+/// A transaction older than any the node under test starts.
+const OLDER: TxnId = TxnId { seq: 0, node: 0 };
+
+fn id(seq: u64) -> TxnId {
+    TxnId { seq, node: 0 }
+}
+
+fn granted(acquired: Result<Acquire<TxnId>, LockError<TxnId>>) {
+    assert!(matches!(acquired, Ok(Acquire::Granted)), "expected the lock to be granted, got {acquired:?}");
+}
+
+/// What `execute_with_retries` needs besides the state and locks, for the local engine,
+/// which never sends anything.
+fn engine(args: &[&str]) -> (Opts, Percolator<Payload>, RpcClient<Payload>) {
+    let opts = Opts::try_parse_from([&["txn"], args].concat()).unwrap();
+    let rpc = RpcClient::new(mpsc::unbounded_channel().0, message_id);
+    let timeout = Duration::from_millis(opts.kv_timeout_ms);
+    let percolator = Percolator::new(KvClient::lin_kv(rpc.clone(), timeout), KvClient::lin_tso(rpc.clone(), timeout));
+    (opts, percolator, rpc)
+}
+
+/// Until `count` transactions are waiting on locks.
+async fn wait_for_waiters(locks: &Mutex<LockManager<usize, TxnId>>, count: usize) {
+    while locks.lock().unwrap().num_waiting() < count {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+}
+
+
+#[test]
+fn snapshot_transactions_read_their_own_writes() {
+    let state = Mutex::new(State::default());
+    let done = txn::execute_snapshot(&state, vec![r(1), w(1, 5), r(1)]).unwrap();
+    assert_eq!(done, vec![read(1, None), w(1, 5), read(1, Some(5))]);
+
+    let done = txn::execute_snapshot(&state, vec![r(1)]).unwrap();
+    assert_eq!(done, vec![read(1, Some(5))]);
+}
+
+
+#[test]
+fn the_first_of_two_snapshot_transactions_to_write_a_key_wins() {
+    let mut state = State::default();
+    let first = state.begin_snapshot();
+    let second = state.begin_snapshot();
+
+    state.commit_snapshot(first, vec![(1, 10)]).unwrap();
+    let err = state.commit_snapshot(second, vec![(1, 20)]).unwrap_err();
+    assert!(matches!(err, TxnError::Conflict(_)), "{err:?}");
+    assert_eq!(err.code().code(), 30);
+    // Writes to keys nobody else wrote go through: snapshot isolation allows write skew.
+    state.commit_snapshot(second, vec![(2, 20)]).unwrap();
+    state.end_snapshot(first);
+    state.end_snapshot(second);
+
+    assert_eq!(state.read_only(vec![r(1), r(2)], true), vec![read(1, Some(10)), read(2, Some(20))]);
+}
+
+
+#[test]
+fn a_snapshot_held_open_conflicts_with_writes_committed_since() {
+    let state = Mutex::new(State::default());
+    txn::execute_snapshot(&state, vec![w(1, 1)]).unwrap();
+    let start_ts = state.lock().unwrap().begin_snapshot();
+    // Each of these commits (and garbage-collects behind it) while the snapshot is open.
+    for value in 2..5 {
+        txn::execute_snapshot(&state, vec![w(1, value)]).unwrap();
+    }
+    let mut state = state.into_inner().unwrap();
+    let err = state.commit_snapshot(start_ts, vec![(1, 9)]).unwrap_err();
+    assert!(matches!(err, TxnError::Conflict(_)));
+    state.end_snapshot(start_ts);
+    assert_eq!(state.read_only(vec![r(1)], true), vec![read(1, Some(4))]);
+}
+
+
+#[test]
+fn optimistic_transactions_abort_when_something_they_read_was_overwritten() {
+    let mut state = State::default();
+    let start_ts = state.begin_snapshot();
+    state.commit_validated(0, &[], vec![(1, 1)]).unwrap();
+
+    // Only writing `2`, but having read `1`, which changed under it.
+    let err = state.commit_validated(start_ts, &[1], vec![(2, 2)]).unwrap_err();
+    assert!(matches!(err, TxnError::Conflict(_)), "{err:?}");
+    // Blind writes to a key someone else wrote don't conflict: only reads are validated.
+    state.commit_validated(start_ts, &[3], vec![(1, 3)]).unwrap();
+    state.end_snapshot(start_ts);
+
+    let state = Mutex::new(state);
+    let done = txn::execute_occ(&state, vec![r(1), w(2, 4), r(2)]).unwrap();
+    assert_eq!(done, vec![read(1, Some(3)), w(2, 4), read(2, Some(4))]);
+}
+
+
+#[tokio::test]
+async fn locking_transactions_wait_for_the_keys_they_touch() {
+    let state = Arc::new(Mutex::new(State::default()));
+    let locks = Arc::new(Mutex::new(LockManager::new()));
+    // An older transaction is halfway through writing key 1.
+    granted(locks.lock().unwrap().acquire(OLDER, 1, LockMode::Exclusive));
+
+    let waiting = tokio::spawn({
+        let (state, locks) = (state.clone(), locks.clone());
+        async move { txn::execute_locking(&state, &locks, vec![r(1), w(1, 2)]).await }
+    });
+    wait_for_waiters(&locks, 1).await;
+    state.lock().unwrap().apply(vec![w(1, 1)]);
+    locks.lock().unwrap().release_all(OLDER);
+
+    assert_eq!(waiting.await.unwrap().unwrap(), vec![read(1, Some(1)), w(1, 2)]);
+    assert_eq!(state.lock().unwrap().read_only(vec![r(1)], false), vec![read(1, Some(2))]);
+    assert!(locks.lock().unwrap().holders(&1).is_empty());
+}
+
+
+#[tokio::test]
+async fn locking_transactions_abort_to_break_a_deadlock() {
+    let state = Arc::new(Mutex::new(State::default()));
+    let locks = Arc::new(Mutex::new(LockManager::new()));
+    granted(locks.lock().unwrap().acquire(OLDER, 2, LockMode::Exclusive));
+
+    let waiting = tokio::spawn({
+        let (state, locks) = (state.clone(), locks.clone());
+        async move { txn::execute_locking(&state, &locks, vec![w(1, 1), w(2, 1)]).await }
+    });
+    wait_for_waiters(&locks, 1).await;
+    // The older transaction now wants key 1, which the younger one holds.
+    let older = locks.lock().unwrap().acquire(OLDER, 1, LockMode::Exclusive).unwrap();
+
+    let err = waiting.await.unwrap().unwrap_err();
+    assert!(matches!(err, TxnError::Conflict(_)), "{err:?}");
+    // Aborting released key 1 for the older transaction, and wrote nothing.
+    let Acquire::Wait(mut granted) = older else { panic!("expected to wait for the younger transaction") };
+    assert_eq!(granted.try_recv().unwrap(), Ok(()));
+    assert_eq!(state.lock().unwrap().read_only(vec![r(1), r(2)], false), vec![read(1, None), read(2, None)]);
+}
+
+
+#[test]
+fn read_committed_transactions_install_their_writes_at_the_end() {
+    let state = Mutex::new(State::default());
+    state.lock().unwrap().apply(vec![w(1, 1)]);
+    let done = txn::execute_read_committed(&state, vec![r(1), w(1, 2), r(1), w(2, 3)]);
+    assert_eq!(done, vec![read(1, Some(1)), w(1, 2), read(1, Some(2)), w(2, 3)]);
+    assert_eq!(state.lock().unwrap().read_only(vec![r(1), r(2)], false), vec![read(1, Some(2)), read(2, Some(3))]);
+}
+
+
+#[tokio::test]
+async fn read_uncommitted_writes_are_seen_early_and_undone_on_abort() {
+    let state = Arc::new(Mutex::new(State::default()));
+    let locks = Arc::new(Mutex::new(LockManager::new()));
+    state.lock().unwrap().apply(vec![w(1, 1)]);
+    granted(locks.lock().unwrap().acquire(OLDER, 2, LockMode::Exclusive));
+
+    let waiting = tokio::spawn({
+        let (state, locks) = (state.clone(), locks.clone());
+        async move { txn::execute_read_uncommitted(&state, &locks, vec![w(1, 9), w(2, 9)]).await }
+    });
+    wait_for_waiters(&locks, 1).await;
+    // Its write to key 1 is already in place, for anyone to read.
+    assert_eq!(state.lock().unwrap().read_only(vec![r(1)], false), vec![read(1, Some(9))]);
+
+    // Then it loses a deadlock, and puts key 1 back the way it found it.
+    locks.lock().unwrap().acquire(OLDER, 1, LockMode::Exclusive).unwrap();
+    let err = waiting.await.unwrap().unwrap_err();
+    assert!(matches!(err, TxnError::Conflict(_)), "{err:?}");
+    assert_eq!(state.lock().unwrap().read_only(vec![r(1), r(2)], false), vec![read(1, Some(1)), read(2, None)]);
+}
+
+
+/// Run `[w(1, 1), w(2, 1)]` with `args` while an older transaction holds key 2, and have
+/// that one go for key 1 too, so the first attempt loses the deadlock. The older one
+/// finishes as soon as it gets key 1.
+async fn deadlock_once(args: &[&str]) -> (Arc<Mutex<State>>, Result<Vec<MicroOp>, TxnError>) {
+    let state = Arc::new(Mutex::new(State::default()));
+    let locks = Arc::new(Mutex::new(LockManager::new()));
+    granted(locks.lock().unwrap().acquire(OLDER, 2, LockMode::Exclusive));
+
+    let (opts, percolator, rpc) = engine(args);
+    let running = tokio::spawn({
+        let (state, locks) = (state.clone(), locks.clone());
+        async move { txn::execute_with_retries(&state, &percolator, &locks, &rpc, &opts, &[w(1, 1), w(2, 1)]).await }
+    });
+    wait_for_waiters(&locks, 1).await;
+    locks.lock().unwrap().acquire(OLDER, 1, LockMode::Exclusive).unwrap();
+    while locks.lock().unwrap().holders(&1) != vec![(OLDER, LockMode::Exclusive)] {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    locks.lock().unwrap().release_all(OLDER);
+    (state, running.await.unwrap())
+}
+
+
+#[tokio::test]
+async fn conflicted_transactions_are_retried() {
+    let (state, result) = deadlock_once(&["--cc", "locking", "--retry-backoff-ms", "1"]).await;
+    assert_eq!(result.unwrap(), vec![w(1, 1), w(2, 1)]);
+    assert_eq!(state.lock().unwrap().read_only(vec![r(1), r(2)], false), vec![read(1, Some(1)), read(2, Some(1))]);
+}
+
+
+#[tokio::test]
+async fn conflicts_reach_the_client_once_attempts_run_out() {
+    let (state, result) = deadlock_once(&["--cc", "locking", "--txn-attempts", "1"]).await;
+    assert!(matches!(result, Err(TxnError::Conflict(_))), "{result:?}");
+    assert_eq!(state.lock().unwrap().read_only(vec![r(1), r(2)], false), vec![read(1, None), read(2, None)]);
+}
+
+
+#[tokio::test]
+async fn read_only_transactions_dont_wait_for_locks() {
+    let state = Mutex::new(State::default());
+    let locks = Mutex::new(LockManager::new());
+    state.lock().unwrap().apply(vec![w(1, 1)]);
+    // Someone is partway through writing key 1, and hasn't installed anything yet.
+    granted(locks.lock().unwrap().acquire(OLDER, 1, LockMode::Exclusive));
+
+    let (opts, percolator, rpc) = engine(&["--cc", "locking"]);
+    let done = tokio::time::timeout(Duration::from_secs(1), txn::execute(&state, &percolator, &locks, &rpc, &opts, vec![r(1), r(2)])).await;
+    assert_eq!(done.expect("waited on a lock").unwrap(), vec![read(1, Some(1)), read(2, None)]);
+    assert_eq!(locks.lock().unwrap().holders(&1), vec![(OLDER, LockMode::Exclusive)]);
+}
+
+
+#[tokio::test]
+async fn read_only_transactions_read_the_latest_snapshot() {
+    let state = Mutex::new(State::default());
+    let locks = Mutex::new(LockManager::new());
+    let (opts, percolator, rpc) = engine(&["--isolation", "snapshot"]);
+    txn::execute(&state, &percolator, &locks, &rpc, &opts, vec![w(1, 1), w(2, 2)]).await.unwrap();
+    txn::execute(&state, &percolator, &locks, &rpc, &opts, vec![w(1, 3)]).await.unwrap();
+
+    let done = txn::execute(&state, &percolator, &locks, &rpc, &opts, vec![r(1), r(2)]).await.unwrap();
+    assert_eq!(done, vec![read(1, Some(3)), read(2, Some(2))]);
+}
+
+
+#[tokio::test]
+async fn shard_executes_that_turn_up_after_their_txn_finished_are_turned_away() {
+    let state = Mutex::new(State::default());
+    let locks = Mutex::new(LockManager::new());
+    txn::shard_execute(&state, &locks, id(7), vec![w(1, 1)]).await.unwrap();
+    txn::shard_finish(&state, &locks, id(7), true);
+    // The coordinator retrying changes nothing.
+    txn::shard_finish(&state, &locks, id(7), false);
+    assert_eq!(state.lock().unwrap().read_only(vec![r(1)], false), vec![read(1, Some(1))]);
+
+    let err = txn::shard_execute(&state, &locks, id(7), vec![w(2, 2)]).await.unwrap_err();
+    assert!(matches!(err, TxnError::Conflict(_)), "{err:?}");
+    assert!(locks.lock().unwrap().holders(&2).is_empty());
+    assert_eq!(state.lock().unwrap().read_only(vec![r(2)], false), vec![read(2, None)]);
+}
+
+
+#[test]
+fn finished_txns_are_forgotten_after_a_while() {
+    let mut state = State::default();
+    let start = Instant::now();
+    state.finish(id(1), start);
+    state.finish(id(2), start + FINISHED_TTL / 2);
+    state.finish(id(2), start + FINISHED_TTL / 2);
+    assert_eq!(state.num_finished(), 2);
+
+    state.finish(id(3), start + FINISHED_TTL);
+    assert_eq!(state.num_finished(), 2);
+    state.finish(id(4), start + FINISHED_TTL * 2);
+    assert_eq!(state.num_finished(), 1);
+}
+
+
+#[tokio::test]
+async fn txn_ids_from_different_nodes_never_collide() {
+    let node_ids: Vec<String> = (0..=1024).map(|index| format!("n{index}")).collect();
+    let mut ids = HashSet::new();
+    for node_id in ["n0", "n1024"] {
+        let state = Arc::new(Mutex::new(State::default()));
+        let (opts, percolator, rpc) = engine(&["--sharding"]);
+        let init = Payload::Init { node_id: node_id.to_string(), node_ids: node_ids.clone() };
+        let envelope = Envelope::new("c1", node_id, Body { msg_id: Some(1), in_reply_to: None, message: init });
+        txn::handle_envelope(state.clone(), percolator, Arc::new(Mutex::new(LockManager::new())), rpc, Arc::new(opts), envelope, mpsc::unbounded_channel().0).await;
+        let mut state = state.lock().unwrap();
+        ids.extend([state.next_txn_id(), state.next_txn_id()]);
+    }
+    assert_eq!(ids.len(), 4, "{ids:?}");
+}
+
+
+#[tokio::test]
+async fn shards_are_told_how_a_txn_ended_until_they_acknowledge_it() {
+    const IGNORED: usize = 4;
+    let state = Arc::new(Mutex::new(State::default()));
+    let locks = Arc::new(Mutex::new(LockManager::new()));
+    let (opts, percolator, _) = engine(&["--sharding", "--shard-timeout-ms", "20", "--finish-attempts", "2"]);
+    let (writer, mut outbox) = mpsc::unbounded_channel();
+    let rpc = RpcClient::new(writer, message_id);
+    let init = Payload::Init { node_id: "n1".to_string(), node_ids: vec!["n1".to_string(), "n2".to_string()] };
+    let envelope = Envelope::new("c1", "n1", Body { msg_id: Some(1), in_reply_to: None, message: init });
+    txn::handle_envelope(state.clone(), percolator, locks.clone(), rpc.clone(), Arc::new(opts.clone()), envelope, mpsc::unbounded_channel().0).await;
+
+    // n2 runs its share of the transaction, but doesn't acknowledge the first few times
+    // it's told to commit it.
+    let finishes = Arc::new(AtomicUsize::new(0));
+    tokio::spawn({
+        let (rpc, finishes) = (rpc.clone(), finishes.clone());
+        async move {
+            while let Some(envelope) = outbox.recv().await {
+                let reply = match &envelope.body.message {
+                    Payload::ShardExecute { txn, .. } => Some(Payload::ShardExecuteOk { txn: txn.clone() }),
+                    Payload::ShardFinish { commit: true, .. } => (finishes.fetch_add(1, Ordering::SeqCst) >= IGNORED).then_some(Payload::ShardFinishOk),
+                    _ => None,
+                };
+                if let Some(reply) = reply {
+                    rpc.try_complete(envelope.reply_with(Some(message_id()), reply));
+                }
+            }
+        }
+    });
+
+    let ring = HashRing::new(&["n1", "n2"]);
+    let owned_by = |node: &str| (0..).find(|key: &usize| ring.owner(key).is_some_and(|owner| &**owner == node)).unwrap();
+    let (mine, theirs) = (owned_by("n1"), owned_by("n2"));
+    let scheduler = Scheduler::new();
+    txn::finish_every_so_often(&scheduler, state.clone(), rpc.clone(), Duration::from_millis(opts.shard_timeout_ms));
+    let result = txn::execute_sharded(&state, &locks, &rpc, &opts, vec![w(mine, 1), w(theirs, 1)]).await;
+    assert!(matches!(result, Err(TxnError::Indeterminate(KvError::GaveUp(_)))), "{result:?}");
+    assert_eq!(state.lock().unwrap().read_only(vec![r(mine)], false), vec![read(mine, Some(1))]);
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while finishes.load(Ordering::SeqCst) <= IGNORED {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    })
+    .await
+    .expect("stopped telling n2 the txn committed before it acknowledged");
+}
+
+#[test]
+fn the_read_uncommitted_and_read_committed_binaries_run_transactions() {
+    for binary in [env!("CARGO_BIN_EXE_totally_available_read_uncommitted_transactions_single_node"), env!("CARGO_BIN_EXE_totally_available_read_committed_transactions_single_node")] {
+        let mut node =
+            Command::new(binary)
+            .env_remove("CONSISTENCY")
+            .env_remove("CONFIG")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut stdin = node.stdin.take().unwrap();
+        writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}}}"#).unwrap();
+        writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "txn", "msg_id": 2, "txn": [["w", 1, 10], ["r", 1, null]]}}}}"#).unwrap();
+        let mut stdout = BufReader::new(node.stdout.take().unwrap()).lines();
+        stdout.next().unwrap().unwrap();
+        let reply: serde_json::Value = serde_json::from_str(&stdout.next().unwrap().unwrap()).unwrap();
+        assert_eq!(reply["body"]["txn"], serde_json::json!([["w", 1, 10], ["r", 1, 10]]), "{binary}: {reply}");
+        drop(stdin);
+        node.wait().unwrap();
+    }
+}
@@ -0,0 +1,59 @@
+//! Changing a node's parameters while it runs.
+
+use std::{io::{BufRead, BufReader, Write}, process::{Command, Stdio}, time::Duration};
+use serde_json::{json, Value};
+use solutions::tuning::Tunables;
+
+
+fn body(value: Value) -> serde_json::Map<String, Value> {
+    value.as_object().unwrap().clone()
+}
+
+#[test]
+fn configure_changes_only_what_it_names() {
+    let tunables = Tunables::new();
+    tunables.configure(&body(json!({"type": "configure", "msg_id": 1, "tick_rate_ms": 250, "fanout": 3}))).unwrap();
+    assert_eq!(tunables.tick_rate(), Duration::from_millis(250));
+    assert_eq!(tunables.fanout(), Some(3));
+    assert_eq!(tunables.batch_size(), None);
+
+    tunables.configure(&body(json!({"batch_size": 50, "fanout": null, "window": 2}))).unwrap();
+    assert_eq!(tunables.to_json(), json!({"tick_rate_ms": 250, "fanout": null, "fanout_strategy": "peers", "batch_size": 50, "window": 2}));
+}
+
+#[test]
+fn bad_configure_requests_change_nothing() {
+    let tunables = Tunables::new();
+    let before = tunables.to_json();
+    assert!(tunables.configure(&body(json!({"fanout": 2, "tick_rate_ms": 0}))).is_err());
+    assert!(tunables.configure(&body(json!({"fanout": 2, "stride": 3}))).unwrap_err().contains("unknown parameter `stride`"));
+    assert!(tunables.configure(&body(json!({"batch_size": "lots"}))).is_err());
+    assert!(tunables.configure(&body(json!({"fanout": 2, "fanout_strategy": "everyone"}))).is_err());
+    assert_eq!(tunables.to_json(), before);
+}
+
+#[test]
+fn nodes_answer_configure_requests_themselves() {
+    let mut node =
+        Command::new(env!("CARGO_BIN_EXE_broadcast"))
+        .args(["--stride", "1", "--tick-rate-ms", "150"])
+        .env("METRICS_INTERVAL_MS", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = node.stdin.take().unwrap();
+    let mut stdout = BufReader::new(node.stdout.take().unwrap()).lines();
+    let mut ask = |request: Value| -> Value {
+        writeln!(stdin, "{}", json!({"src": "c1", "dest": "n1", "body": request})).unwrap();
+        serde_json::from_str::<Value>(&stdout.next().unwrap().unwrap()).unwrap()["body"].clone()
+    };
+
+    let reply = ask(json!({"type": "configure", "msg_id": 1, "batch_size": 10}));
+    assert_eq!(reply, json!({"type": "configure_ok", "in_reply_to": 1, "tick_rate_ms": 150, "fanout": null, "fanout_strategy": "peers", "batch_size": 10, "window": null}));
+    let reply = ask(json!({"type": "configure", "msg_id": 2, "tick_rate_ms": -5}));
+    assert_eq!((reply["type"].as_str(), reply["code"].as_u64(), reply["in_reply_to"].as_u64()), (Some("error"), Some(12), Some(2)), "{reply}");
+    drop(stdin);
+    node.wait().unwrap();
+}
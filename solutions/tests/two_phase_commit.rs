@@ -0,0 +1,178 @@
+//! The 2PC coordinator and participant: deciding on the votes (or their absence), resending
+//! decisions until every participant acknowledges them, and what a participant remembers
+//! across a restart.
+
+use std::{collections::HashMap, time::{Duration, Instant}};
+use solutions::{storage::FileStorage, two_phase_commit::{Coordinator, Decision, Message, Participant, Step, TxnId}};
+
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+fn coordinator() -> Coordinator<u64> {
+    Coordinator::new("c1", TIMEOUT, TIMEOUT)
+}
+
+fn writes(participants: &[&str]) -> HashMap<String, u64> {
+    participants.iter().enumerate().map(|(i, participant)| (participant.to_string(), i as u64)).collect()
+}
+
+/// Who each outgoing message goes to, along with its phase.
+fn sent(step: &Step<u64>) -> Vec<(String, &'static str)> {
+    let mut sent: Vec<_> =
+        step.outgoing
+        .iter()
+        .map(|outgoing| {
+            let phase = match outgoing.message {
+                Message::Prepare { .. } => "prepare",
+                Message::Vote { .. } => "vote",
+                Message::Commit { .. } => "commit",
+                Message::Abort { .. } => "abort",
+                Message::Ack { .. } => "ack",
+            };
+            (outgoing.destination.clone(), phase)
+        })
+        .collect();
+    sent.sort();
+    sent
+}
+
+fn to(participants: &[&str], phase: &'static str) -> Vec<(String, &'static str)> {
+    participants.iter().map(|participant| (participant.to_string(), phase)).collect()
+}
+
+fn vote(txn: &TxnId, commit: bool) -> Message<u64> {
+    Message::Vote { txn: txn.clone(), commit }
+}
+
+fn ack(txn: &TxnId) -> Message<u64> {
+    Message::Ack { txn: txn.clone() }
+}
+
+
+#[test]
+fn a_transaction_commits_once_everyone_votes_yes() {
+    let mut coordinator = coordinator();
+    let now = Instant::now();
+    let (txn, step) = coordinator.begin(writes(&["n1", "n2"]), now);
+    assert_eq!(sent(&step), to(&["n1", "n2"], "prepare"));
+
+    assert!(coordinator.on_message("n1", vote(&txn, true), now).decided.is_empty());
+    let step = coordinator.on_message("n2", vote(&txn, true), now);
+    assert_eq!(step.decided, vec![(txn.clone(), Decision::Commit)]);
+    assert_eq!(sent(&step), to(&["n1", "n2"], "commit"));
+
+    coordinator.on_message("n1", ack(&txn), now);
+    // Acks from anyone but the participants don't count.
+    coordinator.on_message("n3", ack(&txn), now);
+    assert_eq!(coordinator.in_flight(), 1);
+    coordinator.on_message("n2", ack(&txn), now);
+    assert_eq!(coordinator.in_flight(), 0);
+}
+
+
+#[test]
+fn a_single_no_vote_aborts_the_transaction() {
+    let mut coordinator = coordinator();
+    let now = Instant::now();
+    let (txn, _) = coordinator.begin(writes(&["n1", "n2", "n3"]), now);
+
+    coordinator.on_message("n1", vote(&txn, true), now);
+    let step = coordinator.on_message("n2", vote(&txn, false), now);
+    assert_eq!(step.decided, vec![(txn.clone(), Decision::Abort)]);
+    assert_eq!(sent(&step), to(&["n1", "n2", "n3"], "abort"));
+    // A vote that turns up after the decision doesn't change it.
+    assert!(coordinator.on_message("n3", vote(&txn, true), now).decided.is_empty());
+}
+
+
+#[test]
+fn votes_from_outside_the_transaction_are_ignored() {
+    let mut coordinator = coordinator();
+    let now = Instant::now();
+    let (txn, _) = coordinator.begin(writes(&["n1", "n2"]), now);
+
+    coordinator.on_message("n1", vote(&txn, true), now);
+    // n3 isn't a participant, so its yes doesn't stand in for n2's, nor does its no abort.
+    assert!(coordinator.on_message("n3", vote(&txn, true), now).decided.is_empty());
+    assert!(coordinator.on_message("n3", vote(&txn, false), now).decided.is_empty());
+
+    let step = coordinator.on_message("n2", vote(&txn, true), now);
+    assert_eq!(step.decided, vec![(txn.clone(), Decision::Commit)]);
+}
+
+
+#[test]
+fn missing_votes_are_asked_for_again_until_the_transaction_times_out() {
+    let mut coordinator = Coordinator::new("c1", TIMEOUT * 3, TIMEOUT);
+    let start = Instant::now();
+    let (txn, _) = coordinator.begin(writes(&["n1", "n2"]), start);
+    coordinator.on_message("n1", vote(&txn, true), start);
+
+    assert!(coordinator.tick(start + TIMEOUT / 2).outgoing.is_empty());
+    let step = coordinator.tick(start + TIMEOUT);
+    assert!(step.decided.is_empty());
+    assert_eq!(sent(&step), to(&["n2"], "prepare"));
+
+    let step = coordinator.tick(start + TIMEOUT * 3);
+    assert_eq!(step.decided, vec![(txn, Decision::Abort)]);
+    assert_eq!(sent(&step), to(&["n1", "n2"], "abort"));
+}
+
+
+#[test]
+fn a_transaction_without_participants_commits_at_once() {
+    let mut coordinator = coordinator();
+    let (txn, step) = coordinator.begin(HashMap::new(), Instant::now());
+    assert_eq!(step.decided, vec![(txn, Decision::Commit)]);
+    assert!(step.outgoing.is_empty());
+    assert_eq!(coordinator.in_flight(), 0);
+}
+
+
+#[test]
+fn an_announced_decision_is_resent_until_acknowledged() {
+    let mut coordinator = coordinator();
+    let start = Instant::now();
+    let txn = TxnId { coordinator: "c1".to_string(), seq: 7 };
+    let step = coordinator.announce(txn.clone(), ["n1".to_string(), "n2".to_string()], Decision::Commit, start);
+    assert_eq!(step.decided, vec![(txn.clone(), Decision::Commit)]);
+    assert_eq!(sent(&step), to(&["n1", "n2"], "commit"));
+
+    coordinator.on_message("n1", ack(&txn), start);
+    // Long past the prepare timeout, but there's nothing left to time out.
+    let step = coordinator.tick(start + TIMEOUT * 10);
+    assert!(step.decided.is_empty());
+    assert_eq!(sent(&step), to(&["n2"], "commit"));
+
+    coordinator.on_message("n2", ack(&txn), start);
+    assert!(coordinator.tick(start + TIMEOUT * 20).outgoing.is_empty());
+    assert_eq!(coordinator.in_flight(), 0);
+}
+
+
+#[test]
+fn a_participant_remembers_what_it_prepared_across_restarts() {
+    let dir = tempfile::tempdir().unwrap();
+    let committed = TxnId { coordinator: "c1".to_string(), seq: 1 };
+    let pending = TxnId { coordinator: "c1".to_string(), seq: 2 };
+    let refused = TxnId { coordinator: "c1".to_string(), seq: 3 };
+
+    {
+        let mut participant = Participant::<u64, _>::open(FileStorage::open(dir.path()).unwrap()).unwrap();
+        participant.prepare(committed.clone(), 10, true).unwrap();
+        participant.prepare(pending.clone(), 20, true).unwrap();
+        participant.prepare(refused.clone(), 30, false).unwrap();
+        let (writes, reply) = participant.resolve(committed.clone(), Decision::Commit).unwrap();
+        assert_eq!(writes, Some(10));
+        assert!(matches!(reply, Message::Ack { txn } if txn == committed));
+    }
+
+    let mut participant = Participant::<u64, _>::open(FileStorage::open(dir.path()).unwrap()).unwrap();
+    let in_doubt: Vec<_> = participant.in_doubt().map(|(txn, &writes)| (txn.clone(), writes)).collect();
+    assert_eq!(in_doubt, vec![(pending.clone(), 20)]);
+
+    // Hearing about a transaction twice (or one we never prepared) just gets another ack.
+    assert_eq!(participant.resolve(committed, Decision::Commit).unwrap().0, None);
+    assert_eq!(participant.resolve(pending, Decision::Abort).unwrap().0, Some(20));
+    assert_eq!(participant.in_doubt().count(), 0);
+}
@@ -0,0 +1,76 @@
+//! Linting the messages a node is given.
+
+use std::{io::Write, process::{Command, Stdio}};
+use solutions::validate::{Validator, Violation};
+
+
+fn violation(line: usize, problem: &str) -> Violation {
+    Violation { line, problem: problem.to_owned() }
+}
+
+#[test]
+fn validator_reports_protocol_violations() {
+    let mut validator = Validator::new();
+    validator.received(1, br#"{"src": "c1", "dest": "n1", "body": {"type": "echo", "msg_id": 1, "echo": "hi"}}"#, Ok(()));
+    validator.received(2, br#"{"src": "c1", "dest": "n1", "body": {"type": "echo", "echo": "hi"}}"#, Ok(()));
+    validator.received(3, b"", Ok(()));
+    validator.received(4, b"not json", Ok(()));
+    validator.received(5, br#"{"dest": "n1", "body": {"msg_id": 3}}"#, Ok(()));
+    validator.sent(br#"{"src": "n1", "dest": "n2", "body": {"type": "sync", "msg_id": 7}}"#);
+    validator.received(6, br#"{"src": "n2", "dest": "n1", "body": {"type": "sync_ok", "in_reply_to": 7}}"#, Ok(()));
+    validator.received(7, br#"{"src": "n3", "dest": "n1", "body": {"type": "sync_ok", "in_reply_to": 7}}"#, Ok(()));
+
+    assert_eq!(validator.num_received(), 6);
+    assert_eq!(validator.finish(), [
+        violation(2, "`echo` request has no `msg_id`"),
+        violation(4, "not a JSON message"),
+        violation(5, "no `src`"),
+        violation(5, "no body `type`"),
+        violation(7, "reply from n3 to unknown msg_id 7"),
+    ]);
+}
+
+#[test]
+fn validator_tells_unknown_types_from_malformed_ones() {
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    #[allow(dead_code)]
+    enum Payload {
+        Echo { echo: String },
+    }
+    let mut validator = Validator::new();
+    for (line, message) in [br#"{"type": "echo"}"#.as_slice(), br#"{"type": "shout"}"#].into_iter().enumerate() {
+        let err = serde_json::from_slice::<Payload>(message).unwrap_err();
+        let envelope = format!(r#"{{"src": "c1", "dest": "n1", "body": {}}}"#, String::from_utf8_lossy(message).replace('}', r#", "msg_id": 1}"#));
+        validator.received(line + 1, envelope.as_bytes(), Err(&err));
+    }
+    let problems: Vec<&str> = validator.finish().iter().map(|violation| violation.problem.as_str()).collect();
+    assert!(problems[0].starts_with("malformed `echo`: missing field `echo`"), "{problems:?}");
+    assert_eq!(problems[1], "unknown type `shout`");
+}
+
+#[test]
+fn validating_nodes_report_instead_of_replying() {
+    let mut node =
+        Command::new(env!("CARGO_BIN_EXE_echo"))
+        .arg("--validate")
+        .env("METRICS_INTERVAL_MS", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = node.stdin.take().unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}}}"#).unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "echo", "msg_id": 2, "echo": "hi"}}}}"#).unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "topology", "msg_id": 3, "topology": {{}}}}}}"#).unwrap();
+    writeln!(stdin, r#"{{"src": "c1", "dest": "n1", "body": {{"type": "echo_ok", "in_reply_to": 9, "echo": "hi"}}}}"#).unwrap();
+    drop(stdin);
+    let output = node.wait_with_output().unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+    assert!(output.stdout.is_empty());
+    assert!(stderr.contains("violation: line 3: unknown type `topology`"), "{stderr}");
+    assert!(stderr.contains("violation: line 4: reply from c1 to unknown msg_id 9"), "{stderr}");
+    assert!(stderr.contains("validate: 4 messages, 2 violations"), "{stderr}");
+}
@@ -0,0 +1,37 @@
+//! Vector clocks: which of two versions has seen everything the other has, if either.
+
+use solutions::vector_clock::VectorClock;
+use std::cmp::Ordering;
+
+
+fn clock(counts: &[(&str, u64)]) -> VectorClock {
+    let mut clock = VectorClock::new();
+    for &(node, count) in counts {
+        for _ in 0..count {
+            clock.increment(node);
+        }
+    }
+    clock
+}
+
+#[test]
+fn later_writes_descend_from_earlier_ones_and_others_are_concurrent() {
+    let first = clock(&[("n1", 1)]);
+    let second = clock(&[("n1", 2)]);
+    assert_eq!(second.partial_cmp(&first), Some(Ordering::Greater));
+    assert!(second.descends(&first) && !first.descends(&second));
+    assert!(first.descends(&VectorClock::new()));
+
+    let elsewhere = clock(&[("n1", 1), ("n2", 1)]);
+    assert!(elsewhere.concurrent(&second));
+    assert_eq!(elsewhere.partial_cmp(&second), None);
+    assert_eq!(first.partial_cmp(&first.clone()), Some(Ordering::Equal));
+}
+
+#[test]
+fn merging_takes_the_most_seen_of_each_node() {
+    let mut merged = clock(&[("n1", 2), ("n2", 1)]);
+    merged.merge(&clock(&[("n2", 3), ("n3", 1)]));
+    assert_eq!((merged.get("n1"), merged.get("n2"), merged.get("n3"), merged.get("n4")), (2, 3, 1, 0));
+    assert_eq!(serde_json::to_value(&merged).unwrap(), serde_json::json!({"n1": 2, "n2": 3, "n3": 1}));
+}